@@ -0,0 +1,177 @@
+//! Typed async client for the week-1 book API.
+//!
+//! The crate also builds for `wasm32-unknown-unknown`, where reqwest goes
+//! through the browser's `fetch`. A Rust front-end served from the API's own
+//! origin can use [`Client::same_origin`]:
+//!
+//! ```text
+//! cargo build -p books-client --target wasm32-unknown-unknown
+//! ```
+//!
+//! Futures returned by the client are not `Send` on wasm, so drive them with
+//! `wasm_bindgen_futures::spawn_local` instead of a multi-threaded executor.
+
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+pub use books_model::{Book, CreateBookRequest, UpdateBookRequest};
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    Status { status: u16, body: String },
+}
+
+impl Error {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Http(e) => e.status().map(|s| s.as_u16()),
+            Error::Status { status, .. } => Some(*status),
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(404)
+    }
+
+    // The book changed since the ETag given to an update or delete was read.
+    pub fn is_precondition_failed(&self) -> bool {
+        self.status() == Some(412)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "request failed: {}", e),
+            Error::Status { status, body } => write!(f, "server returned {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Status { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    base_url: String,
+    http: reqwest::Client,
+    api_key: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Client::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    // Browsers need absolute URLs for fetch, so resolve the page's origin.
+    #[cfg(target_arch = "wasm32")]
+    pub fn same_origin() -> Option<Self> {
+        let origin = web_sys::window()?.location().origin().ok()?;
+        Some(Client::new(origin))
+    }
+
+    pub fn with_http_client(base_url: impl Into<String>, http: reqwest::Client) -> Self {
+        Client {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http,
+            api_key: None,
+        }
+    }
+
+    // Sends the key in X-Api-Key, which writes need once the server has
+    // keys configured.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    pub async fn list_books(&self) -> Result<Vec<Book>, Error> {
+        let response = self.request(reqwest::Method::GET, "/v1/books").send().await?;
+        json(response).await
+    }
+
+    pub async fn get_book(&self, id: u64) -> Result<Book, Error> {
+        let response = self.request(reqwest::Method::GET, &format!("/v1/books/{}", id)).send().await?;
+        json(response).await
+    }
+
+    pub async fn create_book(&self, request: &CreateBookRequest) -> Result<Book, Error> {
+        let response = self.request(reqwest::Method::POST, "/v1/books").json(request).send().await?;
+        json(response).await
+    }
+
+    // The book along with its ETag, which updates and deletes need.
+    pub async fn get_book_with_etag(&self, id: u64) -> Result<(Book, String), Error> {
+        let response = self.request(reqwest::Method::GET, &format!("/v1/books/{}", id)).send().await?;
+        tagged(response).await
+    }
+
+    // Fails with 412 if the book has changed since `etag`, or use "*" to
+    // update whatever version is stored. Returns the new ETag.
+    pub async fn update_book(&self, id: u64, etag: &str, request: &UpdateBookRequest) -> Result<(Book, String), Error> {
+        let response = self
+            .request(reqwest::Method::PUT, &format!("/v1/books/{}", id))
+            .header(reqwest::header::IF_MATCH, etag)
+            .json(request)
+            .send()
+            .await?;
+        tagged(response).await
+    }
+
+    pub async fn delete_book(&self, id: u64, etag: &str) -> Result<(), Error> {
+        let response = self
+            .request(reqwest::Method::DELETE, &format!("/v1/books/{}", id))
+            .header(reqwest::header::IF_MATCH, etag)
+            .send()
+            .await?;
+        check(response).await.map(|_| ())
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.http.request(method, format!("{}{}", self.base_url, path));
+        match &self.api_key {
+            Some(key) => request.header("X-Api-Key", key),
+            None => request,
+        }
+    }
+}
+
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(Error::Status {
+        status: status.as_u16(),
+        body,
+    })
+}
+
+async fn json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, Error> {
+    Ok(check(response).await?.json().await?)
+}
+
+async fn tagged<T: DeserializeOwned>(response: reqwest::Response) -> Result<(T, String), Error> {
+    let response = check(response).await?;
+    let etag = response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    Ok((response.json().await?, etag))
+}
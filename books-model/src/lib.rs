@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Book {
+    pub id: u64,
+    pub title: String,
+    pub author: String,
+    pub isbn: Option<String>,
+    // The author this book is filed under, whose name `author` then is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_id: Option<u64>,
+    // Lowercase, sorted and without duplicates.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub edition: Edition,
+    // A UUID or ULID the book can be reached by besides its id, minted when
+    // the server is configured to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    // The NFT minted for the book on MultiversX, once one has been.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nft: Option<Nft>,
+    // The book's latest record hash written to MultiversX.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<Anchor>,
+    // When the book was deleted, in milliseconds since the epoch. Only
+    // books in the trash have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<u64>,
+}
+
+// What's known of the edition a book is, each of it optional. Books, and
+// the requests that create and update them, carry these as fields of their
+// own.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Edition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_year: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pages: Option<u32>,
+    // An ISO 639 code, lowercase, like "en" or "fra".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl Edition {
+    // `self` with the fields `other` has replacing its own.
+    pub fn updated(&self, other: Edition) -> Edition {
+        Edition {
+            published_year: other.published_year.or(self.published_year),
+            publisher: other.publisher.or_else(|| self.publisher.clone()),
+            pages: other.pages.or(self.pages),
+            language: other.language.or_else(|| self.language.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Nft {
+    // The hash of the ESDTNFTCreate transaction.
+    pub tx_hash: String,
+    pub status: NftStatus,
+    // The collection's identifier and the NFT's nonce, e.g. BOOKS-a1b2c3-01,
+    // known once the transaction has been executed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_identifier: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum NftStatus {
+    Pending,
+    Minted,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Anchor {
+    // SHA-256 of the book's record as it was anchored, in hex.
+    pub hash: String,
+    pub tx_hash: String,
+    pub status: TxStatus,
+    // When it was sent, in seconds since the epoch.
+    pub anchored_at: u64,
+}
+
+// How a transaction stands, in the gateway's terms.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum TxStatus {
+    Pending,
+    Success,
+    Fail,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CreateBookRequest {
+    pub title: String,
+    // May be left out when `author_id` is given.
+    #[serde(default)]
+    pub author: String,
+    pub isbn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub edition: Edition,
+    // Set by the server as the book is stored; whatever a client sends is
+    // replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UpdateBookRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isbn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author_id: Option<u64>,
+    // Replaces the book's tags as a whole.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub edition: Edition,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Author {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CreateAuthorRequest {
+    pub name: String,
+}
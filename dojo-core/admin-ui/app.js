@@ -0,0 +1,195 @@
+"use strict";
+
+const tokenInput = document.getElementById("token");
+const apiKeyInput = document.getElementById("api-key");
+const booksBody = document.getElementById("books");
+const deadJobsBody = document.getElementById("dead-jobs");
+const output = document.getElementById("output");
+const errorBox = document.getElementById("error");
+
+tokenInput.value = localStorage.getItem("dojo-admin-token") || "";
+tokenInput.addEventListener("change", () => {
+  localStorage.setItem("dojo-admin-token", tokenInput.value);
+  loadBooks();
+  run(loadDeadJobs);
+});
+apiKeyInput.value = localStorage.getItem("dojo-api-key") || "";
+apiKeyInput.addEventListener("change", () => {
+  localStorage.setItem("dojo-api-key", apiKeyInput.value);
+  loadBooks();
+});
+
+// What an error response says went wrong: the problem's detail, or the
+// body as it came.
+async function problem(response) {
+  const text = await response.text();
+  if (!(response.headers.get("Content-Type") || "").startsWith("application/problem+json")) return text;
+  try {
+    return JSON.parse(text).detail ?? text;
+  } catch {
+    return text;
+  }
+}
+
+async function api(method, path, body, extraHeaders) {
+  const headers = { ...extraHeaders };
+  if (tokenInput.value) headers["Authorization"] = "Bearer " + tokenInput.value;
+  if (apiKeyInput.value) headers["X-Api-Key"] = apiKeyInput.value;
+  if (body !== undefined) headers["Content-Type"] = "application/json";
+  const response = await fetch(path, {
+    method,
+    headers,
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  if (!response.ok) throw new Error(`${method} ${path}: ${response.status} ${await problem(response)}`);
+  const text = await response.text();
+  const type = response.headers.get("Content-Type") || "";
+  return type.startsWith("application/json") ? JSON.parse(text) : text;
+}
+
+function run(task) {
+  errorBox.textContent = "";
+  task().catch((e) => (errorBox.textContent = e.message));
+}
+
+function cell(value) {
+  const td = document.createElement("td");
+  td.textContent = value ?? "";
+  return td;
+}
+
+function input(value) {
+  const td = document.createElement("td");
+  const field = document.createElement("input");
+  field.value = value ?? "";
+  td.append(field);
+  return [td, field];
+}
+
+function button(label, onClick) {
+  const b = document.createElement("button");
+  b.textContent = label;
+  b.addEventListener("click", () => run(onClick));
+  return b;
+}
+
+// The ETag of the book as the table shows it. The listing carries no
+// ETags, so the book is fetched again; if it no longer matches the row, the
+// write is refused rather than overwriting a change made elsewhere.
+async function etagOf(book) {
+  const headers = { Accept: "application/json" };
+  if (tokenInput.value) headers["Authorization"] = "Bearer " + tokenInput.value;
+  if (apiKeyInput.value) headers["X-Api-Key"] = apiKeyInput.value;
+  const response = await fetch(`/v1/books/${book.id}`, { headers });
+  if (!response.ok) throw new Error(`GET /v1/books/${book.id}: ${response.status} ${await problem(response)}`);
+  const current = await response.json();
+  if (current.title !== book.title || current.author !== book.author || (current.isbn ?? null) !== (book.isbn ?? null)) {
+    await loadBooks();
+    throw new Error(`book ${book.id} was changed elsewhere; the table has been reloaded`);
+  }
+  return response.headers.get("ETag");
+}
+
+function renderBook(book) {
+  const row = document.createElement("tr");
+  const [titleCell, title] = input(book.title);
+  const [authorCell, author] = input(book.author);
+  const [isbnCell, isbn] = input(book.isbn);
+  const actions = document.createElement("td");
+  actions.append(
+    button("Save", async () => {
+      const update = { title: title.value, author: author.value };
+      if (isbn.value) update.isbn = isbn.value;
+      await api("PUT", `/v1/books/${book.id}`, update, { "If-Match": await etagOf(book) });
+      await loadBooks();
+    }),
+    button("Delete", async () => {
+      await api("DELETE", `/v1/books/${book.id}`, undefined, { "If-Match": await etagOf(book) });
+      await loadBooks();
+    }),
+  );
+  row.append(cell(book.id), titleCell, authorCell, isbnCell, actions);
+  return row;
+}
+
+async function loadBooks() {
+  const books = await api("GET", "/v1/books");
+  books.sort((a, b) => a.id - b.id);
+  booksBody.replaceChildren(...books.map(renderBook));
+}
+
+document.getElementById("create").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const form = event.target;
+  run(async () => {
+    const book = { title: form.title.value, author: form.author.value };
+    if (form.isbn.value) book.isbn = form.isbn.value;
+    await api("POST", "/v1/books", book);
+    form.reset();
+    await loadBooks();
+  });
+});
+
+function show(value) {
+  output.textContent = typeof value === "string" ? value : JSON.stringify(value, null, 2);
+}
+
+async function pollTask(task) {
+  show(task);
+  while (task.status === "running") {
+    await new Promise((resolve) => setTimeout(resolve, 500));
+    task = await api("GET", `/admin/tasks/${task.id}`);
+    show(task);
+  }
+  await loadBooks();
+}
+
+function renderDeadJob(job) {
+  const row = document.createElement("tr");
+  const actions = document.createElement("td");
+  actions.append(
+    button("History", async () => show(await api("GET", `/admin/dead-jobs/${job.id}`))),
+    button("Retry", async () => {
+      await api("POST", `/admin/dead-jobs/${job.id}/retry`);
+      await loadDeadJobs();
+    }),
+    button("Discard", async () => {
+      await api("DELETE", `/admin/dead-jobs/${job.id}`);
+      await loadDeadJobs();
+    }),
+  );
+  row.append(cell(job.id), cell(job.kind), cell(job.attempts), cell(job.last_error), actions);
+  return row;
+}
+
+async function loadDeadJobs() {
+  const jobs = await api("GET", "/admin/dead-jobs");
+  deadJobsBody.replaceChildren(...jobs.map(renderDeadJob));
+}
+
+const actions = {
+  check: async () => {
+    const fix = document.getElementById("fix").checked;
+    show(await api("POST", `/admin/check?fix=${fix}`));
+    if (fix) await loadBooks();
+  },
+  compact: async () => show(await api("POST", "/admin/compact")),
+  migrate: async () => pollTask(await api("POST", "/admin/migrate-data")),
+  metrics: async () => show(await api("GET", "/admin/storage-metrics")),
+  deadJobs: loadDeadJobs,
+  retryAll: async () => {
+    show(await api("POST", "/admin/dead-jobs/retry"));
+    await loadDeadJobs();
+  },
+  discardAll: async () => {
+    show(await api("DELETE", "/admin/dead-jobs"));
+    await loadDeadJobs();
+  },
+};
+
+for (const element of document.querySelectorAll("[data-action]")) {
+  element.addEventListener("click", () => run(actions[element.dataset.action]));
+}
+
+run(loadBooks);
+run(loadDeadJobs);
@@ -0,0 +1,90 @@
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, header, Method, Request, StatusCode};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpListener;
+
+// How long a 100k-book listing takes to start arriving and to finish, over
+// the network against an in-memory catalog. Run with
+// `cargo bench --bench listing`.
+const BOOKS: usize = 100_000;
+const PER_IMPORT: usize = 10_000;
+const RUNS: usize = 5;
+
+async fn start() -> SocketAddr {
+    let app = dojo_core::app(dojo_core::open().await);
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            let app = app.clone();
+            tokio::spawn(async move {
+                let _ = auto::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(tcp), app).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn seed(client: &Client<HttpConnector, Full<Bytes>>, addr: SocketAddr) {
+    for start in (0..BOOKS).step_by(PER_IMPORT) {
+        let mut csv = String::from("title,author\n");
+        for n in start..start + PER_IMPORT {
+            csv.push_str(&format!("Book {},Author {}\n", n, n % 1000));
+        }
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/v1/books/import", addr))
+            .header(header::CONTENT_TYPE, "text/csv")
+            .body(Full::from(csv))
+            .unwrap();
+        let response = client.request(req).await.unwrap();
+        assert!(response.status().is_success(), "import answered {}", response.status());
+        response.into_body().collect().await.unwrap();
+    }
+}
+
+// Time to the first chunk of the body, time to its end, and its size.
+async fn fetch(client: &Client<HttpConnector, Full<Bytes>>, addr: SocketAddr, path: &str) -> (Duration, Duration, usize) {
+    let started = Instant::now();
+    let response = client.get(format!("http://{}{}", addr, path).parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK, "{}", path);
+    let mut body = response.into_body();
+    let mut first = None;
+    let mut size = 0;
+    while let Some(frame) = body.frame().await {
+        first.get_or_insert_with(|| started.elapsed());
+        size += frame.unwrap().into_data().map_or(0, |data| data.len());
+    }
+    (first.unwrap_or_default(), started.elapsed(), size)
+}
+
+#[tokio::main]
+async fn main() {
+    let addr = start().await;
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    seed(&client, addr).await;
+    for path in ["/v1/books", "/v1/books?fields=id,title", "/v2/books?limit=100000"] {
+        let mut runs = Vec::with_capacity(RUNS);
+        for _ in 0..RUNS {
+            runs.push(fetch(&client, addr, path).await);
+        }
+        runs.sort_by_key(|(_, total, _)| *total);
+        let (first, total, size) = runs[RUNS / 2];
+        println!(
+            "GET {:<28} first byte {:>8.2?}  total {:>8.2?}  {:>6} KiB  (median of {})",
+            path,
+            first,
+            total,
+            size / 1024,
+            RUNS
+        );
+    }
+}
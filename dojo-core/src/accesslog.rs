@@ -0,0 +1,314 @@
+use http_body::Body as HttpBody;
+use hyper::{Request, Response};
+use serde_json::json;
+use std::{
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+
+use crate::{
+    body::{self, Body},
+    listen::PeerAddr,
+    proxy::ClientIp,
+    request_id::RequestId,
+    SharedState,
+};
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_FILES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Common,
+    Json,
+}
+
+enum Sink {
+    Stdout,
+    File(Rotating),
+}
+
+// A file that's moved aside to <path>.1 once it would grow past
+// `max_bytes`, shifting older ones up to <path>.<files>.
+struct Rotating {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    files: usize,
+}
+
+impl Rotating {
+    fn open(path: PathBuf, max_bytes: u64, files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Rotating { path, file, size, max_bytes, files })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.files).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                fs::rename(from, numbered(&self.path, n + 1))?;
+            }
+        }
+        if self.files > 0 {
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// One line per request, apart from the application's logs on stderr.
+// DOJO_ACCESS_LOG is `stdout` or a file, rotated at
+// DOJO_ACCESS_LOG_MAX_BYTES (10 MiB by default, 0 never) keeping
+// DOJO_ACCESS_LOG_FILES old ones (5 by default). DOJO_ACCESS_LOG_FORMAT is
+// `common`, the Common Log Format followed by the latency in milliseconds
+// and the request ID, or `json`.
+pub struct AccessLog {
+    format: Format,
+    sink: Mutex<Sink>,
+}
+
+impl AccessLog {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let target = match std::env::var("DOJO_ACCESS_LOG") {
+            Ok(target) if !target.is_empty() => target,
+            _ => return Ok(None),
+        };
+        let format = match std::env::var("DOJO_ACCESS_LOG_FORMAT").as_deref() {
+            Err(_) | Ok("common") => Format::Common,
+            Ok("json") => Format::Json,
+            Ok(other) => return Err(format!("DOJO_ACCESS_LOG_FORMAT must be common or json, got {:?}", other)),
+        };
+        let number = |name: &str, default| match std::env::var(name) {
+            Ok(value) => value.parse().map_err(|_| format!("{} must be a number, got {:?}", name, value)),
+            Err(_) => Ok(default),
+        };
+        let sink = match target.as_str() {
+            "stdout" => Sink::Stdout,
+            path => {
+                let max_bytes = number("DOJO_ACCESS_LOG_MAX_BYTES", DEFAULT_MAX_BYTES)?;
+                let files = number("DOJO_ACCESS_LOG_FILES", DEFAULT_FILES as u64)? as usize;
+                let file = Rotating::open(PathBuf::from(path), max_bytes, files).map_err(|e| format!("{}: {}", path, e))?;
+                Sink::File(file)
+            }
+        };
+        Ok(Some(AccessLog { format, sink: Mutex::new(sink) }))
+    }
+
+    // A failed write is reported but doesn't fail the request.
+    fn write(&self, line: &Line) {
+        let mut text = match self.format {
+            Format::Common => line.common(),
+            Format::Json => line.json(),
+        };
+        text.push('\n');
+        let written = match &mut *self.sink.lock().unwrap() {
+            Sink::Stdout => io::stdout().lock().write_all(text.as_bytes()),
+            Sink::File(file) => file.write(text.as_bytes()),
+        };
+        if let Err(e) = written {
+            tracing::warn!("writing the access log failed: {}", e);
+        }
+    }
+}
+
+// What's known of a request before it's handled, kept until its response
+// is ready.
+pub struct Pending {
+    state: SharedState,
+    line: Line,
+    start: Instant,
+}
+
+impl Pending {
+    // None when there's no access log.
+    pub fn of(req: &Request<Body>, id: &RequestId, state: &SharedState) -> Option<Self> {
+        state.access_log.as_ref()?;
+        let client = req
+            .extensions()
+            .get::<ClientIp>()
+            .map(|ip| ip.0)
+            .or_else(|| req.extensions().get::<PeerAddr>().map(PeerAddr::ip));
+        let line = Line {
+            at: SystemTime::now(),
+            client,
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            version: format!("{:?}", req.version()),
+            status: None,
+            bytes: None,
+            latency_ms: 0.0,
+            request_id: id.as_str().to_string(),
+        };
+        Some(Pending { state: state.clone(), line, start: Instant::now() })
+    }
+}
+
+// Writes the line once the response is ready. Streamed bodies have no size
+// until they're sent, so theirs is left out.
+pub async fn logged(
+    pending: Option<Pending>,
+    response: impl Future<Output = Result<Response<Body>, body::Error>>,
+) -> Result<Response<Body>, body::Error> {
+    let result = response.await;
+    if let Some(Pending { state, mut line, start }) = pending {
+        line.latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        if let Ok(response) = &result {
+            line.status = Some(response.status().as_u16());
+            line.bytes = response.body().size_hint().exact();
+        }
+        if let Some(log) = &state.access_log {
+            log.write(&line);
+        }
+    }
+    result
+}
+
+// The query is left out, as it may carry tokens.
+#[derive(Debug)]
+struct Line {
+    at: SystemTime,
+    client: Option<IpAddr>,
+    method: String,
+    path: String,
+    version: String,
+    status: Option<u16>,
+    bytes: Option<u64>,
+    latency_ms: f64,
+    request_id: String,
+}
+
+impl Line {
+    fn common(&self) -> String {
+        let (year, month, day, hour, minute, second, _) = civil(self.at);
+        const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        let dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {} {:.3} {}",
+            dash(self.client.map(|ip| ip.to_string())),
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            hour,
+            minute,
+            second,
+            self.method,
+            self.path.replace('\\', "\\\\").replace('"', "\\\""),
+            self.version,
+            dash(self.status.map(|status| status.to_string())),
+            dash(self.bytes.map(|bytes| bytes.to_string())),
+            self.latency_ms,
+            self.request_id,
+        )
+    }
+
+    fn json(&self) -> String {
+        let (year, month, day, hour, minute, second, millis) = civil(self.at);
+        json!({
+            "time": format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, hour, minute, second, millis),
+            "client": self.client.map(|ip| ip.to_string()),
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.version,
+            "status": self.status,
+            "bytes": self.bytes,
+            "latency_ms": self.latency_ms,
+            "request_id": self.request_id,
+        })
+        .to_string()
+    }
+}
+
+// The UTC date and time of `at`, down to the millisecond.
+pub fn civil(at: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (days, time) = (secs.div_euclid(86_400), secs.rem_euclid(86_400) as u32);
+    // Howard Hinnant's civil_from_days.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, time / 3600, time / 60 % 60, time % 60, since.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn line() -> Line {
+        Line {
+            at: UNIX_EPOCH + Duration::from_millis(1_791_419_455_250),
+            client: Some("10.0.0.7".parse().unwrap()),
+            method: "GET".to_string(),
+            path: "/books/\"1\"".to_string(),
+            version: "HTTP/1.1".to_string(),
+            status: Some(200),
+            bytes: None,
+            latency_ms: 1.5,
+            request_id: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn formats_common_and_json_lines() {
+        assert_eq!(
+            line().common(),
+            r#"10.0.0.7 - - [08/Oct/2026:00:30:55 +0000] "GET /books/\"1\" HTTP/1.1" 200 - 1.500 abc"#
+        );
+        let json: serde_json::Value = serde_json::from_str(&line().json()).unwrap();
+        assert_eq!(json["time"], "2026-10-08T00:30:55.250Z");
+        assert_eq!(json["client"], "10.0.0.7");
+        assert_eq!(json["bytes"], serde_json::Value::Null);
+        assert_eq!(json["request_id"], "abc");
+    }
+
+    #[test]
+    fn rotates_past_the_size_limit() {
+        let path = std::env::temp_dir().join(format!("book-api-access-{}.log", uuid::Uuid::new_v4()));
+        let mut file = Rotating::open(path.clone(), 10, 2).unwrap();
+        for line in ["one----\n", "two----\n", "three--\n", "four---\n"] {
+            file.write(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four---\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "three--\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 2)).unwrap(), "two----\n");
+        assert!(!numbered(&path, 3).exists());
+        for n in 0..=2 {
+            let _ = fs::remove_file(if n == 0 { path.clone() } else { numbered(&path, n) });
+        }
+    }
+}
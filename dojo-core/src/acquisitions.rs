@@ -0,0 +1,249 @@
+use books_model::{CreateBookRequest, Edition};
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    events::Topic,
+    extract::{Json, Path, State},
+    json_response,
+    validate::{self, Validate, Violations},
+    SharedState,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AcquisitionStatus {
+    Pending,
+    UnderReview,
+    Approved,
+    Rejected,
+}
+
+impl AcquisitionStatus {
+    fn can_become(self, next: AcquisitionStatus) -> bool {
+        use AcquisitionStatus::*;
+        matches!(
+            (self, next),
+            (Pending, UnderReview) | (Pending | UnderReview, Approved | Rejected)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Acquisition {
+    id: u64,
+    title: String,
+    author: String,
+    isbn: Option<String>,
+    suggested_by: String,
+    voters: BTreeSet<String>,
+    status: AcquisitionStatus,
+    book_id: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AcquisitionView {
+    pub id: u64,
+    pub title: String,
+    pub author: String,
+    pub isbn: Option<String>,
+    pub suggested_by: String,
+    pub votes: usize,
+    pub status: AcquisitionStatus,
+    // The catalog entry created when the request was approved.
+    pub book_id: Option<u64>,
+}
+
+impl From<&Acquisition> for AcquisitionView {
+    fn from(request: &Acquisition) -> Self {
+        AcquisitionView {
+            id: request.id,
+            title: request.title.clone(),
+            author: request.author.clone(),
+            isbn: request.isbn.clone(),
+            suggested_by: request.suggested_by.clone(),
+            votes: request.voters.len(),
+            status: request.status,
+            book_id: request.book_id,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SuggestRequest {
+    pub title: String,
+    pub author: String,
+    pub isbn: Option<String>,
+    pub member: String,
+}
+
+impl Validate for SuggestRequest {
+    fn validate(&mut self, violations: &mut Violations) {
+        violations.text("title", &mut self.title, validate::MAX_TITLE);
+        violations.text("author", &mut self.author, validate::MAX_AUTHOR);
+        violations.isbn(&mut self.isbn);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VoteRequest {
+    pub member: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatusRequest {
+    pub status: AcquisitionStatus,
+}
+
+// The member who suggests a book counts as its first vote.
+pub async fn suggest(
+    State(state): State<SharedState>,
+    Json(mut suggestion): Json<SuggestRequest>,
+) -> Result<Response<Body>, ApiError> {
+    // Approving the request adds the book, so it's checked up front.
+    validate::check(&mut suggestion)?;
+    let params = format!("title={:?}", suggestion.title);
+    let view = state
+        .with_storage("acquisition_insert", || params, |storage| {
+            let id = storage.next_acquisition_id;
+            storage.next_acquisition_id += 1;
+            let request = Acquisition {
+                id,
+                title: suggestion.title,
+                author: suggestion.author,
+                isbn: suggestion.isbn,
+                voters: BTreeSet::from([suggestion.member.clone()]),
+                suggested_by: suggestion.member,
+                status: AcquisitionStatus::Pending,
+                book_id: None,
+            };
+            let view = AcquisitionView::from(&request);
+            storage.acquisitions.insert(id, request);
+            storage.emit(Topic::Acquisitions, "acquisition.suggested", id, &view);
+            view
+        })
+        .await?;
+    Ok(json_response(StatusCode::CREATED, &view)?)
+}
+
+// Most-voted first, oldest first among ties.
+pub async fn list(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let views = state
+        .read_storage("acquisition_list", String::new, |storage| {
+            let mut views: Vec<AcquisitionView> = storage.acquisitions.values().map(AcquisitionView::from).collect();
+            views.sort_by(|a, b| b.votes.cmp(&a.votes).then(a.id.cmp(&b.id)));
+            views
+        })
+        .await?;
+    Ok(json_response(StatusCode::OK, &views)?)
+}
+
+pub async fn get(Path(id): Path<u64>, State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let view = state
+        .read_storage("acquisition_get", || format!("id={}", id), |storage| {
+            storage.acquisitions.get(&id).map(AcquisitionView::from)
+        })
+        .await?
+        .ok_or_else(ApiError::not_found)?;
+    Ok(json_response(StatusCode::OK, &view)?)
+}
+
+// Voting twice is a no-op; votes close once a librarian decides.
+pub async fn vote(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+    Json(vote): Json<VoteRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let view = state
+        .with_storage("acquisition_vote", || format!("id={}", id), |storage| {
+            let request = storage.acquisitions.get_mut(&id)?;
+            if matches!(request.status, AcquisitionStatus::Approved | AcquisitionStatus::Rejected) {
+                return Some(Err(ApiError::Conflict(format!("acquisition request {} is already decided", id))));
+            }
+            request.voters.insert(vote.member);
+            Some(Ok(AcquisitionView::from(&*request)))
+        })
+        .await?
+        .ok_or_else(ApiError::not_found)??;
+    Ok(json_response(StatusCode::OK, &view)?)
+}
+
+// Librarian triage. Approving adds the suggested book to the catalog.
+pub async fn set_status(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+    Json(change): Json<StatusRequest>,
+) -> Result<Response<Body>, ApiError> {
+    // A replicated catalog only takes new books through the raft log, so
+    // the approval is recorded first and the book added once it commits.
+    let replicated = state.raft.is_some();
+    let params = format!("id={} status={:?}", id, change.status);
+    let result = state
+        .with_storage("acquisition_status", || params, |storage| {
+            let request = storage.acquisitions.get(&id)?;
+            if !request.status.can_become(change.status) {
+                return Some(Err(ApiError::Conflict(format!(
+                    "acquisition request {} cannot move from {:?} to {:?}",
+                    id, request.status, change.status
+                ))));
+            }
+
+            let previous = request.status;
+            let book = CreateBookRequest {
+                title: request.title.clone(),
+                author: request.author.clone(),
+                isbn: request.isbn.clone(),
+                author_id: None,
+                tags: Vec::new(),
+                edition: Edition::default(),
+                uid: state.ids.mint(),
+            };
+            let approved = change.status == AcquisitionStatus::Approved;
+            if approved && !replicated {
+                if let Err(duplicate) = storage.check_isbn(book.isbn.as_deref(), 0) {
+                    return Some(Err(ApiError::Conflict(duplicate.to_string())));
+                }
+            }
+            let book_id = (approved && !replicated).then(|| storage.insert_book(book.clone()).id);
+
+            let request = storage.acquisitions.get_mut(&id)?;
+            request.status = change.status;
+            request.book_id = request.book_id.or(book_id);
+            let pending = (approved && request.book_id.is_none()).then_some(book);
+            let view = AcquisitionView::from(&*request);
+            // A replicated approval is announced once its book exists.
+            if pending.is_none() {
+                storage.emit(Topic::Acquisitions, "acquisition.status_changed", id, &view);
+            }
+            Some(Ok((view, previous, pending)))
+        })
+        .await?;
+    let (view, previous, pending) = result.ok_or_else(ApiError::not_found)??;
+    let (Some(book), Some(raft)) = (pending, &state.raft) else {
+        return Ok(json_response(StatusCode::OK, &view)?);
+    };
+
+    let created = raft.create(book).await;
+    let result = state
+        .with_storage("acquisition_book", || format!("id={}", id), |storage| {
+            let request = storage.acquisitions.get_mut(&id)?;
+            match &created {
+                Ok(book) => request.book_id = Some(book.id),
+                Err(_) => request.status = previous,
+            }
+            let view = AcquisitionView::from(&*request);
+            if created.is_ok() {
+                storage.emit(Topic::Acquisitions, "acquisition.status_changed", id, &view);
+            }
+            Some(view)
+        })
+        .await;
+    if let Err(rejection) = created {
+        return Ok(rejection);
+    }
+    let view = result?.ok_or_else(ApiError::not_found)?;
+    Ok(json_response(StatusCode::OK, &view)?)
+}
@@ -0,0 +1,339 @@
+use hyper::{body::Bytes, header, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{self, Write as _};
+use tokio::runtime::Handle;
+
+use crate::{
+    body::{self, Body, Sender},
+    check, compact,
+    error::ApiError,
+    extract::{Json, Path, Query, State},
+    federation, isbn, json_response, problem,
+    snapshot::{self, Image},
+    sqlite::Write,
+    tasks::{DeadJob, DeadJobSummary, Failure, Job},
+    webhooks, Book, SharedState,
+};
+
+const MIGRATION_BATCH_SIZE: usize = 100;
+// How much of a backup is written before it's sent on.
+const BACKUP_CHUNK: usize = 64 * 1024;
+
+pub async fn start_migration(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let ids = state
+        .with_storage("list_ids", String::new, |storage| {
+            storage.books.keys().copied().collect::<Vec<u64>>()
+        })
+        .await?;
+    let task = state.tasks.lock().await.start("migrate-data", ids.len());
+
+    tokio::spawn(run_migration(task.id, ids, state, Vec::new()));
+    Ok(json_response(StatusCode::ACCEPTED, &task)?)
+}
+
+pub async fn get_task(Path(id): Path<u64>, State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let tasks = state.tasks.lock().await;
+    Ok(json_response(StatusCode::OK, tasks.get(id).ok_or_else(ApiError::not_found)?)?)
+}
+
+#[derive(Deserialize)]
+pub struct CheckParams {
+    #[serde(default)]
+    fix: bool,
+}
+
+pub async fn run_check(
+    Query(params): Query<CheckParams>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let fix = params.fix;
+    let report = state
+        .with_storage("check", || format!("fix={}", fix), |storage| check::check(storage, fix))
+        .await?;
+    Ok(json_response(StatusCode::OK, &report)?)
+}
+
+pub async fn run_compaction(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let report = state.with_storage("compact", String::new, compact::compact).await?;
+    Ok(json_response(StatusCode::OK, &report)?)
+}
+
+// POST /admin/backup streams everything this instance's storage holds, as
+// the snapshot file would have it, for /admin/restore on another instance.
+// It's taken at one point in time, under the storage lock; serializing and
+// sending it happen after, a chunk at a time.
+pub async fn backup(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    if state.postgres.is_some() || state.redis.is_some() {
+        return Ok(problem::respond(
+            StatusCode::NOT_IMPLEMENTED,
+            "backups aren't supported with DATABASE_URL or DOJO_STORAGE=redis yet",
+        ));
+    }
+    let image = state.read_storage("backup", String::new, Image::capture).await?;
+    let (sender, body) = Body::channel();
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let mut chunks = Chunks {
+            sender,
+            handle,
+            buf: Vec::with_capacity(BACKUP_CHUNK),
+        };
+        let written = serde_json::to_writer(&mut chunks, &image).map_err(io::Error::from).and_then(|()| chunks.flush());
+        if let Err(e) = written {
+            tracing::warn!("sending a backup failed: {}", e);
+            chunks.sender.abort();
+        }
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_DISPOSITION, "attachment; filename=\"backup.json\"")
+        .body(body)
+        .unwrap())
+}
+
+// Hands what's written to the response body in BACKUP_CHUNK pieces, from
+// a blocking thread.
+struct Chunks {
+    sender: Sender,
+    handle: Handle,
+    buf: Vec<u8>,
+}
+
+impl io::Write for Chunks {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= BACKUP_CHUNK {
+            self.flush()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(&mut self.buf, Vec::with_capacity(BACKUP_CHUNK)));
+        self.handle
+            .block_on(self.sender.send_data(chunk))
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }
+}
+
+#[derive(Serialize)]
+pub struct RestoreReport {
+    pub books: usize,
+    pub authors: usize,
+    pub next_id: u64,
+}
+
+// POST /admin/restore replaces everything the storage holds with a backup,
+// in one update, so requests see either the old catalog or the new one.
+// Only storage kept in memory can be swapped like that; the snapshot file,
+// if there is one, is rewritten straight after.
+pub async fn restore(
+    State(state): State<SharedState>,
+    Json(mut image): Json<Value>,
+) -> Result<Response<Body>, ApiError> {
+    let elsewhere = state.postgres.is_some()
+        || state.redis.is_some()
+        || state.cluster.is_some()
+        || state.raft.is_some()
+        || state.shards.is_some();
+    let journaled = {
+        let storage = state.storage.read().await;
+        storage.disk.is_some() || storage.wal.is_some() || storage.history.is_some()
+    };
+    if elsewhere || journaled {
+        return Ok(problem::respond(
+            StatusCode::NOT_IMPLEMENTED,
+            "restoring a backup isn't supported with DOJO_STORAGE, DATABASE_URL, DOJO_WAL_FILE, DOJO_CLUSTER_NODE, DOJO_RAFT_NODE or DOJO_SHARD_NODE yet",
+        ));
+    }
+    Image::upgrade(&mut image)
+        .map_err(|e| ApiError::invalid(StatusCode::UNPROCESSABLE_ENTITY, "format", format!("is {}", e)))?;
+    // Read like any other body, once it's in the current format.
+    let image: Image = serde_path_to_error::deserialize(image).map_err(|e| match e.path().iter().next() {
+        Some(_) => ApiError::invalid(StatusCode::BAD_REQUEST, e.path().to_string(), e.inner().to_string()),
+        None => ApiError::bad_request("Invalid request body"),
+    })?;
+    let report = state
+        .with_storage("restore", String::new, |storage| {
+            image.apply(storage);
+            RestoreReport {
+                books: storage.books.len(),
+                authors: storage.authors.len(),
+                next_id: storage.next_id,
+            }
+        })
+        .await?;
+    tracing::info!("restored {} books and {} authors from a backup", report.books, report.authors);
+    if let Err(e) = snapshot::save(&state).await {
+        tracing::error!("writing the snapshot after a restore failed: {}", e);
+    }
+    Ok(json_response(StatusCode::OK, &report)?)
+}
+
+pub async fn storage_metrics(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    json_response(StatusCode::OK, &state.storage_metrics.snapshot())
+}
+
+// Books are migrated in small batches, releasing the storage lock between
+// them so regular requests keep being served while the task runs. A failed
+// migration is dead-lettered with the books it didn't reach.
+async fn run_migration(task_id: u64, ids: Vec<u64>, state: SharedState, mut failures: Vec<Failure>) {
+    for (index, batch) in ids.chunks(MIGRATION_BATCH_SIZE).enumerate() {
+        let result = state
+            .with_storage("migrate_batch", || format!("task={} size={}", task_id, batch.len()), |storage| {
+                let mut changed = 0;
+                for id in batch {
+                    if let Some(book) = storage.books.get_mut(id) {
+                        if normalize_book(book) {
+                            let book = book.clone();
+                            storage.index.insert(&book);
+                            storage.modified.touch(book.id);
+                            storage.record(Write::Updated(book));
+                            changed += 1;
+                        }
+                    }
+                }
+                changed
+            })
+            .await;
+        let changed = match result {
+            Ok(changed) => changed,
+            Err(e) => {
+                let mut tasks = state.tasks.lock().await;
+                tasks.fail(task_id, e.to_string());
+                failures.push(Failure::now(format!("task {}: {}", task_id, e)));
+                let remaining = ids[index * MIGRATION_BATCH_SIZE..].to_vec();
+                tasks.bury(Job::MigrateData { ids: remaining }, failures);
+                return;
+            }
+        };
+
+        state.tasks.lock().await.record_progress(task_id, batch.len(), changed);
+        tokio::task::yield_now().await;
+    }
+
+    state.tasks.lock().await.complete(task_id);
+}
+
+#[derive(Deserialize)]
+pub struct DeadJobFilter {
+    kind: Option<String>,
+}
+
+impl DeadJobFilter {
+    fn matches(&self, dead: &DeadJob) -> bool {
+        self.kind.as_deref().is_none_or(|kind| kind == dead.job.kind())
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeadJobIds {
+    pub ids: Vec<u64>,
+}
+
+pub async fn list_dead_jobs(
+    Query(filter): Query<DeadJobFilter>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, body::Error> {
+    let tasks = state.tasks.lock().await;
+    let dead: Vec<DeadJobSummary> = tasks.dead().filter(|dead| filter.matches(dead)).map(DeadJobSummary::from).collect();
+    json_response(StatusCode::OK, &dead)
+}
+
+pub async fn get_dead_job(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let tasks = state.tasks.lock().await;
+    Ok(json_response(StatusCode::OK, tasks.dead_job(id).ok_or_else(ApiError::not_found)?)?)
+}
+
+// The job runs again with its failure history, so failing once more puts
+// it back with one more entry.
+fn resubmit(state: &SharedState, dead: DeadJob, tasks: &mut crate::tasks::TaskRegistry) {
+    let DeadJob { job, failures, .. } = dead;
+    match job {
+        Job::MigrateData { ids } => {
+            let task = tasks.start("migrate-data", ids.len());
+            tokio::spawn(run_migration(task.id, ids, state.clone(), failures));
+        }
+        Job::PushLoanStatus { peer, remote_id, status } => {
+            tokio::spawn(federation::push_status(state.clone(), peer, remote_id, status, failures));
+        }
+        Job::DeliverWebhook { webhook, event } => {
+            tokio::spawn(webhooks::deliver(state.clone(), webhook, event, failures));
+        }
+    }
+}
+
+pub async fn retry_dead_job(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let mut tasks = state.tasks.lock().await;
+    let dead = tasks.exhume(id).ok_or_else(ApiError::not_found)?;
+    let response = json_response(StatusCode::ACCEPTED, &dead)?;
+    resubmit(&state, dead, &mut tasks);
+    Ok(response)
+}
+
+pub async fn discard_dead_job(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    state.tasks.lock().await.exhume(id).ok_or_else(ApiError::not_found)?;
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap())
+}
+
+fn exhume_matching(tasks: &mut crate::tasks::TaskRegistry, filter: &DeadJobFilter) -> Vec<DeadJob> {
+    let ids: Vec<u64> = tasks.dead().filter(|dead| filter.matches(dead)).map(|dead| dead.id).collect();
+    ids.into_iter().filter_map(|id| tasks.exhume(id)).collect()
+}
+
+pub async fn retry_dead_jobs(
+    Query(filter): Query<DeadJobFilter>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, body::Error> {
+    let mut tasks = state.tasks.lock().await;
+    let mut ids = Vec::new();
+    for dead in exhume_matching(&mut tasks, &filter) {
+        ids.push(dead.id);
+        resubmit(&state, dead, &mut tasks);
+    }
+    json_response(StatusCode::ACCEPTED, &DeadJobIds { ids })
+}
+
+pub async fn discard_dead_jobs(
+    Query(filter): Query<DeadJobFilter>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, body::Error> {
+    let mut tasks = state.tasks.lock().await;
+    let ids = exhume_matching(&mut tasks, &filter).iter().map(|dead| dead.id).collect();
+    json_response(StatusCode::OK, &DeadJobIds { ids })
+}
+
+fn normalize_book(book: &mut Book) -> bool {
+    let title = book.title.trim().to_string();
+    let author = book.author.trim().to_string();
+    let isbn = book.isbn.as_deref().map(isbn::strip).filter(|isbn| !isbn.is_empty());
+    let publisher = book.edition.publisher.as_deref().map(str::trim).filter(|publisher| !publisher.is_empty());
+    let publisher = publisher.map(str::to_string);
+    let language = book.edition.language.as_deref().map(|language| language.trim().to_lowercase());
+
+    let changed = title != book.title
+        || author != book.author
+        || isbn != book.isbn
+        || publisher != book.edition.publisher
+        || language != book.edition.language;
+    book.title = title;
+    book.author = author;
+    book.isbn = isbn;
+    book.edition.publisher = publisher;
+    book.edition.language = language;
+    changed
+}
@@ -0,0 +1,468 @@
+use books_model::Book;
+use hyper::{header::HeaderValue, HeaderMap, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{Read, Write as _},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::sync::watch;
+
+use crate::{
+    auth,
+    body::{self, Body},
+    conditional::now_ms,
+    error::ApiError,
+    extract::{Path, Query, State},
+    json_response,
+    links::Links,
+    listing::{self, decode_cursor, encode_cursor},
+    problem,
+    request_id::RequestId,
+    store::{BookStore, Store},
+    SharedState,
+};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+const MAX_POLL_SECS: u64 = 300;
+
+// Who made a change: the principal `auth::principal` names, or None when
+// anonymous, and the request it came in on. Background work, such as a
+// task or a change replicated from a peer, has neither.
+#[derive(Debug, Clone, Default)]
+pub struct Actor {
+    principal: Option<String>,
+    request_id: Option<String>,
+}
+
+tokio::task_local! {
+    static ACTOR: Actor;
+}
+
+impl Actor {
+    pub fn new(principal: Option<String>, request_id: Option<String>) -> Self {
+        Actor { principal, request_id }
+    }
+
+    // Reads don't change anything, so only writes look up their principal.
+    pub fn of(req: &Request<Body>, state: &SharedState) -> Self {
+        let read = matches!(*req.method(), hyper::Method::GET | hyper::Method::HEAD | hyper::Method::OPTIONS);
+        Actor {
+            principal: (!read).then(|| auth::principal(req.headers(), state)).flatten(),
+            request_id: req.extensions().get::<RequestId>().map(|id| id.as_str().to_string()),
+        }
+    }
+
+    fn current() -> Self {
+        ACTOR.try_with(Actor::clone).unwrap_or_default()
+    }
+}
+
+// Runs `work` with every change it makes recorded as `actor`'s.
+pub fn acting<F: Future>(actor: Actor, work: F) -> impl Future<Output = F::Output> {
+    ACTOR.scope(actor, work)
+}
+
+// One change to a book. A create has no `before`, a delete no `after`;
+// restoring a book from the trash counts as creating it again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    pub at: u64,
+    pub action: String,
+    pub book_id: u64,
+    pub principal: Option<String>,
+    pub request_id: Option<String>,
+    pub before: Option<Book>,
+    pub after: Option<Book>,
+}
+
+// Every change to the catalog, oldest first. Entries are only ever
+// appended. DOJO_AUDIT_FILE keeps them across restarts as one JSON entry
+// per line; without it the log starts empty each time.
+//
+// Each instance keeps its own log, so with Postgres or Redis shared by
+// several, every instance records the changes made through it.
+//
+// As every entry has the book before and after, the catalog at an earlier
+// time is the current one with the later entries undone, back to when the
+// log began. A log made with `default` began with an empty catalog.
+#[derive(Default)]
+pub struct Audit {
+    inner: Mutex<Inner>,
+    // The number of the latest entry, for requests waiting on the next.
+    latest: watch::Sender<u64>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: Vec<Entry>,
+    file: Option<(PathBuf, File)>,
+    // Milliseconds since the epoch from which every change is logged.
+    since: u64,
+}
+
+impl Audit {
+    pub fn from_env() -> Result<Self, String> {
+        let path = match std::env::var("DOJO_AUDIT_FILE") {
+            Ok(path) if !path.is_empty() => PathBuf::from(path),
+            _ => {
+                return Ok(Audit {
+                    inner: Mutex::new(Inner { since: now_ms(), ..Default::default() }),
+                    latest: Default::default(),
+                })
+            }
+        };
+        let describe = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| describe(&e))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).map_err(|e| describe(&e))?;
+        // As in the write-ahead log, a line without its newline was cut
+        // short by a crash.
+        let complete = contents.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+        if complete < contents.len() {
+            tracing::warn!("dropping an unfinished entry at the end of {}", path.display());
+            file.set_len(complete as u64).map_err(|e| describe(&e))?;
+        }
+        let mut entries = Vec::new();
+        for (number, line) in contents[..complete].split(|b| *b == b'\n').enumerate() {
+            if !line.is_empty() {
+                entries.push(serde_json::from_slice(line).map_err(|e| describe(&format!("line {}: {}", number + 1, e)))?);
+            }
+        }
+        // The catalog may have been changed before the file was first
+        // written to, but not in between.
+        let since = entries.first().map_or_else(now_ms, |entry: &Entry| entry.at);
+        let latest = watch::Sender::new(entries.last().map_or(0, |entry: &Entry| entry.seq));
+        Ok(Audit {
+            inner: Mutex::new(Inner {
+                entries,
+                file: Some((path, file)),
+                since,
+            }),
+            latest,
+        })
+    }
+
+    // An entry for the current actor, numbered once it's appended.
+    pub fn entry(&self, action: &str, book_id: u64, before: Option<Book>, after: Option<Book>) -> Entry {
+        let actor = Actor::current();
+        Entry {
+            seq: 0,
+            at: now_ms(),
+            action: action.to_string(),
+            book_id,
+            principal: actor.principal,
+            request_id: actor.request_id,
+            before,
+            after,
+        }
+    }
+
+    // For changes that are already stored. A log that can't be written is
+    // reported but doesn't fail them.
+    pub fn append(&self, entries: Vec<Entry>) {
+        if entries.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let mut seq = inner.entries.last().map_or(0, |entry| entry.seq);
+        let mut lines = Vec::new();
+        for mut entry in entries {
+            seq += 1;
+            entry.seq = seq;
+            if inner.file.is_some() {
+                if let Ok(line) = serde_json::to_vec(&entry) {
+                    lines.extend(line);
+                    lines.push(b'\n');
+                }
+            }
+            inner.entries.push(entry);
+        }
+        self.latest.send_replace(seq);
+        if let Some((path, file)) = &mut inner.file {
+            if let Err(e) = file.write_all(&lines).and_then(|()| file.sync_data()) {
+                tracing::error!("writing the audit log to {} failed: {}", path.display(), e);
+            }
+        }
+    }
+
+    pub fn record(&self, action: &str, book_id: u64, before: Option<Book>, after: Option<Book>) {
+        self.append(vec![self.entry(action, book_id, before, after)]);
+    }
+
+    // Up to `limit` entries after `after` that `matches` picks, and whether
+    // more follow.
+    fn page(&self, after: u64, limit: usize, matches: impl Fn(&Entry) -> bool) -> (Vec<Entry>, bool) {
+        let inner = self.inner.lock().unwrap();
+        let start = inner.entries.partition_point(|entry| entry.seq <= after);
+        let mut found = inner.entries[start..].iter().filter(|entry| matches(entry)).take(limit + 1).cloned();
+        let page: Vec<Entry> = found.by_ref().take(limit).collect();
+        let more = found.next().is_some();
+        (page, more)
+    }
+
+    // The catalog as it was at `at`, in milliseconds since the epoch, from
+    // the books in it now. Changes that raced the read of `books` are
+    // undone to the same state, as each entry carries the whole book.
+    pub fn as_of(&self, at: u64, books: Vec<Book>) -> Result<Vec<Book>, String> {
+        let inner = self.inner.lock().unwrap();
+        if at < inner.since {
+            return Err(format!("as_of can't go back before {}, when the audit log began", inner.since));
+        }
+        let mut books: BTreeMap<u64, Book> = books.into_iter().map(|book| (book.id, book)).collect();
+        for entry in inner.entries.iter().rev().filter(|entry| entry.at > at) {
+            match &entry.before {
+                Some(before) => books.insert(entry.book_id, before.clone()),
+                None => books.remove(&entry.book_id),
+            };
+        }
+        Ok(books.into_values().collect())
+    }
+
+    // The number of the latest entry, 0 while there are none.
+    fn latest(&self) -> u64 {
+        *self.latest.borrow()
+    }
+
+    // Up to `limit` changes after `since`, as the feed shows them.
+    fn feed(&self, since: u64, limit: usize) -> Feed {
+        let (entries, more) = self.page(since, limit, |_| true);
+        let next = entries.last().map_or(since, |entry| entry.seq);
+        let changes = entries
+            .into_iter()
+            .map(|entry| Change { seq: entry.seq, at: entry.at, action: entry.action, book_id: entry.book_id, book: entry.after })
+            .collect();
+        Feed { changes, next, more }
+    }
+
+    pub fn approx_bytes(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let strings = |entry: &Entry| {
+            entry.action.capacity()
+                + entry.principal.as_ref().map_or(0, String::capacity)
+                + entry.request_id.as_ref().map_or(0, String::capacity)
+        };
+        inner.entries.capacity() * std::mem::size_of::<Entry>() + inner.entries.iter().map(strings).sum::<usize>()
+    }
+}
+
+// ?limit= entries at a time, 100 unless asked, and ?cursor= from the
+// previous page's X-Next-Cursor. Reviews page the same way.
+#[derive(Debug, Deserialize)]
+pub struct Page {
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+impl Page {
+    pub fn bounds(&self) -> Result<(u64, usize), String> {
+        let after = match &self.cursor {
+            Some(cursor) => decode_cursor(cursor).ok_or("Invalid cursor")?,
+            None => 0,
+        };
+        match self.limit.unwrap_or(DEFAULT_LIMIT) {
+            limit @ 1..=MAX_LIMIT => Ok((after, limit)),
+            limit => Err(format!("limit must be between 1 and {}, got {}", MAX_LIMIT, limit)),
+        }
+    }
+}
+
+// ?book_id=, ?principal= and ?action= narrow the log down.
+#[derive(Debug, Deserialize)]
+pub struct Filter {
+    book_id: Option<u64>,
+    principal: Option<String>,
+    action: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.book_id.is_none_or(|id| entry.book_id == id)
+            && self.principal.as_ref().is_none_or(|principal| entry.principal.as_ref() == Some(principal))
+            && self.action.as_ref().is_none_or(|action| &entry.action == action)
+    }
+}
+
+// The page, with a link to the next one after the last item's `key` when
+// `more` follow.
+pub fn respond<T: Serialize>(
+    links: &Links,
+    page: &Page,
+    items: Vec<T>,
+    more: bool,
+    key: impl Fn(&T) -> u64,
+) -> Result<Response<Body>, body::Error> {
+    let mut response = json_response(StatusCode::OK, &items)?;
+    let (Some(last), true) = (items.last(), more) else {
+        return Ok(response);
+    };
+    let cursor = encode_cursor(key(last));
+    let uri = links.uri();
+    let limit = page.limit.unwrap_or(DEFAULT_LIMIT).to_string();
+    let query = listing::with_page(uri, &[("cursor", cursor.clone()), ("limit", limit)]);
+    let headers: &mut HeaderMap = response.headers_mut();
+    if let (Ok(cursor), Ok(link)) =
+        (HeaderValue::from_str(&cursor), HeaderValue::from_str(&format!("<{}?{}>; rel=\"next\"", uri.path(), query)))
+    {
+        headers.insert(listing::NEXT_CURSOR, cursor);
+        headers.insert(hyper::header::LINK, link);
+    }
+    Ok(response)
+}
+
+// The log and a book's history are for admins, as entries name who made
+// each change.
+pub async fn list(
+    Query(page): Query<Page>,
+    Query(filter): Query<Filter>,
+    links: Links,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let (after, limit) = page.bounds().map_err(ApiError::BadRequest)?;
+    let (entries, more) = state.audit().page(after, limit, |entry| filter.matches(entry));
+    Ok(respond(&links, &page, entries, more, |entry| entry.seq)?)
+}
+
+// A book's changes, oldest first. A book that was never changed through
+// this instance has an empty history if it exists.
+pub async fn history<S: BookStore>(
+    Path(id): Path<u64>,
+    Query(page): Query<Page>,
+    links: Links,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    let (after, limit) = page.bounds().map_err(ApiError::BadRequest)?;
+    let (entries, more) = state.audit().page(after, limit, |entry| entry.book_id == id);
+    if entries.is_empty() && after == 0 {
+        store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    }
+    Ok(respond(&links, &page, entries, more, |entry| entry.seq)?)
+}
+
+// A change as the feed shows it, with the book as it is after, or null
+// once it's deleted.
+#[derive(Serialize)]
+struct Change {
+    seq: u64,
+    at: u64,
+    action: String,
+    book_id: u64,
+    book: Option<Book>,
+}
+
+#[derive(Serialize)]
+struct Feed {
+    changes: Vec<Change>,
+    // What to pass as ?since= next time.
+    next: u64,
+    more: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Since {
+    since: Option<u64>,
+    limit: Option<usize>,
+}
+
+impl Since {
+    fn limit(&self) -> Result<usize, ApiError> {
+        match self.limit.unwrap_or(DEFAULT_LIMIT) {
+            limit @ 1..=MAX_LIMIT => Ok(limit),
+            limit => Err(ApiError::BadRequest(format!("limit must be between 1 and {}, got {}", MAX_LIMIT, limit))),
+        }
+    }
+}
+
+// The catalog's changes after ?since=, oldest first and numbered by the
+// audit log, for clients that keep a copy in sync. Without ?since= only
+// `next` comes back: take it, read the collection, then follow from it;
+// applying a change the copy already has is harmless. A ?since= past the
+// latest change means the log restarted, as it does with the server
+// without DOJO_AUDIT_FILE, and the copy has to be read again.
+pub async fn changes(Query(feed): Query<Since>, State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    if let Some(response) = unsupported(&state) {
+        return Ok(response);
+    }
+    let limit = feed.limit()?;
+    let audit = state.audit();
+    let latest = audit.latest();
+    let Some(since) = feed.since else {
+        return Ok(json_response(StatusCode::OK, &Feed { changes: Vec::new(), next: latest, more: false })?);
+    };
+    if since > latest {
+        return Ok(gone(since, latest));
+    }
+    Ok(json_response(StatusCode::OK, &audit.feed(since, limit))?)
+}
+
+// ?timeout= seconds to wait for a change, 30 unless asked.
+#[derive(Debug, Deserialize)]
+pub struct Wait {
+    timeout: Option<u64>,
+}
+
+// GET /books/poll: the change feed for clients that can't hold a stream
+// open. It answers as soon as there are changes after ?since=, which
+// defaults to the latest, or with none once ?timeout= runs out. The wait
+// ends a second before the request timeout would, and at shutdown.
+pub async fn poll(
+    Query(feed): Query<Since>,
+    Query(wait): Query<Wait>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    if let Some(response) = unsupported(&state) {
+        return Ok(response);
+    }
+    let limit = feed.limit()?;
+    let mut timeout = match wait.timeout.unwrap_or(30) {
+        secs @ 0..=MAX_POLL_SECS => Duration::from_secs(secs),
+        secs => return Err(ApiError::BadRequest(format!("timeout must be at most {} seconds, got {}", MAX_POLL_SECS, secs))),
+    };
+    if let Some(limit) = state.request_timeout {
+        timeout = timeout.min(limit.saturating_sub(Duration::from_secs(1)));
+    }
+    let audit = state.audit();
+    let mut latest = audit.latest.subscribe();
+    let since = feed.since.unwrap_or(*latest.borrow());
+    if since > *latest.borrow() {
+        return Ok(gone(since, *latest.borrow()));
+    }
+    let mut closed = state.live().closing();
+    let changed = async {
+        tokio::select! {
+            _ = latest.wait_for(|latest| *latest > since) => {}
+            _ = closed.wait_for(|closed| *closed) => {}
+        }
+    };
+    let _ = tokio::time::timeout(timeout, changed).await;
+    Ok(json_response(StatusCode::OK, &audit.feed(since, limit))?)
+}
+
+// Other instances' changes aren't in this one's log.
+fn unsupported(state: &SharedState) -> Option<Response<Body>> {
+    (state.postgres.is_some() || state.redis.is_some()).then(|| {
+        problem::respond(
+            StatusCode::NOT_IMPLEMENTED,
+            "the change feed isn't supported with DATABASE_URL or DOJO_STORAGE=redis yet",
+        )
+    })
+}
+
+fn gone(since: u64, latest: u64) -> Response<Body> {
+    problem::respond(
+        StatusCode::GONE,
+        format!("since {} is past the latest change, {}; read the collection again", since, latest),
+    )
+}
@@ -0,0 +1,395 @@
+use hyper::{header, header::HeaderMap, Method, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    body::Body,
+    jwt::{Claims, Role},
+    not_found, problem, SharedState,
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+struct ApiKey {
+    name: String,
+    digest: [u8; 32],
+    access: Access,
+}
+
+// Keys for the catalog API, sent in X-Api-Key. DOJO_API_KEYS lists
+// `name=key` pairs that may write and DOJO_API_READ_KEYS pairs that may
+// only read. Once any key is configured, writes need one; reads stay open
+// unless DOJO_API_KEY_READS=required.
+#[derive(Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+    reads_need_key: bool,
+}
+
+impl ApiKeys {
+    pub fn from_env() -> Result<Self, String> {
+        let mut keys = Vec::new();
+        for (var, access) in [("DOJO_API_KEYS", Access::Write), ("DOJO_API_READ_KEYS", Access::Read)] {
+            let Ok(list) = std::env::var(var) else {
+                continue;
+            };
+            for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+                let (name, key) = entry
+                    .split_once('=')
+                    .filter(|(name, key)| !name.is_empty() && !key.is_empty())
+                    .ok_or_else(|| format!("invalid {} entry {:?}, expected name=key", var, entry))?;
+                keys.push(ApiKey {
+                    name: name.to_string(),
+                    digest: Sha256::digest(key.as_bytes()).into(),
+                    access,
+                });
+            }
+        }
+        let reads_need_key = match std::env::var("DOJO_API_KEY_READS").as_deref() {
+            Err(_) | Ok("open") => false,
+            Ok("required") => true,
+            Ok(other) => return Err(format!("DOJO_API_KEY_READS must be open or required, got {:?}", other)),
+        };
+        if reads_need_key && keys.is_empty() {
+            return Err("DOJO_API_KEY_READS=required needs DOJO_API_KEYS or DOJO_API_READ_KEYS".to_string());
+        }
+        Ok(ApiKeys { keys, reads_need_key })
+    }
+
+    // Keys are compared as digests, which have the same length whatever
+    // was sent, and every key is compared, so the time taken says nothing
+    // about how close a guess came.
+    fn find(&self, headers: &HeaderMap) -> Option<&ApiKey> {
+        let digest = Sha256::digest(headers.get(API_KEY_HEADER)?.as_bytes());
+        let mut found = None;
+        for key in &self.keys {
+            if bool::from(key.digest.ct_eq(digest.as_slice())) {
+                found = Some(key);
+            }
+        }
+        found
+    }
+
+    // The name of the configured key a request carries, if it carries one.
+    pub fn name(&self, headers: &HeaderMap) -> Option<&str> {
+        self.find(headers).map(|key| key.name.as_str())
+    }
+}
+
+enum Credential<'a> {
+    Key(&'a ApiKey),
+    Token(Claims),
+}
+
+impl Credential<'_> {
+    fn access(&self) -> Access {
+        match self {
+            Credential::Key(key) => key.access,
+            Credential::Token(claims) if claims.role >= Role::Editor => Access::Write,
+            Credential::Token(_) => Access::Read,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Credential::Key(key) => format!("API key {:?}", key.name),
+            Credential::Token(claims) => format!("role {}", claims.role.as_str()),
+        }
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// Secrets are kept and compared as digests, like API keys.
+pub fn digest(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+fn matches(token: &str, digest: &[u8; 32]) -> bool {
+    bool::from(Sha256::digest(token.as_bytes()).ct_eq(digest))
+}
+
+// Whether the bearer token is `secret`.
+pub fn bearer_matches(headers: &HeaderMap, secret: &str) -> bool {
+    bearer(headers).is_some_and(|token| matches(token, &digest(secret)))
+}
+
+fn unauthorized(message: impl Into<String>, challenge: &'static str) -> Response<Body> {
+    let mut response = problem::respond(StatusCode::UNAUTHORIZED, message);
+    response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static(challenge));
+    response
+}
+
+// A bearer token other than the admin token is taken for a JWT when JWTs
+// are configured. Ok(None) when the request carries no credential, and
+// the 401's detail and challenge when it carries an invalid one.
+type Invalid = (String, &'static str);
+
+fn credential<'a>(headers: &HeaderMap, state: &'a SharedState) -> Result<Option<Credential<'a>>, Invalid> {
+    if let (Some(jwt), Some(token)) = (&state.jwt, bearer(headers)) {
+        if !state.admin_token.as_ref().is_some_and(|admin| matches(token, admin)) {
+            return match jwt.verify(token) {
+                Ok(claims) => Ok(Some(Credential::Token(claims))),
+                Err(message) => Err((message, r#"Bearer error="invalid_token""#)),
+            };
+        }
+    }
+    match state.api_keys.find(headers) {
+        Some(key) => Ok(Some(Credential::Key(key))),
+        None if headers.contains_key(API_KEY_HEADER) => Err(("Invalid API key".to_string(), "ApiKey")),
+        None => Ok(None),
+    }
+}
+
+// Why a request may not use the catalog: no valid credential where one is
+// needed, with the detail and the challenge to send, or a reader's
+// credential on a write.
+pub enum Refusal {
+    Unauthenticated(String, &'static str),
+    Forbidden(String),
+}
+
+// Who may use the catalog. It's open until API keys or JWTs are
+// configured; then writes need a write key or an editor's or admin's
+// token, and reads need a credential if either says so.
+pub fn check(write: bool, headers: &HeaderMap, state: &SharedState) -> Result<(), Refusal> {
+    let keys = &state.api_keys;
+    let jwt = state.jwt.as_ref();
+    if keys.keys.is_empty() && jwt.is_none() {
+        return Ok(());
+    }
+    let reads_need_credential = keys.reads_need_key || jwt.is_some_and(|jwt| jwt.reads_need_token);
+    let credential = match credential(headers, state) {
+        Ok(credential) => credential,
+        Err((message, challenge)) => return Err(Refusal::Unauthenticated(message, challenge)),
+    };
+    if !write && !reads_need_credential {
+        return Ok(());
+    }
+    let Some(credential) = credential else {
+        return Err(match (keys.keys.is_empty(), jwt.is_none()) {
+            (false, true) => Refusal::Unauthenticated("An API key is required in X-Api-Key".to_string(), "ApiKey"),
+            (true, false) => Refusal::Unauthenticated("A bearer token is required".to_string(), "Bearer"),
+            _ => Refusal::Unauthenticated(
+                "An API key in X-Api-Key or a bearer token is required".to_string(),
+                "Bearer, ApiKey",
+            ),
+        });
+    };
+    match (write, credential.access()) {
+        (true, Access::Read) => Err(Refusal::Forbidden(format!("{} may only read", credential.describe()))),
+        _ => Ok(()),
+    }
+}
+
+// `check` for an HTTP request: 401 without a valid credential where one
+// is needed, 403 for a reader's that tries to write.
+pub fn authorize(method: &Method, headers: &HeaderMap, state: &SharedState) -> Option<Response<Body>> {
+    let write = !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+    match check(write, headers, state) {
+        Ok(()) => None,
+        Err(Refusal::Unauthenticated(message, challenge)) => Some(unauthorized(message, challenge)),
+        Err(Refusal::Forbidden(message)) => Some(problem::respond(StatusCode::FORBIDDEN, message)),
+    }
+}
+
+// The admin token, the name of an API key, or a token's subject. Every
+// other request is treated as anonymous.
+pub fn principal(headers: &HeaderMap, state: &SharedState) -> Option<String> {
+    if admin(headers, state) {
+        return Some("admin".to_string());
+    }
+    match credential(headers, state) {
+        Ok(Some(Credential::Key(key))) => Some(format!("key:{}", key.name)),
+        Ok(Some(Credential::Token(claims))) => Some(format!("jwt:{}", claims.sub.as_deref().unwrap_or(claims.role.as_str()))),
+        _ => None,
+    }
+}
+
+// The admin token, or a valid token with the admin role.
+fn admin(headers: &HeaderMap, state: &SharedState) -> bool {
+    let Some(token) = bearer(headers) else {
+        return false;
+    };
+    if state.admin_token.as_ref().is_some_and(|admin| matches(token, admin)) {
+        return true;
+    }
+    let claims = state.jwt.as_ref().and_then(|jwt| jwt.verify(token).ok());
+    claims.is_some_and(|claims| claims.role == Role::Admin)
+}
+
+// Debug endpoints are hidden entirely unless an admin token or JWTs are
+// configured. A valid token without the admin role gets a 403.
+pub fn reject_non_admin(headers: &HeaderMap, state: &SharedState) -> Option<Response<Body>> {
+    if state.admin_token.is_none() && state.jwt.is_none() {
+        return Some(not_found());
+    }
+    if admin(headers, state) {
+        return None;
+    }
+    match credential(headers, state) {
+        Ok(Some(Credential::Token(claims))) => Some(problem::respond(
+            StatusCode::FORBIDDEN,
+            format!("role {} may not use admin endpoints", claims.role.as_str()),
+        )),
+        Err((message, challenge)) => Some(unauthorized(message, challenge)),
+        _ => Some(unauthorized("Unauthorized", "Bearer")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jwt::Jwt, sim::Sim};
+    use hyper::header::HeaderValue;
+    use std::sync::Arc;
+
+    const SECRET: &str = "auth-test-secret-at-least-32-bytes-long";
+
+    fn key(name: &str, key: &str, access: Access) -> ApiKey {
+        ApiKey { name: name.to_string(), digest: digest(key), access }
+    }
+
+    // A state with a write key "ci", a read key "dashboard" and the admin
+    // token "root".
+    fn state(jwt: Option<Jwt>, reads_need_key: bool) -> SharedState {
+        let mut state = Sim::new(1).state;
+        let app = Arc::get_mut(&mut state).unwrap();
+        app.api_keys = ApiKeys {
+            keys: vec![key("ci", "write-key", Access::Write), key("dashboard", "read-key", Access::Read)],
+            reads_need_key,
+        };
+        app.jwt = jwt;
+        app.admin_token = Some(digest("root"));
+        state
+    }
+
+    fn token(role: &str) -> String {
+        Jwt::new(SECRET).sign(&serde_json::json!({ "sub": format!("{}-user", role), "role": role, "exp": u32::MAX }))
+    }
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if !name.is_empty() {
+            headers.insert(header::HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn bearing(token: &str) -> HeaderMap {
+        headers("authorization", &format!("Bearer {}", token))
+    }
+
+    // What `check` answers for a read and for a write: "ok", the 401's
+    // challenge, or the 403's detail.
+    fn outcome(state: &SharedState, headers: &HeaderMap) -> [String; 2] {
+        [false, true].map(|write| match check(write, headers, state) {
+            Ok(()) => "ok".to_string(),
+            Err(Refusal::Unauthenticated(_, challenge)) => format!("401 {}", challenge),
+            Err(Refusal::Forbidden(message)) => format!("403 {}", message),
+        })
+    }
+
+    #[test]
+    fn the_catalog_is_open_without_keys_or_tokens() {
+        let state = Sim::new(1).state;
+        for headers in [HeaderMap::new(), headers(API_KEY_HEADER, "anything"), bearing("anything")] {
+            assert_eq!(outcome(&state, &headers), ["ok", "ok"]);
+        }
+    }
+
+    #[test]
+    fn keys_and_roles_decide_who_may_write() {
+        let keys_only = state(None, false);
+        let both = state(Some(Jwt::new(SECRET)), false);
+        let cases: [(&str, HeaderMap, [&str; 2], [&str; 2]); 9] = [
+            ("nothing", HeaderMap::new(), ["ok", "401 ApiKey"], ["ok", "401 Bearer, ApiKey"]),
+            ("write key", headers(API_KEY_HEADER, "write-key"), ["ok", "ok"], ["ok", "ok"]),
+            (
+                "read key",
+                headers(API_KEY_HEADER, "read-key"),
+                ["ok", r#"403 API key "dashboard" may only read"#],
+                ["ok", r#"403 API key "dashboard" may only read"#],
+            ),
+            ("unknown key", headers(API_KEY_HEADER, "write-key "), ["401 ApiKey", "401 ApiKey"], ["401 ApiKey", "401 ApiKey"]),
+            (
+                "reader",
+                bearing(&token("reader")),
+                ["ok", "401 ApiKey"],
+                ["ok", "403 role reader may only read"],
+            ),
+            ("editor", bearing(&token("editor")), ["ok", "401 ApiKey"], ["ok", "ok"]),
+            ("admin", bearing(&token("admin")), ["ok", "401 ApiKey"], ["ok", "ok"]),
+            (
+                "forged token",
+                bearing(&Jwt::new("another-secret-at-least-32-bytes-long").sign(&serde_json::json!({ "role": "admin", "exp": u32::MAX }))),
+                ["ok", "401 ApiKey"],
+                [r#"401 Bearer error="invalid_token""#, r#"401 Bearer error="invalid_token""#],
+            ),
+            // The admin token is for the admin endpoints, not the catalog.
+            ("admin token", bearing("root"), ["ok", "401 ApiKey"], ["ok", "401 Bearer, ApiKey"]),
+        ];
+        for (name, headers, without_jwt, with_jwt) in cases {
+            assert_eq!(outcome(&keys_only, &headers), without_jwt, "{} with keys", name);
+            assert_eq!(outcome(&both, &headers), with_jwt, "{} with keys and JWTs", name);
+        }
+    }
+
+    #[test]
+    fn reads_can_require_a_credential() {
+        let mut jwt = Jwt::new(SECRET);
+        jwt.reads_need_token = true;
+        let by_token = state(Some(jwt), false);
+        let by_key = state(None, true);
+        for state in [&by_token, &by_key] {
+            assert!(outcome(state, &HeaderMap::new())[0].starts_with("401"));
+            assert_eq!(outcome(state, &headers(API_KEY_HEADER, "read-key"))[0], "ok");
+        }
+        assert_eq!(outcome(&by_token, &bearing(&token("reader")))[0], "ok");
+    }
+
+    #[test]
+    fn admin_endpoints_need_the_admin_token_or_role() {
+        let status = |state: &SharedState, headers: &HeaderMap| reject_non_admin(headers, state).map(|response| response.status());
+        assert_eq!(status(&Sim::new(1).state, &bearing("root")), Some(StatusCode::NOT_FOUND));
+
+        let state = state(Some(Jwt::new(SECRET)), false);
+        assert_eq!(status(&state, &bearing("root")), None);
+        assert_eq!(status(&state, &bearing(&token("admin"))), None);
+        assert_eq!(status(&state, &bearing(&token("editor"))), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status(&state, &bearing("wrong")), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&state, &headers(API_KEY_HEADER, "write-key")), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&state, &HeaderMap::new()), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn principals_name_the_credential() {
+        let state = state(Some(Jwt::new(SECRET)), false);
+        assert_eq!(principal(&bearing("root"), &state).as_deref(), Some("admin"));
+        assert_eq!(principal(&bearing(&token("admin")), &state).as_deref(), Some("admin"));
+        assert_eq!(principal(&bearing(&token("editor")), &state).as_deref(), Some("jwt:editor-user"));
+        assert_eq!(principal(&headers(API_KEY_HEADER, "read-key"), &state).as_deref(), Some("key:dashboard"));
+        assert_eq!(principal(&headers(API_KEY_HEADER, "nope"), &state), None);
+        assert_eq!(principal(&HeaderMap::new(), &state), None);
+    }
+
+    #[test]
+    fn only_configured_keys_have_a_name() {
+        let state = state(None, false);
+        assert_eq!(state.api_keys.name(&headers(API_KEY_HEADER, "write-key")), Some("ci"));
+        assert_eq!(state.api_keys.name(&headers(API_KEY_HEADER, "read-key")), Some("dashboard"));
+        assert_eq!(state.api_keys.name(&headers(API_KEY_HEADER, "made-up")), None);
+        assert_eq!(state.api_keys.name(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,106 @@
+use books_model::CreateAuthorRequest;
+use hyper::{Response, StatusCode};
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    extract::{Json, Path, Query, State},
+    fields::Fields,
+    html::Format,
+    json_response,
+    links::Links,
+    listing, problem,
+    store::{AuthorStore, BookStore, Store},
+    validate::{self, Validate, Violations},
+    AppState, SharedState,
+};
+
+impl Validate for CreateAuthorRequest {
+    fn validate(&mut self, violations: &mut Violations) {
+        violations.text("name", &mut self.name, validate::MAX_AUTHOR);
+    }
+}
+
+// Gossip and shards replicate or split books field by field and id by id,
+// and neither carries authors yet.
+fn refused(state: &AppState) -> Option<Response<Body>> {
+    (state.cluster.is_some() || state.shards.is_some()).then(|| {
+        problem::respond(
+            StatusCode::NOT_IMPLEMENTED,
+            "authors aren't supported with DOJO_CLUSTER_NODE or DOJO_SHARD_NODE yet",
+        )
+    })
+}
+
+// The name of the author a book is being filed under, which becomes the
+// book's `author`, or what to answer when there's no such author. `field`
+// is where the request gave the id.
+pub async fn name<S: AuthorStore>(store: &S, field: String, id: u64) -> Result<String, ApiError> {
+    match store.author(id).await? {
+        Some(author) => Ok(author.name),
+        None => Err(ApiError::invalid(StatusCode::UNPROCESSABLE_ENTITY, field, format!("there's no author {}", id))),
+    }
+}
+
+pub async fn create<S: AuthorStore>(
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+    Json(mut request): Json<CreateAuthorRequest>,
+) -> Result<Response<Body>, ApiError> {
+    if let Some(refused) = refused(&state) {
+        return Ok(refused);
+    }
+    validate::check(&mut request)?;
+    let author = store.insert_author(request.name).await?;
+    Ok(json_response(StatusCode::CREATED, &author)?)
+}
+
+pub async fn list<S: AuthorStore>(Store(store): Store<S>) -> Result<Response<Body>, ApiError> {
+    Ok(json_response(StatusCode::OK, &store.authors().await?)?)
+}
+
+pub async fn get<S: AuthorStore>(Path(id): Path<u64>, Store(store): Store<S>) -> Result<Response<Body>, ApiError> {
+    let author = store.author(id).await?.ok_or_else(ApiError::not_found)?;
+    Ok(json_response(StatusCode::OK, &author)?)
+}
+
+// An author with books, even deleted ones, can't go: the books would be
+// left filed under nobody.
+pub async fn delete<S: AuthorStore>(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    if let Some(refused) = refused(&state) {
+        return Ok(refused);
+    }
+    match store.delete_author(id).await? {
+        Some(Ok(_)) => Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap()),
+        Some(Err(books)) => Err(ApiError::Conflict(format!(
+            "author {} still has {} books, including any in the trash",
+            id, books
+        ))),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+// The books filed under the author, paged like the catalog.
+pub async fn books<S: BookStore + AuthorStore>(
+    Path(id): Path<u64>,
+    Query(page): Query<listing::Page>,
+    Query(fields): Query<Fields>,
+    format: Format,
+    links: Links,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    let after = page.after().map_err(ApiError::bad_request)?;
+    fields.check(listing::BOOK_FIELDS).map_err(ApiError::bad_request)?;
+    store.author(id).await?.ok_or_else(ApiError::not_found)?;
+    let mut books = store.list().await?;
+    books.retain(|book| book.author_id == Some(id));
+    let total = page.counts_total().then_some(books.len());
+    Ok(listing::respond(format, &links, &page, &fields, page.apply(books, after), total)?)
+}
@@ -0,0 +1,251 @@
+use http_body::{Body as HttpBody, Frame, SizeHint};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt};
+use hyper::body::{Bytes, Incoming};
+use std::{
+    borrow::Cow,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+    task::{Context, Poll},
+};
+use tokio::sync::{mpsc, oneshot};
+
+// The body of every request and response the API handles: one buffer, a
+// stream a task writes to, or what a connection is still receiving.
+// hyper 1 leaves the body type to the application; this one keeps the
+// shape 0.14's had, so handlers and middleware build and read bodies the
+// same way whichever runner serves them.
+#[derive(Default)]
+pub struct Body(Kind);
+
+#[derive(Default)]
+enum Kind {
+    #[default]
+    Empty,
+    Full(Bytes),
+    Channel {
+        chunks: mpsc::Receiver<Bytes>,
+        abort: Option<oneshot::Receiver<()>>,
+    },
+    Incoming(Incoming),
+    // Behind a lock only so that Body stays Sync; being polled takes it
+    // mutably anyway.
+    Other(Mutex<UnsyncBoxBody<Bytes, Error>>),
+}
+
+// Why a body couldn't be read or written, whatever was behind it.
+#[derive(Debug)]
+pub struct Error(Box<dyn std::error::Error + Send + Sync>);
+
+impl Error {
+    fn new(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Error(error.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        Error::new(e)
+    }
+}
+
+impl From<hyper_util::client::legacy::Error> for Error {
+    fn from(e: hyper_util::client::legacy::Error) -> Self {
+        Error::new(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::new(e)
+    }
+}
+
+// The writing end of `Body::channel`. Each chunk waits for the one before
+// it to be taken, so a slow reader slows the writer down.
+pub struct Sender {
+    chunks: mpsc::Sender<Bytes>,
+    abort: oneshot::Sender<()>,
+}
+
+impl Sender {
+    // Fails once the body has been dropped, typically because the client
+    // went away.
+    pub async fn send_data(&mut self, chunk: Bytes) -> Result<(), Error> {
+        self.chunks.send(chunk).await.map_err(|_| Error::new("the body was dropped"))
+    }
+
+    // Ends the body with an error instead of a clean end, so the client can
+    // tell it's been cut short.
+    pub fn abort(self) {
+        let _ = self.abort.send(());
+    }
+}
+
+impl Body {
+    pub fn empty() -> Self {
+        Body(Kind::Empty)
+    }
+
+    pub fn channel() -> (Sender, Self) {
+        let (chunks, receiver) = mpsc::channel(1);
+        let (abort, aborted) = oneshot::channel();
+        let body = Body(Kind::Channel {
+            chunks: receiver,
+            abort: Some(aborted),
+        });
+        (Sender { chunks, abort }, body)
+    }
+
+    // Any other body, such as one another framework hands over.
+    #[cfg_attr(not(feature = "axum"), allow(dead_code))]
+    pub fn wrap<B>(body: B) -> Self
+    where
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Body(Kind::Other(Mutex::new(body.map_err(Error::new).boxed_unsync())))
+    }
+
+    // The next chunk of data, skipping trailers.
+    pub async fn data(&mut self) -> Option<Result<Bytes, Error>> {
+        loop {
+            let frame = std::future::poll_fn(|cx| Pin::new(&mut *self).poll_frame(cx)).await?;
+            match frame.map(Frame::into_data) {
+                Ok(Ok(data)) => return Some(Ok(data)),
+                Ok(Err(_)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Reads a whole body into one buffer, a request's or a response's,
+// including one a client got back.
+pub async fn to_bytes<B>(body: B) -> Result<Bytes, Error>
+where
+    B: HttpBody<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    body.collect().await.map(|collected| collected.to_bytes()).map_err(Error::new)
+}
+
+impl HttpBody for Body {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        match &mut self.0 {
+            Kind::Empty => Poll::Ready(None),
+            Kind::Full(bytes) => {
+                let bytes = std::mem::take(bytes);
+                self.0 = Kind::Empty;
+                Poll::Ready((!bytes.is_empty()).then(|| Ok(Frame::data(bytes))))
+            }
+            Kind::Channel { chunks, abort } => {
+                if let Some(aborted) = abort {
+                    match Pin::new(aborted).poll(cx) {
+                        Poll::Ready(Ok(())) => return Poll::Ready(Some(Err(Error::new("the body was aborted")))),
+                        // Dropped without aborting, which is how a body ends.
+                        Poll::Ready(Err(_)) => *abort = None,
+                        Poll::Pending => {}
+                    }
+                }
+                chunks.poll_recv(cx).map(|chunk| chunk.map(|chunk| Ok(Frame::data(chunk))))
+            }
+            Kind::Incoming(incoming) => Pin::new(incoming).poll_frame(cx).map_err(Error::from),
+            Kind::Other(body) => Pin::new(body.get_mut().unwrap_or_else(PoisonError::into_inner)).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match &self.0 {
+            Kind::Empty => true,
+            Kind::Full(bytes) => bytes.is_empty(),
+            Kind::Channel { .. } => false,
+            Kind::Incoming(incoming) => incoming.is_end_stream(),
+            Kind::Other(body) => body.lock().unwrap_or_else(PoisonError::into_inner).is_end_stream(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.0 {
+            Kind::Empty => SizeHint::with_exact(0),
+            Kind::Full(bytes) => SizeHint::with_exact(bytes.len() as u64),
+            Kind::Channel { .. } => SizeHint::default(),
+            Kind::Incoming(incoming) => incoming.size_hint(),
+            Kind::Other(body) => body.lock().unwrap_or_else(PoisonError::into_inner).size_hint(),
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match &self.0 {
+            Kind::Empty => "Empty",
+            Kind::Full(_) => "Full",
+            Kind::Channel { .. } => "Channel",
+            Kind::Incoming(_) => "Incoming",
+            Kind::Other(_) => "Other",
+        };
+        f.debug_tuple("Body").field(&kind).finish()
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(bytes: Bytes) -> Self {
+        Body(Kind::Full(bytes))
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::from(Bytes::from(bytes))
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::from(Bytes::from(text))
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(text: &'static str) -> Self {
+        Body::from(Bytes::from_static(text.as_bytes()))
+    }
+}
+
+impl From<&'static [u8]> for Body {
+    fn from(bytes: &'static [u8]) -> Self {
+        Body::from(Bytes::from_static(bytes))
+    }
+}
+
+impl From<Cow<'static, str>> for Body {
+    fn from(text: Cow<'static, str>) -> Self {
+        match text {
+            Cow::Borrowed(text) => Body::from(text),
+            Cow::Owned(text) => Body::from(text),
+        }
+    }
+}
+
+impl From<Incoming> for Body {
+    fn from(incoming: Incoming) -> Self {
+        Body(Kind::Incoming(incoming))
+    }
+}
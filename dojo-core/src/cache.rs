@@ -0,0 +1,277 @@
+use books_model::Book;
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::StorageError;
+
+// What's worth keeping from a slow backend: single books, and the first
+// page of the listing by how many books it asked for. Books that don't
+// exist aren't kept, so a new book's id never has to be forgotten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Book(u64),
+    FirstPage(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Book(Box<Book>),
+    Page(Vec<Book>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    stored: Instant,
+    used: u64,
+}
+
+// Entries by key, and the keys by when they were last used, the least
+// recently used first.
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<Key, Entry>,
+    recency: BTreeMap<u64, Key>,
+    clock: u64,
+    // Bumped by every write, so a read that started before one doesn't
+    // keep what it read afterwards.
+    generation: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: Key, ttl: Option<Duration>) -> Option<Value> {
+        let entry = self.entries.get_mut(&key)?;
+        if ttl.is_some_and(|ttl| entry.stored.elapsed() >= ttl) {
+            let used = entry.used;
+            self.entries.remove(&key);
+            self.recency.remove(&used);
+            return None;
+        }
+        self.recency.remove(&entry.used);
+        self.clock += 1;
+        entry.used = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(entry.value.clone())
+    }
+
+    fn put(&mut self, key: Key, value: Value, capacity: usize) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.recency.remove(&old.used);
+        }
+        while self.entries.len() >= capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key);
+        self.entries.insert(key, Entry { value, stored: Instant::now(), used: self.clock });
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+}
+
+// A bounded read-through cache in front of Postgres or Redis. Every write
+// through the store forgets what it could have changed: the book and each
+// cached first page. Other instances sharing the backend write past it,
+// so entries also expire after DOJO_CACHE_TTL_SECS.
+#[derive(Debug, Default)]
+pub struct Cache {
+    capacity: usize,
+    ttl: Option<Duration>,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    // DOJO_CACHE_ENTRIES turns the cache on with room for that many books
+    // and pages. DOJO_CACHE_TTL_SECS defaults to 10, and 0 keeps entries
+    // until they're evicted or a write forgets them.
+    pub fn from_env() -> Result<Self, String> {
+        let capacity = match std::env::var("DOJO_CACHE_ENTRIES") {
+            Ok(entries) => entries
+                .parse()
+                .map_err(|_| format!("DOJO_CACHE_ENTRIES must be a number of entries, got {:?}", entries))?,
+            Err(_) => 0,
+        };
+        let ttl = match std::env::var("DOJO_CACHE_TTL_SECS") {
+            Ok(secs) => match secs.parse() {
+                Ok(0) => None,
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(_) => return Err(format!("DOJO_CACHE_TTL_SECS must be a number of seconds, got {:?}", secs)),
+            },
+            Err(_) => Some(Duration::from_secs(10)),
+        };
+        Ok(Cache::new(capacity, ttl))
+    }
+
+    pub fn new(capacity: usize, ttl: Option<Duration>) -> Self {
+        Cache { capacity, ttl, ..Default::default() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // The book, from the cache or else from `load`.
+    pub async fn book(
+        &self,
+        id: u64,
+        load: impl Future<Output = Result<Option<Book>, StorageError>>,
+    ) -> Result<Option<Book>, StorageError> {
+        let key = Key::Book(id);
+        let generation = match self.lookup(key, |value| match value {
+            Value::Book(book) => Some(book),
+            Value::Page(_) => None,
+        }) {
+            None => return load.await,
+            Some(Ok(book)) => return Ok(Some(*book)),
+            Some(Err(generation)) => generation,
+        };
+        let book = load.await?;
+        if let Some(book) = &book {
+            self.store(key, Value::Book(Box::new(book.clone())), generation);
+        }
+        Ok(book)
+    }
+
+    // The first `limit` books, from the cache or else from `load`.
+    pub async fn first_page(
+        &self,
+        limit: usize,
+        load: impl Future<Output = Result<Vec<Book>, StorageError>>,
+    ) -> Result<Vec<Book>, StorageError> {
+        let key = Key::FirstPage(limit);
+        let generation = match self.lookup(key, |value| match value {
+            Value::Page(books) => Some(books),
+            Value::Book(_) => None,
+        }) {
+            None => return load.await,
+            Some(Ok(books)) => return Ok(books),
+            Some(Err(generation)) => generation,
+        };
+        let books = load.await?;
+        self.store(key, Value::Page(books.clone()), generation);
+        Ok(books)
+    }
+
+    // Awaits a write, then forgets the book it was for, if any, and every
+    // first page, whether or not the write went through.
+    pub async fn invalidating<T>(&self, id: Option<u64>, write: impl Future<Output = T>) -> T {
+        let result = write.await;
+        if self.enabled() {
+            let mut lru = self.lru.lock().unwrap();
+            lru.generation += 1;
+            if let Some(id) = id {
+                lru.remove(&Key::Book(id));
+            }
+            let pages: Vec<Key> = lru.entries.keys().filter(|key| matches!(key, Key::FirstPage(_))).copied().collect();
+            for page in pages {
+                lru.remove(&page);
+            }
+        }
+        result
+    }
+
+    // Hits and misses so far, and how many entries are kept.
+    pub fn stats(&self) -> (u64, u64, usize) {
+        let entries = self.lru.lock().unwrap().entries.len();
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed), entries)
+    }
+
+    // None when the cache is off, otherwise the cached value or, on a
+    // miss, the generation a fill has to match to be kept.
+    fn lookup<T>(&self, key: Key, unpack: impl FnOnce(Value) -> Option<T>) -> Option<Result<T, u64>> {
+        if !self.enabled() {
+            return None;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        match lru.get(key, self.ttl).and_then(unpack) {
+            Some(value) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(Ok(value))
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Some(Err(lru.generation))
+            }
+        }
+    }
+
+    fn store(&self, key: Key, value: Value, generation: u64) {
+        let mut lru = self.lru.lock().unwrap();
+        if lru.generation == generation {
+            lru.put(key, value, self.capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(id: u64) -> Book {
+        Book {
+            id,
+            title: format!("Book {}", id),
+            author: "Author".to_string(),
+            isbn: None,
+            author_id: None,
+            tags: Vec::new(),
+            edition: Default::default(),
+            uid: None,
+            nft: None,
+            anchor: None,
+            deleted_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_through_and_forgets_on_writes() {
+        let cache = Cache::new(2, None);
+        assert_eq!(cache.book(1, async { Ok(Some(book(1))) }).await.unwrap(), Some(book(1)));
+        assert_eq!(cache.book(1, async { Ok(None) }).await.unwrap(), Some(book(1)));
+        assert_eq!(cache.book(2, async { Ok(None) }).await.unwrap(), None);
+        assert_eq!(cache.book(2, async { Ok(Some(book(2))) }).await.unwrap(), Some(book(2)));
+        assert_eq!(cache.stats(), (1, 3, 2));
+
+        cache.first_page(10, async { Ok(vec![book(1), book(2)]) }).await.unwrap();
+        assert_eq!(cache.book(1, async { Ok(None) }).await.unwrap(), None, "book 1 was the least recently used");
+
+        cache.invalidating(Some(2), async {}).await;
+        assert_eq!(cache.stats().2, 0);
+        assert_eq!(cache.first_page(10, async { Ok(vec![book(3)]) }).await.unwrap(), vec![book(3)]);
+    }
+
+    #[tokio::test]
+    async fn a_read_overtaken_by_a_write_is_not_kept() {
+        let cache = Cache::new(8, None);
+        let stale = cache.book(1, async {
+            cache.invalidating(Some(1), async {}).await;
+            Ok(Some(book(1)))
+        });
+        assert_eq!(stale.await.unwrap(), Some(book(1)));
+        assert_eq!(cache.stats().2, 0);
+    }
+
+    #[tokio::test]
+    async fn a_disabled_cache_always_loads() {
+        let cache = Cache::new(0, None);
+        cache.book(1, async { Ok(Some(book(1))) }).await.unwrap();
+        assert_eq!(cache.book(1, async { Ok(None) }).await.unwrap(), None);
+        assert_eq!(cache.stats(), (0, 0, 0));
+    }
+}
@@ -0,0 +1,240 @@
+use http_body::Body as HttpBody;
+use hyper::{header::HeaderMap, Request, Response, StatusCode};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+};
+
+use crate::{
+    body::{self, Body},
+    conditional::now_ms,
+    extract::State,
+    json_response,
+    stack::Next,
+    SharedState,
+};
+
+const REDACTED_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"];
+
+// Bodies kept in memory are cut off past this.
+const MAX_KEPT_BODY: usize = 64 * 1024;
+
+// Where captured exchanges would only capture themselves.
+const UNCAPTURED: &str = "/admin/debug/requests";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureRecord {
+    pub request: CapturedRequest,
+    pub response: CapturedResponse,
+}
+
+// An exchange kept in memory, numbered in the order it finished.
+#[derive(Debug, Clone, Serialize)]
+struct Recent {
+    id: u64,
+    // Milliseconds since the epoch.
+    at: u64,
+    #[serde(flatten)]
+    record: CaptureRecord,
+}
+
+#[derive(Default)]
+struct Ring {
+    next_id: u64,
+    recent: VecDeque<Recent>,
+}
+
+// Records requests and their responses: every one to DOJO_CAPTURE_FILE, as
+// JSON lines the replay tool reads, and the last DOJO_DEBUG_CAPTURE in
+// memory for GET /admin/debug/requests. The headers named in
+// DOJO_CAPTURE_REDACT, by default the ones that carry credentials, are
+// recorded as [redacted].
+pub struct Recorder {
+    file: Option<Mutex<File>>,
+    keep: usize,
+    ring: Mutex<Ring>,
+    redacted: Vec<String>,
+}
+
+impl Recorder {
+    // None when there's nowhere to record to.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let file = match std::env::var("DOJO_CAPTURE_FILE") {
+            Ok(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(&path);
+                Some(Mutex::new(file.map_err(|e| format!("failed to open capture file {}: {}", path, e))?))
+            }
+            Err(_) => None,
+        };
+        let keep = match std::env::var("DOJO_DEBUG_CAPTURE") {
+            Ok(keep) => keep.parse().map_err(|_| format!("DOJO_DEBUG_CAPTURE must be a number of requests, got {:?}", keep))?,
+            Err(_) => 0,
+        };
+        let redacted = match std::env::var("DOJO_CAPTURE_REDACT") {
+            Ok(names) => names.split(',').map(|name| name.trim().to_ascii_lowercase()).filter(|name| !name.is_empty()).collect(),
+            Err(_) => REDACTED_HEADERS.iter().map(|name| name.to_string()).collect(),
+        };
+        if file.is_none() && keep == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Recorder { file, keep, ring: Mutex::default(), redacted }))
+    }
+
+    fn write(&self, record: CaptureRecord) {
+        if let Some(file) = &self.file {
+            let mut line = match serde_json::to_string(&record) {
+                Ok(line) => line,
+                Err(e) => {
+                    tracing::warn!("capture: failed to serialize record: {}", e);
+                    return;
+                }
+            };
+            line.push('\n');
+
+            let mut file = file.lock().unwrap();
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                tracing::warn!("capture: failed to write record: {}", e);
+            }
+        }
+        if self.keep > 0 {
+            let mut record = record;
+            for body in [&mut record.request.body, &mut record.response.body] {
+                truncate(body);
+            }
+            let mut ring = self.ring.lock().unwrap();
+            ring.next_id += 1;
+            let id = ring.next_id;
+            if ring.recent.len() == self.keep {
+                ring.recent.pop_front();
+            }
+            ring.recent.push_back(Recent { id, at: now_ms(), record });
+        }
+    }
+
+    fn sanitize(&self, headers: &HeaderMap) -> BTreeMap<String, String> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.redacted.iter().any(|redacted| redacted == name.as_str()) {
+                    "[redacted]".to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+}
+
+fn truncate(body: &mut String) {
+    if body.len() > MAX_KEPT_BODY {
+        let mut end = MAX_KEPT_BODY;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        let cut = body.len() - end;
+        body.truncate(end);
+        body.push_str(&format!("... [{} more bytes]", cut));
+    }
+}
+
+// Buffers both bodies so they can be written out, then hands the same bytes
+// on to the handler and the client. A streamed response, like an event
+// stream, is passed on as it comes and recorded without its body.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let recorder = match &state.recorder {
+        Some(recorder) if req.uri().path() != UNCAPTURED => recorder,
+        _ => return next.run(req, state.clone()).await,
+    };
+
+    let (parts, body) = req.into_parts();
+    let request_body = body::to_bytes(body).await?;
+    let captured_request = CapturedRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        headers: recorder.sanitize(&parts.headers),
+        body: String::from_utf8_lossy(&request_body).into_owned(),
+    };
+
+    let req = Request::from_parts(parts, Body::from(request_body));
+    let response = next.run(req, state.clone()).await?;
+
+    let (parts, body) = response.into_parts();
+    let streamed = body.size_hint().exact().is_none();
+    let (response_body, body) = match streamed {
+        true => ("[streamed]".to_string(), body),
+        false => {
+            let bytes = body::to_bytes(body).await?;
+            (String::from_utf8_lossy(&bytes).into_owned(), Body::from(bytes))
+        }
+    };
+    recorder.write(CaptureRecord {
+        request: captured_request,
+        response: CapturedResponse {
+            status: parts.status.as_u16(),
+            headers: recorder.sanitize(&parts.headers),
+            body: response_body,
+        },
+    });
+
+    Ok(Response::from_parts(parts, body))
+}
+
+// The exchanges kept in memory, newest first. 404 unless DOJO_DEBUG_CAPTURE
+// is set.
+pub async fn recent(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let Some(recorder) = state.recorder.as_ref().filter(|recorder| recorder.keep > 0) else {
+        return Ok(crate::not_found());
+    };
+    let recent: Vec<Recent> = recorder.ring.lock().unwrap().recent.iter().rev().cloned().collect();
+    json_response(StatusCode::OK, &recent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(uri: &str) -> CaptureRecord {
+        CaptureRecord {
+            request: CapturedRequest { method: "GET".to_string(), uri: uri.to_string(), headers: BTreeMap::new(), body: String::new() },
+            response: CapturedResponse { status: 200, headers: BTreeMap::new(), body: "x".repeat(MAX_KEPT_BODY + 10) },
+        }
+    }
+
+    #[test]
+    fn keeps_the_last_exchanges_with_credentials_redacted() {
+        let recorder = Recorder { file: None, keep: 2, ring: Mutex::default(), redacted: vec!["x-session".to_string()] };
+        for uri in ["/books/1", "/books/2", "/books/3"] {
+            recorder.write(exchange(uri));
+        }
+        let ring = recorder.ring.lock().unwrap();
+        let kept: Vec<(u64, &str)> = ring.recent.iter().map(|recent| (recent.id, recent.record.request.uri.as_str())).collect();
+        assert_eq!(kept, [(2, "/books/2"), (3, "/books/3")]);
+        assert!(ring.recent[0].record.response.body.ends_with("... [10 more bytes]"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-session", "secret".parse().unwrap());
+        headers.insert("authorization", "Bearer t".parse().unwrap());
+        let sanitized = recorder.sanitize(&headers);
+        assert_eq!(sanitized["x-session"], "[redacted]");
+        assert_eq!(sanitized["authorization"], "Bearer t");
+    }
+}
@@ -0,0 +1,307 @@
+use serde_json::{Map, Value};
+
+use crate::msgpack::float;
+
+// CBOR (RFC 8949) for what JSON can hold. Tags are read through to the
+// value they wrap; byte strings have no JSON counterpart and are rejected.
+pub const MEDIA_TYPE: &str = "application/cbor";
+
+const MAX_DEPTH: usize = 128;
+const BREAK: u8 = 0xff;
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write(&mut out, value);
+    out
+}
+
+fn write(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(n), _) => head(out, 0, n),
+            (None, Some(n)) => head(out, 1, !(n as u64)),
+            _ => {
+                out.push(0xfb);
+                out.extend(number.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        },
+        Value::String(text) => {
+            head(out, 3, text.len() as u64);
+            out.extend(text.as_bytes());
+        }
+        Value::Array(items) => {
+            head(out, 4, items.len() as u64);
+            items.iter().for_each(|item| write(out, item));
+        }
+        Value::Object(object) => {
+            head(out, 5, object.len() as u64);
+            for (key, value) in object {
+                head(out, 3, key.len() as u64);
+                out.extend(key.as_bytes());
+                write(out, value);
+            }
+        }
+    }
+}
+
+// The major type and its argument in the shortest form.
+fn head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend([major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { bytes, at: 0 };
+    let value = reader.value(0)?;
+    match reader.at == bytes.len() {
+        true => Ok(value),
+        false => Err(format!("{} bytes left over after the value", bytes.len() - reader.at)),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self.at.checked_add(len).filter(|end| *end <= self.bytes.len()).ok_or("truncated value")?;
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, String> {
+        Ok(self.take(len)?.iter().fold(0, |n, byte| n << 8 | u64::from(*byte)))
+    }
+
+    fn peek_break(&mut self) -> Result<bool, String> {
+        match self.bytes.get(self.at) {
+            Some(&BREAK) => {
+                self.at += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err("truncated value".to_string()),
+        }
+    }
+
+    // The argument of a head; None for an indefinite length.
+    fn argument(&mut self, info: u8) -> Result<Option<u64>, String> {
+        match info {
+            0..=23 => Ok(Some(u64::from(info))),
+            24 => self.uint(1).map(Some),
+            25 => self.uint(2).map(Some),
+            26 => self.uint(4).map(Some),
+            27 => self.uint(8).map(Some),
+            31 => Ok(None),
+            _ => Err(format!("reserved additional information {}", info)),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("nested too deeply".to_string());
+        }
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        if major == 7 {
+            return match info {
+                20 => Ok(Value::Bool(false)),
+                21 => Ok(Value::Bool(true)),
+                22 | 23 => Ok(Value::Null),
+                25 => float(half(self.uint(2)? as u16)),
+                26 => float(f64::from(f32::from_bits(self.uint(4)? as u32))),
+                27 => float(f64::from_bits(self.uint(8)?)),
+                _ => Err(format!("unsupported simple value {}", info)),
+            };
+        }
+        let argument = self.argument(info)?;
+        match (major, argument) {
+            (0, Some(n)) => Ok(Value::from(n)),
+            (1, Some(n)) => match i64::try_from(n) {
+                Ok(n) => Ok(Value::from(-1 - n)),
+                Err(_) => Err("negative integer out of range".to_string()),
+            },
+            (2, _) => Err("byte strings aren't supported".to_string()),
+            (3, Some(len)) => self.text(len).map(Value::String),
+            // Chunks of a definite length each, ended by a break.
+            (3, None) => {
+                let mut text = String::new();
+                while !self.peek_break()? {
+                    let initial = self.take(1)?[0];
+                    match (initial >> 5, self.argument(initial & 0x1f)?) {
+                        (3, Some(len)) => text.push_str(&self.text(len)?),
+                        _ => return Err("an indefinite string holds something other than text".to_string()),
+                    }
+                }
+                Ok(Value::String(text))
+            }
+            (4, length) => {
+                let mut items = Vec::new();
+                while self.more(length, items.len())? {
+                    items.push(self.value(depth + 1)?);
+                }
+                Ok(Value::Array(items))
+            }
+            (5, length) => {
+                let mut object = Map::new();
+                let mut count = 0;
+                while self.more(length, count)? {
+                    let Value::String(key) = self.value(depth + 1)? else {
+                        return Err("map keys must be strings".to_string());
+                    };
+                    object.insert(key, self.value(depth + 1)?);
+                    count += 1;
+                }
+                Ok(Value::Object(object))
+            }
+            (6, Some(_)) => self.value(depth + 1),
+            _ => Err(format!("indefinite length on major type {}", major)),
+        }
+    }
+
+    // Whether another item follows in an array or map with `length` items,
+    // or up to a break when the length is indefinite.
+    fn more(&mut self, length: Option<u64>, read: usize) -> Result<bool, String> {
+        match length {
+            Some(length) => Ok((read as u64) < length),
+            None => Ok(!self.peek_break()?),
+        }
+    }
+
+    fn text(&mut self, len: u64) -> Result<String, String> {
+        let len = usize::try_from(len).map_err(|_| "string too long")?;
+        let bytes = self.take(len)?;
+        std::str::from_utf8(bytes).map(str::to_string).map_err(|_| "a string isn't valid UTF-8".to_string())
+    }
+}
+
+// IEEE 754 half precision.
+fn half(bits: u16) -> f64 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((bits >> 10) & 0x1f);
+    let fraction = f64::from(bits & 0x03ff);
+    sign * match exponent {
+        0 => fraction * 2f64.powi(-24),
+        31 if fraction == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + fraction / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{hex, unhex};
+    use serde_json::json;
+
+    fn decoded(wire: &str) -> Result<Value, String> {
+        decode(&unhex(&wire.replace(' ', "")).unwrap())
+    }
+
+    // From the examples in appendix A of RFC 8949.
+    #[test]
+    fn values_match_rfc_8949() {
+        let cases = [
+            (json!(0), "00"),
+            (json!(23), "17"),
+            (json!(24), "1818"),
+            (json!(1000), "1903e8"),
+            (json!(1000000), "1a000f4240"),
+            (json!(1000000000000u64), "1b000000e8d4a51000"),
+            (json!(u64::MAX), "1bffffffffffffffff"),
+            (json!(-1), "20"),
+            (json!(-1000), "3903e7"),
+            (json!(1.1), "fb3ff199999999999a"),
+            (json!(false), "f4"),
+            (json!(true), "f5"),
+            (json!(null), "f6"),
+            (json!(""), "60"),
+            (json!("IETF"), "6449455446"),
+            (json!("\u{fc}"), "62c3bc"),
+            (json!([1, [2, 3], [4, 5]]), "8301820203820405"),
+            (json!({ "a": 1, "b": [2, 3] }), "a26161016162820203"),
+        ];
+        for (value, wire) in cases {
+            assert_eq!(hex(&encode(&value)), wire, "{}", value);
+            assert_eq!(decoded(wire), Ok(value));
+        }
+
+        // Forms this side reads but never writes.
+        let cases = [
+            ("f93e00", json!(1.5)),
+            ("f97bff", json!(65504.0)),
+            ("f9c400", json!(-4.0)),
+            ("f90001", json!(5.960464477539063e-8)),
+            ("fa47c35000", json!(100000.0)),
+            ("f7", json!(null)),
+            ("9fff", json!([])),
+            ("9f 01 820203 9f0405ff ff", json!([1, [2, 3], [4, 5]])),
+            ("bf 6161 01 6162 9f0203ff ff", json!({ "a": 1, "b": [2, 3] })),
+            ("7f 657374726561 646d696e67 ff", json!("streaming")),
+            ("c0 74 323031332d30332d32315432303a30343a30305a", json!("2013-03-21T20:04:00Z")),
+        ];
+        for (wire, value) in cases {
+            assert_eq!(decoded(wire), Ok(value), "{}", wire);
+        }
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let value = json!({
+            "id": 7,
+            "title": "Solaris — Stanisław Lem",
+            "tags": ["sf", "classic", ""],
+            "edition": { "published_year": 1961, "weight": -0.25, "pages": null },
+            "blurb": "x".repeat(70_000),
+            "numbers": (0..300).map(|n| n * 997 - 150_000).collect::<Vec<i64>>(),
+            "extremes": [u64::MAX, i64::MIN, f64::MAX],
+        });
+        assert_eq!(decode(&encode(&value)), Ok(value));
+    }
+
+    #[test]
+    fn malformed_input_is_refused() {
+        let cases = [
+            ("", "truncated value"),
+            ("1903", "truncated value"),
+            ("9f01", "truncated value"),
+            ("0000", "1 bytes left over after the value"),
+            ("4100", "byte strings aren't supported"),
+            ("f820", "unsupported simple value 24"),
+            ("1c", "reserved additional information 28"),
+            ("1f", "indefinite length on major type 0"),
+            ("3bffffffffffffffff", "negative integer out of range"),
+            ("7f 01 ff", "an indefinite string holds something other than text"),
+            ("a1 01 02", "map keys must be strings"),
+            ("62c328", "a string isn't valid UTF-8"),
+            ("f97e00", "NaN can't be sent as JSON"),
+        ];
+        for (wire, error) in cases {
+            assert_eq!(decoded(wire), Err(error.to_string()), "{}", wire);
+        }
+        let deep = format!("{}00", "81".repeat(200));
+        assert_eq!(decoded(&deep), Err("nested too deeply".to_string()));
+        assert_eq!(decoded("9bffffffffffffffff"), Err("truncated value".to_string()));
+    }
+}
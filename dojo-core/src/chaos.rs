@@ -0,0 +1,76 @@
+use std::{env, sync::Mutex, time::Duration};
+
+use crate::StorageError;
+
+// Built only with the `chaos` feature. Every decision is drawn from one
+// seeded generator, so a fixed seed and request order replay the same faults.
+pub struct Chaos {
+    latency_rate: f64,
+    latency: Duration,
+    storage_error_rate: f64,
+    rng: Mutex<u64>,
+}
+
+impl Chaos {
+    pub fn new(seed: u64, latency_rate: f64, latency: Duration, storage_error_rate: f64) -> Self {
+        Chaos {
+            latency_rate,
+            latency,
+            storage_error_rate,
+            rng: Mutex::new(seed),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Chaos::new(
+            number("DOJO_CHAOS_SEED", 0)?,
+            rate("DOJO_CHAOS_LATENCY_RATE")?,
+            Duration::from_millis(number("DOJO_CHAOS_LATENCY_MS", 100)?),
+            rate("DOJO_CHAOS_STORAGE_ERROR_RATE")?,
+        ))
+    }
+
+    pub async fn before_storage(&self, op: &str) -> Result<(), StorageError> {
+        if self.roll(self.latency_rate) {
+            tokio::time::sleep(self.latency).await;
+        }
+        if self.roll(self.storage_error_rate) {
+            return Err(StorageError::Failed(format!("injected failure in {}", op)));
+        }
+        Ok(())
+    }
+
+    fn roll(&self, rate: f64) -> bool {
+        rate > 0.0 && self.next_f64() < rate
+    }
+
+    // splitmix64
+    fn next_f64(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn rate(name: &str) -> Result<f64, String> {
+    match env::var(name) {
+        Ok(value) => match value.parse::<f64>() {
+            Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+            _ => Err(format!("{} must be a number between 0 and 1, got {:?}", name, value)),
+        },
+        Err(_) => Ok(0.0),
+    }
+}
+
+fn number(name: &str, default: u64) -> Result<u64, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{} must be a non-negative integer, got {:?}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,76 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{sqlite::Write, Storage};
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingKind {
+    KeyMismatch,
+    StaleNextId,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub detail: String,
+    pub fixed: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct CheckReport {
+    pub checked: usize,
+    pub findings: Vec<Finding>,
+}
+
+pub fn check(storage: &mut Storage, fix: bool) -> CheckReport {
+    let mut findings = Vec::new();
+
+    let mismatched: Vec<u64> = storage
+        .books
+        .iter()
+        .filter(|(key, book)| **key != book.id)
+        .map(|(key, _)| *key)
+        .collect();
+
+    for key in mismatched {
+        let book_id = storage.books[&key].id;
+        findings.push(Finding {
+            kind: FindingKind::KeyMismatch,
+            detail: format!("book stored under key {} has id {}", key, book_id),
+            fixed: fix,
+        });
+
+        if fix {
+            let mut book = storage.books.remove(&key).unwrap();
+            if storage.books.contains_key(&book.id) {
+                let max_id = storage.books.keys().max().copied().unwrap_or(0);
+                storage.next_id = storage.next_id.max(max_id + 1);
+                book.id = storage.allocate_book_id();
+            }
+            storage.record(Write::Updated(book.clone()));
+            storage.books.insert(book.id, book);
+        }
+    }
+    if fix {
+        storage.reindex();
+    }
+
+    let max_id = storage.books.keys().max().copied().unwrap_or(0);
+    if storage.next_id <= max_id {
+        findings.push(Finding {
+            kind: FindingKind::StaleNextId,
+            detail: format!("next_id is {} but the highest book id is {}", storage.next_id, max_id),
+            fixed: fix,
+        });
+
+        if fix {
+            storage.next_id = max_id + 1;
+        }
+    }
+
+    CheckReport {
+        checked: storage.books.len(),
+        findings,
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::PathBuf,
+};
+
+use crate::{export, import, snapshot, SharedState};
+
+pub const USAGE: &str = "\
+usage: book-api [serve] [--addr HOST:PORT] [--port PORT] [--seed FILE] [options]
+       book-api export [--out FILE] [--format json|csv] [options]
+       book-api import FILE [options]
+
+commands:
+  serve             run the server, the default
+  export            write the catalog to FILE, or to stdout
+  import            add the books in a CSV or JSON file to the catalog
+
+options:
+  --config FILE     read settings from FILE rather than ./config.toml
+  --storage SPEC    memory, sqlite, sqlite:<path> or redis://host:port
+  --seed FILE       fill an empty catalog with the books in a JSON file
+  -h, --help        print this and exit
+
+Every other setting comes from the config file or the environment.
+";
+
+pub enum Command {
+    Serve,
+    Export { out: Option<PathBuf>, format: export::Format },
+    Import { file: PathBuf },
+    Help,
+}
+
+// Flags stand for environment variables, like the config file's keys do,
+// and are returned as such for main to set. A flag wins over both.
+pub type Overrides = Vec<(&'static str, String)>;
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<(Command, Overrides), String> {
+    let mut args = args.into_iter().peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("serve" | "export" | "import" | "help") => args.next().unwrap(),
+        Some(word) if !word.starts_with('-') => return Err(format!("unknown command {:?}", word)),
+        _ => "serve".to_string(),
+    };
+    let mut overrides = Vec::new();
+    let mut out = None;
+    let mut format = None;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg, None),
+        };
+        if !flag.starts_with('-') {
+            match (command.as_str(), &file) {
+                ("import", None) => file = Some(PathBuf::from(flag)),
+                _ => return Err(format!("unexpected argument {:?}", flag)),
+            }
+            continue;
+        }
+        if matches!(flag.as_str(), "-h" | "--help") {
+            return Ok((Command::Help, overrides));
+        }
+        let (var, commands): (Option<&'static str>, &[&str]) = match flag.as_str() {
+            "--config" => (Some("DOJO_CONFIG"), &["serve", "export", "import"]),
+            "--storage" => (Some("DOJO_STORAGE"), &["serve", "export", "import"]),
+            "--addr" => (Some("DOJO_ADDR"), &["serve"]),
+            "--port" => (Some("DOJO_PORT"), &["serve"]),
+            "--seed" => (Some("DOJO_SEED_FILE"), &["serve"]),
+            "--out" | "--format" => (None, &["export"]),
+            _ => return Err(format!("unknown option {}", flag)),
+        };
+        if !commands.contains(&command.as_str()) {
+            return Err(format!("{} doesn't apply to {}", flag, command));
+        }
+        let Some(value) = inline.or_else(|| args.next()) else {
+            return Err(format!("{} needs a value", flag));
+        };
+        match flag.as_str() {
+            "--port" if value.parse::<u16>().is_err() => {
+                return Err(format!("--port must be a port number, got {:?}", value));
+            }
+            "--out" => out = Some(PathBuf::from(&value)),
+            "--format" => {
+                format = Some(match value.as_str() {
+                    "json" => export::Format::Json,
+                    "csv" => export::Format::Csv,
+                    _ => return Err(format!("--format must be json or csv, got {:?}", value)),
+                })
+            }
+            _ => {}
+        }
+        if let Some(var) = var {
+            overrides.push((var, value));
+        }
+    }
+    let command = match command.as_str() {
+        "serve" => Command::Serve,
+        "help" => Command::Help,
+        // The format follows the file's extension unless it's given.
+        "export" => Command::Export {
+            format: format.unwrap_or_else(|| match out.as_ref().and_then(|out| out.to_str()).and_then(import::Kind::of_extension) {
+                Some(import::Kind::Csv) => export::Format::Csv,
+                _ => export::Format::Json,
+            }),
+            out,
+        },
+        _ => Command::Import {
+            file: file.ok_or("import needs the file to read")?,
+        },
+    };
+    Ok((command, overrides))
+}
+
+// The offline commands open the storage the server would, so they need it
+// to be somewhere other than this process's memory. Run beside a server,
+// they see what it has written; a running server with a snapshot file
+// overwrites what an import added to it.
+async fn check_offline(state: &SharedState, command: &str) -> Result<(), String> {
+    if state.cluster.is_some() || state.raft.is_some() || state.shards.is_some() {
+        return Err(format!(
+            "{} works on this instance's own storage, which a cluster keeps in memory; use the API instead",
+            command
+        ));
+    }
+    let storage = state.storage.read().await;
+    let persistent = state.postgres.is_some()
+        || state.redis.is_some()
+        || state.snapshots.is_some()
+        || storage.disk.is_some()
+        || storage.wal.is_some()
+        || storage.history.is_some();
+    if !persistent {
+        return Err(format!(
+            "{} needs the catalog to be stored somewhere: set DOJO_STORAGE, DATABASE_URL, DOJO_SNAPSHOT_FILE or DOJO_WAL_FILE",
+            command
+        ));
+    }
+    Ok(())
+}
+
+pub async fn export(state: SharedState, out: Option<PathBuf>, format: export::Format) -> Result<(), String> {
+    check_offline(&state, "export").await?;
+    match out {
+        Some(path) => {
+            let file = File::create(&path).map_err(|e| format!("creating {}: {}", path.display(), e))?;
+            let written = export::write(&*state, format, &mut BufWriter::new(file))
+                .await
+                .map_err(|e| format!("writing {}: {}", path.display(), e))?;
+            eprintln!("exported {} books to {}", written, path.display());
+        }
+        None => {
+            export::write(&*state, format, &mut io::stdout().lock()).await?;
+        }
+    }
+    Ok(())
+}
+
+// Rows that fail are listed by line and fail the command; the others are
+// imported all the same, as with the endpoint.
+pub async fn import(state: SharedState, file: PathBuf) -> Result<(), String> {
+    check_offline(&state, "import").await?;
+    let kind = file
+        .to_str()
+        .and_then(import::Kind::of_extension)
+        .ok_or_else(|| format!("{} has to end in .csv or .json", file.display()))?;
+    let bytes = std::fs::read(&file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+    let report = import::import(&*state, kind, &bytes, usize::MAX)
+        .await
+        .map_err(|e| format!("{}: {}", file.display(), e))?;
+    for outcome in &report.rows {
+        if let Some(error) = &outcome.error {
+            eprintln!("{}:{}: {}", file.display(), outcome.line, error);
+        }
+    }
+    snapshot::save(&state).await.map_err(|e| format!("writing the snapshot failed: {}", e))?;
+    eprintln!("imported {} books from {}", report.imported, file.display());
+    match report.failed {
+        0 => Ok(()),
+        failed => Err(format!("{} rows were not imported", failed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(args: &str) -> Result<(Command, Overrides), String> {
+        parse(args.split_whitespace().map(str::to_string))
+    }
+
+    fn overrides(args: &str) -> Overrides {
+        parsed(args).unwrap().1
+    }
+
+    fn error(args: &str) -> String {
+        parsed(args).err().unwrap()
+    }
+
+    #[test]
+    fn serve_is_the_default() {
+        assert!(matches!(parsed(""), Ok((Command::Serve, overrides)) if overrides.is_empty()));
+        assert_eq!(
+            overrides("--port 8080 --addr=0.0.0.0:80 --storage sqlite:books.db --seed=seed.json"),
+            [
+                ("DOJO_PORT", "8080".to_string()),
+                ("DOJO_ADDR", "0.0.0.0:80".to_string()),
+                ("DOJO_STORAGE", "sqlite:books.db".to_string()),
+                ("DOJO_SEED_FILE", "seed.json".to_string()),
+            ]
+        );
+        // Only the first = splits an inline value.
+        assert_eq!(overrides("serve --storage=redis://:a=b@cache"), [("DOJO_STORAGE", "redis://:a=b@cache".to_string())]);
+        assert!(matches!(parsed("serve --port 1 -h"), Ok((Command::Help, _))));
+        assert!(matches!(parsed("help"), Ok((Command::Help, _))));
+    }
+
+    #[test]
+    fn export_takes_its_format_from_the_file() {
+        let export = |args: &str| match parsed(args) {
+            Ok((Command::Export { out, format }, _)) => (out.map(|out| out.display().to_string()), format),
+            _ => panic!("{:?} isn't an export", args),
+        };
+        assert!(matches!(export("export"), (None, export::Format::Json)));
+        assert!(matches!(export("export --out books.csv"), (Some(out), export::Format::Csv) if out == "books.csv"));
+        assert!(matches!(export("export --out books.json"), (_, export::Format::Json)));
+        assert!(matches!(export("export --out books.csv --format json"), (_, export::Format::Json)));
+        assert!(matches!(export("export --format=csv"), (None, export::Format::Csv)));
+        assert_eq!(overrides("export --config prod.toml --out books.csv"), [("DOJO_CONFIG", "prod.toml".to_string())]);
+    }
+
+    #[test]
+    fn import_takes_one_file() {
+        let file = match parsed("import --storage sqlite books.csv") {
+            Ok((Command::Import { file }, overrides)) => {
+                assert_eq!(overrides, [("DOJO_STORAGE", "sqlite".to_string())]);
+                file
+            }
+            _ => panic!("not an import"),
+        };
+        assert_eq!(file, PathBuf::from("books.csv"));
+        assert_eq!(error("import"), "import needs the file to read");
+        assert_eq!(error("import a.csv b.csv"), "unexpected argument \"b.csv\"");
+    }
+
+    #[test]
+    fn mistakes_are_explained() {
+        let cases = [
+            ("start", "unknown command \"start\""),
+            ("serve books.csv", "unexpected argument \"books.csv\""),
+            ("--verbose", "unknown option --verbose"),
+            ("--port", "--port needs a value"),
+            ("--port=", "--port must be a port number, got \"\""),
+            ("--port 99999", "--port must be a port number, got \"99999\""),
+            ("--out books.json", "--out doesn't apply to serve"),
+            ("export --port 80", "--port doesn't apply to export"),
+            ("import books.csv --seed seed.json", "--seed doesn't apply to import"),
+            ("export --format xml", "--format must be json or csv, got \"xml\""),
+        ];
+        for (args, message) in cases {
+            assert_eq!(error(args), message, "{}", args);
+        }
+    }
+
+    #[tokio::test]
+    async fn offline_commands_need_somewhere_to_store() {
+        let sim = crate::sim::Sim::new(1);
+        let error = check_offline(&sim.state, "export").await.unwrap_err();
+        assert!(error.starts_with("export needs the catalog to be stored somewhere"), "{}", error);
+    }
+}
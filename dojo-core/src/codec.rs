@@ -0,0 +1,107 @@
+// Base64 and hex, shared by everything that writes bytes as text: SCRAM
+// and the WebSocket handshake, wallets, JWTs, hashes, tags and cursors.
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn encode(alphabet: &[u8; 64], data: &[u8], padded: bool) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(alphabet[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false if padded => out.push('='),
+                false => {}
+            }
+        }
+    }
+    out
+}
+
+fn decode(alphabet: &[u8; 64], text: impl Iterator<Item = u8>) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in text {
+        let value = alphabet.iter().position(|b| *b == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    encode(BASE64, data, true)
+}
+
+pub fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    decode(BASE64, text.bytes().filter(|c| *c != b'=')).ok_or_else(|| "invalid base64".to_string())
+}
+
+// JWTs use base64url without padding.
+#[cfg(test)]
+pub fn base64url_encode(data: &[u8]) -> String {
+    encode(BASE64URL, data, false)
+}
+
+pub fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    decode(BASE64URL, text.bytes())
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unhex(text: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would take a sign, as in "+f".
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: [&[u8]; 6] = [b"", b"f", b"fo", b"foo", b"foob", &[0, 255, 128, 7, 251]];
+
+    #[test]
+    fn base64_round_trips() {
+        for data in SAMPLES {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+            assert_eq!(base64url_decode(&base64url_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(&[251, 255]), "+/8=");
+        assert_eq!(base64url_encode(&[251, 255]), "-_8");
+    }
+
+    #[test]
+    fn each_base64_refuses_the_other_alphabet() {
+        assert_eq!(base64_decode("-_8").unwrap_err(), "invalid base64");
+        assert_eq!(base64url_decode("+/8"), None);
+        assert_eq!(base64url_decode("Zm8="), None);
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        for data in SAMPLES {
+            assert_eq!(unhex(&hex(data)).unwrap(), data);
+        }
+        assert_eq!(hex(&[0, 10, 255]), "000aff");
+        assert_eq!(unhex("000AFF").unwrap(), [0, 10, 255]);
+    }
+
+    #[test]
+    fn unhex_refuses_anything_but_pairs_of_digits() {
+        for text in ["0", "0g", "+1", "é"] {
+            assert_eq!(unhex(text), None, "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::{SharedState, Storage};
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct CompactionReport {
+    pub records: usize,
+    pub capacity_before: usize,
+    pub capacity_after: usize,
+    pub reclaimed_bytes: usize,
+}
+
+// The catalog is a BTreeMap, whose nodes hold exactly the books in it, so
+// the capacities are the record count and only string slack is reclaimed.
+pub fn compact(storage: &mut Storage) -> CompactionReport {
+    let capacity_before = storage.books.len();
+    let mut reclaimed_bytes = 0;
+
+    for book in storage.books.values_mut() {
+        reclaimed_bytes += shrink(&mut book.title) + shrink(&mut book.author);
+        if let Some(isbn) = book.isbn.as_mut() {
+            reclaimed_bytes += shrink(isbn);
+        }
+    }
+
+    CompactionReport {
+        records: storage.books.len(),
+        capacity_before,
+        capacity_after: storage.books.len(),
+        reclaimed_bytes,
+    }
+}
+
+fn shrink(value: &mut String) -> usize {
+    let before = value.capacity();
+    value.shrink_to_fit();
+    before - value.capacity()
+}
+
+pub async fn run_scheduled(state: SharedState, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let report = match state.with_storage("compact", String::new, compact).await {
+            Ok(report) => report,
+            Err(e) => {
+                tracing::warn!("scheduled compaction failed: {}", e);
+                continue;
+            }
+        };
+        if report.reclaimed_bytes > 0 {
+            tracing::info!(
+                "compaction reclaimed {} bytes ({} records)",
+                report.reclaimed_bytes, report.records
+            );
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use flate2::{write::GzEncoder, Compression as Level};
+use http_body::Body as HttpBody;
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Method, Request, Response, StatusCode,
+};
+use std::io::Write;
+
+use crate::{
+    body::{self, Body},
+    stack::Next,
+    SharedState,
+};
+
+// Gzip for responses a client accepts it for. DOJO_COMPRESS_MIN_BYTES
+// (1024 by default) leaves smaller bodies alone, as the gzip framing
+// would outweigh what it saves.
+pub struct Compression {
+    min_bytes: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression { min_bytes: 1024 }
+    }
+}
+
+impl Compression {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("DOJO_COMPRESS_MIN_BYTES") {
+            Ok(bytes) => bytes
+                .parse()
+                .map(|min_bytes| Compression { min_bytes })
+                .map_err(|_| format!("DOJO_COMPRESS_MIN_BYTES must be a number of bytes, got {:?}", bytes)),
+            Err(_) => Ok(Compression::default()),
+        }
+    }
+}
+
+// Whether Accept-Encoding lets the response be gzipped: `gzip` or `*`
+// with a q-value above zero, `gzip` taking precedence.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let mut wildcard = None;
+    for coding in headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            return q > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(q > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+// JSON of any flavour and text compress well; images and the binary
+// codecs mostly don't. An event stream is left alone, as some clients
+// and proxies wait for a gzip stream to fill a buffer.
+fn compressible(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    let media = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media == "application/json" || media.ends_with("+json") || (media.starts_with("text/") && media != "text/event-stream")
+}
+
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let gzip = accepts_gzip(req.headers()) && req.method() != Method::HEAD;
+    let mut response = next.run(req, state.clone()).await?;
+    let status = response.status();
+    let headers = response.headers_mut();
+    if !compressible(headers) || headers.contains_key(header::CONTENT_ENCODING) {
+        return Ok(response);
+    }
+    // Caches have to keep the compressed and plain copies apart.
+    headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    if !gzip || matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED) {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match body.size_hint().exact() {
+        Some(size) if size < state.compression.min_bytes => return Ok(Response::from_parts(parts, body)),
+        Some(_) => {
+            let bytes = body::to_bytes(body).await?;
+            let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+            encoder.write_all(&bytes).expect("writing to a Vec can't fail");
+            let compressed = encoder.finish().expect("writing to a Vec can't fail");
+            parts.headers.insert(header::CONTENT_LENGTH, compressed.len().into());
+            Body::from(compressed)
+        }
+        None => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            stream(body)
+        }
+    };
+    parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    Ok(Response::from_parts(parts, body))
+}
+
+// A streamed body, such as an export, is compressed a chunk at a time
+// and each chunk flushed, so the client gets data as it's produced.
+fn stream(mut body: Body) -> Body {
+    let (mut sender, compressed) = Body::channel();
+    tokio::spawn(async move {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        loop {
+            match body.data().await {
+                Some(Ok(chunk)) => {
+                    encoder.write_all(&chunk).expect("writing to a Vec can't fail");
+                    encoder.flush().expect("writing to a Vec can't fail");
+                }
+                Some(Err(_)) => {
+                    sender.abort();
+                    return;
+                }
+                None => break,
+            }
+            let out = std::mem::take(encoder.get_mut());
+            if !out.is_empty() && sender.send_data(out.into()).await.is_err() {
+                return;
+            }
+        }
+        let out = encoder.finish().expect("writing to a Vec can't fail");
+        let _ = sender.send_data(out.into()).await;
+    });
+    compressed
+}
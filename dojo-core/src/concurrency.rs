@@ -0,0 +1,169 @@
+use http_body::Body as HttpBody;
+use hyper::{header, Method, Response, StatusCode};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    body::{self, Body},
+    problem,
+    stack::ResponseFuture,
+};
+
+struct Limit {
+    method: Method,
+    pattern: String,
+    running: usize,
+    queue: usize,
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+// Counts a request as waiting for as long as it does, even when it's
+// cancelled.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// DOJO_CONCURRENCY_LIMITS caps how many requests to a route run at once,
+// as `;`-separated `<METHOD> <route>=<running>[:<queue>]` entries naming
+// routes as they're registered, without a version prefix, like
+// `POST /books/import=2;GET /books/export=1:4`. Up to `queue` more
+// requests wait for a turn, and past that they're answered 503 with
+// Retry-After. A streamed response holds its turn until it's sent. Routes
+// without an entry aren't limited.
+#[derive(Default)]
+pub struct Limits(Vec<Arc<Limit>>);
+
+impl Limits {
+    // `known` tells whether a route is registered, so a typo doesn't go
+    // unnoticed.
+    pub fn from_env(known: impl Fn(&Method, &str) -> bool) -> Result<Self, String> {
+        match std::env::var("DOJO_CONCURRENCY_LIMITS") {
+            Ok(spec) => Self::parse(&spec, known),
+            Err(_) => Ok(Limits::default()),
+        }
+    }
+
+    fn parse(spec: &str, known: impl Fn(&Method, &str) -> bool) -> Result<Self, String> {
+        let mut limits = Vec::new();
+        for entry in spec.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let invalid = || format!("invalid concurrency limit {:?}, expected <METHOD> <route>=<running>[:<queue>]", entry);
+            let (route, counts) = entry.rsplit_once('=').ok_or_else(invalid)?;
+            let (method, pattern) = route.trim().split_once(' ').ok_or_else(invalid)?;
+            let method: Method = method.parse().map_err(|_| invalid())?;
+            let pattern = pattern.trim();
+            let (running, queue) = counts.split_once(':').unwrap_or((counts, "0"));
+            let running: usize = running.trim().parse().ok().filter(|running| *running > 0).ok_or_else(invalid)?;
+            let queue: usize = queue.trim().parse().map_err(|_| invalid())?;
+            if !known(&method, pattern) {
+                return Err(format!("concurrency limit for {} {}, which isn't a route", method, pattern));
+            }
+            limits.push(Arc::new(Limit {
+                method,
+                pattern: pattern.to_string(),
+                running,
+                queue,
+                permits: Arc::new(Semaphore::new(running)),
+                waiting: AtomicUsize::new(0),
+            }));
+        }
+        Ok(Limits(limits))
+    }
+
+    // Runs the route's handler once it has a turn.
+    pub fn run(
+        &self,
+        method: &Method,
+        pattern: &str,
+        response: impl Future<Output = Result<Response<Body>, body::Error>> + Send + 'static,
+    ) -> ResponseFuture {
+        let Some(limit) = self.0.iter().find(|limit| limit.method == method && limit.pattern == pattern).cloned() else {
+            return Box::pin(response);
+        };
+        Box::pin(async move {
+            let permit = match limit.permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    if limit.waiting.fetch_add(1, Ordering::Relaxed) >= limit.queue {
+                        limit.waiting.fetch_sub(1, Ordering::Relaxed);
+                        return Ok(queue_full(&limit));
+                    }
+                    let _waiting = Waiting(&limit.waiting);
+                    limit.permits.clone().acquire_owned().await.expect("the semaphore is never closed")
+                }
+            };
+            Ok(holding(permit, response.await?))
+        })
+    }
+}
+
+fn queue_full(limit: &Limit) -> Response<Body> {
+    let detail = format!(
+        "{} {} is running {} requests and its queue of {} is full",
+        limit.method, limit.pattern, limit.running, limit.queue
+    );
+    let mut response = problem::respond(StatusCode::SERVICE_UNAVAILABLE, detail);
+    response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(1));
+    response
+}
+
+// Keeps the turn until a streamed body is sent, or the client goes.
+fn holding(permit: OwnedSemaphorePermit, response: Response<Body>) -> Response<Body> {
+    if response.body().size_hint().exact().is_some() {
+        return response;
+    }
+    let (parts, mut body) = response.into_parts();
+    let (mut sender, relayed) = Body::channel();
+    tokio::spawn(async move {
+        let _permit = permit;
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                sender.abort();
+                return;
+            };
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+    Response::from_parts(parts, relayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_past_the_queue_are_refused() {
+        let limits = Limits::parse("POST /books/import=1:1", |_, pattern| pattern == "/books/import").unwrap();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let slow = tokio::spawn(limits.run(&Method::POST, "/books/import", async {
+            let _ = released.await;
+            Ok(Response::new(Body::from("done")))
+        }));
+        tokio::task::yield_now().await;
+        let queued = tokio::spawn(limits.run(&Method::POST, "/books/import", async { Ok(Response::new(Body::empty())) }));
+        tokio::task::yield_now().await;
+
+        let refused = limits.run(&Method::POST, "/books/import", async { Ok(Response::new(Body::empty())) }).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(refused.headers()[header::RETRY_AFTER], "1");
+        let other = limits.run(&Method::GET, "/books", async { Ok(Response::new(Body::empty())) }).await.unwrap();
+        assert_eq!(other.status(), StatusCode::OK);
+
+        release.send(()).unwrap();
+        assert_eq!(slow.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(queued.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert!(Limits::parse("POST /books/nope=1", |_, pattern| pattern == "/books/import").is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use http_body::Body as HttpBody;
+use hyper::{header, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    body::{self, Body},
+    etag,
+    extract::{FromRequest, RequestContext},
+};
+
+#[cfg(not(test))]
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+// In tests the wall clock moves with tokio's, so a paused simulation can
+// step it forward with `Sim::advance` instead of sleeping.
+#[cfg(test)]
+pub fn now_ms() -> u64 {
+    thread_local! {
+        static START: (SystemTime, tokio::time::Instant) = (SystemTime::now(), tokio::time::Instant::now());
+    }
+    let (wall, instant) = START.with(|start| *start);
+    let now = wall + tokio::time::Instant::now().saturating_duration_since(instant);
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+// When each book in the in-memory catalog, and the catalog as a whole, last
+// changed. Nothing of it is kept across restarts: a book that hasn't
+// changed since the catalog was loaded counts as changed when it was.
+pub struct Clock {
+    books: HashMap<u64, u64>,
+    since: u64,
+    catalog: u64,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        let now = now_ms();
+        Clock {
+            books: HashMap::new(),
+            since: now,
+            catalog: now,
+        }
+    }
+}
+
+impl Clock {
+    // Never earlier than the last change, so a clock set back doesn't make
+    // a client's copy look newer than it is.
+    fn tick(&mut self) -> u64 {
+        self.catalog = now_ms().max(self.catalog);
+        self.catalog
+    }
+
+    pub fn touch(&mut self, id: u64) {
+        let now = self.tick();
+        self.books.insert(id, now);
+    }
+
+    pub fn forget(&mut self, id: u64) {
+        self.tick();
+        self.books.remove(&id);
+    }
+
+    pub fn book(&self, id: u64) -> u64 {
+        self.books.get(&id).copied().unwrap_or(self.since)
+    }
+
+    pub fn catalog(&self) -> u64 {
+        self.catalog
+    }
+}
+
+// If-None-Match and If-Modified-Since, which let a client polling a GET be
+// answered 304 while its copy is current. If-None-Match wins when both are
+// sent, since Last-Modified only has whole seconds.
+#[derive(Default)]
+pub struct Conditional {
+    none_match: Option<Vec<String>>,
+    modified_since: Option<SystemTime>,
+}
+
+impl FromRequest for Conditional {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let headers = ctx.headers();
+        let mut tags: Vec<String> = Vec::new();
+        for value in headers.get_all(header::IF_NONE_MATCH) {
+            let value = value.to_str().unwrap_or_default();
+            tags.extend(value.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(str::to_string));
+        }
+        // A date that doesn't parse is ignored, as if it weren't sent.
+        let modified_since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok());
+        Ok(Conditional {
+            none_match: (!tags.is_empty()).then_some(tags),
+            modified_since,
+        })
+    }
+}
+
+impl Conditional {
+    // For a response its ETag doesn't wholly cover, which only
+    // If-Modified-Since can tell is unchanged.
+    pub fn by_date(self) -> Self {
+        Conditional {
+            none_match: None,
+            ..self
+        }
+    }
+
+    // Adds Last-Modified to a 200 and an ETag hashed from its body if it has
+    // none, then answers 304 instead when the client's copy is current.
+    // `modified` is in milliseconds since the epoch. A streamed body isn't
+    // read to be hashed, so only its date can tell it's unchanged.
+    pub async fn respond(&self, response: Response<Body>, modified: Option<u64>) -> Response<Body> {
+        if response.status() != StatusCode::OK {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        let streamed = body.size_hint().exact().is_none();
+        let body = match parts.headers.contains_key(header::ETAG) || streamed {
+            true => body,
+            false => {
+                let bytes = body::to_bytes(body).await.unwrap_or_default();
+                if let Ok(value) = etag::hash(&bytes).parse() {
+                    parts.headers.insert(header::ETAG, value);
+                }
+                Body::from(bytes)
+            }
+        };
+        let modified = modified.map(|ms| UNIX_EPOCH + Duration::from_secs(ms / 1000));
+        if let Some(modified) = modified {
+            if let Ok(value) = httpdate::fmt_http_date(modified).parse() {
+                parts.headers.insert(header::LAST_MODIFIED, value);
+            }
+        }
+        let etag = parts.headers.get(header::ETAG).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let fresh = match (&self.none_match, self.modified_since, modified) {
+            (Some(tags), _, _) => tags.iter().any(|tag| tag == "*" || weak(tag) == weak(etag)),
+            (None, Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        };
+        if !fresh {
+            return Response::from_parts(parts, body);
+        }
+        let mut not_modified = Response::builder().status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+        for name in [header::ETAG, header::LAST_MODIFIED, header::VARY, header::CACHE_CONTROL] {
+            if let Some(value) = parts.headers.get(&name) {
+                not_modified.headers_mut().insert(name, value.clone());
+            }
+        }
+        not_modified
+    }
+}
+
+// If-None-Match compares tags weakly: W/"x" and "x" are the same.
+fn weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
@@ -0,0 +1,639 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+// Every setting the server reads is an environment variable. A config file
+// is another way to give them: each key below stands for the variable next
+// to it, and a variable that's already set wins over the file, so one
+// deployment can share a file and still override a setting or two.
+//
+// The file is `config.toml` in the working directory if there is one, or
+// whatever DOJO_CONFIG names, which then has to exist. It's the part of
+// TOML a settings file needs: `[section]` headers, `key = value` lines,
+// strings, integers, decimals, booleans, arrays and `#` comments.
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    // Zero or more.
+    Integer,
+    // A fraction between 0 and 1.
+    Rate,
+    // Sets the variable to `true`; `false` leaves it unset.
+    Flag,
+    // An array of strings, joined with the separator the variable expects.
+    // A single string is taken as it is.
+    List(char),
+}
+
+use Kind::*;
+
+// A key, the variable it sets and what it takes.
+type Setting = (&'static str, &'static str, Kind);
+
+const SETTINGS: &[(&str, &[Setting])] = &[
+    (
+        "server",
+        &[
+            ("addr", "DOJO_ADDR", Text),
+            ("port", "DOJO_PORT", Integer),
+            ("listen", "DOJO_LISTEN", List(';')),
+            ("unix_socket", "DOJO_UNIX_SOCKET", Text),
+            ("unix_socket_mode", "DOJO_UNIX_SOCKET_MODE", Text),
+            ("reuse_port", "DOJO_REUSE_PORT", Flag),
+            ("runner", "DOJO_RUNNER", Text),
+            ("public_url", "DOJO_PUBLIC_URL", Text),
+            ("hosts", "DOJO_HOSTS", List(';')),
+            ("trusted_proxies", "DOJO_TRUSTED_PROXIES", List(',')),
+            ("node_name", "DOJO_NODE_NAME", Text),
+            ("shutdown_timeout_secs", "DOJO_SHUTDOWN_TIMEOUT_SECS", Integer),
+            ("keep_alive_secs", "DOJO_KEEP_ALIVE_SECS", Integer),
+            ("http2_ping_secs", "DOJO_HTTP2_PING_SECS", Integer),
+            ("http2_max_streams", "DOJO_HTTP2_MAX_STREAMS", Integer),
+            ("request_timeout_secs", "DOJO_REQUEST_TIMEOUT_SECS", Integer),
+            ("concurrency_limits", "DOJO_CONCURRENCY_LIMITS", List(';')),
+            ("contract_check", "DOJO_CONTRACT_CHECK", Flag),
+            ("jsonapi", "DOJO_JSONAPI", Flag),
+        ],
+    ),
+    (
+        "tls",
+        &[
+            ("addr", "DOJO_TLS_ADDR", Text),
+            ("cert", "DOJO_TLS_CERT", Text),
+            ("key", "DOJO_TLS_KEY", Text),
+        ],
+    ),
+    ("grpc", &[("addr", "DOJO_GRPC_ADDR", Text)]),
+    ("covers", &[("dir", "DOJO_COVERS_DIR", Text), ("max_bytes", "DOJO_COVER_MAX_BYTES", Integer)]),
+    (
+        "openlibrary",
+        &[("url", "DOJO_OPENLIBRARY_URL", Text), ("timeout_secs", "DOJO_OPENLIBRARY_TIMEOUT_SECS", Integer)],
+    ),
+    (
+        "multiversx",
+        &[
+            ("gateway", "DOJO_MX_GATEWAY", Text),
+            ("chain_id", "DOJO_MX_CHAIN_ID", Text),
+            ("timeout_secs", "DOJO_MX_TIMEOUT_SECS", Integer),
+            ("pem_file", "DOJO_MX_PEM", Text),
+            ("keystore_file", "DOJO_MX_KEYSTORE", Text),
+            ("keystore_password", "DOJO_MX_KEYSTORE_PASSWORD", Text),
+            ("collection", "DOJO_MX_COLLECTION", Text),
+        ],
+    ),
+    (
+        "logging",
+        &[
+            ("level", "DOJO_LOG", Text),
+            ("format", "DOJO_LOG_FORMAT", Text),
+            ("slow_storage_ms", "DOJO_SLOW_STORAGE_MS", Integer),
+            ("otlp_endpoint", "DOJO_OTLP_ENDPOINT", Text),
+            ("access_log", "DOJO_ACCESS_LOG", Text),
+            ("access_log_format", "DOJO_ACCESS_LOG_FORMAT", Text),
+            ("access_log_max_bytes", "DOJO_ACCESS_LOG_MAX_BYTES", Integer),
+            ("access_log_files", "DOJO_ACCESS_LOG_FILES", Integer),
+        ],
+    ),
+    (
+        "storage",
+        &[
+            ("backend", "DOJO_STORAGE", Text),
+            ("database_url", "DATABASE_URL", Text),
+            ("database_pool_size", "DOJO_DATABASE_POOL_SIZE", Integer),
+            ("redis_pool_size", "DOJO_REDIS_POOL_SIZE", Integer),
+            ("redis_prefix", "DOJO_REDIS_PREFIX", Text),
+            ("cache_entries", "DOJO_CACHE_ENTRIES", Integer),
+            ("cache_ttl_secs", "DOJO_CACHE_TTL_SECS", Integer),
+            ("wal_file", "DOJO_WAL_FILE", Text),
+            ("audit_file", "DOJO_AUDIT_FILE", Text),
+            ("snapshot_file", "DOJO_SNAPSHOT_FILE", Text),
+            ("snapshot_interval_secs", "DOJO_SNAPSHOT_INTERVAL_SECS", Integer),
+            ("seed_file", "DOJO_SEED_FILE", Text),
+            ("max_tenants", "DOJO_MAX_TENANTS", Integer),
+            ("id_strategy", "DOJO_ID_STRATEGY", Text),
+            ("compact_interval_secs", "DOJO_COMPACT_INTERVAL_SECS", Integer),
+            ("merkle_anchor_interval_secs", "DOJO_MERKLE_ANCHOR_INTERVAL_SECS", Integer),
+        ],
+    ),
+    (
+        "auth",
+        &[
+            ("admin_token", "DOJO_ADMIN_TOKEN", Text),
+            ("api_keys", "DOJO_API_KEYS", List(',')),
+            ("read_keys", "DOJO_API_READ_KEYS", List(',')),
+            ("key_reads", "DOJO_API_KEY_READS", Text),
+            ("jwt_secret", "DOJO_JWT_SECRET", Text),
+            ("jwt_issuer", "DOJO_JWT_ISSUER", Text),
+            ("jwt_audience", "DOJO_JWT_AUDIENCE", Text),
+            ("jwt_reads", "DOJO_JWT_READS", Text),
+            ("kiosk_tokens", "DOJO_KIOSK_TOKENS", List(',')),
+        ],
+    ),
+    (
+        "cors",
+        &[
+            ("origins", "DOJO_CORS_ORIGINS", List(',')),
+            ("methods", "DOJO_CORS_METHODS", List(',')),
+            ("headers", "DOJO_CORS_HEADERS", List(',')),
+            ("max_age", "DOJO_CORS_MAX_AGE", Integer),
+            ("credentials", "DOJO_CORS_CREDENTIALS", Flag),
+        ],
+    ),
+    ("compression", &[("min_bytes", "DOJO_COMPRESS_MIN_BYTES", Integer)]),
+    (
+        "rate_limit",
+        &[
+            ("rps", "DOJO_RATE_LIMIT_RPS", Text),
+            ("burst", "DOJO_RATE_LIMIT_BURST", Integer),
+            ("by", "DOJO_RATE_LIMIT_BY", Text),
+            ("prefix", "DOJO_RATE_LIMIT_PREFIX", Text),
+            ("redis", "DOJO_RATE_LIMIT_REDIS", Text),
+        ],
+    ),
+    (
+        "cluster",
+        &[
+            ("node", "DOJO_CLUSTER_NODE", Text),
+            ("peers", "DOJO_CLUSTER_PEERS", List(',')),
+            ("secret", "DOJO_CLUSTER_SECRET", Text),
+            ("gossip_interval_ms", "DOJO_GOSSIP_INTERVAL_MS", Integer),
+        ],
+    ),
+    (
+        "raft",
+        &[
+            ("node", "DOJO_RAFT_NODE", Text),
+            ("members", "DOJO_RAFT_MEMBERS", List(',')),
+            ("secret", "DOJO_RAFT_SECRET", Text),
+            ("heartbeat_ms", "DOJO_RAFT_HEARTBEAT_MS", Integer),
+            ("snapshot_every", "DOJO_RAFT_SNAPSHOT_EVERY", Integer),
+            ("dir", "DOJO_RAFT_DIR", Text),
+        ],
+    ),
+    (
+        "shard",
+        &[
+            ("node", "DOJO_SHARD_NODE", Text),
+            ("members", "DOJO_SHARD_MEMBERS", List(',')),
+            ("routing", "DOJO_SHARD_ROUTING", Text),
+        ],
+    ),
+    (
+        "leader",
+        &[("lock", "DOJO_LEADER_LOCK", Text), ("ttl_ms", "DOJO_LEADER_TTL_MS", Integer)],
+    ),
+    (
+        "events",
+        &[("broker", "DOJO_EVENTS_BROKER", Text), ("topics", "DOJO_EVENTS_TOPICS", List(','))],
+    ),
+    (
+        "federation",
+        &[("name", "DOJO_FEDERATION_NAME", Text), ("peers", "DOJO_FEDERATION_PEERS", List(','))],
+    ),
+    ("webhooks", &[("hooks", "DOJO_WEBHOOKS", List(','))]),
+    (
+        "debug",
+        &[
+            ("history", "DOJO_DEBUG_HISTORY", Integer),
+            ("capture_file", "DOJO_CAPTURE_FILE", Text),
+            ("capture", "DOJO_DEBUG_CAPTURE", Integer),
+            ("capture_redact", "DOJO_CAPTURE_REDACT", List(',')),
+            ("pprof", "DOJO_ENABLE_PPROF", Flag),
+        ],
+    ),
+    (
+        "chaos",
+        &[
+            ("seed", "DOJO_CHAOS_SEED", Integer),
+            ("latency_rate", "DOJO_CHAOS_LATENCY_RATE", Rate),
+            ("latency_ms", "DOJO_CHAOS_LATENCY_MS", Integer),
+            ("storage_error_rate", "DOJO_CHAOS_STORAGE_ERROR_RATE", Rate),
+        ],
+    ),
+];
+
+// What was loaded, for the startup log once logging is set up.
+pub struct Loaded {
+    pub path: PathBuf,
+    pub applied: usize,
+    pub overridden: Vec<&'static str>,
+}
+
+// Reads the config file, if there is one, into the environment. It has to
+// run before anything reads a setting, and before other threads start, as
+// it sets environment variables.
+pub fn load() -> Result<Option<Loaded>, String> {
+    let path = match env::var("DOJO_CONFIG") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) if Path::new("config.toml").is_file() => PathBuf::from("config.toml"),
+        Err(_) => return Ok(None),
+    };
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let settings = parse(&text).map_err(|(line, message)| format!("{}:{}: {}", path.display(), line, message))?;
+    let mut loaded = Loaded {
+        path,
+        applied: 0,
+        overridden: Vec::new(),
+    };
+    for (var, value) in settings {
+        if env::var_os(var).is_some() {
+            loaded.overridden.push(var);
+        } else if let Some(value) = value {
+            env::set_var(var, value);
+            loaded.applied += 1;
+        }
+    }
+    Ok(Some(loaded))
+}
+
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a decimal",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+// The value a key gives its variable, None for a flag that's off, or what
+// was expected instead.
+fn render(kind: Kind, value: Value) -> Result<Option<String>, String> {
+    match (kind, value) {
+        (Text, Value::String(text)) => Ok(Some(text)),
+        (Text, Value::Integer(n)) => Ok(Some(n.to_string())),
+        (Text, Value::Float(n)) => Ok(Some(n.to_string())),
+        (Integer, Value::Integer(n)) if n >= 0 => Ok(Some(n.to_string())),
+        (Integer, _) => Err("must be an integer of zero or more".to_string()),
+        (Rate, Value::Integer(n)) if (0..=1).contains(&n) => Ok(Some(n.to_string())),
+        (Rate, Value::Float(n)) if (0.0..=1.0).contains(&n) => Ok(Some(n.to_string())),
+        (Rate, _) => Err("must be a number between 0 and 1".to_string()),
+        (Flag, Value::Boolean(on)) => Ok(on.then(|| "true".to_string())),
+        (Flag, _) => Err("must be true or false".to_string()),
+        (List(_), Value::String(text)) => Ok(Some(text)),
+        (List(separator), Value::Array(items)) => {
+            let items = items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(text) if !text.contains(separator) => Ok(text),
+                    Value::String(_) => Err(format!("has an entry containing {:?}, which separates entries", separator)),
+                    other => Err(format!("must be an array of strings, but has {}", other.describe())),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Some(items.join(&separator.to_string())))
+        }
+        (_, other) => Err(format!("must be a string, not {}", other.describe())),
+    }
+}
+
+type Error = (usize, String);
+
+// The variables a file sets, in the order they appear, each with its value
+// or None for a flag that's off. Errors carry the line they're on.
+fn parse(text: &str) -> Result<Vec<(&'static str, Option<String>)>, Error> {
+    let mut parser = Parser { text, pos: 0, line: 1 };
+    let mut section: Option<(&str, &[Setting])> = None;
+    let mut seen: Vec<(&str, &str)> = Vec::new();
+    let mut settings = Vec::new();
+    loop {
+        parser.skip_trivia();
+        let line = parser.line;
+        let Some(c) = parser.peek() else {
+            return Ok(settings);
+        };
+        if c == '[' {
+            parser.bump();
+            parser.skip_blank();
+            let name = parser.key().map_err(|message| (line, message))?;
+            parser.skip_blank();
+            if parser.bump() != Some(']') {
+                return Err((line, format!("expected ] after [{}", name)));
+            }
+            parser.end_of_line().map_err(|message| (line, message))?;
+            let keys = SETTINGS
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, keys)| *keys)
+                .ok_or_else(|| {
+                    let known: Vec<_> = SETTINGS.iter().map(|(known, _)| *known).collect();
+                    (line, format!("unknown section [{}], expected one of {}", name, known.join(", ")))
+                })?;
+            if seen.contains(&(name, "")) {
+                return Err((line, format!("[{}] appears more than once", name)));
+            }
+            seen.push((name, ""));
+            section = Some((name, keys));
+            continue;
+        }
+        let key = parser.key().map_err(|message| (line, message))?;
+        let Some((name, keys)) = section else {
+            return Err((line, format!("{} has to be in a section, like [server]", key)));
+        };
+        let Some((_, var, kind)) = keys.iter().find(|(known, _, _)| *known == key) else {
+            let known: Vec<_> = keys.iter().map(|(known, _, _)| *known).collect();
+            return Err((line, format!("unknown key {} in [{}], expected one of {}", key, name, known.join(", "))));
+        };
+        if seen.contains(&(name, key)) {
+            return Err((line, format!("{}.{} is set more than once", name, key)));
+        }
+        seen.push((name, key));
+        parser.skip_blank();
+        if parser.bump() != Some('=') {
+            return Err((line, format!("expected = after {}", key)));
+        }
+        parser.skip_blank();
+        let value = parser.value().map_err(|message| (parser.line, message))?;
+        parser.end_of_line().map_err(|message| (parser.line, message))?;
+        let value = render(*kind, value).map_err(|message| (line, format!("{}.{} {}", name, key, message)))?;
+        settings.push((*var, value));
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_blank(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    // Blank lines and comments, which may also come between array items.
+    fn skip_trivia(&mut self) {
+        loop {
+            self.skip_blank();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => {
+                    self.bump();
+                }
+                Some('\r') if self.text[self.pos..].starts_with("\r\n") => {
+                    self.bump();
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_blank();
+        self.skip_comment();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.text[self.pos..].starts_with("\r\n") => Ok(()),
+            Some(c) => Err(format!("unexpected {:?} after the value", c)),
+        }
+    }
+
+    fn key(&mut self) -> Result<&'a str, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            self.bump();
+        }
+        match &self.text[start..self.pos] {
+            "" => Err(match self.peek() {
+                Some(c) => format!("expected a key, found {:?}", c),
+                None => "expected a key".to_string(),
+            }),
+            key => Ok(key),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some(c) if c.is_ascii_alphanumeric() || matches!(c, '+' | '-') => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.')) {
+                    self.bump();
+                }
+                let word = &self.text[start..self.pos];
+                match word {
+                    "true" => return Ok(Value::Boolean(true)),
+                    "false" => return Ok(Value::Boolean(false)),
+                    _ => {}
+                }
+                // Underscores may only separate digits.
+                let digits = word.trim_start_matches(['+', '-']);
+                if digits.starts_with('_') || digits.ends_with('_') || word.contains("__") {
+                    return Err(format!("invalid number {}", word));
+                }
+                let number = word.replace('_', "");
+                if let Ok(n) = number.parse() {
+                    Ok(Value::Integer(n))
+                } else if number.contains('.') && !number.ends_with('.') {
+                    number.parse().map(Value::Float).map_err(|_| format!("invalid number {}", word))
+                } else if word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                    Err(format!("{} needs quotes to be a string", word))
+                } else {
+                    Err(format!("invalid number {}", word))
+                }
+            }
+            Some(c) => Err(format!("expected a value, found {:?}", c)),
+            None => Err("expected a value".to_string()),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut out = String::new();
+        // A string cut short by the end of its line leaves the newline
+        // alone, so the error is reported on the string's own line.
+        while let Some(c) = self.peek().filter(|c| *c != '\n') {
+            self.bump();
+            match c {
+                '"' => return Ok(out),
+                '\\' => out.push(match self.bump() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(kind @ ('u' | 'U')) => {
+                        let len = if kind == 'u' { 4 } else { 8 };
+                        let hex = self.text.get(self.pos..self.pos + len).unwrap_or_default();
+                        let c = u32::from_str_radix(hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape \\{}{}", kind, hex))?;
+                        self.pos += len;
+                        c
+                    }
+                    Some(c) => return Err(format!("invalid escape \\{}", c)),
+                    None => return Err("unterminated string".to_string()),
+                }),
+                c => out.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.bump();
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|c| *c != '\n') {
+            self.bump();
+            if c == '\'' {
+                return Ok(self.text[start..self.pos - 1].to_string());
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    // Items may be spread over lines, with a trailing comma.
+    fn array(&mut self) -> Result<Value, String> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip_trivia();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_trivia();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                Some(c) => return Err(format!("expected , or ] in an array, found {:?}", c)),
+                None => return Err("unterminated array".to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(text: &str) -> Vec<(&'static str, Option<String>)> {
+        parse(text).unwrap()
+    }
+
+    fn error(text: &str) -> (usize, String) {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn files_set_variables() {
+        let text = "\
+# Settings for the staging box.\r
+[server]\r
+port = 8_080   # behind the proxy\r
+addr = '0.0.0.0'\r
+reuse_port = true\r
+contract_check = false\r
+
+[cors]
+origins = [
+    \"https://a.example\",  # the shop
+    \"https://b.example\",
+]
+methods = \"GET,POST\"
+
+[chaos]
+latency_rate = 0.25
+storage_error_rate = 1
+
+[auth]
+admin_token = \"tab\\there \\\"quoted\\\" \\u00e9\\U0001F4DA\"
+";
+        let expected = [
+            ("DOJO_PORT", Some("8080")),
+            ("DOJO_ADDR", Some("0.0.0.0")),
+            ("DOJO_REUSE_PORT", Some("true")),
+            ("DOJO_CONTRACT_CHECK", None),
+            ("DOJO_CORS_ORIGINS", Some("https://a.example,https://b.example")),
+            ("DOJO_CORS_METHODS", Some("GET,POST")),
+            ("DOJO_CHAOS_LATENCY_RATE", Some("0.25")),
+            ("DOJO_CHAOS_STORAGE_ERROR_RATE", Some("1")),
+            ("DOJO_ADMIN_TOKEN", Some("tab\there \"quoted\" é📚")),
+        ];
+        let expected: Vec<_> = expected.iter().map(|(var, value)| (*var, value.map(str::to_string))).collect();
+        assert_eq!(settings(text), expected);
+        assert_eq!(settings(""), []);
+    }
+
+    #[test]
+    fn the_example_file_parses() {
+        let example = include_str!("../../week-1/config.example.toml");
+        assert!(!settings(example).is_empty());
+    }
+
+    #[test]
+    fn mistakes_point_at_their_line() {
+        let cases = [
+            ("port = 1", (1, "port has to be in a section, like [server]")),
+            ("[servers]", (1, "unknown section [servers], expected one of")),
+            ("[server]\n[server]", (2, "[server] appears more than once")),
+            ("[server\n", (1, "expected ] after [server")),
+            ("[server] port = 1", (1, "unexpected 'p' after the value")),
+            ("[server]\nprot = 1", (2, "unknown key prot in [server], expected one of addr, port")),
+            ("[server]\nport = 1\nport = 2", (3, "server.port is set more than once")),
+            ("[server]\nport 1", (2, "expected = after port")),
+            ("[server]\nport = -1", (2, "server.port must be an integer of zero or more")),
+            ("[server]\nport = 80 80", (2, "unexpected '8' after the value")),
+            ("[server]\nport = 1__0", (2, "invalid number 1__0")),
+            ("[server]\nport = _1", (2, "expected a value, found '_'")),
+            ("[server]\nport = 1.", (2, "invalid number 1.")),
+            ("[server]\naddr = localhost", (2, "localhost needs quotes to be a string")),
+            ("[server]\naddr = true", (2, "server.addr must be a string, not a boolean")),
+            ("[server]\naddr = \"open", (2, "unterminated string")),
+            ("[server]\naddr = 'open\n'", (2, "unterminated string")),
+            ("[server]\naddr = \"\\q\"", (2, "invalid escape \\q")),
+            ("[server]\naddr = \"\\uD800\"", (2, "invalid escape \\uD800")),
+            ("[server]\nreuse_port = 1", (2, "server.reuse_port must be true or false")),
+            ("[chaos]\nlatency_rate = 1.5", (2, "chaos.latency_rate must be a number between 0 and 1")),
+            ("[cors]\norigins = [\n\"a\",\n1]", (2, "cors.origins must be an array of strings, but has an integer")),
+            ("[cors]\norigins = [\"a,b\"]", (2, "cors.origins has an entry containing ',', which separates entries")),
+            ("[cors]\norigins = [\"a\"\n\"b\"]", (3, "expected , or ] in an array, found '\"'")),
+            ("[cors]\norigins = [\"a\"", (2, "unterminated array")),
+            ("[cors]\norigins = [\"a\",", (2, "expected a value")),
+            ("[server]\n= 1", (2, "expected a key, found '='")),
+        ];
+        for (text, (line, message)) in cases {
+            let (at, error) = error(text);
+            assert!(at == line && error.starts_with(message), "{:?}: {}: {}", text, at, error);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use hyper::{header, HeaderMap, Method, Request, Response, StatusCode};
+use serde_json::Value;
+use std::sync::OnceLock;
+
+use crate::{
+    body::{self, Body},
+    problem,
+    stack::Next,
+    tenants::Refused,
+    SharedState,
+};
+
+pub static SPEC: &str = include_str!("../openapi.json");
+
+// Attached to responses when contract checking is on, so tests can assert
+// on them without scraping logs.
+#[derive(Debug, Clone)]
+pub struct Violations(pub Vec<String>);
+
+pub struct Contract {
+    spec: Value,
+}
+
+impl Contract {
+    pub fn load() -> Self {
+        Contract {
+            spec: serde_json::from_str(SPEC).expect("openapi.json must be valid JSON"),
+        }
+    }
+
+    pub fn check(
+        &self,
+        method: &Method,
+        path: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Vec<String> {
+        let mut out = Vec::new();
+
+        let item = match self.path_item(path) {
+            Some(item) => item,
+            // Unknown paths, and those only known without a trailing slash.
+            None if status == StatusCode::NOT_FOUND || status == StatusCode::PERMANENT_REDIRECT => return out,
+            None => {
+                out.push(format!("path {} is not in the spec", path));
+                return out;
+            }
+        };
+        let operation = &item[method.as_str().to_ascii_lowercase()];
+        // A method the path doesn't take is answered 405, which can't be
+        // documented under it, and OPTIONS and HEAD are answered for every
+        // path by the router.
+        let generic = matches!(*method, Method::OPTIONS | Method::HEAD);
+        if !operation.is_object() && (status == StatusCode::METHOD_NOT_ALLOWED || generic) {
+            return out;
+        }
+        if !operation.is_object() {
+            out.push(format!("{} is not documented for this path", method));
+            return out;
+        }
+
+        let code = status.as_u16().to_string();
+        let range = format!("{}XX", &code[..1]);
+        let responses = &operation["responses"];
+        let response = match [code.as_str(), range.as_str(), "default"]
+            .iter()
+            .find_map(|key| responses.get(*key))
+        {
+            Some(response) => response,
+            None => {
+                out.push(format!("status {} is not documented", code));
+                return out;
+            }
+        };
+
+        if let Some(documented) = response["headers"].as_object() {
+            // Headers only sent sometimes are documented with "required": false.
+            for (name, header) in documented {
+                if header["required"] != false && !headers.contains_key(name.as_str()) {
+                    out.push(format!("missing header {}", name));
+                }
+            }
+        }
+
+        let content = match response["content"].as_object() {
+            Some(content) => content,
+            None => {
+                if !body.is_empty() {
+                    out.push("response has a body but none is documented".to_string());
+                }
+                return out;
+            }
+        };
+        let content_type = match headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            Some(content_type) => content_type,
+            None => {
+                out.push("missing Content-Type".to_string());
+                return out;
+            }
+        };
+        let media_type = match content.get(content_type).or_else(|| content.get(base_type(content_type))) {
+            Some(media_type) => media_type,
+            None => {
+                out.push(format!("content type {} is not documented", content_type));
+                return out;
+            }
+        };
+
+        if matches!(base_type(content_type), "application/json" | problem::MEDIA_TYPE) {
+            match serde_json::from_slice::<Value>(body) {
+                Ok(value) => self.validate(&media_type["schema"], &value, "body", &mut out),
+                Err(e) => out.push(format!("body is not valid JSON: {}", e)),
+            }
+        }
+        out
+    }
+
+    // Legacy paths are checked as the /v1 paths they stand for.
+    fn path_item(&self, path: &str) -> Option<&Value> {
+        let path = &*crate::api().canonical(path);
+        let paths = self.spec["paths"].as_object()?;
+        paths.get(path).or_else(|| {
+            paths
+                .iter()
+                .find(|(template, _)| matches_template(template, path))
+                .map(|(_, item)| item)
+        })
+    }
+
+    fn resolve<'a>(&'a self, schema: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str().and_then(|r| r.strip_prefix("#/components/schemas/")) {
+            Some(name) => &self.spec["components"]["schemas"][name],
+            None => schema,
+        }
+    }
+
+    fn validate(&self, schema: &Value, value: &Value, at: &str, out: &mut Vec<String>) {
+        let schema = self.resolve(schema);
+
+        // A value fitting any of the alternatives is fine; otherwise what's
+        // wrong with the first one is reported.
+        if let Some(alternatives) = schema["anyOf"].as_array() {
+            let mut first = None;
+            for alternative in alternatives {
+                let mut found = Vec::new();
+                self.validate(alternative, value, at, &mut found);
+                if found.is_empty() {
+                    return;
+                }
+                first.get_or_insert(found);
+            }
+            out.extend(first.unwrap_or_default());
+            return;
+        }
+
+        if value.is_null() {
+            if schema["nullable"] != Value::Bool(true) {
+                out.push(format!("{}: null is not allowed", at));
+            }
+            return;
+        }
+
+        if let Some(expected) = schema["type"].as_str() {
+            let ok = match expected {
+                "object" => value.is_object(),
+                "array" => value.is_array(),
+                "string" => value.is_string(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "boolean" => value.is_boolean(),
+                _ => true,
+            };
+            if !ok {
+                out.push(format!("{}: expected {}, got {}", at, expected, value));
+                return;
+            }
+        }
+
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                out.push(format!("{}: {} is not one of {:?}", at, value, allowed));
+            }
+        }
+
+        match value {
+            Value::Object(fields) => {
+                if let Some(required) = schema["required"].as_array() {
+                    for name in required.iter().filter_map(Value::as_str) {
+                        if !fields.contains_key(name) {
+                            out.push(format!("{}.{}: required field is missing", at, name));
+                        }
+                    }
+                }
+                for (name, field) in fields {
+                    let child = format!("{}.{}", at, name);
+                    match (schema["properties"].get(name), &schema["additionalProperties"]) {
+                        (Some(property), _) => self.validate(property, field, &child, out),
+                        (None, Value::Bool(false)) => out.push(format!("{}: field is not documented", child)),
+                        (None, additional @ Value::Object(_)) => self.validate(additional, field, &child, out),
+                        _ => {}
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.validate(item_schema, item, &format!("{}[{}]", at, i), out);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// The documented path a request falls under, e.g. /v1/books/{id} for
+// /v1/books/7 or /books/7, so metrics are labelled with a bounded set of
+// routes.
+pub fn route(path: &str) -> Option<&'static str> {
+    let path = &*crate::api().canonical(path);
+    static TEMPLATES: OnceLock<Vec<String>> = OnceLock::new();
+    let templates = TEMPLATES.get_or_init(|| {
+        let spec: Value = serde_json::from_str(SPEC).unwrap_or_default();
+        spec["paths"].as_object().map(|paths| paths.keys().cloned().collect()).unwrap_or_default()
+    });
+    templates
+        .iter()
+        .find(|template| *template == path)
+        .or_else(|| templates.iter().find(|template| matches_template(template, path)))
+        .map(String::as_str)
+}
+
+fn matches_template(template: &str, path: &str) -> bool {
+    let template: Vec<&str> = template.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    template.len() == path.len() && template.iter().zip(&path).all(|(t, p)| matches_segment(t, p))
+}
+
+// Handles both whole-segment parameters (`{id}`) and ones with a literal
+// prefix or suffix (`{name}.json`).
+fn matches_segment(template: &str, segment: &str) -> bool {
+    match (template.find('{'), template.find('}')) {
+        (Some(open), Some(close)) if open < close => {
+            let (prefix, suffix) = (&template[..open], &template[close + 1..]);
+            segment.len() > prefix.len() + suffix.len()
+                && segment.starts_with(prefix)
+                && segment.ends_with(suffix)
+        }
+        _ => template == segment,
+    }
+}
+
+fn base_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or(content_type).trim()
+}
+
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let contract = match &state.contract {
+        Some(contract) => contract,
+        None => return next.run(req, state.clone()).await,
+    };
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    // A tenant that can't be picked is refused whatever the path, so,
+    // like a 405, that's not documented under it.
+    let refused = req.extensions().get::<Refused>().is_some();
+    let response = next.run(req, state.clone()).await?;
+    // An event stream never ends, so there's no body to check.
+    let streaming = response.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(base_type);
+    if streaming == Some("text/event-stream") {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = body::to_bytes(body).await?;
+    let violations = match refused {
+        true => Violations(Vec::new()),
+        false => Violations(contract.check(&method, &path, parts.status, &parts.headers, &bytes)),
+    };
+    for violation in &violations.0 {
+        tracing::warn!(%violation, "contract violation");
+    }
+    parts.extensions.insert(violations);
+
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
@@ -0,0 +1,230 @@
+use hyper::{
+    header::{self, HeaderValue},
+    Method, Request, Response, StatusCode,
+};
+
+use crate::{
+    body::{self, Body},
+    problem,
+    stack::Next,
+    SharedState,
+};
+
+const DEFAULT_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE";
+const DEFAULT_HEADERS: &str = "Authorization, Content-Type, If-Match, If-None-Match, X-Api-Key";
+// Response headers a page may read besides the CORS-safelisted ones.
+const EXPOSED: &str = "ETag, Location, Retry-After, X-Total-Count, X-Next-Cursor";
+const DEFAULT_MAX_AGE: u32 = 600;
+
+// Cross-origin access for browsers. DOJO_CORS_ORIGINS is a comma-separated
+// allowlist of origins, like https://catalog.example.org, or `*` for any.
+// DOJO_CORS_METHODS and DOJO_CORS_HEADERS list what preflights may ask for,
+// and DOJO_CORS_MAX_AGE how many seconds browsers may cache the answer.
+// DOJO_CORS_CREDENTIALS=true lets pages send cookies and Authorization.
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    allow_methods: HeaderValue,
+    headers: Vec<String>,
+    allow_headers: HeaderValue,
+    max_age: u32,
+    credentials: bool,
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+fn header_value(name: &str, value: String) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(&value).map_err(|_| format!("{} isn't a valid header value: {:?}", name, value))
+}
+
+impl Cors {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(origins) = std::env::var("DOJO_CORS_ORIGINS") else {
+            return Ok(None);
+        };
+        let origins: Vec<String> = list(&origins).map(|origin| origin.trim_end_matches('/').to_string()).collect();
+        if origins.is_empty() {
+            return Err("DOJO_CORS_ORIGINS lists no origins".to_string());
+        }
+        let methods = std::env::var("DOJO_CORS_METHODS").unwrap_or_else(|_| DEFAULT_METHODS.to_string());
+        let methods = list(&methods)
+            .map(|method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("DOJO_CORS_METHODS has an invalid method {:?}", method))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = std::env::var("DOJO_CORS_HEADERS").unwrap_or_else(|_| DEFAULT_HEADERS.to_string());
+        let headers: Vec<String> = list(&headers).map(str::to_ascii_lowercase).collect();
+        let max_age = match std::env::var("DOJO_CORS_MAX_AGE") {
+            Ok(secs) => secs
+                .parse()
+                .map_err(|_| format!("DOJO_CORS_MAX_AGE must be a number of seconds, got {:?}", secs))?,
+            Err(_) => DEFAULT_MAX_AGE,
+        };
+        let credentials = std::env::var("DOJO_CORS_CREDENTIALS").is_ok_and(|value| value == "true");
+        Ok(Some(Cors {
+            origins,
+            allow_methods: header_value("DOJO_CORS_METHODS", methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", "))?,
+            methods,
+            allow_headers: header_value("DOJO_CORS_HEADERS", headers.join(", "))?,
+            headers,
+            max_age,
+            credentials,
+        }))
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    // What the preflight asks for that isn't allowed, if anything.
+    fn refuses(&self, req: &Request<Body>) -> Option<String> {
+        let method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+        let allowed = Method::from_bytes(method.as_bytes()).is_ok_and(|method| self.methods.contains(&method));
+        if !allowed {
+            return Some(format!("method {} is not allowed cross-origin", String::from_utf8_lossy(method.as_bytes())));
+        }
+        let requested = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let refused = list(&requested).find(|name| !self.headers.iter().any(|allowed| allowed == name));
+        refused.map(|name| format!("header {} is not allowed cross-origin", name))
+    }
+
+    // The origin is echoed rather than answered with `*`, so the same header
+    // works when credentials are allowed.
+    fn decorate(&self, response: &mut Response<Body>, origin: HeaderValue) {
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+    }
+
+    fn preflight(&self, origin: HeaderValue) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, self.allow_methods.clone())
+            .header(header::ACCESS_CONTROL_ALLOW_HEADERS, self.allow_headers.clone())
+            .header(header::ACCESS_CONTROL_MAX_AGE, self.max_age)
+            .body(Body::empty())
+            .unwrap();
+        self.decorate(&mut response, origin);
+        response
+    }
+}
+
+fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+// Preflights are answered here and never reach the routes. Other requests
+// from an allowed origin run as usual and get the headers that let the page
+// read the response; requests from other origins are untouched, and the
+// browser keeps their responses from the page. Every response varies by
+// Origin, so a cache doesn't hand one origin's answer to another.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let Some(cors) = &state.cors else {
+        return next.run(req, state).await;
+    };
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .filter(|origin| origin.to_str().is_ok_and(|origin| cors.allows_origin(origin)))
+        .cloned();
+    let mut response = match (is_preflight(&req), origin) {
+        (true, None) => problem::respond(StatusCode::FORBIDDEN, "origin is not allowed"),
+        (true, Some(origin)) => match cors.refuses(&req) {
+            Some(message) => problem::respond(StatusCode::FORBIDDEN, message),
+            None => cors.preflight(origin),
+        },
+        (false, Some(origin)) => {
+            let mut response = next.run(req, state.clone()).await?;
+            cors.decorate(&mut response, origin);
+            response.headers_mut().insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSED));
+            response
+        }
+        (false, None) => next.run(req, state.clone()).await?,
+    };
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("Origin"));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As from_env has it with the default methods and headers.
+    fn cors(origins: &[&str], credentials: bool) -> Cors {
+        let methods: Vec<Method> = list(DEFAULT_METHODS).map(|method| Method::from_bytes(method.as_bytes()).unwrap()).collect();
+        let headers: Vec<String> = list(DEFAULT_HEADERS).map(str::to_ascii_lowercase).collect();
+        Cors {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allow_methods: HeaderValue::from_static(DEFAULT_METHODS),
+            methods,
+            allow_headers: HeaderValue::from_str(&headers.join(", ")).unwrap(),
+            headers,
+            max_age: DEFAULT_MAX_AGE,
+            credentials,
+        }
+    }
+
+    fn preflight(method: &str, headers: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/books")
+            .header(header::ORIGIN, "https://catalog.example.org")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method);
+        if let Some(headers) = headers {
+            req = req.header(header::ACCESS_CONTROL_REQUEST_HEADERS, headers);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn only_listed_origins_are_allowed() {
+        let listed = cors(&["https://catalog.example.org"], false);
+        assert!(listed.allows_origin("https://catalog.example.org"));
+        assert!(!listed.allows_origin("https://catalog.example.org.evil.test"));
+        assert!(!listed.allows_origin("http://catalog.example.org"));
+        assert!(cors(&["*"], false).allows_origin("https://anywhere.test"));
+    }
+
+    #[test]
+    fn preflights_ask_for_allowed_methods_and_headers() {
+        let cors = cors(&["*"], false);
+        assert!(is_preflight(&preflight("PUT", None)));
+        assert_eq!(cors.refuses(&preflight("PUT", Some("content-type, If-Match"))), None);
+        assert_eq!(cors.refuses(&preflight("TRACE", None)).as_deref(), Some("method TRACE is not allowed cross-origin"));
+        assert_eq!(
+            cors.refuses(&preflight("GET", Some("Content-Type, X-Secret"))).as_deref(),
+            Some("header x-secret is not allowed cross-origin")
+        );
+        let plain = Request::builder().method(Method::OPTIONS).uri("/books").body(Body::empty()).unwrap();
+        assert!(!is_preflight(&plain));
+    }
+
+    // The origin comes back as it was sent, credentials or not.
+    #[test]
+    fn answers_echo_the_origin() {
+        let origin = HeaderValue::from_static("https://catalog.example.org");
+        let answer = cors(&["*"], false).preflight(origin.clone());
+        assert_eq!(answer.status(), StatusCode::NO_CONTENT);
+        let headers = answer.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], DEFAULT_METHODS);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "authorization, content-type, if-match, if-none-match, x-api-key");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+
+        let mut response = Response::new(Body::empty());
+        cors(&["https://catalog.example.org"], true).decorate(&mut response, origin.clone());
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+}
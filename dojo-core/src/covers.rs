@@ -0,0 +1,259 @@
+use hyper::{body::Bytes, header, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    bad_request,
+    body::Body,
+    conditional::{now_ms, Conditional},
+    error::ApiError,
+    extract::{FromRequest, Path, RequestContext, State},
+    import, problem,
+    store::{BookStore, Store},
+    SharedState, StorageError,
+};
+
+const DEFAULT_MAX_BYTES: usize = 2 * 1024 * 1024;
+
+// Caches may keep a cover but have to check it's still current, since a
+// new one can be uploaded under the same URL at any time.
+const CACHE_CONTROL: &str = "public, no-cache";
+
+#[derive(Clone)]
+pub struct Cover {
+    media: &'static str,
+    bytes: Bytes,
+    // Milliseconds since the epoch.
+    modified: u64,
+}
+
+// The image formats a cover may be, told apart by their first bytes
+// rather than by what the client says they are.
+fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+enum Place {
+    Memory(Mutex<HashMap<u64, Cover>>),
+    // One file per book, named by its id.
+    Dir(PathBuf),
+}
+
+// Cover images, kept apart from the books. DOJO_COVERS_DIR keeps them as
+// files in that directory, created if it's missing; without it they're
+// kept in memory and lost on restart. DOJO_COVER_MAX_BYTES caps an
+// upload, 2 MiB unless set.
+pub struct Covers {
+    place: Place,
+    max_bytes: usize,
+}
+
+impl Default for Covers {
+    fn default() -> Self {
+        Covers {
+            place: Place::Memory(Mutex::new(HashMap::new())),
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl Covers {
+    pub fn from_env() -> Result<Self, String> {
+        let max_bytes = match std::env::var("DOJO_COVER_MAX_BYTES") {
+            Ok(max) => max
+                .parse()
+                .ok()
+                .filter(|max| *max > 0)
+                .ok_or_else(|| format!("DOJO_COVER_MAX_BYTES must be a positive integer, got {:?}", max))?,
+            Err(_) => DEFAULT_MAX_BYTES,
+        };
+        let place = match std::env::var("DOJO_COVERS_DIR") {
+            Ok(dir) if !dir.is_empty() => {
+                std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir, e))?;
+                Place::Dir(PathBuf::from(dir))
+            }
+            _ => Place::Memory(Mutex::new(HashMap::new())),
+        };
+        Ok(Covers { place, max_bytes })
+    }
+
+    pub async fn get(&self, id: u64) -> Result<Option<Cover>, String> {
+        let dir = match &self.place {
+            Place::Memory(covers) => {
+                return Ok(covers.lock().unwrap().get(&id).cloned());
+            }
+            Place::Dir(dir) => dir.join(id.to_string()),
+        };
+        let read = tokio::task::spawn_blocking(move || -> std::io::Result<Option<Cover>> {
+            let bytes = match std::fs::read(&dir) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let modified = std::fs::metadata(&dir)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            // A file that isn't an image anymore is treated as missing.
+            Ok(sniff(&bytes).map(|media| Cover {
+                media,
+                bytes: bytes.into(),
+                modified,
+            }))
+        });
+        read.await.map_err(|e| e.to_string())?.map_err(|e| format!("reading the cover of book {}: {}", id, e))
+    }
+
+    // Replaces the book's cover. On disk the new file is written beside the
+    // old one and renamed over it, so a reader never sees half of it.
+    pub async fn put(&self, id: u64, media: &'static str, bytes: Bytes) -> Result<(), String> {
+        let dir = match &self.place {
+            Place::Memory(covers) => {
+                let cover = Cover {
+                    media,
+                    bytes,
+                    modified: now_ms(),
+                };
+                covers.lock().unwrap().insert(id, cover);
+                return Ok(());
+            }
+            Place::Dir(dir) => dir.clone(),
+        };
+        let write = tokio::task::spawn_blocking(move || {
+            let partial = dir.join(format!(".{}.partial", id));
+            std::fs::write(&partial, &bytes)?;
+            std::fs::rename(&partial, dir.join(id.to_string()))
+        });
+        write.await.map_err(|e| e.to_string())?.map_err(|e| format!("writing the cover of book {}: {}", id, e))
+    }
+}
+
+// A cover upload: the image as the raw body, or the file part of a
+// multipart/form-data form. The body is read no further than the limit.
+pub struct Image {
+    media: &'static str,
+    bytes: Bytes,
+}
+
+impl FromRequest for Image {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let content_type = ctx
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let max_bytes = ctx.state().covers.max_bytes;
+        let too_large = || {
+            problem::respond(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("a cover can be at most {} bytes", max_bytes),
+            )
+        };
+        let declared = ctx.headers().get(header::CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+        // A form carries its boundaries and headers besides the file.
+        let allowance = max_bytes.saturating_add(16 * 1024);
+        if declared.is_some_and(|length| length > allowance) {
+            return Err(too_large());
+        }
+        let mut body = ctx.take_body();
+        let mut read = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                return Err(bad_request("Failed to read request body"));
+            };
+            if read.len() + chunk.len() > allowance {
+                return Err(too_large());
+            }
+            read.extend_from_slice(&chunk);
+        }
+        let mut bytes = Bytes::from(read);
+        let mut media = import::media(&content_type);
+        if media == "multipart/form-data" {
+            let Some(boundary) = import::parameter(&content_type, "boundary") else {
+                return Err(bad_request("multipart/form-data needs a boundary"));
+            };
+            let Some(part) = import::file_part(&bytes, &boundary) else {
+                return Err(bad_request("the form has no file in it"));
+            };
+            media = part.media;
+            bytes = bytes.slice(part.content);
+        }
+        if bytes.len() > max_bytes {
+            return Err(too_large());
+        }
+        if bytes.is_empty() {
+            return Err(bad_request("the cover is empty"));
+        }
+        let Some(sniffed) = sniff(&bytes) else {
+            return Err(problem::respond(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "a cover has to be a PNG, JPEG, GIF or WebP image",
+            ));
+        };
+        // Without a type, or with a generic one, the bytes decide.
+        if !matches!(media.as_str(), "" | "application/octet-stream") && media != sniffed {
+            return Err(problem::respond(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("the image was sent as {} but is {}", media, sniffed),
+            ));
+        }
+        Ok(Image { media: sniffed, bytes })
+    }
+}
+
+pub async fn put_cover<S: BookStore>(
+    Path(id): Path<u64>,
+    Store(store): Store<S>,
+    State(state): State<SharedState>,
+    image: Image,
+) -> Result<Response<Body>, ApiError> {
+    store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    let tag = crate::etag::hash(&image.bytes);
+    state
+        .covers()
+        .put(id, image.media, image.bytes)
+        .await
+        .map_err(|e| StorageError::Failed(format!("storing a cover failed: {}", e)))?;
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ETAG, tag)
+        .body(Body::empty())
+        .unwrap())
+}
+
+// The cover with its own type, an ETag of its bytes and when it was
+// uploaded, so a client can revalidate it with If-None-Match or
+// If-Modified-Since.
+pub async fn get_cover<S: BookStore>(
+    Path(id): Path<u64>,
+    Store(store): Store<S>,
+    State(state): State<SharedState>,
+    conditional: Conditional,
+) -> Result<Response<Body>, ApiError> {
+    store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    let cover = state
+        .covers()
+        .get(id)
+        .await
+        .map_err(|e| StorageError::Failed(format!("loading a cover failed: {}", e)))?
+        .ok_or_else(|| ApiError::NotFound(format!("book {} has no cover", id)))?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, cover.media)
+        .header(header::CACHE_CONTROL, CACHE_CONTROL)
+        .body(Body::from(cover.bytes))
+        .unwrap();
+    Ok(conditional.respond(response, Some(cover.modified)).await)
+}
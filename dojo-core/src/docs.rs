@@ -0,0 +1,161 @@
+use hyper::{header, Response, StatusCode};
+use maud::{html, Markup};
+use serde_json::Value;
+
+use crate::{
+    body::{self, Body},
+    conditional::Conditional,
+    contract, html,
+};
+
+const METHODS: &[&str] = &["get", "post", "put", "patch", "delete"];
+
+// GET /openapi.json serves the spec the contract layer checks responses
+// against, so what's published is what's enforced.
+pub async fn spec(conditional: Conditional) -> Result<Response<Body>, body::Error> {
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(contract::SPEC))
+        .unwrap();
+    Ok(conditional.respond(response, None).await)
+}
+
+// GET /docs renders the same spec as a reference page: every operation with
+// its parameters and responses, then the schemas they name. It's rendered
+// here rather than by a script, so it works without network access.
+pub async fn page() -> Result<Response<Body>, body::Error> {
+    let spec: Value = serde_json::from_str(contract::SPEC).unwrap_or_default();
+    let title = spec["info"]["title"].as_str().unwrap_or("API");
+    let empty = serde_json::Map::new();
+    let paths = spec["paths"].as_object().unwrap_or(&empty);
+    let schemas = spec["components"]["schemas"].as_object().unwrap_or(&empty);
+    Ok(html::page(
+        title,
+        html! {
+            h1 { (title) " " small { (spec["info"]["version"].as_str().unwrap_or_default()) } }
+            @if let Some(description) = spec["info"]["description"].as_str() { p { (description) } }
+            p { "The machine-readable spec is at " a href="/openapi.json" { "/openapi.json" } "." }
+            @for (path, item) in paths {
+                @for method in METHODS.iter().filter(|method| item[**method].is_object()) {
+                    (operation(method, path, item, &item[*method]))
+                }
+            }
+            h2 { "Schemas" }
+            @for (name, schema) in schemas {
+                section id={ "schema-" (name) } {
+                    h3 { (name) }
+                    @if let Some(properties) = schema["properties"].as_object() {
+                        table {
+                            thead { tr { th { "Field" } th { "Type" } th { "Required" } th { "Description" } } }
+                            tbody {
+                                @for (field, property) in properties {
+                                    tr {
+                                        td { code { (field) } }
+                                        td { (type_of(property)) }
+                                        td { (if required(schema, field) { "yes" } else { "no" }) }
+                                        td { (property["description"].as_str().unwrap_or_default()) }
+                                    }
+                                }
+                            }
+                        }
+                    } @else {
+                        p { (type_of(schema)) }
+                    }
+                }
+            }
+        },
+    ))
+}
+
+fn operation(method: &str, path: &str, item: &Value, operation: &Value) -> Markup {
+    // Parameters shared by the path come first, as the spec lists them.
+    let parameters: Vec<&Value> = [&item["parameters"], &operation["parameters"]]
+        .into_iter()
+        .filter_map(Value::as_array)
+        .flatten()
+        .collect();
+    let empty = serde_json::Map::new();
+    html! {
+        section id=[operation["operationId"].as_str()] {
+            h2 { code { (method.to_ascii_uppercase()) " " (path) } }
+            @if let Some(description) = operation["description"].as_str() { p { (description) } }
+            @if !parameters.is_empty() {
+                h4 { "Parameters" }
+                table {
+                    thead { tr { th { "Name" } th { "In" } th { "Type" } th { "Required" } th { "Description" } } }
+                    tbody {
+                        @for parameter in &parameters {
+                            tr {
+                                td { code { (parameter["name"].as_str().unwrap_or_default()) } }
+                                td { (parameter["in"].as_str().unwrap_or_default()) }
+                                td { (type_of(&parameter["schema"])) }
+                                td { (if parameter["required"] == true { "yes" } else { "no" }) }
+                                td { (parameter["description"].as_str().unwrap_or_default()) }
+                            }
+                        }
+                    }
+                }
+            }
+            @if let Some(content) = operation["requestBody"]["content"].as_object() {
+                h4 { "Request body" }
+                ul {
+                    @for (media, body) in content {
+                        li { code { (media) } ": " (type_of(&body["schema"])) }
+                    }
+                }
+            }
+            h4 { "Responses" }
+            dl {
+                @for (status, response) in operation["responses"].as_object().unwrap_or(&empty) {
+                    dt { (status) }
+                    dd {
+                        (response["description"].as_str().unwrap_or_default())
+                        @for (media, body) in response["content"].as_object().unwrap_or(&empty) {
+                            br; code { (media) } ": " (type_of(&body["schema"]))
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn required(schema: &Value, field: &str) -> bool {
+    schema["required"].as_array().is_some_and(|required| required.iter().any(|name| name == field))
+}
+
+// A schema in a few words, with named schemas linked.
+fn type_of(schema: &Value) -> Markup {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.rsplit('/').next().unwrap_or(reference);
+        return html! { a href={ "#schema-" (name) } { (name) } };
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(options) = schema[key].as_array() {
+            return html! {
+                @for (index, option) in options.iter().enumerate() {
+                    @if index > 0 { " or " }
+                    (type_of(option))
+                }
+            };
+        }
+    }
+    let kind = schema["type"].as_str().unwrap_or("any");
+    html! {
+        @if kind == "array" {
+            "array of " (type_of(&schema["items"]))
+        } @else if let Some(values) = schema["enum"].as_array() {
+            "one of "
+            @for (index, value) in values.iter().enumerate() {
+                @if index > 0 { ", " }
+                code { (value) }
+            }
+        } @else {
+            (kind)
+            @if let Some(format) = schema["format"].as_str() { " (" (format) ")" }
+        }
+        @if schema["nullable"] == true { " or null" }
+    }
+}
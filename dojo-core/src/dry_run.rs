@@ -0,0 +1,128 @@
+use books_model::CreateBookRequest;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    extract::{Json, RequestContext},
+    json_response,
+    problem::FieldError,
+    store::{AuthorStore, BookStore, Store},
+    validate, StorageError, MAX_BATCH,
+};
+
+// `?dry_run=true` on POST /books and PUT /books/{id} goes through
+// everything the write would, up to the write itself, and answers 200
+// with the book as it would be stored or with the error the write would
+// have met.
+#[derive(Debug, Default, Deserialize)]
+pub struct DryRun {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl DryRun {
+    pub fn is_set(&self) -> bool {
+        self.dry_run
+    }
+}
+
+// For the routes, which keep a dry run on this instance even where writes
+// go through raft, since it writes nothing.
+pub fn requested(ctx: &RequestContext) -> bool {
+    let query = ctx.parts().uri.query().unwrap_or("");
+    serde_urlencoded::from_str::<DryRun>(query).is_ok_and(|dry_run| dry_run.is_set())
+}
+
+// The 409 a write would meet when another book than `id` has the ISBN.
+pub async fn check_isbn<S: BookStore>(store: &S, isbn: Option<String>, id: u64) -> Result<(), ApiError> {
+    let Some(isbn) = isbn else {
+        return Ok(());
+    };
+    match store.isbn_holders(vec![isbn.clone()], id).await?.pop().flatten() {
+        Some(holder) => Err(ApiError::Storage(StorageError::DuplicateIsbn { isbn, id: holder })),
+        None => Ok(()),
+    }
+}
+
+// A book, or a list of them as POST /books/batch takes, answered in the
+// same shape.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Books {
+    Many(Vec<CreateBookRequest>),
+    One(CreateBookRequest),
+}
+
+#[derive(Debug, Serialize)]
+struct Validation {
+    valid: bool,
+    errors: Vec<FieldError>,
+    books: Books,
+}
+
+// POST /books/validate checks books the way creating them would, from
+// their fields to whether the catalog already has their ISBNs, and
+// answers with everything wrong with them at once along with the books as
+// they'd be stored. It's always a 200; nothing is written either way.
+pub async fn validate_books<S: BookStore + AuthorStore>(
+    Store(store): Store<S>,
+    Json(books): Json<Books>,
+) -> Result<Response<Body>, ApiError> {
+    let (mut books, many) = match books {
+        Books::Many(books) => (books, true),
+        Books::One(book) => (vec![book], false),
+    };
+    if books.len() > MAX_BATCH {
+        return Err(ApiError::BadRequest(format!("A batch holds at most {} books", MAX_BATCH)));
+    }
+    let field = |index: usize, name: &str| match many {
+        true => format!("[{}].{}", index, name),
+        false => name.to_string(),
+    };
+
+    let mut errors = Vec::new();
+    for (index, book) in books.iter_mut().enumerate() {
+        let Some(id) = book.author_id else {
+            continue;
+        };
+        match store.author(id).await? {
+            Some(author) => book.author = author.name,
+            None => errors.push(FieldError {
+                field: field(index, "author_id"),
+                message: format!("there's no author {}", id),
+            }),
+        }
+    }
+    match many {
+        true => {
+            errors.extend(validate::errors_all(&mut books));
+            errors.extend(validate::shared_isbns(&books));
+        }
+        false => errors.extend(validate::errors(&mut books[0])),
+    }
+
+    let (indexes, isbns): (Vec<_>, Vec<_>) =
+        books.iter().enumerate().filter_map(|(index, book)| Some((index, book.isbn.clone()?))).unzip();
+    let holders = store.isbn_holders(isbns, 0).await?;
+    for (index, holder) in indexes.into_iter().zip(holders) {
+        if let Some(holder) = holder {
+            errors.push(FieldError {
+                field: field(index, "isbn"),
+                message: format!("is already the ISBN of book {}", holder),
+            });
+        }
+    }
+
+    let books = match many {
+        true => Books::Many(books),
+        false => Books::One(books.remove(0)),
+    };
+    let validation = Validation {
+        valid: errors.is_empty(),
+        errors,
+        books,
+    };
+    Ok(json_response(StatusCode::OK, &validation)?)
+}
@@ -0,0 +1,235 @@
+use books_model::Book;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    etag,
+    extract::{Json, State},
+    json_response,
+    links::{Linked, Links},
+    search::tokenize,
+    store::{BookStore, Store},
+    validate::Violations,
+    SharedState,
+};
+
+// Accented Latin letters and the letter they're compared as.
+const FOLDS: &[(&str, &str)] = &[
+    ("àáâãäåāăą", "a"), ("çćĉċč", "c"), ("ďđ", "d"), ("èéêëēĕėęě", "e"), ("ĝğġģ", "g"), ("ĥħ", "h"),
+    ("ìíîïĩīĭįı", "i"), ("ĵ", "j"), ("ķ", "k"), ("ĺļľŀł", "l"), ("ñńņň", "n"), ("òóôõöōŏőø", "o"),
+    ("ŕŗř", "r"), ("śŝşš", "s"), ("ţťŧ", "t"), ("ùúûüũūŭůűų", "u"), ("ŵ", "w"), ("ýÿŷ", "y"),
+    ("źżž", "z"), ("ß", "ss"), ("æ", "ae"), ("œ", "oe"), ("þ", "th"),
+];
+
+// A title or author as it's compared: its words lowercased, without
+// accents, punctuation or the spacing between them.
+fn fold(text: &str) -> String {
+    let words: Vec<String> = tokenize(text)
+        .map(|word| {
+            word.chars()
+                .map(|c| FOLDS.iter().find(|(accented, _)| accented.contains(c)).map_or(c.to_string(), |(_, plain)| plain.to_string()))
+                .collect()
+        })
+        .collect();
+    words.join(" ")
+}
+
+// The first twelve digits of the ISBN-13 an ISBN is or stands for, so an
+// ISBN-10 and its ISBN-13, or two that only differ in the check digit,
+// compare equal.
+fn isbn_stem(isbn: &str) -> Option<String> {
+    let digits: String = isbn.chars().filter(char::is_ascii_alphanumeric).collect::<String>().to_uppercase();
+    match digits.len() {
+        10 => Some(format!("978{}", &digits[..9])),
+        13 => Some(digits[..12].to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Match {
+    TitleAndAuthor,
+    Isbn,
+}
+
+#[derive(Serialize)]
+struct Cluster<'a> {
+    // Why the books were put together; one reason may hold for only some
+    // pairs of them.
+    matched_on: BTreeSet<Match>,
+    books: Vec<Linked<&'a Book>>,
+}
+
+// Groups of books that are likely the same book, by their place in
+// `books`.
+fn clusters(books: &[Book]) -> Vec<(BTreeSet<Match>, Vec<usize>)> {
+    let mut parent: Vec<usize> = (0..books.len()).collect();
+    fn root(parent: &mut [usize], mut at: usize) -> usize {
+        while parent[at] != at {
+            parent[at] = parent[parent[at]];
+            at = parent[at];
+        }
+        at
+    }
+    let mut reasons: HashMap<usize, BTreeSet<Match>> = HashMap::new();
+    let mut first: HashMap<(Match, String), usize> = HashMap::new();
+    for (at, book) in books.iter().enumerate() {
+        let keys = [
+            Some((Match::TitleAndAuthor, format!("{}\n{}", fold(&book.title), fold(&book.author)))),
+            book.isbn.as_deref().and_then(isbn_stem).map(|stem| (Match::Isbn, stem)),
+        ];
+        for (kind, key) in keys.into_iter().flatten() {
+            let Some(&other) = first.get(&(kind, key.clone())) else {
+                first.insert((kind, key), at);
+                continue;
+            };
+            let (a, b) = (root(&mut parent, other), root(&mut parent, at));
+            let mut matched = reasons.remove(&a).unwrap_or_default();
+            if a != b {
+                matched.extend(reasons.remove(&b).unwrap_or_default());
+                parent[b] = a;
+            }
+            matched.insert(kind);
+            reasons.insert(a, matched);
+        }
+    }
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for at in 0..books.len() {
+        members.entry(root(&mut parent, at)).or_default().push(at);
+    }
+    let mut clusters: Vec<(BTreeSet<Match>, Vec<usize>)> = members
+        .into_iter()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(root, members)| (reasons.remove(&root).unwrap_or_default(), members))
+        .collect();
+    clusters.sort_by_key(|(_, members)| books[members[0]].id);
+    clusters
+}
+
+// Books that look like the same one entered twice: the same title and
+// author once case, accents, punctuation and spacing are set aside, or
+// ISBNs that only differ in form or check digit. Only the books this
+// instance holds are compared; a sharded catalog is checked shard by shard.
+pub async fn find<S: BookStore>(links: Links, Store(store): Store<S>) -> Result<Response<Body>, ApiError> {
+    let mut books = store.list().await?;
+    books.sort_by_key(|book| book.id);
+    let clusters: Vec<Cluster> = clusters(&books)
+        .into_iter()
+        .map(|(matched_on, members)| Cluster {
+            matched_on,
+            books: members.into_iter().map(|at| links.book(&books[at], &books[at])).collect(),
+        })
+        .collect();
+    Ok(json_response(StatusCode::OK, &clusters)?)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MergeRequest {
+    // The book that goes, and the one that stays.
+    pub from: u64,
+    pub into: u64,
+}
+
+// Folds one book into another: the one kept gains the other's tags, its
+// ISBN if it has none, and its reviews, and the other goes to the trash,
+// where it can be restored from. A book out on loan has to be returned
+// first.
+pub async fn merge<S: BookStore>(
+    links: Links,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+    Json(request): Json<MergeRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let MergeRequest { from, into } = request;
+    if from == into {
+        return Err(ApiError::bad_request("a book can't be merged into itself"));
+    }
+    let not_found = |id| move || ApiError::NotFound(format!("book {} not found", id));
+    let gone = store.get(from).await?.ok_or_else(not_found(from))?;
+    // Checked before anything changes, and again as the book is written.
+    combined(&store.get(into).await?.ok_or_else(not_found(into))?, &gone)?;
+    state
+        .with_storage("merge_reviews", || format!("from={} into={}", from, into), |storage| {
+            if storage.loans.contains_key(&from) {
+                return Err(ApiError::Conflict(format!("book {} is checked out", from)));
+            }
+            let mut reviews = storage.reviews.remove(&from).unwrap_or_default();
+            if !reviews.is_empty() {
+                let kept = storage.reviews.entry(into).or_default();
+                reviews.iter_mut().for_each(|review| review.book_id = into);
+                kept.append(&mut reviews);
+                kept.sort_by_key(|review| review.id);
+            }
+            Ok(())
+        })
+        .await??;
+    // Gone before the ISBN moves, which has to stay unique.
+    store.delete(from).await?.ok_or_else(not_found(from))?;
+    let book = store.modify(into, |kept| combined(kept, &gone)).await?.ok_or_else(not_found(into))??;
+    Ok(etag::tagged(json_response(StatusCode::OK, &links.book(&book, &book))?, &book))
+}
+
+// The kept book with what it gains from the other, unless that's more tags
+// than a book can have.
+fn combined(kept: &Book, gone: &Book) -> Result<Book, ApiError> {
+    let mut book = kept.clone();
+    book.isbn = book.isbn.or_else(|| gone.isbn.clone());
+    book.tags.extend(gone.tags.iter().cloned());
+    let mut violations = Violations::default();
+    violations.tags(&mut book.tags);
+    let errors = violations.into_errors();
+    match errors.is_empty() {
+        true => Ok(book),
+        false => Err(ApiError::Validation {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            errors,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(id: u64, title: &str, author: &str, isbn: Option<&str>) -> Book {
+        Book {
+            id,
+            title: title.to_string(),
+            author: author.to_string(),
+            isbn: isbn.map(str::to_string),
+            author_id: None,
+            tags: Vec::new(),
+            edition: Default::default(),
+            uid: None,
+            nft: None,
+            anchor: None,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn finds_books_entered_twice() {
+        assert_eq!(fold("  Les Misérables:  TOME I "), "les miserables tome i");
+        assert_eq!(isbn_stem("0-261-10221-4"), isbn_stem("9780261102217"));
+        let books = [
+            book(1, "Les Misérables", "Victor Hugo", None),
+            book(2, "les  miserables", "VICTOR HUGO", Some("9780140444308")),
+            book(3, "The Hobbit", "J.R.R. Tolkien", Some("0261102214")),
+            book(4, "Hobbit", "Tolkien", Some("978-0-261-10221-7")),
+            book(5, "Dune", "Frank Herbert", Some("9780441013593")),
+            book(6, "Les Misérables (abridged)", "Victor Hugo", Some("9780140444300")),
+        ];
+        let clusters = clusters(&books);
+        let ids = |members: &[usize]| members.iter().map(|&at| books[at].id).collect::<Vec<_>>();
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(ids(&clusters[0].1), [1, 2, 6]);
+        assert_eq!(clusters[0].0, BTreeSet::from([Match::TitleAndAuthor, Match::Isbn]));
+        assert_eq!(ids(&clusters[1].1), [3, 4]);
+        assert_eq!(clusters[1].0, BTreeSet::from([Match::Isbn]));
+    }
+}
@@ -0,0 +1,100 @@
+use hyper::{header, Response, StatusCode};
+
+use crate::{
+    body::{self, Body},
+    problem::{self, FieldError, Problem},
+    StorageError,
+};
+
+pub use crate::{etag::Changed, outbound::OutboundError};
+
+// Why a handler couldn't do what it was asked. Each kind has one status
+// and one problem body, decided here rather than at every `return`.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    NotFound(String),
+    // Fields that broke the rules, answered with a 400 when they couldn't
+    // be read and a 422 when they could but aren't allowed.
+    #[error("{}", problem::summary(errors))]
+    Validation { status: StatusCode, errors: Vec<FieldError> },
+    #[error("{0}")]
+    Conflict(String),
+    // If-Match named a version the book has moved on from.
+    #[error(transparent)]
+    Changed(#[from] Changed),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    // Another service a request needed failed it.
+    #[error("{service} {source}")]
+    Upstream { service: String, source: OutboundError },
+    // Reading the request or writing the response broke, which hyper
+    // deals with itself.
+    #[error(transparent)]
+    Http(#[from] body::Error),
+}
+
+impl ApiError {
+    pub fn not_found() -> Self {
+        ApiError::NotFound("Not found".to_string())
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::BadRequest(message.into())
+    }
+
+    // A request with one invalid field.
+    pub fn invalid(status: StatusCode, field: impl Into<String>, message: impl Into<String>) -> Self {
+        let errors = vec![FieldError {
+            field: field.into(),
+            message: message.into(),
+        }];
+        ApiError::Validation { status, errors }
+    }
+
+    pub fn upstream(service: impl Into<String>) -> impl FnOnce(OutboundError) -> Self {
+        let service = service.into();
+        move |source| ApiError::Upstream { service, source }
+    }
+
+    // Only a duplicate ISBN of what the storage raises is the client's doing,
+    // so the rest is logged and its detail kept to ourselves.
+    pub fn response(&self) -> Response<Body> {
+        match self {
+            ApiError::BadRequest(message) => problem::respond(StatusCode::BAD_REQUEST, message),
+            ApiError::NotFound(message) => problem::respond(StatusCode::NOT_FOUND, message),
+            ApiError::Validation { status, errors } => Problem::validation(*status, errors.clone()).response(),
+            ApiError::Conflict(message) => problem::respond(StatusCode::CONFLICT, message),
+            ApiError::Changed(changed) => changed.response(),
+            ApiError::Storage(StorageError::DuplicateIsbn { isbn, id }) => Problem::duplicate_isbn(isbn, *id).response(),
+            ApiError::Storage(StorageError::Backlogged(pending)) => {
+                tracing::warn!("refused an update, {} events are waiting for the broker", pending);
+                let mut response = problem::respond(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many events are waiting for the event broker, try again later",
+                );
+                response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(30));
+                response
+            }
+            ApiError::Storage(StorageError::Failed(e)) => {
+                tracing::error!("storage error: {}", e);
+                problem::respond(StatusCode::INTERNAL_SERVER_ERROR, "Storage error")
+            }
+            ApiError::Upstream { service, source } => source.response(service),
+            ApiError::Http(e) => {
+                tracing::error!("handling a request failed: {}", e);
+                problem::respond(StatusCode::INTERNAL_SERVER_ERROR, "The request couldn't be handled")
+            }
+        }
+    }
+
+    // What a handler's router hands hyper: the problem, or the body error.
+    pub fn into_result(self) -> Result<Response<Body>, body::Error> {
+        match self {
+            ApiError::Http(e) => Err(e),
+            e => Ok(e.response()),
+        }
+    }
+}
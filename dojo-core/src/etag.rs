@@ -0,0 +1,116 @@
+use books_model::Book;
+use hyper::{header, Response, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    body::Body,
+    codec,
+    extract::{FromRequest, RequestContext},
+    problem,
+};
+
+// A book's entity tag is a hash of its JSON, so every backend and every
+// shard gives the same version of a book the same tag without storing a
+// version number.
+pub fn of(book: &Book) -> String {
+    hash(&serde_json::to_vec(book).unwrap_or_default())
+}
+
+// A strong tag for exactly these bytes.
+pub fn hash(bytes: &[u8]) -> String {
+    format!("\"{}\"", codec::hex(&Sha256::digest(bytes)[..8]))
+}
+
+pub fn tagged(mut response: Response<Body>, book: &Book) -> Response<Body> {
+    if response.status().is_success() {
+        if let Ok(value) = of(book).parse() {
+            response.headers_mut().insert(header::ETAG, value);
+        }
+    }
+    response
+}
+
+// The If-Match header PUT and DELETE on a book require, so a client can't
+// overwrite a change it hasn't seen. `*` matches any version.
+pub enum IfMatch {
+    Any,
+    OneOf(Vec<String>),
+}
+
+impl FromRequest for IfMatch {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let mut values = Vec::new();
+        for value in ctx.headers().get_all(header::IF_MATCH) {
+            let Ok(value) = value.to_str() else {
+                return Err(crate::bad_request("If-Match isn't valid text"));
+            };
+            values.push(value);
+        }
+        IfMatch::parse(&values.join(",")).ok_or_else(|| {
+            text(
+                StatusCode::PRECONDITION_REQUIRED,
+                "send If-Match with the book's ETag, or * for any version".to_string(),
+            )
+        })
+    }
+}
+
+impl IfMatch {
+    // A comma-separated list of tags as If-Match has it. None without any.
+    pub fn parse(value: &str) -> Option<Self> {
+        let tags: Vec<String> = value.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(str::to_string).collect();
+        if tags.is_empty() {
+            return None;
+        }
+        match tags.iter().any(|tag| tag == "*") {
+            true => Some(IfMatch::Any),
+            false => Some(IfMatch::OneOf(tags)),
+        }
+    }
+
+    // Strong comparison: a weak tag never matches.
+    pub fn holds(&self, book: &Book) -> bool {
+        match self {
+            IfMatch::Any => true,
+            IfMatch::OneOf(tags) => tags.contains(&of(book)),
+        }
+    }
+
+    pub fn check(&self, book: &Book) -> Result<(), Changed> {
+        match self.holds(book) {
+            true => Ok(()),
+            false => Err(Changed {
+                id: book.id,
+                current: of(book),
+            }),
+        }
+    }
+}
+
+// The book has changed since the client read it.
+#[derive(Debug, thiserror::Error)]
+#[error("book {id} has changed, its ETag is now {current}")]
+pub struct Changed {
+    id: u64,
+    current: String,
+}
+
+impl Changed {
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    // A 412 with the current tag, but not the book, which the client has to
+    // fetch anyway.
+    pub fn response(&self) -> Response<Body> {
+        let mut response = text(StatusCode::PRECONDITION_FAILED, self.to_string());
+        if let Ok(value) = self.current.parse() {
+            response.headers_mut().insert(header::ETAG, value);
+        }
+        response
+    }
+}
+
+fn text(status: StatusCode, message: String) -> Response<Body> {
+    problem::respond(status, message)
+}
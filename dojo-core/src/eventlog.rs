@@ -0,0 +1,401 @@
+use books_model::{Author, Book};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read as _, Seek, SeekFrom, Write as _},
+    path::{Path, PathBuf},
+};
+
+use crate::{conditional::now_ms, sqlite::Write, Storage};
+
+// What happened to the catalog. A deleted book is the one that went to the
+// trash, with `deleted_at` set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Event {
+    BookCreated { book: Book },
+    BookUpdated { book: Book },
+    BookDeleted { book: Book },
+    BookRestored { book: Book },
+    AuthorCreated { author: Author },
+    AuthorDeleted { id: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    seq: u64,
+    // Milliseconds since the epoch.
+    at: u64,
+    #[serde(flatten)]
+    event: Event,
+}
+
+// How many events are appended between snapshots.
+const SNAPSHOT_EVERY: usize = 10_000;
+
+// Where a snapshot stands in the log: the last event it holds, when that
+// happened, and the byte the events after it start at.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Mark {
+    seq: u64,
+    at: u64,
+    offset: u64,
+}
+
+// The projection after the events up to `mark`, kept next to the log as
+// <path>.snapshot so that neither startup nor `as_of` has to replay the
+// events before it.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    #[serde(flatten)]
+    mark: Mark,
+    next_id: u64,
+    next_author_id: u64,
+    books: Vec<Book>,
+    trash: Vec<Book>,
+    authors: Vec<Author>,
+}
+
+// DOJO_STORAGE=events:<path> keeps the catalog as the events that made it,
+// one JSON record per line, appended before the request is answered and
+// never rewritten. The books in memory are a projection of them, rebuilt
+// on startup from the latest snapshot and the events after it, and so is
+// the catalog at any earlier time. The events themselves stay on disk and
+// are read back a line at a time.
+pub struct EventLog {
+    path: PathBuf,
+    file: File,
+    // The last event written, when, and the length of the log after it.
+    seq: u64,
+    at: u64,
+    len: u64,
+    snapshot: Option<Mark>,
+    // Events appended since the snapshot.
+    unsnapshotted: usize,
+    snapshot_every: usize,
+}
+
+impl EventLog {
+    // Opens the log and reads through the events after the snapshot. As in
+    // the write-ahead log, a line without its newline was cut short by a
+    // crash, so it's dropped.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let describe = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| describe(&e))?;
+        let snapshot = match File::open(snapshot_path(&path)) {
+            // Only the mark is kept; the books are skipped over unread.
+            Ok(snapshot) => Some(
+                serde_json::from_reader::<_, Mark>(BufReader::new(snapshot))
+                    .map_err(|e| describe(&format!("the snapshot: {}", e)))?,
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(describe(&format!("the snapshot: {}", e))),
+        };
+        let mark = snapshot.unwrap_or_default();
+        let len = file.metadata().map_err(|e| describe(&e))?.len();
+        if mark.offset > len {
+            return Err(describe(&"the snapshot holds events the log doesn't"));
+        }
+
+        let mut reader = BufReader::new(&file);
+        reader.seek(SeekFrom::Start(mark.offset)).map_err(|e| describe(&e))?;
+        let (mut seq, mut at, mut end, mut unsnapshotted) = (mark.seq, mark.at, mark.offset, 0);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line).map_err(|e| describe(&e))?;
+            if read == 0 {
+                break;
+            }
+            if line.last() != Some(&b'\n') {
+                tracing::warn!("dropping an unfinished event at the end of {}", path.display());
+                file.set_len(end).map_err(|e| describe(&e))?;
+                break;
+            }
+            if line.len() > 1 {
+                let record: Record =
+                    serde_json::from_slice(&line).map_err(|e| describe(&format!("the event at byte {}: {}", end, e)))?;
+                (seq, at) = (record.seq, record.at);
+                unsnapshotted += 1;
+            }
+            end += read as u64;
+        }
+        Ok(EventLog { path, file, seq, at, len: end, snapshot, unsnapshotted, snapshot_every: SNAPSHOT_EVERY })
+    }
+
+    pub fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    // Rebuilds the catalog, the trash and the authors from the snapshot and
+    // the events after it, and says how many events that took.
+    pub fn project(&self, storage: &mut Storage) -> Result<usize, String> {
+        let mut from = 0;
+        if let Some(mark) = self.snapshot {
+            let snapshot = self.load().map_err(|e| self.failed(e))?;
+            storage.next_id = storage.next_id.max(snapshot.next_id);
+            storage.next_author_id = storage.next_author_id.max(snapshot.next_author_id);
+            for book in snapshot.books {
+                storage.index.insert(&book);
+                storage.modified.touch(book.id);
+                storage.books.insert(book.id, book);
+            }
+            storage.deleted.extend(snapshot.trash.into_iter().map(|book| (book.id, book)));
+            storage.authors.extend(snapshot.authors.into_iter().map(|author| (author.id, author)));
+            from = mark.offset;
+        }
+        let mut replayed = 0;
+        for record in self.records(from).map_err(|e| self.failed(e))? {
+            match record.map_err(|e| self.failed(e))?.event {
+                Event::BookCreated { book } | Event::BookUpdated { book } | Event::BookRestored { book } => {
+                    storage.next_id = storage.next_id.max(book.id + 1);
+                    storage.index.insert(&book);
+                    storage.modified.touch(book.id);
+                    storage.deleted.remove(&book.id);
+                    storage.books.insert(book.id, book);
+                }
+                Event::BookDeleted { book } => {
+                    storage.next_id = storage.next_id.max(book.id + 1);
+                    storage.books.remove(&book.id);
+                    storage.index.remove(book.id);
+                    storage.modified.forget(book.id);
+                    storage.deleted.insert(book.id, book);
+                }
+                Event::AuthorCreated { author } => {
+                    storage.next_author_id = storage.next_author_id.max(author.id + 1);
+                    storage.authors.insert(author.id, author);
+                }
+                Event::AuthorDeleted { id } => {
+                    storage.authors.remove(&id);
+                }
+            }
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+
+    // The catalog, without the trash, after the events up to `at`, in id
+    // order. Replaying starts from the snapshot when it's old enough, and
+    // from the first event otherwise.
+    pub fn as_of(&self, at: u64) -> Result<Vec<Book>, String> {
+        let mut books = BTreeMap::new();
+        let mut from = 0;
+        if let Some(mark) = self.snapshot.filter(|mark| mark.at <= at) {
+            let snapshot = self.load().map_err(|e| self.failed(e))?;
+            books.extend(snapshot.books.into_iter().map(|book| (book.id, book)));
+            from = mark.offset;
+        }
+        for record in self.records(from).map_err(|e| self.failed(e))? {
+            let record = record.map_err(|e| self.failed(e))?;
+            if record.at > at {
+                break;
+            }
+            match record.event {
+                Event::BookCreated { book } | Event::BookUpdated { book } | Event::BookRestored { book } => {
+                    books.insert(book.id, book);
+                }
+                Event::BookDeleted { book } => {
+                    books.remove(&book.id);
+                }
+                Event::AuthorCreated { .. } | Event::AuthorDeleted { .. } => {}
+            }
+        }
+        Ok(books.into_values().collect())
+    }
+
+    // Appends an update's changes to books and authors and waits for them
+    // to reach the disk.
+    pub fn append(&mut self, writes: &[Write]) -> io::Result<()> {
+        let at = now_ms();
+        let mut seq = self.seq;
+        let mut lines = Vec::new();
+        for write in writes {
+            let event = match write {
+                Write::Created(book) => Event::BookCreated { book: book.clone() },
+                Write::Updated(book) => Event::BookUpdated { book: book.clone() },
+                Write::Trashed(book) => Event::BookDeleted { book: book.clone() },
+                Write::Restored(book) => Event::BookRestored { book: book.clone() },
+                Write::AuthorCreated(author) => Event::AuthorCreated { author: author.clone() },
+                Write::AuthorDeleted(id) => Event::AuthorDeleted { id: *id },
+                Write::Event(_) | Write::Delivered(_) | Write::Hook(_) | Write::Hooked(_) => continue,
+            };
+            seq += 1;
+            serde_json::to_writer(&mut lines, &Record { seq, at, event })?;
+            lines.push(b'\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&lines)?;
+        self.file.sync_data()?;
+        self.unsnapshotted += (seq - self.seq) as usize;
+        (self.seq, self.at, self.len) = (seq, at, self.len + lines.len() as u64);
+        Ok(())
+    }
+
+    // Whether enough events were appended since the last snapshot to take
+    // another.
+    pub fn snapshot_due(&self) -> bool {
+        self.unsnapshotted >= self.snapshot_every
+    }
+
+    // Writes the projection as it stands after the last event appended. It
+    // replaces the previous snapshot only once it's all on disk.
+    pub fn snapshot(&mut self, storage: &Storage) -> io::Result<()> {
+        let mark = Mark { seq: self.seq, at: self.at, offset: self.len };
+        let snapshot = Snapshot {
+            mark,
+            next_id: storage.next_id,
+            next_author_id: storage.next_author_id,
+            books: storage.books.values().cloned().collect(),
+            trash: storage.deleted.values().cloned().collect(),
+            authors: storage.authors.values().cloned().collect(),
+        };
+        let path = snapshot_path(&self.path);
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        serde_json::to_writer(&mut writer, &snapshot)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp, &path)?;
+        self.snapshot = Some(mark);
+        self.unsnapshotted = 0;
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Snapshot> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(snapshot_path(&self.path))?))?)
+    }
+
+    // The events from byte `from` to the end of the log, read as they're
+    // needed.
+    fn records(&self, from: u64) -> io::Result<impl Iterator<Item = io::Result<Record>>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(from))?;
+        let lines = BufReader::new(file.take(self.len - from)).split(b'\n');
+        Ok(lines.filter(|line| !line.as_ref().is_ok_and(Vec::is_empty)).map(|line| Ok(serde_json::from_slice(&line?)?)))
+    }
+
+    fn failed(&self, e: io::Error) -> String {
+        format!("{}: {}", self.describe(), e)
+    }
+}
+
+fn snapshot_path(log: &Path) -> PathBuf {
+    let mut path = log.to_path_buf().into_os_string();
+    path.push(".snapshot");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(id: u64, title: &str) -> Book {
+        serde_json::from_value(serde_json::json!({ "id": id, "title": title, "author": "Lem" })).unwrap()
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("dojo-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(snapshot_path(&path));
+        path
+    }
+
+    fn titles(storage: &Storage) -> Vec<&str> {
+        storage.books.values().map(|book| book.title.as_str()).collect()
+    }
+
+    #[test]
+    fn reopened_logs_project_the_same_catalog() {
+        let path = scratch("eventlog-reopen");
+        let mut log = EventLog::open(&path).unwrap();
+        let mut trashed = book(2, "Eden");
+        trashed.deleted_at = Some(1);
+        log.append(&[Write::Created(book(1, "Solaris")), Write::Created(book(2, "Eden"))]).unwrap();
+        log.append(&[Write::Updated(book(1, "Fiasco")), Write::Trashed(trashed), Write::Delivered("e1".to_string())])
+            .unwrap();
+        // Cut short by a crash mid-write.
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"seq\":5").unwrap();
+
+        let log = EventLog::open(&path).unwrap();
+        assert_eq!(log.seq, 4);
+        let mut storage = Storage::new();
+        assert_eq!(log.project(&mut storage).unwrap(), 4);
+        assert_eq!(titles(&storage), ["Fiasco"]);
+        assert!(storage.deleted.contains_key(&2));
+        assert_eq!(storage.next_id, 3);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn write_records(path: &Path, records: &[Record]) {
+        let lines: String = records.iter().map(|record| serde_json::to_string(record).unwrap() + "\n").collect();
+        OpenOptions::new().append(true).create(true).open(path).unwrap().write_all(lines.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn as_of_replays_up_to_the_time() {
+        let path = scratch("eventlog-as-of");
+        write_records(
+            &path,
+            &[
+                Record { seq: 1, at: 10, event: Event::BookCreated { book: book(1, "Solaris") } },
+                Record { seq: 2, at: 20, event: Event::BookUpdated { book: book(1, "Fiasco") } },
+                Record { seq: 3, at: 30, event: Event::BookDeleted { book: book(1, "Fiasco") } },
+            ],
+        );
+        let log = EventLog::open(&path).unwrap();
+        let titles = |at| log.as_of(at).unwrap().into_iter().map(|book| book.title).collect::<Vec<_>>();
+        assert!(titles(5).is_empty());
+        assert_eq!(titles(10), ["Solaris"]);
+        assert_eq!(titles(29), ["Fiasco"]);
+        assert!(titles(30).is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn snapshots_stand_in_for_the_events_before_them() {
+        let path = scratch("eventlog-snapshot");
+        write_records(
+            &path,
+            &[
+                Record { seq: 1, at: 10, event: Event::BookCreated { book: book(1, "Solaris") } },
+                Record { seq: 2, at: 20, event: Event::BookCreated { book: book(2, "Eden") } },
+            ],
+        );
+        let mut log = EventLog::open(&path).unwrap();
+        let mut storage = Storage::new();
+        log.project(&mut storage).unwrap();
+        log.snapshot(&storage).unwrap();
+        write_records(&path, &[Record { seq: 3, at: 30, event: Event::BookUpdated { book: book(1, "Fiasco") } }]);
+
+        let mut log = EventLog::open(&path).unwrap();
+        let mut storage = Storage::new();
+        assert_eq!(log.project(&mut storage).unwrap(), 1);
+        assert_eq!(titles(&storage), ["Fiasco", "Eden"]);
+        assert_eq!(storage.next_id, 3);
+        // The events before the snapshot are still there for earlier times.
+        let titles_at = |at| log.as_of(at).unwrap().into_iter().map(|book| book.title).collect::<Vec<_>>();
+        assert_eq!(titles_at(15), ["Solaris"]);
+        assert_eq!(titles_at(20), ["Solaris", "Eden"]);
+        assert_eq!(titles_at(30), ["Fiasco", "Eden"]);
+
+        log.snapshot_every = 2;
+        log.append(&[Write::Created(book(3, "Ubik"))]).unwrap();
+        assert!(log.snapshot_due());
+
+        // Without the snapshot the whole log is replayed to the same catalog.
+        std::fs::remove_file(snapshot_path(&path)).unwrap();
+        let mut storage = Storage::new();
+        assert_eq!(EventLog::open(&path).unwrap().project(&mut storage).unwrap(), 4);
+        assert_eq!(titles(&storage), ["Fiasco", "Eden", "Ubik"]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
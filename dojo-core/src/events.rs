@@ -0,0 +1,518 @@
+use hyper::{header, Method, Request, StatusCode};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::Notify,
+};
+
+use crate::{
+    body::{self, Body},
+    sqlite::Write,
+    SharedState, StorageError,
+};
+
+// Undelivered events held while the broker is down. None are ever dropped:
+// once this many are waiting, updates are refused until the broker takes
+// some, so the outbox can't grow without bound.
+pub const MAX_PENDING: usize = 100_000;
+const BATCH: usize = 100;
+
+// The update that takes delivered events out of the outbox, the one update
+// a full outbox lets through.
+pub const ACK: &str = "events_ack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Topic {
+    Books,
+    Loans,
+    // Acquisition requests, the library's purchase orders.
+    Acquisitions,
+}
+
+impl Topic {
+    const ALL: [Topic; 3] = [Topic::Books, Topic::Loans, Topic::Acquisitions];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Topic::Books => "books",
+            Topic::Loans => "loans",
+            Topic::Acquisitions => "acquisitions",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Topic> {
+        Topic::ALL.into_iter().find(|topic| topic.name() == name)
+    }
+}
+
+// Sent with every event. Bump it whenever an event's `data` changes
+// incompatibly, so consumers can tell the shapes apart.
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub version: u32,
+    pub source: String,
+    pub occurred_at_ms: u64,
+    // The broker key, so events about one entity stay in order.
+    pub key: String,
+    pub data: Value,
+    #[serde(skip)]
+    pub topic: Topic,
+}
+
+impl Event {
+    // `seq` only needs to be unique per source and millisecond.
+    pub fn new(source: &str, seq: u64, topic: Topic, kind: &'static str, key: u64, data: impl Serialize) -> Self {
+        let occurred_at_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        Event {
+            id: format!("{}-{}-{}", source, occurred_at_ms, seq),
+            kind: kind.to_string(),
+            version: SCHEMA_VERSION,
+            source: source.to_string(),
+            occurred_at_ms,
+            key: key.to_string(),
+            data: serde_json::to_value(data).unwrap_or(Value::Null),
+            topic,
+        }
+    }
+}
+
+// Events waiting for the relay, recorded in the same storage update as the
+// change they describe.
+pub struct Outbox {
+    source: String,
+    next_seq: u64,
+    queue: VecDeque<Event>,
+    // Whether updates are being refused, to warn once when they start.
+    full: bool,
+    notify: Arc<Notify>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, topic: Topic, kind: &'static str, key: u64, data: impl Serialize) -> &Event {
+        self.next_seq += 1;
+        self.queue.push_back(Event::new(&self.source, self.next_seq, topic, kind, key, data));
+        self.notify.notify_one();
+        self.queue.back().unwrap()
+    }
+
+    // Puts back events that were stored but not delivered before a restart.
+    pub fn restore(&mut self, events: Vec<Event>) {
+        self.queue.extend(events);
+        self.notify.notify_one();
+    }
+
+    // Takes back events recorded by an update that didn't make it to disk.
+    pub fn forget(&mut self, ids: &[&str]) {
+        self.queue.retain(|event| !ids.contains(&event.id.as_str()));
+    }
+
+    // Undelivered events.
+    pub fn backlog(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Whether an update has to wait for the broker to take some events
+    // before it may add more.
+    pub fn admit(&mut self) -> Result<(), StorageError> {
+        let full = self.queue.len() >= MAX_PENDING;
+        if full != self.full {
+            match full {
+                true => tracing::warn!("{} events are waiting for the broker, refusing updates", self.queue.len()),
+                false => tracing::info!("the event backlog is below {} again, accepting updates", MAX_PENDING),
+            }
+            self.full = full;
+        }
+        match full {
+            true => Err(StorageError::Backlogged(self.queue.len())),
+            false => Ok(()),
+        }
+    }
+}
+
+enum Broker {
+    Nats { addr: String },
+    KafkaRest { url: String, client: Box<Client<HttpConnector, Body>> },
+}
+
+// DOJO_EVENTS_BROKER picks the broker: `nats://host:port`, or
+// `kafka-rest+http://host:port` for Kafka through a REST proxy.
+// DOJO_EVENTS_TOPICS overrides topic names as `books=x,loans=y`; they
+// default to dojo.books, dojo.loans and dojo.acquisitions.
+pub struct Publisher {
+    broker: Broker,
+    topics: BTreeMap<Topic, String>,
+    notify: Arc<Notify>,
+}
+
+impl Publisher {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(url) = std::env::var("DOJO_EVENTS_BROKER") else {
+            return Ok(None);
+        };
+        let broker = if let Some(addr) = url.strip_prefix("nats://") {
+            let addr = addr.trim_end_matches('/');
+            Broker::Nats {
+                addr: match addr.contains(':') {
+                    true => addr.to_string(),
+                    false => format!("{}:4222", addr),
+                },
+            }
+        } else if let Some(url) = url.strip_prefix("kafka-rest+") {
+            if !url.starts_with("http://") {
+                return Err(format!("expected kafka-rest+http://host:port, got {:?}", url));
+            }
+            Broker::KafkaRest {
+                url: url.trim_end_matches('/').to_string(),
+                client: Box::new(Client::builder(TokioExecutor::new()).build_http()),
+            }
+        } else {
+            return Err(format!(
+                "DOJO_EVENTS_BROKER must start with nats:// or kafka-rest+http://, got {:?}",
+                url
+            ));
+        };
+
+        let mut topics: BTreeMap<Topic, String> =
+            Topic::ALL.iter().map(|topic| (*topic, format!("dojo.{}", topic.name()))).collect();
+        let overrides = std::env::var("DOJO_EVENTS_TOPICS").unwrap_or_default();
+        for entry in overrides.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, topic) = entry
+                .split_once('=')
+                .filter(|(_, topic)| !topic.is_empty())
+                .ok_or_else(|| format!("invalid topic entry {:?}, expected name=topic", entry))?;
+            let key = Topic::from_name(name)
+                .ok_or_else(|| format!("unknown event topic {:?}, expected books, loans or acquisitions", name))?;
+            topics.insert(key, topic.to_string());
+        }
+        Ok(Some(Publisher {
+            broker,
+            topics,
+            notify: Arc::new(Notify::new()),
+        }))
+    }
+
+    pub fn outbox(&self) -> Outbox {
+        Outbox {
+            source: crate::leader::node_name(),
+            next_seq: 0,
+            queue: VecDeque::new(),
+            full: false,
+            notify: self.notify.clone(),
+        }
+    }
+}
+
+struct Nats {
+    stream: BufStream<TcpStream>,
+}
+
+impl Nats {
+    async fn connect(addr: &str) -> Result<Self, String> {
+        let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+        let mut nats = Nats {
+            stream: BufStream::new(stream),
+        };
+        // The server greets with INFO before anything else.
+        nats.read_line().await?;
+        nats.write(br#"CONNECT {"verbose":false,"pedantic":false,"name":"book-api"}"#).await?;
+        Ok(nats)
+    }
+
+    async fn write(&mut self, line: &[u8]) -> Result<(), String> {
+        self.stream.write_all(line).await.map_err(|e| e.to_string())?;
+        self.stream.write_all(b"\r\n").await.map_err(|e| e.to_string())
+    }
+
+    async fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+            return Err("connection closed".to_string());
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    // NATS doesn't acknowledge publishes, so a PING after the batch stands
+    // in: its PONG means everything before it was processed.
+    async fn publish(&mut self, messages: &[(&str, Vec<u8>)]) -> Result<(), String> {
+        for (subject, payload) in messages {
+            self.write(format!("PUB {} {}", subject, payload.len()).as_bytes()).await?;
+            self.write(payload).await?;
+        }
+        self.write(b"PING").await?;
+        self.stream.flush().await.map_err(|e| e.to_string())?;
+        loop {
+            match self.read_line().await?.as_str() {
+                "PONG" => return Ok(()),
+                "PING" => self.write(b"PONG").await?,
+                line if line.starts_with("-ERR") => return Err(line.to_string()),
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn publish_kafka(
+    client: &Client<HttpConnector, Body>,
+    url: &str,
+    topic: &str,
+    events: &[&Event],
+) -> Result<(), String> {
+    let records: Vec<Value> = events
+        .iter()
+        .map(|event| serde_json::json!({ "key": event.key, "value": event }))
+        .collect();
+    let body = serde_json::to_vec(&serde_json::json!({ "records": records })).map_err(|e| e.to_string())?;
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("{}/topics/{}", url, topic))
+        .header(header::CONTENT_TYPE, "application/vnd.kafka.json.v2+json")
+        .header(header::ACCEPT, "application/vnd.kafka.v2+json")
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    let response = client.request(req).await.map_err(|e| e.to_string())?;
+    if response.status() != StatusCode::OK {
+        return Err(format!("REST proxy answered {}", response.status()));
+    }
+    // The proxy reports per-record failures inside a 200.
+    let body = body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+    let reply: Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+    let failed = reply["offsets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(|offset| offset["error"].as_str().map(str::to_string));
+    match failed {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+// Publishes batches to the configured broker, keeping the NATS connection
+// open between them.
+struct Sender<'a> {
+    publisher: &'a Publisher,
+    nats: Option<Nats>,
+    backoff: Duration,
+    failing: bool,
+}
+
+impl<'a> Sender<'a> {
+    fn new(publisher: &'a Publisher) -> Self {
+        Sender {
+            publisher,
+            nats: None,
+            backoff: Duration::from_millis(200),
+            failing: false,
+        }
+    }
+
+    async fn send(&mut self, batch: &[Event]) -> Result<(), String> {
+        let publisher = self.publisher;
+        match &publisher.broker {
+            Broker::Nats { addr } => {
+                let messages: Vec<(&str, Vec<u8>)> = batch
+                    .iter()
+                    .map(|event| (publisher.topics[&event.topic].as_str(), serde_json::to_vec(event).unwrap_or_default()))
+                    .collect();
+                let nats = &mut self.nats;
+                let attempt = async {
+                    if nats.is_none() {
+                        *nats = Some(Nats::connect(addr).await?);
+                    }
+                    nats.as_mut().unwrap().publish(&messages).await
+                };
+                let result = tokio::time::timeout(Duration::from_secs(10), attempt)
+                    .await
+                    .unwrap_or_else(|_| Err("timed out".to_string()));
+                if result.is_err() {
+                    self.nats = None;
+                }
+                result
+            }
+            Broker::KafkaRest { url, client } => {
+                // One request per topic, keeping the batch's order within it.
+                for topic in Topic::ALL {
+                    let events: Vec<&Event> = batch.iter().filter(|event| event.topic == topic).collect();
+                    if events.is_empty() {
+                        continue;
+                    }
+                    let publish = publish_kafka(client, url, &publisher.topics[&topic], &events);
+                    tokio::time::timeout(Duration::from_secs(10), publish)
+                        .await
+                        .unwrap_or_else(|_| Err("timed out".to_string()))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn delivered(&mut self) {
+        if self.failing {
+            tracing::info!("event broker is reachable again");
+            self.failing = false;
+        }
+        self.backoff = Duration::from_millis(200);
+    }
+
+    async fn failed(&mut self, error: String) {
+        if !self.failing {
+            tracing::warn!("publishing events failed, will retry: {}", error);
+            self.failing = true;
+        }
+        tokio::time::sleep(self.backoff).await;
+        self.backoff = (self.backoff * 2).min(Duration::from_secs(30));
+    }
+}
+
+// Drains the outbox in order. A batch is only removed once the broker has
+// taken it, so events survive broker outages (and restarts, when the
+// catalog is kept on disk) and may be delivered twice after a failure.
+pub async fn run(state: SharedState) {
+    let Some(publisher) = &state.events else {
+        return;
+    };
+    let mut sender = Sender::new(publisher);
+    loop {
+        let batch = state
+            .read_storage("events_peek", String::new, |storage| {
+                let outbox = storage.outbox.as_ref()?;
+                Some(outbox.queue.iter().take(BATCH).cloned().collect::<Vec<Event>>())
+            })
+            .await;
+        let batch = match batch {
+            Ok(Some(batch)) if !batch.is_empty() => batch,
+            Ok(_) => {
+                publisher.notify.notified().await;
+                continue;
+            }
+            Err(_) => {
+                tokio::time::sleep(sender.backoff).await;
+                continue;
+            }
+        };
+
+        match sender.send(&batch).await {
+            Ok(()) => {
+                let count = batch.len();
+                let last = batch.last().map(|event| event.id.clone());
+                let _ = state
+                    .with_storage(ACK, || format!("events={}", count), |storage| {
+                        if let Some(outbox) = storage.outbox.as_mut() {
+                            // Events taken back while the batch was in
+                            // flight shift the queue, so only matching ids go.
+                            let position = outbox.queue.iter().position(|event| Some(&event.id) == last.as_ref());
+                            if let Some(position) = position {
+                                outbox.queue.drain(..=position);
+                            }
+                        }
+                        if let Some(last) = last {
+                            storage.record(Write::Delivered(last));
+                        }
+                    })
+                    .await;
+                sender.delivered();
+            }
+            Err(e) => sender.failed(e).await,
+        }
+    }
+}
+
+// Relays the events Postgres recorded with each catalog change. The batch
+// stays locked until it's been published and deleted, so instances sharing
+// the database take turns and events keep their order.
+pub async fn run_postgres(state: SharedState) {
+    let (Some(publisher), Some(postgres)) = (&state.events, &state.postgres) else {
+        return;
+    };
+    let mut sender = Sender::new(publisher);
+    loop {
+        let claimed = match postgres.claim_events(BATCH).await {
+            Ok(Some(claimed)) => claimed,
+            Ok(None) => {
+                // Other instances don't wake this one, so poll as well.
+                let _ = tokio::time::timeout(Duration::from_secs(1), postgres.events_ready()).await;
+                continue;
+            }
+            Err(e) => {
+                sender.failed(format!("reading the outbox: {}", e)).await;
+                continue;
+            }
+        };
+        match sender.send(claimed.events()).await {
+            Ok(()) => match postgres.finish(claimed, true).await {
+                Ok(()) => sender.delivered(),
+                Err(e) => sender.failed(format!("clearing the outbox: {}", e)).await,
+            },
+            Err(e) => {
+                let _ = postgres.finish(claimed, false).await;
+                sender.failed(e).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Sim;
+    use hyper::Method;
+
+    fn outbox() -> Outbox {
+        Outbox {
+            source: "test".to_string(),
+            next_seq: 0,
+            queue: VecDeque::new(),
+            full: false,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    #[test]
+    fn undelivered_events_are_never_dropped() {
+        let mut outbox = outbox();
+        for id in 0..MAX_PENDING as u64 + 10 {
+            outbox.push(Topic::Books, "book.created", id, Value::Null);
+        }
+        assert_eq!(outbox.backlog(), MAX_PENDING + 10);
+        assert_eq!(outbox.queue.front().map(|event| event.key.as_str()), Some("0"));
+        assert!(matches!(outbox.admit(), Err(StorageError::Backlogged(pending)) if pending == MAX_PENDING + 10));
+        outbox.queue.drain(..11);
+        assert!(outbox.admit().is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_full_outbox_holds_back_writes_until_events_are_delivered() {
+        let sim = Sim::new(1);
+        let mut full = outbox();
+        for id in 0..MAX_PENDING as u64 {
+            full.push(Topic::Books, "book.created", id, Value::Null);
+        }
+        sim.state.storage.write().await.outbox = Some(full);
+
+        let book = || Some(serde_json::json!({ "title": "Solaris", "author": "Stanislaw Lem" }));
+        assert_eq!(sim.request(Method::POST, "/books", book()).await.0, StatusCode::SERVICE_UNAVAILABLE);
+        // Acknowledging delivered events is what makes room.
+        sim.state
+            .with_storage(ACK, String::new, |storage| storage.outbox.as_mut().unwrap().queue.drain(..BATCH).count())
+            .await
+            .unwrap();
+        assert_eq!(sim.request(Method::POST, "/books", book()).await.0, StatusCode::CREATED);
+        assert_eq!(sim.state.storage.read().await.outbox.as_ref().unwrap().backlog(), MAX_PENDING - BATCH + 1);
+    }
+}
@@ -0,0 +1,151 @@
+use books_model::Book;
+use hyper::{header, Response, StatusCode};
+use serde::Deserialize;
+use std::io::Write;
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    extract::Query,
+    listing::Filter,
+    store::{BookStore, Store},
+    tenants,
+};
+
+// How many books are read from the store for each chunk of the stream.
+const PAGE: usize = 500;
+
+const COLUMNS: &[&str] = &["id", "title", "author", "isbn"];
+
+#[derive(Debug, Deserialize)]
+pub struct Export {
+    format: Option<String>,
+}
+
+impl Export {
+    pub fn check(&self) -> Result<(), String> {
+        match self.format.as_deref() {
+            Some("csv") => Ok(()),
+            Some(other) => Err(format!("unknown export format {:?}, expected csv", other)),
+            None => Err("format is required, e.g. ?format=csv".to_string()),
+        }
+    }
+}
+
+// RFC 4180 with CRLF line ends, led by a UTF-8 byte order mark so that
+// spreadsheets don't read the text as Latin-1.
+pub fn header_row() -> String {
+    format!("\u{feff}{}\r\n", COLUMNS.join(","))
+}
+
+pub fn row(book: &Book) -> String {
+    let id = book.id.to_string();
+    let fields = [id.as_str(), &book.title, &book.author, book.isbn.as_deref().unwrap_or_default()];
+    let mut line = fields.map(field).join(",");
+    line.push_str("\r\n");
+    line
+}
+
+// Quoted when it has to be. A leading =, +, - or @ would be run as a
+// formula by a spreadsheet, so it's escaped with a ' the way spreadsheets
+// mark text themselves.
+fn field(value: &str) -> String {
+    let value = match value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        true => format!("'{}", value),
+        false => value.to_string(),
+    };
+    match value.contains([',', '"', '\r', '\n']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value,
+    }
+}
+
+pub fn csv_response(body: Body) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/csv; charset=utf-8")
+        .header(header::CONTENT_DISPOSITION, "attachment; filename=\"books.csv\"")
+        .body(body)
+        .unwrap()
+}
+
+// GET /books/export?format=csv streams the catalog in id order a page at a
+// time, so a large catalog isn't held in memory. The listing filters apply.
+// The status is sent before the first page is read; a storage error after
+// that cuts the download short rather than ending it cleanly.
+pub async fn export_books<S: BookStore>(
+    Query(export): Query<Export>,
+    Query(filter): Query<Filter>,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    export.check().map_err(ApiError::BadRequest)?;
+    // The first page is read up front, so a store that's down is a 500.
+    let first = store.list_after(0, PAGE).await?;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(tenants::carry(async move {
+        let mut page = first;
+        let mut chunk = header_row();
+        loop {
+            let after = page.last().map(|book| book.id);
+            for book in page.iter().filter(|book| filter.matches(book)) {
+                chunk.push_str(&row(book));
+            }
+            if sender.send_data(std::mem::take(&mut chunk).into()).await.is_err() {
+                return;
+            }
+            let Some(after) = after.filter(|_| page.len() == PAGE) else {
+                return;
+            };
+            page = match store.list_after(after, PAGE).await {
+                Ok(books) => books,
+                Err(e) => {
+                    tracing::warn!("export stopped after book {}: {}", after, e);
+                    sender.abort();
+                    return;
+                }
+            };
+        }
+    }));
+    Ok(csv_response(body))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+// `book-api export`: the whole catalog in id order, read a page at a time
+// like the endpoint does. JSON is an array of books, one per line, which
+// the import reads back. Returns how many books were written.
+pub async fn write<S: BookStore>(store: &S, format: Format, out: &mut impl Write) -> Result<usize, String> {
+    let mut written = 0;
+    let mut after = 0;
+    let mut chunk = match format {
+        Format::Csv => header_row(),
+        Format::Json => "[".to_string(),
+    };
+    loop {
+        let page = store.list_after(after, PAGE).await.map_err(|e| format!("reading the catalog failed: {}", e))?;
+        for book in &page {
+            match format {
+                Format::Csv => chunk.push_str(&row(book)),
+                Format::Json => {
+                    chunk.push_str(if written == 0 { "\n" } else { ",\n" });
+                    chunk.push_str(&serde_json::to_string(book).unwrap());
+                }
+            }
+            written += 1;
+        }
+        out.write_all(std::mem::take(&mut chunk).as_bytes()).map_err(|e| e.to_string())?;
+        match page.last() {
+            Some(last) if page.len() == PAGE => after = last.id,
+            _ => break,
+        }
+    }
+    if let Format::Json = format {
+        out.write_all(b"\n]\n").map_err(|e| e.to_string())?;
+    }
+    out.flush().map_err(|e| e.to_string())?;
+    Ok(written)
+}
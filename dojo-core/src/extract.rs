@@ -0,0 +1,197 @@
+use hyper::{header::HeaderMap, http::request::Parts, upgrade::OnUpgrade, Request, Response, StatusCode, Uri};
+use serde::de::DeserializeOwned;
+use std::{future::Future, str::FromStr};
+
+use crate::{
+    bad_request,
+    body::{self, Body},
+    error::ApiError,
+    negotiate, problem,
+    stack::ResponseFuture,
+    SharedState,
+};
+
+// Everything an extractor may need. The router fills in the path parameter
+// (and how to describe it in errors) and the API version before calling the
+// handler.
+pub struct RequestContext {
+    parts: Parts,
+    body: Option<Body>,
+    state: SharedState,
+    param: Option<(String, &'static str)>,
+    version: Option<&'static str>,
+}
+
+impl RequestContext {
+    pub fn new(req: Request<Body>, state: SharedState) -> Self {
+        let (parts, body) = req.into_parts();
+        RequestContext {
+            parts,
+            body: Some(body),
+            state,
+            param: None,
+            version: None,
+        }
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.parts.headers
+    }
+
+    pub fn parts(&self) -> &Parts {
+        &self.parts
+    }
+
+    pub fn state(&self) -> &SharedState {
+        &self.state
+    }
+
+    pub fn take_body(&mut self) -> Body {
+        self.body.take().unwrap_or_else(Body::empty)
+    }
+
+    // What hyper hands over once a 101 response is sent, for the one
+    // handler that switches protocols.
+    pub fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.parts.extensions.remove::<OnUpgrade>()
+    }
+
+    pub fn with_param(mut self, value: &str, label: &'static str) -> Self {
+        self.param = Some((value.to_string(), label));
+        self
+    }
+
+    pub fn with_version(mut self, version: &'static str) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    // None for endpoints that aren't versioned.
+    pub fn version(&self) -> Option<&'static str> {
+        self.version
+    }
+
+    pub fn call<H, Args>(self, handler: H) -> ResponseFuture
+    where
+        H: Handler<Args>,
+    {
+        handler.call(self)
+    }
+}
+
+pub trait FromRequest: Sized {
+    fn from_request(ctx: &mut RequestContext) -> impl Future<Output = Result<Self, Response<Body>>> + Send;
+}
+
+pub struct Json<T>(pub T);
+
+// A body has to be declared as JSON, or 415 says what would be accepted.
+impl<T: DeserializeOwned + Send> FromRequest for Json<T> {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        if let Some(rejected) = negotiate::reject_non_json(ctx.headers()) {
+            return Err(rejected);
+        }
+        let bytes = match body::to_bytes(ctx.take_body()).await {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(bad_request("Failed to read request body")),
+        };
+        // A body that parses but has a field of the wrong shape is answered
+        // with the field's path, like `[2].year`.
+        let mut deserializer = serde_json::Deserializer::from_slice(&bytes);
+        match serde_path_to_error::deserialize(&mut deserializer) {
+            Ok(value) if deserializer.end().is_ok() => Ok(Json(value)),
+            Err(e) if e.inner().is_data() && e.path().iter().next().is_some() => {
+                Err(problem::invalid(StatusCode::BAD_REQUEST, e.path().to_string(), e.inner().to_string()))
+            }
+            _ => Err(bad_request("Invalid request body")),
+        }
+    }
+}
+
+pub struct Path<T>(pub T);
+
+impl<T: FromStr + Send> FromRequest for Path<T> {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let (value, label) = match &ctx.param {
+            Some((value, label)) => (value.as_str(), *label),
+            None => ("", "path parameter"),
+        };
+        value
+            .parse()
+            .map(Path)
+            .map_err(|_| bad_request(&format!("Invalid {}", label)))
+    }
+}
+
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromRequest for Query<T> {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let query = ctx.parts.uri.query().unwrap_or("");
+        serde_urlencoded::from_str(query)
+            .map(Query)
+            .map_err(|e| bad_request(&format!("Invalid query string: {}", e)))
+    }
+}
+
+pub struct State<S>(pub S);
+
+impl FromRequest for State<SharedState> {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        Ok(State(ctx.state.clone()))
+    }
+}
+
+impl FromRequest for HeaderMap {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        Ok(ctx.parts.headers.clone())
+    }
+}
+
+impl FromRequest for Uri {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        Ok(ctx.parts.uri.clone())
+    }
+}
+
+pub trait Handler<Args> {
+    fn call(self, ctx: RequestContext) -> ResponseFuture;
+}
+
+// Implements Handler for async functions whose arguments are all
+// extractors, running the extractors in order and returning the first
+// rejection as the response. A handler fails with an ApiError, or with
+// a body error, which is one too.
+macro_rules! impl_handler {
+    ($($ty:ident => $var:ident),*) => {
+        impl<F, Fut, Error, $($ty,)*> Handler<($($ty,)*)> for F
+        where
+            F: FnOnce($($ty),*) -> Fut + Send + 'static,
+            Fut: Future<Output = Result<Response<Body>, Error>> + Send + 'static,
+            Error: Into<ApiError>,
+            $($ty: FromRequest + Send + 'static,)*
+        {
+            #[allow(unused_mut, unused_variables)]
+            fn call(self, mut ctx: RequestContext) -> ResponseFuture {
+                Box::pin(async move {
+                    $(
+                        let $var = match $ty::from_request(&mut ctx).await {
+                            Ok(value) => value,
+                            Err(rejection) => return Ok(rejection),
+                        };
+                    )*
+                    self($($var),*).await.or_else(|e| e.into().into_result())
+                })
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(A => a);
+impl_handler!(A => a, B => b);
+impl_handler!(A => a, B => b, C => c);
+impl_handler!(A => a, B => b, C => c, D => d);
+impl_handler!(A => a, B => b, C => c, D => d, E => e);
+impl_handler!(A => a, B => b, C => c, D => d, E => e, G => g);
+impl_handler!(A => a, B => b, C => c, D => d, E => e, G => g, H => h);
@@ -0,0 +1,807 @@
+use hmac::{Hmac, Mac};
+use hyper::{
+    body::Bytes,
+    header,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::JoinSet;
+
+use crate::{
+    bad_request,
+    body::{self, Body},
+    codec,
+    error::ApiError,
+    events::Topic,
+    extract::{FromRequest, Json, Path, Query, RequestContext, State},
+    json_response,
+    kiosk::{self, Loan},
+    not_found,
+    outbound::OutboundError,
+    problem,
+    tasks::{Failure, Job},
+    SharedState,
+};
+
+const PEER_HEADER: &str = "x-federation-peer";
+const TIMESTAMP_HEADER: &str = "x-federation-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-federation-signature";
+
+// How far a peer's clock may drift before its requests are refused.
+const MAX_SKEW_SECS: u64 = 300;
+const STATUS_PUSH_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    url: String,
+    secret: String,
+}
+
+// Libraries we exchange with. Each pair of libraries shares a secret; both
+// sides sign their requests and responses with it, so either end can tell
+// the other apart from an impostor.
+pub struct Federation {
+    name: Option<String>,
+    peers: Mutex<BTreeMap<String, Peer>>,
+    client: Client<HttpConnector, Body>,
+}
+
+impl Default for Federation {
+    fn default() -> Self {
+        Federation {
+            name: None,
+            peers: Mutex::new(BTreeMap::new()),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+}
+
+impl Federation {
+    // DOJO_FEDERATION_NAME is how this library introduces itself to peers;
+    // federation stays off without it. DOJO_FEDERATION_PEERS seeds the
+    // registry with comma-separated `name=url=secret` entries.
+    pub fn from_env() -> Result<Self, String> {
+        let federation = Federation {
+            name: std::env::var("DOJO_FEDERATION_NAME").ok().filter(|name| !name.is_empty()),
+            ..Federation::default()
+        };
+        let list = std::env::var("DOJO_FEDERATION_PEERS").unwrap_or_default();
+        let mut peers = federation.peers.lock().unwrap();
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let invalid = || format!("invalid peer entry {:?}, expected name=url=secret", entry);
+            let (name, rest) = entry.split_once('=').ok_or_else(invalid)?;
+            let (url, secret) = rest.rsplit_once('=').ok_or_else(invalid)?;
+            let peer = Peer::new(url, secret).map_err(|e| format!("peer {:?}: {}", name, e))?;
+            if name.is_empty() {
+                return Err(invalid());
+            }
+            peers.insert(name.to_string(), peer);
+        }
+        drop(peers);
+        Ok(federation)
+    }
+
+    fn peer(&self, name: &str) -> Option<Peer> {
+        self.peers.lock().unwrap().get(name).cloned()
+    }
+
+    fn peer_names(&self) -> Vec<String> {
+        self.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    // Sends a signed request to a peer and checks that the answer was signed
+    // by that peer too.
+    async fn call(
+        &self,
+        peer_name: &str,
+        method: Method,
+        path_and_query: &str,
+        body: Vec<u8>,
+    ) -> Result<(StatusCode, Bytes), String> {
+        let name = self.name.as_deref().ok_or("federation is not enabled")?;
+        let peer = self.peer(peer_name).ok_or_else(|| format!("unknown peer {:?}", peer_name))?;
+        let timestamp = now().to_string();
+        let signature = sign(
+            &peer.secret,
+            &[name.as_bytes(), method.as_str().as_bytes(), path_and_query.as_bytes(), timestamp.as_bytes(), &body],
+        );
+
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", peer.url, path_and_query))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(PEER_HEADER, name)
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, &signature)
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())?;
+        let exchange = async {
+            let response = self.client.request(req).await.map_err(|e| e.to_string())?;
+            let (parts, body) = response.into_parts();
+            let body = body::to_bytes(body).await.map_err(|e| e.to_string())?;
+            Ok::<_, String>((parts, body))
+        };
+        let (parts, body) = tokio::time::timeout(Duration::from_secs(10), exchange)
+            .await
+            .map_err(|_| format!("peer {:?} timed out", peer_name))??;
+
+        let status = parts.status.as_u16().to_string();
+        let fields: [&[u8]; 4] = [peer_name.as_bytes(), signature.as_bytes(), status.as_bytes(), &body];
+        match parts.headers.get(SIGNATURE_HEADER).and_then(|value| value.to_str().ok()) {
+            Some(reply) if verify(&peer.secret, &fields, reply) => Ok((parts.status, body)),
+            _ => Err(format!("peer {:?} sent an unsigned or forged response", peer_name)),
+        }
+    }
+}
+
+impl Peer {
+    fn new(url: &str, secret: &str) -> Result<Self, String> {
+        let url = url.trim_end_matches('/');
+        if !url.starts_with("http://") {
+            return Err(format!("url must start with http://, got {:?}", url));
+        }
+        if secret.is_empty() {
+            return Err("secret must not be empty".to_string());
+        }
+        Ok(Peer {
+            url: url.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn mac(secret: &str, fields: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            mac.update(b"\n");
+        }
+        mac.update(field);
+    }
+    mac
+}
+
+pub(crate) fn sign(secret: &str, fields: &[&[u8]]) -> String {
+    codec::hex(&mac(secret, fields).finalize().into_bytes())
+}
+
+fn verify(secret: &str, fields: &[&[u8]], signature: &str) -> bool {
+    codec::unhex(signature).is_some_and(|bytes| mac(secret, fields).verify_slice(&bytes).is_ok())
+}
+
+// A request from a registered peer whose signature checked out. Handlers
+// answer through `reply` so the peer can authenticate us in turn.
+pub struct Signed {
+    peer: String,
+    secret: String,
+    signature: String,
+    name: String,
+    body: Bytes,
+}
+
+fn unauthorized(message: &str) -> Response<Body> {
+    problem::respond(StatusCode::UNAUTHORIZED, message)
+}
+
+impl FromRequest for Signed {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let state = ctx.state().clone();
+        let federation = &state.federation;
+        let name = federation.name.clone().ok_or_else(not_found)?;
+        let header = |key: &str| ctx.headers().get(key).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let (peer, timestamp, signature) = match (header(PEER_HEADER), header(TIMESTAMP_HEADER), header(SIGNATURE_HEADER)) {
+            (Some(peer), Some(timestamp), Some(signature)) => (peer, timestamp, signature),
+            _ => return Err(unauthorized("Missing federation signature")),
+        };
+        let secret = federation
+            .peer(&peer)
+            .ok_or_else(|| unauthorized("Unknown federation peer"))?
+            .secret;
+        let fresh = timestamp.parse::<u64>().is_ok_and(|at| at.abs_diff(now()) <= MAX_SKEW_SECS);
+        if !fresh {
+            return Err(unauthorized("Stale federation timestamp"));
+        }
+
+        let method = ctx.parts().method.to_string();
+        let path_and_query = ctx.parts().uri.path_and_query().map_or("", |pq| pq.as_str()).to_string();
+        let body = body::to_bytes(ctx.take_body())
+            .await
+            .map_err(|_| bad_request("Failed to read request body"))?;
+        let fields: [&[u8]; 5] = [
+            peer.as_bytes(),
+            method.as_bytes(),
+            path_and_query.as_bytes(),
+            timestamp.as_bytes(),
+            &body,
+        ];
+        if !verify(&secret, &fields, &signature) {
+            return Err(unauthorized("Invalid federation signature"));
+        }
+        Ok(Signed {
+            peer,
+            secret,
+            signature,
+            name,
+            body,
+        })
+    }
+}
+
+impl Signed {
+    fn json<T: DeserializeOwned>(&self) -> Option<T> {
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    fn reply<T: Serialize>(&self, status: StatusCode, data: &T) -> Response<Body> {
+        let body = serde_json::to_vec(data).unwrap_or_default();
+        self.reply_raw(status, "application/json", body)
+    }
+
+    fn reply_text(&self, status: StatusCode, message: &str) -> Response<Body> {
+        let body = serde_json::to_vec(&problem::Problem::new(status, message)).unwrap_or_default();
+        self.reply_raw(status, problem::MEDIA_TYPE, body)
+    }
+
+    fn reply_raw(&self, status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Body> {
+        let code = status.as_u16().to_string();
+        let signature = sign(
+            &self.secret,
+            &[self.name.as_bytes(), self.signature.as_bytes(), code.as_bytes(), &body],
+        );
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(PEER_HEADER, &self.name)
+            .header(SIGNATURE_HEADER, signature)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Borrowing,
+    Lending,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoanStatus {
+    Requested,
+    Declined,
+    Shipped,
+    Received,
+    Returned,
+    Completed,
+}
+
+impl LoanStatus {
+    // The lender ships (or declines) and confirms the book came back; the
+    // borrower confirms receipt and sends it back.
+    fn can_become(self, next: LoanStatus, by: Role) -> bool {
+        use LoanStatus::*;
+        match by {
+            Role::Lending => matches!((self, next), (Requested, Shipped | Declined) | (Returned, Completed)),
+            Role::Borrowing => matches!((self, next), (Shipped, Received) | (Received, Returned)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterlibraryLoan {
+    pub id: u64,
+    pub peer: String,
+    pub role: Role,
+    // The book's id in the lending library's catalog.
+    pub book_id: u64,
+    pub title: String,
+    // Only known to the borrowing library.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub member: Option<String>,
+    // The id the peer uses for the same loan, once it has one.
+    pub remote_id: Option<u64>,
+    pub status: LoanStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    #[serde(default)]
+    pub q: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub book: crate::Book,
+    pub available: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerResults {
+    pub peer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub books: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LendRequest {
+    pub book_id: u64,
+    pub borrower_loan_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BorrowRequest {
+    pub peer: String,
+    pub book_id: u64,
+    pub member: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusRequest {
+    pub status: LoanStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PeerRequest {
+    pub url: String,
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerView {
+    pub name: String,
+    pub url: String,
+}
+
+fn matches(book: &crate::Book, query: &str) -> bool {
+    let query = query.to_lowercase();
+    [Some(&book.title), Some(&book.author), book.isbn.as_ref()]
+        .into_iter()
+        .flatten()
+        .any(|field| field.to_lowercase().contains(&query))
+}
+
+// Peer-facing catalog search.
+pub async fn search(
+    signed: Signed,
+    Query(params): Query<SearchParams>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let query = params.q;
+    let params = format!("peer={} q={:?}", signed.peer, query);
+    let hits = state
+        .read_storage("federation_search", || params, |storage| {
+            let mut hits: Vec<SearchHit> = storage
+                .books
+                .values()
+                .filter(|book| matches(book, &query))
+                .map(|book| SearchHit {
+                    book: book.clone(),
+                    available: !storage.loans.contains_key(&book.id),
+                })
+                .collect();
+            hits.sort_by_key(|hit| hit.book.id);
+            hits
+        })
+        .await?;
+    Ok(signed.reply(StatusCode::OK, &hits))
+}
+
+// Searches every peer's catalog at once. A peer that can't be reached is
+// reported alongside the others rather than failing the whole search.
+pub async fn catalog(
+    Query(params): Query<SearchParams>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    if state.federation.name.is_none() {
+        return Err(ApiError::not_found());
+    }
+    let path = format!(
+        "/federation/search?{}",
+        serde_urlencoded::to_string([("q", &params.q)]).unwrap_or_default()
+    );
+    let mut searches = JoinSet::new();
+    for peer in state.federation.peer_names() {
+        let state = state.clone();
+        let path = path.clone();
+        searches.spawn(async move {
+            let result = match state.federation.call(&peer, Method::GET, &path, Vec::new()).await {
+                Ok((StatusCode::OK, body)) => serde_json::from_slice(&body).map_err(|e| e.to_string()),
+                Ok((status, _)) => Err(format!("peer answered {}", status)),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(books) => PeerResults { peer, books: Some(books), error: None },
+                Err(error) => PeerResults { peer, books: None, error: Some(error) },
+            }
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(Ok(result)) = searches.join_next().await {
+        results.push(result);
+    }
+    results.sort_by(|a, b| a.peer.cmp(&b.peer));
+    Ok(json_response(StatusCode::OK, &results)?)
+}
+
+// A peer asks to borrow one of our books. Asking again for the same loan
+// returns the existing record, so a borrower can safely retry.
+pub async fn lend(signed: Signed, State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let request: LendRequest = match signed.json() {
+        Some(request) => request,
+        None => return Ok(signed.reply_text(StatusCode::BAD_REQUEST, "Invalid request body")),
+    };
+    let peer = signed.peer.clone();
+    let params = format!("peer={} book={}", peer, request.book_id);
+    let result = state
+        .with_storage("federation_lend", || params, |storage| {
+            if let Some(existing) = storage
+                .interlibrary_loans
+                .values()
+                .find(|loan| loan.role == Role::Lending && loan.peer == peer && loan.remote_id == Some(request.borrower_loan_id))
+            {
+                return Ok((StatusCode::OK, existing.clone()));
+            }
+            let book = storage.books.get(&request.book_id).ok_or(None)?;
+            if storage.loans.contains_key(&book.id) {
+                return Err(Some(format!("book {} is on loan", book.id)));
+            }
+            let loan = InterlibraryLoan {
+                id: storage.next_interlibrary_loan_id,
+                peer: peer.clone(),
+                role: Role::Lending,
+                book_id: book.id,
+                title: book.title.clone(),
+                member: None,
+                remote_id: Some(request.borrower_loan_id),
+                status: LoanStatus::Requested,
+            };
+            storage.next_interlibrary_loan_id += 1;
+            let book_loan = Loan {
+                member: format!("peer:{}", peer),
+                device: "federation".to_string(),
+                checked_out_at: now(),
+                due_date: None,
+            };
+            let event = kiosk::loan_event(loan.book_id, &book_loan, book_loan.checked_out_at);
+            storage.emit(Topic::Loans, "loan.checked_out", loan.book_id, event);
+            storage.lend(loan.book_id, book_loan);
+            storage.interlibrary_loans.insert(loan.id, loan.clone());
+            storage.emit(Topic::Loans, "interlibrary_loan.status_changed", loan.id, &loan);
+            Ok((StatusCode::CREATED, loan))
+        })
+        .await?;
+    match result {
+        Ok((status, loan)) => Ok(signed.reply(status, &loan)),
+        Err(Some(message)) => Ok(signed.reply_text(StatusCode::CONFLICT, &message)),
+        Err(None) => Ok(signed.reply_text(StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+enum StatusError {
+    NotFound,
+    Conflict(String),
+}
+
+impl From<StatusError> for ApiError {
+    fn from(e: StatusError) -> Self {
+        match e {
+            StatusError::NotFound => ApiError::not_found(),
+            StatusError::Conflict(message) => ApiError::Conflict(message),
+        }
+    }
+}
+
+// Moves a loan along on behalf of `by`. A lender that sees its book come
+// back (or declines to send it) puts it back on the shelf.
+fn advance(
+    storage: &mut crate::Storage,
+    id: u64,
+    peer: Option<&str>,
+    by: Role,
+    status: LoanStatus,
+) -> Result<InterlibraryLoan, StatusError> {
+    let loan = storage
+        .interlibrary_loans
+        .get_mut(&id)
+        .filter(|loan| peer.is_none_or(|peer| loan.peer == peer))
+        .ok_or(StatusError::NotFound)?;
+    if loan.status == status {
+        return Ok(loan.clone());
+    }
+    if !loan.status.can_become(status, by) {
+        return Err(StatusError::Conflict(format!(
+            "interlibrary loan {} cannot move from {:?} to {:?}",
+            id, loan.status, status
+        )));
+    }
+    loan.status = status;
+    let loan = loan.clone();
+    storage.emit(Topic::Loans, "interlibrary_loan.status_changed", loan.id, &loan);
+    if loan.role == Role::Lending && matches!(status, LoanStatus::Completed | LoanStatus::Declined) {
+        if let Some(book_loan) = storage.take_back(loan.book_id) {
+            let event = kiosk::loan_event(loan.book_id, &book_loan, now());
+            storage.emit(Topic::Loans, "loan.returned", loan.book_id, event);
+        }
+    }
+    Ok(loan)
+}
+
+// Status pushed by the other library. `id` is our id for the loan.
+pub async fn peer_status(
+    Path(id): Path<u64>,
+    signed: Signed,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let change: StatusRequest = match signed.json() {
+        Some(change) => change,
+        None => return Ok(signed.reply_text(StatusCode::BAD_REQUEST, "Invalid request body")),
+    };
+    let peer = signed.peer.clone();
+    let params = format!("id={} peer={} status={:?}", id, peer, change.status);
+    let result = state
+        .with_storage("federation_status", || params, |storage| {
+            let role = storage.interlibrary_loans.get(&id).map(|loan| loan.role);
+            // The peer plays the other side of the loan.
+            let by = match role {
+                Some(Role::Borrowing) => Role::Lending,
+                _ => Role::Borrowing,
+            };
+            advance(storage, id, Some(&peer), by, change.status)
+        })
+        .await?;
+    match result {
+        Ok(loan) => Ok(signed.reply(StatusCode::OK, &loan)),
+        Err(StatusError::NotFound) => Ok(signed.reply_text(StatusCode::NOT_FOUND, "Not found")),
+        Err(StatusError::Conflict(message)) => Ok(signed.reply_text(StatusCode::CONFLICT, &message)),
+    }
+}
+
+// A member asks to borrow a book from a peer library. The local record is
+// created first so the lender has an id to report status against; it is
+// dropped again if the lender refuses.
+pub async fn borrow(
+    State(state): State<SharedState>,
+    Json(request): Json<BorrowRequest>,
+) -> Result<Response<Body>, ApiError> {
+    if state.federation.name.is_none() {
+        return Err(ApiError::not_found());
+    }
+    if state.federation.peer(&request.peer).is_none() {
+        return Err(ApiError::bad_request("Unknown peer"));
+    }
+
+    let params = format!("peer={} book={}", request.peer, request.book_id);
+    let loan = state
+        .with_storage("interlibrary_loan_insert", || params, |storage| {
+            let loan = InterlibraryLoan {
+                id: storage.next_interlibrary_loan_id,
+                peer: request.peer.clone(),
+                role: Role::Borrowing,
+                book_id: request.book_id,
+                title: String::new(),
+                member: Some(request.member.clone()),
+                remote_id: None,
+                status: LoanStatus::Requested,
+            };
+            storage.next_interlibrary_loan_id += 1;
+            storage.interlibrary_loans.insert(loan.id, loan.clone());
+            loan
+        })
+        .await?;
+
+    let body = serde_json::json!({ "book_id": loan.book_id, "borrower_loan_id": loan.id }).to_string();
+    let answer = state
+        .federation
+        .call(&loan.peer, Method::POST, "/federation/loans", body.into_bytes())
+        .await;
+    let accepted = match &answer {
+        Ok((status, body)) if status.is_success() => serde_json::from_slice::<Value>(body).ok(),
+        _ => None,
+    };
+
+    let id = loan.id;
+    let result = state
+        .with_storage("interlibrary_loan_accept", || format!("id={}", id), |storage| match &accepted {
+            Some(remote) => {
+                let loan = storage.interlibrary_loans.get_mut(&id)?;
+                loan.remote_id = remote["id"].as_u64();
+                loan.title = remote["title"].as_str().unwrap_or_default().to_string();
+                let loan = loan.clone();
+                storage.emit(Topic::Loans, "interlibrary_loan.status_changed", loan.id, &loan);
+                Some(loan)
+            }
+            None => {
+                storage.interlibrary_loans.remove(&id);
+                None
+            }
+        })
+        .await?;
+    let peer = format!("peer {:?}", loan.peer);
+    match (result, answer) {
+        (Some(loan), _) => Ok(json_response(StatusCode::CREATED, &loan)?),
+        (None, Ok((StatusCode::NOT_FOUND, body))) => Err(ApiError::NotFound(problem::detail(&body))),
+        (None, Ok((StatusCode::CONFLICT, body))) => Err(ApiError::Conflict(problem::detail(&body))),
+        (None, Ok((status, _))) => Err(ApiError::upstream(peer)(OutboundError::Status(status))),
+        (None, Err(message)) => Err(ApiError::upstream(peer)(OutboundError::Unreachable(message))),
+    }
+}
+
+pub async fn list(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let loans = state
+        .with_storage("interlibrary_loan_list", String::new, |storage| {
+            storage.interlibrary_loans.values().cloned().collect::<Vec<_>>()
+        })
+        .await?;
+    Ok(json_response(StatusCode::OK, &loans)?)
+}
+
+// Librarian update. The peer is told in the background, retrying with
+// backoff, so a peer that is briefly down doesn't block the desk.
+pub async fn set_status(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+    Json(change): Json<StatusRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let params = format!("id={} status={:?}", id, change.status);
+    let loan = state
+        .with_storage("interlibrary_loan_status", || params, |storage| {
+            let by = storage.interlibrary_loans.get(&id).ok_or(StatusError::NotFound)?.role;
+            advance(storage, id, None, by, change.status)
+        })
+        .await??;
+    if let Some(remote_id) = loan.remote_id {
+        let push = push_status(state.clone(), loan.peer.clone(), remote_id, loan.status, Vec::new());
+        tokio::spawn(push);
+    }
+    Ok(json_response(StatusCode::OK, &loan)?)
+}
+
+// Pushes that give up land in the dead-letter store for a librarian to
+// retry or discard.
+pub async fn push_status(
+    state: SharedState,
+    peer: String,
+    remote_id: u64,
+    status: LoanStatus,
+    mut failures: Vec<Failure>,
+) {
+    let path = format!("/federation/loans/{}/status", remote_id);
+    let body = serde_json::to_vec(&StatusRequest { status }).unwrap_or_default();
+    for attempt in 0..STATUS_PUSH_ATTEMPTS {
+        let error = match state.federation.call(&peer, Method::POST, &path, body.clone()).await {
+            Ok((status, _)) if status.is_success() => return,
+            // The peer disagrees about the loan; retrying won't change that.
+            Ok((status, body)) if status.is_client_error() => {
+                let error = format!("rejected: {} {}", status, problem::detail(&body));
+                tracing::warn!("peer {:?} {} for loan {}", peer, error, remote_id);
+                failures.push(Failure::now(error));
+                break;
+            }
+            Ok((status, _)) => format!("answered {}", status),
+            Err(e) => e.to_string(),
+        };
+        tracing::warn!("failed to notify peer {:?} about loan {}: {}", peer, remote_id, error);
+        failures.push(Failure::now(error));
+        if attempt + 1 < STATUS_PUSH_ATTEMPTS {
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+        }
+    }
+    tracing::warn!("giving up on notifying peer {:?} about loan {}", peer, remote_id);
+    let job = Job::PushLoanStatus { peer, remote_id, status };
+    state.tasks.lock().await.bury(job, failures);
+}
+
+pub async fn list_peers(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let peers: Vec<PeerView> = state
+        .federation
+        .peers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, peer)| PeerView {
+            name: name.clone(),
+            url: peer.url.clone(),
+        })
+        .collect();
+    json_response(StatusCode::OK, &peers)
+}
+
+pub async fn put_peer(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+    Json(request): Json<PeerRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let peer = Peer::new(&request.url, &request.secret).map_err(ApiError::BadRequest)?;
+    let view = PeerView {
+        name: name.clone(),
+        url: peer.url.clone(),
+    };
+    state.federation.peers.lock().unwrap().insert(name, peer);
+    Ok(json_response(StatusCode::OK, &view)?)
+}
+
+pub async fn delete_peer(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    state.federation.peers.lock().unwrap().remove(&name).ok_or_else(ApiError::not_found)?;
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: [&[u8]; 5] = [b"alexandria", b"GET", b"/federation/search?q=lem", b"1700000000", b""];
+
+    #[test]
+    fn signatures_are_hmacs_of_the_fields() {
+        // HMAC-SHA256 of the fields joined by newlines, worked out apart.
+        let signature = sign("shared", &FIELDS);
+        assert_eq!(signature, "b543f30ec026c2847fce188c6bcb6d5607d0edb9a33a18e0e60743c48f461d86");
+        assert!(verify("shared", &FIELDS, &signature));
+    }
+
+    #[test]
+    fn forged_signatures_are_refused() {
+        let signature = sign("shared", &FIELDS);
+        assert!(!verify("guessed", &FIELDS, &signature));
+        let mut tampered = FIELDS;
+        tampered[2] = b"/federation/search?q=lem&limit=1000";
+        assert!(!verify("shared", &tampered, &signature));
+        // Moving a byte across a field boundary changes the signature.
+        let shifted: [&[u8]; 5] = [b"alexandri", b"aGET", FIELDS[2], FIELDS[3], FIELDS[4]];
+        assert!(!verify("shared", &shifted, &signature));
+
+        assert!(!verify("shared", &FIELDS, &signature[..62]));
+        assert!(!verify("shared", &FIELDS, &signature[..63]));
+        assert!(!verify("shared", &FIELDS, &format!("{}zz", &signature[..62])));
+        assert!(!verify("shared", &FIELDS, ""));
+    }
+
+    #[test]
+    fn peers_need_an_http_url_and_a_secret() {
+        let peer = Peer::new("http://alexandria.example/", "shared").unwrap();
+        assert_eq!(peer.url, "http://alexandria.example");
+        assert!(Peer::new("https://alexandria.example", "shared").is_err());
+        assert!(Peer::new("http://alexandria.example", "").is_err());
+    }
+
+    #[test]
+    fn each_side_moves_a_loan_its_own_way() {
+        use LoanStatus::*;
+        assert!(Requested.can_become(Shipped, Role::Lending));
+        assert!(Requested.can_become(Declined, Role::Lending));
+        assert!(Shipped.can_become(Received, Role::Borrowing));
+        assert!(Received.can_become(Returned, Role::Borrowing));
+        assert!(Returned.can_become(Completed, Role::Lending));
+
+        assert!(!Requested.can_become(Shipped, Role::Borrowing));
+        assert!(!Shipped.can_become(Received, Role::Lending));
+        assert!(!Returned.can_become(Completed, Role::Borrowing));
+        assert!(!Requested.can_become(Completed, Role::Lending));
+        assert!(!Declined.can_become(Shipped, Role::Lending));
+    }
+}
@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// ?fields=id,title trims every object in a JSON response down to the named
+// fields. Without it objects are sent whole.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Fields {
+    fields: Option<String>,
+}
+
+impl Fields {
+    fn names(&self) -> Option<Vec<&str>> {
+        let fields = self.fields.as_deref()?;
+        Some(fields.split(',').map(str::trim).filter(|name| !name.is_empty()).collect())
+    }
+
+    // Rejects names the resource doesn't have, so a typo isn't answered
+    // with empty objects.
+    pub fn check(&self, known: &[&str]) -> Result<(), String> {
+        let Some(names) = self.names() else {
+            return Ok(());
+        };
+        if names.is_empty() {
+            return Err("fields is empty".to_string());
+        }
+        match names.iter().find(|name| !known.contains(name)) {
+            Some(name) => Err(format!("unknown field {:?}, expected one of {}", name, known.join(", "))),
+            None => Ok(()),
+        }
+    }
+
+    // Objects that aren't trimmed are serialized as they are, keeping their
+    // field order.
+    pub fn project<'a, T: Serialize>(&self, item: &'a T) -> Projected<'a, T> {
+        let Some(names) = self.names() else {
+            return Projected::Whole(item);
+        };
+        let mut value = serde_json::to_value(item).unwrap_or(Value::Null);
+        trim(&mut value, &names);
+        Projected::Trimmed(value)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Projected<'a, T> {
+    Whole(&'a T),
+    Trimmed(Value),
+}
+
+fn trim(value: &mut Value, names: &[&str]) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|item| trim(item, names)),
+        Value::Object(object) => object.retain(|key, _| names.contains(&key.as_str())),
+        _ => {}
+    }
+}
+
+// Drops ?fields= from a query string passed on to another node, which has to
+// answer with whole objects for them to be merged.
+pub fn strip(query: &str) -> String {
+    let mut pairs: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+    pairs.retain(|(name, _)| name != "fields");
+    serde_urlencoded::to_string(pairs).unwrap_or_default()
+}
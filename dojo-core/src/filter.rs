@@ -0,0 +1,427 @@
+use books_model::Book;
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+
+use crate::isbn;
+
+// ?filter= narrows a listing with an expression like
+//
+//     author eq "Tolkien" and (title contains "ring" or isbn pr)
+//
+// Comparisons are `<field> <op> <value>`: `eq`, `ne`, `contains`,
+// `starts_with`, `ends_with`, `gt`, `ge`, `lt` and `le`, or `<field> pr`
+// for a field that's there. Text is quoted, with `\"` and `\\` for a quote
+// and a backslash, and compared ignoring case; `id` and `author_id` take
+// numbers. `tags` matches when any one tag does. `not`, `and` and `or`
+// combine them, tightest first, and parentheses group.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Present(Field),
+    Compare(Field, Op, Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Id,
+    Title,
+    Author,
+    Isbn,
+    AuthorId,
+    Tags,
+    PublishedYear,
+    Publisher,
+    Pages,
+    Language,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("id", Field::Id),
+    ("title", Field::Title),
+    ("author", Field::Author),
+    ("isbn", Field::Isbn),
+    ("author_id", Field::AuthorId),
+    ("tags", Field::Tags),
+    ("published_year", Field::PublishedYear),
+    ("publisher", Field::Publisher),
+    ("pages", Field::Pages),
+    ("language", Field::Language),
+];
+
+impl Field {
+    fn is_number(self) -> bool {
+        matches!(self, Field::Id | Field::AuthorId | Field::PublishedYear | Field::Pages)
+    }
+
+    fn name(self) -> &'static str {
+        FIELDS.iter().find(|(_, field)| *field == self).map_or("", |(name, _)| name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+const OPS: &[(&str, Op)] = &[
+    ("eq", Op::Eq),
+    ("ne", Op::Ne),
+    ("contains", Op::Contains),
+    ("starts_with", Op::StartsWith),
+    ("ends_with", Op::EndsWith),
+    ("gt", Op::Gt),
+    ("ge", Op::Ge),
+    ("lt", Op::Lt),
+    ("le", Op::Le),
+];
+
+impl Op {
+    fn is_text_only(self) -> bool {
+        matches!(self, Op::Contains | Op::StartsWith | Op::EndsWith)
+    }
+
+    fn name(self) -> &'static str {
+        OPS.iter().find(|(_, op)| *op == self).map_or("", |(name, _)| name)
+    }
+}
+
+// Text is kept lowercased, as it's compared.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(u64),
+}
+
+// What's wrong with an expression, and the column, counted in characters
+// from 1, where it is.
+#[derive(Debug, PartialEq)]
+pub struct SyntaxError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    Text(String),
+    Number(u64),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Open => write!(f, "\"(\""),
+            Token::Close => write!(f, "\")\""),
+            Token::Word(word) => write!(f, "{:?}", word),
+            Token::Text(text) => write!(f, "the text {:?}", text),
+            Token::Number(number) => write!(f, "the number {}", number),
+            Token::End => write!(f, "the end"),
+        }
+    }
+}
+
+// Each token with the column it starts at.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, SyntaxError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().enumerate().peekable();
+    while let Some((at, c)) = chars.next() {
+        let column = at + 1;
+        let token = match c {
+            _ if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped @ ('"' | '\\'))) => text.push(escaped),
+                            Some((at, other)) => {
+                                let message = format!("unknown escape \\{} in text", other);
+                                return Err(SyntaxError { column: at, message });
+                            }
+                            None => return Err(SyntaxError { column, message: "text isn't closed with \"".to_string() }),
+                        },
+                        Some((_, c)) => text.push(c),
+                        None => return Err(SyntaxError { column, message: "text isn't closed with \"".to_string() }),
+                    }
+                }
+                Token::Text(text)
+            }
+            _ if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    digits.push(digit);
+                }
+                let number = digits.parse().map_err(|_| SyntaxError { column, message: format!("{} is too big", digits) })?;
+                Token::Number(number)
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            _ => return Err(SyntaxError { column, message: format!("unexpected {:?}", c) }),
+        };
+        tokens.push((token, column));
+    }
+    tokens.push((Token::End, source.chars().count() + 1));
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &(Token, usize) {
+        &self.tokens[self.at.min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> (Token, usize) {
+        let token = self.peek().clone();
+        self.at += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(&self.peek().0, Token::Word(word) if word.eq_ignore_ascii_case(keyword));
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expression, SyntaxError> {
+        let mut left = self.and()?;
+        while self.keyword("or") {
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression, SyntaxError> {
+        let mut left = self.not()?;
+        while self.keyword("and") {
+            left = Expression::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expression, SyntaxError> {
+        match self.keyword("not") {
+            true => Ok(Expression::Not(Box::new(self.not()?))),
+            false => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expression, SyntaxError> {
+        let (token, column) = self.next();
+        let name = match token {
+            Token::Open => {
+                let inner = self.or()?;
+                return match self.next() {
+                    (Token::Close, _) => Ok(inner),
+                    (found, at) => {
+                        let message = format!("expected \")\" to close the \"(\" at column {}, found {}", column, found);
+                        Err(SyntaxError { column: at, message })
+                    }
+                };
+            }
+            Token::Word(name) => name,
+            found => return Err(SyntaxError { column, message: format!("expected a field, found {}", found) }),
+        };
+        let field = FIELDS.iter().find(|(known, _)| *known == name).map(|(_, field)| *field).ok_or_else(|| {
+            let names: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+            SyntaxError { column, message: format!("unknown field {:?}, expected one of {}", name, names.join(", ")) }
+        })?;
+
+        let (token, column) = self.next();
+        let op = match &token {
+            Token::Word(word) if word == "pr" => return Ok(Expression::Present(field)),
+            Token::Word(word) => OPS.iter().find(|(name, _)| name == word).map(|(_, op)| *op),
+            _ => None,
+        };
+        let op = op.ok_or_else(|| {
+            let names: Vec<&str> = OPS.iter().map(|(name, _)| *name).collect();
+            let message = format!("expected an operator after {}, one of {} or pr, found {}", field.name(), names.join(", "), token);
+            SyntaxError { column, message }
+        })?;
+
+        let (token, column) = self.next();
+        let value = match (token, field.is_number()) {
+            (Token::Number(number), true) if !op.is_text_only() => Value::Number(number),
+            (Token::Text(text), false) if field == Field::Isbn => Value::Text(isbn::strip(&text).to_lowercase()),
+            (Token::Text(text), false) => Value::Text(text.to_lowercase()),
+            (found, number) => {
+                let wanted = match (number, op.is_text_only()) {
+                    (true, true) => format!("{} can't be compared with {}", field.name(), op.name()),
+                    (true, false) => format!("{} is compared with a number, found {}", field.name(), found),
+                    (false, _) => format!("{} is compared with quoted text, found {}", field.name(), found),
+                };
+                return Err(SyntaxError { column, message: wanted });
+            }
+        };
+        Ok(Expression::Compare(field, op, value))
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, SyntaxError> {
+        let mut parser = Parser { tokens: tokenize(source)?, at: 0 };
+        let expression = parser.or()?;
+        match parser.next() {
+            (Token::End, _) => Ok(expression),
+            (found, column) => Err(SyntaxError { column, message: format!("expected and, or or the end, found {}", found) }),
+        }
+    }
+
+    pub fn matches(&self, book: &Book) -> bool {
+        match self {
+            Expression::And(left, right) => left.matches(book) && right.matches(book),
+            Expression::Or(left, right) => left.matches(book) || right.matches(book),
+            Expression::Not(inner) => !inner.matches(book),
+            Expression::Present(field) => match field {
+                Field::Id => true,
+                Field::Title => !book.title.is_empty(),
+                Field::Author => !book.author.is_empty(),
+                Field::Isbn => book.isbn.is_some(),
+                Field::AuthorId => book.author_id.is_some(),
+                Field::Tags => !book.tags.is_empty(),
+                Field::PublishedYear => book.edition.published_year.is_some(),
+                Field::Publisher => book.edition.publisher.is_some(),
+                Field::Pages => book.edition.pages.is_some(),
+                Field::Language => book.edition.language.is_some(),
+            },
+            Expression::Compare(field, Op::Ne, value) => !Expression::Compare(*field, Op::Eq, value.clone()).matches(book),
+            Expression::Compare(field, op, Value::Number(wanted)) => {
+                let number = match field {
+                    Field::Id => Some(book.id),
+                    Field::PublishedYear => book.edition.published_year.and_then(|year| u64::try_from(year).ok()),
+                    Field::Pages => book.edition.pages.map(u64::from),
+                    _ => book.author_id,
+                };
+                number.is_some_and(|number| compare(*op, &number, wanted))
+            }
+            Expression::Compare(field, op, Value::Text(wanted)) => {
+                let text = |text: &str| compare_text(*op, &text.to_lowercase(), wanted);
+                match field {
+                    Field::Title => text(&book.title),
+                    Field::Author => text(&book.author),
+                    Field::Isbn => book.isbn.as_deref().is_some_and(text),
+                    Field::Tags => book.tags.iter().any(|tag| text(tag)),
+                    Field::Publisher => book.edition.publisher.as_deref().is_some_and(text),
+                    Field::Language => book.edition.language.as_deref().is_some_and(text),
+                    Field::Id | Field::AuthorId | Field::PublishedYear | Field::Pages => false,
+                }
+            }
+        }
+    }
+}
+
+fn compare<T: PartialOrd + ?Sized>(op: Op, have: &T, wanted: &T) -> bool {
+    match op {
+        Op::Eq => have == wanted,
+        Op::Ne => have != wanted,
+        Op::Gt => have > wanted,
+        Op::Ge => have >= wanted,
+        Op::Lt => have < wanted,
+        Op::Le => have <= wanted,
+        Op::Contains | Op::StartsWith | Op::EndsWith => false,
+    }
+}
+
+fn compare_text(op: Op, have: &str, wanted: &str) -> bool {
+    match op {
+        Op::Contains => have.contains(wanted),
+        Op::StartsWith => have.starts_with(wanted),
+        Op::EndsWith => have.ends_with(wanted),
+        _ => compare(op, have, wanted),
+    }
+}
+
+// For `#[serde(deserialize_with = "filter::deserialize")]` on a query field.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Expression>, D::Error> {
+    let source = String::deserialize(deserializer)?;
+    Expression::parse(&source).map(Some).map_err(|e| de::Error::custom(format!("filter: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(id: u64, title: &str, author: &str, isbn: Option<&str>, tags: &[&str]) -> Book {
+        Book {
+            id,
+            title: title.to_string(),
+            author: author.to_string(),
+            isbn: isbn.map(str::to_string),
+            author_id: None,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            edition: Default::default(),
+            uid: None,
+            nft: None,
+            anchor: None,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn expressions_select_books() {
+        let mut books = [
+            book(1, "The Fellowship of the Ring", "Tolkien", None, &["fantasy"]),
+            book(2, "The Hobbit", "Tolkien", Some("9780261102217"), &[]),
+            book(3, "The Silmarillion", "Tolkien", None, &[]),
+            book(4, "Ringworld", "Niven", Some("9780345333926"), &["sf", "classic"]),
+        ];
+        books[1].edition.published_year = Some(1937);
+        books[3].edition.language = Some("en".to_string());
+        let selected = |source: &str| {
+            let expression = Expression::parse(source).unwrap();
+            books.iter().filter(|book| expression.matches(book)).map(|book| book.id).collect::<Vec<_>>()
+        };
+        assert_eq!(selected(r#"author eq "tolkien" and (title contains "RING" or isbn pr)"#), [1, 2]);
+        assert_eq!(selected(r#"not author eq "Tolkien" or id ge 3 and id lt 4"#), [3, 4]);
+        assert_eq!(selected(r#"tags eq "sf""#), [4]);
+        assert_eq!(selected(r#"tags pr and not tags eq "sf""#), [1]);
+        assert_eq!(selected(r#"isbn eq "978-0-261-10221-7""#), [2]);
+        assert_eq!(selected(r#"title starts_with "the \"" or title ends_with "world""#), [4]);
+        assert_eq!(selected("author_id pr"), [0; 0]);
+        assert_eq!(selected(r#"published_year lt 1950 or language eq "EN""#), [2, 4]);
+    }
+
+    #[test]
+    fn syntax_errors_say_where() {
+        let error = |source: &str| Expression::parse(source).unwrap_err().to_string();
+        assert_eq!(error(r#"author eq "Tolkien" and (title contains "ring""#), r#"expected ")" to close the "(" at column 25, found the end at column 47"#);
+        assert_eq!(error(r#"autor eq "x""#), r#"unknown field "autor", expected one of id, title, author, isbn, author_id, tags, published_year, publisher, pages, language at column 1"#);
+        assert_eq!(error(r#"title like "x""#), r#"expected an operator after title, one of eq, ne, contains, starts_with, ends_with, gt, ge, lt, le or pr, found "like" at column 7"#);
+        assert_eq!(error("id eq \"1\""), r#"id is compared with a number, found the text "1" at column 7"#);
+        assert_eq!(error("title eq \"x"), r#"text isn't closed with " at column 10"#);
+        assert_eq!(error("id eq 1 id eq 2"), r#"expected and, or or the end, found "id" at column 9"#);
+        assert_eq!(error("id eq 1 & id eq 2"), r#"unexpected '&' at column 9"#);
+    }
+}
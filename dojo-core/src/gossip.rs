@@ -0,0 +1,734 @@
+use hyper::{
+    header::{self, HeaderMap},
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    auth,
+    body::{self, Body},
+    error::ApiError,
+    extract::{Json, State},
+    json_response, problem, Book, SharedState, Storage,
+};
+
+// Most changes handed out per exchange; a node that is far behind catches
+// up over several rounds.
+const MAX_BATCH: usize = 1000;
+
+// Highest sequence number seen from each node.
+pub type VectorClock = BTreeMap<String, u64>;
+
+fn dominates(a: &VectorClock, b: &VectorClock) -> bool {
+    a != b && b.iter().all(|(node, seq)| a.get(node).is_some_and(|own| own >= seq))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Title,
+    Author,
+    AuthorId,
+    Isbn,
+    Tags,
+    Edition,
+    Uid,
+    Nft,
+    Anchor,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub origin: String,
+    pub seq: u64,
+    pub book_id: u64,
+    pub field: Field,
+    pub value: Value,
+    // What the writer had seen when it made the change.
+    pub clock: VectorClock,
+    pub at_ms: u64,
+}
+
+impl Change {
+    // A change that causally follows another always wins. Concurrent changes
+    // fall back to wall-clock time, then node name, so every node picks the
+    // same winner.
+    fn beats(&self, other: &Change) -> bool {
+        if dominates(&self.clock, &other.clock) {
+            return true;
+        }
+        if dominates(&other.clock, &self.clock) {
+            return false;
+        }
+        (self.at_ms, &self.origin) > (other.at_ms, &other.origin)
+    }
+}
+
+// The replicated view of the catalog: the winning change for every field
+// of every book, plus the change log other nodes pull from.
+#[derive(Debug)]
+pub struct Replica {
+    node: String,
+    // Book ids are handed out in a residue class per node so nodes never
+    // pick the same id for different books.
+    id_offset: u64,
+    id_step: u64,
+    clock: VectorClock,
+    fields: HashMap<u64, BTreeMap<Field, Change>>,
+    log: BTreeMap<String, Vec<Change>>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn field_values(book: &Book) -> [(Field, Value); 9] {
+    [
+        (Field::Title, Value::from(book.title.clone())),
+        (Field::Author, Value::from(book.author.clone())),
+        (Field::AuthorId, book.author_id.map_or(Value::Null, Value::from)),
+        (Field::Isbn, book.isbn.clone().map_or(Value::Null, Value::from)),
+        (Field::Tags, Value::from(book.tags.clone())),
+        (Field::Edition, serde_json::to_value(&book.edition).unwrap_or_default()),
+        (Field::Uid, book.uid.clone().map_or(Value::Null, Value::from)),
+        (Field::Nft, serde_json::to_value(&book.nft).unwrap_or_default()),
+        (Field::Anchor, serde_json::to_value(&book.anchor).unwrap_or_default()),
+    ]
+}
+
+impl Replica {
+    pub fn allocate_id(&self, next_id: u64) -> u64 {
+        next_id + (self.id_offset + self.id_step - next_id % self.id_step) % self.id_step
+    }
+
+    fn materialize(&self, id: u64) -> Option<Book> {
+        let fields = self.fields.get(&id)?;
+        if fields.get(&Field::Deleted).is_some_and(|change| change.value == Value::Bool(true)) {
+            return None;
+        }
+        Some(Book {
+            id,
+            title: fields.get(&Field::Title)?.value.as_str()?.to_string(),
+            author: fields.get(&Field::Author)?.value.as_str()?.to_string(),
+            isbn: fields.get(&Field::Isbn).and_then(|change| change.value.as_str()).map(str::to_string),
+            author_id: fields.get(&Field::AuthorId).and_then(|change| change.value.as_u64()),
+            tags: fields
+                .get(&Field::Tags)
+                .and_then(|change| serde_json::from_value(change.value.clone()).ok())
+                .unwrap_or_default(),
+            edition: fields
+                .get(&Field::Edition)
+                .and_then(|change| serde_json::from_value(change.value.clone()).ok())
+                .unwrap_or_default(),
+            uid: fields.get(&Field::Uid).and_then(|change| change.value.as_str()).map(str::to_string),
+            nft: fields.get(&Field::Nft).and_then(|change| serde_json::from_value(change.value.clone()).ok()),
+            anchor: fields.get(&Field::Anchor).and_then(|change| serde_json::from_value(change.value.clone()).ok()),
+            deleted_at: None,
+        })
+    }
+
+    // When the winning change to the field was made.
+    fn changed_at(&self, id: u64, field: Field) -> u64 {
+        self.fields.get(&id).and_then(|fields| fields.get(&field)).map_or_else(now_ms, |change| change.at_ms)
+    }
+
+    fn write(&mut self, book_id: u64, field: Field, value: Value) {
+        let seq = self.clock.entry(self.node.clone()).or_default();
+        *seq += 1;
+        let change = Change {
+            origin: self.node.clone(),
+            seq: *seq,
+            book_id,
+            field,
+            value,
+            clock: self.clock.clone(),
+            at_ms: now_ms(),
+        };
+        self.fields.entry(book_id).or_default().insert(field, change.clone());
+        self.log.entry(change.origin.clone()).or_default().push(change);
+    }
+
+    // Turns whatever changed in the local catalog since the last sweep into
+    // changes of our own. Every write path goes through the catalog, so
+    // comparing against it catches them all without hooking each one.
+    fn sweep(&mut self, books: &BTreeMap<u64, Book>) {
+        let mut ids: Vec<u64> = books.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let book = &books[&id];
+            for (field, value) in field_values(book) {
+                let current = self.fields.get(&id).and_then(|fields| fields.get(&field));
+                // No author id, tags, edition, uid, NFT or anchor are what a
+                // book without a change to them has, so books from before
+                // them don't all change at once.
+                let unset = match field {
+                    Field::Tags => value == Value::Array(Vec::new()),
+                    Field::Edition => value == Value::Object(Default::default()),
+                    Field::AuthorId | Field::Uid | Field::Nft | Field::Anchor => value.is_null(),
+                    _ => false,
+                };
+                if current.map_or(!unset, |change| change.value != value) {
+                    self.write(id, field, value);
+                }
+            }
+            if self.materialize(id).is_none() {
+                self.write(id, Field::Deleted, Value::Bool(false));
+            }
+        }
+
+        let removed: Vec<u64> = self
+            .fields
+            .keys()
+            .copied()
+            .filter(|id| !books.contains_key(id) && self.materialize(*id).is_some())
+            .collect();
+        for id in removed {
+            self.write(id, Field::Deleted, Value::Bool(true));
+        }
+    }
+
+    // Changes the holder of `digest` hasn't seen, oldest first per node so
+    // a truncated batch never leaves a gap.
+    fn missing(&self, digest: &VectorClock) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (origin, log) in &self.log {
+            let seen = digest.get(origin).copied().unwrap_or(0) as usize;
+            changes.extend(log.iter().skip(seen).take(MAX_BATCH - changes.len()).cloned());
+            if changes.len() == MAX_BATCH {
+                break;
+            }
+        }
+        changes
+    }
+
+    // Returns the ids of the books whose winning value changed.
+    fn apply(&mut self, changes: Vec<Change>) -> BTreeSet<u64> {
+        let mut touched = BTreeSet::new();
+        for change in changes {
+            let seen = self.clock.get(&change.origin).copied().unwrap_or(0);
+            if change.seq != seen + 1 {
+                continue;
+            }
+            self.clock.insert(change.origin.clone(), change.seq);
+            let fields = self.fields.entry(change.book_id).or_default();
+            if fields.get(&change.field).is_none_or(|current| change.beats(current)) {
+                fields.insert(change.field, change.clone());
+                touched.insert(change.book_id);
+            }
+            self.log.entry(change.origin.clone()).or_default().push(change);
+        }
+        touched
+    }
+}
+
+// Brings the catalog in line with the replica after remote changes. A
+// book deleted elsewhere goes to the trash here the way a local DELETE
+// sends it, with its loans and reviews dropped and the deletion recorded.
+fn merge(storage: &mut Storage, changes: Vec<Change>) -> usize {
+    let Some(replica) = storage.replica.as_mut() else {
+        return 0;
+    };
+    replica.sweep(&storage.books);
+    let touched = replica.apply(changes);
+    let merged: Vec<(u64, Result<Book, u64>)> = touched
+        .iter()
+        .map(|id| (*id, replica.materialize(*id).ok_or_else(|| replica.changed_at(*id, Field::Deleted))))
+        .collect();
+    for (id, merged) in merged {
+        match merged {
+            Ok(book) => {
+                storage.index.insert(&book);
+                storage.modified.touch(id);
+                storage.deleted.remove(&id);
+                storage.books.insert(id, book);
+            }
+            Err(at) => {
+                storage.remove_book(id, at);
+            }
+        }
+        storage.next_id = storage.next_id.max(id + 1);
+    }
+    touched.len()
+}
+
+#[derive(Debug, Default)]
+struct PeerStatus {
+    last_exchange: Option<Instant>,
+    last_error: Option<String>,
+}
+
+// Cluster membership is static: DOJO_CLUSTER_NODE names this node and
+// DOJO_CLUSTER_PEERS lists the others as comma-separated `name=url` pairs.
+// Every node accepts writes and serves reads from its own copy.
+pub struct Cluster {
+    node: String,
+    peers: Vec<(String, String)>,
+    secret: String,
+    interval: Duration,
+    client: Client<HttpConnector, Body>,
+    status: Mutex<HashMap<String, PeerStatus>>,
+}
+
+impl Cluster {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let node = match std::env::var("DOJO_CLUSTER_NODE") {
+            Ok(node) if !node.is_empty() => node,
+            _ => return Ok(None),
+        };
+        let mut peers = Vec::new();
+        let list = std::env::var("DOJO_CLUSTER_PEERS").unwrap_or_default();
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, url) = entry
+                .split_once('=')
+                .filter(|(name, url)| !name.is_empty() && url.starts_with("http://"))
+                .ok_or_else(|| format!("invalid peer entry {:?}, expected name=http://host:port", entry))?;
+            if name == node || peers.iter().any(|(peer, _)| peer == name) {
+                return Err(format!("node {:?} is listed twice", name));
+            }
+            peers.push((name.to_string(), url.trim_end_matches('/').to_string()));
+        }
+        // /cluster/gossip hands out the whole change log and merges what it
+        // is sent, outside the API keys, so peers must prove themselves.
+        let secret = std::env::var("DOJO_CLUSTER_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .ok_or("DOJO_CLUSTER_SECRET must be set when DOJO_CLUSTER_NODE is")?;
+        let interval = match std::env::var("DOJO_GOSSIP_INTERVAL_MS") {
+            Ok(ms) => ms
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("DOJO_GOSSIP_INTERVAL_MS must be a number, got {:?}", ms))?,
+            Err(_) => Duration::from_secs(1),
+        };
+        Ok(Some(Cluster {
+            node,
+            peers,
+            secret,
+            interval,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            status: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn replica(&self) -> Replica {
+        let mut members: Vec<&str> = self.peers.iter().map(|(name, _)| name.as_str()).collect();
+        members.push(&self.node);
+        members.sort_unstable();
+        Replica {
+            node: self.node.clone(),
+            id_offset: members.iter().position(|name| *name == self.node).unwrap_or(0) as u64,
+            id_step: members.len() as u64,
+            clock: VectorClock::new(),
+            fields: HashMap::new(),
+            log: BTreeMap::new(),
+        }
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        auth::bearer_matches(headers, &self.secret)
+    }
+
+    async fn pull(&self, url: &str, digest: VectorClock) -> Result<GossipReply, String> {
+        let body = serde_json::to_vec(&GossipRequest {
+            node: self.node.clone(),
+            digest,
+        })
+        .map_err(|e| e.to_string())?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/cluster/gossip", url))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.secret))
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())?;
+        let exchange = async {
+            let response = self.client.request(req).await.map_err(|e| e.to_string())?;
+            if response.status() != StatusCode::OK {
+                return Err(format!("answered {}", response.status()));
+            }
+            let body = body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+            serde_json::from_slice(&body).map_err(|e| e.to_string())
+        };
+        tokio::time::timeout(Duration::from_secs(10), exchange)
+            .await
+            .map_err(|_| "timed out".to_string())?
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GossipRequest {
+    pub node: String,
+    pub digest: VectorClock,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GossipReply {
+    pub node: String,
+    pub digest: VectorClock,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerView {
+    pub name: String,
+    pub url: String,
+    pub last_exchange_ms_ago: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClusterStatus {
+    pub node: String,
+    pub clock: VectorClock,
+    pub changes: usize,
+    pub peers: Vec<PeerView>,
+}
+
+// Each round pulls from the next peer in turn. Since every node does the
+// same, changes reach the whole cluster within a few rounds.
+pub async fn run(state: SharedState) {
+    let Some(cluster) = &state.cluster else {
+        return;
+    };
+    if cluster.peers.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(cluster.interval);
+    for (name, url) in cluster.peers.iter().cycle() {
+        interval.tick().await;
+        let digest = state
+            .with_storage("gossip_digest", || format!("peer={}", name), |storage| {
+                let Storage { books, replica, .. } = storage;
+                replica.as_mut().map(|replica| {
+                    replica.sweep(books);
+                    replica.clock.clone()
+                })
+            })
+            .await;
+        let result = match digest {
+            Ok(digest) => cluster.pull(url, digest.unwrap_or_default()).await,
+            Err(e) => Err(e.to_string()),
+        };
+        let result = match result {
+            Ok(reply) => {
+                let count = reply.changes.len();
+                state
+                    .with_storage("gossip_merge", || format!("peer={} changes={}", name, count), |storage| {
+                        merge(storage, reply.changes)
+                    })
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e),
+        };
+
+        let mut status = cluster.status.lock().unwrap();
+        let status = status.entry(name.clone()).or_default();
+        match result {
+            Ok(_) => {
+                status.last_exchange = Some(Instant::now());
+                status.last_error = None;
+            }
+            Err(e) => {
+                if status.last_error.as_ref() != Some(&e) {
+                    tracing::warn!("gossip with {} failed: {}", name, e);
+                }
+                status.last_error = Some(e);
+            }
+        }
+    }
+}
+
+fn forbidden() -> Response<Body> {
+    problem::respond(StatusCode::FORBIDDEN, "Forbidden")
+}
+
+pub async fn exchange(
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    Json(request): Json<GossipRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let cluster = state.cluster.as_ref().ok_or_else(ApiError::not_found)?;
+    if !cluster.authorized(&headers) {
+        return Ok(forbidden());
+    }
+
+    let reply = state
+        .with_storage("gossip_serve", || format!("peer={}", request.node), |storage| {
+            let Storage { books, replica, .. } = storage;
+            replica.as_mut().map(|replica| {
+                replica.sweep(books);
+                GossipReply {
+                    node: replica.node.clone(),
+                    changes: replica.missing(&request.digest),
+                    digest: replica.clock.clone(),
+                }
+            })
+        })
+        .await?
+        .ok_or_else(ApiError::not_found)?;
+    Ok(json_response(StatusCode::OK, &reply)?)
+}
+
+pub async fn status(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let cluster = state.cluster.as_ref().ok_or_else(ApiError::not_found)?;
+    let (clock, changes) = state
+        .with_storage("gossip_status", String::new, |storage| {
+            let Storage { books, replica, .. } = storage;
+            replica.as_mut().map(|replica| {
+                replica.sweep(books);
+                (replica.clock.clone(), replica.log.values().map(Vec::len).sum::<usize>())
+            })
+        })
+        .await?
+        .ok_or_else(ApiError::not_found)?;
+    let status = cluster.status.lock().unwrap();
+    let peers = cluster
+        .peers
+        .iter()
+        .map(|(name, url)| {
+            let peer = status.get(name);
+            PeerView {
+                name: name.clone(),
+                url: url.clone(),
+                last_exchange_ms_ago: peer
+                    .and_then(|peer| peer.last_exchange)
+                    .map(|at| at.elapsed().as_millis() as u64),
+                last_error: peer.and_then(|peer| peer.last_error.clone()),
+            }
+        })
+        .collect();
+    let view = ClusterStatus {
+        node: cluster.node.clone(),
+        clock,
+        changes,
+        peers,
+    };
+    Ok(json_response(StatusCode::OK, &view)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replica(node: &str, id_offset: u64) -> Replica {
+        Replica {
+            node: node.to_string(),
+            id_offset,
+            id_step: 2,
+            clock: VectorClock::new(),
+            fields: HashMap::new(),
+            log: BTreeMap::new(),
+        }
+    }
+
+    fn book(id: u64, title: &str) -> Book {
+        serde_json::from_value(serde_json::json!({ "id": id, "title": title, "author": "Lem" })).unwrap()
+    }
+
+    fn catalog(books: &[Book]) -> BTreeMap<u64, Book> {
+        books.iter().map(|book| (book.id, book.clone())).collect()
+    }
+
+    fn title(replica: &Replica, id: u64) -> Option<String> {
+        replica.materialize(id).map(|book| book.title)
+    }
+
+    // One round each way, as two nodes gossiping would.
+    fn exchange(a: &mut Replica, b: &mut Replica) {
+        let to_b = a.missing(&b.clock);
+        let to_a = b.missing(&a.clock);
+        b.apply(to_b);
+        a.apply(to_a);
+    }
+
+    fn change(origin: &str, clock: &[(&str, u64)], at_ms: u64, title: &str) -> Change {
+        Change {
+            origin: origin.to_string(),
+            seq: 1,
+            book_id: 1,
+            field: Field::Title,
+            value: Value::from(title),
+            clock: clock.iter().map(|(node, seq)| (node.to_string(), *seq)).collect(),
+            at_ms,
+        }
+    }
+
+    #[test]
+    fn clocks_order_only_what_they_have_seen() {
+        let clock = |entries: &[(&str, u64)]| -> VectorClock {
+            entries.iter().map(|(node, seq)| (node.to_string(), *seq)).collect()
+        };
+        assert!(dominates(&clock(&[("a", 2), ("b", 1)]), &clock(&[("a", 1), ("b", 1)])));
+        assert!(dominates(&clock(&[("a", 1), ("b", 1)]), &clock(&[("a", 1)])));
+        assert!(!dominates(&clock(&[("a", 1)]), &clock(&[("a", 1)])));
+        assert!(!dominates(&clock(&[("a", 2)]), &clock(&[("b", 1)])));
+        assert!(!dominates(&clock(&[("a", 2), ("b", 0)]), &clock(&[("a", 1), ("b", 1)])));
+    }
+
+    #[test]
+    fn later_changes_beat_earlier_ones_whatever_the_wall_clock() {
+        let earlier = change("a", &[("a", 1)], 2_000, "Solaris");
+        let later = change("b", &[("a", 1), ("b", 1)], 1_000, "Fiasco");
+        assert!(later.beats(&earlier));
+        assert!(!earlier.beats(&later));
+
+        // Concurrent: the later wall clock wins, then the greater node name.
+        let a = change("a", &[("a", 1)], 1_000, "Solaris");
+        let b = change("b", &[("b", 1)], 2_000, "Fiasco");
+        assert!(b.beats(&a) && !a.beats(&b));
+        let b = change("b", &[("b", 1)], 1_000, "Fiasco");
+        assert!(b.beats(&a) && !a.beats(&b));
+    }
+
+    #[test]
+    fn replicas_converge_on_writes_and_deletes() {
+        let mut a = replica("a", 0);
+        let mut b = replica("b", 1);
+        a.sweep(&catalog(&[book(2, "Solaris"), book(4, "Eden")]));
+        exchange(&mut a, &mut b);
+        assert_eq!(title(&b, 2).as_deref(), Some("Solaris"));
+        assert_eq!(b.clock, a.clock);
+
+        // Both edit the same title before hearing from each other.
+        a.sweep(&catalog(&[book(2, "Fiasco"), book(4, "Eden")]));
+        b.sweep(&catalog(&[book(2, "The Invincible"), book(4, "Eden"), book(3, "Golem XIV")]));
+        exchange(&mut a, &mut b);
+        assert_eq!(title(&a, 2), title(&b, 2));
+        assert_eq!(title(&a, 3).as_deref(), Some("Golem XIV"));
+
+        b.sweep(&catalog(&[book(2, "The Invincible"), book(3, "Golem XIV")]));
+        exchange(&mut a, &mut b);
+        assert_eq!(title(&a, 4), None);
+        assert_eq!(a.clock, b.clock);
+        assert!(a.missing(&b.clock).is_empty());
+    }
+
+    #[test]
+    fn changes_after_a_gap_wait_for_the_gap() {
+        let mut a = replica("a", 0);
+        a.sweep(&catalog(&[book(2, "Solaris")]));
+        a.sweep(&catalog(&[book(2, "Fiasco")]));
+        let changes = a.missing(&VectorClock::new());
+        assert_eq!(changes.iter().map(|change| change.seq).collect::<Vec<_>>(), [1, 2, 3, 4]);
+
+        let mut b = replica("b", 1);
+        assert!(b.apply(changes[3..].to_vec()).is_empty());
+        assert_eq!(title(&b, 2), None);
+        b.apply(changes.clone());
+        assert_eq!(title(&b, 2).as_deref(), Some("Fiasco"));
+        // Replayed changes are ignored.
+        assert!(b.apply(changes).is_empty());
+        let digest = VectorClock::from([("a".to_string(), 2)]);
+        assert_eq!(a.missing(&digest).iter().map(|change| change.seq).collect::<Vec<_>>(), [3, 4]);
+    }
+
+    #[test]
+    fn nodes_hand_out_ids_of_their_own() {
+        let a = replica("a", 0);
+        let b = replica("b", 1);
+        assert_eq!([4, 5, 6].map(|next| a.allocate_id(next)), [4, 6, 6]);
+        assert_eq!([4, 5, 6].map(|next| b.allocate_id(next)), [5, 5, 7]);
+    }
+
+    #[test]
+    fn merges_reach_the_catalog() {
+        let mut a = replica("a", 0);
+        a.sweep(&catalog(&[book(2, "Solaris"), book(4, "Eden")]));
+        let mut storage = Storage::new();
+        storage.replica = Some(replica("b", 1));
+        assert_eq!(merge(&mut storage, a.missing(&VectorClock::new())), 2);
+        assert_eq!(storage.books.keys().copied().collect::<Vec<_>>(), [2, 4]);
+        assert_eq!(storage.next_id, 5);
+    }
+
+    #[test]
+    fn remote_deletes_go_to_the_trash() {
+        let mut a = replica("a", 0);
+        a.sweep(&catalog(&[book(2, "Solaris"), book(4, "Eden")]));
+        let mut storage = Storage::new();
+        storage.replica = Some(replica("b", 1));
+        merge(&mut storage, a.missing(&VectorClock::new()));
+        let loan = crate::kiosk::Loan {
+            member: "m1".to_string(),
+            device: "desk".to_string(),
+            checked_out_at: 1,
+            due_date: None,
+        };
+        storage.lend(2, loan);
+        storage.audit = Some(Default::default());
+
+        a.sweep(&catalog(&[book(4, "Eden")]));
+        let seen = storage.replica.as_ref().unwrap().clock.clone();
+        assert_eq!(merge(&mut storage, a.missing(&seen)), 1);
+        assert_eq!(storage.books.keys().copied().collect::<Vec<_>>(), [4]);
+        let trashed = &storage.deleted[&2];
+        assert_eq!(trashed.deleted_at, Some(a.changed_at(2, Field::Deleted)));
+        assert!(!storage.loans.contains_key(&2));
+        assert!(!storage.lent.contains_key(&2));
+        assert_eq!(storage.audited.len(), 1);
+
+        // Undeleted elsewhere, it comes back out of the trash.
+        a.sweep(&catalog(&[book(2, "Solaris"), book(4, "Eden")]));
+        let seen = storage.replica.as_ref().unwrap().clock.clone();
+        merge(&mut storage, a.missing(&seen));
+        assert_eq!(storage.books.keys().copied().collect::<Vec<_>>(), [2, 4]);
+        assert!(storage.deleted.is_empty());
+    }
+
+    #[test]
+    fn merges_keep_the_author_a_book_is_filed_under() {
+        let mut a = replica("a", 0);
+        let mut filed = book(2, "Solaris");
+        filed.author_id = Some(7);
+        a.sweep(&catalog(&[filed.clone(), book(4, "Eden")]));
+        let mut storage = Storage::new();
+        storage.replica = Some(replica("b", 1));
+        merge(&mut storage, a.missing(&VectorClock::new()));
+        assert_eq!(storage.books[&2].author_id, Some(7));
+        assert_eq!(storage.books[&4].author_id, None);
+
+        // Changing another field of the book leaves the link alone, and so
+        // does a merge that only touches the title.
+        filed.title = "Fiasco".to_string();
+        a.sweep(&catalog(&[filed.clone(), book(4, "Eden")]));
+        let seen = storage.replica.as_ref().unwrap().clock.clone();
+        merge(&mut storage, a.missing(&seen));
+        assert_eq!((storage.books[&2].title.as_str(), storage.books[&2].author_id), ("Fiasco", Some(7)));
+
+        // Unfiling it is a change of its own.
+        filed.author_id = None;
+        a.sweep(&catalog(&[filed, book(4, "Eden")]));
+        let seen = storage.replica.as_ref().unwrap().clock.clone();
+        merge(&mut storage, a.missing(&seen));
+        assert_eq!(storage.books[&2].author_id, None);
+    }
+
+    #[test]
+    fn peers_prove_themselves_with_the_secret() {
+        let cluster = Cluster {
+            node: "a".to_string(),
+            peers: vec![("b".to_string(), "http://b".to_string())],
+            secret: "shared".to_string(),
+            interval: Duration::from_secs(1),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            status: Mutex::new(HashMap::new()),
+        };
+        let headers = |value: &str| HeaderMap::from_iter([(header::AUTHORIZATION, value.parse().unwrap())]);
+        assert!(cluster.authorized(&headers("Bearer shared")));
+        assert!(!cluster.authorized(&headers("Bearer sharedd")));
+        assert!(!cluster.authorized(&headers("Basic shared")));
+        assert!(!cluster.authorized(&HeaderMap::new()));
+    }
+}
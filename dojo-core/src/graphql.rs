@@ -0,0 +1,1332 @@
+use books_model::{Book, CreateBookRequest, UpdateBookRequest};
+use hyper::{header, Response, StatusCode};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Map, Value as Json};
+use std::collections::HashMap;
+
+use crate::{
+    body::{self, Body},
+    etag::{self, IfMatch},
+    extract::{Json as JsonBody, State},
+    json_response, listing,
+    store::{BookStore, Store},
+    updated, validate, SharedState,
+};
+
+// The schema, as (field, type, arguments) with types written as in SDL.
+// There's no introspection; GET /graphql/schema prints this instead.
+type FieldDef = (&'static str, &'static str, &'static [(&'static str, &'static str)]);
+
+const QUERY: &[FieldDef] = &[
+    (
+        "books",
+        "[Book!]!",
+        &[("author", "String"), ("titleContains", "String"), ("hasIsbn", "Boolean"), ("first", "Int"), ("after", "ID")],
+    ),
+    ("book", "Book", &[("id", "ID!")]),
+];
+
+const MUTATION: &[FieldDef] = &[
+    ("createBook", "Book!", &[("input", "CreateBookInput!")]),
+    ("updateBook", "Book", &[("id", "ID!"), ("input", "UpdateBookInput!"), ("ifMatch", "String!")]),
+    ("deleteBook", "Book", &[("id", "ID!"), ("ifMatch", "String!")]),
+];
+
+const BOOK: &[FieldDef] = &[
+    ("id", "ID!", &[]),
+    ("title", "String!", &[]),
+    ("author", "String!", &[]),
+    ("isbn", "String", &[]),
+    // The book's ETag, for updateBook's and deleteBook's ifMatch.
+    ("etag", "String!", &[]),
+];
+
+const INPUTS: &[(&str, &[(&str, &str)])] = &[
+    ("CreateBookInput", &[("title", "String!"), ("author", "String!"), ("isbn", "String")]),
+    ("UpdateBookInput", &[("title", "String"), ("author", "String"), ("isbn", "String")]),
+];
+
+fn fields_of(object: &str) -> &'static [FieldDef] {
+    match object {
+        "Query" => QUERY,
+        "Mutation" => MUTATION,
+        _ => BOOK,
+    }
+}
+
+fn is_object(name: &str) -> bool {
+    matches!(name, "Query" | "Mutation" | "Book")
+}
+
+pub fn sdl() -> String {
+    let mut out = String::new();
+    for (name, fields) in [("Query", QUERY), ("Mutation", MUTATION), ("Book", BOOK)] {
+        out.push_str(&format!("type {} {{\n", name));
+        for (field, ty, args) in fields {
+            let args: Vec<String> = args.iter().map(|(name, ty)| format!("{}: {}", name, ty)).collect();
+            match args.is_empty() {
+                true => out.push_str(&format!("  {}: {}\n", field, ty)),
+                false => out.push_str(&format!("  {}({}): {}\n", field, args.join(", "), ty)),
+            }
+        }
+        out.push_str("}\n\n");
+    }
+    for (name, fields) in INPUTS {
+        out.push_str(&format!("input {} {{\n", name));
+        for (field, ty) in *fields {
+            out.push_str(&format!("  {}: {}\n", field, ty));
+        }
+        out.push_str("}\n\n");
+    }
+    out.pop();
+    out
+}
+
+// Where something is in the query, 1-based, for error locations.
+#[derive(Debug, Clone, Copy)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    End,
+}
+
+struct Lexer<'a> {
+    text: &'a str,
+    offset: usize,
+    line: usize,
+    line_start: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn pos(&self) -> Pos {
+        Pos {
+            line: self.line,
+            column: self.text[self.line_start..self.offset].chars().count() + 1,
+        }
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.text[self.offset..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.line_start = self.offset;
+        }
+        Some(c)
+    }
+
+    // Whitespace, commas and comments mean nothing.
+    fn skip_ignored(&mut self) {
+        while let Some(c) = self.peek_char() {
+            match c {
+                ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => {
+                    self.bump();
+                }
+                '#' => {
+                    while self.peek_char().is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<(Token, Pos), (String, Pos)> {
+        self.skip_ignored();
+        let pos = self.pos();
+        let Some(c) = self.bump() else {
+            return Ok((Token::End, pos));
+        };
+        let token = match c {
+            '!' | '$' | '&' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '|' | '}' => Token::Punct(c),
+            '.' if self.text[self.offset..].starts_with("..") => {
+                self.offset += 2;
+                Token::Spread
+            }
+            '"' if self.text[self.offset..].starts_with("\"\"") => {
+                self.offset += 2;
+                Token::Str(self.block_string().map_err(|message| (message, pos))?)
+            }
+            '"' => Token::Str(self.string().map_err(|message| (message, pos))?),
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let start = self.offset - 1;
+                while self.peek_char().is_some_and(|c| c == '_' || c.is_ascii_alphanumeric()) {
+                    self.bump();
+                }
+                Token::Name(self.text[start..self.offset].to_string())
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = self.offset - 1;
+                while self.peek_char().is_some_and(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-')) {
+                    self.bump();
+                }
+                let number = &self.text[start..self.offset];
+                let token = match number.contains(['.', 'e', 'E']) {
+                    true => number.parse().ok().map(Token::Float),
+                    false => number.parse().ok().map(Token::Int),
+                };
+                token.ok_or_else(|| (format!("invalid number {}", number), pos))?
+            }
+            c => return Err((format!("unexpected character {:?}", c), pos)),
+        };
+        Ok((token, pos))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err("unterminated string".to_string()),
+                Some('"') => return Ok(out),
+                Some('\\') => match self.bump() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => {
+                        let hex = self.text.get(self.offset..self.offset + 4).ok_or("invalid \\u escape")?;
+                        let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).ok_or("invalid \\u escape")?;
+                        self.offset += 4;
+                        out.push(c);
+                    }
+                    _ => return Err("invalid escape in string".to_string()),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    // A """block string""", with the indentation its lines share removed.
+    fn block_string(&mut self) -> Result<String, String> {
+        let mut raw = String::new();
+        loop {
+            if self.text[self.offset..].starts_with("\"\"\"") {
+                self.offset += 3;
+                break;
+            }
+            if self.text[self.offset..].starts_with("\\\"\"\"") {
+                self.offset += 4;
+                raw.push_str("\"\"\"");
+                continue;
+            }
+            raw.push(self.bump().ok_or("unterminated block string")?);
+        }
+        let lines: Vec<&str> = raw.lines().collect();
+        let indent = lines
+            .iter()
+            .skip(1)
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.len() - line.trim_start().len())
+            .min()
+            .unwrap_or(0);
+        let mut lines: Vec<&str> = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| if i == 0 { line } else { line.get(indent..).unwrap_or("") })
+            .collect();
+        while lines.first().is_some_and(|line| line.trim().is_empty()) {
+            lines.remove(0);
+        }
+        while lines.last().is_some_and(|line| line.trim().is_empty()) {
+            lines.pop();
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Variable(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    Enum(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Named(String),
+    List(Box<Type>),
+    NonNull(Box<Type>),
+}
+
+impl Type {
+    // For the schema's own types, which are known to be valid.
+    fn of(sdl: &str) -> Type {
+        let mut lexer = Lexer {
+            text: sdl,
+            offset: 0,
+            line: 1,
+            line_start: 0,
+        };
+        let mut parser = Parser::new(&mut lexer).expect("the schema's types are valid");
+        parser.parse_type().expect("the schema's types are valid")
+    }
+
+    fn named(&self) -> &str {
+        match self {
+            Type::Named(name) => name,
+            Type::List(inner) | Type::NonNull(inner) => inner.named(),
+        }
+    }
+
+    fn is_non_null(&self) -> bool {
+        matches!(self, Type::NonNull(_))
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Named(name) => f.write_str(name),
+            Type::List(inner) => write!(f, "[{}]", inner),
+            Type::NonNull(inner) => write!(f, "{}!", inner),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Directive {
+    name: String,
+    arguments: Vec<(String, Value)>,
+    pos: Pos,
+}
+
+#[derive(Debug)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    arguments: Vec<(String, Value)>,
+    directives: Vec<Directive>,
+    selections: Vec<Selection>,
+    pos: Pos,
+}
+
+impl Field {
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug)]
+enum Selection {
+    Field(Field),
+    Spread { name: String, directives: Vec<Directive>, pos: Pos },
+    Inline { on: Option<String>, directives: Vec<Directive>, selections: Vec<Selection>, pos: Pos },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OperationKind {
+    Query,
+    Mutation,
+}
+
+#[derive(Debug)]
+struct VariableDef {
+    name: String,
+    ty: Type,
+    default: Option<Value>,
+    pos: Pos,
+}
+
+#[derive(Debug)]
+struct Operation {
+    kind: OperationKind,
+    name: Option<String>,
+    variables: Vec<VariableDef>,
+    selections: Vec<Selection>,
+    pos: Pos,
+}
+
+#[derive(Debug)]
+struct Fragment {
+    on: String,
+    selections: Vec<Selection>,
+}
+
+#[derive(Debug, Default)]
+struct Document {
+    operations: Vec<Operation>,
+    fragments: HashMap<String, Fragment>,
+}
+
+struct Parser<'l, 'a> {
+    lexer: &'l mut Lexer<'a>,
+    token: Token,
+    pos: Pos,
+}
+
+type Parsed<T> = Result<T, (String, Pos)>;
+
+impl<'l, 'a> Parser<'l, 'a> {
+    fn new(lexer: &'l mut Lexer<'a>) -> Parsed<Self> {
+        let (token, pos) = lexer.next()?;
+        Ok(Parser { lexer, token, pos })
+    }
+
+    fn advance(&mut self) -> Parsed<Token> {
+        let (token, pos) = self.lexer.next()?;
+        self.pos = pos;
+        Ok(std::mem::replace(&mut self.token, token))
+    }
+
+    fn unexpected<T>(&self) -> Parsed<T> {
+        let found = match &self.token {
+            Token::Punct(c) => format!("{:?}", c.to_string()),
+            Token::Spread => "\"...\"".to_string(),
+            Token::Name(name) => format!("name {:?}", name),
+            Token::Int(_) | Token::Float(_) => "a number".to_string(),
+            Token::Str(_) => "a string".to_string(),
+            Token::End => "the end of the query".to_string(),
+        };
+        Err((format!("syntax error: unexpected {}", found), self.pos))
+    }
+
+    fn eat(&mut self, c: char) -> Parsed<bool> {
+        if self.token == Token::Punct(c) {
+            self.advance()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn expect(&mut self, c: char) -> Parsed<()> {
+        match self.eat(c)? {
+            true => Ok(()),
+            false => self.unexpected(),
+        }
+    }
+
+    fn name(&mut self) -> Parsed<String> {
+        match &self.token {
+            Token::Name(_) => match self.advance()? {
+                Token::Name(name) => Ok(name),
+                _ => unreachable!(),
+            },
+            _ => self.unexpected(),
+        }
+    }
+
+    fn keyword(&self, word: &str) -> bool {
+        matches!(&self.token, Token::Name(name) if name == word)
+    }
+
+    fn document(&mut self) -> Parsed<Document> {
+        let mut document = Document::default();
+        while self.token != Token::End {
+            let pos = self.pos;
+            if self.token == Token::Punct('{') {
+                let selections = self.selection_set()?;
+                document.operations.push(Operation {
+                    kind: OperationKind::Query,
+                    name: None,
+                    variables: Vec::new(),
+                    selections,
+                    pos,
+                });
+            } else if self.keyword("fragment") {
+                self.advance()?;
+                let name = self.name()?;
+                if name == "on" {
+                    return Err(("a fragment can't be named \"on\"".to_string(), pos));
+                }
+                if !self.keyword("on") {
+                    return self.unexpected();
+                }
+                self.advance()?;
+                let on = self.name()?;
+                self.directives()?;
+                let selections = self.selection_set()?;
+                if document.fragments.insert(name.clone(), Fragment { on, selections }).is_some() {
+                    return Err((format!("there can be only one fragment named {:?}", name), pos));
+                }
+            } else if self.keyword("query") || self.keyword("mutation") || self.keyword("subscription") {
+                let kind = match self.advance()? {
+                    Token::Name(word) if word == "query" => OperationKind::Query,
+                    Token::Name(word) if word == "mutation" => OperationKind::Mutation,
+                    _ => return Err(("subscriptions aren't supported; /ws and /books/events stream changes".to_string(), pos)),
+                };
+                let name = match &self.token {
+                    Token::Name(_) => Some(self.name()?),
+                    _ => None,
+                };
+                let variables = self.variable_defs()?;
+                self.directives()?;
+                let selections = self.selection_set()?;
+                document.operations.push(Operation {
+                    kind,
+                    name,
+                    variables,
+                    selections,
+                    pos,
+                });
+            } else {
+                return self.unexpected();
+            }
+        }
+        if document.operations.is_empty() {
+            return Err(("the document has no operation".to_string(), self.pos));
+        }
+        Ok(document)
+    }
+
+    fn variable_defs(&mut self) -> Parsed<Vec<VariableDef>> {
+        let mut defs = Vec::new();
+        if !self.eat('(')? {
+            return Ok(defs);
+        }
+        while !self.eat(')')? {
+            let pos = self.pos;
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            let ty = self.parse_type()?;
+            let default = match self.eat('=')? {
+                true => Some(self.value(true)?),
+                false => None,
+            };
+            self.directives()?;
+            defs.push(VariableDef { name, ty, default, pos });
+        }
+        Ok(defs)
+    }
+
+    fn parse_type(&mut self) -> Parsed<Type> {
+        let ty = match self.eat('[')? {
+            true => {
+                let inner = self.parse_type()?;
+                self.expect(']')?;
+                Type::List(Box::new(inner))
+            }
+            false => Type::Named(self.name()?),
+        };
+        match self.eat('!')? {
+            true => Ok(Type::NonNull(Box::new(ty))),
+            false => Ok(ty),
+        }
+    }
+
+    fn directives(&mut self) -> Parsed<Vec<Directive>> {
+        let mut directives = Vec::new();
+        while self.token == Token::Punct('@') {
+            let pos = self.pos;
+            self.advance()?;
+            let name = self.name()?;
+            let arguments = self.arguments()?;
+            directives.push(Directive { name, arguments, pos });
+        }
+        Ok(directives)
+    }
+
+    fn arguments(&mut self) -> Parsed<Vec<(String, Value)>> {
+        let mut arguments = Vec::new();
+        if !self.eat('(')? {
+            return Ok(arguments);
+        }
+        while !self.eat(')')? {
+            let name = self.name()?;
+            self.expect(':')?;
+            arguments.push((name, self.value(false)?));
+        }
+        Ok(arguments)
+    }
+
+    // Defaults for variables have to be constant.
+    fn value(&mut self, constant: bool) -> Parsed<Value> {
+        let pos = self.pos;
+        let value = match &self.token {
+            Token::Punct('$') if !constant => {
+                self.advance()?;
+                return Ok(Value::Variable(self.name()?));
+            }
+            Token::Punct('[') => {
+                self.advance()?;
+                let mut items = Vec::new();
+                while !self.eat(']')? {
+                    items.push(self.value(constant)?);
+                }
+                return Ok(Value::List(items));
+            }
+            Token::Punct('{') => {
+                self.advance()?;
+                let mut fields = Vec::new();
+                while !self.eat('}')? {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value(constant)?));
+                }
+                return Ok(Value::Object(fields));
+            }
+            Token::Int(int) => Value::Int(*int),
+            Token::Float(float) => Value::Float(*float),
+            Token::Str(text) => Value::String(text.clone()),
+            Token::Name(name) => match name.as_str() {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                "null" => Value::Null,
+                _ => Value::Enum(name.clone()),
+            },
+            Token::Punct('$') => return Err(("a variable's default can't use variables".to_string(), pos)),
+            _ => return self.unexpected(),
+        };
+        self.advance()?;
+        Ok(value)
+    }
+
+    fn selection_set(&mut self) -> Parsed<Vec<Selection>> {
+        self.expect('{')?;
+        let mut selections = Vec::new();
+        while !self.eat('}')? {
+            let pos = self.pos;
+            if self.token == Token::Spread {
+                self.advance()?;
+                if self.keyword("on") {
+                    self.advance()?;
+                    let on = Some(self.name()?);
+                    let directives = self.directives()?;
+                    let inner = self.selection_set()?;
+                    selections.push(Selection::Inline { on, directives, selections: inner, pos });
+                } else if matches!(self.token, Token::Name(_)) {
+                    let name = self.name()?;
+                    let directives = self.directives()?;
+                    selections.push(Selection::Spread { name, directives, pos });
+                } else {
+                    let directives = self.directives()?;
+                    let inner = self.selection_set()?;
+                    selections.push(Selection::Inline { on: None, directives, selections: inner, pos });
+                }
+                continue;
+            }
+            let mut name = self.name()?;
+            let mut alias = None;
+            if self.eat(':')? {
+                alias = Some(name);
+                name = self.name()?;
+            }
+            let arguments = self.arguments()?;
+            let directives = self.directives()?;
+            let inner = match self.token == Token::Punct('{') {
+                true => self.selection_set()?,
+                false => Vec::new(),
+            };
+            selections.push(Selection::Field(Field {
+                alias,
+                name,
+                arguments,
+                directives,
+                selections: inner,
+                pos,
+            }));
+        }
+        Ok(selections)
+    }
+}
+
+fn parse(query: &str) -> Parsed<Document> {
+    let mut lexer = Lexer {
+        text: query,
+        offset: 0,
+        line: 1,
+        line_start: 0,
+    };
+    Parser::new(&mut lexer)?.document()
+}
+
+fn error(message: impl Into<String>, pos: Pos) -> Json {
+    json!({"message": message.into(), "locations": [{"line": pos.line, "column": pos.column}]})
+}
+
+// Checks the selections against the schema before anything runs, so a
+// mistake in a mutation doesn't leave it half done.
+fn check(object: &str, selections: &[Selection], document: &Document, spreading: &mut Vec<String>, errors: &mut Vec<Json>) {
+    for selection in selections {
+        match selection {
+            Selection::Field(field) if field.name == "__typename" => {
+                if !field.selections.is_empty() || !field.arguments.is_empty() {
+                    errors.push(error("__typename takes no arguments or selections", field.pos));
+                }
+            }
+            Selection::Field(field) => {
+                let Some((_, ty, args)) = fields_of(object).iter().find(|(name, ..)| *name == field.name) else {
+                    errors.push(error(format!("Cannot query field {:?} on type {:?}.", field.name, object), field.pos));
+                    continue;
+                };
+                for (name, _) in &field.arguments {
+                    if !args.iter().any(|(arg, _)| arg == name) {
+                        errors.push(error(format!("Unknown argument {:?} on field \"{}.{}\".", name, object, field.name), field.pos));
+                    }
+                }
+                for (arg, arg_ty) in *args {
+                    if Type::of(arg_ty).is_non_null() && !field.arguments.iter().any(|(name, _)| name == arg) {
+                        errors.push(error(
+                            format!("Field {:?} argument {:?} of type {:?} is required, but it was not provided.", field.name, arg, arg_ty),
+                            field.pos,
+                        ));
+                    }
+                }
+                let ty = Type::of(ty);
+                match (is_object(ty.named()), field.selections.is_empty()) {
+                    (true, true) => errors.push(error(
+                        format!("Field {:?} of type {:?} must have a selection of subfields.", field.name, ty.to_string()),
+                        field.pos,
+                    )),
+                    (false, false) => errors.push(error(
+                        format!("Field {:?} must not have a selection since type {:?} has no subfields.", field.name, ty.to_string()),
+                        field.pos,
+                    )),
+                    (true, false) => check(ty.named(), &field.selections, document, spreading, errors),
+                    (false, true) => {}
+                }
+            }
+            Selection::Spread { name, pos, .. } => {
+                let Some(fragment) = document.fragments.get(name) else {
+                    errors.push(error(format!("Unknown fragment {:?}.", name), *pos));
+                    continue;
+                };
+                if fragment.on != object {
+                    errors.push(error(format!("Fragment {:?} on {:?} can't be spread within {:?}.", name, fragment.on, object), *pos));
+                } else if spreading.contains(name) {
+                    errors.push(error(format!("Cannot spread fragment {:?} within itself.", name), *pos));
+                } else {
+                    spreading.push(name.clone());
+                    check(object, &fragment.selections, document, spreading, errors);
+                    spreading.pop();
+                }
+            }
+            Selection::Inline { on, selections, pos, .. } => match on {
+                Some(on) if on != object => {
+                    errors.push(error(format!("An inline fragment on {:?} can't be spread within {:?}.", on, object), *pos));
+                }
+                _ => check(object, selections, document, spreading, errors),
+            },
+        }
+    }
+}
+
+// A literal with its variables filled in, as the JSON a variable would be
+// given as. None when it names a variable that wasn't given.
+fn resolve(value: &Value, variables: &Map<String, Json>) -> Result<Option<Json>, String> {
+    Ok(Some(match value {
+        Value::Variable(name) => return Ok(variables.get(name).cloned()),
+        Value::Int(int) => json!(int),
+        Value::Float(float) => json!(float),
+        Value::String(text) => json!(text),
+        Value::Boolean(boolean) => json!(boolean),
+        Value::Null => Json::Null,
+        Value::Enum(name) => return Err(format!("the schema has no enum value {}", name)),
+        Value::List(items) => {
+            let mut list = Vec::new();
+            for item in items {
+                list.push(resolve(item, variables)?.unwrap_or(Json::Null));
+            }
+            Json::Array(list)
+        }
+        Value::Object(fields) => {
+            let mut object = Map::new();
+            for (name, value) in fields {
+                if let Some(value) = resolve(value, variables)? {
+                    object.insert(name.clone(), value);
+                }
+            }
+            Json::Object(object)
+        }
+    }))
+}
+
+// Input coercion: a value given for `ty`, as its canonical JSON.
+fn coerce(value: &Json, ty: &Type, what: &str) -> Result<Json, String> {
+    let name = match ty {
+        Type::NonNull(_) if value.is_null() => return Err(format!("{} must not be null", what)),
+        Type::NonNull(inner) => return coerce(value, inner, what),
+        _ if value.is_null() => return Ok(Json::Null),
+        Type::List(inner) => {
+            return match value {
+                Json::Array(items) => items.iter().map(|item| coerce(item, inner, what)).collect(),
+                single => Ok(Json::Array(vec![coerce(single, inner, what)?])),
+            }
+        }
+        Type::Named(name) => name.as_str(),
+    };
+    let wrong = || Err(format!("{} must be {}, got {}", what, name, value));
+    match name {
+        "String" => match value {
+            Json::String(_) => Ok(value.clone()),
+            _ => wrong(),
+        },
+        "ID" => match value {
+            Json::String(_) => Ok(value.clone()),
+            Json::Number(n) if n.is_i64() || n.is_u64() => Ok(json!(n.to_string())),
+            _ => wrong(),
+        },
+        "Int" => match value.as_i64().filter(|&int| i32::try_from(int).is_ok()) {
+            Some(int) => Ok(json!(int)),
+            None => wrong(),
+        },
+        "Float" => match value.as_f64() {
+            Some(float) => Ok(json!(float)),
+            None => wrong(),
+        },
+        "Boolean" => match value {
+            Json::Bool(_) => Ok(value.clone()),
+            _ => wrong(),
+        },
+        _ => {
+            let Some((_, fields)) = INPUTS.iter().find(|(input, _)| *input == name) else {
+                return Err(format!("{} has type {}, which isn't an input type", what, name));
+            };
+            let Json::Object(given) = value else {
+                return wrong();
+            };
+            if let Some(unknown) = given.keys().find(|key| !fields.iter().any(|(field, _)| field == key)) {
+                return Err(format!("{} has no field {:?} in {}", what, unknown, name));
+            }
+            let mut object = Map::new();
+            for (field, field_ty) in *fields {
+                let field_ty = Type::of(field_ty);
+                match given.get(*field) {
+                    Some(value) => {
+                        object.insert(field.to_string(), coerce(value, &field_ty, &format!("{}.{}", what, field))?);
+                    }
+                    None if field_ty.is_non_null() => return Err(format!("{}.{} is required", what, field)),
+                    None => {}
+                }
+            }
+            Ok(Json::Object(object))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphQlRequest {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Json>>,
+    #[serde(default)]
+    operation_name: Option<String>,
+}
+
+// A result, keeping fields in the order the query asked for them, which
+// a JSON map would sort.
+enum Output {
+    Leaf(Json),
+    List(Vec<Output>),
+    Object(Vec<(String, Output)>),
+}
+
+impl Serialize for Output {
+    fn serialize<T: Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        match self {
+            Output::Leaf(value) => value.serialize(serializer),
+            Output::List(items) => serializer.collect_seq(items),
+            Output::Object(fields) => serializer.collect_map(fields.iter().map(|(key, value)| (key, value))),
+        }
+    }
+}
+
+struct Executor<'a, S> {
+    store: &'a S,
+    state: &'a SharedState,
+    document: &'a Document,
+    variables: Map<String, Json>,
+    errors: Vec<Json>,
+}
+
+impl<'a, S: BookStore> Executor<'a, S> {
+    // @skip and @include decide whether a selection counts.
+    fn included(&self, directives: &[Directive]) -> Result<bool, Json> {
+        for directive in directives {
+            let wanted = match directive.name.as_str() {
+                "skip" => false,
+                "include" => true,
+                other => return Err(error(format!("Unknown directive \"@{}\".", other), directive.pos)),
+            };
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .map(|(_, value)| resolve(value, &self.variables))
+                .transpose()
+                .map_err(|message| error(message, directive.pos))?
+                .flatten()
+                .and_then(|value| value.as_bool());
+            match condition {
+                Some(condition) if condition != wanted => return Ok(false),
+                Some(_) => {}
+                None => return Err(error(format!("@{} needs a Boolean `if`", directive.name), directive.pos)),
+            }
+        }
+        Ok(true)
+    }
+
+    // The fields to answer, grouped by response key in query order, with
+    // fragments flattened.
+    fn collect(&self, selections: &'a [Selection], groups: &mut Vec<(&'a str, Vec<&'a Field>)>) -> Result<(), Json> {
+        for selection in selections {
+            match selection {
+                Selection::Field(field) => {
+                    if !self.included(&field.directives)? {
+                        continue;
+                    }
+                    match groups.iter_mut().find(|(key, _)| *key == field.key()) {
+                        Some((_, fields)) => fields.push(field),
+                        None => groups.push((field.key(), vec![field])),
+                    }
+                }
+                Selection::Spread { name, directives, .. } => {
+                    if self.included(directives)? {
+                        self.collect(&self.document.fragments[name].selections, groups)?;
+                    }
+                }
+                Selection::Inline { directives, selections, .. } => {
+                    if self.included(directives)? {
+                        self.collect(selections, groups)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arguments(&self, object: &str, field: &Field) -> Result<Map<String, Json>, Json> {
+        let (_, _, args) = fields_of(object).iter().find(|(name, ..)| *name == field.name).expect("checked");
+        let mut coerced = Map::new();
+        for (arg, ty) in *args {
+            let ty = Type::of(ty);
+            let given = match field.arguments.iter().find(|(name, _)| name == arg) {
+                Some((_, value)) => resolve(value, &self.variables).map_err(|message| error(message, field.pos))?,
+                None => None,
+            };
+            match given {
+                Some(value) => {
+                    let value = coerce(&value, &ty, &format!("argument {:?}", arg)).map_err(|message| error(message, field.pos))?;
+                    coerced.insert(arg.to_string(), value);
+                }
+                None if ty.is_non_null() => {
+                    return Err(error(format!("argument {:?} of type {:?} was not provided", arg, ty.to_string()), field.pos));
+                }
+                None => {}
+            }
+        }
+        Ok(coerced)
+    }
+
+    // The subfields asked for, from every field under the same key.
+    fn book(&self, book: &Book, fields: &[&'a Field]) -> Result<Output, Json> {
+        let mut groups = Vec::new();
+        for field in fields {
+            self.collect(&field.selections, &mut groups)?;
+        }
+        let mut object = Vec::new();
+        for (key, fields) in groups {
+            let value = match fields[0].name.as_str() {
+                "__typename" => json!("Book"),
+                "id" => json!(book.id.to_string()),
+                "title" => json!(book.title),
+                "author" => json!(book.author),
+                "isbn" => json!(book.isbn),
+                "etag" => json!(etag::of(book)),
+                _ => unreachable!("checked"),
+            };
+            object.push((key.to_string(), Output::Leaf(value)));
+        }
+        Ok(Output::Object(object))
+    }
+
+    async fn root(&mut self, kind: OperationKind, selections: &'a [Selection]) -> Output {
+        let object = match kind {
+            OperationKind::Query => "Query",
+            OperationKind::Mutation => "Mutation",
+        };
+        let mut groups = Vec::new();
+        if let Err(error) = self.collect(selections, &mut groups) {
+            self.errors.push(error);
+            return Output::Leaf(Json::Null);
+        }
+        let mut data = Vec::new();
+        // Mutations run one after the other, in the order they're asked for.
+        for (key, fields) in groups {
+            let field = fields[0];
+            let result = match field.name.as_str() {
+                "__typename" => Ok(Output::Leaf(json!(object))),
+                _ => self.field(object, field, &fields).await,
+            };
+            match result {
+                Ok(value) => data.push((key.to_string(), value)),
+                Err(mut error) => {
+                    error["path"] = json!([key]);
+                    self.errors.push(error);
+                    let (_, ty, _) = fields_of(object).iter().find(|(name, ..)| *name == field.name).expect("checked");
+                    // A field that can't be null takes the whole result
+                    // with it.
+                    if Type::of(ty).is_non_null() {
+                        return Output::Leaf(Json::Null);
+                    }
+                    data.push((key.to_string(), Output::Leaf(Json::Null)));
+                }
+            }
+        }
+        Output::Object(data)
+    }
+
+    async fn field(&self, object: &str, field: &Field, fields: &[&'a Field]) -> Result<Output, Json> {
+        let args = self.arguments(object, field)?;
+        let failed = |message: String| error(message, field.pos);
+        let stored = |e: crate::StorageError| {
+            if let crate::StorageError::DuplicateIsbn { .. } = e {
+                return failed(e.to_string());
+            }
+            tracing::error!("storage error: {}", e);
+            error("Storage error", field.pos)
+        };
+        let id = || -> Result<u64, Json> {
+            let id = args["id"].as_str().unwrap_or_default();
+            id.parse().map_err(|_| failed(format!("{:?} isn't a book ID", id)))
+        };
+        if object == "Mutation" && self.state.raft.is_some() {
+            return Err(failed(format!("{} isn't supported with the raft backend yet", field.name)));
+        }
+        let book = match field.name.as_str() {
+            "books" => {
+                let filter = listing::Filter::from_json(json!({
+                    "author": args.get("author"),
+                    "title_contains": args.get("titleContains"),
+                    "has_isbn": args.get("hasIsbn"),
+                }))
+                .map_err(failed)?;
+                let first = match args.get("first").and_then(Json::as_i64) {
+                    Some(first) if first < 0 => return Err(failed("first must not be negative".to_string())),
+                    first => first.map(|first| first as usize),
+                };
+                let after = match args.get("after").and_then(Json::as_str) {
+                    Some(after) => Some(after.parse::<u64>().map_err(|_| failed(format!("{:?} isn't a book ID", after)))?),
+                    None => None,
+                };
+                let books = match (first, filter.is_empty()) {
+                    (Some(first), true) => self.store.list_after(after.unwrap_or(0), first).await.map_err(stored)?,
+                    _ => {
+                        let books = self.store.list().await.map_err(stored)?;
+                        let books = books.into_iter().filter(|book| after.is_none_or(|after| book.id > after));
+                        books.filter(|book| filter.matches(book)).take(first.unwrap_or(usize::MAX)).collect()
+                    }
+                };
+                let books: Result<Vec<Output>, Json> = books.iter().map(|book| self.book(book, fields)).collect();
+                return books.map(Output::List);
+            }
+            "book" => self.store.get(id()?).await.map_err(stored)?,
+            "createBook" => {
+                let mut request: CreateBookRequest = serde_json::from_value(args["input"].clone()).map_err(|e| failed(e.to_string()))?;
+                invalid(validate::errors(&mut request), field.pos)?;
+                Some(self.store.insert(request).await.map_err(stored)?)
+            }
+            "updateBook" => {
+                let id = id()?;
+                let mut request: UpdateBookRequest = serde_json::from_value(args["input"].clone()).map_err(|e| failed(e.to_string()))?;
+                invalid(validate::errors(&mut request), field.pos)?;
+                let if_match = if_match(&args);
+                let change = |book: &Book| if_match.check(book).map(|()| updated(book, request.clone()));
+                match self.store.modify(id, change).await.map_err(stored)? {
+                    Some(Ok(book)) => Some(book),
+                    Some(Err(changed)) => return Err(precondition(&changed, field.pos)),
+                    None => None,
+                }
+            }
+            "deleteBook" => {
+                let if_match = if_match(&args);
+                match self.store.delete_if(id()?, |book| if_match.check(book)).await.map_err(stored)? {
+                    Some(Ok(book)) => Some(book),
+                    Some(Err(changed)) => return Err(precondition(&changed, field.pos)),
+                    None => None,
+                }
+            }
+            _ => unreachable!("checked"),
+        };
+        match book {
+            Some(book) => self.book(&book, fields),
+            None => Ok(Output::Leaf(Json::Null)),
+        }
+    }
+}
+
+fn if_match(args: &Map<String, Json>) -> IfMatch {
+    match args["ifMatch"].as_str().unwrap_or_default() {
+        "*" => IfMatch::Any,
+        tag => IfMatch::OneOf(vec![tag.to_string()]),
+    }
+}
+
+fn invalid(errors: Vec<crate::problem::FieldError>, pos: Pos) -> Result<(), Json> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let mut error = error("the input is invalid", pos);
+    error["extensions"] = json!({"code": "BAD_USER_INPUT", "errors": errors});
+    Err(error)
+}
+
+fn precondition(changed: &etag::Changed, pos: Pos) -> Json {
+    let mut error = error(changed.to_string(), pos);
+    error["extensions"] = json!({"code": "PRECONDITION_FAILED", "etag": changed.current()});
+    error
+}
+
+// POST /graphql: the usual {query, variables, operationName}. A query
+// that doesn't parse or doesn't fit the schema is a 400 with `errors` and
+// no `data`; once it runs the answer is a 200, with `errors` for the
+// fields that failed. updateBook and deleteBook take the book's ETag, or
+// "*", as REST's PUT and DELETE take If-Match.
+pub async fn execute<S: BookStore>(
+    Store(store): Store<S>,
+    State(state): State<SharedState>,
+    JsonBody(request): JsonBody<GraphQlRequest>,
+) -> Result<Response<Body>, body::Error> {
+    let rejected = |error: Json| json_response(StatusCode::BAD_REQUEST, &json!({"errors": [error]}));
+    let document = match parse(&request.query) {
+        Ok(document) => document,
+        Err((message, pos)) => return rejected(error(message, pos)),
+    };
+    if let Some(anonymous) = document.operations.iter().find(|operation| operation.name.is_none()) {
+        if document.operations.len() > 1 {
+            return rejected(error("an operation without a name has to be the only one", anonymous.pos));
+        }
+    }
+    let operation = match (&request.operation_name, document.operations.as_slice()) {
+        (None, [operation]) => operation,
+        (None, _) => return rejected(json!({"message": "operationName is required when the document has several operations"})),
+        (Some(name), operations) => match operations.iter().find(|operation| operation.name.as_ref() == Some(name)) {
+            Some(operation) => operation,
+            None => return rejected(json!({"message": format!("unknown operation named {:?}", name)})),
+        },
+    };
+    let mut errors = Vec::new();
+    let root = match operation.kind {
+        OperationKind::Query => "Query",
+        OperationKind::Mutation => "Mutation",
+    };
+    check(root, &operation.selections, &document, &mut Vec::new(), &mut errors);
+    if !errors.is_empty() {
+        return json_response(StatusCode::BAD_REQUEST, &json!({"errors": errors}));
+    }
+    let given = request.variables.unwrap_or_default();
+    let mut variables = Map::new();
+    for def in &operation.variables {
+        let what = format!("variable ${}", def.name);
+        let value = match (given.get(&def.name), &def.default) {
+            (Some(value), _) => Some(value.clone()),
+            (None, Some(default)) => match resolve(default, &Map::new()) {
+                Ok(default) => default,
+                Err(message) => return rejected(error(message, def.pos)),
+            },
+            (None, None) => None,
+        };
+        let value = match value {
+            Some(value) => coerce(&value, &def.ty, &what),
+            None if def.ty.is_non_null() => Err(format!("{} of required type {} was not provided", what, def.ty)),
+            None => continue,
+        };
+        match value {
+            Ok(value) => {
+                variables.insert(def.name.clone(), value);
+            }
+            Err(message) => return rejected(error(message, def.pos)),
+        }
+    }
+    let mut executor = Executor {
+        store: &*store,
+        state: &state,
+        document: &document,
+        variables,
+        errors,
+    };
+    let data = executor.root(operation.kind, &operation.selections).await;
+    let mut body = Vec::new();
+    if !executor.errors.is_empty() {
+        body.push(("errors".to_string(), Output::Leaf(Json::Array(executor.errors))));
+    }
+    body.push(("data".to_string(), data));
+    json_response(StatusCode::OK, &Output::Object(body))
+}
+
+// GET /graphql/schema: the schema in SDL, as there's no introspection.
+pub async fn schema() -> Result<Response<Body>, body::Error> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(sdl()))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where and why a query is refused before it runs, or nothing.
+    fn refused(query: &str) -> Vec<String> {
+        let document = match parse(query) {
+            Ok(document) => document,
+            Err((message, pos)) => return vec![format!("{}:{} {}", pos.line, pos.column, message)],
+        };
+        let operation = &document.operations[0];
+        let root = match operation.kind {
+            OperationKind::Query => "Query",
+            OperationKind::Mutation => "Mutation",
+        };
+        let mut errors = Vec::new();
+        check(root, &operation.selections, &document, &mut Vec::new(), &mut errors);
+        errors
+            .iter()
+            .map(|error| {
+                let location = &error["locations"][0];
+                format!("{}:{} {}", location["line"], location["column"], error["message"].as_str().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn documents_parse() {
+        let query = r#"
+            # Everything the parser knows, at once.
+            query Shelf($author: String = "Lem", $first: Int!, $skip: Boolean = false) @cached {
+              shelf: books(author: $author, first: $first, after: "3") {
+                ...Basics
+                ... on Book @include(if: true) { isbn }
+                ... @skip(if: $skip) { etag }
+              }
+            }
+            fragment Basics on Book { id, title }
+            mutation Add { createBook(input: {title: """
+                Solaris
+                  revised
+            """, author: "Stanis\u0142aw \"Lem\"", tags: [sf, null, 1.5e3, -2]}) { id } }
+        "#;
+        let document = parse(query).unwrap();
+        let [shelf, add] = &document.operations[..] else {
+            panic!("two operations");
+        };
+        assert_eq!((shelf.kind, shelf.name.as_deref()), (OperationKind::Query, Some("Shelf")));
+        let variables: Vec<String> = shelf.variables.iter().map(|def| format!("{}: {}", def.name, def.ty)).collect();
+        assert_eq!(variables, ["author: String", "first: Int!", "skip: Boolean"]);
+        assert!(matches!(shelf.variables[0].default, Some(Value::String(ref lem)) if lem == "Lem"));
+
+        let [Selection::Field(books)] = &shelf.selections[..] else {
+            panic!("one field");
+        };
+        assert_eq!((books.key(), books.name.as_str(), books.pos.line, books.pos.column), ("shelf", "books", 4, 15));
+        assert!(matches!(&books.arguments[1].1, Value::Variable(name) if name == "first"));
+        assert!(matches!(
+            &books.selections[..],
+            [Selection::Spread { .. }, Selection::Inline { on: Some(_), .. }, Selection::Inline { on: None, .. }]
+        ));
+        assert_eq!(document.fragments["Basics"].on, "Book");
+
+        let [Selection::Field(create)] = &add.selections[..] else {
+            panic!("one field");
+        };
+        let Value::Object(input) = &create.arguments[0].1 else {
+            panic!("an input object");
+        };
+        assert!(matches!(&input[0].1, Value::String(title) if title == "Solaris\n  revised"));
+        assert!(matches!(&input[1].1, Value::String(author) if author == "Stanisław \"Lem\""));
+        assert!(matches!(
+            &input[2].1,
+            Value::List(tags) if matches!(tags[..], [Value::Enum(_), Value::Null, Value::Float(_), Value::Int(-2)])
+        ));
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_token() {
+        let cases = [
+            ("{ books { id } ", "1:16 syntax error: unexpected the end of the query"),
+            ("{ book(id: 1) { id ", "1:20 syntax error: unexpected the end of the query"),
+            ("{ books(first: ) { id } }", "1:16 syntax error: unexpected \")\""),
+            ("{\n  books { id }\n  ?\n}", "3:3 unexpected character '?'"),
+            ("{ book(id: \"1) { id } }", "1:12 unterminated string"),
+            ("{ book(id: \"\\q\") { id } }", "1:12 invalid escape in string"),
+            ("{ book(id: \"\\u12\") { id } }", "1:12 invalid \\u escape"),
+            ("{ book(id: \"\"\"1) { id } }", "1:12 unterminated block string"),
+            ("{ books(first: 1-2) { id } }", "1:16 invalid number 1-2"),
+            ("books { id }", "1:1 syntax error: unexpected name \"books\""),
+            ("fragment on on Book { id }", "1:1 a fragment can't be named \"on\""),
+            ("fragment A on Book { id } fragment A on Book { title }", "1:27 there can be only one fragment named \"A\""),
+            ("subscription { books { id } }", "1:1 subscriptions aren't supported; /ws and /books/events stream changes"),
+            ("fragment A on Book { id }", "1:26 the document has no operation"),
+            ("query ($a: Int = $b) { books { id } }", "1:18 a variable's default can't use variables"),
+            ("query ($a: [Int!) { books { id } }", "1:17 syntax error: unexpected \")\""),
+            ("", "1:1 the document has no operation"),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(refused(query), [expected], "{}", query);
+        }
+    }
+
+    #[test]
+    fn queries_are_checked_against_the_schema() {
+        let cases: [(&str, &[&str]); 9] = [
+            ("{ books { id __typename } __typename }", &[]),
+            ("{ shelves { id } }", &["1:3 Cannot query field \"shelves\" on type \"Query\"."]),
+            ("{ book { id } }", &["1:3 Field \"book\" argument \"id\" of type \"ID!\" is required, but it was not provided."]),
+            (
+                "{ book(id: 1, isbn: 2) { id, pages } }",
+                &["1:3 Unknown argument \"isbn\" on field \"Query.book\".", "1:30 Cannot query field \"pages\" on type \"Book\"."],
+            ),
+            ("{ books }", &["1:3 Field \"books\" of type \"[Book!]!\" must have a selection of subfields."]),
+            ("{ books { title { id } } }", &["1:11 Field \"title\" must not have a selection since type \"String!\" has no subfields."]),
+            ("{ books { ...Missing } }", &["1:11 Unknown fragment \"Missing\"."]),
+            ("{ books { ...A } } fragment A on Book { ...A }", &["1:41 Cannot spread fragment \"A\" within itself."]),
+            ("{ ...OnBook } fragment OnBook on Book { id }", &["1:3 Fragment \"OnBook\" on \"Book\" can't be spread within \"Query\"."]),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(refused(query), expected, "{}", query);
+        }
+        assert_eq!(refused("mutation { books { id } }"), ["1:12 Cannot query field \"books\" on type \"Mutation\"."]);
+    }
+
+    #[test]
+    fn inputs_are_coerced_to_their_types() {
+        let coerced = |value: Json, ty: &str| coerce(&value, &Type::of(ty), "argument \"x\"");
+        assert_eq!(coerced(json!(7), "ID!"), Ok(json!("7")));
+        assert_eq!(coerced(json!("7"), "ID"), Ok(json!("7")));
+        assert_eq!(coerced(json!(null), "Int"), Ok(json!(null)));
+        assert_eq!(coerced(json!(1), "[Int!]"), Ok(json!([1])));
+        assert_eq!(coerced(json!(null), "String!"), Err("argument \"x\" must not be null".to_string()));
+        assert_eq!(coerced(json!(1.5), "Int"), Err("argument \"x\" must be Int, got 1.5".to_string()));
+        assert_eq!(coerced(json!(3_000_000_000i64), "Int"), Err("argument \"x\" must be Int, got 3000000000".to_string()));
+        assert_eq!(coerced(json!([1, null]), "[Int!]"), Err("argument \"x\" must not be null".to_string()));
+        assert_eq!(
+            coerced(json!({"title": "Solaris", "author": "Lem"}), "CreateBookInput!"),
+            Ok(json!({"title": "Solaris", "author": "Lem"}))
+        );
+        assert_eq!(
+            coerced(json!({"title": "Solaris"}), "CreateBookInput!"),
+            Err("argument \"x\".author is required".to_string())
+        );
+        assert_eq!(
+            coerced(json!({"title": "Solaris", "pages": 204}), "UpdateBookInput"),
+            Err("argument \"x\" has no field \"pages\" in UpdateBookInput".to_string())
+        );
+        assert_eq!(coerced(json!({}), "Book"), Err("argument \"x\" has type Book, which isn't an input type".to_string()));
+    }
+}
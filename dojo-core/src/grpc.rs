@@ -0,0 +1,401 @@
+use books_model::{CreateBookRequest as NewBook, Edition, UpdateBookRequest as BookChange};
+use serde_json::json;
+use std::{
+    convert::Infallible,
+    env,
+    future::Future,
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+use tonic::{
+    body::BoxBody,
+    codec::ProstCodec,
+    codegen::{http, Body, BoxFuture, Service, StdError},
+    metadata::MetadataMap,
+    server::{Grpc, NamedService, UnaryService},
+    Code, Request, Response, Status,
+};
+
+use crate::{
+    audit,
+    auth::{self, Refusal},
+    etag::{self, IfMatch},
+    listing, request_id,
+    runner::Shutdown,
+    store::BookStore,
+    updated, validate, SharedState,
+};
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+// The messages of proto/books.proto. There's no protoc to generate them
+// from it, so they're written out here with the same tags.
+#[derive(Clone, PartialEq, prost::Message)]
+struct Book {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(string, tag = "2")]
+    title: String,
+    #[prost(string, tag = "3")]
+    author: String,
+    #[prost(string, optional, tag = "4")]
+    isbn: Option<String>,
+    #[prost(string, tag = "5")]
+    etag: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct GetBookRequest {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ListBooksRequest {
+    #[prost(uint32, tag = "1")]
+    page_size: u32,
+    #[prost(string, tag = "2")]
+    page_token: String,
+    #[prost(string, optional, tag = "3")]
+    author: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    title_contains: Option<String>,
+    #[prost(bool, optional, tag = "5")]
+    has_isbn: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ListBooksResponse {
+    #[prost(message, repeated, tag = "1")]
+    books: Vec<Book>,
+    #[prost(string, tag = "2")]
+    next_page_token: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CreateBookRequest {
+    #[prost(string, tag = "1")]
+    title: String,
+    #[prost(string, tag = "2")]
+    author: String,
+    #[prost(string, optional, tag = "3")]
+    isbn: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct UpdateBookRequest {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(string, optional, tag = "2")]
+    title: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    author: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    isbn: Option<String>,
+    #[prost(string, tag = "5")]
+    etag: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct DeleteBookRequest {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(string, tag = "2")]
+    etag: String,
+}
+
+fn message(book: &books_model::Book) -> Book {
+    Book {
+        id: book.id,
+        title: book.title.clone(),
+        author: book.author.clone(),
+        isbn: book.isbn.clone(),
+        etag: etag::of(book),
+    }
+}
+
+// DOJO_GRPC_ADDR, as host:port, serves dojo.books.v1.BookService over
+// plaintext HTTP/2. Without it there's no gRPC listener.
+pub fn addr_from_env() -> Result<Option<SocketAddr>, String> {
+    match env::var("DOJO_GRPC_ADDR") {
+        Ok(addr) => addr
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("DOJO_GRPC_ADDR must be host:port, got {:?}", addr)),
+        Err(_) => Ok(None),
+    }
+}
+
+// Runs until the shutdown, then lets the calls in flight finish. Nothing
+// to do without an address.
+pub async fn serve(addr: Option<SocketAddr>, state: SharedState, shutdown: Shutdown) -> Result<(), String> {
+    let Some(addr) = addr else {
+        return Ok(());
+    };
+    tracing::info!(bind = %addr, "gRPC server running");
+    tonic::transport::Server::builder()
+        .add_service(BookService(state))
+        .serve_with_shutdown(addr, shutdown.wait())
+        .await
+        .map_err(|e| match std::error::Error::source(&e) {
+            Some(source) => format!("gRPC on {}: {}", addr, source),
+            None => format!("gRPC on {}: {}", addr, e),
+        })
+}
+
+// What tonic-build would generate for the service: a route per method,
+// each decoding its request and encoding the answer with prost.
+#[derive(Clone)]
+struct BookService(SharedState);
+
+impl NamedService for BookService {
+    const NAME: &'static str = "dojo.books.v1.BookService";
+}
+
+impl<B> Service<http::Request<B>> for BookService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let state = self.0.clone();
+        match request.uri().path().strip_prefix("/dojo.books.v1.BookService/") {
+            Some("Get") => unary(request, move |request| get(state, request)),
+            Some("List") => unary(request, move |request| list(state, request)),
+            Some("Create") => unary(request, move |request| create(state, request)),
+            Some("Update") => unary(request, move |request| update(state, request)),
+            Some("Delete") => unary(request, move |request| delete(state, request)),
+            _ => Box::pin(async { Ok(Status::unimplemented("").into_http()) }),
+        }
+    }
+}
+
+fn unary<Req, Res, F, Fut, B>(request: http::Request<B>, method: F) -> BoxFuture<http::Response<BoxBody>, Infallible>
+where
+    Req: prost::Message + Default + Send + 'static,
+    Res: prost::Message + Send + 'static,
+    F: FnOnce(Request<Req>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Response<Res>, Status>> + Send + 'static,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    Box::pin(async move { Ok(Grpc::new(ProstCodec::default()).unary(Once(Some(method)), request).await) })
+}
+
+// Grpc::unary calls the service it's given once per request.
+struct Once<F>(Option<F>);
+
+impl<Req, Res, F, Fut> UnaryService<Req> for Once<F>
+where
+    F: FnOnce(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Res>, Status>>,
+{
+    type Response = Res;
+    type Future = Fut;
+
+    fn call(&mut self, request: Request<Req>) -> Fut {
+        (self.0.take().expect("a unary service is called once"))(request)
+    }
+}
+
+// The same keys and tokens as REST, sent as metadata: x-api-key, or
+// authorization with a bearer token. Writes with the raft backend go
+// through the log, which only REST does.
+fn refused<T>(state: &SharedState, request: &Request<T>, write: bool) -> Option<Status> {
+    match auth::check(write, &credentials(request), state) {
+        Err(Refusal::Unauthenticated(message, _)) => Some(Status::unauthenticated(message)),
+        Err(Refusal::Forbidden(message)) => Some(Status::permission_denied(message)),
+        Ok(()) if write && state.raft.is_some() => {
+            Some(Status::unimplemented("writes over gRPC aren't supported with the raft backend yet"))
+        }
+        Ok(()) => None,
+    }
+}
+
+fn credentials<T>(request: &Request<T>) -> hyper::HeaderMap {
+    let mut headers = hyper::HeaderMap::new();
+    for name in ["authorization", auth::API_KEY_HEADER] {
+        let value = request.metadata().get(name).map(|value| hyper::header::HeaderValue::from_bytes(value.as_bytes()));
+        if let Some(Ok(value)) = value {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+// Who the audit log records for a write: the same principal as over REST,
+// and the caller's x-request-id if it sent one.
+fn actor<T>(state: &SharedState, request: &Request<T>) -> audit::Actor {
+    let request_id = request.metadata().get(request_id::HEADER).and_then(|id| id.to_str().ok()).map(str::to_string);
+    audit::Actor::new(auth::principal(&credentials(request), state), request_id)
+}
+
+fn stored(e: crate::StorageError) -> Status {
+    if let crate::StorageError::DuplicateIsbn { .. } = e {
+        return Status::already_exists(e.to_string());
+    }
+    tracing::error!("storage error: {}", e);
+    Status::internal("Storage error")
+}
+
+fn not_found(id: u64) -> Status {
+    Status::not_found(format!("no book {}", id))
+}
+
+fn invalid(errors: Vec<crate::problem::FieldError>) -> Option<Status> {
+    if errors.is_empty() {
+        return None;
+    }
+    let errors: Vec<String> = errors.iter().map(|error| format!("{}: {}", error.field, error.message)).collect();
+    Some(Status::invalid_argument(errors.join("; ")))
+}
+
+// None when there isn't one, which Update and Delete refuse.
+fn if_match(etag: &str) -> Option<IfMatch> {
+    match etag {
+        "" => None,
+        "*" => Some(IfMatch::Any),
+        tag => Some(IfMatch::OneOf(vec![tag.to_string()])),
+    }
+}
+
+fn etag_required() -> Status {
+    Status::failed_precondition("send etag with the book's etag, or * for any version")
+}
+
+// FAILED_PRECONDITION, with the book's current etag in the `etag`
+// trailer.
+fn changed(changed: &etag::Changed) -> Status {
+    let mut metadata = MetadataMap::new();
+    if let Ok(value) = changed.current().parse() {
+        metadata.insert("etag", value);
+    }
+    Status::with_metadata(Code::FailedPrecondition, changed.to_string(), metadata)
+}
+
+async fn get(state: SharedState, request: Request<GetBookRequest>) -> Result<Response<Book>, Status> {
+    if let Some(refused) = refused(&state, &request, false) {
+        return Err(refused);
+    }
+    let id = request.into_inner().id;
+    match state.get(id).await.map_err(stored)? {
+        Some(book) => Ok(Response::new(message(&book))),
+        None => Err(not_found(id)),
+    }
+}
+
+// In id order, a page at a time; next_page_token is a REST cursor.
+async fn list(state: SharedState, request: Request<ListBooksRequest>) -> Result<Response<ListBooksResponse>, Status> {
+    if let Some(refused) = refused(&state, &request, false) {
+        return Err(refused);
+    }
+    let request = request.into_inner();
+    let size = match request.page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        size if size > MAX_PAGE_SIZE => {
+            return Err(Status::invalid_argument(format!("page_size must be at most {}", MAX_PAGE_SIZE)))
+        }
+        size => size,
+    } as usize;
+    let after = match request.page_token.as_str() {
+        "" => 0,
+        token => listing::decode_cursor(token).ok_or_else(|| Status::invalid_argument("Invalid page_token"))?,
+    };
+    let filter = listing::Filter::from_json(json!({
+        "author": request.author,
+        "title_contains": request.title_contains,
+        "has_isbn": request.has_isbn,
+    }))
+    .map_err(Status::invalid_argument)?;
+    // One more than the page, to tell whether another follows.
+    let mut books = match filter.is_empty() {
+        true => state.list_after(after, size + 1).await.map_err(stored)?,
+        false => {
+            let mut books = state.list().await.map_err(stored)?;
+            books.retain(|book| book.id > after && filter.matches(book));
+            books.sort_by_key(|book| book.id);
+            books
+        }
+    };
+    let more = books.len() > size;
+    books.truncate(size);
+    let next_page_token = match (more, books.last()) {
+        (true, Some(last)) => listing::encode_cursor(last.id),
+        _ => String::new(),
+    };
+    Ok(Response::new(ListBooksResponse {
+        books: books.iter().map(message).collect(),
+        next_page_token,
+    }))
+}
+
+async fn create(state: SharedState, request: Request<CreateBookRequest>) -> Result<Response<Book>, Status> {
+    if let Some(refused) = refused(&state, &request, true) {
+        return Err(refused);
+    }
+    let actor = actor(&state, &request);
+    let request = request.into_inner();
+    let mut book = NewBook {
+        title: request.title,
+        author: request.author,
+        isbn: request.isbn,
+        author_id: None,
+        tags: Vec::new(),
+        edition: Edition::default(),
+        uid: None,
+    };
+    if let Some(invalid) = invalid(validate::errors(&mut book)) {
+        return Err(invalid);
+    }
+    let book = audit::acting(actor, state.insert(book)).await.map_err(stored)?;
+    Ok(Response::new(message(&book)))
+}
+
+async fn update(state: SharedState, request: Request<UpdateBookRequest>) -> Result<Response<Book>, Status> {
+    if let Some(refused) = refused(&state, &request, true) {
+        return Err(refused);
+    }
+    let actor = actor(&state, &request);
+    let request = request.into_inner();
+    let if_match = if_match(&request.etag).ok_or_else(etag_required)?;
+    let mut change = BookChange {
+        title: request.title,
+        author: request.author,
+        isbn: request.isbn,
+        author_id: None,
+        tags: None,
+        edition: Edition::default(),
+    };
+    if let Some(invalid) = invalid(validate::errors(&mut change)) {
+        return Err(invalid);
+    }
+    let apply = |book: &books_model::Book| if_match.check(book).map(|()| updated(book, change.clone()));
+    match audit::acting(actor, state.modify(request.id, apply)).await.map_err(stored)? {
+        Some(Ok(book)) => Ok(Response::new(message(&book))),
+        Some(Err(e)) => Err(changed(&e)),
+        None => Err(not_found(request.id)),
+    }
+}
+
+async fn delete(state: SharedState, request: Request<DeleteBookRequest>) -> Result<Response<Book>, Status> {
+    if let Some(refused) = refused(&state, &request, true) {
+        return Err(refused);
+    }
+    let actor = actor(&state, &request);
+    let request = request.into_inner();
+    let if_match = if_match(&request.etag).ok_or_else(etag_required)?;
+    match audit::acting(actor, state.delete_if(request.id, |book| if_match.check(book))).await.map_err(stored)? {
+        Some(Ok(book)) => Ok(Response::new(message(&book))),
+        Some(Err(e)) => Err(changed(&e)),
+        None => Err(not_found(request.id)),
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    env, io,
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    process::Command,
+};
+
+use crate::listen::{Bind, Listener};
+
+const INHERITED_FDS: &str = "DOJO_INHERITED_FDS";
+
+// On SIGUSR2 the server replaces itself with a new process from the same
+// executable and arguments, handing its listening sockets over instead of
+// closing them. It first stops accepting, lets its requests finish and
+// saves its snapshot as it would on SIGTERM; the new process then loads
+// that, takes the sockets and accepts the connections that queued up in
+// the meantime, so they wait rather than being refused. The gRPC listener
+// isn't handed over.
+pub struct Sockets(Vec<OwnedFd>);
+
+impl Sockets {
+    // Binds every listener now, keeping a copy of each socket so it stays
+    // open once the servers are done with it.
+    pub fn bind(listeners: Vec<Listener>) -> Result<(Vec<Listener>, Self), String> {
+        let mut sockets = Vec::new();
+        let mut bound = Vec::new();
+        for listener in listeners {
+            let bind = listener.bind.bound()?;
+            if let Some(socket) = bind.socket() {
+                sockets.push(socket.map_err(|e| format!("{}: {}", bind, e))?);
+            }
+            bound.push(Listener { bind, ..listener });
+        }
+        Ok((bound, Sockets(sockets)))
+    }
+
+    // Starts the new process with the sockets, returning its pid.
+    pub fn hand_over(self) -> io::Result<u32> {
+        let mut fds = Vec::new();
+        for socket in &self.0 {
+            let fd = socket.as_raw_fd();
+            // SAFETY: only clears close-on-exec on a descriptor this owns.
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            fds.push(fd.to_string());
+        }
+        let child = Command::new(env::current_exe()?)
+            .args(env::args_os().skip(1))
+            .env(INHERITED_FDS, fds.join(","))
+            .spawn()?;
+        Ok(child.id())
+    }
+}
+
+// Puts the sockets a previous process handed over in place of the
+// listeners they were bound for.
+pub fn adopt(listeners: &mut [Listener]) -> Result<(), String> {
+    let Ok(fds) = env::var(INHERITED_FDS) else {
+        return Ok(());
+    };
+    // Processes this one starts get their own.
+    env::remove_var(INHERITED_FDS);
+    for fd in fds.split(',').filter(|fd| !fd.is_empty()) {
+        let fd: RawFd = fd
+            .parse()
+            .map_err(|_| format!("{} must be a list of descriptors, got {:?}", INHERITED_FDS, fds))?;
+        let inherited = Bind::from_fd(fd)?;
+        match listeners.iter_mut().find(|listener| bound_for(&listener.bind, &inherited)) {
+            Some(listener) => listener.bind = inherited,
+            None => tracing::warn!("closing {}, which is no longer configured", inherited),
+        }
+    }
+    Ok(())
+}
+
+fn bound_for(configured: &Bind, inherited: &Bind) -> bool {
+    match (configured, inherited) {
+        (Bind::Tcp(tcp), Bind::InheritedTcp(listener)) => listener.local_addr().is_ok_and(|addr| tcp.matches(addr)),
+        (Bind::Unix(unix), Bind::InheritedUnix(listener)) => listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path == unix.path))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listen::TcpBind;
+
+    #[test]
+    fn handed_over_sockets_find_their_listeners() {
+        let configured = |addr: &str| Bind::Tcp(TcpBind { addr: addr.parse().unwrap(), v6only: false, reuse_port: false });
+        let (bound, sockets) = Sockets::bind(vec![Listener::from(configured("127.0.0.1:0"))]).unwrap();
+        assert_eq!(sockets.0.len(), 1);
+        let Bind::InheritedTcp(listener) = &bound[0].bind else {
+            panic!("expected a bound TCP socket");
+        };
+        let port = listener.local_addr().unwrap().port();
+        assert!(bound_for(&configured(&format!("127.0.0.1:{}", port)), &bound[0].bind));
+        assert!(!bound_for(&configured("127.0.0.1:1"), &bound[0].bind));
+    }
+}
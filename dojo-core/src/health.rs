@@ -0,0 +1,112 @@
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    body::{self, Body},
+    extract::State,
+    json_response,
+    maintenance::Mode,
+    SharedState,
+};
+
+// How long the in-memory catalog's lock may take before the instance counts
+// as stuck.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    latency_ms: u64,
+}
+
+impl Check {
+    async fn run(probe: impl std::future::Future<Output = Result<String, String>>) -> Self {
+        let start = Instant::now();
+        let (status, detail) = match probe.await {
+            Ok(detail) => (Status::Ok, Some(detail)),
+            Err(e) => (Status::Failed, Some(e)),
+        };
+        Check {
+            status,
+            detail: detail.filter(|detail| !detail.is_empty()),
+            latency_ms: start.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: Status,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, Check>,
+}
+
+// GET /health: the process is up and serving. Nothing it depends on is
+// checked, so an orchestrator doesn't restart it over a database outage.
+pub async fn health() -> Result<Response<Body>, body::Error> {
+    let health = Health {
+        status: Status::Ok,
+        checks: BTreeMap::new(),
+    };
+    json_response(StatusCode::OK, &health)
+}
+
+// Whether the storage backend answers.
+pub async fn storage(state: &SharedState) -> Check {
+    Check::run(async {
+        if let Some(postgres) = &state.postgres {
+            return postgres.ping().await.map(|()| format!("postgres {}", postgres.describe()));
+        }
+        if let Some(redis) = &state.redis {
+            return redis.ping().await.map(|()| format!("redis {}", redis.describe()));
+        }
+        match tokio::time::timeout(LOCK_TIMEOUT, state.storage.read()).await {
+            Ok(_) => Ok("memory".to_string()),
+            Err(_) => Err("the catalog lock is held too long".to_string()),
+        }
+    })
+    .await
+}
+
+// GET /ready: whether requests can be served, that is the storage backend
+// answers, with raft a leader is known, and the server isn't down for
+// maintenance. 503 when any check fails, so a load balancer stops sending
+// traffic until it passes again.
+pub async fn ready(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let mut checks = BTreeMap::new();
+    checks.insert("storage", storage(&state).await);
+    if let Some(raft) = &state.raft {
+        let raft = Check::run(async {
+            match raft.leadership() {
+                (true, _, term) => Ok(format!("leader in term {}", term)),
+                (false, Some(leader), term) => Ok(format!("following {} in term {}", leader, term)),
+                (false, None, _) => Err("no leader is known".to_string()),
+            }
+        })
+        .await;
+        checks.insert("raft", raft);
+    }
+    if state.maintenance.mode() == Mode::Unavailable {
+        checks.insert("maintenance", Check::run(async { Err("down for maintenance".to_string()) }).await);
+    }
+    let failed = checks.values().any(|check| matches!(check.status, Status::Failed));
+    let health = Health {
+        status: if failed { Status::Failed } else { Status::Ok },
+        checks,
+    };
+    let status = if failed { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+    json_response(status, &health)
+}
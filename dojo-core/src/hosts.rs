@@ -0,0 +1,81 @@
+use hyper::{header, http::uri::Authority, Request};
+
+use crate::{body::Body, stack::Stack};
+
+#[derive(Debug)]
+enum HostPattern {
+    Exact(String),
+    // `*.example.com` matches any subdomain, but not `example.com` itself.
+    Suffix(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Self {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Suffix(suffix.to_string()),
+            _ => HostPattern::Exact(pattern),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Suffix(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Hosts(Vec<(HostPattern, Stack)>);
+
+impl Hosts {
+    // DOJO_HOSTS holds `;`-separated entries of the form `<host> [options]`
+    // with the same options as a listener's stack. Hosts are tried in order
+    // and requests for other hosts keep the listener's stack.
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("DOJO_HOSTS") {
+            Ok(spec) => Self::parse(&spec),
+            Err(_) => Ok(Hosts::default()),
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut hosts = Vec::new();
+        for entry in spec.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let mut words = entry.split_whitespace();
+            let host = words.next().unwrap_or_default();
+            let mut stack = Stack::default();
+            for option in words {
+                let (key, value) = option
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid host option {:?}, expected key=value", option))?;
+                if !stack.set_option(key, value)? {
+                    return Err(format!("unsupported host option {:?} for {}", key, host));
+                }
+            }
+            hosts.push((HostPattern::parse(host), stack));
+        }
+        Ok(Hosts(hosts))
+    }
+
+    pub fn select(&self, req: &Request<Body>) -> Option<&Stack> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let host = request_host(req)?;
+        self.0
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&host))
+            .map(|(_, stack)| stack)
+    }
+}
+
+// HTTP/2 requests carry the host in the URI authority rather than a header.
+fn request_host(req: &Request<Body>) -> Option<String> {
+    let host = match req.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => req.headers().get(header::HOST)?.to_str().ok()?.parse::<Authority>().ok()?,
+    };
+    Some(host.host().trim_end_matches('.').to_ascii_lowercase())
+}
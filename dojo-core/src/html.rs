@@ -0,0 +1,143 @@
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Response, StatusCode,
+};
+use maud::{html, Markup, DOCTYPE};
+
+use crate::{
+    body::Body,
+    extract::{FromRequest, RequestContext},
+    jsonapi, Book,
+};
+
+// The representation picked from the Accept header. JSON wins ties, so
+// clients sending `*/*` or nothing keep getting JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Html,
+}
+
+impl Format {
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let mut json = 0.0;
+        let mut html = 0.0;
+        for range in headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let mut params = range.split(';');
+            let media = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match media.as_str() {
+                // The negotiate and jsonapi layers turn JSON into these.
+                "application/json" | "application/*" | "application/msgpack" | "application/x-msgpack"
+                | "application/vnd.msgpack" | "application/cbor" | jsonapi::MEDIA_TYPE => json = f32::max(json, q),
+                "text/html" | "text/*" => html = f32::max(html, q),
+                "*/*" => {
+                    json = f32::max(json, q);
+                    html = f32::max(html, q);
+                }
+                _ => {}
+            }
+        }
+        if html > json {
+            Format::Html
+        } else {
+            Format::Json
+        }
+    }
+}
+
+impl FromRequest for Format {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        Ok(Format::negotiate(ctx.headers()))
+    }
+}
+
+// Both representations share a URL, so caches must key on Accept.
+pub fn vary_accept(mut response: Response<Body>) -> Response<Body> {
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("Accept"));
+    response
+}
+
+pub fn page(title: &str, content: Markup) -> Response<Body> {
+    let markup = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                title { (title) }
+            }
+            body {
+                nav { a href="/v1/books" { "Catalog" } }
+                (content)
+            }
+        }
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(markup.into_string()))
+        .unwrap()
+}
+
+pub fn catalog(mut books: Vec<Book>) -> Response<Body> {
+    books.sort_by_key(|book| book.id);
+    page(
+        "Catalog",
+        html! {
+            h1 { "Catalog" }
+            @if books.is_empty() {
+                p { "No books yet." }
+            } @else {
+                table {
+                    thead { tr { th { "Title" } th { "Author" } th { "ISBN" } } }
+                    tbody {
+                        @for book in &books {
+                            tr {
+                                td { a href={ "/v1/books/" (book.id) } { (book.title) } }
+                                td { (book.author) }
+                                td { (book.isbn.as_deref().unwrap_or("")) }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+pub fn book_detail(book: &Book) -> Response<Body> {
+    page(
+        &book.title,
+        html! {
+            h1 { (book.title) }
+            dl {
+                dt { "Author" } dd { (book.author) }
+                dt { "ISBN" } dd { (book.isbn.as_deref().unwrap_or("—")) }
+                @if !book.tags.is_empty() {
+                    dt { "Tags" } dd { (book.tags.join(", ")) }
+                }
+                @if let Some(publisher) = &book.edition.publisher {
+                    dt { "Publisher" } dd { (publisher) }
+                }
+                @if let Some(year) = book.edition.published_year {
+                    dt { "Published" } dd { (year) }
+                }
+                @if let Some(pages) = book.edition.pages {
+                    dt { "Pages" } dd { (pages) }
+                }
+                @if let Some(language) = &book.edition.language {
+                    dt { "Language" } dd { (language) }
+                }
+                dt { "ID" } dd { (book.id) }
+            }
+        },
+    )
+}
@@ -0,0 +1,294 @@
+use hyper::{header, header::HeaderMap, header::HeaderValue, Request, Response};
+use rust_embed::RustEmbed;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::OnceLock,
+};
+
+use crate::{
+    body::{self, Body},
+    import::media,
+    problem,
+    stack::Next,
+    SharedState,
+};
+
+const FALLBACK: &str = "en";
+
+// One JSON file per language, from message codes to templates whose
+// `{name}` placeholders stand for what varies, like an id or a length.
+// en.json is what the server writes; the others translate it.
+#[derive(RustEmbed)]
+#[folder = "locales/"]
+struct Locales;
+
+#[derive(Debug)]
+enum Piece {
+    Text(String),
+    Slot(String),
+}
+
+#[derive(Debug)]
+struct Template(Vec<Piece>);
+
+impl Template {
+    fn parse(text: &str) -> Self {
+        let mut pieces = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            if start > 0 {
+                pieces.push(Piece::Text(rest[..start].to_string()));
+            }
+            pieces.push(Piece::Slot(rest[start + 1..start + end].to_string()));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Text(rest.to_string()));
+        }
+        Template(pieces)
+    }
+
+    // How much of a message the template pins down, so the most specific
+    // of several that match is the one used.
+    fn fixed(&self) -> usize {
+        self.0.iter().map(|piece| if let Piece::Text(text) = piece { text.len() } else { 0 }).sum()
+    }
+
+    // What each placeholder stands for in `message`, if it's one of this
+    // template's.
+    fn capture<'a>(&'a self, message: &'a str) -> Option<HashMap<&'a str, &'a str>> {
+        let mut values = HashMap::new();
+        let mut slot = None;
+        let mut at = 0;
+        for (index, piece) in self.0.iter().enumerate() {
+            match piece {
+                Piece::Slot(name) => slot = Some(name.as_str()),
+                Piece::Text(text) => {
+                    let found = match (slot.take(), index + 1 == self.0.len()) {
+                        (None, _) => message[at..].starts_with(text.as_str()).then_some(at),
+                        (Some(name), last) => {
+                            let found = match last {
+                                true => message.len().checked_sub(text.len()).filter(|&end| end >= at && message.ends_with(text.as_str())),
+                                false => message[at..].find(text.as_str()).map(|offset| at + offset),
+                            }?;
+                            values.insert(name, &message[at..found]);
+                            Some(found)
+                        }
+                    }?;
+                    at = found + text.len();
+                }
+            }
+        }
+        match slot {
+            Some(name) => {
+                values.insert(name, &message[at..]);
+            }
+            None if at != message.len() => return None,
+            None => {}
+        }
+        Some(values)
+    }
+
+    fn render(&self, values: &HashMap<&str, &str>) -> String {
+        self.0
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.as_str(),
+                Piece::Slot(name) => values.get(name.as_str()).copied().unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+struct Catalog {
+    // English templates by code, most specific first.
+    english: Vec<(String, Template)>,
+    translations: HashMap<String, HashMap<String, Template>>,
+}
+
+fn catalog() -> &'static Catalog {
+    static CATALOG: OnceLock<Catalog> = OnceLock::new();
+    CATALOG.get_or_init(|| {
+        let mut english = Vec::new();
+        let mut translations = HashMap::new();
+        for file in Locales::iter() {
+            let Some(language) = file.strip_suffix(".json") else {
+                continue;
+            };
+            let data = Locales::get(&file).map(|file| file.data).unwrap_or_default();
+            let messages: BTreeMap<String, String> = serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::error!("locales/{} is invalid: {}", file, e);
+                BTreeMap::new()
+            });
+            let templates = messages.into_iter().map(|(code, text)| (code, Template::parse(&text)));
+            match language {
+                FALLBACK => english.extend(templates),
+                _ => {
+                    translations.insert(language.to_string(), templates.collect());
+                }
+            }
+        }
+        english.sort_by_key(|(_, template)| std::cmp::Reverse(template.fixed()));
+        Catalog { english, translations }
+    })
+}
+
+impl Catalog {
+    // The language Accept-Language prefers of those there are messages
+    // in, unless that's English or nothing is asked for.
+    fn language(&self, headers: &HeaderMap) -> Option<&str> {
+        let mut ranges: Vec<(&str, f32)> = headers
+            .get_all(header::ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let tag = parts.next().filter(|tag| !tag.is_empty())?;
+                let quality = parts.find_map(|param| param.strip_prefix("q=")).map_or(Some(1.0), |q| q.parse().ok())?;
+                (quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        // Stable, so equally wanted languages keep the order they were sent in.
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.into_iter().find_map(|(tag, _)| {
+            let primary = tag.split('-').next().unwrap_or(tag).to_ascii_lowercase();
+            match primary.as_str() {
+                FALLBACK | "*" => Some(None),
+                _ => self.translations.get_key_value(primary.as_str()).map(|(language, _)| Some(language.as_str())),
+            }
+        })?
+    }
+
+    // `message` in `language`, or as it is when it has no translation.
+    fn translate(&self, language: &str, message: &str) -> String {
+        let translated = self
+            .english
+            .iter()
+            .find_map(|(code, template)| template.capture(message).map(|values| (code, values)))
+            .and_then(|(code, values)| Some(self.translations.get(language)?.get(code)?.render(&values)));
+        translated.unwrap_or_else(|| message.to_string())
+    }
+
+    fn problem(&self, language: &str, problem: &mut Value) {
+        let Some(fields) = problem.as_object_mut() else {
+            return;
+        };
+        let translate = |value: &Value| value.as_str().map(|text| Value::from(self.translate(language, text)));
+        if let Some(title) = fields.get("title").and_then(translate) {
+            fields.insert("title".to_string(), title);
+        }
+        let errors = fields.get_mut("errors").and_then(Value::as_array_mut).filter(|errors| !errors.is_empty());
+        let Some(errors) = errors else {
+            if let Some(detail) = fields.get("detail").and_then(translate) {
+                fields.insert("detail".to_string(), detail);
+            }
+            return;
+        };
+        for error in errors.iter_mut() {
+            if let Some(message) = error.get("message").and_then(translate) {
+                error["message"] = message;
+            }
+        }
+        // The detail sums up the errors, so it's made again from theirs.
+        let detail = match errors.as_slice() {
+            [only] => format!("{}: {}", only["field"].as_str().unwrap_or_default(), only["message"].as_str().unwrap_or_default()),
+            _ => self.translate(language, &problem::summary_of(errors.len())),
+        };
+        fields.insert("detail".to_string(), Value::from(detail));
+    }
+}
+
+// Problems are answered in the language Accept-Language prefers of those
+// in locales/, with Content-Language naming it. Messages there's no
+// translation for stay in English, as does everything else.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let catalog = catalog();
+    let language = catalog.language(req.headers()).map(str::to_string);
+    let response = next.run(req, state).await?;
+    let is_problem = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| media(value) == problem::MEDIA_TYPE);
+    if !is_problem {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    parts.headers.append(header::VARY, HeaderValue::from_static("Accept-Language"));
+    let Some(language) = language else {
+        return Ok(Response::from_parts(parts, body));
+    };
+    let bytes = body::to_bytes(body).await?;
+    let Ok(mut value) = serde_json::from_slice::<Value>(&bytes) else {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    };
+    catalog.problem(&language, &mut value);
+    let encoded = serde_json::to_vec(&value).unwrap_or_default();
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(encoded.len()));
+    if let Ok(language) = HeaderValue::from_str(&language) {
+        parts.headers.insert(header::CONTENT_LANGUAGE, language);
+    }
+    Ok(Response::from_parts(parts, Body::from(encoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn every_translation_has_an_english_message_with_the_same_placeholders() {
+        let catalog = catalog();
+        assert!(catalog.translations.len() >= 3);
+        fn slots(template: &Template) -> Vec<&str> {
+            let mut slots: Vec<&str> = template.0.iter().filter_map(|piece| match piece {
+                Piece::Slot(name) => Some(name.as_str()),
+                Piece::Text(_) => None,
+            }).collect();
+            slots.sort();
+            slots
+        }
+        for (language, messages) in &catalog.translations {
+            for (code, template) in messages {
+                let english = catalog.english.iter().find(|(english, _)| english == code);
+                let english = english.unwrap_or_else(|| panic!("{} has {}, which en.json doesn't", language, code));
+                assert_eq!(slots(template), slots(&english.1), "{} in {}", code, language);
+            }
+        }
+    }
+
+    #[test]
+    fn problems_are_translated() {
+        let catalog = catalog();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static("de-CH;q=0.5, fr-CA, en;q=0.8"));
+        assert_eq!(catalog.language(&headers), Some("fr"));
+        headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static("pt, en;q=0.9, fr;q=0.1"));
+        assert_eq!(catalog.language(&headers), None);
+
+        let mut invalid = json!({
+            "title": "The request has invalid fields",
+            "detail": "2 fields are invalid",
+            "errors": [
+                { "field": "title", "message": "must be at most 300 characters" },
+                { "field": "isbn", "message": "ISBN \"12\" is neither 10 nor 13 digits long" },
+            ],
+        });
+        catalog.problem("fr", &mut invalid);
+        assert_eq!(invalid["title"], "La requête contient des champs invalides");
+        assert_eq!(invalid["detail"], "2 champs sont invalides");
+        assert_eq!(invalid["errors"][0]["message"], "doit faire au plus 300 caractères");
+        assert_eq!(invalid["errors"][1]["message"], "l'ISBN \"12\" ne fait ni 10 ni 13 chiffres");
+
+        let mut bad = json!({ "title": "Bad Request", "detail": "Invalid book ID" });
+        catalog.problem("es", &mut bad);
+        assert_eq!(bad, json!({ "title": "Solicitud incorrecta", "detail": "ID de libro no válido" }));
+        assert_eq!(catalog.translate("de", "Invalid author ID"), "Ungültiger Parameter: author ID");
+        assert_eq!(catalog.translate("de", "something new"), "something new");
+    }
+}
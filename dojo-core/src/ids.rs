@@ -0,0 +1,58 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Crockford's base32, which ULIDs are written in.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+// How new books get the uid they can be reached by. Ids stay sequential
+// either way, as every store and the cluster key books by them; the uid is
+// what links point at, so a client following them can't guess its way to
+// other books, and what survives a catalog being merged into another.
+// DOJO_ID_STRATEGY picks one: `sequential` (the default, no uid), `uuid`
+// for random UUIDv4s or `ulid` for ULIDs, which sort by creation time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Strategy {
+    #[default]
+    Sequential,
+    Uuid,
+    Ulid,
+}
+
+impl Strategy {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("DOJO_ID_STRATEGY").as_deref() {
+            Err(_) | Ok("sequential") => Ok(Strategy::Sequential),
+            Ok("uuid") => Ok(Strategy::Uuid),
+            Ok("ulid") => Ok(Strategy::Ulid),
+            Ok(other) => Err(format!("DOJO_ID_STRATEGY must be sequential, uuid or ulid, got {:?}", other)),
+        }
+    }
+
+    pub fn mint(self) -> Option<String> {
+        match self {
+            Strategy::Sequential => None,
+            Strategy::Uuid => Some(Uuid::new_v4().hyphenated().to_string()),
+            Strategy::Ulid => Some(ulid()),
+        }
+    }
+}
+
+// 48 bits of milliseconds since the epoch, then 80 random ones.
+fn ulid() -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let random = Uuid::new_v4().as_u128() & ((1 << 80) - 1);
+    let value = (u128::from(millis & ((1 << 48) - 1)) << 80) | random;
+    (0..26).rev().map(|digit| CROCKFORD[((value >> (digit * 5)) & 31) as usize] as char).collect()
+}
+
+// A uid as it's stored: UUIDs lowercase with hyphens, ULIDs uppercase.
+// None for anything that's neither, which includes numeric ids.
+pub fn parse(value: &str) -> Option<String> {
+    if let Ok(uuid) = Uuid::try_parse(value) {
+        return Some(uuid.hyphenated().to_string());
+    }
+    let ulid = value.to_ascii_uppercase();
+    // The first digit only has three bits to give.
+    let fits = ulid.len() == 26 && ulid.as_bytes()[0] <= b'7';
+    (fits && ulid.bytes().all(|c| CROCKFORD.contains(&c))).then_some(ulid)
+}
@@ -0,0 +1,439 @@
+use books_model::{CreateBookRequest, Edition};
+use hyper::{body::Bytes, header, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap};
+
+use crate::{
+    bad_request,
+    body::{self, Body},
+    error::ApiError,
+    extract::{FromRequest, RequestContext},
+    json_response, problem,
+    store::{BookStore, Store},
+    validate, MAX_BATCH, StorageError,
+};
+
+// An upload has at most this many rows, so one request can't tie up the
+// store.
+const MAX_ROWS: usize = 10_000;
+
+pub enum Kind {
+    Csv,
+    Json,
+}
+
+impl Kind {
+    pub fn of_extension(name: &str) -> Option<Self> {
+        match name.rsplit_once('.') {
+            Some((_, extension)) if extension.eq_ignore_ascii_case("csv") => Some(Kind::Csv),
+            Some((_, extension)) if extension.eq_ignore_ascii_case("json") => Some(Kind::Json),
+            _ => None,
+        }
+    }
+
+    fn of_media(media: &str) -> Option<Self> {
+        match media {
+            "text/csv" | "application/csv" => Some(Kind::Csv),
+            "application/json" => Some(Kind::Json),
+            _ => None,
+        }
+    }
+}
+
+// The file to import: a CSV or JSON body, or the file part of a
+// multipart/form-data upload as a browser form sends it.
+pub struct Upload {
+    kind: Kind,
+    bytes: Bytes,
+}
+
+impl FromRequest for Upload {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let content_type = ctx
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let media = media(&content_type);
+        let bytes = match body::to_bytes(ctx.take_body()).await {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(bad_request("Failed to read request body")),
+        };
+        if let Some(kind) = Kind::of_media(&media) {
+            return Ok(Upload { kind, bytes });
+        }
+        if media != "multipart/form-data" {
+            return Err(problem::respond(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "send the file as text/csv, application/json or multipart/form-data",
+            ));
+        }
+        let Some(boundary) = parameter(&content_type, "boundary") else {
+            return Err(bad_request("multipart/form-data needs a boundary"));
+        };
+        let Some(part) = file_part(&bytes, &boundary) else {
+            return Err(bad_request("the form has no file in it"));
+        };
+        let kind = Kind::of_media(&part.media).or(Kind::of_extension(&part.filename));
+        match kind {
+            Some(kind) => Ok(Upload {
+                kind,
+                bytes: bytes.slice(part.content),
+            }),
+            None => Err(bad_request("the file has to be CSV or JSON, by its type or its extension")),
+        }
+    }
+}
+
+pub fn media(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+pub fn parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+pub struct Part {
+    pub media: String,
+    pub filename: String,
+    pub content: std::ops::Range<usize>,
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?.windows(needle.len()).position(|window| window == needle).map(|at| at + from)
+}
+
+// The first part of a multipart body that carries a filename.
+pub fn file_part(body: &[u8], boundary: &str) -> Option<Part> {
+    let delimiter = format!("--{}", boundary);
+    let mut at = find(body, delimiter.as_bytes(), 0)? + delimiter.len();
+    loop {
+        // The closing delimiter is followed by "--".
+        if body.get(at..at + 2) == Some(b"--") {
+            return None;
+        }
+        let headers_start = find(body, b"\r\n", at)? + 2;
+        let headers_end = find(body, b"\r\n\r\n", headers_start)?;
+        let next = find(body, format!("\r\n{}", delimiter).as_bytes(), headers_end)?;
+        let headers = String::from_utf8_lossy(&body[headers_start..headers_end]);
+        let mut media = String::new();
+        let mut filename = None;
+        for line in headers.split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-type" => media = self::media(value),
+                "content-disposition" => filename = parameter(value, "filename"),
+                _ => {}
+            }
+        }
+        if let Some(filename) = filename {
+            return Some(Part {
+                media,
+                filename,
+                content: headers_end + 4..next,
+            });
+        }
+        at = next + 2 + delimiter.len();
+    }
+}
+
+// One row of the file. An id column is accepted, so an export can be
+// imported again, but it's ignored: every book imported gets a new id.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Row {
+    title: String,
+    author: String,
+    #[serde(default)]
+    isbn: Option<String>,
+}
+
+impl Row {
+    // A blank ISBN cell means the book has none.
+    fn validate(self) -> Result<CreateBookRequest, String> {
+        let mut book = CreateBookRequest {
+            title: self.title,
+            author: self.author,
+            isbn: self.isbn.filter(|isbn| !isbn.trim().is_empty()),
+            author_id: None,
+            tags: Vec::new(),
+            edition: Edition::default(),
+            uid: None,
+        };
+        let errors = validate::errors(&mut book);
+        if !errors.is_empty() {
+            let errors: Vec<_> = errors.iter().map(|error| format!("{} {}", error.field, error.message)).collect();
+            return Err(errors.join("; "));
+        }
+        Ok(book)
+    }
+}
+
+// The line each row starts on, with the row or why it can't be read.
+type Rows = Vec<(usize, Result<Row, String>)>;
+
+fn csv_rows(text: &str) -> Result<Rows, String> {
+    let mut records = records(text.strip_prefix('\u{feff}').unwrap_or(text))?.into_iter();
+    let Some((_, header)) = records.next() else {
+        return Err("the file is empty".to_string());
+    };
+    let columns: Vec<String> = header.iter().map(|name| name.trim().to_ascii_lowercase()).collect();
+    for column in &columns {
+        if !["id", "title", "author", "isbn"].contains(&column.as_str()) {
+            return Err(format!("unknown column {:?}, expected id, title, author and isbn", column));
+        }
+    }
+    for required in ["title", "author"] {
+        if !columns.iter().any(|column| column == required) {
+            return Err(format!("the header row has no {} column", required));
+        }
+    }
+    Ok(records
+        .map(|(line, fields)| {
+            if fields.len() != columns.len() {
+                return (line, Err(format!("expected {} fields, found {}", columns.len(), fields.len())));
+            }
+            let mut row = Row {
+                title: String::new(),
+                author: String::new(),
+                isbn: None,
+            };
+            for (column, value) in columns.iter().zip(fields) {
+                let value = unescape(value);
+                match column.as_str() {
+                    "title" => row.title = value,
+                    "author" => row.author = value,
+                    "isbn" => row.isbn = Some(value),
+                    _ => {}
+                }
+            }
+            (line, Ok(row))
+        })
+        .collect())
+}
+
+// Undoes the ' the export puts before what a spreadsheet would run as a
+// formula.
+fn unescape(value: String) -> String {
+    match value.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(['=', '+', '-', '@', '\t', '\r']) => rest.to_string(),
+        _ => value,
+    }
+}
+
+// RFC 4180 records with the line each starts on. Quoted fields can hold
+// commas, quotes doubled and line breaks; blank lines are skipped.
+fn records(text: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut start = 1;
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => {
+                quoted = false;
+                if !matches!(chars.peek(), None | Some(',' | '\r' | '\n')) {
+                    return Err(format!("line {}: a closing quote has to end the field", line));
+                }
+            }
+            (true, c) => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push((start, std::mem::take(&mut record)));
+                }
+                record.clear();
+                line += 1;
+                start = line;
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("line {}: a quoted field isn't closed", start));
+    }
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push((start, record));
+    }
+    Ok(records)
+}
+
+// A JSON array of books. Each element is read on its own, so one that isn't
+// a book fails only its own row.
+fn json_rows(bytes: &[u8]) -> Result<Rows, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "the file isn't valid UTF-8".to_string())?;
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let line_at = |offset: usize| text[..offset].matches('\n').count() + 1;
+    let mut at = skip_whitespace(text, 0);
+    if !text[at..].starts_with('[') {
+        return Err("the file has to hold a JSON array of books".to_string());
+    }
+    at = skip_whitespace(text, at + 1);
+    let mut rows = Vec::new();
+    if text[at..].starts_with(']') {
+        at += 1;
+    } else {
+        loop {
+            let mut stream = serde_json::Deserializer::from_str(&text[at..]).into_iter::<serde_json::Value>();
+            let mut value = match stream.next() {
+                Some(Ok(value)) => value,
+                Some(Err(e)) => return Err(format!("line {}: {}", line_at(at) + e.line() - 1, e)),
+                None => return Err(format!("line {}: the array isn't closed", line_at(at))),
+            };
+            if let Some(book) = value.as_object_mut() {
+                book.remove("id");
+            }
+            rows.push((line_at(at), serde_json::from_value(value).map_err(|e| e.to_string())));
+            at = skip_whitespace(text, at + stream.byte_offset());
+            match text[at..].chars().next() {
+                Some(',') => at = skip_whitespace(text, at + 1),
+                Some(']') => {
+                    at += 1;
+                    break;
+                }
+                _ => return Err(format!("line {}: expected , or ] after a book", line_at(at))),
+            }
+        }
+    }
+    match skip_whitespace(text, at) == text.len() {
+        true => Ok(rows),
+        false => Err(format!("line {}: trailing characters after the array", line_at(at))),
+    }
+}
+
+fn skip_whitespace(text: &str, at: usize) -> usize {
+    text[at..].find(|c: char| !c.is_whitespace()).map_or(text.len(), |skipped| at + skipped)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub imported: usize,
+    pub failed: usize,
+    pub rows: Vec<Outcome>,
+}
+
+// POST /books/import validates every row of the file and inserts the ones
+// that pass, answering with what became of each row by its line number. A
+// file that can't be read as a whole is a 400 and nothing is imported.
+pub async fn import_books<S: BookStore>(Store(store): Store<S>, upload: Upload) -> Result<Response<Body>, ApiError> {
+    let report = import(&*store, upload.kind, &upload.bytes, MAX_ROWS).await.map_err(ApiError::BadRequest)?;
+    Ok(json_response(StatusCode::OK, &report)?)
+}
+
+// What the endpoint and `book-api import` share. Err when the file can't
+// be read as a whole or has more than `max_rows` rows.
+pub async fn import<S: BookStore>(store: &S, kind: Kind, bytes: &[u8], max_rows: usize) -> Result<Report, String> {
+    let rows = match kind {
+        Kind::Csv => match std::str::from_utf8(bytes) {
+            Ok(text) => csv_rows(text),
+            Err(_) => Err("the file isn't valid UTF-8".to_string()),
+        },
+        Kind::Json => json_rows(bytes),
+    }?;
+    if rows.len() > max_rows {
+        return Err(format!("a file holds at most {} rows", max_rows));
+    }
+    let mut outcomes = Vec::with_capacity(rows.len());
+    let mut valid = Vec::new();
+    // The line each ISBN was first seen on. Only the first row with one is
+    // imported.
+    let mut isbns = HashMap::new();
+    for (line, row) in rows {
+        let row = row.and_then(Row::validate).and_then(|book| match book.isbn.clone().map(|isbn| isbns.entry(isbn)) {
+            Some(Entry::Occupied(first)) => Err(format!("line {} has the same ISBN", first.get())),
+            Some(Entry::Vacant(entry)) => {
+                entry.insert(line);
+                Ok(book)
+            }
+            None => Ok(book),
+        });
+        match row {
+            Ok(book) => {
+                valid.push((outcomes.len(), book));
+                outcomes.push(Outcome { line, id: None, error: None });
+            }
+            Err(error) => outcomes.push(Outcome { line, id: None, error: Some(error) }),
+        }
+    }
+    // Batches are all or nothing, so a storage failure fails the rows of
+    // its batch and the ones after, while earlier batches stay imported.
+    let mut failed = false;
+    for batch in valid.chunks(MAX_BATCH) {
+        if !failed {
+            match store.insert_many(batch.iter().map(|(_, book)| book.clone()).collect()).await {
+                Ok(books) => {
+                    for ((index, _), book) in batch.iter().zip(books) {
+                        outcomes[*index].id = Some(book.id);
+                    }
+                    continue;
+                }
+                // Row by row, only the ones whose ISBN the catalog already
+                // has fail.
+                Err(StorageError::DuplicateIsbn { .. }) => {
+                    for (index, book) in batch {
+                        outcomes[*index].error = match failed {
+                            true => Some("storage error, not imported".to_string()),
+                            false => match store.insert(book.clone()).await {
+                                Ok(book) => {
+                                    outcomes[*index].id = Some(book.id);
+                                    None
+                                }
+                                Err(e @ StorageError::DuplicateIsbn { .. }) => Some(e.to_string()),
+                                Err(e) => {
+                                    tracing::warn!("import: storing a row failed: {}", e);
+                                    failed = true;
+                                    Some("storage error, not imported".to_string())
+                                }
+                            },
+                        };
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("import: storing a batch failed: {}", e);
+                    failed = true;
+                }
+            }
+        }
+        for (index, _) in batch {
+            outcomes[*index].error = Some("storage error, not imported".to_string());
+        }
+    }
+    let imported = outcomes.iter().filter(|outcome| outcome.id.is_some()).count();
+    Ok(Report {
+        imported,
+        failed: outcomes.len() - imported,
+        rows: outcomes,
+    })
+}
@@ -0,0 +1,201 @@
+use hyper::{Request, Response, StatusCode};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    net::IpAddr,
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{
+    auth,
+    body::{self, Body},
+    extract::State,
+    instrument::RequestMetrics,
+    json_response,
+    proxy::ClientIp,
+    request_id::RequestId,
+    stack::Next,
+    SharedState,
+};
+
+struct InFlight {
+    request_id: String,
+    method: String,
+    path: String,
+    client: Option<IpAddr>,
+    principal: Option<String>,
+    started: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InFlightView {
+    pub id: u64,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub client: Option<IpAddr>,
+    pub principal: Option<String>,
+    pub elapsed_ms: f64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CompletedView {
+    pub id: u64,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub client: Option<IpAddr>,
+    pub principal: Option<String>,
+    pub status: Option<u16>,
+    pub duration_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RequestsView {
+    pub in_flight: Vec<InFlightView>,
+    pub completed: Vec<CompletedView>,
+}
+
+struct TrackerInner {
+    next_id: u64,
+    in_flight: HashMap<u64, InFlight>,
+    completed: VecDeque<CompletedView>,
+}
+
+pub struct RequestTracker {
+    history: usize,
+    inner: Mutex<TrackerInner>,
+    metrics: RequestMetrics,
+}
+
+impl RequestTracker {
+    pub fn new(history: usize) -> Self {
+        RequestTracker {
+            history,
+            inner: Mutex::new(TrackerInner {
+                next_id: 1,
+                in_flight: HashMap::new(),
+                completed: VecDeque::new(),
+            }),
+            metrics: RequestMetrics::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> &RequestMetrics {
+        &self.metrics
+    }
+
+    fn begin(&self, req: &Request<Body>, principal: Option<String>) -> RequestGuard<'_> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.in_flight.insert(
+            id,
+            InFlight {
+                request_id: req.extensions().get::<RequestId>().map(|id| id.as_str().to_string()).unwrap_or_default(),
+                method: req.method().to_string(),
+                path: req.uri().path().to_string(),
+                client: req.extensions().get::<ClientIp>().map(|ip| ip.0),
+                principal,
+                started: Instant::now(),
+            },
+        );
+
+        RequestGuard {
+            tracker: self,
+            id,
+            status: None,
+        }
+    }
+
+    fn finish(&self, id: u64, status: Option<StatusCode>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(request) = inner.in_flight.remove(&id) {
+            let elapsed = request.started.elapsed();
+            self.metrics.record(&request.method, &request.path, status.map(|s| s.as_u16()), elapsed);
+            inner.completed.push_back(CompletedView {
+                id,
+                request_id: request.request_id,
+                method: request.method,
+                path: request.path,
+                client: request.client,
+                principal: request.principal,
+                status: status.map(|s| s.as_u16()),
+                duration_ms: elapsed.as_secs_f64() * 1000.0,
+            });
+            while inner.completed.len() > self.history {
+                inner.completed.pop_front();
+            }
+        }
+    }
+
+    pub fn approx_bytes(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let strings = |request_id: &String, method: &String, path: &String, principal: &Option<String>| {
+            request_id.capacity() + method.capacity() + path.capacity() + principal.as_ref().map_or(0, String::capacity)
+        };
+        inner.completed.capacity() * mem::size_of::<CompletedView>()
+            + inner.in_flight.capacity() * mem::size_of::<(u64, InFlight)>()
+            + inner
+                .completed
+                .iter()
+                .map(|r| strings(&r.request_id, &r.method, &r.path, &r.principal))
+                .sum::<usize>()
+            + inner
+                .in_flight
+                .values()
+                .map(|r| strings(&r.request_id, &r.method, &r.path, &r.principal))
+                .sum::<usize>()
+    }
+
+    fn snapshot(&self) -> RequestsView {
+        let inner = self.inner.lock().unwrap();
+        let mut in_flight: Vec<InFlightView> = inner
+            .in_flight
+            .iter()
+            .map(|(id, request)| InFlightView {
+                id: *id,
+                request_id: request.request_id.clone(),
+                method: request.method.clone(),
+                path: request.path.clone(),
+                client: request.client,
+                principal: request.principal.clone(),
+                elapsed_ms: request.started.elapsed().as_secs_f64() * 1000.0,
+            })
+            .collect();
+        in_flight.sort_by_key(|request| request.id);
+
+        RequestsView {
+            in_flight,
+            completed: inner.completed.iter().rev().cloned().collect(),
+        }
+    }
+}
+
+// Finishes the request when dropped, so futures cancelled by a client
+// disconnect still leave the in-flight list (with no status).
+struct RequestGuard<'a> {
+    tracker: &'a RequestTracker,
+    id: u64,
+    status: Option<StatusCode>,
+}
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        self.tracker.finish(self.id, self.status);
+    }
+}
+
+pub async fn track(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let principal = auth::principal(req.headers(), &state);
+    let mut guard = state.requests.begin(&req, principal);
+    let response = next.run(req, state.clone()).await?;
+    guard.status = Some(response.status());
+    Ok(response)
+}
+
+pub async fn debug_requests(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    json_response(StatusCode::OK, &state.requests.snapshot())
+}
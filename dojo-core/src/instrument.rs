@@ -0,0 +1,111 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::Duration,
+};
+
+#[derive(Debug, Default, Serialize, Clone)]
+pub struct OpStats {
+    pub count: u64,
+    pub slow: u64,
+    pub total_ms: f64,
+    pub max_ms: f64,
+}
+
+pub struct StorageMetrics {
+    slow_threshold: Duration,
+    ops: Mutex<BTreeMap<&'static str, OpStats>>,
+}
+
+impl StorageMetrics {
+    pub fn new(slow_threshold: Duration) -> Self {
+        StorageMetrics {
+            slow_threshold,
+            ops: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record(&self, op: &'static str, params: impl FnOnce() -> String, elapsed: Duration) {
+        let millis = elapsed.as_secs_f64() * 1000.0;
+        let slow = elapsed >= self.slow_threshold;
+
+        {
+            let mut ops = self.ops.lock().unwrap();
+            let stats = ops.entry(op).or_default();
+            stats.count += 1;
+            stats.total_ms += millis;
+            stats.max_ms = stats.max_ms.max(millis);
+            if slow {
+                stats.slow += 1;
+            }
+        }
+
+        if slow {
+            tracing::warn!(op, params = %params(), elapsed_ms = millis, "slow storage operation");
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, OpStats> {
+        self.ops.lock().unwrap().clone()
+    }
+}
+
+// Upper bounds, in seconds, of the request latency histogram's buckets.
+pub const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default, Clone)]
+pub struct Latency {
+    // Requests at or under each bound in LATENCY_BUCKETS, not cumulative.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_seconds: f64,
+}
+
+// Requests counted by route, method and status, with latency by route and
+// method. Routes are the documented paths, so an unknown path can't add a
+// series of its own.
+#[derive(Default)]
+pub struct RequestMetrics {
+    inner: Mutex<RequestCounts>,
+}
+
+#[derive(Default, Clone)]
+pub struct RequestCounts {
+    pub requests: BTreeMap<(&'static str, &'static str, String), u64>,
+    pub latency: BTreeMap<(&'static str, &'static str), Latency>,
+}
+
+impl RequestMetrics {
+    // `status` is None for a request the client gave up on.
+    pub fn record(&self, method: &str, path: &str, status: Option<u16>, elapsed: Duration) {
+        let route = crate::contract::route(path).unwrap_or("unmatched");
+        let method = match method {
+            "GET" => "GET",
+            "HEAD" => "HEAD",
+            "POST" => "POST",
+            "PUT" => "PUT",
+            "PATCH" => "PATCH",
+            "DELETE" => "DELETE",
+            "OPTIONS" => "OPTIONS",
+            _ => "OTHER",
+        };
+        let status = status.map_or("cancelled".to_string(), |status| status.to_string());
+        let seconds = elapsed.as_secs_f64();
+        let mut inner = self.inner.lock().unwrap();
+        *inner.requests.entry((route, method, status)).or_default() += 1;
+        let latency = inner.latency.entry((route, method)).or_default();
+        if latency.buckets.is_empty() {
+            latency.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            latency.buckets[bucket] += 1;
+        }
+        latency.count += 1;
+        latency.sum_seconds += seconds;
+    }
+
+    pub fn snapshot(&self) -> RequestCounts {
+        self.inner.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,109 @@
+// ISBNs are compared and stored without the hyphens and spaces they're
+// usually printed with, and with an ISBN-10's check digit X upper-cased.
+pub fn strip(isbn: &str) -> String {
+    isbn.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_uppercase()
+}
+
+// The stripped ISBN if it's a valid ISBN-10 or ISBN-13, or why not.
+pub fn normalize(isbn: &str) -> Result<String, String> {
+    let kept: Vec<char> = isbn.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+    let digits: Vec<u32> = kept
+        .iter()
+        .enumerate()
+        .map(|(index, c)| match c {
+            'X' | 'x' if index == 9 && kept.len() == 10 => Ok(10),
+            _ => c.to_digit(10).ok_or_else(|| format!("ISBN {:?} contains {:?}; only digits, hyphens and a final X are allowed", isbn, c)),
+        })
+        .collect::<Result<_, _>>()?;
+    let (expected, given) = match digits.len() {
+        // Weights 10 down to 1, the sum a multiple of 11.
+        10 => {
+            let sum: u32 = digits[..9].iter().zip((2..=10).rev()).map(|(digit, weight)| digit * weight).sum();
+            ((11 - sum % 11) % 11, digits[9])
+        }
+        // Weights alternating 1 and 3, the sum a multiple of 10.
+        13 => {
+            let sum: u32 = digits[..12].iter().zip([1, 3].into_iter().cycle()).map(|(digit, weight)| digit * weight).sum();
+            ((10 - sum % 10) % 10, digits[12])
+        }
+        _ => return Err(format!("ISBN {:?} is neither 10 nor 13 digits long", isbn)),
+    };
+    if expected != given {
+        let show = |digit: u32| if digit == 10 { 'X' } else { char::from_digit(digit, 10).unwrap_or('?') };
+        return Err(format!("ISBN {:?} has check digit {}, but its other digits call for {}", isbn, show(given), show(expected)));
+    }
+    Ok(strip(isbn))
+}
+
+// What ISBNs are kept unique by: an ISBN-10 and the 978 ISBN-13 it was
+// reissued as are one book, so both come out as the ISBN-13. Takes an
+// ISBN as `normalize` leaves it.
+pub fn key(isbn: &str) -> String {
+    if isbn.len() != 10 || !isbn.bytes().take(9).all(|b| b.is_ascii_digit()) {
+        return isbn.to_string();
+    }
+    let body = format!("978{}", &isbn[..9]);
+    let sum: u32 = body.bytes().map(|b| u32::from(b - b'0')).zip([1, 3].into_iter().cycle()).map(|(digit, weight)| digit * weight).sum();
+    format!("{}{}", body, (10 - sum % 10) % 10)
+}
+
+// Normalizes a book's ISBN, when it has one.
+pub fn validate(isbn: &mut Option<String>) -> Result<(), String> {
+    if let Some(value) = isbn {
+        *value = normalize(value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_decide_validity() {
+        for valid in ["0306406152", "9780306406157", "9791090636071"] {
+            assert_eq!(normalize(valid).as_deref(), Ok(valid));
+        }
+        let wrong = normalize("0306406153").unwrap_err();
+        assert!(wrong.contains("has check digit 3, but its other digits call for 2"), "{}", wrong);
+        let wrong = normalize("9780306406158").unwrap_err();
+        assert!(wrong.contains("has check digit 8, but its other digits call for 7"), "{}", wrong);
+        assert!(normalize("03064O6152").unwrap_err().contains("contains 'O'"));
+        assert!(normalize("030640615").unwrap_err().contains("neither 10 nor 13 digits"));
+    }
+
+    // Only an ISBN-10 ends in X, standing for 10.
+    #[test]
+    fn x_is_the_isbn_10_check_digit_for_ten() {
+        assert_eq!(normalize("080442957X").as_deref(), Ok("080442957X"));
+        assert_eq!(normalize("080442957x").as_deref(), Ok("080442957X"));
+        assert!(normalize("0804429571").unwrap_err().contains("call for X"));
+        assert!(normalize("X804429570").unwrap_err().contains("contains 'X'"));
+        assert!(normalize("978030640615X").unwrap_err().contains("contains 'X'"));
+    }
+
+    #[test]
+    fn hyphens_and_spaces_are_dropped() {
+        assert_eq!(normalize("978-0-306-40615-7").as_deref(), Ok("9780306406157"));
+        assert_eq!(normalize(" 0 306 40615 2 ").as_deref(), Ok("0306406152"));
+        assert_eq!(strip("0-8044-2957-x"), "080442957X");
+        let mut isbn = Some("0-306-40615-2".to_string());
+        assert_eq!(validate(&mut isbn), Ok(()));
+        assert_eq!(isbn.as_deref(), Some("0306406152"));
+    }
+
+    // An ISBN-10 keys as the 978 ISBN-13 it became; an ISBN-13 as itself.
+    #[test]
+    fn isbn_10s_key_as_their_978_isbn_13() {
+        assert_eq!(key("0306406152"), "9780306406157");
+        assert_eq!(key("080442957X"), "9780804429573");
+        for isbn13 in ["9780306406157", "9791090636071"] {
+            assert_eq!(key(isbn13), isbn13);
+        }
+        assert_eq!(normalize(&key("0306406152")).as_deref(), Ok("9780306406157"));
+        assert_eq!(key(&key("0306406152")), "9780306406157");
+    }
+}
@@ -0,0 +1,315 @@
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap, Method, Request, Response, Uri,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    bad_request,
+    body::{self, Body},
+    import::media,
+    listing::TOTAL_COUNT,
+    problem,
+    stack::Next,
+    SharedState,
+};
+
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+// The resource types the catalog's paths name. Anything else is typed by
+// the path's first segment.
+const TYPES: &[&str] = &["books", "authors", "reviews", "acquisition-requests", "interlibrary-loans"];
+
+fn asked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| media(range) == MEDIA_TYPE)
+}
+
+fn sent(headers: &HeaderMap) -> bool {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    content_type.is_some_and(|value| media(value) == MEDIA_TYPE)
+}
+
+// The type of what a catalog path returns: /authors/7/books lists books.
+fn kind(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    let known = segments.iter().rev().find(|segment| TYPES.contains(segment));
+    known.or(segments.first()).map_or_else(|| "books".to_string(), |segment| segment.to_string())
+}
+
+// Lets JSON:API clients use the catalog: with DOJO_JSONAPI set, or for a
+// request that accepts or sends application/vnd.api+json, request
+// documents are unwrapped into the JSON handlers take, and JSON responses
+// wrapped into documents, problems into error objects. Like negotiate it
+// sits outside the contract layer, which checks the plain JSON.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let api = crate::api();
+    let path = req.uri().path();
+    let sent = sent(req.headers());
+    if !api.in_catalog(path) || !(state.jsonapi || sent || asked(req.headers())) {
+        return next.run(req, state).await;
+    }
+    let uri = req.uri().clone();
+    let kind = kind(api.unversioned(path));
+    let (mut parts, body) = req.into_parts();
+    let body = match sent {
+        true => {
+            let bytes = body::to_bytes(body).await?;
+            let unwrapped = serde_json::from_slice(&bytes).map_err(|e| e.to_string()).and_then(|doc| unwrap(&doc));
+            let json = match unwrapped {
+                Ok(json) => serde_json::to_vec(&json).unwrap_or_default(),
+                Err(e) => return document(bad_request(&format!("body isn't a JSON:API document: {}", e)), &uri, &kind).await,
+            };
+            parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(json.len()));
+            Body::from(json)
+        }
+        false => body,
+    };
+    // A HEAD response has no body to wrap.
+    let head = parts.method == Method::HEAD;
+    let response = next.run(Request::from_parts(parts, body), state).await?;
+    match head {
+        true => Ok(response),
+        false => document(response, &uri, &kind).await,
+    }
+}
+
+// The attributes of a request document's resource, or of each of them, with
+// each to-one relationship given as `<name>_id`.
+fn unwrap(doc: &Value) -> Result<Value, String> {
+    match &doc["data"] {
+        Value::Array(resources) => resources.iter().map(flatten).collect::<Result<_, _>>().map(Value::Array),
+        resource @ Value::Object(_) => flatten(resource),
+        _ => Err("it has no data".to_string()),
+    }
+}
+
+fn flatten(resource: &Value) -> Result<Value, String> {
+    let mut fields = match &resource["attributes"] {
+        Value::Object(attributes) => attributes.clone(),
+        Value::Null => Map::new(),
+        _ => return Err("attributes have to be an object".to_string()),
+    };
+    if let Some(relationships) = resource["relationships"].as_object() {
+        for (name, relationship) in relationships {
+            let id = match &relationship["data"] {
+                Value::Null => Value::Null,
+                linkage => {
+                    let id = linkage["id"].as_str().and_then(|id| id.parse::<u64>().ok());
+                    Value::from(id.ok_or_else(|| format!("relationship {} needs a numeric id", name))?)
+                }
+            };
+            fields.insert(format!("{}_id", name), id);
+        }
+    }
+    Ok(Value::Object(fields))
+}
+
+async fn document(response: Response<Body>, uri: &Uri, kind: &str) -> Result<Response<Body>, body::Error> {
+    let content_type = response.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(media);
+    let is_problem = match content_type.as_deref() {
+        Some("application/json") => false,
+        Some(problem::MEDIA_TYPE) => true,
+        _ => return Ok(response),
+    };
+    let (mut parts, body) = response.into_parts();
+    let bytes = body::to_bytes(body).await?;
+    // A body that doesn't parse as JSON is passed on as it is.
+    let Ok(value) = serde_json::from_slice::<Value>(&bytes) else {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    };
+    let doc = match is_problem {
+        true => json!({ "errors": errors(&value) }),
+        false => data(value, kind, uri, &parts.headers),
+    };
+    let encoded = serde_json::to_vec(&doc).unwrap_or_default();
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(MEDIA_TYPE));
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(encoded.len()));
+    let varies = parts
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("accept"));
+    if !varies {
+        parts.headers.append(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    Ok(Response::from_parts(parts, Body::from(encoded)))
+}
+
+// A top-level document for a response body: a list or a v2 collection as
+// resources with the page's links, an object with an id as one resource,
+// and anything else as meta.
+fn data(value: Value, kind: &str, uri: &Uri, headers: &HeaderMap) -> Value {
+    let mut doc = Map::new();
+    match value {
+        Value::Array(items) => {
+            doc.insert("data".to_string(), items.into_iter().map(|item| resource(item, kind)).collect());
+            doc.insert("links".to_string(), Value::Object(page_links(uri, headers)));
+        }
+        Value::Object(mut object) if object.get("books").is_some_and(Value::is_array) && object.contains_key("_links") => {
+            let books = object.remove("books").unwrap_or_default();
+            let books = books.as_array().into_iter().flatten().map(|book| resource(book.clone(), "books")).collect();
+            doc.insert("data".to_string(), books);
+            doc.insert("links".to_string(), Value::Object(hrefs(&object["_links"])));
+        }
+        object @ Value::Object(_) if object.get("id").is_some() => {
+            doc.insert("data".to_string(), resource(object, kind));
+        }
+        other => {
+            doc.insert("meta".to_string(), other);
+        }
+    }
+    let total = headers.get(TOTAL_COUNT).and_then(|value| value.to_str().ok()).and_then(|value| value.parse::<u64>().ok());
+    if let Some(total) = total {
+        if let Some(meta) = doc.entry("meta").or_insert_with(|| json!({})).as_object_mut() {
+            meta.insert("total".to_string(), total.into());
+        }
+    }
+    Value::Object(doc)
+}
+
+// `self`, and `next` as the Link header gives it, for a version without
+// links of its own.
+fn page_links(uri: &Uri, headers: &HeaderMap) -> Map<String, Value> {
+    let mut links = Map::new();
+    let own = uri.path_and_query().map_or_else(|| uri.path().to_string(), |path| path.to_string());
+    links.insert("self".to_string(), Value::from(own));
+    let next = headers
+        .get(header::LINK)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.contains("rel=\"next\""))
+        .and_then(|value| value.split_once('<'))
+        .and_then(|(_, rest)| rest.split_once('>'))
+        .map(|(href, _)| href.to_string());
+    if let Some(next) = next {
+        links.insert("next".to_string(), Value::from(next));
+    }
+    links
+}
+
+// Links as JSON:API has them, by their hrefs alone. Those that name the
+// method to use don't fit and are left out.
+fn hrefs(links: &Value) -> Map<String, Value> {
+    let links = links.as_object().into_iter().flatten();
+    links
+        .filter(|(_, link)| link.get("method").is_none())
+        .filter_map(|(rel, link)| Some((rel.clone(), link.get("href")?.clone())))
+        .collect()
+}
+
+// An object as a resource of the type: its id as a string, each numeric
+// `<name>_id` as a to-one relationship to a `<name>s` and the rest as
+// attributes.
+fn resource(item: Value, kind: &str) -> Value {
+    let Value::Object(mut fields) = item else {
+        return item;
+    };
+    let Some(id) = fields.remove("id") else {
+        return Value::Object(fields);
+    };
+    let id = match id {
+        Value::String(id) => id,
+        id => id.to_string(),
+    };
+    let links = fields.remove("_links").map(|links| hrefs(&links));
+    let mut attributes = Map::new();
+    let mut relationships = Map::new();
+    for (name, value) in fields {
+        match name.strip_suffix("_id") {
+            Some(related) if !related.is_empty() && (value.is_u64() || value.is_null()) => {
+                let linkage = value.as_u64().map(|id| json!({ "type": format!("{}s", related), "id": id.to_string() }));
+                relationships.insert(related.to_string(), json!({ "data": linkage }));
+            }
+            _ => {
+                attributes.insert(name, value);
+            }
+        }
+    }
+    let mut resource = json!({ "type": kind, "id": id, "attributes": attributes });
+    if !relationships.is_empty() {
+        resource["relationships"] = Value::Object(relationships);
+    }
+    if let Some(links) = links.filter(|links| !links.is_empty()) {
+        resource["links"] = Value::Object(links);
+    }
+    resource
+}
+
+// A problem as error objects, one for each invalid field when it lists
+// them. The field becomes a pointer into the request document.
+fn errors(problem: &Value) -> Vec<Value> {
+    let status = problem["status"].as_u64().unwrap_or(500).to_string();
+    let title = problem["title"].clone();
+    let code = problem["type"].as_str().filter(|kind| *kind != "about:blank");
+    let fields = problem["errors"].as_array().filter(|fields| !fields.is_empty());
+    let Some(fields) = fields else {
+        let mut error = json!({ "status": status, "title": title, "detail": problem["detail"] });
+        if let Some(code) = code {
+            error["code"] = Value::from(code);
+        }
+        if let Some(book_id) = problem.get("book_id") {
+            error["meta"] = json!({ "book_id": book_id });
+        }
+        return vec![error];
+    };
+    fields
+        .iter()
+        .map(|field| {
+            let pointer = pointer(field["field"].as_str().unwrap_or_default());
+            let mut error = json!({ "status": status, "title": title, "detail": field["message"], "source": { "pointer": pointer } });
+            if let Some(code) = code {
+                error["code"] = Value::from(code);
+            }
+            error
+        })
+        .collect()
+}
+
+// `[2].title` is /data/2/attributes/title, and `author_id` the author
+// relationship.
+fn pointer(field: &str) -> String {
+    let (index, field) = match field.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        Some((index, rest)) => (format!("/{}", index), rest.trim_start_matches('.')),
+        None => (String::new(), field),
+    };
+    let path = field.replace('.', "/");
+    match path.strip_suffix("_id").filter(|related| !related.is_empty() && !related.contains('/')) {
+        Some(related) => format!("/data{}/relationships/{}", index, related),
+        None => format!("/data{}/attributes/{}", index, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{kind, pointer, unwrap};
+    use serde_json::json;
+
+    #[test]
+    fn paths_name_their_resources() {
+        assert_eq!(kind("/books/7"), "books");
+        assert_eq!(kind("/authors/3/books"), "books");
+        assert_eq!(kind("/books/7/reviews"), "reviews");
+        assert_eq!(kind("/overdue"), "overdue");
+    }
+
+    #[test]
+    fn fields_point_into_the_document() {
+        assert_eq!(pointer("title"), "/data/attributes/title");
+        assert_eq!(pointer("[2].isbn"), "/data/2/attributes/isbn");
+        assert_eq!(pointer("author_id"), "/data/relationships/author");
+    }
+
+    #[test]
+    fn documents_unwrap_into_fields() {
+        let doc = json!({ "data": { "type": "books", "attributes": { "title": "Dune" }, "relationships": { "author": { "data": { "type": "authors", "id": "7" } } } } });
+        assert_eq!(unwrap(&doc), Ok(json!({ "title": "Dune", "author_id": 7 })));
+        assert!(unwrap(&json!({ "title": "Dune" })).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::codec;
+
+// Clock skew allowed between the issuer and this server.
+const LEEWAY_SECS: u64 = 30;
+
+// Each role may do what the ones before it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    #[serde(default)]
+    pub sub: Option<String>,
+    pub role: Role,
+    exp: u64,
+    #[serde(default)]
+    nbf: Option<u64>,
+    #[serde(default)]
+    iss: Option<String>,
+    #[serde(default)]
+    aud: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+// Bearer JWTs signed with HS256 by whoever holds DOJO_JWT_SECRET. Tokens
+// must carry `exp` and a `role` of reader, editor or admin. With
+// DOJO_JWT_ISSUER or DOJO_JWT_AUDIENCE set, `iss` has to match it or `aud`
+// has to include it. DOJO_JWT_READS=required makes catalog reads need a
+// token (or an API key) too.
+pub struct Jwt {
+    secret: Vec<u8>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    pub reads_need_token: bool,
+}
+
+impl Jwt {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Jwt {
+            secret: secret.into(),
+            issuer: None,
+            audience: None,
+            reads_need_token: false,
+        }
+    }
+
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(secret) = std::env::var("DOJO_JWT_SECRET") else {
+            return Ok(None);
+        };
+        // HS256 keys shorter than the hash give away the brute-force margin.
+        if secret.len() < 32 {
+            return Err("DOJO_JWT_SECRET must be at least 32 bytes".to_string());
+        }
+        let reads_need_token = match std::env::var("DOJO_JWT_READS").as_deref() {
+            Err(_) | Ok("open") => false,
+            Ok("required") => true,
+            Ok(other) => return Err(format!("DOJO_JWT_READS must be open or required, got {:?}", other)),
+        };
+        Ok(Some(Jwt {
+            issuer: std::env::var("DOJO_JWT_ISSUER").ok(),
+            audience: std::env::var("DOJO_JWT_AUDIENCE").ok(),
+            reads_need_token,
+            ..Jwt::new(secret)
+        }))
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length")
+    }
+
+    // The token's claims, or why it isn't accepted.
+    pub fn verify(&self, token: &str) -> Result<Claims, String> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("the token is not a JWT".to_string());
+        };
+        let header: Header = serde_json::from_slice(&decode(header)?).map_err(|_| "the token's header is invalid".to_string())?;
+        // The algorithm is fixed here, never taken from the token, so a token
+        // can't switch to `none`.
+        if header.alg != "HS256" {
+            return Err(format!("tokens must be signed with HS256, not {}", header.alg));
+        }
+        let (signing_input, _) = token.rsplit_once('.').unwrap_or_default();
+        let mut mac = self.mac();
+        mac.update(signing_input.as_bytes());
+        mac.verify_slice(&decode(signature)?)
+            .map_err(|_| "the token's signature is invalid".to_string())?;
+
+        let claims: Claims = serde_json::from_slice(&decode(payload)?)
+            .map_err(|e| format!("the token's claims are invalid: {}", e))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        if now > claims.exp + LEEWAY_SECS {
+            return Err("the token has expired".to_string());
+        }
+        if claims.nbf.is_some_and(|nbf| now + LEEWAY_SECS < nbf) {
+            return Err("the token is not valid yet".to_string());
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err("the token is from another issuer".to_string());
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let included = match &claims.aud {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !included {
+                return Err("the token is for another audience".to_string());
+            }
+        }
+        Ok(claims)
+    }
+
+    // Signs claims the way an issuer would, for tests.
+    #[cfg(test)]
+    pub fn sign(&self, claims: &Value) -> String {
+        let header = codec::base64url_encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let signing_input = format!("{}.{}", header, codec::base64url_encode(claims.to_string().as_bytes()));
+        let mut mac = self.mac();
+        mac.update(signing_input.as_bytes());
+        format!("{}.{}", signing_input, codec::base64url_encode(&mac.finalize().into_bytes()))
+    }
+}
+
+fn decode(text: &str) -> Result<Vec<u8>, String> {
+    codec::base64url_decode(text).ok_or_else(|| "the token is not base64url".to_string())
+}
@@ -0,0 +1,277 @@
+use hyper::{
+    header::{self, HeaderMap},
+    Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    events::Topic,
+    extract::{Json, State},
+    isbn, json_response, problem, Book, SharedState, Storage,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Loan {
+    pub member: String,
+    pub device: String,
+    pub checked_out_at: u64,
+    // The day the book is due back, as YYYY-MM-DD, for loans made at the
+    // desk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due_date: Option<String>,
+}
+
+// Payload of loan.checked_out and loan.returned events.
+#[derive(Debug, Serialize)]
+pub struct LoanEvent<'a> {
+    pub book_id: u64,
+    #[serde(flatten)]
+    pub loan: &'a Loan,
+    pub at: u64,
+}
+
+pub fn loan_event(book_id: u64, loan: &Loan, at: u64) -> LoanEvent<'_> {
+    LoanEvent { book_id, loan, at }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Checkout,
+    Return,
+}
+
+// Device tokens come from DOJO_KIOSK_TOKENS as `device=token` pairs
+// separated by commas. Kiosk tokens only unlock the /kiosk endpoints.
+#[derive(Debug, Default)]
+pub struct Devices(HashMap<String, String>);
+
+impl Devices {
+    pub fn from_env() -> Result<Self, String> {
+        let list = match std::env::var("DOJO_KIOSK_TOKENS") {
+            Ok(list) => list,
+            Err(_) => return Ok(Devices::default()),
+        };
+        let mut devices = HashMap::new();
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (device, token) = entry
+                .split_once('=')
+                .filter(|(device, token)| !device.is_empty() && !token.is_empty())
+                .ok_or_else(|| format!("invalid kiosk token entry {:?}, expected device=token", entry))?;
+            devices.insert(token.to_string(), device.to_string());
+        }
+        Ok(Devices(devices))
+    }
+
+    fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let token = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+        self.0.get(token).cloned()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScanRequest {
+    pub isbn: String,
+    pub member: String,
+    // Without an explicit action a scan toggles: books on loan are returned,
+    // others are checked out.
+    pub action: Option<Action>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScanResult {
+    pub action: Action,
+    pub book: Book,
+    pub member: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueuedScan {
+    // Chosen by the kiosk; replays of the same id are ignored.
+    pub id: String,
+    pub isbn: String,
+    pub member: String,
+    pub action: Action,
+    pub scanned_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncRequest {
+    pub events: Vec<QueuedScan>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Applied,
+    Duplicate,
+    Conflict,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventResult {
+    pub id: String,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncReport {
+    pub results: Vec<EventResult>,
+}
+
+enum ScanError {
+    UnknownIsbn,
+    Conflict(String),
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn apply(
+    storage: &mut Storage,
+    device: &str,
+    isbn: &str,
+    member: &str,
+    action: Option<Action>,
+    at: u64,
+) -> Result<ScanResult, ScanError> {
+    let isbn = isbn::strip(isbn);
+    let book = storage
+        .books
+        .values()
+        .filter(|book| book.isbn.as_deref().map(isbn::strip).as_deref() == Some(isbn.as_str()))
+        .min_by_key(|book| book.id)
+        .cloned()
+        .ok_or(ScanError::UnknownIsbn)?;
+
+    let on_loan = storage.loans.get(&book.id);
+    let action = action.unwrap_or(if on_loan.is_some() { Action::Return } else { Action::Checkout });
+    match (action, on_loan) {
+        (Action::Checkout, Some(loan)) if loan.member == member => {}
+        (Action::Checkout, Some(_)) => {
+            return Err(ScanError::Conflict(format!("book {} is on loan to another member", book.id)))
+        }
+        (Action::Checkout, None) => {
+            let loan = Loan {
+                member: member.to_string(),
+                device: device.to_string(),
+                checked_out_at: at,
+                due_date: None,
+            };
+            storage.emit(Topic::Loans, "loan.checked_out", book.id, loan_event(book.id, &loan, at));
+            storage.lend(book.id, loan);
+        }
+        (Action::Return, Some(_)) => {
+            if let Some(loan) = storage.take_back(book.id) {
+                storage.emit(Topic::Loans, "loan.returned", book.id, loan_event(book.id, &loan, at));
+            }
+        }
+        (Action::Return, None) => {
+            return Err(ScanError::Conflict(format!("book {} is not on loan", book.id)))
+        }
+    }
+
+    Ok(ScanResult {
+        action,
+        book,
+        member: member.to_string(),
+    })
+}
+
+fn unauthorized() -> Response<Body> {
+    let mut response = problem::respond(StatusCode::UNAUTHORIZED, "Unauthorized");
+    response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    response
+}
+
+pub async fn scan(
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    Json(scan): Json<ScanRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let device = match state.kiosk_devices.authenticate(&headers) {
+        Some(device) => device,
+        None => return Ok(unauthorized()),
+    };
+
+    let params = format!("device={} isbn={:?}", device, scan.isbn);
+    let result = state
+        .with_storage("kiosk_scan", || params, |storage| {
+            apply(storage, &device, &scan.isbn, &scan.member, scan.action, now())
+        })
+        .await?;
+    match result {
+        Ok(result) => Ok(json_response(StatusCode::OK, &result)?),
+        Err(ScanError::UnknownIsbn) => Err(ApiError::NotFound("No book with that ISBN".to_string())),
+        Err(ScanError::Conflict(message)) => Err(ApiError::Conflict(message)),
+    }
+}
+
+// Scans queued while the kiosk was offline are applied in the order they
+// were made. Each event is reported on its own, so one conflict doesn't
+// hold back the rest of the queue.
+pub async fn sync(
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    Json(mut sync): Json<SyncRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let device = match state.kiosk_devices.authenticate(&headers) {
+        Some(device) => device,
+        None => return Ok(unauthorized()),
+    };
+
+    sync.events.sort_by_key(|event| event.scanned_at);
+    let params = format!("device={} events={}", device, sync.events.len());
+    let report = state
+        .with_storage("kiosk_sync", || params, |storage| {
+            let results = sync
+                .events
+                .iter()
+                .map(|event| {
+                    let key = (device.clone(), event.id.clone());
+                    if storage.kiosk_events.contains(&key) {
+                        return EventResult {
+                            id: event.id.clone(),
+                            outcome: Outcome::Duplicate,
+                            reason: None,
+                        };
+                    }
+                    let applied = apply(storage, &device, &event.isbn, &event.member, Some(event.action), event.scanned_at);
+                    storage.kiosk_events.insert(key);
+                    match applied {
+                        Ok(_) => EventResult {
+                            id: event.id.clone(),
+                            outcome: Outcome::Applied,
+                            reason: None,
+                        },
+                        Err(ScanError::UnknownIsbn) => EventResult {
+                            id: event.id.clone(),
+                            outcome: Outcome::Conflict,
+                            reason: Some(format!("no book with ISBN {}", event.isbn)),
+                        },
+                        Err(ScanError::Conflict(reason)) => EventResult {
+                            id: event.id.clone(),
+                            outcome: Outcome::Conflict,
+                            reason: Some(reason),
+                        },
+                    }
+                })
+                .collect();
+            SyncReport { results }
+        })
+        .await?;
+    Ok(json_response(StatusCode::OK, &report)?)
+}
@@ -0,0 +1,277 @@
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{self, Read, Seek, Write},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+
+use crate::{
+    body::{self, Body},
+    extract::State,
+    json_response, SharedState,
+};
+
+#[derive(Debug, Clone)]
+enum Lease {
+    // A single instance is always the leader.
+    Always,
+    // A lease file on storage every instance can reach.
+    File(PathBuf),
+    // Whoever leads the raft group also runs the jobs.
+    Raft,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    pub leader: bool,
+    pub holder: Option<String>,
+    // Bumped whenever the lease changes hands, so a deposed leader's late
+    // writes can be told apart from the current one's.
+    pub term: u64,
+}
+
+// Decides which instance runs the background jobs that must only run once
+// across the deployment. DOJO_LEADER_LOCK picks the lock: `file:<path>` or
+// `raft`. Without it every instance considers itself the leader.
+pub struct Leadership {
+    node: String,
+    lease: Lease,
+    ttl: Duration,
+    status: watch::Sender<Status>,
+    jobs: Mutex<Vec<&'static str>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LeaseRecord {
+    holder: String,
+    term: u64,
+    expires_at_ms: u64,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Default for Leadership {
+    fn default() -> Self {
+        Leadership {
+            node: String::new(),
+            lease: Lease::Always,
+            ttl: Duration::from_secs(10),
+            status: watch::Sender::new(Status::default()),
+            jobs: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+// DOJO_NODE_NAME identifies this instance to other services; it defaults
+// to the host name and process id.
+pub fn node_name() -> String {
+    std::env::var("DOJO_NODE_NAME").unwrap_or_else(|_| {
+        let host = std::fs::read_to_string("/etc/hostname").unwrap_or_default();
+        format!("{}:{}", host.trim(), std::process::id())
+    })
+}
+
+impl Leadership {
+    pub fn from_env(raft_enabled: bool) -> Result<Self, String> {
+        let lease = match std::env::var("DOJO_LEADER_LOCK") {
+            Err(_) => Lease::Always,
+            Ok(lock) if lock == "raft" && raft_enabled => Lease::Raft,
+            Ok(lock) if lock == "raft" => return Err("DOJO_LEADER_LOCK=raft needs DOJO_RAFT_NODE".to_string()),
+            Ok(lock) => match lock.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Lease::File(path.into()),
+                _ => return Err(format!("DOJO_LEADER_LOCK must be file:<path> or raft, got {:?}", lock)),
+            },
+        };
+        let ttl = match std::env::var("DOJO_LEADER_TTL_MS") {
+            Ok(ms) => ms
+                .parse()
+                .ok()
+                .filter(|ms| *ms >= 100)
+                .map(Duration::from_millis)
+                .ok_or_else(|| format!("DOJO_LEADER_TTL_MS must be at least 100, got {:?}", ms))?,
+            Err(_) => Duration::from_secs(10),
+        };
+        Ok(Leadership {
+            node: node_name(),
+            lease,
+            ttl,
+            ..Leadership::default()
+        })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.status.borrow().leader
+    }
+}
+
+// Takes or renews the lease under an exclusive flock, so two instances
+// never both see it as free.
+fn claim(path: &PathBuf, node: &str, ttl: Duration) -> io::Result<LeaseRecord> {
+    let mut file: File = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let current: Option<LeaseRecord> = serde_json::from_str(&contents).ok();
+    let now = now_ms();
+    let record = match current {
+        Some(lease) if lease.holder != node && lease.expires_at_ms > now => return Ok(lease),
+        Some(lease) if lease.holder == node => LeaseRecord {
+            term: lease.term,
+            ..new_lease(node, now, ttl, 0)
+        },
+        Some(lease) => new_lease(node, now, ttl, lease.term + 1),
+        None => new_lease(node, now, ttl, 1),
+    };
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(serde_json::to_string(&record)?.as_bytes())?;
+    file.sync_data()?;
+    // Closing the file releases the flock.
+    Ok(record)
+}
+
+fn new_lease(node: &str, now: u64, ttl: Duration, term: u64) -> LeaseRecord {
+    LeaseRecord {
+        holder: node.to_string(),
+        term,
+        expires_at_ms: now + ttl.as_millis() as u64,
+    }
+}
+
+// Renews three times per lease period. A leader that can't renew steps
+// down well before its lease runs out, so the next holder never overlaps
+// with it.
+pub async fn campaign(state: SharedState) {
+    let leadership = &state.leadership;
+    let path = match &leadership.lease {
+        Lease::Always => {
+            leadership.status.send_replace(Status {
+                leader: true,
+                holder: Some(leadership.node.clone()),
+                term: 1,
+            });
+            return;
+        }
+        Lease::File(path) => Some(path.clone()),
+        Lease::Raft => None,
+    };
+
+    let mut last_renewed: Option<Instant> = None;
+    let mut interval = tokio::time::interval(leadership.ttl / 3);
+    loop {
+        interval.tick().await;
+        let status = match &path {
+            Some(path) => {
+                let (path, node, ttl) = (path.clone(), leadership.node.clone(), leadership.ttl);
+                let attempt = Instant::now();
+                let claimed = tokio::task::spawn_blocking(move || claim(&path, &node, ttl))
+                    .await
+                    .unwrap_or_else(|e| Err(io::Error::other(e)));
+                match claimed {
+                    Ok(record) => {
+                        let leader = record.holder == leadership.node;
+                        last_renewed = leader.then_some(attempt);
+                        Status {
+                            leader,
+                            holder: Some(record.holder),
+                            term: record.term,
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("leader lease renewal failed: {}", e);
+                        let current = leadership.status.borrow().clone();
+                        let fresh = last_renewed.is_some_and(|at| at.elapsed() < leadership.ttl * 2 / 3);
+                        Status {
+                            leader: current.leader && fresh,
+                            ..current
+                        }
+                    }
+                }
+            }
+            None => match &state.raft {
+                Some(raft) => {
+                    let (leader, holder, term) = raft.leadership();
+                    Status { leader, holder, term }
+                }
+                None => Status::default(),
+            },
+        };
+
+        let changed = status.leader != leadership.is_leader();
+        if changed {
+            match status.leader {
+                true => tracing::info!("{} is now the leader (term {})", leadership.node, status.term),
+                false => tracing::info!("{} is no longer the leader", leadership.node),
+            }
+        }
+        leadership.status.send_if_modified(|current| {
+            let modified = current.leader != status.leader || current.holder != status.holder || current.term != status.term;
+            *current = status;
+            modified
+        });
+    }
+}
+
+// Runs `job` only while this instance leads, restarting it from scratch
+// each time leadership is regained.
+pub fn spawn_singleton<F, Fut>(state: &SharedState, name: &'static str, job: F)
+where
+    F: Fn(SharedState) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    state.leadership.jobs.lock().unwrap().push(name);
+    let mut status = state.leadership.status.subscribe();
+    let state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            while !status.borrow_and_update().leader {
+                if status.changed().await.is_err() {
+                    return;
+                }
+            }
+            tracing::info!("starting {}", name);
+            let running = tokio::spawn(job(state.clone()));
+            while status.borrow_and_update().leader {
+                if status.changed().await.is_err() {
+                    break;
+                }
+            }
+            running.abort();
+            tracing::info!("stopped {}", name);
+        }
+    });
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeaderView {
+    pub node: String,
+    pub lock: &'static str,
+    #[serde(flatten)]
+    pub status: Status,
+    pub jobs: Vec<&'static str>,
+}
+
+pub async fn status(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let leadership = &state.leadership;
+    let view = LeaderView {
+        node: leadership.node.clone(),
+        lock: match leadership.lease {
+            Lease::Always => "none",
+            Lease::File(_) => "file",
+            Lease::Raft => "raft",
+        },
+        status: leadership.status.borrow().clone(),
+        jobs: leadership.jobs.lock().unwrap().clone(),
+    };
+    json_response(StatusCode::OK, &view)
+}
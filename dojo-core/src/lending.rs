@@ -0,0 +1,191 @@
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    events::Topic,
+    extract::{Json, Path, State},
+    json_response,
+    kiosk::{self, Loan},
+    problem::FieldError,
+    store::{BookStore, Store},
+    validate::{self, Validate, Violations},
+    SharedState, Storage,
+};
+
+const MAX_BORROWER: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct CheckoutRequest {
+    pub borrower: String,
+    // YYYY-MM-DD, today or later.
+    pub due_date: String,
+}
+
+impl Validate for CheckoutRequest {
+    fn validate(&mut self, violations: &mut Violations) {
+        violations.text("borrower", &mut self.borrower, MAX_BORROWER);
+    }
+}
+
+// A book's loan as the lending endpoints show it, whichever way it was
+// made.
+#[derive(Debug, Serialize)]
+pub struct LoanView {
+    pub book_id: u64,
+    pub borrower: String,
+    pub checked_out_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub due_date: Option<String>,
+}
+
+impl LoanView {
+    fn new(book_id: u64, loan: &Loan) -> Self {
+        LoanView {
+            book_id,
+            borrower: loan.member.clone(),
+            checked_out_at: loan.checked_out_at,
+            due_date: loan.due_date.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Overdue {
+    #[serde(flatten)]
+    pub loan: LoanView,
+    pub days_overdue: i64,
+}
+
+// Days since 1970-01-01 for a YYYY-MM-DD date, or None if it isn't one.
+fn day_number(date: &str) -> Option<i64> {
+    let bytes = date.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = date.get(range)?;
+        digits.bytes().all(|b| b.is_ascii_digit()).then(|| digits.parse().ok())?
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    // Counting from March, so the leap day comes last in the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+// Today in UTC, as `day_number` counts.
+fn today() -> i64 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    (secs / 86_400) as i64
+}
+
+// Whether the book is on the shelf, and when that last changed if it has
+// since the process started.
+pub fn availability(storage: &Storage, id: u64) -> (bool, Option<u64>) {
+    (!storage.loans.contains_key(&id), storage.lent.get(&id).copied())
+}
+
+pub async fn checkout<S: BookStore>(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+    Json(mut request): Json<CheckoutRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let mut errors = validate::errors(&mut request);
+    match day_number(&request.due_date) {
+        Some(due) if due < today() => errors.push(FieldError {
+            field: "due_date".to_string(),
+            message: "must not be in the past".to_string(),
+        }),
+        Some(_) => {}
+        None => errors.push(FieldError {
+            field: "due_date".to_string(),
+            message: "must be a date as YYYY-MM-DD".to_string(),
+        }),
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            errors,
+        });
+    }
+    store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    let view = state
+        .with_storage("lending_checkout", || format!("id={}", id), |storage| {
+            if storage.loans.contains_key(&id) {
+                return Err(ApiError::Conflict(format!("book {} is already checked out", id)));
+            }
+            let at = kiosk::now();
+            let loan = Loan {
+                member: request.borrower,
+                device: "desk".to_string(),
+                checked_out_at: at,
+                due_date: Some(request.due_date),
+            };
+            let view = LoanView::new(id, &loan);
+            storage.emit(Topic::Loans, "loan.checked_out", id, kiosk::loan_event(id, &loan, at));
+            storage.lend(id, loan);
+            Ok(view)
+        })
+        .await??;
+    Ok(json_response(StatusCode::CREATED, &view)?)
+}
+
+// Returns a book however it was lent, from the kiosk and the desk alike.
+pub async fn return_book<S: BookStore>(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    let view = state
+        .with_storage("lending_return", || format!("id={}", id), |storage| {
+            let loan = storage.take_back(id)?;
+            storage.emit(Topic::Loans, "loan.returned", id, kiosk::loan_event(id, &loan, kiosk::now()));
+            Some(LoanView::new(id, &loan))
+        })
+        .await?
+        .ok_or_else(|| ApiError::Conflict(format!("book {} is not checked out", id)))?;
+    Ok(json_response(StatusCode::OK, &view)?)
+}
+
+// Loans past their due date, the longest overdue first. Loans without one,
+// from the kiosk or another library, are never overdue.
+pub async fn overdue(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let today = today();
+    let overdue = state
+        .read_storage("lending_overdue", String::new, |storage| {
+            let mut overdue: Vec<Overdue> = storage
+                .loans
+                .iter()
+                .filter_map(|(id, loan)| {
+                    let due = day_number(loan.due_date.as_deref()?)?;
+                    (due < today).then(|| Overdue {
+                        loan: LoanView::new(*id, loan),
+                        days_overdue: today - due,
+                    })
+                })
+                .collect();
+            overdue.sort_by(|a, b| b.days_overdue.cmp(&a.days_overdue).then(a.loan.book_id.cmp(&b.loan.book_id)));
+            overdue
+        })
+        .await?;
+    Ok(json_response(StatusCode::OK, &overdue)?)
+}
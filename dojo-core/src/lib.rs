@@ -0,0 +1,1827 @@
+use body::Body;
+use hyper::{body::Incoming, Method, Request, Response, StatusCode};
+use books_model::{Author, Book, CreateBookRequest, UpdateBookRequest};
+use conditional::Conditional;
+use dry_run::DryRun;
+use error::ApiError;
+use etag::IfMatch;
+use extract::{Json, Path, Query, RequestContext, State};
+use fields::Fields;
+use html::Format;
+use links::Links;
+use router::{Api, Router};
+use serde::Serialize;
+use store::{AuthorStore, BookStore, Store};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::Instrument;
+
+// The `pub` modules are the part later weeks build on: the router and
+// middleware stack, the error and problem types, and the store traits.
+mod accesslog;
+mod acquisitions;
+mod admin;
+mod audit;
+mod auth;
+mod authors;
+pub mod body;
+mod capture;
+mod cache;
+mod cbor;
+mod codec;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod check;
+mod cli;
+mod compact;
+mod compress;
+mod concurrency;
+mod conditional;
+mod config;
+mod contract;
+mod covers;
+mod cors;
+mod docs;
+mod dry_run;
+mod duplicates;
+pub mod error;
+mod etag;
+mod eventlog;
+mod events;
+mod export;
+pub mod extract;
+mod fields;
+mod filter;
+mod federation;
+mod gossip;
+mod graphql;
+mod grpc;
+mod handover;
+mod health;
+mod hosts;
+mod html;
+mod i18n;
+mod ids;
+mod import;
+mod inspect;
+mod instrument;
+mod isbn;
+mod jsonapi;
+mod jwt;
+mod kiosk;
+mod lending;
+mod leader;
+mod links;
+mod listen;
+mod listing;
+mod live;
+// tokio-console installs its own subscriber in place of these two.
+#[cfg_attr(feature = "console", allow(dead_code))]
+mod logging;
+mod maintenance;
+mod memory;
+mod merkle;
+mod migrate;
+mod metrics;
+mod msgpack;
+mod multiversx;
+mod negotiate;
+mod openlibrary;
+#[cfg_attr(feature = "console", allow(dead_code))]
+mod otel;
+mod outbound;
+mod patch;
+mod postgres;
+pub mod problem;
+mod profile;
+mod proxy;
+mod raft;
+mod ratelimit;
+mod redis;
+mod request_id;
+mod reviews;
+pub mod router;
+mod runner;
+mod schemas;
+mod search;
+mod seed;
+mod shard;
+#[cfg(test)]
+mod sim;
+mod snapshot;
+mod sort;
+mod sqlite;
+pub mod stack;
+mod stats;
+pub mod store;
+mod systemd;
+mod tags;
+mod tasks;
+mod tenants;
+mod timeout;
+mod tls;
+mod transactions;
+mod ui;
+mod validate;
+mod wal;
+mod wallet;
+mod webhooks;
+mod ws;
+
+use inspect::RequestTracker;
+use instrument::StorageMetrics;
+use tasks::TaskRegistry;
+
+struct Storage {
+    books: BTreeMap<u64, Book>,
+    // Deleted books, which keep their ids until they're restored.
+    deleted: BTreeMap<u64, Book>,
+    authors: BTreeMap<u64, Author>,
+    next_author_id: u64,
+    index: search::Index,
+    modified: conditional::Clock,
+    next_id: u64,
+    loans: HashMap<u64, kiosk::Loan>,
+    // When each book last went out on loan or came back, in milliseconds
+    // since the epoch.
+    lent: HashMap<u64, u64>,
+    // Each book's reviews, oldest first.
+    reviews: HashMap<u64, Vec<reviews::Review>>,
+    next_review_id: u64,
+    kiosk_events: HashSet<(String, String)>,
+    acquisitions: BTreeMap<u64, acquisitions::Acquisition>,
+    next_acquisition_id: u64,
+    interlibrary_loans: BTreeMap<u64, federation::InterlibraryLoan>,
+    next_interlibrary_loan_id: u64,
+    replica: Option<gossip::Replica>,
+    partition: Option<shard::Partition>,
+    outbox: Option<events::Outbox>,
+    webhooks: Option<Arc<webhooks::Webhooks>>,
+    live: Option<Arc<live::Live>>,
+    // Events for the webhooks and the live streams, sent once the update
+    // is flushed.
+    hooked: Vec<events::Event>,
+    watched: Vec<events::Event>,
+    // The update's changes for the audit log, appended with the events.
+    audit: Option<Arc<audit::Audit>>,
+    audited: Vec<audit::Entry>,
+    disk: Option<sqlite::Db>,
+    wal: Option<wal::Wal>,
+    // With DOJO_STORAGE=events, the log the catalog above is projected from.
+    history: Option<eventlog::EventLog>,
+    // What the current update changed, for `flush` to write to disk.
+    journal: Vec<sqlite::Write>,
+    created: stats::Days,
+}
+
+impl Storage {
+    fn new() -> Self {
+        Storage {
+            books: BTreeMap::new(),
+            deleted: BTreeMap::new(),
+            authors: BTreeMap::new(),
+            next_author_id: 1,
+            index: search::Index::default(),
+            modified: conditional::Clock::default(),
+            next_id: 1,
+            loans: HashMap::new(),
+            lent: HashMap::new(),
+            reviews: HashMap::new(),
+            next_review_id: 1,
+            kiosk_events: HashSet::new(),
+            acquisitions: BTreeMap::new(),
+            next_acquisition_id: 1,
+            interlibrary_loans: BTreeMap::new(),
+            next_interlibrary_loan_id: 1,
+            replica: None,
+            partition: None,
+            outbox: None,
+            webhooks: None,
+            live: None,
+            hooked: Vec::new(),
+            watched: Vec::new(),
+            audit: None,
+            audited: Vec::new(),
+            disk: None,
+            wal: None,
+            history: None,
+            journal: Vec::new(),
+            created: stats::Days::default(),
+        }
+    }
+
+    // Replaces the catalog with what's on disk, returning the events that
+    // were still waiting for the broker and for the webhooks.
+    fn load(&mut self) -> Result<(Vec<events::Event>, Vec<events::Event>), String> {
+        let Some(disk) = &self.disk else {
+            return Ok(Default::default());
+        };
+        let snapshot = disk.load()?;
+        self.set_books(snapshot.books);
+        self.next_id = self.next_id.max(snapshot.next_id);
+        self.set_authors(snapshot.authors);
+        self.next_author_id = self.next_author_id.max(snapshot.next_author_id);
+        Ok((snapshot.events, snapshot.hooks))
+    }
+
+    // Replaces the catalog and the trash with `books`, putting the ones
+    // with `deleted_at` in the trash. Ids carry on after the highest of
+    // either.
+    fn set_books(&mut self, books: Vec<Book>) {
+        let (deleted, books): (Vec<Book>, Vec<Book>) = books.into_iter().partition(|book| book.deleted_at.is_some());
+        self.books = books.into_iter().map(|book| (book.id, book)).collect();
+        self.deleted = deleted.into_iter().map(|book| (book.id, book)).collect();
+        self.reindex();
+        let max_id = self.books.keys().chain(self.deleted.keys()).max().copied().unwrap_or(0);
+        self.next_id = max_id + 1;
+    }
+
+    fn set_authors(&mut self, authors: Vec<Author>) {
+        self.authors = authors.into_iter().map(|author| (author.id, author)).collect();
+        let max_id = self.authors.keys().max().copied().unwrap_or(0);
+        self.next_author_id = max_id + 1;
+    }
+
+    // The catalog and the trash together, in id order, as `set_books`
+    // takes them.
+    fn all_books(&self) -> Vec<Book> {
+        let mut books: Vec<Book> = self.books.values().chain(self.deleted.values()).cloned().collect();
+        books.sort_by_key(|book| book.id);
+        books
+    }
+
+    // Rebuilds the search index after the catalog was replaced as a whole,
+    // which counts as a change to every book.
+    fn reindex(&mut self) {
+        self.index = search::Index::build(self.books.values());
+        self.modified = conditional::Clock::default();
+    }
+
+    fn record(&mut self, write: sqlite::Write) {
+        if self.disk.is_some() || self.wal.is_some() || self.history.is_some() {
+            self.journal.push(write);
+        }
+    }
+
+    // Writes the update's changes to disk, then lets the webhooks, live
+    // streams and audit log know about them. If writing fails the catalog
+    // is reloaded, so memory never gets ahead of what's stored, and they
+    // hear nothing.
+    fn flush(&mut self) -> Result<(), StorageError> {
+        let flushed = self.write();
+        let hooked = std::mem::take(&mut self.hooked);
+        let watched = std::mem::take(&mut self.watched);
+        let audited = std::mem::take(&mut self.audited);
+        if flushed.is_ok() {
+            if let Some(webhooks) = &self.webhooks {
+                webhooks.send(hooked);
+            }
+            if let Some(live) = &self.live {
+                live.send(watched);
+            }
+            if let Some(audit) = &self.audit {
+                audit.append(audited);
+            }
+        }
+        flushed
+    }
+
+    fn audit(&mut self, action: &str, id: u64, before: Option<Book>, after: Option<Book>) {
+        if let Some(audit) = &self.audit {
+            self.audited.push(audit.entry(action, id, before, after));
+        }
+    }
+
+    fn write(&mut self) -> Result<(), StorageError> {
+        if self.journal.is_empty() {
+            return Ok(());
+        }
+        let writes = std::mem::take(&mut self.journal);
+        if let Some(mut history) = self.history.take() {
+            // As with the write-ahead log, the change is already in memory.
+            if let Err(e) = history.append(&writes) {
+                tracing::error!("writing the event log failed: {}, exiting", e);
+                std::process::exit(1);
+            }
+            // The log alone can rebuild the catalog, so a missed snapshot
+            // only makes the next startup replay more of it.
+            if history.snapshot_due() {
+                if let Err(e) = history.snapshot(self) {
+                    tracing::warn!("writing the event log's snapshot failed: {}", e);
+                }
+            }
+            self.history = Some(history);
+            return Ok(());
+        }
+        if let Some(wal) = &mut self.wal {
+            // The change is already in memory and can't be taken back, and
+            // serving it would lose it on the next restart.
+            if let Err(e) = wal.append(&writes) {
+                tracing::error!("writing the write-ahead log failed: {}, exiting", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        let Some(disk) = &self.disk else {
+            return Ok(());
+        };
+        let Err(e) = disk.commit(&writes, self.next_id) else {
+            return Ok(());
+        };
+        let events: Vec<&str> = writes
+            .iter()
+            .filter_map(|write| match write {
+                sqlite::Write::Event(event) => Some(event.id.as_str()),
+                _ => None,
+            })
+            .collect();
+        if let Some(outbox) = &mut self.outbox {
+            outbox.forget(&events);
+        }
+        if let Err(reload) = self.load() {
+            tracing::error!("reloading books from disk failed: {}", reload);
+        }
+        Err(StorageError::Failed(format!("writing to disk failed: {}", e)))
+    }
+
+    fn allocate_book_id(&mut self) -> u64 {
+        let mut id = match &self.replica {
+            Some(replica) => replica.allocate_id(self.next_id),
+            None => self.next_id,
+        };
+        // A shard only hands out ids that hash to itself.
+        while self.partition.is_some_and(|partition| !partition.owns(id)) {
+            id += 1;
+        }
+        self.next_id = id + 1;
+        id
+    }
+
+    // Refuses to give book `id` an ISBN another book in the catalog already
+    // has. A new book, which has no id yet, is checked as 0.
+    fn check_isbn(&self, isbn: Option<&str>, id: u64) -> Result<(), StorageError> {
+        let Some(isbn) = isbn else {
+            return Ok(());
+        };
+        match self.index.isbn_holder(isbn, id) {
+            Some(holder) => Err(StorageError::DuplicateIsbn {
+                isbn: isbn.to_string(),
+                id: holder,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn insert_book(&mut self, create_req: CreateBookRequest) -> Book {
+        let id = self.allocate_book_id();
+        self.place_book(id, create_req)
+    }
+
+    // Stores a new book under an id the caller chose, which has to be free.
+    fn place_book(&mut self, id: u64, create_req: CreateBookRequest) -> Book {
+        self.next_id = self.next_id.max(id + 1);
+        let book = new_book(id, create_req);
+        self.books.insert(book.id, book.clone());
+        self.index.insert(&book);
+        self.modified.touch(book.id);
+        self.created.count(1);
+        self.record(sqlite::Write::Created(book.clone()));
+        self.emit(events::Topic::Books, "book.created", book.id, &book);
+        self.audit("book.created", book.id, None, Some(book.clone()));
+        book
+    }
+
+    // The id of the book with the uid, in the catalog or the trash.
+    fn resolve(&self, uid: &str) -> Option<u64> {
+        let trashed = || self.deleted.values().find(|book| book.uid.as_deref() == Some(uid)).map(|book| book.id);
+        self.index.uid(uid).or_else(trashed)
+    }
+
+    fn update_book(&mut self, id: u64, update_req: UpdateBookRequest) -> Option<Book> {
+        let book = updated(self.books.get(&id)?, update_req);
+        Some(self.replace_book(book))
+    }
+
+    // Stores a new version of an existing book.
+    fn replace_book(&mut self, book: Book) -> Book {
+        let before = self.books.insert(book.id, book.clone());
+        self.index.insert(&book);
+        self.modified.touch(book.id);
+        self.record(sqlite::Write::Updated(book.clone()));
+        self.emit(events::Topic::Books, "book.updated", book.id, &book);
+        self.audit("book.updated", book.id, before, Some(book.clone()));
+        book
+    }
+
+    // Moves the book to the trash, deleted at `at`. Its loans and reviews
+    // go with it, as they would if it were gone for good.
+    fn remove_book(&mut self, id: u64, at: u64) -> Option<Book> {
+        self.forget(id);
+        let mut book = self.books.remove(&id)?;
+        self.audit("book.deleted", id, Some(book.clone()), None);
+        book.deleted_at = Some(at);
+        self.index.remove(id);
+        self.modified.forget(id);
+        self.deleted.insert(id, book.clone());
+        self.record(sqlite::Write::Trashed(book.clone()));
+        self.emit(events::Topic::Books, "book.deleted", id, &book);
+        Some(book)
+    }
+
+    // Drops what's kept about a book besides the book itself, once it's
+    // deleted.
+    fn forget(&mut self, id: u64) {
+        self.loans.remove(&id);
+        self.lent.remove(&id);
+        self.reviews.remove(&id);
+    }
+
+    // Every kind of loan, from the kiosk, the desk or another library, goes
+    // out and comes back through these, so the book's availability and when
+    // it changed agree.
+    fn lend(&mut self, id: u64, loan: kiosk::Loan) {
+        self.loans.insert(id, loan);
+        self.lent.insert(id, conditional::now_ms());
+    }
+
+    fn take_back(&mut self, id: u64) -> Option<kiosk::Loan> {
+        let loan = self.loans.remove(&id)?;
+        self.lent.insert(id, conditional::now_ms());
+        Some(loan)
+    }
+
+    // Takes the book back out of the trash.
+    fn restore_book(&mut self, id: u64) -> Option<Book> {
+        let mut book = self.deleted.remove(&id)?;
+        book.deleted_at = None;
+        self.books.insert(id, book.clone());
+        self.index.insert(&book);
+        self.modified.touch(id);
+        self.record(sqlite::Write::Restored(book.clone()));
+        self.emit(events::Topic::Books, "book.restored", id, &book);
+        self.audit("book.restored", id, None, Some(book.clone()));
+        Some(book)
+    }
+
+    fn insert_author(&mut self, name: String) -> Author {
+        let author = Author { id: self.next_author_id, name };
+        self.next_author_id += 1;
+        self.authors.insert(author.id, author.clone());
+        self.record(sqlite::Write::AuthorCreated(author.clone()));
+        author
+    }
+
+    // Refuses with how many books still name the author, counting the
+    // trash, as a restored book would otherwise point nowhere.
+    fn remove_author(&mut self, id: u64) -> Option<Result<Author, usize>> {
+        if !self.authors.contains_key(&id) {
+            return None;
+        }
+        let books = self.books.values().chain(self.deleted.values()).filter(|book| book.author_id == Some(id)).count();
+        if books > 0 {
+            return Some(Err(books));
+        }
+        let author = self.authors.remove(&id)?;
+        self.record(sqlite::Write::AuthorDeleted(id));
+        Some(Ok(author))
+    }
+
+    // Queues an event for the broker, if one is configured, for the
+    // webhooks that want it and for anyone watching live. On disk the
+    // webhooks' copy is stored with the change, so a crash before it's
+    // delivered doesn't lose it.
+    fn emit(&mut self, topic: events::Topic, kind: &'static str, key: u64, data: impl Serialize) {
+        if let Some(event) = self.webhooks.as_ref().and_then(|webhooks| webhooks.event(kind, key, &data)) {
+            if self.disk.is_some() {
+                self.journal.push(sqlite::Write::Hook(event.clone()));
+            }
+            self.hooked.push(event);
+        }
+        if let Some(event) = self.live.as_ref().and_then(|live| live.event(kind, key, &data)) {
+            self.watched.push(event);
+        }
+        if let Some(outbox) = &mut self.outbox {
+            let event = outbox.push(topic, kind, key, data);
+            if self.disk.is_some() {
+                self.journal.push(sqlite::Write::Event(event.clone()));
+            }
+        }
+    }
+}
+
+// The book a POST creates, once it has an id.
+fn new_book(id: u64, create_req: CreateBookRequest) -> Book {
+    Book {
+        id,
+        title: create_req.title,
+        author: create_req.author,
+        isbn: create_req.isbn,
+        author_id: create_req.author_id,
+        tags: create_req.tags,
+        edition: create_req.edition,
+        uid: create_req.uid,
+        nft: None,
+        anchor: None,
+        deleted_at: None,
+    }
+}
+
+// The book with what a PUT sends applied. Fields left out keep their value.
+fn updated(book: &Book, update_req: UpdateBookRequest) -> Book {
+    let mut book = book.clone();
+    if let Some(title) = update_req.title {
+        book.title = title;
+    }
+    if let Some(author) = update_req.author {
+        book.author = author;
+    }
+    if let Some(isbn) = update_req.isbn {
+        book.isbn = Some(isbn);
+    }
+    if let Some(author_id) = update_req.author_id {
+        book.author_id = Some(author_id);
+    }
+    if let Some(tags) = update_req.tags {
+        book.tags = tags;
+    }
+    book.edition = book.edition.updated(update_req.edition);
+    book
+}
+
+// Why the storage refused or failed a request. Only a duplicate ISBN is
+// the client's doing; anything else is logged and answered with a 500.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    // Raised by chaos mode, by an external store, or when an update can't
+    // be written to disk.
+    #[error("{0}")]
+    Failed(String),
+    // The book that already has the ISBN a write wanted to give another.
+    #[error("book {id} already has ISBN {isbn}")]
+    DuplicateIsbn { isbn: String, id: u64 },
+    // The outbox holds as many undelivered events as it may.
+    #[error("{0} events are waiting for the broker")]
+    Backlogged(usize),
+}
+
+impl From<String> for StorageError {
+    fn from(e: String) -> Self {
+        StorageError::Failed(e)
+    }
+}
+
+pub struct AppState {
+    storage: RwLock<Storage>,
+    storage_metrics: StorageMetrics,
+    tasks: Mutex<TaskRegistry>,
+    requests: RequestTracker,
+    // A digest, see `auth::digest`.
+    admin_token: Option<[u8; 32]>,
+    api_keys: auth::ApiKeys,
+    jwt: Option<jwt::Jwt>,
+    pprof_enabled: bool,
+    // Whether every catalog response is a JSON:API document, not only those
+    // that ask to be.
+    jsonapi: bool,
+    recorder: Option<capture::Recorder>,
+    access_log: Option<accesslog::AccessLog>,
+    contract: Option<contract::Contract>,
+    trusted_proxies: proxy::TrustedProxies,
+    hosts: hosts::Hosts,
+    kiosk_devices: kiosk::Devices,
+    federation: federation::Federation,
+    webhooks: Arc<webhooks::Webhooks>,
+    live: Arc<live::Live>,
+    audit: Arc<audit::Audit>,
+    covers: Arc<covers::Covers>,
+    openlibrary: openlibrary::OpenLibrary,
+    multiversx: multiversx::MultiversX,
+    // What new books are given to be reached by besides their id.
+    ids: ids::Strategy,
+    cluster: Option<gossip::Cluster>,
+    raft: Option<raft::Raft>,
+    shards: Option<shard::Shards>,
+    rate_limiter: Option<ratelimit::Limiter>,
+    cors: Option<cors::Cors>,
+    compression: compress::Compression,
+    // How long a handler may take, unless it's unlimited.
+    request_timeout: Option<Duration>,
+    concurrency: concurrency::Limits,
+    events: Option<events::Publisher>,
+    postgres: Option<postgres::Postgres>,
+    redis: Option<redis::Redis>,
+    // The books Postgres or Redis created per day, which in memory the
+    // storage counts itself.
+    created: std::sync::Mutex<stats::Days>,
+    // Books and first pages read from Postgres or Redis, when configured.
+    cache: cache::Cache,
+    snapshots: Option<snapshot::Snapshots>,
+    leadership: leader::Leadership,
+    merkle: merkle::Anchors,
+    // Where clients reach the API, for links in responses.
+    public_url: Option<String>,
+    tenants: tenants::Tenants,
+    maintenance: maintenance::Maintenance,
+    #[cfg(feature = "chaos")]
+    chaos: chaos::Chaos,
+}
+
+impl AppState {
+    async fn with_storage<T>(
+        &self,
+        op: &'static str,
+        params: impl FnOnce() -> String,
+        f: impl FnOnce(&mut Storage) -> T,
+    ) -> Result<T, StorageError> {
+        let start = Instant::now();
+        #[cfg(feature = "chaos")]
+        if let Err(e) = self.chaos.before_storage(op).await {
+            self.storage_metrics.record(op, params, start.elapsed());
+            return Err(e);
+        }
+
+        let update = async {
+            let tenant = tenants::current();
+            let mut storage = match &tenant {
+                Some(tenant) => tenant.storage.write().await,
+                None => self.storage.write().await,
+            };
+            if op != events::ACK && op != webhooks::ACK {
+                if let Some(outbox) = storage.outbox.as_mut() {
+                    outbox.admit()?;
+                }
+            }
+            let result = f(&mut storage);
+            let flushed = storage.flush();
+            drop(storage);
+            flushed.map(|()| result)
+        };
+        let result = update.instrument(tracing::info_span!("storage", op)).await;
+        self.storage_metrics.record(op, params, start.elapsed());
+        result
+    }
+
+    // `with_storage` for lookups that change nothing. Readers share the
+    // lock, so they only ever wait for writers.
+    async fn read_storage<T>(
+        &self,
+        op: &'static str,
+        params: impl FnOnce() -> String,
+        f: impl FnOnce(&Storage) -> T,
+    ) -> Result<T, StorageError> {
+        let start = Instant::now();
+        #[cfg(feature = "chaos")]
+        if let Err(e) = self.chaos.before_storage(op).await {
+            self.storage_metrics.record(op, params, start.elapsed());
+            return Err(e);
+        }
+
+        let read = async {
+            match tenants::current() {
+                Some(tenant) => f(&*tenant.storage.read().await),
+                None => f(&*self.storage.read().await),
+            }
+        };
+        let result = read.instrument(tracing::info_span!("storage", op)).await;
+        self.storage_metrics.record(op, params, start.elapsed());
+        Ok(result)
+    }
+
+    // What goes with the catalog the running request is for, a tenant's
+    // or the default one.
+    fn live(&self) -> Arc<live::Live> {
+        tenants::current().map_or_else(|| self.live.clone(), |tenant| tenant.live.clone())
+    }
+
+    fn audit(&self) -> Arc<audit::Audit> {
+        tenants::current().map_or_else(|| self.audit.clone(), |tenant| tenant.audit.clone())
+    }
+
+    fn covers(&self) -> Arc<covers::Covers> {
+        tenants::current().map_or_else(|| self.covers.clone(), |tenant| tenant.covers.clone())
+    }
+
+    // The same bookkeeping as `with_storage`, for a query against an
+    // external database.
+    async fn with_database<T>(
+        &self,
+        op: &'static str,
+        params: impl FnOnce() -> String,
+        query: impl Future<Output = Result<T, StorageError>>,
+    ) -> Result<T, StorageError> {
+        let start = Instant::now();
+        #[cfg(feature = "chaos")]
+        if let Err(e) = self.chaos.before_storage(op).await {
+            self.storage_metrics.record(op, params, start.elapsed());
+            return Err(e);
+        }
+
+        let result = query.instrument(tracing::info_span!("storage", op)).await;
+        self.storage_metrics.record(op, params, start.elapsed());
+        result
+    }
+}
+
+pub type SharedState = Arc<AppState>;
+
+// The backend the catalog handlers are built for.
+type Books = AppState;
+
+// What the binary was asked to do, with the config file it read.
+pub struct Config {
+    command: cli::Command,
+    file: Option<config::Loaded>,
+}
+
+impl Config {
+    // Flags and the config file are applied here, before the runtime
+    // starts, since they set environment variables and no other thread may
+    // be reading them then. Bad arguments or a bad file exit.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+        let (command, overrides) = cli::parse(args).unwrap_or_else(|message| {
+            eprintln!("{}\nbook-api --help lists the commands and options", message);
+            std::process::exit(2);
+        });
+        if let cli::Command::Help = command {
+            return Config { command, file: None };
+        }
+        for (var, value) in overrides {
+            env::set_var(var, value);
+        }
+        let file = config::load().unwrap_or_else(|message| {
+            eprintln!("invalid configuration file: {}", message);
+            std::process::exit(1);
+        });
+        Config { command, file }
+    }
+}
+
+// Runs the command: serves until a shutdown signal, or exports or imports
+// the catalog and returns.
+pub async fn run(config: Config) {
+    if let cli::Command::Help = config.command {
+        print!("{}", cli::USAGE);
+        return;
+    }
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+    #[cfg(not(feature = "console"))]
+    logging::init().unwrap_or_else(|message| {
+        eprintln!("invalid logging configuration: {}", message);
+        std::process::exit(1);
+    });
+    if let Some(file) = config.file {
+        tracing::info!(
+            path = %file.path.display(),
+            applied = file.applied,
+            overridden = ?file.overridden,
+            "loaded the configuration file"
+        );
+    }
+    let result = match config.command {
+        cli::Command::Serve => {
+            serve().await;
+            Ok(())
+        }
+        cli::Command::Export { out, format } => cli::export(open().await, out, format).await,
+        cli::Command::Import { file } => cli::import(open().await, file).await,
+        cli::Command::Help => Ok(()),
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
+
+// The whole API, middleware included, as a service answering requests
+// in-process; what every listener runs with the default stack.
+#[derive(Clone)]
+pub struct App {
+    stack: stack::Stack,
+    state: SharedState,
+}
+
+pub fn app(state: SharedState) -> App {
+    App {
+        stack: stack::Stack::default(),
+        state,
+    }
+}
+
+impl hyper::service::Service<Request<Incoming>> for App {
+    type Response = Response<Body>;
+    type Error = body::Error;
+    type Future = stack::ResponseFuture;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        self.stack.call(req.map(Body::from), self.state.clone())
+    }
+}
+
+async fn serve() {
+    let runner = runner::Runner::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid runner configuration: {}", message);
+        std::process::exit(1);
+    });
+    let listeners = listen::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid listener configuration: {}", message);
+        std::process::exit(1);
+    });
+    let protocols = runner::Protocols::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid HTTP configuration: {}", message);
+        std::process::exit(1);
+    });
+    let grpc = grpc::addr_from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid gRPC configuration: {}", message);
+        std::process::exit(1);
+    });
+    systemd::notify_status("loading the catalog");
+    let state = open().await;
+    systemd::notify_status("loading the seed file");
+    if let Err(message) = seed::load(&state).await {
+        tracing::error!("invalid seed file: {}", message);
+        std::process::exit(1);
+    }
+
+    // Jobs that must run on one instance only wait for this one to lead.
+    tokio::spawn(leader::campaign(state.clone()));
+    tokio::spawn(webhooks::run(state.clone()));
+    tokio::spawn(multiversx::track(state.clone()));
+    if let Some(secs) = env::var("DOJO_COMPACT_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()) {
+        leader::spawn_singleton(&state, "compaction", move |state| {
+            compact::run_scheduled(state, Duration::from_secs(secs))
+        });
+    }
+    if let Some(secs) = env::var("DOJO_MERKLE_ANCHOR_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()) {
+        tokio::spawn(merkle::run_scheduled(state.clone(), Duration::from_secs(secs)));
+    }
+    if state.events.is_some() {
+        tokio::spawn(events::run(state.clone()));
+        if state.postgres.is_some() {
+            tokio::spawn(events::run_postgres(state.clone()));
+        }
+    }
+    if state.snapshots.is_some() {
+        tokio::spawn(snapshot::run_scheduled(state.clone()));
+    }
+    if state.cluster.is_some() {
+        tokio::spawn(gossip::run(state.clone()));
+    }
+    if state.raft.is_some() {
+        tokio::spawn(raft::run(state.clone()));
+    }
+
+    for listener in &listeners {
+        tracing::info!(bind = %listener, runner = runner.name(), "server running");
+    }
+    let (listeners, sockets) = handover::Sockets::bind(listeners).unwrap_or_else(|message| {
+        tracing::error!("binding failed: {}", message);
+        std::process::exit(1);
+    });
+
+    // On SIGTERM or SIGINT the listeners stop accepting and in-flight
+    // requests get DOJO_SHUTDOWN_TIMEOUT_SECS to finish. A second signal
+    // stops waiting for them. SIGUSR2 does the same before handing the
+    // listeners over to a new process, see `handover`.
+    let grace = Duration::from_secs(env::var("DOJO_SHUTDOWN_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30));
+    let (stop, shutdown) = runner::Shutdown::new();
+    let serving = async {
+        tokio::try_join!(
+            runner.serve(listeners, state.clone(), shutdown.clone(), protocols),
+            grpc::serve(grpc, state.clone(), shutdown),
+        )
+        .map(|_| ())
+    };
+    tokio::pin!(serving);
+    let mut handing_over = false;
+    tokio::select! {
+        result = &mut serving => {
+            if let Err(e) = result {
+                tracing::error!("server error: {}", e);
+            }
+        }
+        signal = shutdown_signal() => {
+            tracing::info!("received {}, finishing in-flight requests", signal);
+            handing_over = signal == "SIGUSR2";
+            systemd::notify_stopping();
+            let _ = stop.send(true);
+            state.live.close();
+            state.tenants.close();
+            tokio::select! {
+                result = tokio::time::timeout(grace, &mut serving) => match result {
+                    Ok(Ok(())) => tracing::info!("all connections finished"),
+                    Ok(Err(e)) => tracing::error!("server error: {}", e),
+                    Err(_) => tracing::warn!("requests still running after {}s, stopping anyway", grace.as_secs()),
+                },
+                signal = shutdown_signal() => tracing::warn!("received {} again, stopping without waiting", signal),
+            }
+        }
+    }
+    match snapshot::save(&state).await {
+        Ok(true) => tracing::info!("wrote the snapshot"),
+        Ok(false) => {}
+        Err(e) => tracing::error!("writing the snapshot failed: {}", e),
+    }
+    if handing_over {
+        match sockets.hand_over() {
+            Ok(pid) => tracing::info!(pid, "handed the listeners over"),
+            Err(e) => tracing::error!("starting the new process failed: {}", e),
+        }
+    }
+}
+
+// The catalog and everything around it, as configured from the
+// environment. The offline commands share it with the server.
+pub async fn open() -> SharedState {
+    let slow_threshold = env::var("DOJO_SLOW_STORAGE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map_or(Duration::from_millis(50), Duration::from_millis);
+    let cluster = gossip::Cluster::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid cluster configuration: {}", message);
+        std::process::exit(1);
+    });
+    let raft = raft::Raft::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid raft configuration: {}", message);
+        std::process::exit(1);
+    });
+    let shards = shard::Shards::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid shard configuration: {}", message);
+        std::process::exit(1);
+    });
+    if [cluster.is_some(), raft.is_some(), shards.is_some()].iter().filter(|set| **set).count() > 1 {
+        tracing::error!("invalid configuration: only one of DOJO_CLUSTER_NODE, DOJO_RAFT_NODE and DOJO_SHARD_NODE can be set");
+        std::process::exit(1);
+    }
+    let leadership = leader::Leadership::from_env(raft.is_some()).unwrap_or_else(|message| {
+        tracing::error!("invalid leader election configuration: {}", message);
+        std::process::exit(1);
+    });
+    let mut storage = Storage::new();
+    storage.replica = cluster.as_ref().map(gossip::Cluster::replica);
+    storage.partition = shards.as_ref().map(shard::Shards::partition);
+    let events = events::Publisher::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid event broker configuration: {}", message);
+        std::process::exit(1);
+    });
+    storage.outbox = events.as_ref().map(events::Publisher::outbox);
+    let webhooks = Arc::new(webhooks::Webhooks::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid webhook configuration: {}", message);
+        std::process::exit(1);
+    }));
+    storage.webhooks = Some(webhooks.clone());
+    let live = Arc::new(live::Live::default());
+    storage.live = Some(live.clone());
+    let audit = Arc::new(audit::Audit::from_env().unwrap_or_else(|message| {
+        tracing::error!("failed to open the audit log: {}", message);
+        std::process::exit(1);
+    }));
+    storage.audit = Some(audit.clone());
+    let redis = match store::backend_from_env() {
+        Ok(store::Backend::Memory) => None,
+        Ok(store::Backend::Sqlite(disk)) => {
+            storage.disk = Some(disk);
+            None
+        }
+        Ok(store::Backend::Redis(redis)) => Some(redis),
+        Ok(store::Backend::Events(history)) => {
+            let replayed = history.project(&mut storage).unwrap_or_else(|message| {
+                tracing::error!("failed to read the event log: {}", message);
+                std::process::exit(1);
+            });
+            tracing::info!(
+                "rebuilt {} books in {}, replaying {} events",
+                storage.books.len(),
+                history.describe(),
+                replayed
+            );
+            storage.history = Some(history);
+            None
+        }
+        Err(message) => {
+            tracing::error!("invalid storage configuration: {}", message);
+            std::process::exit(1);
+        }
+    };
+    let postgres = postgres::Postgres::from_env(events.is_some()).unwrap_or_else(|message| {
+        tracing::error!("invalid database configuration: {}", message);
+        std::process::exit(1);
+    });
+    if storage.history.is_some() && (cluster.is_some() || raft.is_some() || shards.is_some()) {
+        // Their catalogs change through their peers too, not only through
+        // the events this instance logs.
+        tracing::error!("invalid configuration: DOJO_STORAGE=events can't be combined with DOJO_CLUSTER_NODE, DOJO_RAFT_NODE or DOJO_SHARD_NODE");
+        std::process::exit(1);
+    }
+    if postgres.is_some() && (storage.disk.is_some() || storage.history.is_some() || redis.is_some() || cluster.is_some() || raft.is_some() || shards.is_some()) {
+        // Instances sharing the database already share the catalog.
+        tracing::error!("invalid configuration: DATABASE_URL can't be combined with DOJO_STORAGE, DOJO_CLUSTER_NODE, DOJO_RAFT_NODE or DOJO_SHARD_NODE");
+        std::process::exit(1);
+    }
+    if let Some(postgres) = &postgres {
+        if let Err(e) = postgres.migrate().await {
+            tracing::error!("failed to set up the database at {}: {}", postgres.describe(), e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(redis) = &redis {
+        if cluster.is_some() || raft.is_some() || shards.is_some() {
+            tracing::error!("invalid configuration: DOJO_STORAGE=redis can't be combined with DOJO_CLUSTER_NODE, DOJO_RAFT_NODE or DOJO_SHARD_NODE");
+            std::process::exit(1);
+        }
+        if let Err(e) = redis.ping().await {
+            tracing::error!("failed to reach redis at {}: {}", redis.describe(), e);
+            std::process::exit(1);
+        }
+        if let Err(e) = redis.migrate().await {
+            tracing::error!("failed to migrate the catalog in redis at {}: {}", redis.describe(), e);
+            std::process::exit(1);
+        }
+    }
+    let cache = cache::Cache::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid cache configuration: {}", message);
+        std::process::exit(1);
+    });
+    if cache.enabled() && postgres.is_none() && redis.is_none() {
+        // The other backends already read from memory.
+        tracing::error!("invalid configuration: DOJO_CACHE_ENTRIES needs DATABASE_URL or DOJO_STORAGE=redis");
+        std::process::exit(1);
+    }
+    let snapshots = snapshot::Snapshots::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid snapshot configuration: {}", message);
+        std::process::exit(1);
+    });
+    if snapshots.is_some() && (storage.disk.is_some() || storage.history.is_some() || redis.is_some() || postgres.is_some() || cluster.is_some() || raft.is_some()) {
+        // Those keep the catalog elsewhere, or rebuild it from their peers.
+        tracing::error!("invalid configuration: DOJO_SNAPSHOT_FILE can't be combined with DOJO_STORAGE, DATABASE_URL, DOJO_CLUSTER_NODE or DOJO_RAFT_NODE");
+        std::process::exit(1);
+    }
+    let wal = wal::Wal::from_env().unwrap_or_else(|message| {
+        tracing::error!("failed to open the write-ahead log: {}", message);
+        std::process::exit(1);
+    });
+    if wal.is_some() && (storage.disk.is_some() || storage.history.is_some() || redis.is_some() || postgres.is_some() || cluster.is_some() || raft.is_some()) {
+        tracing::error!("invalid configuration: DOJO_WAL_FILE can't be combined with DOJO_STORAGE, DATABASE_URL, DOJO_CLUSTER_NODE or DOJO_RAFT_NODE");
+        std::process::exit(1);
+    }
+    let mut wal_seq = 0;
+    if let Some(snapshots) = &snapshots {
+        match snapshots.load(&mut storage) {
+            Ok(Some(restored)) => {
+                tracing::info!("restored {} books from the snapshot", restored.books);
+                wal_seq = restored.wal_seq;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!("failed to load the snapshot: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some((mut wal, records)) = wal {
+        let applied = wal.replay(records, wal_seq, &mut storage);
+        if applied > 0 {
+            tracing::info!("replayed {} changes from the write-ahead log", applied);
+        }
+        storage.wal = Some(wal);
+    }
+    if storage.disk.is_some() && (cluster.is_some() || raft.is_some()) {
+        // Both replicate the in-memory catalog and would bypass the disk.
+        tracing::error!("invalid configuration: DOJO_STORAGE=sqlite can't be combined with DOJO_CLUSTER_NODE or DOJO_RAFT_NODE");
+        std::process::exit(1);
+    }
+    let tenants = tenants::Tenants::from_env().unwrap_or_else(|message| {
+        tracing::error!("invalid tenant configuration: {}", message);
+        std::process::exit(1);
+    });
+    if tenants.enabled()
+        && (storage.disk.is_some()
+            || storage.history.is_some()
+            || redis.is_some()
+            || postgres.is_some()
+            || cluster.is_some()
+            || raft.is_some()
+            || shards.is_some())
+    {
+        // Tenants' catalogs are kept in this instance's memory, apart from
+        // wherever those keep the default one.
+        tracing::error!("invalid configuration: DOJO_MAX_TENANTS can't be combined with DOJO_STORAGE, DATABASE_URL, DOJO_CLUSTER_NODE, DOJO_RAFT_NODE or DOJO_SHARD_NODE");
+        std::process::exit(1);
+    }
+    match storage.load() {
+        Ok((pending, hooks)) => {
+            if let Some(outbox) = &mut storage.outbox {
+                outbox.restore(pending);
+            }
+            webhooks.send(hooks);
+        }
+        Err(e) => {
+            tracing::error!("failed to load the catalog: {}", e);
+            std::process::exit(1);
+        }
+    }
+    Arc::new(AppState {
+        storage: RwLock::new(storage),
+        storage_metrics: StorageMetrics::new(slow_threshold),
+        tasks: Mutex::new(TaskRegistry::new()),
+        requests: RequestTracker::new(
+            env::var("DOJO_DEBUG_HISTORY").ok().and_then(|v| v.parse().ok()).unwrap_or(100),
+        ),
+        admin_token: env::var("DOJO_ADMIN_TOKEN").ok().map(|token| auth::digest(&token)),
+        api_keys: auth::ApiKeys::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid API key configuration: {}", message);
+            std::process::exit(1);
+        }),
+        jwt: jwt::Jwt::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid JWT configuration: {}", message);
+            std::process::exit(1);
+        }),
+        pprof_enabled: env::var("DOJO_ENABLE_PPROF").is_ok_and(|v| v == "1" || v == "true"),
+        jsonapi: env::var("DOJO_JSONAPI").is_ok_and(|v| v == "1" || v == "true"),
+        recorder: capture::Recorder::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid capture configuration: {}", message);
+            std::process::exit(1);
+        }),
+        access_log: accesslog::AccessLog::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid access log configuration: {}", message);
+            std::process::exit(1);
+        }),
+        contract: env::var("DOJO_CONTRACT_CHECK")
+            .is_ok_and(|v| v == "1" || v == "true")
+            .then(contract::Contract::load),
+        trusted_proxies: proxy::TrustedProxies::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid DOJO_TRUSTED_PROXIES: {}", message);
+            std::process::exit(1);
+        }),
+        hosts: hosts::Hosts::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid DOJO_HOSTS: {}", message);
+            std::process::exit(1);
+        }),
+        kiosk_devices: kiosk::Devices::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid DOJO_KIOSK_TOKENS: {}", message);
+            std::process::exit(1);
+        }),
+        federation: federation::Federation::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid federation configuration: {}", message);
+            std::process::exit(1);
+        }),
+        webhooks,
+        live,
+        audit,
+        covers: Arc::new(covers::Covers::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid cover configuration: {}", message);
+            std::process::exit(1);
+        })),
+        openlibrary: openlibrary::OpenLibrary::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid OpenLibrary configuration: {}", message);
+            std::process::exit(1);
+        }),
+        multiversx: multiversx::MultiversX::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid MultiversX configuration: {}", message);
+            std::process::exit(1);
+        }),
+        ids: ids::Strategy::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid id configuration: {}", message);
+            std::process::exit(1);
+        }),
+        cluster,
+        raft,
+        shards,
+        rate_limiter: ratelimit::Limiter::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid rate limit configuration: {}", message);
+            std::process::exit(1);
+        }),
+        cors: cors::Cors::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid CORS configuration: {}", message);
+            std::process::exit(1);
+        }),
+        compression: compress::Compression::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid compression configuration: {}", message);
+            std::process::exit(1);
+        }),
+        request_timeout: timeout::from_env().unwrap_or_else(|message| {
+            tracing::error!("invalid timeout configuration: {}", message);
+            std::process::exit(1);
+        }),
+        concurrency: concurrency::Limits::from_env(|method, pattern| api().has_route(method, pattern))
+            .unwrap_or_else(|message| {
+                tracing::error!("invalid concurrency configuration: {}", message);
+                std::process::exit(1);
+            }),
+        events,
+        postgres,
+        redis,
+        created: Default::default(),
+        cache,
+        snapshots,
+        leadership,
+        merkle: merkle::Anchors::default(),
+        public_url: env::var("DOJO_PUBLIC_URL").ok(),
+        tenants,
+        maintenance: Default::default(),
+        #[cfg(feature = "chaos")]
+        chaos: match chaos::Chaos::from_env() {
+            Ok(chaos) => chaos,
+            Err(message) => {
+                tracing::error!("invalid chaos configuration: {}", message);
+                std::process::exit(1);
+            }
+        },
+    })
+}
+
+async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (Ok(mut terminate), Ok(mut interrupt), Ok(mut hand_over)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::user_defined2()),
+    ) else {
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+        _ = hand_over.recv() => "SIGUSR2",
+    }
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    state: SharedState,
+) -> Result<Response<Body>, body::Error> {
+    // A sharded catalog serves each book from the shard that owns it.
+    let req = match &state.shards {
+        Some(shards) => match shards.route(req).await {
+            Ok(req) => req,
+            Err(response) => return Ok(response),
+        },
+        None => req,
+    };
+    api().dispatch(RequestContext::new(req, state)).await
+}
+
+// With a raft backend, catalog writes go through the replicated log.
+fn replicated(ctx: &RequestContext) -> bool {
+    ctx.state().raft.is_some()
+}
+
+// A sharded catalog answers lists and searches from every shard, unless
+// another shard is the one asking.
+fn gather(ctx: &RequestContext) -> bool {
+    ctx.state().shards.is_some() && !ctx.headers().contains_key(shard::FORWARDED)
+}
+
+fn api() -> &'static Api {
+    static API: OnceLock<Api> = OnceLock::new();
+    API.get_or_init(|| {
+        Api::new(operations())
+            .version("v1", catalog())
+            .version("v2", catalog())
+            .legacy("v1")
+    })
+}
+
+// The versions share handlers; what differs between them is decided by
+// extractors, as links::Links does for v2's `_links`.
+fn catalog() -> Router {
+    Router::default()
+        .route(Method::GET, "/books", |ctx| {
+            if gather(&ctx) { ctx.call(shard::list_books) } else { ctx.call(get_all_books::<Books>) }
+        })
+        .route(Method::POST, "/books", |ctx| {
+            if replicated(&ctx) && !dry_run::requested(&ctx) {
+                ctx.call(raft::create_book)
+            } else {
+                ctx.call(create_book::<Books>)
+            }
+        })
+        .route(Method::DELETE, "/books", |ctx| match (replicated(&ctx), gather(&ctx)) {
+            (true, _) => ctx.call(raft::delete_books),
+            (false, true) => ctx.call(shard::delete_books),
+            (false, false) => ctx.call(delete_books::<Books>),
+        })
+        .route(Method::POST, "/books/batch", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/batch")) })
+            } else {
+                ctx.call(create_books::<Books>)
+            }
+        })
+        .route(Method::POST, "/books/import", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/import")) })
+            } else {
+                ctx.call(import::import_books::<Books>)
+            }
+        })
+        .route(Method::POST, "/books/transactions", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/transactions")) })
+            } else {
+                ctx.call(transactions::run)
+            }
+        })
+        .route(Method::POST, "/books/validate", |ctx| ctx.call(dry_run::validate_books::<Books>))
+        .route(Method::POST, "/books/lookup", |ctx| ctx.call(openlibrary::lookup::<Books>))
+        .route(Method::GET, "/books/search", |ctx| {
+            if gather(&ctx) { ctx.call(shard::search_books) } else { ctx.call(search::search_books::<Books>) }
+        })
+        .route(Method::GET, "/books/export", |ctx| {
+            if gather(&ctx) { ctx.call(shard::export_books) } else { ctx.call(export::export_books::<Books>) }
+        })
+        .route(Method::GET, "/books/duplicates", |ctx| ctx.call(duplicates::find::<Books>))
+        .route(Method::POST, "/books/merge", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/merge")) })
+            } else {
+                ctx.call(duplicates::merge::<Books>)
+            }
+        })
+        .route(Method::GET, "/books/events", |ctx| ctx.call(live::stream_events))
+        .route(Method::GET, "/books/poll", |ctx| ctx.call(audit::poll))
+        .route(Method::GET, "/books/{book ID}", |ctx| ctx.call(get_book::<Books>))
+        .route(Method::PUT, "/books/{book ID}", |ctx| {
+            if replicated(&ctx) && !dry_run::requested(&ctx) {
+                ctx.call(raft::update_book)
+            } else {
+                ctx.call(update_book::<Books>)
+            }
+        })
+        .route(Method::PATCH, "/books/{book ID}", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("PATCH")) })
+            } else {
+                ctx.call(patch::patch_book::<Books>)
+            }
+        })
+        .route(Method::DELETE, "/books/{book ID}", |ctx| {
+            if replicated(&ctx) { ctx.call(raft::delete_book) } else { ctx.call(delete_book::<Books>) }
+        })
+        .route(Method::POST, "/books/{book ID}/restore", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/{id}/restore")) })
+            } else {
+                ctx.call(restore_book::<Books>)
+            }
+        })
+        .route(Method::GET, "/books/{book ID}/cover", |ctx| ctx.call(covers::get_cover::<Books>))
+        .route(Method::PUT, "/books/{book ID}/cover", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("PUT /books/{id}/cover")) })
+            } else {
+                ctx.call(covers::put_cover::<Books>)
+            }
+        })
+        .route(Method::POST, "/books/{book ID}/anchor", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/{id}/anchor")) })
+            } else {
+                ctx.call(multiversx::anchor::<Books>)
+            }
+        })
+        .route(Method::GET, "/books/{book ID}/anchor", |ctx| ctx.call(multiversx::provenance))
+        .route(Method::POST, "/books/{book ID}/mint", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/{id}/mint")) })
+            } else {
+                ctx.call(multiversx::mint::<Books>)
+            }
+        })
+        .admin(Method::GET, "/books/{book ID}/history", |ctx| ctx.call(audit::history::<Books>))
+        .route(Method::POST, "/books/{book ID}/tags", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /books/{id}/tags")) })
+            } else {
+                ctx.call(tags::add::<Books>)
+            }
+        })
+        .route(Method::DELETE, "/books/{book ID}/tags", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("DELETE /books/{id}/tags")) })
+            } else {
+                ctx.call(tags::remove::<Books>)
+            }
+        })
+        .route(Method::GET, "/books/{book ID}/reviews", |ctx| ctx.call(reviews::list::<Books>))
+        .route(Method::POST, "/books/{book ID}/reviews", |ctx| ctx.call(reviews::create::<Books>))
+        .route(Method::POST, "/books/{book ID}/checkout", |ctx| ctx.call(lending::checkout::<Books>))
+        .route(Method::POST, "/books/{book ID}/return", |ctx| ctx.call(lending::return_book::<Books>))
+        .route(Method::GET, "/overdue", |ctx| ctx.call(lending::overdue))
+        .route(Method::GET, "/mx/accounts/{address}/balance", |ctx| ctx.call(multiversx::balance))
+        .route(Method::POST, "/mx/contracts/query", |ctx| ctx.call(multiversx::query))
+        .route(Method::GET, "/mx/transactions/{hash}", |ctx| ctx.call(multiversx::transaction))
+        .route(Method::GET, "/tags", |ctx| {
+            if gather(&ctx) { ctx.call(shard::list_tags) } else { ctx.call(tags::list::<Books>) }
+        })
+        .admin(Method::GET, "/audit", |ctx| ctx.call(audit::list))
+        .route(Method::GET, "/changes", |ctx| ctx.call(audit::changes))
+        .route(Method::GET, "/authors", |ctx| ctx.call(authors::list::<Books>))
+        .route(Method::POST, "/authors", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("POST /authors")) })
+            } else {
+                ctx.call(authors::create::<Books>)
+            }
+        })
+        .route(Method::GET, "/authors/{author ID}", |ctx| ctx.call(authors::get::<Books>))
+        .route(Method::DELETE, "/authors/{author ID}", |ctx| {
+            if replicated(&ctx) {
+                Box::pin(async { Ok(raft::not_replicated("DELETE /authors/{id}")) })
+            } else {
+                ctx.call(authors::delete::<Books>)
+            }
+        })
+        .route(Method::GET, "/authors/{author ID}/books", |ctx| ctx.call(authors::books::<Books>))
+        .route(Method::GET, "/books/{book ID}/proof", |ctx| ctx.call(merkle::proof))
+        .route(Method::GET, "/merkle/root", |ctx| ctx.call(merkle::root))
+        .route(Method::POST, "/acquisition-requests", |ctx| ctx.call(acquisitions::suggest))
+        .route(Method::GET, "/acquisition-requests", |ctx| ctx.call(acquisitions::list))
+        .route(Method::GET, "/acquisition-requests/{acquisition request ID}", |ctx| ctx.call(acquisitions::get))
+        .route(Method::POST, "/acquisition-requests/{acquisition request ID}/votes", |ctx| {
+            ctx.call(acquisitions::vote)
+        })
+        .admin(Method::PUT, "/acquisition-requests/{acquisition request ID}/status", |ctx| {
+            ctx.call(acquisitions::set_status)
+        })
+        .authenticated(Method::POST, "/kiosk/scan", |ctx| ctx.call(kiosk::scan))
+        .authenticated(Method::POST, "/kiosk/sync", |ctx| ctx.call(kiosk::sync))
+        .route(Method::POST, "/interlibrary-loans", |ctx| ctx.call(federation::borrow))
+        .route(Method::GET, "/interlibrary-loans", |ctx| ctx.call(federation::list))
+        .admin(Method::PUT, "/interlibrary-loans/{loan ID}/status", |ctx| ctx.call(federation::set_status))
+        .route(Method::GET, "/schemas", |ctx| ctx.call(schemas::list_schemas))
+        .route(Method::GET, "/schemas/{schema name}.json", |ctx| ctx.call(schemas::get_schema))
+}
+
+// Operator, cluster and peer endpoints, which stay where they are whatever
+// the API version.
+fn operations() -> Router {
+    Router::default()
+        .route(Method::POST, "/admin/migrate-data", |ctx| ctx.call(admin::start_migration))
+        .route(Method::POST, "/admin/check", |ctx| ctx.call(admin::run_check))
+        .route(Method::POST, "/admin/compact", |ctx| ctx.call(admin::run_compaction))
+        .route(Method::POST, "/admin/backup", |ctx| ctx.call(admin::backup))
+        .route(Method::POST, "/admin/restore", |ctx| ctx.call(admin::restore))
+        .route(Method::GET, "/admin/storage-metrics", |ctx| ctx.call(admin::storage_metrics))
+        .route(Method::GET, "/admin/stats", |ctx| ctx.call(stats::stats))
+        .route(Method::GET, "/admin/dead-jobs", |ctx| ctx.call(admin::list_dead_jobs))
+        .route(Method::DELETE, "/admin/dead-jobs", |ctx| ctx.call(admin::discard_dead_jobs))
+        .route(Method::POST, "/admin/dead-jobs/retry", |ctx| ctx.call(admin::retry_dead_jobs))
+        .route(Method::GET, "/admin/dead-jobs/{dead job ID}", |ctx| ctx.call(admin::get_dead_job))
+        .route(Method::DELETE, "/admin/dead-jobs/{dead job ID}", |ctx| ctx.call(admin::discard_dead_job))
+        .route(Method::POST, "/admin/dead-jobs/{dead job ID}/retry", |ctx| ctx.call(admin::retry_dead_job))
+        .route(Method::GET, "/admin/tasks/{task ID}", |ctx| ctx.call(admin::get_task))
+        .route(Method::GET, "/federation/search", |ctx| ctx.call(federation::search))
+        .route(Method::GET, "/federation/catalog", |ctx| ctx.call(federation::catalog))
+        .route(Method::POST, "/federation/loans", |ctx| ctx.call(federation::lend))
+        .route(Method::POST, "/federation/loans/{loan ID}/status", |ctx| ctx.call(federation::peer_status))
+        .route(Method::GET, "/admin/federation/peers", |ctx| ctx.call(federation::list_peers))
+        .route(Method::PUT, "/admin/federation/peers/{peer name}", |ctx| ctx.call(federation::put_peer))
+        .route(Method::DELETE, "/admin/federation/peers/{peer name}", |ctx| ctx.call(federation::delete_peer))
+        .route(Method::GET, "/admin/webhooks", |ctx| ctx.call(webhooks::list_webhooks))
+        .route(Method::PUT, "/admin/webhooks/{webhook name}", |ctx| ctx.call(webhooks::put_webhook))
+        .route(Method::DELETE, "/admin/webhooks/{webhook name}", |ctx| ctx.call(webhooks::delete_webhook))
+        .route(Method::GET, "/admin/webhooks/{webhook name}/deliveries", |ctx| ctx.call(webhooks::list_deliveries))
+        .route(Method::POST, "/cluster/gossip", |ctx| ctx.call(gossip::exchange))
+        .route(Method::GET, "/admin/cluster", |ctx| ctx.call(gossip::status))
+        .route(Method::POST, "/raft/append", |ctx| ctx.call(raft::append_entries))
+        .route(Method::POST, "/raft/vote", |ctx| ctx.call(raft::request_vote))
+        .route(Method::POST, "/raft/snapshot", |ctx| ctx.call(raft::install_snapshot))
+        .route(Method::POST, "/raft/propose", |ctx| ctx.call(raft::propose))
+        .route(Method::GET, "/admin/raft", |ctx| ctx.call(raft::status))
+        .route(Method::POST, "/admin/raft/members", |ctx| ctx.call(raft::add_member))
+        .route(Method::DELETE, "/admin/raft/members/{member name}", |ctx| ctx.call(raft::remove_member))
+        .route(Method::GET, "/admin/leader", |ctx| ctx.call(leader::status))
+        .route(Method::GET, "/admin/log-level", |ctx| ctx.call(logging::get_level))
+        .route(Method::PUT, "/admin/log-level", |ctx| ctx.call(logging::put_level))
+        .route(Method::GET, "/admin/maintenance", |ctx| ctx.call(maintenance::status))
+        .route(Method::POST, "/admin/maintenance", |ctx| ctx.call(maintenance::toggle))
+        .route(Method::GET, "/admin/debug/requests", |ctx| ctx.call(capture::recent))
+        .route(Method::POST, "/admin/merkle/anchor", |ctx| ctx.call(merkle::create_anchor))
+        .route(Method::GET, "/admin/ui", |ctx| ctx.with_param("index.html", "asset").call(ui::asset))
+        .route(Method::GET, "/admin/ui/{asset}", |ctx| ctx.call(ui::asset))
+        .route(Method::POST, "/graphql", |ctx| ctx.call(graphql::execute::<Books>))
+        .route(Method::GET, "/graphql/schema", |ctx| ctx.call(graphql::schema))
+        .route(Method::GET, "/ws", |ctx| ctx.call(ws::connect))
+        .route(Method::GET, "/health", |ctx| ctx.call(health::health))
+        .route(Method::GET, "/ready", |ctx| ctx.call(health::ready))
+        .route(Method::GET, "/openapi.json", |ctx| ctx.call(docs::spec))
+        .route(Method::GET, "/docs", |ctx| ctx.call(docs::page))
+        .route(Method::GET, "/metrics", |ctx| ctx.call(metrics::render))
+        .route(Method::GET, "/debug/requests", |ctx| ctx.call(inspect::debug_requests))
+        .route(Method::GET, "/debug/memory", |ctx| ctx.call(memory::report))
+        .route(Method::GET, "/debug/pprof/profile", |ctx| ctx.call(profile::cpu_profile))
+}
+
+// A book given an `author_id` takes that author's name. A dry run answers
+// with the book as it would be stored, which has no id yet.
+async fn create_book<S: BookStore + AuthorStore>(
+    Query(dry_run): Query<DryRun>,
+    links: Links,
+    Store(store): Store<S>,
+    Json(mut create_req): Json<CreateBookRequest>,
+) -> Result<Response<Body>, ApiError> {
+    if let Some(id) = create_req.author_id {
+        create_req.author = authors::name(&*store, "author_id".to_string(), id).await?;
+    }
+    validate::check(&mut create_req)?;
+    if dry_run.is_set() {
+        dry_run::check_isbn(&*store, create_req.isbn.clone(), 0).await?;
+        return Ok(json_response(StatusCode::OK, &create_req)?);
+    }
+    let book = store.insert(create_req).await?;
+    Ok(json_response(StatusCode::CREATED, &links.book(&book, &book))?)
+}
+
+const MAX_BATCH: usize = 1000;
+
+// Creates all the books or none of them.
+async fn create_books<S: BookStore + AuthorStore>(
+    links: Links,
+    Store(store): Store<S>,
+    Json(mut create_reqs): Json<Vec<CreateBookRequest>>,
+) -> Result<Response<Body>, ApiError> {
+    if create_reqs.len() > MAX_BATCH {
+        return Err(ApiError::BadRequest(format!("A batch holds at most {} books", MAX_BATCH)));
+    }
+    for (index, create_req) in create_reqs.iter_mut().enumerate() {
+        let Some(id) = create_req.author_id else {
+            continue;
+        };
+        create_req.author = authors::name(&*store, format!("[{}].author_id", index), id).await?;
+    }
+    validate::check_all(&mut create_reqs)?;
+    validate::distinct_isbns(&create_reqs)?;
+    let books = store.insert_many(create_reqs).await?;
+    let books: Vec<_> = books.iter().map(|book| links.book(book, book)).collect();
+    Ok(json_response(StatusCode::CREATED, &books)?)
+}
+
+// DELETE /books takes `{"ids": [...]}` or `{"filter": {...}}`, the filter
+// naming the same criteria as the listing's query parameters. Like DELETE
+// on one book it needs If-Match: `*`, or the ETags the books were read
+// with, in which case one that has changed since is left alone.
+#[derive(Debug, Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BulkDelete {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ids: Option<Vec<u64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<serde_json::Value>,
+}
+
+enum Selection {
+    Ids(HashSet<u64>),
+    Filter(listing::Filter),
+}
+
+impl BulkDelete {
+    fn selection(&self) -> Result<Selection, String> {
+        match (&self.ids, &self.filter) {
+            (Some(ids), None) => Ok(Selection::Ids(ids.iter().copied().collect())),
+            (None, Some(filter)) => {
+                let filter = listing::Filter::from_json(filter.clone())?;
+                // An empty filter would match the whole catalog.
+                match filter.is_empty() {
+                    true => Err("the filter has no criteria".to_string()),
+                    false => Ok(Selection::Filter(filter)),
+                }
+            }
+            _ => Err("give either ids or filter".to_string()),
+        }
+    }
+}
+
+impl Selection {
+    fn matches(&self, book: &Book) -> bool {
+        match self {
+            Selection::Ids(ids) => ids.contains(&book.id),
+            Selection::Filter(filter) => filter.matches(book),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, serde::Deserialize)]
+struct Deleted {
+    deleted: usize,
+}
+
+async fn delete_books<S: BookStore>(
+    if_match: IfMatch,
+    Store(store): Store<S>,
+    Json(request): Json<BulkDelete>,
+) -> Result<Response<Body>, ApiError> {
+    let selection = request.selection().map_err(ApiError::BadRequest)?;
+    let deleted = store.delete_where(move |book| selection.matches(book) && if_match.holds(book)).await?;
+    Ok(json_response(StatusCode::OK, &Deleted { deleted })?)
+}
+
+async fn get_all_books<S: BookStore>(
+    Query(page): Query<listing::Page>,
+    Query(filter): Query<listing::Filter>,
+    Query(fields): Query<Fields>,
+    format: Format,
+    links: Links,
+    Store(store): Store<S>,
+    conditional: Conditional,
+) -> Result<Response<Body>, ApiError> {
+    let after = page.after().map_err(ApiError::bad_request)?;
+    fields.check(listing::BOOK_FIELDS).map_err(ApiError::bad_request)?;
+    if filter.as_of.is_some() && filter.include_deleted {
+        return Err(ApiError::bad_request("as_of and include_deleted can't be combined"));
+    }
+    let listed = async {
+        // Read before the books, so a change in between makes the time too
+        // early rather than too late for what's sent.
+        let modified = store.modified(None).await?;
+        let (books, total) = match (filter.as_of, after, page.limit) {
+            (Some(at), _, _) => {
+                let mut books = store.as_of(at).await?.map_err(ApiError::BadRequest)?;
+                books.retain(|book| filter.matches(book));
+                let total = books.len();
+                (books, page.counts_total().then_some(total))
+            }
+            // One past the page, to tell whether another follows. A filter
+            // could leave that short, so filtered listings read everything.
+            (None, Some(after), Some(limit)) if filter.is_empty() => (store.list_after(after, limit.saturating_add(1)).await?, None),
+            (None, None, Some(limit)) if filter.is_empty() && page.is_first() => {
+                let books = store.list_after(0, limit.saturating_add(1)).await?;
+                (books, Some(store.count().await?))
+            }
+            _ => {
+                let mut books = with_deleted(&*store, &filter).await?;
+                books.retain(|book| filter.matches(book));
+                let total = books.len();
+                (books, page.counts_total().then_some(total))
+            }
+        };
+        let listed = page.apply(books, after);
+        let response = listing::respond(format, &links, &page, &fields, listed, total)?;
+        Ok::<_, ApiError>(conditional.respond(response, modified).await)
+    };
+    // The HTML listing answers the same URL, so its failures vary too.
+    match listed.await {
+        Ok(response) => Ok(response),
+        Err(e) => Ok(html::vary_accept(e.into_result()?)),
+    }
+}
+
+// The whole catalog, and the trash too if the filter asks for it. A tag
+// narrows it down through the store's tag index first.
+async fn with_deleted<S: BookStore>(store: &S, filter: &listing::Filter) -> Result<Vec<Book>, StorageError> {
+    let mut books = match filter.tag() {
+        Some(tag) => store.tagged(tag).await?,
+        None => store.list().await?,
+    };
+    if filter.include_deleted {
+        books.extend(store.deleted().await?);
+    }
+    Ok(books)
+}
+
+// A book as GET /books/{id} shows it, with its average rating once it has
+// reviews and whether it's on the shelf.
+#[derive(Serialize)]
+struct Detail<T> {
+    #[serde(flatten)]
+    book: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    average_rating: Option<f64>,
+    available: bool,
+}
+
+// The ETag is the book's own version, which PUT and DELETE compare, so it
+// stays put when a review or a loan changes the rest. Once either has,
+// the book is only revalidated by date, which they move on.
+async fn get_book<S: BookStore>(
+    Path(id): Path<u64>,
+    Query(fields): Query<Fields>,
+    format: Format,
+    links: Links,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+    mut conditional: Conditional,
+) -> Result<Response<Body>, ApiError> {
+    fields.check(listing::BOOK_DETAIL_FIELDS).map_err(ApiError::bad_request)?;
+    let detail = async {
+        let mut modified = store.modified(Some(id)).await?;
+        let (rating, (available, lent)) = state
+            .read_storage("book_detail", || format!("id={}", id), |storage| {
+                (reviews::rating(storage, id), lending::availability(storage, id))
+            })
+            .await?;
+        let changed = rating.as_ref().map(|rating| rating.at).max(lent);
+        if let (Some(changed), Some(at)) = (changed, &mut modified) {
+            *at = (*at).max(changed);
+            conditional = conditional.by_date();
+        }
+        let book = store.get(id).await?.ok_or_else(ApiError::not_found)?;
+        let response = match format {
+            Format::Html => etag::tagged(html::book_detail(&book), &book),
+            _ => {
+                let detail = Detail {
+                    book: &book,
+                    average_rating: rating.map(|rating| rating.average),
+                    available,
+                };
+                etag::tagged(json_response(StatusCode::OK, &links.book(fields.project(&detail), &book))?, &book)
+            }
+        };
+        Ok::<_, ApiError>(conditional.respond(response, modified).await)
+    };
+    let response = match detail.await {
+        Ok(response) => response,
+        Err(e) => e.into_result()?,
+    };
+    Ok(html::vary_accept(response))
+}
+
+// PUT and DELETE need the book's ETag in If-Match and answer 412 when it has
+// changed. The check and the write happen together in the backend.
+async fn update_book<S: BookStore + AuthorStore>(
+    Path(id): Path<u64>,
+    Query(dry_run): Query<DryRun>,
+    if_match: IfMatch,
+    links: Links,
+    Store(store): Store<S>,
+    Json(mut update_req): Json<UpdateBookRequest>,
+) -> Result<Response<Body>, ApiError> {
+    if let Some(author_id) = update_req.author_id {
+        update_req.author = Some(authors::name(&*store, "author_id".to_string(), author_id).await?);
+    }
+    validate::check(&mut update_req)?;
+    // Without an ETag, since the book it would tag isn't stored.
+    if dry_run.is_set() {
+        let book = store.get(id).await?.ok_or_else(ApiError::not_found)?;
+        if_match.check(&book)?;
+        let book = updated(&book, update_req);
+        dry_run::check_isbn(&*store, book.isbn.clone(), id).await?;
+        return Ok(json_response(StatusCode::OK, &links.book(&book, &book))?);
+    }
+    let change = |book: &Book| if_match.check(book).map(|()| updated(book, update_req.clone()));
+    let book = store.modify(id, change).await?.ok_or_else(ApiError::not_found)??;
+    Ok(etag::tagged(json_response(StatusCode::OK, &links.book(&book, &book))?, &book))
+}
+
+async fn delete_book<S: BookStore>(
+    Path(id): Path<u64>,
+    if_match: IfMatch,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    store.delete_if(id, |book| if_match.check(book)).await?.ok_or_else(ApiError::not_found)??;
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+// Takes a deleted book back out of the trash under its old id. A book that
+// was never deleted is a conflict rather than a no-op, so a client can tell
+// it restored nothing.
+async fn restore_book<S: BookStore>(
+    Path(id): Path<u64>,
+    links: Links,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    match store.restore(id).await? {
+        Some(book) => Ok(etag::tagged(json_response(StatusCode::OK, &links.book(&book, &book))?, &book)),
+        None => match store.get(id).await? {
+            Some(_) => Err(ApiError::Conflict(format!("book {} isn't deleted", id))),
+            None => Err(ApiError::not_found()),
+        },
+    }
+}
+
+fn json_response<T: Serialize>(
+    status: StatusCode,
+    data: &T,
+) -> Result<Response<Body>, body::Error> {
+    match serde_json::to_string(data) {
+        Ok(body) => Ok(Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap()),
+        Err(_) => Ok(problem::respond(StatusCode::INTERNAL_SERVER_ERROR, "Error serializing response")),
+    }
+}
+
+// For extractors and middleware, which answer with a response of their own
+// rather than through a handler's ApiError.
+fn bad_request(message: &str) -> Response<Body> {
+    ApiError::bad_request(message).response()
+}
+
+fn storage_error(err: StorageError) -> Response<Body> {
+    ApiError::Storage(err).response()
+}
+
+fn not_found() -> Response<Body> {
+    ApiError::not_found().response()
+}
\ No newline at end of file
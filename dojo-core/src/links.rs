@@ -0,0 +1,109 @@
+use books_model::Book;
+use hyper::{header, Response, Uri};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    body::Body,
+    extract::{FromRequest, RequestContext},
+    listen::Https,
+    tenants::Prefix,
+};
+
+#[derive(Debug, Serialize)]
+pub struct Link {
+    href: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<&'static str>,
+}
+
+pub type LinkMap = BTreeMap<&'static str, Link>;
+
+// An object with the links to follow from it. Without links it serializes
+// as the object alone.
+#[derive(Serialize)]
+pub struct Linked<T> {
+    #[serde(flatten)]
+    item: T,
+    #[serde(rename = "_links", skip_serializing_if = "Option::is_none")]
+    links: Option<LinkMap>,
+}
+
+// Builds links for the API version a request came in on. v1 predates them
+// and keeps its shape, so there `base` is None and nothing is added.
+//
+// Hrefs are absolute: DOJO_PUBLIC_URL when it's set, as it has to be behind
+// a proxy that changes the scheme or host, and the request's Host otherwise,
+// over https when the request came in on a TLS listener.
+// The request's URI is kept for links relative to it, like a listing's
+// other pages.
+#[derive(Clone)]
+pub struct Links {
+    base: Option<String>,
+    uri: Uri,
+}
+
+impl FromRequest for Links {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let uri = ctx.parts().uri.clone();
+        let Some(version) = ctx.version().filter(|version| *version != "v1") else {
+            return Ok(Links { base: None, uri });
+        };
+        let origin = match &ctx.state().public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                // HTTP/2 carries the host in the URI rather than a Host header.
+                let host = ctx.headers().get(header::HOST).and_then(|host| host.to_str().ok());
+                match host.or(ctx.parts().uri.authority().map(|authority| authority.as_str())) {
+                    Some(host) if ctx.parts().extensions.get::<Https>().is_some() => format!("https://{}", host),
+                    Some(host) => format!("http://{}", host),
+                    None => String::new(),
+                }
+            }
+        };
+        // A tenant picked by path keeps it in every link.
+        let prefix = ctx.parts().extensions.get::<Prefix>().map_or("", |prefix| prefix.0.as_str());
+        Ok(Links {
+            base: Some(format!("{}{}/{}", origin, prefix, version)),
+            uri,
+        })
+    }
+}
+
+impl Links {
+    pub fn enabled(&self) -> bool {
+        self.base.is_some()
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    // A link to `path` under the version, e.g. /books?cursor=...
+    pub fn to(&self, path: &str) -> Option<Link> {
+        Some(Link {
+            href: format!("{}{}", self.base.as_ref()?, path),
+            method: None,
+        })
+    }
+
+    pub fn book_url(&self, book: &Book) -> Option<String> {
+        let path = match &book.uid {
+            Some(uid) => format!("/books/{}", uid),
+            None => format!("/books/{}", book.id),
+        };
+        self.to(&path).map(|link| link.href)
+    }
+
+    // Links point at the book's uid when it has one, its id otherwise.
+    pub fn book<T: Serialize>(&self, item: T, book: &Book) -> Linked<T> {
+        let links = self.book_url(book).map(|href| {
+            let link = |method| Link {
+                href: href.clone(),
+                method,
+            };
+            LinkMap::from([("self", link(None)), ("update", link(Some("PUT"))), ("delete", link(Some("DELETE")))])
+        });
+        Linked { item, links }
+    }
+}
@@ -0,0 +1,363 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    env, fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net,
+        },
+    },
+    path::PathBuf,
+};
+use tokio::net::UnixListener;
+
+use crate::{stack::Stack, tls::Tls};
+
+#[derive(Debug, Clone)]
+pub struct TcpBind {
+    pub addr: SocketAddr,
+    // Only meaningful for IPv6 addresses; when false, `[::]` also accepts
+    // IPv4 clients.
+    pub v6only: bool,
+    // SO_REUSEPORT, so a new process can bind the same port while this one
+    // drains. Only safe with storage the processes share, as each keeps
+    // its own memory.
+    pub reuse_port: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnixBind {
+    pub path: PathBuf,
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum Bind {
+    Tcp(TcpBind),
+    Unix(UnixBind),
+    // Sockets that are already bound: handed over by systemd socket
+    // activation or by the process this one replaces, or bound ahead of
+    // serving so they can be handed on.
+    InheritedTcp(TcpListener),
+    InheritedUnix(net::UnixListener),
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(tcp) => write!(f, "http://{}", tcp.addr),
+            Bind::Unix(unix) => write!(f, "unix:{}", unix.path.display()),
+            Bind::InheritedTcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "http://{} (inherited)", addr),
+                Err(_) => write!(f, "inherited TCP socket"),
+            },
+            Bind::InheritedUnix(listener) => {
+                let addr = listener.local_addr().ok();
+                match addr.as_ref().and_then(|addr| addr.as_pathname()) {
+                    Some(path) => write!(f, "unix:{} (inherited)", path.display()),
+                    None => write!(f, "inherited Unix socket"),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Listener {
+    pub bind: Bind,
+    pub stack: Stack,
+    // TCP listeners with a certificate serve HTTPS.
+    pub tls: Option<Tls>,
+}
+
+impl From<Bind> for Listener {
+    fn from(bind: Bind) -> Self {
+        Listener {
+            bind,
+            stack: Stack::default(),
+            tls: None,
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bind = self.bind.to_string();
+        match (&self.tls, bind.strip_prefix("http://")) {
+            (Some(_), Some(rest)) => write!(f, "https://{}", rest),
+            _ => f.write_str(&bind),
+        }
+    }
+}
+
+// The connection's remote address, attached to each request by the runner.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+// Attached by the runner to requests that came in over TLS.
+#[derive(Debug, Clone, Copy)]
+pub struct Https;
+
+impl PeerAddr {
+    // Dual-stack sockets report IPv4 clients as `::ffff:a.b.c.d`.
+    pub fn ip(&self) -> IpAddr {
+        self.0.ip().to_canonical()
+    }
+}
+
+// DOJO_LISTEN replaces the default listeners with `;`-separated entries of
+// the form `<host:port|unix:path> [mode=660] [v6only=true|false]
+// [reuseport=true|false] [cert=chain.pem key=key.pem]` followed by any
+// stack options (see `Stack::set_option`). A TCP entry with a cert and key
+// serves HTTPS.
+//
+// Without it, DOJO_ADDR serves HTTP, on DOJO_PORT instead if that's set,
+// and, when DOJO_TLS_CERT and DOJO_TLS_KEY are set, DOJO_TLS_ADDR
+// (127.0.0.1:3443 by default) serves HTTPS beside it. DOJO_REUSE_PORT
+// sets SO_REUSEPORT on both.
+//
+// Sockets a previous process handed over take the place of the listeners
+// they were bound for, keeping their options.
+pub fn from_env() -> Result<Vec<Listener>, String> {
+    let inherited = crate::systemd::listeners()?;
+    if !inherited.is_empty() {
+        return Ok(inherited.into_iter().map(Listener::from).collect());
+    }
+    let mut listeners = configured()?;
+    crate::handover::adopt(&mut listeners)?;
+    Ok(listeners)
+}
+
+fn configured() -> Result<Vec<Listener>, String> {
+    if let Ok(spec) = env::var("DOJO_LISTEN") {
+        return spec
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(parse_listener)
+            .collect();
+    }
+
+    let addr = env::var("DOJO_ADDR").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
+    let mut addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| format!("DOJO_ADDR must be host:port, got {:?}", addr))?;
+    if let Ok(port) = env::var("DOJO_PORT") {
+        addr.set_port(port.parse().map_err(|_| format!("DOJO_PORT must be a port number, got {:?}", port))?);
+    }
+    let reuse_port = env::var("DOJO_REUSE_PORT").is_ok_and(|v| v == "1" || v == "true");
+    let mut listeners = vec![Listener::from(Bind::Tcp(TcpBind { addr, v6only: false, reuse_port }))];
+    match (env::var("DOJO_TLS_CERT"), env::var("DOJO_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            let addr = env::var("DOJO_TLS_ADDR").unwrap_or_else(|_| "127.0.0.1:3443".to_string());
+            let addr = addr
+                .parse()
+                .map_err(|_| format!("DOJO_TLS_ADDR must be host:port, got {:?}", addr))?;
+            listeners.push(Listener {
+                tls: Some(Tls::load(cert.as_ref(), key.as_ref())?),
+                ..Listener::from(Bind::Tcp(TcpBind { addr, v6only: false, reuse_port }))
+            });
+        }
+        (Err(_), Err(_)) => {}
+        _ => return Err("DOJO_TLS_CERT and DOJO_TLS_KEY have to be set together".to_string()),
+    }
+    if let Ok(path) = env::var("DOJO_UNIX_SOCKET") {
+        let mode = match env::var("DOJO_UNIX_SOCKET_MODE") {
+            Ok(mode) => Some(parse_mode(&mode)?),
+            Err(_) => None,
+        };
+        listeners.push(Listener::from(Bind::Unix(UnixBind { path: path.into(), mode })));
+    }
+    Ok(listeners)
+}
+
+fn parse_listener(entry: &str) -> Result<Listener, String> {
+    let mut words = entry.split_whitespace();
+    let address = words.next().unwrap_or_default();
+    let mut bind = match address.strip_prefix("unix:") {
+        Some(path) => Bind::Unix(UnixBind {
+            path: path.into(),
+            mode: None,
+        }),
+        None => Bind::Tcp(TcpBind {
+            addr: address
+                .parse()
+                .map_err(|_| format!("invalid listen address {:?}", address))?,
+            v6only: false,
+            reuse_port: false,
+        }),
+    };
+    let mut stack = Stack::default();
+    let (mut cert, mut private_key) = (None, None);
+    for option in words {
+        let (key, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("invalid listener option {:?}, expected key=value", option))?;
+        match (key, &mut bind) {
+            ("mode", Bind::Unix(unix)) => unix.mode = Some(parse_mode(value)?),
+            ("v6only", Bind::Tcp(tcp)) if tcp.addr.is_ipv6() => {
+                tcp.v6only = value
+                    .parse()
+                    .map_err(|_| format!("v6only must be true or false, got {:?}", value))?
+            }
+            ("reuseport", Bind::Tcp(tcp)) => {
+                tcp.reuse_port = value
+                    .parse()
+                    .map_err(|_| format!("reuseport must be true or false, got {:?}", value))?
+            }
+            ("cert", Bind::Tcp(_)) => cert = Some(PathBuf::from(value)),
+            ("key", Bind::Tcp(_)) => private_key = Some(PathBuf::from(value)),
+            _ if stack.set_option(key, value)? => {}
+            _ => return Err(format!("unsupported listener option {:?} for {}", key, address)),
+        }
+    }
+    let tls = match (cert, private_key) {
+        (Some(cert), Some(key)) => Some(Tls::load(&cert, &key)?),
+        (None, None) => None,
+        _ => return Err(format!("{} needs both cert and key to serve HTTPS", address)),
+    };
+    Ok(Listener { bind, stack, tls })
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("socket mode must be octal, got {:?}", mode))
+}
+
+impl TcpBind {
+    // `[::]` falls back to `0.0.0.0` on hosts without IPv6, so dual-stack
+    // can be the configured default everywhere.
+    pub fn bind(&self) -> io::Result<TcpListener> {
+        match bind_tcp(self.addr, self.v6only, self.reuse_port) {
+            Err(e) if self.addr.ip() == Ipv6Addr::UNSPECIFIED && ipv6_unavailable(&e) => {
+                let fallback = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.addr.port()));
+                tracing::warn!("IPv6 unavailable ({}), listening on {} instead", e, fallback);
+                bind_tcp(fallback, false, self.reuse_port)
+            }
+            result => result,
+        }
+    }
+
+    // Whether a socket bound for this would be `addr`, allowing for the
+    // IPv4 fallback.
+    pub fn matches(&self, addr: SocketAddr) -> bool {
+        addr == self.addr
+            || (self.addr.ip() == Ipv6Addr::UNSPECIFIED
+                && addr.ip() == Ipv4Addr::UNSPECIFIED
+                && addr.port() == self.addr.port())
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, v6only: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+// socket2 only offers this with its `all` feature.
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    // SAFETY: the descriptor is the socket's own and `on` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            (&on as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ipv6_unavailable(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::AddrNotAvailable || e.raw_os_error() == Some(libc::EAFNOSUPPORT)
+}
+
+impl Bind {
+    // Binds now rather than when serving starts, so the socket can be kept
+    // for a handover.
+    pub fn bound(self) -> Result<Bind, String> {
+        let label = self.to_string();
+        let error = |e: io::Error| format!("{}: {}", label, e);
+        match self {
+            Bind::Tcp(tcp) => tcp.bind().map(Bind::InheritedTcp).map_err(error),
+            Bind::Unix(unix) => {
+                unix.remove_stale().map_err(error)?;
+                let listener = net::UnixListener::bind(&unix.path).map_err(error)?;
+                unix.apply_mode().map_err(error)?;
+                Ok(Bind::InheritedUnix(listener))
+            }
+            bound => Ok(bound),
+        }
+    }
+
+    // The listening socket, for a bound one.
+    pub fn socket(&self) -> Option<io::Result<OwnedFd>> {
+        match self {
+            Bind::InheritedTcp(listener) => Some(listener.try_clone().map(OwnedFd::from)),
+            Bind::InheritedUnix(listener) => Some(listener.try_clone().map(OwnedFd::from)),
+            Bind::Tcp(_) | Bind::Unix(_) => None,
+        }
+    }
+
+    // Takes over a listening socket this process was given.
+    pub fn from_fd(fd: RawFd) -> Result<Bind, String> {
+        // SAFETY: whoever passed the descriptor handed over its ownership,
+        // and each one is only wrapped once.
+        let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+        if tcp.local_addr().is_ok() {
+            return Ok(Bind::InheritedTcp(tcp));
+        }
+        let unix = net::UnixListener::from(OwnedFd::from(tcp));
+        if unix.local_addr().is_err() {
+            return Err(format!("inherited descriptor {} is not a TCP or Unix socket", fd));
+        }
+        Ok(Bind::InheritedUnix(unix))
+    }
+}
+
+impl UnixBind {
+    // A socket file left behind by a previous run would make the bind fail,
+    // but anything that is not a socket is left alone.
+    pub fn remove_stale(&self) -> io::Result<()> {
+        match fs::symlink_metadata(&self.path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(&self.path),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", self.path.display()),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn apply_mode(&self) -> io::Result<()> {
+        match self.mode {
+            Some(mode) => fs::set_permissions(&self.path, fs::Permissions::from_mode(mode)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn bind(&self) -> io::Result<UnixListener> {
+        self.remove_stale()?;
+        let listener = UnixListener::bind(&self.path)?;
+        self.apply_mode()?;
+        Ok(listener)
+    }
+}
@@ -0,0 +1,420 @@
+use books_model::Book;
+use hyper::{header, header::HeaderValue, Response, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::{
+    body::{self, Body},
+    codec,
+    fields::Fields,
+    filter::{self, Expression},
+    html::{self, Format},
+    json_response,
+    links::{LinkMap, Links},
+    sort::{self, Sort, Sortable},
+};
+
+pub const TOTAL_COUNT: &str = "x-total-count";
+pub const NEXT_CURSOR: &str = "x-next-cursor";
+
+// Listings of more books than this are streamed, a chunk of books at a
+// time, rather than serialized into one string before any of it is sent.
+const STREAMED: usize = 1000;
+const CHUNK: usize = 500;
+
+// What a book can be sorted on, or trimmed down to with ?fields=.
+pub const BOOK_FIELDS: &[&str] =
+    &["id", "title", "author", "isbn", "author_id", "tags", "published_year", "publisher", "pages", "language"];
+
+// A single book also has its average rating and availability.
+pub const BOOK_DETAIL_FIELDS: &[&str] = &[
+    "id",
+    "title",
+    "author",
+    "isbn",
+    "author_id",
+    "tags",
+    "published_year",
+    "publisher",
+    "pages",
+    "language",
+    "average_rating",
+    "available",
+];
+
+// ?limit= and ?offset= page through the catalog in id order. Without a limit
+// everything from the offset on is returned.
+//
+// ?cursor= resumes after the last book of an earlier page instead. Ids only
+// grow, so books added while a client pages through are never skipped or
+// seen twice, which offsets can't promise. Every limited page that isn't
+// the last one carries the cursor for the next in X-Next-Cursor and a
+// rel="next" Link.
+//
+// ?sort= orders the listing by other keys first, see `sort`. Cursors only
+// follow id order, so sorted listings page with offsets.
+#[derive(Debug, Default, Deserialize)]
+pub struct Page {
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+    cursor: Option<String>,
+    #[serde(default, deserialize_with = "sort::deserialize")]
+    sort: Sort<Book>,
+}
+
+impl Sortable for Book {
+    const KEYS: &'static [&'static str] = BOOK_FIELDS;
+
+    fn compare(&self, other: &Self, key: &str) -> Ordering {
+        match key {
+            "title" => sort::text(&self.title, &other.title),
+            "author" => sort::text(&self.author, &other.author),
+            "isbn" => self.isbn.cmp(&other.isbn),
+            "author_id" => self.author_id.cmp(&other.author_id),
+            "tags" => self.tags.cmp(&other.tags),
+            "published_year" => self.edition.published_year.cmp(&other.edition.published_year),
+            "publisher" => match (&self.edition.publisher, &other.edition.publisher) {
+                (Some(a), Some(b)) => sort::text(a, b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            },
+            "pages" => self.edition.pages.cmp(&other.edition.pages),
+            "language" => self.edition.language.cmp(&other.edition.language),
+            _ => self.id.cmp(&other.id),
+        }
+    }
+}
+
+// ?author=, ?author_id=, ?title_contains=, ?has_isbn= and ?tag= narrow the
+// listing down before it's paged. The text filters ignore case; author has
+// to match the whole name. ?filter= takes an expression for anything more,
+// see `filter`, and has to hold as well. ?include_deleted=true lists the
+// trash alongside.
+// ?as_of= lists the catalog as it was at a time in milliseconds since the
+// epoch instead, see `BookStore::as_of`.
+#[derive(Debug, Default, Deserialize)]
+pub struct Filter {
+    author: Option<String>,
+    author_id: Option<u64>,
+    title_contains: Option<String>,
+    has_isbn: Option<bool>,
+    tag: Option<String>,
+    #[serde(default, deserialize_with = "filter::deserialize")]
+    filter: Option<Expression>,
+    #[serde(default)]
+    pub include_deleted: bool,
+    pub as_of: Option<u64>,
+}
+
+impl Filter {
+    // A filter given as a JSON object, as DELETE /books takes it. Unlike in
+    // a query string an unknown name is an error, since ignoring it would
+    // match more books than meant.
+    pub fn from_json(value: serde_json::Value) -> Result<Self, String> {
+        const NAMES: &[&str] = &["author", "author_id", "title_contains", "has_isbn", "tag", "filter"];
+        if let Some(name) = value.as_object().and_then(|object| object.keys().find(|name| !NAMES.contains(&name.as_str()))) {
+            return Err(format!("unknown filter {:?}, expected one of {}", name, NAMES.join(", ")));
+        }
+        serde_json::from_value(value).map_err(|e| format!("invalid filter: {}", e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.author.is_none()
+            && self.author_id.is_none()
+            && self.title_contains.is_none()
+            && self.has_isbn.is_none()
+            && self.tag.is_none()
+            && self.filter.is_none()
+            && !self.include_deleted
+            && self.as_of.is_none()
+    }
+
+    // The tag asked for, as tags are stored.
+    pub fn tag(&self) -> Option<String> {
+        self.tag.as_ref().map(|tag| tag.trim().to_lowercase())
+    }
+
+    pub fn matches(&self, book: &Book) -> bool {
+        self.author.as_ref().is_none_or(|author| book.author.to_lowercase() == author.to_lowercase())
+            && self
+                .title_contains
+                .as_ref()
+                .is_none_or(|part| book.title.to_lowercase().contains(&part.to_lowercase()))
+            && self.has_isbn.is_none_or(|has_isbn| book.isbn.is_some() == has_isbn)
+            && self.author_id.is_none_or(|id| book.author_id == Some(id))
+            && self.tag().is_none_or(|tag| book.tags.contains(&tag))
+            && self.filter.as_ref().is_none_or(|filter| filter.matches(book))
+    }
+}
+
+// Hex of "after:<id>". Clients aren't meant to read or build one, so the
+// format can change.
+pub fn encode_cursor(after: u64) -> String {
+    codec::hex(format!("after:{}", after).as_bytes())
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<u64> {
+    String::from_utf8(codec::unhex(cursor)?).ok()?.strip_prefix("after:")?.parse().ok()
+}
+
+impl Page {
+    // The id the page starts after, when it's given by a cursor.
+    pub fn after(&self) -> Result<Option<u64>, &'static str> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        if self.offset > 0 {
+            return Err("cursor and offset can't be combined");
+        }
+        if !self.sort.is_empty() {
+            return Err("cursor and sort can't be combined");
+        }
+        decode_cursor(cursor).map(Some).ok_or("Invalid cursor")
+    }
+
+    // Cuts the page out of `books`, which may hold more than it, and says
+    // whether more books follow.
+    pub fn apply(&self, mut books: Vec<Book>, after: Option<u64>) -> Listed {
+        books.sort_by_key(|book| book.id);
+        if let Some(after) = after {
+            books.retain(|book| book.id > after);
+        }
+        self.sort.apply(&mut books);
+        let mut books: Vec<Book> = books.into_iter().skip(self.offset).collect();
+        let more = self.limit.is_some_and(|limit| books.len() > limit);
+        if let Some(limit) = self.limit {
+            books.truncate(limit);
+        }
+        Listed { books, more }
+    }
+
+    // What to ask each shard for so the merged listing contains this page
+    // and tells whether more follow: the same cursor, and the first
+    // offset + limit + 1 books.
+    pub fn for_shards(&self) -> Vec<(&'static str, String)> {
+        let mut page = Vec::new();
+        if let Some(cursor) = &self.cursor {
+            page.push(("cursor", cursor.clone()));
+        }
+        if let Some(limit) = self.limit {
+            page.push(("limit", self.offset.saturating_add(limit).saturating_add(1).to_string()));
+        }
+        page
+    }
+
+    // Whether this is the start of the listing in id order, which the store
+    // can read like a cursor page from the beginning.
+    pub fn is_first(&self) -> bool {
+        self.cursor.is_none() && self.offset == 0 && self.sort.is_empty()
+    }
+
+    // Whether the whole listing has to be read. A limited cursor page only
+    // needs the books it holds, so X-Total-Count is left out there.
+    pub fn counts_total(&self) -> bool {
+        self.cursor.is_none() || self.limit.is_none()
+    }
+}
+
+pub struct Listed {
+    pub books: Vec<Book>,
+    pub more: bool,
+}
+
+// The body of a listing in versions with links, as a list can't carry its
+// own.
+#[derive(Serialize)]
+struct Collection<T> {
+    books: Vec<T>,
+    #[serde(rename = "_links")]
+    links: LinkMap,
+}
+
+// The query string with the page replaced by `page`, keeping any other
+// parameters.
+pub fn with_page(uri: &Uri, page: &[(&str, String)]) -> String {
+    let mut pairs: Vec<(String, String)> = serde_urlencoded::from_str(uri.query().unwrap_or("")).unwrap_or_default();
+    pairs.retain(|(name, _)| !matches!(name.as_str(), "limit" | "offset" | "cursor"));
+    pairs.extend(page.iter().map(|(name, value)| (name.to_string(), value.clone())));
+    serde_urlencoded::to_string(pairs).unwrap_or_default()
+}
+
+// `self`, `next` and `prev` for a collection. Unsorted pages lead on with
+// the same cursor as the Link header, sorted ones with offsets; only offset
+// pages lead back, since a cursor can't.
+fn page_links(links: &Links, page: &Page, listed: &Listed, cursor: Option<&(String, usize)>) -> LinkMap {
+    let uri = links.uri();
+    let path = crate::api().unversioned(uri.path());
+    let at = |query: String| match query.is_empty() {
+        true => links.to(path),
+        false => links.to(&format!("{}?{}", path, query)),
+    };
+    let mut map = LinkMap::new();
+    map.extend(at(uri.query().unwrap_or("").to_string()).map(|link| ("self", link)));
+    let next = match (cursor, page.limit) {
+        (Some((cursor, limit)), _) => Some(with_page(uri, &[("cursor", cursor.clone()), ("limit", limit.to_string())])),
+        (None, Some(limit)) if listed.more => Some(with_page(
+            uri,
+            &[("offset", page.offset.saturating_add(limit).to_string()), ("limit", limit.to_string())],
+        )),
+        _ => None,
+    };
+    map.extend(next.and_then(at).map(|link| ("next", link)));
+    if let (Some(limit), true, None) = (page.limit, page.offset > 0, &page.cursor) {
+        let prev = with_page(uri, &[("offset", page.offset.saturating_sub(limit).to_string()), ("limit", limit.to_string())]);
+        map.extend(at(prev).map(|link| ("prev", link)));
+    }
+    map
+}
+
+// A JSON listing written between `before` and `after`, with `write`
+// adding each book, sent in chunks as the client reads them. What's sent
+// can't be taken back, so a book that fails to serialize cuts the body
+// short.
+fn streamed(
+    before: &str,
+    books: Vec<Book>,
+    after: String,
+    write: impl Fn(&mut Vec<u8>, &Book) -> serde_json::Result<()> + Send + 'static,
+) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let mut chunk = before.as_bytes().to_vec();
+    tokio::spawn(async move {
+        for (index, book) in books.iter().enumerate() {
+            if index > 0 {
+                chunk.push(b',');
+            }
+            if let Err(e) = write(&mut chunk, book) {
+                tracing::warn!("listing stopped at book {}: {}", book.id, e);
+                sender.abort();
+                return;
+            }
+            if (index + 1) % CHUNK == 0 && sender.send_data(std::mem::take(&mut chunk).into()).await.is_err() {
+                return;
+            }
+        }
+        chunk.extend(after.into_bytes());
+        let _ = sender.send_data(chunk.into()).await;
+    });
+    Response::builder().status(StatusCode::OK).header(header::CONTENT_TYPE, "application/json").body(body).unwrap()
+}
+
+// The page itself, with the size of the whole listing in X-Total-Count
+// when it's known and the way to the next page when there is one.
+pub fn respond(
+    format: Format,
+    links: &Links,
+    page: &Page,
+    fields: &Fields,
+    listed: Listed,
+    total: Option<usize>,
+) -> Result<Response<Body>, body::Error> {
+    let next = match (listed.more, listed.books.last(), page.limit) {
+        (true, Some(last), Some(limit)) if page.sort.is_empty() => Some((encode_cursor(last.id), limit)),
+        _ => None,
+    };
+    let mut response = match format {
+        Format::Html => html::catalog(listed.books),
+        Format::Json if listed.books.len() > STREAMED && links.enabled() => {
+            let around = page_links(links, page, &listed, next.as_ref());
+            let after = format!("],\"_links\":{}}}", serde_json::to_string(&around).unwrap_or_default());
+            let (links, fields) = (links.clone(), fields.clone());
+            streamed("{\"books\":[", listed.books, after, move |out, book| {
+                serde_json::to_writer(out, &links.book(fields.project(book), book))
+            })
+        }
+        Format::Json if listed.books.len() > STREAMED => {
+            let fields = fields.clone();
+            streamed("[", listed.books, "]".to_string(), move |out, book| serde_json::to_writer(out, &fields.project(book)))
+        }
+        Format::Json if links.enabled() => {
+            let collection = Collection {
+                links: page_links(links, page, &listed, next.as_ref()),
+                books: listed.books.iter().map(|book| links.book(fields.project(book), book)).collect(),
+            };
+            json_response(StatusCode::OK, &collection)?
+        }
+        Format::Json => json_response(StatusCode::OK, &fields.project(&listed.books))?,
+    };
+    let headers = response.headers_mut();
+    if let Some(total) = total {
+        headers.insert(TOTAL_COUNT, HeaderValue::from(total));
+    }
+    if let Some((cursor, limit)) = next {
+        let uri = links.uri();
+        let query = with_page(uri, &[("cursor", cursor.clone()), ("limit", limit.to_string())]);
+        let link = format!("<{}?{}>; rel=\"next\"", uri.path(), query);
+        if let (Ok(cursor), Ok(link)) = (HeaderValue::from_str(&cursor), HeaderValue::from_str(&link)) {
+            headers.insert(NEXT_CURSOR, cursor);
+            headers.insert(header::LINK, link);
+        }
+    }
+    Ok(html::vary_accept(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(id: u64) -> Book {
+        serde_json::from_value(serde_json::json!({ "id": id, "title": format!("Book {}", id), "author": "Someone", "isbn": null })).unwrap()
+    }
+
+    fn page(query: serde_json::Value) -> Page {
+        serde_json::from_value(query).unwrap()
+    }
+
+    fn ids(listed: &Listed) -> Vec<u64> {
+        listed.books.iter().map(|book| book.id).collect()
+    }
+
+    #[test]
+    fn cursors_round_trip_and_reject_anything_else() {
+        assert_eq!(encode_cursor(42), "61667465723a3432");
+        for after in [0, 42, u64::MAX] {
+            assert_eq!(decode_cursor(&encode_cursor(after)), Some(after));
+        }
+        for cursor in ["", "6", "zz", "61667465723a", "61667465723a2d31", "6265666f72653a3432"] {
+            assert_eq!(decode_cursor(cursor), None, "{:?}", cursor);
+        }
+    }
+
+    // Ids only grow, so books added between pages come after any cursor
+    // already handed out: none is skipped and none is shown twice.
+    #[test]
+    fn pages_resume_after_the_cursor_whatever_was_added() {
+        let mut catalog: Vec<Book> = [5, 1, 3, 7].into_iter().map(book).collect();
+        let first = page(serde_json::json!({ "limit": 2 })).apply(catalog.clone(), None);
+        assert_eq!((ids(&first), first.more), (vec![1, 3], true));
+
+        catalog.extend([book(9), book(8)]);
+        let cursor = encode_cursor(first.books.last().unwrap().id);
+        let next = page(serde_json::json!({ "limit": 2, "cursor": cursor }));
+        let after = next.after().unwrap();
+        assert_eq!(after, Some(3));
+        let second = next.apply(catalog.clone(), after);
+        assert_eq!((ids(&second), second.more), (vec![5, 7], true));
+
+        let last = page(serde_json::json!({ "limit": 2, "cursor": encode_cursor(7) })).apply(catalog, Some(7));
+        assert_eq!((ids(&last), last.more), (vec![8, 9], false));
+    }
+
+    #[test]
+    fn cursors_only_page_in_id_order() {
+        let cursor = encode_cursor(3);
+        let after = |query: serde_json::Value| page(query).after();
+        assert_eq!(after(serde_json::json!({ "cursor": cursor, "offset": 2 })), Err("cursor and offset can't be combined"));
+        assert_eq!(after(serde_json::json!({ "cursor": cursor, "sort": "title" })), Err("cursor and sort can't be combined"));
+        assert_eq!(after(serde_json::json!({ "cursor": "nonsense" })), Err("Invalid cursor"));
+        assert_eq!(after(serde_json::json!({})), Ok(None));
+    }
+
+    // Each shard is asked for enough to fill the page and tell if more follow.
+    #[test]
+    fn shards_get_the_cursor_and_one_past_the_page() {
+        let cursor = encode_cursor(3);
+        let paged = page(serde_json::json!({ "cursor": cursor, "limit": 10 }));
+        assert_eq!(paged.for_shards(), [("cursor", cursor.clone()), ("limit", "11".to_string())]);
+        assert!(!paged.is_first() && !paged.counts_total());
+        assert_eq!(page(serde_json::json!({ "offset": 5, "limit": 10 })).for_shards(), [("limit", "16".to_string())]);
+    }
+}
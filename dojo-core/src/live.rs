@@ -0,0 +1,120 @@
+use hyper::{header, Response, StatusCode};
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    body::{self, Body},
+    events::{Event, Topic},
+    extract::State,
+    SharedState,
+};
+
+// Events a subscriber can fall behind by before it misses some.
+const CAPACITY: usize = 1024;
+// Proxies drop connections that stay quiet for long.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// Changes to books as they're stored, for whoever is watching live.
+// Nothing is kept: a subscriber sees what happens after it subscribes.
+pub struct Live {
+    sender: broadcast::Sender<Event>,
+    closed: watch::Sender<bool>,
+    source: String,
+    seq: AtomicU64,
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        Live {
+            sender: broadcast::channel(CAPACITY).0,
+            closed: watch::channel(false).0,
+            source: crate::leader::node_name(),
+            seq: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Live {
+    // The event for a change to a book, or None when nobody is watching.
+    pub fn event(&self, kind: &'static str, key: u64, data: impl Serialize) -> Option<Event> {
+        if !kind.starts_with("book.") || self.sender.receiver_count() == 0 {
+            return None;
+        }
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        Some(Event::new(&self.source, seq, Topic::Books, kind, key, data))
+    }
+
+    pub fn send(&self, events: impl IntoIterator<Item = Event>) {
+        for event in events {
+            let _ = self.sender.send(event);
+        }
+    }
+
+    // For changes made outside `Storage`, once they're stored.
+    pub fn announce(&self, kind: &'static str, book: &crate::Book) {
+        self.send(self.event(kind, book.id, book));
+    }
+
+    // The changes from now on, and whether the server is shutting down.
+    pub fn subscribe(&self) -> (broadcast::Receiver<Event>, watch::Receiver<bool>) {
+        (self.sender.subscribe(), self.closed.subscribe())
+    }
+
+    // Whether the server is shutting down, for waits that aren't streams.
+    pub fn closing(&self) -> watch::Receiver<bool> {
+        self.closed.subscribe()
+    }
+
+    // Ends every stream, so a shutdown doesn't wait for them.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+}
+
+fn frame(event: &Event) -> String {
+    let data = serde_json::to_string(event).expect("an event serializes");
+    format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind, data)
+}
+
+// GET /books/events: a text/event-stream with an event for each book
+// created, updated or deleted, carrying the broker's envelope. A client
+// that falls too far behind gets a `resync` event saying how many it
+// missed, and should fetch the catalog again. Last-Event-ID isn't
+// honoured, as there's no history to replay.
+pub async fn stream_events(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let (mut events, mut closed) = state.live().subscribe();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        // Tells the client to wait a few seconds before reconnecting.
+        if sender.send_data("retry: 3000\n\n".into()).await.is_err() {
+            return;
+        }
+        let mut keep_alive = tokio::time::interval_at(tokio::time::Instant::now() + KEEP_ALIVE, KEEP_ALIVE);
+        loop {
+            let chunk = tokio::select! {
+                received = events.recv() => match received {
+                    Ok(event) => frame(&event),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        format!("event: resync\ndata: {{\"missed\":{}}}\n\n", missed)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+                _ = closed.wait_for(|closed| *closed) => return,
+            };
+            if sender.send_data(chunk.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap())
+}
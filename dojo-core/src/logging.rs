@@ -0,0 +1,244 @@
+use http_body::Body as HttpBody;
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{fmt, future::Future, sync::OnceLock};
+use tokio::time::Instant;
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Span, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    field::RecordFields,
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Registry,
+};
+
+use crate::{
+    body::{self, Body},
+    error::ApiError,
+    extract, json_response, otel, problem,
+    request_id::RequestId,
+};
+
+type Filter = reload::Handle<EnvFilter, Registry>;
+
+// The filter `init` installed, which PUT /admin/log-level replaces.
+static FILTER: OnceLock<Filter> = OnceLock::new();
+
+// Logs go to stderr, one line per event. DOJO_LOG_FORMAT picks `pretty`
+// (the default, for people, with the fields of the spans the event
+// happened in) or `json` (an object per line, for collectors). DOJO_LOG
+// filters by level and target, like `info` (the default) or
+// `dojo_core=debug,hyper=warn`, and can be changed while running with
+// PUT /admin/log-level.
+//
+// Spans also go to an OpenTelemetry collector when one is configured, see
+// `otel`.
+pub fn init() -> Result<(), String> {
+    let filter = match std::env::var("DOJO_LOG") {
+        Ok(directives) => EnvFilter::try_new(&directives).map_err(|e| format!("invalid DOJO_LOG {:?}: {}", directives, e))?,
+        Err(_) => EnvFilter::new("info"),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(filter).with(otel::Tracer::from_env()?);
+    let lines = tracing_subscriber::fmt::layer().with_writer(std::io::stderr).with_ansi(false);
+    let installed = match std::env::var("DOJO_LOG_FORMAT").as_deref() {
+        Err(_) | Ok("pretty") => registry.with(lines).try_init(),
+        Ok("json") => registry.with(lines.event_format(Json).fmt_fields(JsonFields)).try_init(),
+        Ok(other) => return Err(format!("DOJO_LOG_FORMAT must be pretty or json, got {:?}", other)),
+    };
+    installed.map_err(|e| e.to_string())?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevel {
+    level: String,
+}
+
+pub async fn get_level() -> Result<Response<Body>, ApiError> {
+    let Some(filter) = FILTER.get() else {
+        return Ok(not_set_up());
+    };
+    let level = filter.with_current(EnvFilter::to_string).unwrap_or_default();
+    Ok(json_response(StatusCode::OK, &LogLevel { level })?)
+}
+
+// Takes directives like DOJO_LOG's, which last until the next change or
+// a restart.
+pub async fn put_level(extract::Json(request): extract::Json<LogLevel>) -> Result<Response<Body>, ApiError> {
+    let Some(filter) = FILTER.get() else {
+        return Ok(not_set_up());
+    };
+    let level = set_level(filter, &request.level).map_err(ApiError::BadRequest)?;
+    tracing::info!(level = level.as_str(), "log level changed");
+    Ok(json_response(StatusCode::OK, &LogLevel { level })?)
+}
+
+fn set_level(filter: &Filter, directives: &str) -> Result<String, String> {
+    let new = EnvFilter::try_new(directives).map_err(|e| format!("invalid log level {:?}: {}", directives, e))?;
+    let level = new.to_string();
+    filter.reload(new).map_err(|e| e.to_string())?;
+    Ok(level)
+}
+
+// Only when the server runs without its logging, as in tests.
+fn not_set_up() -> Response<Body> {
+    problem::respond(StatusCode::SERVICE_UNAVAILABLE, "logging isn't set up in this process")
+}
+
+// A request's span, so whatever is logged while handling it carries its
+// ID, method and path. The query is left out, as it may carry tokens.
+// A traceparent header puts it in the caller's trace.
+pub fn span(req: &Request<Body>, id: &RequestId) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = id.as_str(),
+        method = %req.method(),
+        path = req.uri().path(),
+        traceparent = req.headers().get("traceparent").and_then(|value| value.to_str().ok()),
+        status = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+        bytes = tracing::field::Empty,
+    )
+}
+
+// Runs the request in its span and logs how it ended.
+pub async fn finish(
+    response: impl Future<Output = Result<Response<Body>, body::Error>>,
+) -> Result<Response<Body>, body::Error> {
+    let start = Instant::now();
+    let result = response.await;
+    let span = Span::current();
+    span.record("latency_ms", start.elapsed().as_secs_f64() * 1000.0);
+    match &result {
+        Ok(response) => {
+            span.record("status", response.status().as_u16());
+            // Streamed bodies have no size until they're sent.
+            if let Some(bytes) = response.body().size_hint().exact() {
+                span.record("bytes", bytes);
+            }
+            tracing::info!("request finished");
+        }
+        Err(e) => tracing::warn!("request failed: {}", e),
+    }
+    result
+}
+
+// Collects fields as JSON values, keeping numbers and booleans as such.
+#[derive(Default)]
+pub struct JsonVisitor(pub Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::String(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+}
+
+// Keeps each span's fields as a JSON object, merging fields recorded later.
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(&self, current: &'writer mut FormattedFields<Self>, fields: &span::Record<'_>) -> fmt::Result {
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+// An event as one object: its time, level, target and fields, and the
+// fields of the spans it happened in, outermost first.
+struct Json;
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), Value::String(timestamp));
+        line.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        line.insert("target".to_string(), Value::from(metadata.target()));
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+        line.extend(fields.0);
+        let spans: Vec<Value> = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let mut object = span
+                    .extensions()
+                    .get::<FormattedFields<N>>()
+                    .and_then(|fields| serde_json::from_str(&fields.fields).ok())
+                    .unwrap_or_else(Map::new);
+                object.insert("name".to_string(), Value::from(span.name()));
+                Value::Object(object)
+            })
+            .collect();
+        if !spans.is_empty() {
+            line.insert("spans".to_string(), Value::Array(spans));
+        }
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_level_changes_while_running() {
+        let (layer, filter) = reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!tracing::enabled!(tracing::Level::DEBUG));
+            assert_eq!(set_level(&filter, "debug").unwrap(), "debug");
+            tracing::callsite::rebuild_interest_cache();
+            assert!(tracing::enabled!(tracing::Level::DEBUG));
+            assert!(set_level(&filter, "dojo_core=loud").is_err());
+            assert_eq!(filter.with_current(EnvFilter::to_string).unwrap(), "debug");
+        });
+    }
+}
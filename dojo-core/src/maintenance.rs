@@ -0,0 +1,146 @@
+use hyper::{header, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    body::{self, Body},
+    error::ApiError,
+    extract::{Json, State},
+    json_response, problem,
+    stack::Next,
+    SharedState,
+};
+
+const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    #[default]
+    Off,
+    // Reads are served, writes are refused.
+    ReadOnly,
+    // Nothing but the operator endpoints and probes is served.
+    Unavailable,
+}
+
+impl Mode {
+    fn refuses(self, method: &Method, path: &str) -> bool {
+        let exempt = path == "/metrics"
+            || path == "/health"
+            || path == "/ready"
+            || path.starts_with("/admin/")
+            || path.starts_with("/debug/");
+        match self {
+            _ if exempt => false,
+            Mode::Off => false,
+            Mode::ReadOnly => !method.is_safe(),
+            Mode::Unavailable => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    mode: Mode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    // Sent as Retry-After with every refusal.
+    retry_after_secs: u64,
+    // Milliseconds since the epoch, while the server is in maintenance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Toggle {
+    mode: Mode,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    retry_after_secs: Option<u64>,
+}
+
+// Set through POST /admin/maintenance while a backup, restore or storage
+// migration runs. It's this instance's alone and doesn't survive a restart.
+#[derive(Debug, Default)]
+pub struct Maintenance(Mutex<Status>);
+
+impl Maintenance {
+    pub fn mode(&self) -> Mode {
+        self.0.lock().unwrap().mode
+    }
+}
+
+pub async fn status(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let status = state.maintenance.0.lock().unwrap().clone();
+    Ok(json_response(StatusCode::OK, &status)?)
+}
+
+pub async fn toggle(
+    State(state): State<SharedState>,
+    Json(toggle): Json<Toggle>,
+) -> Result<Response<Body>, ApiError> {
+    if toggle.retry_after_secs == Some(0) {
+        return Err(ApiError::BadRequest("retry_after_secs must be at least 1".to_string()));
+    }
+    let status = {
+        let mut status = state.maintenance.0.lock().unwrap();
+        let since = match (status.since, toggle.mode) {
+            (_, Mode::Off) => None,
+            (Some(since), _) => Some(since),
+            (None, _) => Some(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)),
+        };
+        *status = Status {
+            mode: toggle.mode,
+            reason: toggle.reason.filter(|_| toggle.mode != Mode::Off),
+            retry_after_secs: toggle.retry_after_secs.unwrap_or(DEFAULT_RETRY_AFTER_SECS),
+            since,
+        };
+        status.clone()
+    };
+    match status.mode {
+        Mode::Off => tracing::warn!("maintenance over"),
+        mode => tracing::warn!(?mode, reason = status.reason.as_deref(), "in maintenance"),
+    }
+    Ok(json_response(StatusCode::OK, &status)?)
+}
+
+// Answers what the mode refuses with a 503 and Retry-After.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let refused = {
+        let status = state.maintenance.0.lock().unwrap();
+        status.mode.refuses(req.method(), req.uri().path()).then(|| status.clone())
+    };
+    let Some(status) = refused else {
+        return next.run(req, state).await;
+    };
+    let detail = status.reason.unwrap_or_else(|| match status.mode {
+        Mode::ReadOnly => "the server is read-only for maintenance".to_string(),
+        _ => "the server is down for maintenance".to_string(),
+    });
+    let mut response = problem::respond(StatusCode::SERVICE_UNAVAILABLE, detail);
+    response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(status.retry_after_secs));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_refuse_what_they_should() {
+        assert!(!Mode::Off.refuses(&Method::POST, "/books"));
+        assert!(!Mode::ReadOnly.refuses(&Method::GET, "/books"));
+        assert!(Mode::ReadOnly.refuses(&Method::POST, "/books"));
+        assert!(Mode::ReadOnly.refuses(&Method::DELETE, "/books/1"));
+        assert!(Mode::Unavailable.refuses(&Method::GET, "/books"));
+        assert!(!Mode::Unavailable.refuses(&Method::POST, "/admin/maintenance"));
+        assert!(!Mode::Unavailable.refuses(&Method::POST, "/admin/restore"));
+        assert!(!Mode::Unavailable.refuses(&Method::GET, "/ready"));
+    }
+}
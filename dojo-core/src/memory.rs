@@ -0,0 +1,133 @@
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use std::{collections::BTreeMap, mem};
+
+use crate::{
+    body::{self, Body},
+    extract::State,
+    json_response, Book, SharedState,
+};
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the `jemalloc` and `mimalloc` features are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[derive(Debug, Serialize)]
+pub struct MemoryReport {
+    pub rss_bytes: Option<u64>,
+    pub allocator: &'static str,
+    pub allocator_stats: BTreeMap<&'static str, u64>,
+    pub subsystems: BTreeMap<&'static str, usize>,
+}
+
+pub async fn report(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let mut subsystems = BTreeMap::new();
+    let store = state
+        .read_storage("memory_estimate", String::new, |storage| {
+            storage.books.len() * mem::size_of::<(u64, Book)>()
+                + storage.books.values().map(book_heap_bytes).sum::<usize>()
+        })
+        .await;
+    if let Ok(bytes) = store {
+        subsystems.insert("store", bytes);
+    }
+    subsystems.insert("tasks", state.tasks.lock().await.approx_bytes());
+    subsystems.insert("request_history", state.requests.approx_bytes());
+    subsystems.insert("audit", state.audit.approx_bytes());
+
+    let report = MemoryReport {
+        rss_bytes: rss_bytes(),
+        allocator: allocator_name(),
+        allocator_stats: allocator_stats(),
+        subsystems,
+    };
+    json_response(StatusCode::OK, &report)
+}
+
+fn book_heap_bytes(book: &Book) -> usize {
+    book.title.capacity() + book.author.capacity() + book.isbn.as_ref().map_or(0, String::capacity)
+}
+
+// /proc/self/statm reports sizes in pages; the second field is the resident set.
+fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+#[cfg(feature = "jemalloc")]
+fn allocator_name() -> &'static str {
+    "jemalloc"
+}
+
+#[cfg(feature = "mimalloc")]
+fn allocator_name() -> &'static str {
+    "mimalloc"
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn allocator_name() -> &'static str {
+    "system"
+}
+
+#[cfg(feature = "jemalloc")]
+fn allocator_stats() -> BTreeMap<&'static str, u64> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    let mut out = BTreeMap::new();
+    if epoch::advance().is_err() {
+        return out;
+    }
+    let readings = [
+        ("allocated", stats::allocated::read()),
+        ("active", stats::active::read()),
+        ("resident", stats::resident::read()),
+        ("mapped", stats::mapped::read()),
+        ("retained", stats::retained::read()),
+        ("metadata", stats::metadata::read()),
+    ];
+    for (name, value) in readings {
+        if let Ok(value) = value {
+            out.insert(name, value as u64);
+        }
+    }
+    out
+}
+
+#[cfg(feature = "mimalloc")]
+fn allocator_stats() -> BTreeMap<&'static str, u64> {
+    let (mut elapsed, mut user, mut system) = (0, 0, 0);
+    let (mut rss, mut peak_rss, mut commit, mut peak_commit, mut faults) = (0, 0, 0, 0, 0);
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed,
+            &mut user,
+            &mut system,
+            &mut rss,
+            &mut peak_rss,
+            &mut commit,
+            &mut peak_commit,
+            &mut faults,
+        );
+    }
+
+    BTreeMap::from([
+        ("current_rss", rss as u64),
+        ("peak_rss", peak_rss as u64),
+        ("current_commit", commit as u64),
+        ("peak_commit", peak_commit as u64),
+        ("page_faults", faults as u64),
+    ])
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn allocator_stats() -> BTreeMap<&'static str, u64> {
+    BTreeMap::new()
+}
@@ -0,0 +1,253 @@
+use books_model::Book;
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    body::Body,
+    codec::hex,
+    error::ApiError,
+    extract::{Path, State},
+    json_response,
+    store::BookStore,
+    SharedState, StorageError,
+};
+
+type Hash = [u8; 32];
+
+// Domain prefixes keep a leaf from ever hashing like an inner node.
+fn leaf_hash(book: &Book) -> Hash {
+    let record = serde_json::to_vec(book).unwrap_or_default();
+    Sha256::new().chain_update([0u8]).chain_update(record).finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new().chain_update([1u8]).chain_update(left).chain_update(right).finalize().into()
+}
+
+// A tree over the catalog, leaves ordered by book id. A level with an odd
+// count carries its last node up unchanged.
+struct Tree {
+    ids: Vec<u64>,
+    // levels[0] are the leaves; the last level holds only the root.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl Tree {
+    fn build(books: &[Book]) -> Self {
+        let mut books: Vec<&Book> = books.iter().collect();
+        books.sort_by_key(|book| book.id);
+        let mut levels = vec![books.iter().map(|book| leaf_hash(book)).collect::<Vec<Hash>>()];
+        while levels.last().is_some_and(|level| level.len() > 1) {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Tree {
+            ids: books.iter().map(|book| book.id).collect(),
+            levels,
+        }
+    }
+
+    // The hash of an empty catalog is the hash of nothing.
+    fn root(&self) -> Hash {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => Sha256::digest([]).into(),
+        }
+    }
+
+    fn proof(&self, index: usize) -> Vec<Step> {
+        let mut path = Vec::new();
+        let mut index = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                path.push(Step {
+                    side: if sibling < index { Side::Left } else { Side::Right },
+                    hash: hex(hash),
+                });
+            }
+            index /= 2;
+        }
+        path
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Step {
+    side: Side,
+    hash: String,
+}
+
+struct Anchor {
+    tree: Tree,
+    at: u64,
+}
+
+// The most recently anchored tree. Proofs are served against it, so they
+// stay valid for as long as the published root does.
+#[derive(Default)]
+pub struct Anchors {
+    latest: Mutex<Option<Anchor>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RootView {
+    pub root: String,
+    pub leaves: usize,
+    pub anchored_at: u64,
+}
+
+impl Anchor {
+    fn view(&self) -> RootView {
+        RootView {
+            root: hex(&self.tree.root()),
+            leaves: self.tree.ids.len(),
+            anchored_at: self.at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProofView {
+    pub book: Book,
+    pub leaf: String,
+    pub index: usize,
+    pub path: Vec<Step>,
+    #[serde(flatten)]
+    pub root: RootView,
+}
+
+async fn anchor(state: &SharedState) -> Result<RootView, StorageError> {
+    let books = state.list().await?;
+    let tree = Tree::build(&books);
+    let at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let anchor = Anchor { tree, at };
+    let view = anchor.view();
+    *state.merkle.latest.lock().unwrap() = Some(anchor);
+    Ok(view)
+}
+
+// Anchors on a schedule when DOJO_MERKLE_ANCHOR_INTERVAL_SECS is set.
+pub async fn run_scheduled(state: SharedState, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match anchor(&state).await {
+            Ok(view) => tracing::info!("anchored catalog root {} over {} books", view.root, view.leaves),
+            Err(e) => tracing::error!("anchoring the catalog root failed: {}", e),
+        }
+    }
+}
+
+pub async fn create_anchor(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    Ok(json_response(StatusCode::CREATED, &anchor(&state).await?)?)
+}
+
+pub async fn root(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let view = state.merkle.latest.lock().unwrap().as_ref().map(Anchor::view);
+    Ok(json_response(StatusCode::OK, &view.ok_or_else(ApiError::not_found)?)?)
+}
+
+// Verifying: start from SHA-256(0x00 || record), where the record is the
+// book's JSON exactly as returned here, then for each step hash
+// 0x01 || left || right with the step on the given side. The result must
+// equal the root.
+pub async fn proof(Path(id): Path<u64>, State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let book = state.get(id).await?.ok_or_else(ApiError::not_found)?;
+
+    let latest = state.merkle.latest.lock().unwrap();
+    let Some(anchor) = latest.as_ref() else {
+        return Err(ApiError::Conflict("no catalog root has been anchored yet".to_string()));
+    };
+    let Ok(index) = anchor.tree.ids.binary_search(&id) else {
+        return Err(ApiError::Conflict(format!("book {} was added after the last anchor", id)));
+    };
+    let leaf = leaf_hash(&book);
+    if anchor.tree.levels[0][index] != leaf {
+        return Err(ApiError::Conflict(format!("book {} changed after the last anchor", id)));
+    }
+    let view = ProofView {
+        book,
+        leaf: hex(&leaf),
+        index,
+        path: anchor.tree.proof(index),
+        root: anchor.view(),
+    };
+    drop(latest);
+    Ok(json_response(StatusCode::OK, &view)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::unhex;
+
+    fn catalog(titles: &[&str]) -> Vec<Book> {
+        let book = |(index, title): (usize, &&str)| {
+            serde_json::from_value(serde_json::json!({ "id": index + 1, "title": title, "author": "Someone", "isbn": null })).unwrap()
+        };
+        titles.iter().enumerate().map(book).collect()
+    }
+
+    // Follows a proof the way the comment on `proof` tells clients to.
+    fn verify(leaf: Hash, path: &[Step]) -> Hash {
+        path.iter().fold(leaf, |hash, step| {
+            let sibling: Hash = unhex(&step.hash).unwrap().try_into().unwrap();
+            match step.side {
+                Side::Left => node_hash(&sibling, &hash),
+                Side::Right => node_hash(&hash, &sibling),
+            }
+        })
+    }
+
+    #[test]
+    fn roots_hash_the_books_in_id_order() {
+        let mut books = catalog(&["Dune", "Ubik", "Solaris"]);
+        assert_eq!(serde_json::to_string(&books[0]).unwrap(), r#"{"id":1,"title":"Dune","author":"Someone","isbn":null}"#);
+        assert_eq!(hex(&leaf_hash(&books[0])), "1121613d941bd13a0d2fddb10c518d3c0a12b36afa1e1099058e1e1fbbaa7001");
+        let root = "4ca6cae236d2c26726c2dcbfa7a5e76fc1fef4aee7a5dc900d293b09627c0033";
+        assert_eq!(hex(&Tree::build(&books).root()), root);
+        books.reverse();
+        assert_eq!(hex(&Tree::build(&books).root()), root);
+        assert_eq!(hex(&Tree::build(&[]).root()), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    // Odd levels carry their last node up, so its proof skips that level.
+    #[test]
+    fn every_leaf_proves_its_way_to_the_root() {
+        let titles = ["Dune", "Ubik", "Solaris", "Anathem", "Neuromancer", "Emma", "Persuasion"];
+        for count in 1..=titles.len() {
+            let books = catalog(&titles[..count]);
+            let tree = Tree::build(&books);
+            for (index, book) in books.iter().enumerate() {
+                assert_eq!(verify(leaf_hash(book), &tree.proof(index)), tree.root(), "leaf {} of {}", index, count);
+            }
+        }
+        let tree = Tree::build(&catalog(&titles[..5]));
+        assert_eq!(tree.proof(4).len(), 1);
+        assert_eq!(tree.proof(0).len(), 3);
+        let forged = catalog(&["Dune (forged)"]).remove(0);
+        assert_ne!(verify(leaf_hash(&forged), &tree.proof(0)), tree.root());
+    }
+}
@@ -0,0 +1,176 @@
+use hyper::{header, Response, StatusCode};
+use std::fmt::Write;
+use tokio::runtime::Handle;
+
+use crate::{
+    body::{self, Body},
+    extract::State,
+    instrument::LATENCY_BUCKETS,
+    store::BookStore,
+    SharedState,
+};
+
+pub async fn render(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let mut out = String::new();
+    write_runtime_metrics(&mut out);
+    write_request_metrics(&mut out, &state);
+    write_storage_metrics(&mut out, &state);
+    write_cache_metrics(&mut out, &state);
+    write_catalog_metrics(&mut out, &state).await;
+    write_event_metrics(&mut out, &state).await;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(out))
+        .unwrap())
+}
+
+fn write_runtime_metrics(out: &mut String) {
+    let metrics = Handle::current().metrics();
+    let workers = metrics.num_workers();
+
+    gauge(out, "tokio_workers", "Number of runtime worker threads.", workers as f64);
+    gauge(out, "tokio_alive_tasks", "Number of tasks currently alive.", metrics.num_alive_tasks() as f64);
+    gauge(
+        out,
+        "tokio_global_queue_depth",
+        "Tasks waiting in the runtime's global queue.",
+        metrics.global_queue_depth() as f64,
+    );
+
+    header(out, "tokio_worker_busy_seconds_total", "counter", "Time each worker spent executing tasks.");
+    for worker in 0..workers {
+        let busy = metrics.worker_total_busy_duration(worker).as_secs_f64();
+        let _ = writeln!(out, "tokio_worker_busy_seconds_total{{worker=\"{}\"}} {}", worker, busy);
+    }
+
+    header(out, "tokio_worker_park_total", "counter", "Times each worker parked waiting for work.");
+    for worker in 0..workers {
+        let parks = metrics.worker_park_count(worker);
+        let _ = writeln!(out, "tokio_worker_park_total{{worker=\"{}\"}} {}", worker, parks);
+    }
+
+    #[cfg(tokio_unstable)]
+    write_unstable_runtime_metrics(out, &metrics);
+}
+
+// Poll and blocking-pool statistics are only available when the binary is
+// built with RUSTFLAGS="--cfg tokio_unstable".
+#[cfg(tokio_unstable)]
+fn write_unstable_runtime_metrics(out: &mut String, metrics: &tokio::runtime::RuntimeMetrics) {
+    let workers = metrics.num_workers();
+
+    gauge(
+        out,
+        "tokio_blocking_threads",
+        "Threads in the blocking pool.",
+        metrics.num_blocking_threads() as f64,
+    );
+    gauge(
+        out,
+        "tokio_idle_blocking_threads",
+        "Idle threads in the blocking pool.",
+        metrics.num_idle_blocking_threads() as f64,
+    );
+    gauge(
+        out,
+        "tokio_blocking_queue_depth",
+        "Tasks waiting for a blocking thread.",
+        metrics.blocking_queue_depth() as f64,
+    );
+
+    header(out, "tokio_worker_polls_total", "counter", "Tasks polled by each worker.");
+    for worker in 0..workers {
+        let polls = metrics.worker_poll_count(worker);
+        let _ = writeln!(out, "tokio_worker_polls_total{{worker=\"{}\"}} {}", worker, polls);
+    }
+
+    header(out, "tokio_worker_mean_poll_seconds", "gauge", "Moving average of task poll time per worker.");
+    for worker in 0..workers {
+        let mean = metrics.worker_mean_poll_time(worker).as_secs_f64();
+        let _ = writeln!(out, "tokio_worker_mean_poll_seconds{{worker=\"{}\"}} {}", worker, mean);
+    }
+}
+
+fn write_request_metrics(out: &mut String, state: &SharedState) {
+    let counts = state.requests.metrics().snapshot();
+
+    header(out, "dojo_http_requests_total", "counter", "Requests by route, method and status.");
+    for ((route, method, status), count) in &counts.requests {
+        let _ = writeln!(
+            out,
+            "dojo_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+            route, method, status, count
+        );
+    }
+
+    header(out, "dojo_http_request_duration_seconds", "histogram", "Request latency by route and method.");
+    for ((route, method), latency) in &counts.latency {
+        let labels = format!("route=\"{}\",method=\"{}\"", route, method);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "dojo_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "dojo_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, latency.count);
+        let _ = writeln!(out, "dojo_http_request_duration_seconds_sum{{{}}} {}", labels, latency.sum_seconds);
+        let _ = writeln!(out, "dojo_http_request_duration_seconds_count{{{}}} {}", labels, latency.count);
+    }
+}
+
+// Left out when the backend can't be reached, rather than reported as 0.
+async fn write_catalog_metrics(out: &mut String, state: &SharedState) {
+    match state.count().await {
+        Ok(count) => gauge(out, "dojo_books", "Books in the catalog.", count as f64),
+        Err(e) => tracing::warn!("metrics: counting books failed: {}", e),
+    }
+}
+
+fn write_storage_metrics(out: &mut String, state: &SharedState) {
+    let ops = state.storage_metrics.snapshot();
+
+    header(out, "dojo_storage_operations_total", "counter", "Storage operations by kind.");
+    for (op, stats) in &ops {
+        let _ = writeln!(out, "dojo_storage_operations_total{{op=\"{}\"}} {}", op, stats.count);
+    }
+
+    header(out, "dojo_storage_operation_seconds_total", "counter", "Time spent in storage operations.");
+    for (op, stats) in &ops {
+        let secs = stats.total_ms / 1000.0;
+        let _ = writeln!(out, "dojo_storage_operation_seconds_total{{op=\"{}\"}} {}", op, secs);
+    }
+}
+
+fn write_cache_metrics(out: &mut String, state: &SharedState) {
+    if !state.cache.enabled() {
+        return;
+    }
+    let (hits, misses, entries) = state.cache.stats();
+    header(out, "dojo_cache_hits_total", "counter", "Reads answered from the storage cache.");
+    let _ = writeln!(out, "dojo_cache_hits_total {}", hits);
+    header(out, "dojo_cache_misses_total", "counter", "Reads the storage cache passed on to the backend.");
+    let _ = writeln!(out, "dojo_cache_misses_total {}", misses);
+    gauge(out, "dojo_cache_entries", "Books and pages in the storage cache.", entries as f64);
+}
+
+async fn write_event_metrics(out: &mut String, state: &SharedState) {
+    let backlog = state
+        .read_storage("events_backlog", String::new, |storage| storage.outbox.as_ref().map(|outbox| outbox.backlog()))
+        .await;
+    if let Ok(Some(pending)) = backlog {
+        gauge(out, "dojo_events_pending", "Events waiting to be published.", pending as f64);
+        let full = if pending >= crate::events::MAX_PENDING { 1.0 } else { 0.0 };
+        gauge(out, "dojo_events_outbox_full", "Whether updates are refused until the broker takes events.", full);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
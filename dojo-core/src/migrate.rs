@@ -0,0 +1,20 @@
+use std::ops::Range;
+
+// Each persistent backend keeps its migrations in a list, oldest first, and
+// records with its data how many of them it has had. On startup the rest
+// run in order. Data a newer build has migrated further than this one
+// knows of is refused, rather than read as something it isn't.
+pub fn pending(what: &str, version: u64, known: usize) -> Result<Range<usize>, String> {
+    match usize::try_from(version) {
+        Ok(version) if version <= known => {
+            if version < known {
+                tracing::info!("migrating {} from schema version {} to {}", what, version, known);
+            }
+            Ok(version..known)
+        }
+        _ => Err(format!(
+            "{} is at schema version {}, but this build only knows up to {}",
+            what, version, known
+        )),
+    }
+}
@@ -0,0 +1,310 @@
+use serde_json::{Map, Number, Value};
+
+// MessagePack for what JSON can hold. Binary and extension types have no
+// JSON counterpart, so they're rejected rather than guessed at.
+pub const MEDIA_TYPE: &str = "application/msgpack";
+
+const MAX_DEPTH: usize = 128;
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write(&mut out, value);
+    out
+}
+
+fn write(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(n), _) => write_uint(out, n),
+            (None, Some(n)) => write_int(out, n),
+            _ => {
+                out.push(0xcb);
+                out.extend(number.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        },
+        Value::String(text) => {
+            let len = text.len();
+            match len {
+                0..=31 => out.push(0xa0 | len as u8),
+                32..=0xff => out.extend([0xd9, len as u8]),
+                0x100..=0xffff => {
+                    out.push(0xda);
+                    out.extend((len as u16).to_be_bytes());
+                }
+                _ => {
+                    out.push(0xdb);
+                    out.extend((len as u32).to_be_bytes());
+                }
+            }
+            out.extend(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_len(out, items.len(), 0x90, 0xdc);
+            items.iter().for_each(|item| write(out, item));
+        }
+        Value::Object(object) => {
+            write_len(out, object.len(), 0x80, 0xde);
+            for (key, value) in object {
+                write(out, &Value::String(key.clone()));
+                write(out, value);
+            }
+        }
+    }
+}
+
+fn write_uint(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0x7f => out.push(n as u8),
+        0x80..=0xff => out.extend([0xcc, n as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend((n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xcf);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+// Only ever called with negative numbers; the rest are written unsigned.
+fn write_int(out: &mut Vec<u8>, n: i64) {
+    match n {
+        -32..=-1 => out.push(n as u8),
+        -0x80..=-33 => out.extend([0xd0, n as u8]),
+        -0x8000..=-0x81 => {
+            out.push(0xd1);
+            out.extend((n as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            out.push(0xd2);
+            out.extend((n as i32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xd3);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+// Arrays and maps: a fix form for up to 15 items, then 16 and 32 bit lengths.
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, wide: u8) {
+    match len {
+        0..=15 => out.push(fix | len as u8),
+        16..=0xffff => {
+            out.push(wide);
+            out.extend((len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(wide + 1);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { bytes, at: 0 };
+    let value = reader.value(0)?;
+    match reader.at == bytes.len() {
+        true => Ok(value),
+        false => Err(format!("{} bytes left over after the value", bytes.len() - reader.at)),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self.at.checked_add(len).filter(|end| *end <= self.bytes.len()).ok_or("truncated value")?;
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, String> {
+        Ok(self.take(len)?.iter().fold(0, |n, byte| n << 8 | u64::from(*byte)))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("nested too deeply".to_string());
+        }
+        let marker = self.take(1)?[0];
+        Ok(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.map(usize::from(marker & 0x0f), depth)?,
+            0x90..=0x9f => self.array(usize::from(marker & 0x0f), depth)?,
+            0xa0..=0xbf => self.text(usize::from(marker & 0x1f))?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => float(f64::from(f32::from_bits(self.uint(4)? as u32)))?,
+            0xcb => float(f64::from_bits(self.uint(8)?))?,
+            0xcc => Value::from(self.uint(1)?),
+            0xcd => Value::from(self.uint(2)?),
+            0xce => Value::from(self.uint(4)?),
+            0xcf => Value::from(self.uint(8)?),
+            0xd0 => Value::from(self.uint(1)? as u8 as i8),
+            0xd1 => Value::from(self.uint(2)? as u16 as i16),
+            0xd2 => Value::from(self.uint(4)? as u32 as i32),
+            0xd3 => Value::from(self.uint(8)? as i64),
+            0xd9 => {
+                let len = self.uint(1)? as usize;
+                self.text(len)?
+            }
+            0xda => {
+                let len = self.uint(2)? as usize;
+                self.text(len)?
+            }
+            0xdb => {
+                let len = self.uint(4)? as usize;
+                self.text(len)?
+            }
+            0xdc => {
+                let len = self.uint(2)? as usize;
+                self.array(len, depth)?
+            }
+            0xdd => {
+                let len = self.uint(4)? as usize;
+                self.array(len, depth)?
+            }
+            0xde => {
+                let len = self.uint(2)? as usize;
+                self.map(len, depth)?
+            }
+            0xdf => {
+                let len = self.uint(4)? as usize;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => Value::from(marker as i8),
+            0xc4..=0xc6 => return Err("binary values aren't supported".to_string()),
+            _ => return Err(format!("unsupported type 0x{:02x}", marker)),
+        })
+    }
+
+    fn text(&mut self, len: usize) -> Result<Value, String> {
+        let bytes = self.take(len)?;
+        let text = std::str::from_utf8(bytes).map_err(|_| "a string isn't valid UTF-8")?;
+        Ok(Value::String(text.to_string()))
+    }
+
+    // Lengths come from the input, so nothing is reserved up front.
+    fn array(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        let mut object = Map::new();
+        for _ in 0..len {
+            let Value::String(key) = self.value(depth + 1)? else {
+                return Err("map keys must be strings".to_string());
+            };
+            object.insert(key, self.value(depth + 1)?);
+        }
+        Ok(Value::Object(object))
+    }
+}
+
+pub fn float(value: f64) -> Result<Value, String> {
+    Number::from_f64(value).map(Value::Number).ok_or_else(|| format!("{} can't be sent as JSON", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{hex, unhex};
+    use serde_json::json;
+
+    fn decoded(wire: &str) -> Result<Value, String> {
+        decode(&unhex(&wire.replace(' ', "")).unwrap())
+    }
+
+    #[test]
+    fn values_take_their_shortest_form() {
+        let cases = [
+            (json!(null), "c0"),
+            (json!(false), "c2"),
+            (json!(true), "c3"),
+            (json!(0), "00"),
+            (json!(127), "7f"),
+            (json!(128), "cc80"),
+            (json!(256), "cd0100"),
+            (json!(65536), "ce00010000"),
+            (json!(4294967296u64), "cf0000000100000000"),
+            (json!(-1), "ff"),
+            (json!(-32), "e0"),
+            (json!(-33), "d0df"),
+            (json!(-129), "d1ff7f"),
+            (json!(-32769), "d2ffff7fff"),
+            (json!(-2147483649i64), "d3ffffffff7fffffff"),
+            (json!(1.5), "cb3ff8000000000000"),
+            (json!(""), "a0"),
+            (json!("Sól"), "a453c3b36c"),
+            (json!([1, [2]]), "92019102"),
+            (json!({ "a": 1 }), "81a16101"),
+        ];
+        for (value, wire) in cases {
+            assert_eq!(hex(&encode(&value)), wire, "{}", value);
+            assert_eq!(decoded(wire), Ok(value));
+        }
+        assert!(hex(&encode(&json!("x".repeat(32)))).starts_with("d920"));
+        assert!(hex(&encode(&json!("x".repeat(256)))).starts_with("da0100"));
+        assert!(hex(&encode(&json!(vec![0; 16]))).starts_with("dc0010"));
+        // Single precision, which this side never writes.
+        assert_eq!(decoded("ca3fc00000"), Ok(json!(1.5)));
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let long: Vec<Value> = (0..70_000).map(|n| json!(n % 300 - 150)).collect();
+        let fields: Map<String, Value> = (0..20).map(|n| (format!("field {}", n), json!(n * 1_000_003))).collect();
+        let value = json!({
+            "id": 7,
+            "title": "Solaris — Stanisław Lem",
+            "tags": ["sf", "classic", ""],
+            "edition": { "published_year": 1961, "weight": -0.25, "pages": null },
+            "blurb": "x".repeat(70_000),
+            "numbers": long,
+            "fields": fields,
+            "extremes": [u64::MAX, i64::MIN, f64::MAX],
+        });
+        assert_eq!(decode(&encode(&value)), Ok(value));
+    }
+
+    #[test]
+    fn malformed_input_is_refused() {
+        let cases = [
+            ("", "truncated value"),
+            ("cd01", "truncated value"),
+            ("92 01", "truncated value"),
+            ("0000", "1 bytes left over after the value"),
+            ("c40100", "binary values aren't supported"),
+            ("c1", "unsupported type 0xc1"),
+            ("d40100", "unsupported type 0xd4"),
+            ("8101 02", "map keys must be strings"),
+            ("a2c328", "a string isn't valid UTF-8"),
+            ("cb7ff8000000000000", "NaN can't be sent as JSON"),
+        ];
+        for (wire, error) in cases {
+            assert_eq!(decoded(wire), Err(error.to_string()), "{}", wire);
+        }
+        let deep = format!("{}00", "91".repeat(200));
+        assert_eq!(decoded(&deep), Err("nested too deeply".to_string()));
+        // A length far beyond the input doesn't allocate for it.
+        assert_eq!(decoded("ddffffffff"), Err("truncated value".to_string()));
+    }
+}
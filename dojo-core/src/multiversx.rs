@@ -0,0 +1,942 @@
+use books_model::{Anchor, Book, Nft, NftStatus, TxStatus};
+use hyper::{Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    body::Body,
+    codec::{base64_decode, base64_encode, hex, unhex},
+    error::ApiError,
+    extract::{Json, Path, State},
+    json_response,
+    links::Links,
+    outbound::{Outbound, OutboundError},
+    problem,
+    store::{BookStore, Store},
+    wallet::Wallet,
+    SharedState,
+};
+
+const DEFAULT_GATEWAY: &str = "https://devnet-gateway.multiversx.com";
+const DEFAULT_CHAIN_ID: &str = "D";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+// How failed calls to the gateway name it.
+const GATEWAY: &str = "MultiversX gateway";
+
+// What the protocol charges: a base cost, one per byte of data and, for
+// ESDTNFTCreate, the builtin function itself and a cost per byte it
+// stores.
+const GAS_PRICE: u64 = 1_000_000_000;
+const MIN_GAS: u64 = 50_000;
+const GAS_PER_DATA_BYTE: u64 = 1_500;
+const NFT_CREATE_GAS: u64 = 3_000_000;
+const GAS_PER_STORED_BYTE: u64 = 10_000;
+
+// A round is six seconds, so pending transactions are checked once a
+// round.
+const TRACK_INTERVAL: Duration = Duration::from_secs(6);
+
+const BECH32: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+// What anchoring transactions' data starts with, so they can be told
+// apart from anything else the wallet sends.
+const ANCHOR_PREFIX: &str = "dojo-books:anchor";
+
+// The MultiversX gateway books are minted and anchored through.
+// DOJO_MX_GATEWAY points at another network's gateway or a stand-in,
+// devnet's by default, with DOJO_MX_CHAIN_ID to match ("D" for devnet, "T"
+// for testnet, "1" for mainnet) and DOJO_MX_TIMEOUT_SECS bounding each
+// call, 10 seconds by default.
+//
+// Sending anything needs a wallet, see wallet.rs. Minting needs a
+// collection too: DOJO_MX_COLLECTION is the identifier of an NFT
+// collection the wallet has the ESDTRoleNFTCreate role for, e.g.
+// BOOKS-a1b2c3.
+pub struct MultiversX {
+    gateway: String,
+    chain_id: String,
+    http: Outbound,
+    wallet: Option<Wallet>,
+    collection: Option<String>,
+    // Held from reading the account's nonce to storing the transaction,
+    // so no two are signed with the same nonce and no book is minted or
+    // anchored twice at once.
+    submitting: tokio::sync::Mutex<()>,
+    // The transactions the service sent, by hash, as last seen.
+    tracked: Mutex<HashMap<String, Tracked>>,
+}
+
+impl Default for MultiversX {
+    fn default() -> Self {
+        MultiversX {
+            gateway: DEFAULT_GATEWAY.to_string(),
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            http: Outbound::new(DEFAULT_TIMEOUT),
+            wallet: None,
+            collection: None,
+            submitting: tokio::sync::Mutex::new(()),
+            tracked: Mutex::default(),
+        }
+    }
+}
+
+// What the gateway wraps every answer in.
+#[derive(Deserialize)]
+struct Envelope<T> {
+    data: Option<T>,
+    #[serde(default)]
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    nonce: u64,
+}
+
+#[derive(Deserialize)]
+struct Held {
+    balance: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Balance {
+    pub address: String,
+    // In the smallest unit, 10^-18 EGLD, as the gateway gives it.
+    pub balance: String,
+    // Denominated, e.g. "1.5".
+    pub egld: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    // The contract's address.
+    pub address: String,
+    pub function: String,
+    // Each argument's bytes in hex, as contracts take them.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VmQuery<'a> {
+    sc_address: &'a str,
+    func_name: &'a str,
+    args: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct VmValues {
+    data: VmOutput,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VmOutput {
+    return_code: String,
+    #[serde(default)]
+    return_message: String,
+    // Null when nothing was returned.
+    #[serde(default)]
+    return_data: Option<Vec<Option<String>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryResult {
+    // "ok", or why the contract refused, e.g. "user error".
+    pub return_code: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub return_message: String,
+    pub return_data: Vec<ReturnValue>,
+}
+
+// A returned value decoded every way a contract is likely to have meant
+// it, since the gateway doesn't know its type.
+#[derive(Debug, Serialize)]
+pub struct ReturnValue {
+    pub base64: String,
+    pub hex: String,
+    // As an unsigned big-endian integer, which is how contracts return
+    // numbers, in decimal.
+    pub number: String,
+    // As UTF-8, when it's printable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Sent {
+    #[serde(rename = "txHash")]
+    tx_hash: String,
+}
+
+#[derive(Deserialize)]
+struct Executed {
+    transaction: Status,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    status: String,
+    #[serde(default)]
+    sender: String,
+    // Base64, as it was sent.
+    #[serde(default)]
+    data: Option<String>,
+    #[serde(default)]
+    logs: Option<Logs>,
+}
+
+impl Status {
+    // None while the transaction is pending.
+    fn ended(&self) -> Option<TxStatus> {
+        match self.status.as_str() {
+            "success" | "executed" => Some(TxStatus::Success),
+            "fail" | "invalid" => Some(TxStatus::Fail),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Logs {
+    #[serde(default)]
+    events: Vec<LogEvent>,
+}
+
+#[derive(Deserialize)]
+struct LogEvent {
+    identifier: String,
+    #[serde(default)]
+    topics: Vec<Option<String>>,
+}
+
+// Fields in the order they're signed in. The signature is over the JSON
+// without it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Transaction {
+    nonce: u64,
+    value: String,
+    receiver: String,
+    sender: String,
+    gas_price: u64,
+    gas_limit: u64,
+    data: String,
+    #[serde(rename = "chainID")]
+    chain_id: String,
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl MultiversX {
+    pub fn from_env() -> Result<Self, String> {
+        let gateway = std::env::var("DOJO_MX_GATEWAY").unwrap_or_else(|_| DEFAULT_GATEWAY.to_string());
+        if !gateway.starts_with("http://") && !gateway.starts_with("https://") {
+            return Err(format!("DOJO_MX_GATEWAY must be an http:// or https:// URL, got {:?}", gateway));
+        }
+        let timeout = match std::env::var("DOJO_MX_TIMEOUT_SECS") {
+            Ok(secs) => match secs.parse() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(format!("DOJO_MX_TIMEOUT_SECS must be a positive number of seconds, got {:?}", secs)),
+            },
+            Err(_) => DEFAULT_TIMEOUT,
+        };
+        let wallet = Wallet::from_env()?;
+        let collection = std::env::var("DOJO_MX_COLLECTION").ok();
+        if let Some(collection) = &collection {
+            if wallet.is_none() {
+                return Err("DOJO_MX_COLLECTION needs a wallet to mint with in DOJO_MX_PEM or DOJO_MX_KEYSTORE".to_string());
+            }
+            let (ticker, random) = collection.split_once('-').unwrap_or_default();
+            let valid_ticker = (3..=10).contains(&ticker.len())
+                && ticker.bytes().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+            let valid_random =
+                random.len() == 6 && random.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c));
+            if !valid_ticker || !valid_random {
+                return Err(format!("DOJO_MX_COLLECTION must be a collection identifier like BOOKS-a1b2c3, got {:?}", collection));
+            }
+        }
+        if let Some(wallet) = &wallet {
+            tracing::info!("MultiversX transactions are signed by {}", wallet.address());
+        }
+        Ok(MultiversX {
+            gateway: gateway.trim_end_matches('/').to_string(),
+            chain_id: std::env::var("DOJO_MX_CHAIN_ID").unwrap_or_else(|_| DEFAULT_CHAIN_ID.to_string()),
+            http: Outbound::new(timeout),
+            wallet,
+            collection,
+            submitting: tokio::sync::Mutex::new(()),
+            tracked: Mutex::default(),
+        })
+    }
+
+    // What the gateway answers `path` with. None if it doesn't know it.
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>, OutboundError> {
+        let envelope = self.http.get_json(&format!("{}{}", self.gateway, path)).await?;
+        envelope.map(unwrap).transpose()
+    }
+
+    async fn post<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, OutboundError> {
+        let envelope = self.http.post_json(&format!("{}{}", self.gateway, path), body).await?;
+        unwrap(envelope.ok_or(OutboundError::Status(StatusCode::NOT_FOUND))?)
+    }
+
+    // Signs and sends a transaction from the wallet to itself carrying
+    // `data`, with `gas` on top of what the data itself costs. Answers with
+    // the transaction's hash.
+    async fn send(&self, wallet: &Wallet, data: &str, gas: u64) -> Result<String, OutboundError> {
+        let path = format!("/address/{}/nonce", wallet.address());
+        let account: Account = self.get(&path).await?.ok_or(OutboundError::Status(StatusCode::NOT_FOUND))?;
+        let mut tx = Transaction {
+            nonce: account.nonce,
+            value: "0".to_string(),
+            receiver: wallet.address().to_string(),
+            sender: wallet.address().to_string(),
+            gas_price: GAS_PRICE,
+            gas_limit: MIN_GAS + GAS_PER_DATA_BYTE * data.len() as u64 + gas,
+            data: base64_encode(data.as_bytes()),
+            chain_id: self.chain_id.clone(),
+            version: 1,
+            signature: None,
+        };
+        let unsigned = serde_json::to_vec(&tx).map_err(|e| OutboundError::Malformed(e.to_string()))?;
+        tx.signature = Some(wallet.sign(&unsigned));
+        let sent: Sent = self.post("/transaction/send", &tx).await?;
+        Ok(sent.tx_hash)
+    }
+
+    // Sends the ESDTNFTCreate transaction for the book, whose NFT is named
+    // after its title, carries its id, author and ISBN as attributes and
+    // links to `uri`. The NFT is created in the wallet's own account.
+    async fn submit(&self, wallet: &Wallet, collection: &str, book: &Book, uri: &str) -> Result<String, OutboundError> {
+        let attributes = serde_json::json!({ "id": book.id, "author": book.author, "isbn": book.isbn }).to_string();
+        let data = [
+            "ESDTNFTCreate".to_string(),
+            hex(collection.as_bytes()),
+            "01".to_string(),
+            hex(book.title.as_bytes()),
+            // No royalties and no hash.
+            String::new(),
+            String::new(),
+            hex(attributes.as_bytes()),
+            hex(uri.as_bytes()),
+        ]
+        .join("@");
+        let stored = (attributes.len() + uri.len()) as u64;
+        self.send(wallet, &data, NFT_CREATE_GAS + GAS_PER_STORED_BYTE * stored).await
+    }
+
+    // The transaction as the gateway has it now. None if it doesn't know
+    // it, as it doesn't for a moment after it's sent.
+    async fn transaction(&self, tx_hash: &str) -> Result<Option<Status>, OutboundError> {
+        let executed = self.get::<Executed>(&format!("/transaction/{}?withResults=true", tx_hash)).await?;
+        Ok(executed.map(|executed| executed.transaction))
+    }
+}
+
+// The identifier of the NFT a mint created. The event's topics are the
+// collection, the NFT's nonce, the quantity and the attributes.
+fn minted(collection: &str, transaction: Status) -> Option<String> {
+    let events = transaction.logs.map(|logs| logs.events).unwrap_or_default();
+    let nonce = events
+        .into_iter()
+        .find(|event| event.identifier == "ESDTNFTCreate")
+        .and_then(|event| event.topics.into_iter().nth(1).flatten())
+        .and_then(|nonce| base64_decode(&nonce).ok())?;
+    Some(format!("{}-{}", collection, hex(&nonce)))
+}
+
+fn unwrap<T>(envelope: Envelope<T>) -> Result<T, OutboundError> {
+    envelope.data.ok_or(OutboundError::Malformed(envelope.error))
+}
+
+fn bech32_polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ffffff) << 5 ^ u32::from(value);
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn bech32_hrp(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|c| c & 31));
+    expanded
+}
+
+// Regroups bits, e.g. bytes into the 5-bit groups bech32 writes. Without
+// `pad` leftover bits have to be zero padding.
+fn regroup(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let (mut acc, mut bits, mut out) = (0u32, 0, Vec::new());
+    for value in data {
+        acc = acc << from | u32::from(*value);
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push((acc >> bits & ((1 << to) - 1)) as u8);
+        }
+    }
+    if pad && bits > 0 {
+        out.push((acc << (to - bits) & ((1 << to) - 1)) as u8);
+    } else if !pad && (bits >= from || acc << (to - bits) & ((1 << to) - 1) != 0) {
+        return None;
+    }
+    Some(out)
+}
+
+pub(crate) fn bech32_encode(hrp: &str, bytes: &[u8]) -> String {
+    let data = regroup(bytes, 8, 5, true).unwrap_or_default();
+    let checksum = bech32_polymod(bech32_hrp(hrp).into_iter().chain(data.iter().copied()).chain([0; 6])) ^ 1;
+    let checksum = (0..6).map(|i| (checksum >> (5 * (5 - i)) & 31) as u8);
+    let chars = data.into_iter().chain(checksum).map(|value| BECH32[value as usize] as char);
+    format!("{}1{}", hrp, chars.collect::<String>())
+}
+
+// The bytes a bech32 string with the human-readable part `hrp` encodes.
+pub fn bech32_decode(hrp: &str, text: &str) -> Result<Vec<u8>, String> {
+    if text.bytes().any(|c| c.is_ascii_lowercase()) && text.bytes().any(|c| c.is_ascii_uppercase()) {
+        return Err("mixes upper and lower case".to_string());
+    }
+    let text = text.to_ascii_lowercase();
+    let Some((prefix, data)) = text.rsplit_once('1') else {
+        return Err("has no separator".to_string());
+    };
+    if prefix != hrp {
+        return Err(format!("doesn't start with {}1", hrp));
+    }
+    let data = data
+        .bytes()
+        .map(|c| BECH32.iter().position(|b| *b == c).map(|value| value as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or("has a character bech32 doesn't use")?;
+    if data.len() < 6 || bech32_polymod(bech32_hrp(hrp).into_iter().chain(data.iter().copied())) != 1 {
+        return Err("has a bad checksum".to_string());
+    }
+    regroup(&data[..data.len() - 6], 5, 8, false).ok_or_else(|| "has bad padding".to_string())
+}
+
+// An amount in the smallest unit as EGLD, which has 18 decimals, without
+// trailing zeros.
+fn denominate(amount: &str) -> String {
+    let padded = format!("{:0>19}", amount);
+    let (whole, fraction) = padded.split_at(padded.len() - 18);
+    match fraction.trim_end_matches('0') {
+        "" => whole.to_string(),
+        fraction => format!("{}.{}", whole, fraction),
+    }
+}
+
+// Why `address` isn't an account's address, if it isn't.
+fn check_address(address: &str) -> Result<(), String> {
+    match bech32_decode("erd", address)? {
+        key if key.len() == 32 => Ok(()),
+        _ => Err("isn't 32 bytes long".to_string()),
+    }
+}
+
+// The EGLD an account holds, as the gateway knows it. Accounts it has
+// never seen hold nothing.
+pub async fn balance(
+    State(state): State<SharedState>,
+    Path(address): Path<String>,
+) -> Result<Response<Body>, ApiError> {
+    check_address(&address).map_err(|reason| ApiError::BadRequest(format!("Invalid address: it {}", reason)))?;
+    let address = address.to_ascii_lowercase();
+    let held = match state.multiversx.get::<Held>(&format!("/address/{}/balance", address)).await {
+        Ok(Some(held)) if !held.balance.is_empty() && held.balance.bytes().all(|c| c.is_ascii_digit()) => held,
+        Ok(Some(held)) => {
+            let e = OutboundError::Malformed(format!("the balance {:?} isn't an amount", held.balance));
+            return Err(ApiError::upstream(GATEWAY)(e));
+        }
+        Ok(None) => return Err(ApiError::upstream(GATEWAY)(OutboundError::Status(StatusCode::NOT_FOUND))),
+        Err(e) => {
+            tracing::warn!("looking up the balance of {} failed: {}", address, e);
+            return Err(ApiError::upstream(GATEWAY)(e));
+        }
+    };
+    let balance = Balance {
+        egld: denominate(&held.balance),
+        balance: held.balance,
+        address,
+    };
+    Ok(json_response(StatusCode::OK, &balance)?)
+}
+
+// Bytes as an unsigned big-endian integer, in decimal.
+fn decimal(bytes: &[u8]) -> String {
+    // Least significant digit first.
+    let mut digits = vec![0u8];
+    for byte in bytes {
+        let mut carry = u32::from(*byte);
+        for digit in digits.iter_mut() {
+            let value = u32::from(*digit) * 256 + carry;
+            *digit = (value % 10) as u8;
+            carry = value / 10;
+        }
+        while carry > 0 {
+            digits.push((carry % 10) as u8);
+            carry /= 10;
+        }
+    }
+    while digits.len() > 1 && digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits.iter().rev().map(|digit| char::from(b'0' + digit)).collect()
+}
+
+fn return_value(encoded: Option<String>) -> Result<ReturnValue, OutboundError> {
+    let base64 = encoded.unwrap_or_default();
+    let bytes = base64_decode(&base64).map_err(|e| OutboundError::Malformed(format!("a return value {}", e)))?;
+    let printable = |text: &String| !text.is_empty() && !text.chars().any(char::is_control);
+    let text = String::from_utf8(bytes.clone()).ok().filter(printable);
+    Ok(ReturnValue {
+        hex: hex(&bytes),
+        number: decimal(&bytes),
+        text,
+        base64,
+    })
+}
+
+// Runs a contract's view function through the gateway's vm-values query,
+// which executes it without a transaction, and decodes what it returns.
+// A contract that refuses still answers 200, with its return code.
+pub async fn query(
+    State(state): State<SharedState>,
+    Json(request): Json<QueryRequest>,
+) -> Result<Response<Body>, ApiError> {
+    check_address(&request.address)
+        .map_err(|reason| ApiError::invalid(StatusCode::UNPROCESSABLE_ENTITY, "address", format!("it {}", reason)))?;
+    let valid_function = !request.function.is_empty()
+        && request.function.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_');
+    if !valid_function {
+        return Err(ApiError::invalid(StatusCode::UNPROCESSABLE_ENTITY, "function", "must be a function name"));
+    }
+    if let Some(index) = request.args.iter().position(|arg| unhex(arg).is_none()) {
+        return Err(ApiError::invalid(StatusCode::UNPROCESSABLE_ENTITY, format!("args[{}]", index), "must be hex"));
+    }
+    let address = request.address.to_ascii_lowercase();
+    let args: Vec<String> = request.args.iter().map(|arg| arg.to_ascii_lowercase()).collect();
+    let body = VmQuery {
+        sc_address: &address,
+        func_name: &request.function,
+        args: &args,
+    };
+    let output = state.multiversx.post::<_, VmValues>("/vm-values/query", &body).await.and_then(|values| {
+        let output = values.data;
+        Ok(QueryResult {
+            return_data: output.return_data.unwrap_or_default().into_iter().map(return_value).collect::<Result<_, _>>()?,
+            return_code: output.return_code,
+            return_message: output.return_message,
+        })
+    });
+    let result = output.map_err(|e| {
+        tracing::warn!("querying {} on contract {} failed: {}", request.function, address, e);
+        ApiError::upstream(GATEWAY)(e)
+    })?;
+    Ok(json_response(StatusCode::OK, &result)?)
+}
+
+// Mints an NFT for the book and answers 202 once the transaction is sent,
+// with the book showing it pending. The tracker follows the transaction
+// until it's executed, when the NFT's identifier is stored on the book too. A
+// book has one NFT, though one whose mint failed can be minted again.
+pub async fn mint<S: BookStore>(
+    Path(id): Path<u64>,
+    links: Links,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    let multiversx = &state.multiversx;
+    let (Some(wallet), Some(collection)) = (&multiversx.wallet, &multiversx.collection) else {
+        return Ok(problem::respond(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "minting isn't configured: it needs a wallet and DOJO_MX_COLLECTION",
+        ));
+    };
+    let _submitting = multiversx.submitting.lock().await;
+    let book = store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    if let Some(nft) = book.nft.as_ref().filter(|nft| nft.status != NftStatus::Failed) {
+        let detail = format!("book {} already has an NFT, minted in transaction {}", id, nft.tx_hash);
+        return Err(ApiError::Conflict(detail));
+    }
+    let uri = links.book_url(&book).unwrap_or_else(|| format!("/books/{}", id));
+    let tx_hash = multiversx.submit(wallet, collection, &book, &uri).await.map_err(|e| {
+        tracing::warn!("minting an NFT for book {} failed: {}", id, e);
+        ApiError::upstream(GATEWAY)(e)
+    })?;
+    let nft = Nft {
+        tx_hash: tx_hash.clone(),
+        status: NftStatus::Pending,
+        token_identifier: None,
+    };
+    let minted = |book: &Book| Ok::<_, Infallible>(Book { nft: Some(nft.clone()), ..book.clone() });
+    let stored = store.modify(id, minted).await.inspect_err(|e| {
+        tracing::error!("the NFT for book {} was sent in transaction {} but couldn't be stored: {}", id, tx_hash, e);
+    })?;
+    let book = match stored.ok_or_else(ApiError::not_found)? {
+        Ok(book) => book,
+        Err(never) => match never {},
+    };
+    multiversx.track(Tracked::pending(tx_hash, id, Purpose::Mint));
+    Ok(json_response(StatusCode::ACCEPTED, &links.book(&book, &book))?)
+}
+
+// The hash a book's record is anchored under: SHA-256 of its JSON,
+// without what MultiversX itself adds to it and without deleted_at, so it
+// only changes when the book does.
+pub fn record_hash(book: &Book) -> String {
+    let record = Book {
+        nft: None,
+        anchor: None,
+        deleted_at: None,
+        ..book.clone()
+    };
+    hex(&Sha256::digest(serde_json::to_vec(&record).unwrap_or_default()))
+}
+
+// What an anchoring transaction's data reads, e.g.
+// dojo-books:anchor:7:9f86d0...
+fn anchor_data(id: u64, hash: &str) -> String {
+    format!("{}:{}:{}", ANCHOR_PREFIX, id, hash)
+}
+
+// Writes the hash of the book's record to MultiversX, in the data of a
+// transaction from the wallet to itself, and answers 202 once it's sent
+// with the book showing the anchor pending. Like a mint it's tracked
+// until it's executed. Anchoring again, once the last one is no longer
+// pending, replaces it.
+pub async fn anchor<S: BookStore>(
+    Path(id): Path<u64>,
+    links: Links,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    let multiversx = &state.multiversx;
+    let Some(wallet) = &multiversx.wallet else {
+        return Ok(problem::respond(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "anchoring isn't configured: neither DOJO_MX_PEM nor DOJO_MX_KEYSTORE is set",
+        ));
+    };
+    let _submitting = multiversx.submitting.lock().await;
+    let book = store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    if let Some(anchor) = book.anchor.as_ref().filter(|anchor| anchor.status == TxStatus::Pending) {
+        let detail = format!("book {} is already being anchored, in transaction {}", id, anchor.tx_hash);
+        return Err(ApiError::Conflict(detail));
+    }
+    let hash = record_hash(&book);
+    let tx_hash = multiversx.send(wallet, &anchor_data(id, &hash), 0).await.map_err(|e| {
+        tracing::warn!("anchoring book {} failed: {}", id, e);
+        ApiError::upstream(GATEWAY)(e)
+    })?;
+    let anchor = Anchor {
+        hash,
+        tx_hash: tx_hash.clone(),
+        status: TxStatus::Pending,
+        anchored_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+    };
+    let anchored = |book: &Book| Ok::<_, Infallible>(Book { anchor: Some(anchor.clone()), ..book.clone() });
+    let stored = store.modify(id, anchored).await.inspect_err(|e| {
+        tracing::error!("book {} was anchored in transaction {} but it couldn't be stored: {}", id, tx_hash, e);
+    })?;
+    let book = match stored.ok_or_else(ApiError::not_found)? {
+        Ok(book) => book,
+        Err(never) => match never {},
+    };
+    multiversx.track(Tracked::pending(tx_hash, id, Purpose::Anchor));
+    Ok(json_response(StatusCode::ACCEPTED, &links.book(&book, &book))?)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Provenance {
+    pub id: u64,
+    // The hash that was anchored and the hash of the book as it is now.
+    pub hash: String,
+    pub current_hash: String,
+    // Whether the book is unchanged since it was anchored.
+    pub matches: bool,
+    pub tx_hash: String,
+    // As the gateway has it now, or as it was last seen if the gateway
+    // doesn't know the transaction.
+    pub status: TxStatus,
+    // Whether the transaction on chain is this wallet's and carries the
+    // anchored hash.
+    pub on_chain: bool,
+    pub data: String,
+    pub anchored_at: u64,
+}
+
+// Checks a book's anchor: that the book still hashes to what was anchored
+// and that the transaction on chain carries that hash. Only needs the
+// gateway, so any instance can verify what another anchored.
+pub async fn provenance(Path(id): Path<u64>, State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let book = state.get(id).await?.ok_or_else(ApiError::not_found)?;
+    let anchor = book
+        .anchor
+        .clone()
+        .ok_or_else(|| ApiError::NotFound(format!("book {} has never been anchored", id)))?;
+    let data = anchor_data(id, &anchor.hash);
+    let transaction = state.multiversx.transaction(&anchor.tx_hash).await.map_err(|e| {
+        tracing::warn!("checking the anchor of book {} failed: {}", id, e);
+        ApiError::upstream(GATEWAY)(e)
+    })?;
+    let wallet = state.multiversx.wallet.as_ref().map(Wallet::address);
+    let (status, on_chain) = match &transaction {
+        Some(transaction) => {
+            let carried = transaction.data.as_deref().and_then(|data| base64_decode(data).ok());
+            let sender_matches = wallet.is_none_or(|address| transaction.sender == address);
+            let status = transaction.ended().unwrap_or(TxStatus::Pending);
+            (status, sender_matches && carried.as_deref() == Some(data.as_bytes()))
+        }
+        None => (anchor.status, false),
+    };
+    let current_hash = record_hash(&book);
+    let provenance = Provenance {
+        id,
+        matches: current_hash == anchor.hash,
+        hash: anchor.hash,
+        current_hash,
+        tx_hash: anchor.tx_hash,
+        status,
+        on_chain,
+        data,
+        anchored_at: anchor.anchored_at,
+    };
+    Ok(json_response(StatusCode::OK, &provenance)?)
+}
+
+// What a transaction the service sent was for.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Purpose {
+    Mint,
+    Anchor,
+}
+
+impl Purpose {
+    fn doing(self) -> &'static str {
+        match self {
+            Purpose::Mint => "minting",
+            Purpose::Anchor => "anchoring",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Tracked {
+    pub tx_hash: String,
+    pub book_id: u64,
+    pub purpose: Purpose,
+    pub status: TxStatus,
+    // The NFT a mint created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_identifier: Option<String>,
+    // When the gateway was last asked, in seconds since the epoch. None
+    // for transactions that had ended before this instance started.
+    pub checked_at: Option<u64>,
+}
+
+impl Tracked {
+    fn pending(tx_hash: String, book_id: u64, purpose: Purpose) -> Self {
+        Tracked {
+            tx_hash,
+            book_id,
+            purpose,
+            status: TxStatus::Pending,
+            token_identifier: None,
+            checked_at: None,
+        }
+    }
+}
+
+impl MultiversX {
+    fn track(&self, tracked: Tracked) {
+        self.tracked.lock().unwrap().insert(tracked.tx_hash.clone(), tracked);
+    }
+
+    fn pending(&self) -> Vec<Tracked> {
+        let tracked = self.tracked.lock().unwrap();
+        tracked.values().filter(|tracked| tracked.status == TxStatus::Pending).cloned().collect()
+    }
+}
+
+// How a transaction the service sent stands, as the tracker last saw it,
+// so clients needn't ask the gateway themselves.
+pub async fn transaction(
+    State(state): State<SharedState>,
+    Path(hash): Path<String>,
+) -> Result<Response<Body>, ApiError> {
+    let tracked = state.multiversx.tracked.lock().unwrap().get(&hash.to_ascii_lowercase()).cloned();
+    let tracked =
+        tracked.ok_or_else(|| ApiError::NotFound(format!("transaction {} wasn't sent by this service", hash)))?;
+    Ok(json_response(StatusCode::OK, &tracked)?)
+}
+
+// Follows the transactions the service sent. It starts from those stored
+// on books, so a restart picks up where the last run left off, then asks
+// the gateway about the pending ones once a round and stores how each
+// ended on its book.
+pub async fn track(state: SharedState) {
+    let mut interval = tokio::time::interval(TRACK_INTERVAL);
+    let mut loaded = false;
+    loop {
+        interval.tick().await;
+        if !loaded {
+            loaded = load(&state).await;
+        }
+        for tracked in state.multiversx.pending() {
+            check(&state, tracked).await;
+        }
+    }
+}
+
+// Tracks the latest mint and anchor of every book. False if the books
+// couldn't be listed, to be tried again.
+async fn load(state: &SharedState) -> bool {
+    let books = match state.list().await {
+        Ok(books) => books,
+        Err(e) => {
+            tracing::warn!("looking for MultiversX transactions failed: {}", e);
+            return false;
+        }
+    };
+    let mut tracked = state.multiversx.tracked.lock().unwrap();
+    for book in books {
+        if let Some(nft) = book.nft {
+            let status = match nft.status {
+                NftStatus::Pending => TxStatus::Pending,
+                NftStatus::Minted => TxStatus::Success,
+                NftStatus::Failed => TxStatus::Fail,
+            };
+            let entry = Tracked {
+                status,
+                token_identifier: nft.token_identifier,
+                ..Tracked::pending(nft.tx_hash, book.id, Purpose::Mint)
+            };
+            tracked.entry(entry.tx_hash.clone()).or_insert(entry);
+        }
+        if let Some(anchor) = book.anchor {
+            let entry = Tracked {
+                status: anchor.status,
+                ..Tracked::pending(anchor.tx_hash, book.id, Purpose::Anchor)
+            };
+            tracked.entry(entry.tx_hash.clone()).or_insert(entry);
+        }
+    }
+    true
+}
+
+// Asks the gateway about a pending transaction and, once it has ended,
+// stores how on its book. It stays pending, to be asked about again, if
+// storing fails.
+async fn check(state: &SharedState, mut tracked: Tracked) {
+    let (id, tx_hash) = (tracked.book_id, tracked.tx_hash.clone());
+    let transaction = match state.multiversx.transaction(&tx_hash).await {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            tracing::warn!("checking transaction {} for book {} failed: {}", tx_hash, id, e);
+            return;
+        }
+    };
+    tracked.checked_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    let Some((status, transaction)) = transaction.and_then(|transaction| Some((transaction.ended()?, transaction))) else {
+        state.multiversx.track(tracked);
+        return;
+    };
+    // Unless the book was minted or anchored again meanwhile.
+    let ended = match tracked.purpose {
+        Purpose::Mint => {
+            let collection = state.multiversx.collection.as_deref();
+            let token_identifier = collection
+                .filter(|_| status == TxStatus::Success)
+                .and_then(|collection| minted(collection, transaction));
+            let nft = Nft {
+                tx_hash: tx_hash.clone(),
+                status: if status == TxStatus::Success { NftStatus::Minted } else { NftStatus::Failed },
+                token_identifier: token_identifier.clone(),
+            };
+            tracked.token_identifier = token_identifier;
+            state
+                .modify(id, |book: &Book| match &book.nft {
+                    Some(current) if current.tx_hash == tx_hash => Ok(Book { nft: Some(nft.clone()), ..book.clone() }),
+                    _ => Err(()),
+                })
+                .await
+        }
+        Purpose::Anchor => {
+            state
+                .modify(id, |book: &Book| match &book.anchor {
+                    Some(current) if current.tx_hash == tx_hash => {
+                        Ok(Book { anchor: Some(Anchor { status, ..current.clone() }), ..book.clone() })
+                    }
+                    _ => Err(()),
+                })
+                .await
+        }
+    };
+    match ended {
+        Ok(_) => {
+            tracing::info!("transaction {} {} book {} ended {:?}", tx_hash, tracked.purpose.doing(), id, status);
+            tracked.status = status;
+        }
+        Err(e) => tracing::warn!("storing how transaction {} for book {} ended failed: {}", tx_hash, id, e),
+    }
+    state.multiversx.track(tracked);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The devnet wallet "alice", whose key and address MultiversX publish.
+    const ALICE: &str = "erd1qyu5wthldzr8wx5c9ucg8kjagg0jfs53s8nr3zpz3hypefsdd8ssycr6th";
+    const ALICE_KEY: &str = "0139472eff6886771a982f3083da5d421f24c29181e63888228dc81ca60d69e1";
+
+    #[test]
+    fn addresses_encode_and_decode_public_keys() {
+        let key = unhex(ALICE_KEY).unwrap();
+        assert_eq!(bech32_encode("erd", &key), ALICE);
+        assert_eq!(bech32_decode("erd", ALICE), Ok(key.clone()));
+        assert_eq!(bech32_decode("erd", &ALICE.to_ascii_uppercase()), Ok(key));
+        assert_eq!(check_address(ALICE), Ok(()));
+        // BIP-173's shortest valid string.
+        assert_eq!(bech32_decode("a", "A12UEL5L"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_addresses_say_why() {
+        let reason = |address: &str| check_address(address).unwrap_err();
+        let mut mixed = ALICE.to_string();
+        mixed.replace_range(4..5, "Q");
+        assert_eq!(reason(&mixed), "mixes upper and lower case");
+        assert_eq!(reason("erdqyu5wthldzr8wx5c9"), "has no separator");
+        assert_eq!(reason(&ALICE.replacen("erd", "xrd", 1)), "doesn't start with erd1");
+        assert_eq!(reason(&ALICE.replacen('q', "b", 1)), "has a character bech32 doesn't use");
+        assert_eq!(reason(&format!("{}q", &ALICE[..ALICE.len() - 1])), "has a bad checksum");
+        assert_eq!(reason(&bech32_encode("erd", &[7; 20])), "isn't 32 bytes long");
+    }
+
+    #[test]
+    fn balances_are_denominated_in_egld() {
+        assert_eq!(denominate("0"), "0");
+        assert_eq!(denominate("1"), "0.000000000000000001");
+        assert_eq!(denominate("1000000000000000000"), "1");
+        assert_eq!(denominate("12500000000000000000"), "12.5");
+    }
+}
@@ -0,0 +1,171 @@
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap, Request, Response, StatusCode,
+};
+use serde_json::Value;
+
+use crate::{
+    bad_request,
+    body::{self, Body},
+    cbor, msgpack, problem,
+    stack::Next,
+    SharedState,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Codec {
+    const ALL: [Codec; 3] = [Codec::Json, Codec::MessagePack, Codec::Cbor];
+
+    fn of_media(media: &str) -> Option<Self> {
+        match media {
+            "application/json" => Some(Codec::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Codec::MessagePack),
+            "application/cbor" => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    fn media_type(self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            Codec::MessagePack => msgpack::MEDIA_TYPE,
+            Codec::Cbor => cbor::MEDIA_TYPE,
+        }
+    }
+
+    fn encode(self, value: &Value) -> Vec<u8> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).unwrap_or_default(),
+            Codec::MessagePack => msgpack::encode(value),
+            Codec::Cbor => cbor::encode(value),
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Result<Value, String> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Codec::MessagePack => msgpack::decode(bytes),
+            Codec::Cbor => cbor::decode(bytes),
+        }
+    }
+}
+
+fn media(value: &str) -> String {
+    value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+// 415 for a body sent as anything but JSON, which is all handlers read.
+// MessagePack and CBOR are JSON by the time they get here, so they're
+// listed as accepted too.
+pub fn reject_non_json(headers: &HeaderMap) -> Option<Response<Body>> {
+    let sent = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(media);
+    if sent.as_deref() == Some(Codec::Json.media_type()) {
+        return None;
+    }
+    let accepted: Vec<&str> = Codec::ALL.iter().map(|codec| codec.media_type()).collect();
+    let detail = match sent {
+        Some(sent) => format!("a body sent as {} isn't accepted, only {}", sent, accepted.join(", ")),
+        None => format!("the body needs a Content-Type, one of {}", accepted.join(", ")),
+    };
+    Some(problem::respond(StatusCode::UNSUPPORTED_MEDIA_TYPE, detail))
+}
+
+// The codec the client prefers by Accept q-value. JSON wins ties and
+// wildcards, so clients that don't ask for a binary format never get one.
+fn preferred(headers: &HeaderMap) -> Codec {
+    let mut json = 0.0;
+    let mut best = (Codec::Json, 0.0);
+    for range in headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let q = range
+            .split(';')
+            .skip(1)
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media(range).as_str() {
+            "application/json" | "application/*" | "*/*" => json = f32::max(json, q),
+            other => match Codec::of_media(other) {
+                Some(codec) if q > best.1 => best = (codec, q),
+                _ => {}
+            },
+        }
+    }
+    match best.1 > json {
+        true => best.0,
+        false => Codec::Json,
+    }
+}
+
+// Lets clients send and receive MessagePack or CBOR in place of JSON.
+// Request bodies are turned into JSON on the way in and JSON responses into
+// the format Accept prefers on the way out, so handlers only ever see JSON.
+// It sits inside the capture layer, which records what was on the wire, and
+// outside the contract layer, which checks the JSON the spec describes.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let (mut parts, body) = req.into_parts();
+    let sent = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Codec::of_media(&media(value)));
+    let body = match sent {
+        Some(codec @ (Codec::MessagePack | Codec::Cbor)) => {
+            let bytes = body::to_bytes(body).await?;
+            let json = match codec.decode(&bytes) {
+                Ok(value) => Codec::Json.encode(&value),
+                Err(e) => {
+                    return Ok(bad_request(&format!("body isn't valid {}: {}", codec.media_type(), e)));
+                }
+            };
+            parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(json.len()));
+            Body::from(json)
+        }
+        _ => body,
+    };
+    let wanted = preferred(&parts.headers);
+    let response = next.run(Request::from_parts(parts, body), state).await?;
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| media(value) == "application/json");
+    if !is_json && response.status() != StatusCode::NOT_MODIFIED {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let varies = parts
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("accept"));
+    if !varies {
+        parts.headers.append(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    if !is_json || wanted == Codec::Json {
+        return Ok(Response::from_parts(parts, body));
+    }
+    let bytes = body::to_bytes(body).await?;
+    // A body that doesn't parse as JSON is passed on as it is.
+    let Ok(value) = Codec::Json.decode(&bytes) else {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    };
+    let encoded = wanted.encode(&value);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(wanted.media_type()));
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(encoded.len()));
+    Ok(Response::from_parts(parts, Body::from(encoded)))
+}
@@ -0,0 +1,123 @@
+use books_model::{CreateBookRequest, Edition};
+use hyper::{Response, StatusCode};
+use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    extract::{Json, State},
+    isbn, json_response,
+    links::Links,
+    outbound::{Outbound, OutboundError},
+    raft,
+    store::{BookStore, Store},
+    validate, SharedState,
+};
+
+const DEFAULT_URL: &str = "https://openlibrary.org";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Where ISBNs are looked up. DOJO_OPENLIBRARY_URL points at another
+// instance or a stand-in, https://openlibrary.org by default, and
+// DOJO_OPENLIBRARY_TIMEOUT_SECS bounds each lookup, 5 seconds by default.
+pub struct OpenLibrary {
+    url: String,
+    http: Outbound,
+}
+
+impl Default for OpenLibrary {
+    fn default() -> Self {
+        OpenLibrary {
+            url: DEFAULT_URL.to_string(),
+            http: Outbound::new(DEFAULT_TIMEOUT),
+        }
+    }
+}
+
+// The parts of a jscmd=data record that make a book.
+#[derive(Debug, Deserialize)]
+struct Record {
+    title: String,
+    #[serde(default)]
+    authors: Vec<Name>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Name {
+    name: String,
+}
+
+impl OpenLibrary {
+    pub fn from_env() -> Result<Self, String> {
+        let url = std::env::var("DOJO_OPENLIBRARY_URL").unwrap_or_else(|_| DEFAULT_URL.to_string());
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("DOJO_OPENLIBRARY_URL must be an http:// or https:// URL, got {:?}", url));
+        }
+        let timeout = match std::env::var("DOJO_OPENLIBRARY_TIMEOUT_SECS") {
+            Ok(secs) => match secs.parse() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(format!("DOJO_OPENLIBRARY_TIMEOUT_SECS must be a positive number of seconds, got {:?}", secs)),
+            },
+            Err(_) => DEFAULT_TIMEOUT,
+        };
+        Ok(OpenLibrary {
+            url: url.trim_end_matches('/').to_string(),
+            http: Outbound::new(timeout),
+        })
+    }
+
+    // The book OpenLibrary has under a normalized ISBN, if any. Several
+    // authors are joined with commas.
+    async fn lookup(&self, isbn: &str) -> Result<Option<CreateBookRequest>, OutboundError> {
+        let key = format!("ISBN:{}", isbn);
+        let url = format!("{}/api/books?bibkeys={}&format=json&jscmd=data", self.url, key);
+        let mut records: HashMap<String, Record> = self.http.get_json(&url).await?.unwrap_or_default();
+        Ok(records.remove(&key).map(|record| CreateBookRequest {
+            title: record.title,
+            author: record.authors.into_iter().map(|author| author.name).collect::<Vec<_>>().join(", "),
+            isbn: Some(isbn.to_string()),
+            author_id: None,
+            tags: Vec::new(),
+            edition: Edition::default(),
+            uid: None,
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LookupRequest {
+    pub isbn: String,
+    // Adds the book to the catalog rather than only answering with it.
+    #[serde(default)]
+    pub create: bool,
+}
+
+// Answers with the book OpenLibrary has for the ISBN, for a client to
+// check before creating it, or creates it right away with `create`. What
+// OpenLibrary sends is validated like any new book, so a record without
+// an author can be looked up but not created.
+pub async fn lookup<S: BookStore>(
+    links: Links,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+    Json(request): Json<LookupRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let isbn = isbn::normalize(&request.isbn)
+        .map_err(|message| ApiError::invalid(StatusCode::UNPROCESSABLE_ENTITY, "isbn", message))?;
+    if request.create && state.raft.is_some() {
+        return Ok(raft::not_replicated("POST /books/lookup with create"));
+    }
+    let looked_up = state.openlibrary.lookup(&isbn).await.map_err(|e| {
+        tracing::warn!("looking up ISBN {} on OpenLibrary failed: {}", isbn, e);
+        ApiError::upstream("OpenLibrary")(e)
+    })?;
+    let mut book =
+        looked_up.ok_or_else(|| ApiError::NotFound(format!("OpenLibrary has no book with ISBN {}", isbn)))?;
+    if !request.create {
+        return Ok(json_response(StatusCode::OK, &book)?);
+    }
+    validate::check(&mut book)?;
+    let book = store.insert(book).await?;
+    Ok(json_response(StatusCode::CREATED, &links.book(&book, &book))?)
+}
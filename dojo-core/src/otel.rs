@@ -0,0 +1,261 @@
+use serde_json::{json, Map, Value};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::{logging::JsonVisitor, outbound::Outbound};
+
+// Spans waiting to be sent beyond this are dropped, so a collector that's
+// down can't make the server run out of memory.
+const MAX_QUEUED: usize = 4096;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Where a span sits in its trace. The first span of a trace here is the
+// server's, even when a caller started the trace.
+#[derive(Debug, Clone, Copy)]
+struct Ids {
+    trace: u128,
+    span: u64,
+    parent: Option<u64>,
+    server: bool,
+}
+
+// What a span has recorded while it's open.
+struct Open {
+    ids: Ids,
+    start: SystemTime,
+    fields: Map<String, Value>,
+}
+
+// A closed span, as it's sent.
+#[derive(Debug)]
+struct Closed {
+    ids: Ids,
+    name: String,
+    start: SystemTime,
+    end: SystemTime,
+    fields: Map<String, Value>,
+}
+
+#[derive(Default)]
+struct Queue {
+    spans: Vec<Closed>,
+    dropped: u64,
+}
+
+// DOJO_OTLP_ENDPOINT sends the spans of this crate, a request's and the
+// handler and storage calls within it, to an OpenTelemetry collector such
+// as Jaeger's, as OTLP over HTTP with JSON bodies to <endpoint>/v1/traces.
+// A request carrying a W3C traceparent continues that trace.
+pub struct Tracer {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Tracer {
+    // None unless an endpoint is configured. Exporting starts right away,
+    // so this has to run within the runtime.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let endpoint = match std::env::var("DOJO_OTLP_ENDPOINT") {
+            Ok(endpoint) if !endpoint.is_empty() => endpoint,
+            _ => return Ok(None),
+        };
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            return Err(format!("DOJO_OTLP_ENDPOINT must be an http:// or https:// URL, got {:?}", endpoint));
+        }
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        tokio::spawn(export(url, queue.clone()));
+        Ok(Some(Tracer { queue }))
+    }
+}
+
+async fn export(url: String, queue: Arc<Mutex<Queue>>) {
+    let client = Outbound::new(Duration::from_secs(10));
+    let resource = json!({ "attributes": [
+        attribute("service.name", &Value::from(env!("CARGO_PKG_NAME"))),
+        attribute("service.version", &Value::from(env!("CARGO_PKG_VERSION"))),
+        attribute("service.instance.id", &Value::from(crate::leader::node_name())),
+    ] });
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (spans, dropped) = {
+            let mut queue = queue.lock().unwrap();
+            (std::mem::take(&mut queue.spans), std::mem::take(&mut queue.dropped))
+        };
+        if dropped > 0 {
+            tracing::warn!(dropped, "dropped spans the collector didn't take in time");
+        }
+        if spans.is_empty() {
+            continue;
+        }
+        let body = request(&resource, &spans);
+        if let Err(e) = client.post_json::<_, Value>(&url, &body).await {
+            tracing::warn!(spans = spans.len(), "exporting spans to {} failed: {}", url, e);
+        }
+    }
+}
+
+// An ExportTraceServiceRequest in OTLP's JSON encoding.
+fn request(resource: &Value, spans: &[Closed]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(span).collect();
+    json!({ "resourceSpans": [{
+        "resource": resource,
+        "scopeSpans": [{ "scope": { "name": env!("CARGO_PKG_NAME") }, "spans": spans }],
+    }] })
+}
+
+fn span(closed: &Closed) -> Value {
+    let nanos = |at: SystemTime| at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()).to_string();
+    let failed = closed.fields.get("status").and_then(Value::as_u64).is_some_and(|status| status >= 500);
+    let mut span = json!({
+        "traceId": format!("{:032x}", closed.ids.trace),
+        "spanId": format!("{:016x}", closed.ids.span),
+        "name": closed.name,
+        "kind": if closed.ids.server { 2 } else { 1 },
+        "startTimeUnixNano": nanos(closed.start),
+        "endTimeUnixNano": nanos(closed.end),
+        "attributes": closed.fields.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<_>>(),
+        "status": { "code": if failed { 2 } else { 0 } },
+    });
+    if let Some(parent) = closed.ids.parent {
+        span["parentSpanId"] = Value::from(format!("{:016x}", parent));
+    }
+    span
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(number) if number.is_f64() => json!({ "doubleValue": number }),
+        Value::Number(number) => json!({ "intValue": number.to_string() }),
+        Value::String(value) => json!({ "stringValue": value }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+// The trace and parent span ids of a W3C traceparent header value.
+fn traceparent(value: &str) -> Option<(u128, u64)> {
+    let mut parts = value.split('-');
+    let (Some("00"), Some(trace), Some(span)) = (parts.next(), parts.next(), parts.next()) else {
+        return None;
+    };
+    let trace = u128::from_str_radix(trace, 16).ok().filter(|trace| *trace != 0)?;
+    let span = u64::from_str_radix(span, 16).ok().filter(|span| *span != 0)?;
+    Some((trace, span))
+}
+
+fn random_id() -> u128 {
+    uuid::Uuid::new_v4().as_u128()
+}
+
+// A span's name as the collector shows it, with the route or storage
+// operation it's for.
+fn display_name(name: &str, fields: &Map<String, Value>) -> String {
+    match ["route", "op"].iter().find_map(|key| fields.get(*key).and_then(Value::as_str)) {
+        Some(detail) => format!("{} {}", name, detail),
+        None => name.to_string(),
+    }
+}
+
+impl<S> Layer<S> for Tracer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if !span.metadata().target().starts_with(env!("CARGO_CRATE_NAME")) {
+            return;
+        }
+        let mut fields = JsonVisitor::default();
+        attrs.record(&mut fields);
+        let mut fields = fields.0;
+        let traced = fields.remove("traceparent").and_then(|value| value.as_str().and_then(traceparent));
+        let parent = span.scope().skip(1).find_map(|parent| parent.extensions().get::<Open>().map(|open| open.ids));
+        let ids = match (parent, traced) {
+            (Some(parent), _) => Ids { trace: parent.trace, span: random_id() as u64, parent: Some(parent.span), server: false },
+            (None, Some((trace, span))) => Ids { trace, span: random_id() as u64, parent: Some(span), server: true },
+            (None, None) => Ids { trace: random_id(), span: random_id() as u64, parent: None, server: true },
+        };
+        span.extensions_mut().insert(Open { ids, start: SystemTime::now(), fields });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(open) = extensions.get_mut::<Open>() {
+            let mut fields = JsonVisitor(std::mem::take(&mut open.fields));
+            values.record(&mut fields);
+            open.fields = fields.0;
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(open) = span.extensions_mut().remove::<Open>() else {
+            return;
+        };
+        let closed = Closed {
+            ids: open.ids,
+            name: display_name(span.name(), &open.fields),
+            start: open.start,
+            end: SystemTime::now(),
+            fields: open.fields,
+        };
+        let mut queue = self.queue.lock().unwrap();
+        if queue.spans.len() < MAX_QUEUED {
+            queue.spans.push(closed);
+        } else {
+            queue.dropped += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn nested_spans_share_the_trace() {
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let subscriber = tracing_subscriber::registry().with(Tracer { queue: queue.clone() });
+        tracing::subscriber::with_default(subscriber, || {
+            let traced = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+            let request = tracing::info_span!("request", traceparent = traced, status = tracing::field::Empty);
+            request.in_scope(|| {
+                let _handler = tracing::info_span!("handler", route = "/books/{book ID}").entered();
+                let _storage = tracing::info_span!("storage", op = "get").entered();
+            });
+            request.record("status", 503);
+        });
+
+        let spans = std::mem::take(&mut queue.lock().unwrap().spans);
+        let names: Vec<&str> = spans.iter().map(|span| span.name.as_str()).collect();
+        assert_eq!(names, ["storage get", "handler /books/{book ID}", "request"]);
+        assert!(spans.iter().all(|span| span.ids.trace == 0x4bf92f3577b34da6a3ce929d0e0e4736));
+        assert_eq!(spans[0].ids.parent, Some(spans[1].ids.span));
+        assert_eq!(spans[1].ids.parent, Some(spans[2].ids.span));
+        assert_eq!(spans[2].ids.parent, Some(0x00f067aa0ba902b7));
+
+        let body = request(&json!({}), &spans);
+        let exported = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(exported[2]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!((&exported[2]["kind"], &exported[1]["kind"]), (&json!(2), &json!(1)));
+        assert_eq!(exported[2]["status"]["code"], 2);
+        assert_eq!(exported[2]["attributes"][0], json!({ "key": "status", "value": { "intValue": "503" } }));
+    }
+}
@@ -0,0 +1,341 @@
+use hyper::{header, Request, Response, StatusCode, Uri};
+use hyper_util::{
+    client::legacy::{
+        connect::{Connected, Connection, HttpConnector},
+        Client,
+    },
+    rt::{TokioExecutor, TokioIo},
+};
+use rustls_pki_types::ServerName;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::Instant,
+};
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
+use tower_service::Service;
+
+use crate::{body::Body, problem};
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+// Answers bigger than this are refused rather than read into memory.
+const MAX_BODY: usize = 1 << 20;
+
+// GETs are tried again this many times, waiting twice as long before each.
+const RETRIES: u32 = 2;
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+
+// After this many failed calls in a row the service is left alone for the
+// cooldown, then one call is let through to see whether it's back.
+const BREAKER_FAILURES: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+// Why a call to another service failed. Each maps to the status the
+// request that needed it is answered with.
+#[derive(Debug, thiserror::Error)]
+pub enum OutboundError {
+    #[error("unreachable: {0}")]
+    Unreachable(String),
+    #[error("timed out")]
+    TimedOut,
+    #[error("answered {0}")]
+    Status(StatusCode),
+    #[error("sent an answer that couldn't be read: {0}")]
+    Malformed(String),
+    #[error("has been failing, so it isn't called for another {}s", .0.as_secs().max(1))]
+    Failing(Duration),
+}
+
+impl OutboundError {
+    // A 504 when `service` was too slow, a 503 with Retry-After while it's
+    // left alone, otherwise a 502.
+    pub fn response(&self, service: &str) -> Response<Body> {
+        let status = match self {
+            OutboundError::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+            OutboundError::Failing(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        let mut response = problem::respond(status, format!("{} {}", service, self));
+        if let OutboundError::Failing(wait) = self {
+            response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(wait.as_secs().max(1)));
+        }
+        response
+    }
+
+    // Whether the service itself failed, rather than refusing what it was
+    // sent.
+    fn is_failure(&self) -> bool {
+        match self {
+            OutboundError::Unreachable(_) | OutboundError::TimedOut => true,
+            OutboundError::Status(status) => status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+            OutboundError::Malformed(_) | OutboundError::Failing(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+enum Circuit {
+    #[default]
+    Closed,
+    Open(Instant),
+    // The cooldown is over and one call is finding out.
+    Probing,
+}
+
+// Counts failed calls in a row, and opens once there are too many.
+#[derive(Debug, Default)]
+struct Breaker {
+    circuit: Circuit,
+    failures: u32,
+}
+
+impl Breaker {
+    // Err with how long until the next call is let through.
+    fn admit(&mut self) -> Result<(), Duration> {
+        match self.circuit {
+            Circuit::Closed => Ok(()),
+            Circuit::Open(until) if Instant::now() < until => Err(until - Instant::now()),
+            Circuit::Open(_) => {
+                self.circuit = Circuit::Probing;
+                Ok(())
+            }
+            Circuit::Probing => Err(BREAKER_COOLDOWN),
+        }
+    }
+
+    fn record(&mut self, failed: bool) {
+        if !failed {
+            *self = Breaker::default();
+            return;
+        }
+        self.failures += 1;
+        if matches!(self.circuit, Circuit::Probing) || self.failures >= BREAKER_FAILURES {
+            self.circuit = Circuit::Open(Instant::now() + BREAKER_COOLDOWN);
+        }
+    }
+}
+
+// An HTTP and HTTPS client for services outside the cluster, which unlike
+// peers are usually only reachable over TLS. Certificates are checked
+// against the system's roots. Every attempt, from connecting to the last
+// byte of the body, has to finish within the timeout. GETs that fail are
+// retried with exponential backoff; calls that change something aren't.
+// A service that keeps failing trips a circuit breaker, and is answered
+// for with a 503 rather than called until its cooldown is over.
+pub struct Outbound {
+    client: Client<Connector, Body>,
+    timeout: Duration,
+    breaker: Mutex<Breaker>,
+}
+
+impl Outbound {
+    pub fn new(timeout: Duration) -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(timeout));
+        Outbound {
+            client: Client::builder(TokioExecutor::new()).build(Connector { http }),
+            timeout,
+            breaker: Mutex::default(),
+        }
+    }
+
+    // GETs `url` and reads the JSON it answers with. None if it answered 404.
+    pub async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<Option<T>, OutboundError> {
+        let mut backoff = FIRST_BACKOFF;
+        for _ in 0..RETRIES {
+            match self.exchange(Request::get(url).header(header::ACCEPT, "application/json").body(Body::empty())).await {
+                Err(e) if e.is_failure() => tracing::debug!("GET {} failed, retrying in {:?}: {}", url, backoff, e),
+                result => return result,
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        self.exchange(Request::get(url).header(header::ACCEPT, "application/json").body(Body::empty())).await
+    }
+
+    // POSTs `body` as JSON to `url` and reads the JSON it answers with.
+    pub async fn post_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &B,
+    ) -> Result<Option<T>, OutboundError> {
+        let body = serde_json::to_vec(body).map_err(|e| OutboundError::Malformed(e.to_string()))?;
+        let req = Request::post(url)
+            .header(header::ACCEPT, "application/json")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+        self.exchange(req).await
+    }
+
+    async fn exchange<T: DeserializeOwned>(
+        &self,
+        req: Result<Request<Body>, hyper::http::Error>,
+    ) -> Result<Option<T>, OutboundError> {
+        self.breaker.lock().unwrap().admit().map_err(OutboundError::Failing)?;
+        let result = self.attempt(req).await;
+        let failed = result.as_ref().is_err_and(OutboundError::is_failure);
+        self.breaker.lock().unwrap().record(failed);
+        result
+    }
+
+    async fn attempt<T: DeserializeOwned>(
+        &self,
+        req: Result<Request<Body>, hyper::http::Error>,
+    ) -> Result<Option<T>, OutboundError> {
+        let mut req = req.map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+        req.headers_mut().insert(header::USER_AGENT, header::HeaderValue::from_static(USER_AGENT));
+        let exchange = async {
+            let response = self.client.request(req).await.map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+            match response.status() {
+                StatusCode::NOT_FOUND => return Ok(None),
+                status if !status.is_success() => return Err(OutboundError::Status(status)),
+                _ => {}
+            }
+            let mut body = Body::from(response.into_body());
+            let mut bytes = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+                if bytes.len() + chunk.len() > MAX_BODY {
+                    return Err(OutboundError::Malformed(format!("the body is over {} bytes", MAX_BODY)));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            serde_json::from_slice(&bytes).map(Some).map_err(|e| OutboundError::Malformed(e.to_string()))
+        };
+        tokio::time::timeout(self.timeout, exchange).await.map_err(|_| OutboundError::TimedOut)?
+    }
+}
+
+// The roots are read on the first HTTPS call rather than at startup, which
+// most instances never make.
+fn tls() -> Result<TlsConnector, io::Error> {
+    static TLS: OnceLock<Result<TlsConnector, String>> = OnceLock::new();
+    let tls = TLS.get_or_init(|| {
+        let mut roots = rustls::RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        if added == 0 {
+            return Err("no trusted root certificates were found on this system".to_string());
+        }
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(TlsConnector::from(Arc::new(config)))
+    });
+    tls.clone().map_err(io::Error::other)
+}
+
+#[derive(Clone)]
+struct Connector {
+    http: HttpConnector,
+}
+
+impl Service<Uri> for Connector {
+    type Response = TokioIo<Stream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<TokioIo<Stream>, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.http.poll_ready(cx).map_err(io::Error::other)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connect = self.http.call(uri.clone());
+        Box::pin(async move {
+            let tcp = connect.await.map_err(io::Error::other)?.into_inner();
+            if uri.scheme_str() != Some("https") {
+                return Ok(TokioIo::new(Stream::Plain(tcp)));
+            }
+            let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+            let name = ServerName::try_from(host.to_string()).map_err(io::Error::other)?;
+            Ok(TokioIo::new(Stream::Tls(Box::new(tls()?.connect(name, tcp).await?))))
+        })
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        match self {
+            Stream::Plain(tcp) => tcp.connected(),
+            Stream::Tls(tls) => tls.get_ref().0.connected(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            Stream::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            Stream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_flush(cx),
+            Stream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            Stream::Tls(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn the_breaker_opens_and_lets_one_call_through_after_its_cooldown() {
+        let mut breaker = Breaker::default();
+        for _ in 0..BREAKER_FAILURES - 1 {
+            breaker.record(true);
+        }
+        assert!(breaker.admit().is_ok());
+        breaker.record(true);
+        assert!(breaker.admit().is_err());
+
+        tokio::time::advance(BREAKER_COOLDOWN).await;
+        assert!(breaker.admit().is_ok());
+        assert!(breaker.admit().is_err(), "only one call finds out");
+        breaker.record(true);
+        assert_eq!(breaker.admit(), Err(BREAKER_COOLDOWN));
+
+        tokio::time::advance(BREAKER_COOLDOWN).await;
+        assert!(breaker.admit().is_ok());
+        breaker.record(false);
+        assert!(breaker.admit().is_ok());
+        assert!(!OutboundError::Status(StatusCode::NOT_FOUND).is_failure());
+        assert!(OutboundError::Status(StatusCode::SERVICE_UNAVAILABLE).is_failure());
+    }
+}
@@ -0,0 +1,406 @@
+use books_model::Book;
+use hyper::{header, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    bad_request,
+    body::{self, Body},
+    error::{ApiError, Changed},
+    etag::{self, IfMatch},
+    extract::{FromRequest, Path, RequestContext},
+    json_response,
+    links::Links,
+    listing::BOOK_FIELDS,
+    problem::{self, FieldError, Problem},
+    store::{BookStore, Store},
+    validate::{self, Violations},
+};
+
+pub const MEDIA_TYPE: &str = "application/json-patch+json";
+
+// Fields the server sets. A patch may test them but has to leave them as
+// they are.
+const READ_ONLY: &[&str] = &["id", "uid", "nft", "anchor", "deleted_at"];
+
+// One step of an RFC 6902 patch. move and copy aren't supported; a book has
+// nothing to move between.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Test { path: String, value: Value },
+}
+
+// A patch document, taken from a request body sent as
+// application/json-patch+json.
+pub struct Patch(Vec<Operation>);
+
+// Why a patch wasn't applied. The book is left as it was.
+#[derive(Debug)]
+pub struct Rejected {
+    status: StatusCode,
+    message: String,
+    errors: Vec<FieldError>,
+}
+
+impl Rejected {
+    // A test op that didn't hold: the book isn't in the state the client
+    // expected.
+    fn conflict(message: String) -> Self {
+        Rejected {
+            status: StatusCode::CONFLICT,
+            message,
+            errors: Vec::new(),
+        }
+    }
+
+    // A patch that's well formed but can't be applied to this book.
+    fn unprocessable(message: String) -> Self {
+        Rejected {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            message,
+            errors: Vec::new(),
+        }
+    }
+
+    // Patched fields whose new values aren't allowed.
+    fn invalid(errors: Vec<FieldError>) -> Self {
+        Rejected {
+            errors,
+            ..Rejected::unprocessable(String::new())
+        }
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        match self.errors.is_empty() {
+            true => problem::respond(self.status, self.message.clone()),
+            false => Problem::validation(self.status, self.errors.clone()).response(),
+        }
+    }
+}
+
+impl FromRequest for Patch {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let content_type = ctx.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        let media_type = content_type.and_then(|value| value.split(';').next()).map(str::trim);
+        if !media_type.is_some_and(|media_type| media_type.eq_ignore_ascii_case(MEDIA_TYPE)) {
+            let mut response = problem::respond(StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("PATCH takes {}", MEDIA_TYPE));
+            response.headers_mut().insert("Accept-Patch", header::HeaderValue::from_static(MEDIA_TYPE));
+            return Err(response);
+        }
+        let bytes = match body::to_bytes(ctx.take_body()).await {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(bad_request("Failed to read request body")),
+        };
+        match serde_json::from_slice(&bytes) {
+            Ok(operations) => Ok(Patch(operations)),
+            Err(e) => Err(bad_request(&format!("Invalid patch document: {}", e))),
+        }
+    }
+}
+
+impl Patch {
+    // Applies every operation in order, or none of them.
+    pub fn apply(&self, book: &Book) -> Result<Book, Rejected> {
+        let before = serde_json::to_value(book).map_err(|e| Rejected::unprocessable(e.to_string()))?;
+        let mut doc = before.clone();
+        for (index, operation) in self.0.iter().enumerate() {
+            apply(&mut doc, operation).map_err(|mut rejected| {
+                rejected.message = format!("operation {}: {}", index, rejected.message);
+                rejected
+            })?;
+        }
+
+        let Value::Object(fields) = &doc else {
+            return Err(Rejected::unprocessable("the patched book isn't an object".to_string()));
+        };
+        let known = |name: &String| BOOK_FIELDS.contains(&name.as_str()) || READ_ONLY.contains(&name.as_str());
+        if let Some(name) = fields.keys().find(|name| !known(name)) {
+            return Err(Rejected::unprocessable(format!("books have no field {:?}", name)));
+        }
+        if let Some(name) = READ_ONLY.iter().find(|name| fields.get(**name) != before.get(**name)) {
+            return Err(Rejected::unprocessable(format!("{} is read-only", name)));
+        }
+        let mut patched: Book = serde_json::from_value(doc)
+            .map_err(|e| Rejected::unprocessable(format!("the patched book is invalid: {}", e)))?;
+        // Filing a book under another author has to check the author exists,
+        // which PUT does.
+        if patched.author_id != book.author_id {
+            return Err(Rejected::unprocessable("author_id can't be patched, PUT the book with it instead".to_string()));
+        }
+        // The same rules as PUT, though an ISBN stored before they were
+        // checked doesn't block other changes.
+        let mut violations = Violations::default();
+        violations.text("title", &mut patched.title, validate::MAX_TITLE);
+        violations.text("author", &mut patched.author, validate::MAX_AUTHOR);
+        if patched.isbn != book.isbn {
+            violations.isbn(&mut patched.isbn);
+        }
+        violations.tags(&mut patched.tags);
+        if patched.edition != book.edition {
+            violations.edition(&mut patched.edition);
+        }
+        let errors = violations.into_errors();
+        if !errors.is_empty() {
+            return Err(Rejected::invalid(errors));
+        }
+        Ok(patched)
+    }
+}
+
+fn apply(doc: &mut Value, operation: &Operation) -> Result<(), Rejected> {
+    match operation {
+        Operation::Add { path, value } => {
+            let (parent, last) = parent(doc, path)?;
+            match parent {
+                Value::Object(object) => {
+                    object.insert(last, value.clone());
+                }
+                Value::Array(items) => {
+                    let at = match last.as_str() {
+                        "-" => items.len(),
+                        _ => index(&last, items.len() + 1, path)?,
+                    };
+                    items.insert(at, value.clone());
+                }
+                _ => return Err(missing(path)),
+            }
+        }
+        Operation::Remove { path } => {
+            take(doc, path)?;
+        }
+        Operation::Replace { path, value } => {
+            *pointer(doc, path)? = value.clone();
+        }
+        Operation::Test { path, value } => {
+            let found = pointer(doc, path)?;
+            if found != value {
+                return Err(Rejected::conflict(format!("{} is {}, not {}", path, found, value)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The reference tokens of a JSON Pointer (RFC 6901).
+fn tokens(path: &str) -> Result<Vec<String>, Rejected> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = path.strip_prefix('/') else {
+        return Err(Rejected::unprocessable(format!("{:?} is not a JSON Pointer", path)));
+    };
+    Ok(rest.split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+fn missing(path: &str) -> Rejected {
+    Rejected::unprocessable(format!("{} doesn't exist", path))
+}
+
+fn index(token: &str, len: usize, path: &str) -> Result<usize, Rejected> {
+    match token.parse::<usize>() {
+        Ok(at) if at < len && (token == "0" || !token.starts_with('0')) => Ok(at),
+        _ => Err(missing(path)),
+    }
+}
+
+fn child<'a>(value: &'a mut Value, token: &str, path: &str) -> Result<&'a mut Value, Rejected> {
+    match value {
+        Value::Object(object) => object.get_mut(token).ok_or_else(|| missing(path)),
+        Value::Array(items) => {
+            let at = index(token, items.len(), path)?;
+            Ok(&mut items[at])
+        }
+        _ => Err(missing(path)),
+    }
+}
+
+fn pointer<'a>(doc: &'a mut Value, path: &str) -> Result<&'a mut Value, Rejected> {
+    let mut value = doc;
+    for token in tokens(path)? {
+        value = child(value, &token, path)?;
+    }
+    Ok(value)
+}
+
+// The value holding what `path` points at, and the last token of it.
+fn parent<'a>(doc: &'a mut Value, path: &str) -> Result<(&'a mut Value, String), Rejected> {
+    let mut tokens = tokens(path)?;
+    let Some(last) = tokens.pop() else {
+        return Err(Rejected::unprocessable("the whole book can't be replaced, patch its fields".to_string()));
+    };
+    let mut value = doc;
+    for token in tokens {
+        value = child(value, &token, path)?;
+    }
+    Ok((value, last))
+}
+
+fn take(doc: &mut Value, path: &str) -> Result<Value, Rejected> {
+    let (parent, last) = parent(doc, path)?;
+    match parent {
+        Value::Object(object) => object.remove(&last).ok_or_else(|| missing(path)),
+        Value::Array(items) => {
+            let at = index(&last, items.len(), path)?;
+            Ok(items.remove(at))
+        }
+        _ => Err(missing(path)),
+    }
+}
+
+// PATCH /books/{id}. Unlike PUT, a patch can clear the ISBN, and test ops
+// make it conditional: if one fails the book is left alone and the answer
+// is 409. It needs If-Match as PUT does.
+pub async fn patch_book<S: BookStore>(
+    Path(id): Path<u64>,
+    if_match: IfMatch,
+    links: Links,
+    Store(store): Store<S>,
+    patch: Patch,
+) -> Result<Response<Body>, ApiError> {
+    let change = |book: &Book| {
+        if_match.check(book).map_err(Refused::Changed)?;
+        patch.apply(book).map_err(Refused::Rejected)
+    };
+    match store.modify(id, change).await?.ok_or_else(ApiError::not_found)? {
+        Ok(book) => Ok(etag::tagged(json_response(StatusCode::OK, &links.book(&book, &book))?, &book)),
+        Err(Refused::Changed(changed)) => Err(changed.into()),
+        Err(Refused::Rejected(rejected)) => Ok(rejected.response()),
+    }
+}
+
+// Why a patch left the book alone: it changed since the client read it, or
+// the patch doesn't apply to it.
+enum Refused {
+    Changed(Changed),
+    Rejected(Rejected),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch(operations: Value) -> Patch {
+        Patch(serde_json::from_value(operations).unwrap())
+    }
+
+    fn book(extra: Value) -> Book {
+        let mut book = serde_json::json!({ "id": 7, "title": "Solaris", "author": "Stanislaw Lem", "isbn": null });
+        book.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(book).unwrap()
+    }
+
+    fn refusal(rejected: Rejected) -> (StatusCode, String) {
+        (rejected.status, rejected.message)
+    }
+
+    // Books minted a uid, given an NFT or anchored carry fields the patch
+    // didn't touch, which mustn't make it fail.
+    #[test]
+    fn books_with_server_set_fields_can_be_patched() {
+        let retitle = patch(serde_json::json!([{ "op": "replace", "path": "/title", "value": "Fiasco" }]));
+        for extra in [
+            serde_json::json!({ "uid": "0b7f2c1e-7a43-4a39-9b8e-2f0d4e1c5a6b" }),
+            serde_json::json!({ "nft": { "tx_hash": "ab12", "status": "pending" } }),
+            serde_json::json!({ "anchor": { "hash": "cd34", "tx_hash": "ef56", "status": "success", "anchored_at": 1700000000 } }),
+        ] {
+            let book = book(extra.clone());
+            let patched = retitle.apply(&book).unwrap_or_else(|e| panic!("{}: {:?}", extra, e));
+            assert_eq!(patched.title, "Fiasco");
+            assert_eq!((patched.uid, patched.nft, patched.anchor), (book.uid, book.nft, book.anchor));
+        }
+    }
+
+    #[test]
+    fn operations_set_fields_in_order() {
+        let book = book(serde_json::json!({ "tags": ["sf"] }));
+        let patched = patch(serde_json::json!([
+            { "op": "replace", "path": "/title", "value": "Fiasco" },
+            { "op": "add", "path": "/isbn", "value": "0-306-40615-2" },
+            { "op": "add", "path": "/tags/-", "value": "classic" },
+            { "op": "add", "path": "/tags/0", "value": "polish" },
+            { "op": "test", "path": "/title", "value": "Fiasco" },
+        ]))
+        .apply(&book)
+        .unwrap();
+        assert_eq!((patched.title.as_str(), patched.isbn.as_deref()), ("Fiasco", Some("0306406152")));
+        // Tags are kept sorted, wherever the patch put them.
+        assert_eq!(patched.tags, ["classic", "polish", "sf"]);
+        assert_eq!((patched.id, patched.author), (book.id, book.author));
+    }
+
+    // Unlike PUT, a patch can take the ISBN away, by nulling or removing it.
+    #[test]
+    fn null_clears_the_isbn() {
+        let book = book(serde_json::json!({ "isbn": "9780306406157" }));
+        for operations in [
+            serde_json::json!([{ "op": "replace", "path": "/isbn", "value": null }]),
+            serde_json::json!([{ "op": "remove", "path": "/isbn" }]),
+        ] {
+            assert_eq!(patch(operations).apply(&book).unwrap().isbn, None);
+        }
+    }
+
+    #[test]
+    fn unknown_fields_and_paths_are_refused() {
+        let book = book(serde_json::json!({}));
+        let refused = |operations: Value| refusal(patch(operations).apply(&book).unwrap_err());
+        let unprocessable = |message: &str| (StatusCode::UNPROCESSABLE_ENTITY, message.to_string());
+        assert_eq!(
+            refused(serde_json::json!([{ "op": "add", "path": "/rating", "value": 5 }])),
+            unprocessable("books have no field \"rating\"")
+        );
+        assert_eq!(
+            refused(serde_json::json!([{ "op": "replace", "path": "/subtitle", "value": "x" }])),
+            unprocessable("operation 0: /subtitle doesn't exist")
+        );
+        assert_eq!(
+            refused(serde_json::json!([{ "op": "remove", "path": "/tags/0" }])),
+            unprocessable("operation 0: /tags/0 doesn't exist")
+        );
+        assert_eq!(
+            refused(serde_json::json!([{ "op": "replace", "path": "title", "value": "x" }])),
+            unprocessable("operation 0: \"title\" is not a JSON Pointer")
+        );
+    }
+
+    // A failed test op or an invalid result leaves the book as it was.
+    #[test]
+    fn failed_tests_and_invalid_values_refuse_the_whole_patch() {
+        let book = book(serde_json::json!({}));
+        let stale = patch(serde_json::json!([
+            { "op": "replace", "path": "/title", "value": "Fiasco" },
+            { "op": "test", "path": "/author", "value": "Lem" },
+        ]));
+        assert_eq!(
+            refusal(stale.apply(&book).unwrap_err()),
+            (StatusCode::CONFLICT, "operation 1: /author is \"Stanislaw Lem\", not \"Lem\"".to_string())
+        );
+        let blank = patch(serde_json::json!([{ "op": "replace", "path": "/title", "value": "  " }]));
+        let rejected = blank.apply(&book).unwrap_err();
+        assert_eq!(rejected.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(rejected.errors.iter().map(|error| error.field.as_str()).collect::<Vec<_>>(), ["title"]);
+    }
+
+    #[test]
+    fn server_set_fields_are_read_only() {
+        let book = book(serde_json::json!({ "uid": "0b7f2c1e-7a43-4a39-9b8e-2f0d4e1c5a6b", "nft": { "tx_hash": "ab12", "status": "pending" } }));
+        let unprocessable = |name: &str| (StatusCode::UNPROCESSABLE_ENTITY, format!("{} is read-only", name));
+        for (operations, name) in [
+            (serde_json::json!([{ "op": "replace", "path": "/id", "value": 8 }]), "id"),
+            (serde_json::json!([{ "op": "replace", "path": "/uid", "value": "0b7f2c1e-0000-4a39-9b8e-2f0d4e1c5a6b" }]), "uid"),
+            (serde_json::json!([{ "op": "remove", "path": "/nft" }]), "nft"),
+            (serde_json::json!([{ "op": "replace", "path": "/nft/status", "value": "success" }]), "nft"),
+            (serde_json::json!([{ "op": "add", "path": "/anchor", "value": { "hash": "", "tx_hash": "", "status": "success", "anchored_at": 0 } }]), "anchor"),
+            (serde_json::json!([{ "op": "add", "path": "/deleted_at", "value": 1 }]), "deleted_at"),
+        ] {
+            assert_eq!(refusal(patch(operations).apply(&book).unwrap_err()), unprocessable(name));
+        }
+        // Testing them is fine.
+        let test = patch(serde_json::json!([{ "op": "test", "path": "/nft/tx_hash", "value": "ab12" }]));
+        assert!(test.apply(&book).is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+use hyper::{header, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::body::Body;
+
+pub const MEDIA_TYPE: &str = "application/problem+json";
+
+// Problem types beyond the status code itself. The rest are about:blank,
+// whose title is the status's reason phrase.
+pub const VALIDATION: &str = "/problems/validation";
+pub const DUPLICATE_ISBN: &str = "/problems/duplicate-isbn";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// An RFC 7807 problem details body. `errors` lists what's wrong with each
+// field of a request that failed validation, and `book_id` is the book a
+// request collided with.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book_id: Option<u64>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: Some(detail.into()),
+            errors: Vec::new(),
+            book_id: None,
+        }
+    }
+
+    // A request whose fields broke the rules, one error per field.
+    pub fn validation(status: StatusCode, errors: Vec<FieldError>) -> Self {
+        let detail = summary(&errors);
+        Problem {
+            kind: VALIDATION,
+            title: "The request has invalid fields".to_string(),
+            errors,
+            ..Problem::new(status, detail)
+        }
+    }
+
+    // A write that would give a second book an ISBN, answered with the
+    // book that has it.
+    pub fn duplicate_isbn(isbn: &str, id: u64) -> Self {
+        Problem {
+            kind: DUPLICATE_ISBN,
+            title: "Another book has this ISBN".to_string(),
+            book_id: Some(id),
+            ..Problem::new(StatusCode::CONFLICT, format!("book {} already has ISBN {}", id, isbn))
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        Response::builder()
+            .status(self.status())
+            .header(header::CONTENT_TYPE, MEDIA_TYPE)
+            .body(Body::from(serde_json::to_vec(self).unwrap_or_default()))
+            .unwrap()
+    }
+}
+
+// What's wrong with a request, in one line: its one invalid field, or how
+// many there are.
+pub fn summary(errors: &[FieldError]) -> String {
+    match errors {
+        [only] => format!("{}: {}", only.field, only.message),
+        _ => summary_of(errors.len()),
+    }
+}
+
+pub fn summary_of(invalid: usize) -> String {
+    format!("{} fields are invalid", invalid)
+}
+
+// The response for a request with one invalid field.
+pub fn invalid(status: StatusCode, field: impl Into<String>, message: impl Into<String>) -> Response<Body> {
+    let error = FieldError {
+        field: field.into(),
+        message: message.into(),
+    };
+    Problem::validation(status, vec![error]).response()
+}
+
+// What went wrong, from a body another node answered with: a problem's
+// detail, or the body itself from a node that still answers in plain text.
+pub fn detail(body: &[u8]) -> String {
+    #[derive(Deserialize)]
+    struct Detail {
+        detail: String,
+    }
+    match serde_json::from_slice::<Detail>(body) {
+        Ok(problem) => problem.detail,
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+// The response for a problem that's only a status and what went wrong.
+pub fn respond(status: StatusCode, detail: impl Into<String>) -> Response<Body> {
+    Problem::new(status, detail).response()
+}
@@ -0,0 +1,68 @@
+use hyper::{Response, StatusCode};
+use serde::Deserialize;
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    extract::{Query, State},
+    problem, SharedState,
+};
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+
+#[derive(Deserialize)]
+pub struct ProfileParams {
+    seconds: Option<String>,
+}
+
+pub async fn cpu_profile(
+    Query(params): Query<ProfileParams>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    if !state.pprof_enabled {
+        return Err(ApiError::not_found());
+    }
+    let seconds = match params.seconds {
+        Some(value) => match value.parse::<u64>() {
+            Ok(seconds) if (1..=MAX_SECONDS).contains(&seconds) => seconds,
+            _ => return Err(ApiError::bad_request("seconds must be between 1 and 300")),
+        },
+        None => DEFAULT_SECONDS,
+    };
+
+    Ok(render(seconds).await)
+}
+
+#[cfg(feature = "pprof")]
+async fn render(seconds: u64) -> Response<Body> {
+    let profile = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(99)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| e.to_string())?;
+        std::thread::sleep(std::time::Duration::from_secs(seconds));
+
+        let report = guard.report().build().map_err(|e| e.to_string())?;
+        let mut svg = Vec::new();
+        report.flamegraph(&mut svg).map_err(|e| e.to_string())?;
+        Ok(svg)
+    })
+    .await;
+
+    match profile {
+        Ok(Ok(svg)) => Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "image/svg+xml")
+            .body(Body::from(svg))
+            .unwrap(),
+        Ok(Err(message)) => problem::respond(StatusCode::INTERNAL_SERVER_ERROR, format!("profiling failed: {}", message)),
+        Err(_) => problem::respond(StatusCode::INTERNAL_SERVER_ERROR, "profiling failed"),
+    }
+}
+
+#[cfg(not(feature = "pprof"))]
+async fn render(_seconds: u64) -> Response<Body> {
+    problem::respond(StatusCode::NOT_IMPLEMENTED, "built without the `pprof` feature")
+}
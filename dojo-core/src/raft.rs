@@ -0,0 +1,1587 @@
+use books_model::{Book, CreateBookRequest, UpdateBookRequest};
+use hyper::{
+    header::{self, HeaderMap},
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    hash::BuildHasher,
+    io::{self, Read, Write as _},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, Notify};
+
+use crate::{
+    auth,
+    body::{self, Body},
+    conditional,
+    error::ApiError,
+    etag::{self, IfMatch},
+    extract::{Json, Path, State},
+    json_response,
+    links::Links,
+    problem,
+    store::BookStore,
+    validate, BulkDelete, Deleted, SharedState, Storage,
+};
+
+// Replication is cut into batches so a lagging follower doesn't receive
+// the whole log in one request.
+const MAX_ENTRIES_PER_APPEND: usize = 100;
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Node name to base URL.
+pub type Members = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    // Appended by every new leader so entries from earlier terms commit.
+    Noop,
+    Create { book: CreateBookRequest },
+    Update { id: u64, changes: UpdateBookRequest },
+    // `at` is when the leader took it, so every node gives the book the
+    // same `deleted_at`. Older entries go without.
+    Delete {
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at: Option<u64>,
+    },
+    // The full membership after a single-node change.
+    Members { members: Members },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+// The applied state up to `last_index`, replacing that prefix of the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub members: Members,
+    pub books: Vec<Book>,
+    pub next_id: u64,
+}
+
+// What a client asks the leader for. Membership changes become a
+// `Command::Members` once the leader knows the current membership.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Proposal {
+    Create { book: CreateBookRequest },
+    Update { id: u64, changes: UpdateBookRequest },
+    Delete { id: u64 },
+    AddMember { name: String, url: String },
+    RemoveMember { name: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader: String,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<Entry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendResponse {
+    pub term: u64,
+    pub success: bool,
+    // On success the last index the follower now shares with the leader;
+    // on failure a hint for where to retry from.
+    pub last_index: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: String,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoteResponse {
+    pub term: u64,
+    pub granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    pub term: u64,
+    pub leader: String,
+    pub snapshot: Snapshot,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Outcome {
+    pub book: Option<Book>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusView {
+    pub node: String,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot_index: u64,
+    pub log_entries: usize,
+    pub members: Members,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberRequest {
+    pub name: String,
+    pub url: String,
+}
+
+struct Waiter {
+    term: u64,
+    reply: oneshot::Sender<Option<Book>>,
+}
+
+// The term and vote, which a node must not forget: one that did could vote
+// twice in a term.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+// DOJO_RAFT_DIR keeps what a node needs to rejoin where it left off: the
+// term and vote in state.json, the snapshot in snapshot.json and the log
+// after it in log.jsonl, one entry per line. Whatever an RPC changed is on
+// disk before it's answered. The first two are replaced whole, through a
+// temporary file renamed over them; the log is appended to and rewritten
+// only when it was truncated or compacted.
+struct Disk {
+    dir: PathBuf,
+    log: File,
+    // What the files hold, to tell what changed since.
+    state: (u64, Option<String>),
+    snapshot_index: u64,
+    // The index and term of the last entry in log.jsonl.
+    last: (u64, u64),
+}
+
+impl Disk {
+    fn open(dir: PathBuf) -> Result<(Self, HardState, Option<Snapshot>, Vec<Entry>), String> {
+        let describe = |name: &str, e: &dyn std::fmt::Display| format!("{}: {}", dir.join(name).display(), e);
+        fs::create_dir_all(&dir).map_err(|e| describe("", &e))?;
+        let read = |name: &str| match fs::read(dir.join(name)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(describe(name, &e)),
+        };
+        let state: HardState = match read("state.json")? {
+            Some(bytes) => serde_json::from_slice(&bytes).map_err(|e| describe("state.json", &e))?,
+            None => HardState::default(),
+        };
+        let snapshot: Option<Snapshot> = match read("snapshot.json")? {
+            Some(bytes) => Some(serde_json::from_slice(&bytes).map_err(|e| describe("snapshot.json", &e))?),
+            None => None,
+        };
+        let snapshot_index = snapshot.as_ref().map_or(0, |snapshot| snapshot.last_index);
+
+        // As in the write-ahead log, a line without its newline was cut
+        // short by a crash before it was acknowledged.
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join("log.jsonl"))
+            .map_err(|e| describe("log.jsonl", &e))?;
+        let mut contents = Vec::new();
+        log.read_to_end(&mut contents).map_err(|e| describe("log.jsonl", &e))?;
+        let complete = contents.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+        if complete < contents.len() {
+            tracing::warn!("raft: dropping an unfinished entry at the end of {}", dir.join("log.jsonl").display());
+            log.set_len(complete as u64).map_err(|e| describe("log.jsonl", &e))?;
+        }
+        let mut entries: Vec<Entry> = Vec::new();
+        for (number, line) in contents[..complete].split(|b| *b == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_slice(line)
+                .map_err(|e| describe("log.jsonl", &format!("line {}: {}", number + 1, e)))?;
+            // A crash between writing a snapshot and rewriting the log
+            // leaves entries the snapshot already covers.
+            if entry.index > snapshot_index {
+                entries.push(entry);
+            }
+        }
+        let disk = Disk {
+            last: entries.last().map_or((0, 0), |entry| (entry.index, entry.term)),
+            dir,
+            log,
+            state: (state.term, state.voted_for.clone()),
+            snapshot_index,
+        };
+        Ok((disk, state, snapshot, entries))
+    }
+
+    fn replace(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let temp = self.dir.join(format!(".{}.tmp", name));
+        let mut file = File::create(&temp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp, self.dir.join(name))?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    fn lines(entries: &[Entry]) -> io::Result<Vec<u8>> {
+        let mut lines = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        Ok(lines)
+    }
+
+    // Brings the files up to date with `core`.
+    fn save(&mut self, core: &Core) -> io::Result<()> {
+        if (core.term, &core.voted_for) != (self.state.0, &self.state.1) {
+            let state = HardState {
+                term: core.term,
+                voted_for: core.voted_for.clone(),
+            };
+            self.replace("state.json", &serde_json::to_vec(&state)?)?;
+            self.state = (state.term, state.voted_for);
+        }
+        let rewrite = if core.snapshot.last_index != self.snapshot_index {
+            self.replace("snapshot.json", &serde_json::to_vec(&core.snapshot)?)?;
+            self.snapshot_index = core.snapshot.last_index;
+            true
+        } else {
+            // Entries agree on everything before them once their index
+            // and term do, so the last one written tells whether the log
+            // on disk is still a prefix of this one.
+            let (index, term) = self.last;
+            core.last_index() < index || (index > core.snapshot.last_index && core.term_at(index) != Some(term))
+        };
+        if rewrite {
+            self.replace("log.jsonl", &Disk::lines(&core.log)?)?;
+            self.log = OpenOptions::new().append(true).open(self.dir.join("log.jsonl"))?;
+        } else {
+            let from = self.last.0.max(core.snapshot.last_index);
+            let new = &core.log[core.position(from + 1).min(core.log.len())..];
+            if new.is_empty() {
+                return Ok(());
+            }
+            self.log.write_all(&Disk::lines(new)?)?;
+            self.log.sync_data()?;
+        }
+        self.last = (core.last_index(), core.last_term());
+        Ok(())
+    }
+}
+
+struct Core {
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    leader: Option<String>,
+    votes: HashSet<String>,
+    snapshot: Snapshot,
+    // Entries after the snapshot.
+    log: Vec<Entry>,
+    commit_index: u64,
+    last_applied: u64,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    in_flight: HashSet<String>,
+    election_deadline: Instant,
+    waiters: HashMap<u64, Waiter>,
+    disk: Option<Disk>,
+}
+
+impl Core {
+    fn last_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_term, |entry| entry.term)
+    }
+
+    fn position(&self, index: u64) -> usize {
+        (index - self.snapshot.last_index - 1) as usize
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        if index < self.snapshot.last_index {
+            return None;
+        }
+        self.log.get(self.position(index)).map(|entry| entry.term)
+    }
+
+    // Membership changes take effect as soon as they are appended, whether
+    // or not they have committed yet.
+    fn members_at(&self, index: u64) -> &Members {
+        self.log
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.command {
+                Command::Members { members } => Some(members),
+                _ => None,
+            })
+            .unwrap_or(&self.snapshot.members)
+    }
+
+    fn members(&self) -> &Members {
+        self.members_at(u64::MAX)
+    }
+
+    fn config_pending(&self) -> bool {
+        self.log
+            .iter()
+            .any(|entry| entry.index > self.commit_index && matches!(entry.command, Command::Members { .. }))
+    }
+
+    fn has_quorum(&self, count: impl Fn(&str) -> bool) -> bool {
+        let members = self.members();
+        members.keys().filter(|name| count(name)).count() > members.len() / 2
+    }
+
+    fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.votes.clear();
+    }
+
+    // Writes what changed since the last call to disk, if there is one.
+    fn save(&mut self) -> io::Result<()> {
+        let Some(mut disk) = self.disk.take() else {
+            return Ok(());
+        };
+        let saved = disk.save(self);
+        self.disk = Some(disk);
+        saved
+    }
+
+    // Entries that never committed are dropped; their writers are told by
+    // the closed reply channel.
+    fn truncate_from(&mut self, index: u64) {
+        let position = self.position(index);
+        self.log.truncate(position);
+        self.waiters.retain(|waiting, _| *waiting < index);
+    }
+}
+
+pub struct Raft {
+    node: String,
+    secret: String,
+    heartbeat: Duration,
+    snapshot_every: u64,
+    client: Client<HttpConnector, Body>,
+    core: Mutex<Core>,
+    wake_replication: Notify,
+    wake_apply: Notify,
+    // Held while the state machine changes, so applying entries and
+    // installing a snapshot never interleave.
+    apply_lock: tokio::sync::Mutex<()>,
+}
+
+pub enum SubmitError {
+    Rejected(StatusCode, String),
+    Unavailable(String),
+    // The request never reached the other node, so it is safe to retry.
+    NotDelivered(String),
+}
+
+impl SubmitError {
+    fn response(self) -> Response<Body> {
+        let (status, message) = match self {
+            SubmitError::Rejected(status, message) => (status, message),
+            SubmitError::Unavailable(message) | SubmitError::NotDelivered(message) => {
+                (StatusCode::SERVICE_UNAVAILABLE, message)
+            }
+        };
+        problem::respond(status, message)
+    }
+}
+
+// For catalog writes the log has no command for yet, such as patches and
+// batches.
+pub fn not_replicated(what: &str) -> Response<Body> {
+    problem::respond(StatusCode::NOT_IMPLEMENTED, format!("{} isn't supported with the raft backend yet", what))
+}
+
+fn apply(storage: &mut Storage, command: &Command) -> Option<Book> {
+    match command {
+        Command::Create { book } => Some(storage.insert_book(book.clone())),
+        Command::Update { id, changes } => storage.update_book(*id, changes.clone()),
+        Command::Delete { id, at } => storage.remove_book(*id, at.unwrap_or_else(conditional::now_ms)),
+        Command::Noop | Command::Members { .. } => None,
+    }
+}
+
+enum Outgoing {
+    Vote(String, VoteRequest),
+    Append(String, AppendRequest),
+    Snapshot(String, SnapshotRequest),
+}
+
+impl Raft {
+    // DOJO_RAFT_NODE names this node and DOJO_RAFT_MEMBERS lists the initial
+    // membership, this node included, as comma-separated `name=url` pairs.
+    // A node missing from its own list waits to be added through
+    // /admin/raft/members on the current cluster.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let node = match std::env::var("DOJO_RAFT_NODE") {
+            Ok(node) if !node.is_empty() => node,
+            _ => return Ok(None),
+        };
+        let mut members = Members::new();
+        let list = std::env::var("DOJO_RAFT_MEMBERS").unwrap_or_default();
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, url) = entry
+                .split_once('=')
+                .filter(|(name, url)| !name.is_empty() && url.starts_with("http://"))
+                .ok_or_else(|| format!("invalid member entry {:?}, expected name=http://host:port", entry))?;
+            if members.insert(name.to_string(), url.trim_end_matches('/').to_string()).is_some() {
+                return Err(format!("member {:?} is listed twice", name));
+            }
+        }
+        let number = |key: &str, default: u64| match std::env::var(key) {
+            Ok(value) => value
+                .parse::<u64>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("{} must be a positive number, got {:?}", key, value)),
+            Err(_) => Ok(default),
+        };
+        // The raft routes sit outside the versioned API and its keys, so
+        // without a shared secret anyone could propose writes.
+        let secret = std::env::var("DOJO_RAFT_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .ok_or("DOJO_RAFT_SECRET must be set when DOJO_RAFT_NODE is")?;
+        // A node that forgot its vote or its log could elect a second leader
+        // or lose committed writes, so there is no in-memory mode.
+        let dir = std::env::var("DOJO_RAFT_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .ok_or("DOJO_RAFT_DIR must be set when DOJO_RAFT_NODE is")?;
+        let heartbeat = Duration::from_millis(number("DOJO_RAFT_HEARTBEAT_MS", 100)?);
+        let snapshot_every = number("DOJO_RAFT_SNAPSHOT_EVERY", 1000)?;
+        let disk = Disk::open(PathBuf::from(dir))?;
+        Ok(Some(Raft::new(node, members, secret, heartbeat, snapshot_every, Some(disk))))
+    }
+
+    // Starts from what `disk` held, if anything: the snapshot's members
+    // then take the place of `members`.
+    fn new(
+        node: String,
+        members: Members,
+        secret: String,
+        heartbeat: Duration,
+        snapshot_every: u64,
+        disk: Option<(Disk, HardState, Option<Snapshot>, Vec<Entry>)>,
+    ) -> Self {
+        let (disk, state, snapshot, log) = match disk {
+            Some((disk, state, snapshot, log)) => (Some(disk), state, snapshot, log),
+            None => (None, HardState::default(), None, Vec::new()),
+        };
+        let snapshot = snapshot.unwrap_or(Snapshot {
+            last_index: 0,
+            last_term: 0,
+            members,
+            books: Vec::new(),
+            next_id: 1,
+        });
+        let raft = Raft {
+            node,
+            secret,
+            heartbeat,
+            snapshot_every,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            core: Mutex::new(Core {
+                term: state.term,
+                voted_for: state.voted_for,
+                role: Role::Follower,
+                leader: None,
+                votes: HashSet::new(),
+                commit_index: snapshot.last_index,
+                last_applied: snapshot.last_index,
+                snapshot,
+                log,
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                in_flight: HashSet::new(),
+                election_deadline: Instant::now(),
+                waiters: HashMap::new(),
+                disk,
+            }),
+            wake_replication: Notify::new(),
+            wake_apply: Notify::new(),
+            apply_lock: tokio::sync::Mutex::new(()),
+        };
+        raft.core.lock().unwrap().election_deadline = raft.election_deadline();
+        raft
+    }
+
+    // Randomised so that nodes rarely time out together and split the vote.
+    fn election_deadline(&self) -> Instant {
+        let spread = RandomState::new().hash_one(Instant::now()) % 1000;
+        Instant::now() + self.heartbeat * 5 + self.heartbeat * 5 * spread as u32 / 1000
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        auth::bearer_matches(headers, &self.secret)
+    }
+
+    async fn rpc<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        url: &str,
+        path: &str,
+        body: &Req,
+        timeout: Duration,
+    ) -> Result<Resp, SubmitError> {
+        let unavailable = |e: String| SubmitError::Unavailable(format!("{}{}: {}", url, path, e));
+        let body = serde_json::to_vec(body).map_err(|e| unavailable(e.to_string()))?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}{}", url, path))
+            .header(header::CONTENT_TYPE, "application/json");
+        let req = req
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.secret))
+            .body(Body::from(body)).map_err(|e| unavailable(e.to_string()))?;
+        let exchange = async {
+            let response = self.client.request(req).await.map_err(|e| match e.is_connect() {
+                true => SubmitError::NotDelivered(format!("{}{}: {}", url, path, e)),
+                false => unavailable(e.to_string()),
+            })?;
+            let status = response.status();
+            let body = body::to_bytes(response.into_body())
+                .await
+                .map_err(|e| unavailable(e.to_string()))?;
+            match status {
+                StatusCode::OK => serde_json::from_slice(&body).map_err(|e| unavailable(e.to_string())),
+                StatusCode::MISDIRECTED_REQUEST => Err(SubmitError::NotDelivered(format!("{}: not the leader", url))),
+                StatusCode::SERVICE_UNAVAILABLE => Err(unavailable(problem::detail(&body))),
+                _ => Err(SubmitError::Rejected(status, problem::detail(&body))),
+            }
+        };
+        tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| unavailable("timed out".to_string()))?
+    }
+
+    // Decides what to send this round: heartbeats and log entries as
+    // leader, votes when the election timer ran out.
+    fn tick(&self) -> Vec<Outgoing> {
+        let mut core = self.core.lock().unwrap();
+        if core.role == Role::Leader {
+            let peers: Vec<String> = core.members().keys().filter(|name| **name != self.node).cloned().collect();
+            let mut outgoing = Vec::new();
+            for peer in peers {
+                if !core.in_flight.insert(peer.clone()) {
+                    continue;
+                }
+                let next = core.next_index.get(&peer).copied().unwrap_or(core.last_index() + 1);
+                if next <= core.snapshot.last_index {
+                    let req = SnapshotRequest {
+                        term: core.term,
+                        leader: self.node.clone(),
+                        snapshot: core.snapshot.clone(),
+                    };
+                    outgoing.push(Outgoing::Snapshot(peer, req));
+                    continue;
+                }
+                let start = core.position(next);
+                let req = AppendRequest {
+                    term: core.term,
+                    leader: self.node.clone(),
+                    prev_log_index: next - 1,
+                    prev_log_term: core.term_at(next - 1).unwrap_or(0),
+                    entries: core.log[start.min(core.log.len())..]
+                        .iter()
+                        .take(MAX_ENTRIES_PER_APPEND)
+                        .cloned()
+                        .collect(),
+                    leader_commit: core.commit_index,
+                };
+                outgoing.push(Outgoing::Append(peer, req));
+            }
+            return outgoing;
+        }
+
+        if Instant::now() < core.election_deadline {
+            return Vec::new();
+        }
+        core.election_deadline = self.election_deadline();
+        if !core.members().contains_key(&self.node) {
+            return Vec::new();
+        }
+        core.term += 1;
+        core.role = Role::Candidate;
+        core.voted_for = Some(self.node.clone());
+        core.leader = None;
+        core.votes = HashSet::from([self.node.clone()]);
+        if let Err(e) = core.save() {
+            tracing::warn!("raft: not standing for election, the vote wasn't saved: {}", e);
+            core.role = Role::Follower;
+            return Vec::new();
+        }
+        if core.has_quorum(|name| name == self.node) {
+            self.become_leader(&mut core);
+            return Vec::new();
+        }
+        let req = |core: &Core| VoteRequest {
+            term: core.term,
+            candidate: self.node.clone(),
+            last_log_index: core.last_index(),
+            last_log_term: core.last_term(),
+        };
+        core.members()
+            .keys()
+            .filter(|name| **name != self.node)
+            .map(|name| Outgoing::Vote(name.clone(), req(&core)))
+            .collect()
+    }
+
+    fn become_leader(&self, core: &mut Core) {
+        tracing::info!("raft: {} is leader for term {}", self.node, core.term);
+        core.role = Role::Leader;
+        core.leader = Some(self.node.clone());
+        let next = core.last_index() + 1;
+        core.next_index = core.members().keys().map(|name| (name.clone(), next)).collect();
+        core.match_index.clear();
+        core.in_flight.clear();
+        if let Err(e) = self.append(core, Command::Noop) {
+            tracing::warn!("raft: {} steps down, its log can't be written: {}", self.node, e);
+            core.step_down(core.term);
+            core.leader = None;
+        }
+    }
+
+    // The leader counts itself among the replicas, so the entry is on its
+    // disk before anything can commit it.
+    fn append(&self, core: &mut Core, command: Command) -> io::Result<u64> {
+        let entry = Entry {
+            index: core.last_index() + 1,
+            term: core.term,
+            command,
+        };
+        let index = entry.index;
+        core.log.push(entry);
+        if let Err(e) = core.save() {
+            core.log.pop();
+            return Err(e);
+        }
+        self.advance_commit(core);
+        self.wake_replication.notify_one();
+        Ok(index)
+    }
+
+    // Only entries from the current term are committed by counting
+    // replicas; earlier ones commit along with them.
+    fn advance_commit(&self, core: &mut Core) {
+        let last = core.last_index();
+        for index in (core.commit_index + 1..=last).rev() {
+            if core.term_at(index) != Some(core.term) {
+                break;
+            }
+            let replicated = |name: &str| {
+                if name == self.node {
+                    true
+                } else {
+                    core.match_index.get(name).is_some_and(|matched| *matched >= index)
+                }
+            };
+            if core.has_quorum(replicated) {
+                core.commit_index = index;
+                self.wake_apply.notify_one();
+                break;
+            }
+        }
+        // A leader that removed itself hands over once the change commits.
+        if core.role == Role::Leader && !core.members().contains_key(&self.node) && !core.config_pending() {
+            core.step_down(core.term);
+            core.leader = None;
+        }
+    }
+
+    async fn send(&self, outgoing: Outgoing) {
+        let timeout = self.heartbeat * 5;
+        match outgoing {
+            Outgoing::Vote(peer, req) => {
+                let Some(url) = self.url_of(&peer) else { return };
+                let term = req.term;
+                let Ok(resp) = self.rpc::<_, VoteResponse>(&url, "/raft/vote", &req, timeout).await else {
+                    return;
+                };
+                let mut core = self.core.lock().unwrap();
+                if resp.term > core.term {
+                    core.step_down(resp.term);
+                } else if core.role == Role::Candidate && core.term == term && resp.granted {
+                    core.votes.insert(peer);
+                    let votes = core.votes.clone();
+                    if core.has_quorum(|name| votes.contains(name)) {
+                        self.become_leader(&mut core);
+                    }
+                }
+            }
+            Outgoing::Append(peer, req) => {
+                let url = self.url_of(&peer);
+                let result = match &url {
+                    Some(url) => self.rpc::<_, AppendResponse>(url, "/raft/append", &req, timeout).await.ok(),
+                    None => None,
+                };
+                let mut core = self.core.lock().unwrap();
+                core.in_flight.remove(&peer);
+                let Some(resp) = result else { return };
+                if resp.term > core.term {
+                    core.step_down(resp.term);
+                    return;
+                }
+                if core.role != Role::Leader || core.term != req.term {
+                    return;
+                }
+                if resp.success {
+                    let matched = core.match_index.entry(peer.clone()).or_default();
+                    *matched = (*matched).max(resp.last_index);
+                    let next = *matched + 1;
+                    core.next_index.insert(peer, next);
+                    self.advance_commit(&mut core);
+                    if next <= core.last_index() {
+                        self.wake_replication.notify_one();
+                    }
+                } else {
+                    let next = req.prev_log_index.min(resp.last_index + 1).max(1);
+                    core.next_index.insert(peer, next);
+                    self.wake_replication.notify_one();
+                }
+            }
+            Outgoing::Snapshot(peer, req) => {
+                let url = self.url_of(&peer);
+                let last_index = req.snapshot.last_index;
+                let result = match &url {
+                    Some(url) => self
+                        .rpc::<_, SnapshotResponse>(url, "/raft/snapshot", &req, Duration::from_secs(30))
+                        .await
+                        .ok(),
+                    None => None,
+                };
+                let mut core = self.core.lock().unwrap();
+                core.in_flight.remove(&peer);
+                let Some(resp) = result else { return };
+                if resp.term > core.term {
+                    core.step_down(resp.term);
+                } else if core.role == Role::Leader && core.term == req.term {
+                    core.match_index.insert(peer.clone(), last_index);
+                    core.next_index.insert(peer, last_index + 1);
+                    self.advance_commit(&mut core);
+                    self.wake_replication.notify_one();
+                }
+            }
+        }
+    }
+
+    fn url_of(&self, name: &str) -> Option<String> {
+        self.core.lock().unwrap().members().get(name).cloned()
+    }
+
+    // Answered only once the term and log it leaves are saved.
+    fn handle_append(&self, req: AppendRequest) -> io::Result<AppendResponse> {
+        let mut core = self.core.lock().unwrap();
+        let reply = self.accept_append(&mut core, req);
+        core.save()?;
+        Ok(reply)
+    }
+
+    fn accept_append(&self, core: &mut Core, req: AppendRequest) -> AppendResponse {
+        let reject = |core: &Core, last_index| AppendResponse {
+            term: core.term,
+            success: false,
+            last_index,
+        };
+        if req.term < core.term {
+            return reject(core, core.last_index());
+        }
+        core.step_down(req.term);
+        core.leader = Some(req.leader);
+        core.election_deadline = self.election_deadline();
+
+        if req.prev_log_index > core.last_index() {
+            return reject(core, core.last_index());
+        }
+        if let Some(term) = core.term_at(req.prev_log_index) {
+            if term != req.prev_log_term {
+                core.truncate_from(req.prev_log_index);
+                return reject(core, req.prev_log_index - 1);
+            }
+        }
+
+        let last_new = req.prev_log_index + req.entries.len() as u64;
+        for entry in req.entries {
+            if entry.index <= core.snapshot.last_index {
+                continue;
+            }
+            match core.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    core.truncate_from(entry.index);
+                    core.log.push(entry);
+                }
+                None => core.log.push(entry),
+            }
+        }
+        if req.leader_commit > core.commit_index {
+            core.commit_index = req.leader_commit.min(last_new).max(core.commit_index);
+            self.wake_apply.notify_one();
+        }
+        AppendResponse {
+            term: core.term,
+            success: true,
+            last_index: last_new,
+        }
+    }
+
+    // A vote is saved before it's granted.
+    fn handle_vote(&self, req: VoteRequest) -> io::Result<VoteResponse> {
+        let mut core = self.core.lock().unwrap();
+        if req.term > core.term {
+            core.step_down(req.term);
+        }
+        let up_to_date = (req.last_log_term, req.last_log_index) >= (core.last_term(), core.last_index());
+        let granted = req.term == core.term
+            && up_to_date
+            && core.voted_for.as_ref().is_none_or(|voted| *voted == req.candidate);
+        if granted {
+            core.voted_for = Some(req.candidate);
+            core.election_deadline = self.election_deadline();
+        }
+        core.save()?;
+        Ok(VoteResponse {
+            term: core.term,
+            granted,
+        })
+    }
+
+    fn propose(&self, proposal: &Proposal) -> Result<oneshot::Receiver<Option<Book>>, ProposeError> {
+        let mut core = self.core.lock().unwrap();
+        if core.role != Role::Leader {
+            let leader = core.leader.as_ref().and_then(|leader| core.members().get(leader)).cloned();
+            return Err(ProposeError::NotLeader(leader));
+        }
+        let command = match proposal.clone() {
+            Proposal::Create { book } => Command::Create { book },
+            Proposal::Update { id, changes } => Command::Update { id, changes },
+            Proposal::Delete { id } => Command::Delete {
+                id,
+                at: Some(conditional::now_ms()),
+            },
+            Proposal::AddMember { .. } | Proposal::RemoveMember { .. } if core.config_pending() => {
+                return Err(ProposeError::Rejected(
+                    StatusCode::CONFLICT,
+                    "another membership change is in progress".to_string(),
+                ))
+            }
+            Proposal::AddMember { name, url } => {
+                let mut members = core.members().clone();
+                members.insert(name, url.trim_end_matches('/').to_string());
+                Command::Members { members }
+            }
+            Proposal::RemoveMember { name } => {
+                let mut members = core.members().clone();
+                if members.remove(&name).is_none() {
+                    return Err(ProposeError::Rejected(StatusCode::NOT_FOUND, "Not found".to_string()));
+                }
+                if members.is_empty() {
+                    return Err(ProposeError::Rejected(
+                        StatusCode::CONFLICT,
+                        "cannot remove the last member".to_string(),
+                    ));
+                }
+                Command::Members { members }
+            }
+        };
+        let (reply, receiver) = oneshot::channel();
+        let index = core.last_index() + 1;
+        let term = core.term;
+        core.waiters.insert(index, Waiter { term, reply });
+        if let Err(e) = self.append(&mut core, command) {
+            core.waiters.remove(&index);
+            return Err(ProposeError::Rejected(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("the log can't be written: {}", e),
+            ));
+        }
+        Ok(receiver)
+    }
+
+    // Proposes on this node when it leads and forwards to the leader
+    // otherwise, retrying while an election is under way.
+    pub async fn submit(&self, proposal: Proposal) -> Result<Option<Book>, SubmitError> {
+        for _ in 0..20 {
+            match self.propose(&proposal) {
+                Ok(receiver) => {
+                    return match tokio::time::timeout(COMMIT_TIMEOUT, receiver).await {
+                        Ok(Ok(book)) => Ok(book),
+                        Ok(Err(_)) => Err(SubmitError::Unavailable(
+                            "leadership changed before the write committed".to_string(),
+                        )),
+                        Err(_) => Err(SubmitError::Unavailable("write did not commit in time".to_string())),
+                    };
+                }
+                Err(ProposeError::Rejected(status, message)) => return Err(SubmitError::Rejected(status, message)),
+                // A leader that has just gone away is retried until the
+                // cluster elects a new one.
+                Err(ProposeError::NotLeader(Some(url))) => {
+                    match self.rpc::<_, Outcome>(&url, "/raft/propose", &proposal, COMMIT_TIMEOUT).await {
+                        Err(SubmitError::NotDelivered(_)) => tokio::time::sleep(self.heartbeat * 2).await,
+                        result => return result.map(|outcome| outcome.book),
+                    }
+                }
+                Err(ProposeError::NotLeader(None)) => tokio::time::sleep(self.heartbeat * 2).await,
+            }
+        }
+        Err(SubmitError::Unavailable("no leader elected".to_string()))
+    }
+
+    // Adds a book through the log on behalf of another module.
+    pub async fn create(&self, book: CreateBookRequest) -> Result<Book, Response<Body>> {
+        match self.submit(Proposal::Create { book }).await {
+            Ok(Some(book)) => Ok(book),
+            Ok(None) => Err(problem::respond(StatusCode::SERVICE_UNAVAILABLE, "book was not created")),
+            Err(e) => Err(e.response()),
+        }
+    }
+
+    // Whether this node leads, who does, and in which term.
+    pub fn leadership(&self) -> (bool, Option<String>, u64) {
+        let core = self.core.lock().unwrap();
+        (core.role == Role::Leader, core.leader.clone(), core.term)
+    }
+
+    fn status(&self) -> StatusView {
+        let core = self.core.lock().unwrap();
+        StatusView {
+            node: self.node.clone(),
+            role: core.role,
+            term: core.term,
+            leader: core.leader.clone(),
+            commit_index: core.commit_index,
+            last_applied: core.last_applied,
+            snapshot_index: core.snapshot.last_index,
+            log_entries: core.log.len(),
+            members: core.members().clone(),
+        }
+    }
+}
+
+enum ProposeError {
+    NotLeader(Option<String>),
+    Rejected(StatusCode, String),
+}
+
+pub async fn run(state: SharedState) {
+    let Some(raft) = &state.raft else {
+        return;
+    };
+    // A restarted node picks up from its snapshot; the entries after it
+    // are applied again once the leader says they're committed.
+    let snapshot = raft.core.lock().unwrap().snapshot.clone();
+    if snapshot.last_index > 0 {
+        let params = format!("index={} books={}", snapshot.last_index, snapshot.books.len());
+        if let Err(e) = state.with_storage("raft_restore", || params, |storage| restore(storage, snapshot)).await {
+            tracing::error!("raft: restoring the snapshot failed: {}", e);
+            return;
+        }
+    }
+    tokio::spawn(apply_committed(state.clone()));
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(raft.heartbeat) => {}
+            _ = raft.wake_replication.notified() => {}
+        }
+        for outgoing in raft.tick() {
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Some(raft) = &state.raft {
+                    raft.send(outgoing).await;
+                }
+            });
+        }
+    }
+}
+
+// Feeds committed entries into the catalog and answers the writers
+// waiting on them. Every so often the applied state becomes the new
+// snapshot and the log before it is discarded.
+async fn apply_committed(state: SharedState) {
+    let Some(raft) = &state.raft else {
+        return;
+    };
+    loop {
+        raft.wake_apply.notified().await;
+        let _applying = raft.apply_lock.lock().await;
+        let (entries, compact) = {
+            let core = raft.core.lock().unwrap();
+            let from = core.position(core.last_applied + 1);
+            let to = core.position(core.commit_index + 1).min(core.log.len());
+            let entries: Vec<Entry> = core.log.get(from..to).unwrap_or_default().to_vec();
+            let compact = core.commit_index - core.snapshot.last_index >= raft.snapshot_every;
+            (entries, compact)
+        };
+        let Some(last) = entries.last().map(|entry| entry.index) else {
+            continue;
+        };
+
+        let params = format!("from={} to={}", entries[0].index, last);
+        let result = state
+            .with_storage("raft_apply", || params, |storage| {
+                let outcomes: Vec<(u64, u64, Option<Book>)> = entries
+                    .iter()
+                    .map(|entry| (entry.index, entry.term, apply(storage, &entry.command)))
+                    .collect();
+                let image = compact.then(|| (storage.all_books(), storage.next_id));
+                (outcomes, image)
+            })
+            .await;
+        let (outcomes, image) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::warn!("raft: applying entries failed, will retry: {}", e);
+                tokio::time::sleep(raft.heartbeat).await;
+                raft.wake_apply.notify_one();
+                continue;
+            }
+        };
+
+        let mut core = raft.core.lock().unwrap();
+        core.last_applied = last;
+        for (index, term, book) in outcomes {
+            if let Some(waiter) = core.waiters.remove(&index) {
+                if waiter.term == term {
+                    let _ = waiter.reply.send(book);
+                }
+            }
+        }
+        if let Some((mut books, next_id)) = image {
+            books.sort_by_key(|book| book.id);
+            let snapshot = Snapshot {
+                last_index: last,
+                last_term: core.term_at(last).unwrap_or(0),
+                members: core.members_at(last).clone(),
+                books,
+                next_id,
+            };
+            let covered = core.position(last + 1);
+            core.log.drain(..covered);
+            core.snapshot = snapshot;
+            // Kept in memory either way; the next save tries again.
+            if let Err(e) = core.save() {
+                tracing::warn!("raft: saving the snapshot failed: {}", e);
+            }
+        }
+        if core.last_applied < core.commit_index {
+            raft.wake_apply.notify_one();
+        }
+    }
+}
+
+fn restore(storage: &mut Storage, snapshot: Snapshot) {
+    storage.set_books(snapshot.books);
+    storage.next_id = snapshot.next_id;
+    storage.loans.retain(|id, _| storage.books.contains_key(id));
+    storage.reviews.retain(|id, _| storage.books.contains_key(id));
+}
+
+fn forbidden() -> Response<Body> {
+    problem::respond(StatusCode::FORBIDDEN, "Forbidden")
+}
+
+fn unsaved(e: io::Error) -> Response<Body> {
+    problem::respond(StatusCode::SERVICE_UNAVAILABLE, format!("raft state can't be saved: {}", e))
+}
+
+pub async fn append_entries(
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    Json(req): Json<AppendRequest>,
+) -> Result<Response<Body>, ApiError> {
+    match &state.raft {
+        Some(raft) if raft.authorized(&headers) => match raft.handle_append(req) {
+            Ok(reply) => Ok(json_response(StatusCode::OK, &reply)?),
+            Err(e) => Ok(unsaved(e)),
+        },
+        Some(_) => Ok(forbidden()),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+pub async fn request_vote(
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    Json(req): Json<VoteRequest>,
+) -> Result<Response<Body>, ApiError> {
+    match &state.raft {
+        Some(raft) if raft.authorized(&headers) => match raft.handle_vote(req) {
+            Ok(reply) => Ok(json_response(StatusCode::OK, &reply)?),
+            Err(e) => Ok(unsaved(e)),
+        },
+        Some(_) => Ok(forbidden()),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+// Replaces everything this node has applied with the leader's snapshot.
+pub async fn install_snapshot(
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    Json(req): Json<SnapshotRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = match &state.raft {
+        Some(raft) if raft.authorized(&headers) => raft,
+        Some(_) => return Ok(forbidden()),
+        None => return Err(ApiError::not_found()),
+    };
+    {
+        let mut core = raft.core.lock().unwrap();
+        if req.term < core.term {
+            return Ok(json_response(StatusCode::OK, &SnapshotResponse { term: core.term })?);
+        }
+        core.step_down(req.term);
+        core.leader = Some(req.leader.clone());
+        core.election_deadline = raft.election_deadline();
+        if let Err(e) = core.save() {
+            return Ok(unsaved(e));
+        }
+        if req.snapshot.last_index <= core.last_applied {
+            return Ok(json_response(StatusCode::OK, &SnapshotResponse { term: core.term })?);
+        }
+    }
+
+    let _applying = raft.apply_lock.lock().await;
+    let snapshot = req.snapshot;
+    let params = format!("index={} books={}", snapshot.last_index, snapshot.books.len());
+    let image = snapshot.clone();
+    state
+        .with_storage("raft_install_snapshot", || params, |storage| restore(storage, image))
+        .await?;
+
+    let mut core = raft.core.lock().unwrap();
+    if core.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+        let covered = core.position(snapshot.last_index + 1);
+        core.log.drain(..covered);
+    } else {
+        core.log.clear();
+        core.waiters.clear();
+    }
+    core.commit_index = core.commit_index.max(snapshot.last_index);
+    core.last_applied = snapshot.last_index;
+    core.snapshot = snapshot;
+    raft.wake_apply.notify_one();
+    if let Err(e) = core.save() {
+        return Ok(unsaved(e));
+    }
+    Ok(json_response(StatusCode::OK, &SnapshotResponse { term: core.term })?)
+}
+
+// Writes forwarded by followers. Only the leader accepts them; anyone else
+// answers 421 so the follower retries once a new leader is known.
+pub async fn propose(
+    headers: HeaderMap,
+    State(state): State<SharedState>,
+    Json(proposal): Json<Proposal>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = match &state.raft {
+        Some(raft) if raft.authorized(&headers) => raft,
+        Some(_) => return Ok(forbidden()),
+        None => return Err(ApiError::not_found()),
+    };
+    let receiver = match raft.propose(&proposal) {
+        Ok(receiver) => receiver,
+        Err(ProposeError::Rejected(status, message)) => return Ok(problem::respond(status, message)),
+        Err(ProposeError::NotLeader(_)) => {
+            return Ok(problem::respond(StatusCode::MISDIRECTED_REQUEST, "not the leader"))
+        }
+    };
+    match tokio::time::timeout(COMMIT_TIMEOUT, receiver).await {
+        Ok(Ok(book)) => Ok(json_response(StatusCode::OK, &Outcome { book })?),
+        _ => Ok(problem::respond(StatusCode::SERVICE_UNAVAILABLE, "write did not commit")),
+    }
+}
+
+pub async fn create_book(
+    links: Links,
+    State(state): State<SharedState>,
+    Json(mut book): Json<CreateBookRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = state.raft.as_ref().ok_or_else(ApiError::not_found)?;
+    validate::check(&mut book)?;
+    // Minted before it's proposed, so every node stores the same uid.
+    book.uid = state.ids.mint();
+    match raft.submit(Proposal::Create { book }).await {
+        Ok(Some(book)) => Ok(json_response(StatusCode::CREATED, &links.book(&book, &book))?),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(e) => Ok(e.response()),
+    }
+}
+
+// If-Match is checked against this node's copy before the write is
+// proposed, not when the log applies it, so a write committed in between
+// by another node can still be overwritten.
+async fn precondition(state: &SharedState, id: u64, if_match: &IfMatch) -> Result<(), ApiError> {
+    let book = state.get(id).await?.ok_or_else(ApiError::not_found)?;
+    Ok(if_match.check(&book)?)
+}
+
+pub async fn update_book(
+    Path(id): Path<u64>,
+    if_match: IfMatch,
+    links: Links,
+    State(state): State<SharedState>,
+    Json(mut changes): Json<UpdateBookRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = state.raft.as_ref().ok_or_else(ApiError::not_found)?;
+    validate::check(&mut changes)?;
+    precondition(&state, id, &if_match).await?;
+    match raft.submit(Proposal::Update { id, changes }).await {
+        Ok(Some(book)) => Ok(etag::tagged(json_response(StatusCode::OK, &links.book(&book, &book))?, &book)),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(e) => Ok(e.response()),
+    }
+}
+
+pub async fn delete_book(
+    Path(id): Path<u64>,
+    if_match: IfMatch,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = state.raft.as_ref().ok_or_else(ApiError::not_found)?;
+    precondition(&state, id, &if_match).await?;
+    match raft.submit(Proposal::Delete { id }).await {
+        Ok(Some(_)) => Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap()),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(e) => Ok(e.response()),
+    }
+}
+
+// Picks the books from this node's copy of the catalog and deletes them one
+// entry at a time, so a failure part way leaves the earlier ones deleted.
+pub async fn delete_books(
+    if_match: IfMatch,
+    State(state): State<SharedState>,
+    Json(request): Json<BulkDelete>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = state.raft.as_ref().ok_or_else(ApiError::not_found)?;
+    let selection = request.selection().map_err(ApiError::BadRequest)?;
+    let books = state.list().await?;
+    let mut deleted = 0;
+    for book in books.iter().filter(|book| selection.matches(book) && if_match.holds(book)) {
+        match raft.submit(Proposal::Delete { id: book.id }).await {
+            Ok(Some(_)) => deleted += 1,
+            Ok(None) => {}
+            Err(e) => return Ok(e.response()),
+        }
+    }
+    Ok(json_response(StatusCode::OK, &Deleted { deleted })?)
+}
+
+pub async fn status(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let raft = state.raft.as_ref().ok_or_else(ApiError::not_found)?;
+    Ok(json_response(StatusCode::OK, &raft.status())?)
+}
+
+// Membership changes go through the log one node at a time, so old and
+// new majorities always overlap.
+pub async fn add_member(
+    State(state): State<SharedState>,
+    Json(member): Json<MemberRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = state.raft.as_ref().ok_or_else(ApiError::not_found)?;
+    if !member.url.starts_with("http://") {
+        return Err(ApiError::bad_request("url must start with http://"));
+    }
+    let proposal = Proposal::AddMember {
+        name: member.name,
+        url: member.url,
+    };
+    match raft.submit(proposal).await {
+        Ok(_) => Ok(json_response(StatusCode::OK, &raft.status())?),
+        Err(e) => Ok(e.response()),
+    }
+}
+
+pub async fn remove_member(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let raft = state.raft.as_ref().ok_or_else(ApiError::not_found)?;
+    match raft.submit(Proposal::RemoveMember { name }).await {
+        Ok(_) => Ok(json_response(StatusCode::OK, &raft.status())?),
+        Err(e) => Ok(e.response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, members: &[&str]) -> Raft {
+        let members = members
+            .iter()
+            .map(|member| (member.to_string(), format!("http://{}", member)))
+            .collect();
+        Raft::new(name.to_string(), members, "shared".to_string(), Duration::from_millis(100), 1000, None)
+    }
+
+    // A node kept in `dir`, as it comes back after a restart.
+    fn durable(name: &str, members: &[&str], dir: &std::path::Path) -> Raft {
+        let members = members
+            .iter()
+            .map(|member| (member.to_string(), format!("http://{}", member)))
+            .collect();
+        let disk = Disk::open(dir.to_path_buf()).unwrap();
+        Raft::new(name.to_string(), members, "shared".to_string(), Duration::from_millis(100), 1000, Some(disk))
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dojo-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            command: Command::Noop,
+        }
+    }
+
+    fn terms(raft: &Raft) -> Vec<u64> {
+        raft.core.lock().unwrap().log.iter().map(|entry| entry.term).collect()
+    }
+
+    fn vote(term: u64, candidate: &str, last_log_index: u64, last_log_term: u64) -> VoteRequest {
+        VoteRequest {
+            term,
+            candidate: candidate.to_string(),
+            last_log_index,
+            last_log_term,
+        }
+    }
+
+    fn append(term: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64) -> AppendRequest {
+        AppendRequest {
+            term,
+            leader: "a".to_string(),
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+        }
+    }
+
+    #[test]
+    fn timed_out_followers_stand_for_election() {
+        let a = node("a", &["a", "b", "c"]);
+        a.core.lock().unwrap().election_deadline = Instant::now();
+        let votes: Vec<(String, u64)> = a
+            .tick()
+            .into_iter()
+            .map(|outgoing| match outgoing {
+                Outgoing::Vote(peer, req) => (peer, req.term),
+                _ => panic!("a candidate only asks for votes"),
+            })
+            .collect();
+        assert_eq!(votes, [("b".to_string(), 1), ("c".to_string(), 1)]);
+        let core = a.core.lock().unwrap();
+        assert_eq!(core.role, Role::Candidate);
+        assert_eq!(core.voted_for.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn a_lone_node_leads_at_once() {
+        let a = node("a", &["a"]);
+        a.core.lock().unwrap().election_deadline = Instant::now();
+        assert!(a.tick().is_empty());
+        let core = a.core.lock().unwrap();
+        assert_eq!(core.role, Role::Leader);
+        // The new leader's no-op commits with a quorum of one.
+        assert_eq!(core.commit_index, 1);
+        assert!(matches!(core.log[..], [Entry { index: 1, term: 1, command: Command::Noop }]));
+    }
+
+    #[test]
+    fn votes_go_to_one_up_to_date_candidate_per_term() {
+        let b = node("b", &["a", "b", "c"]);
+        b.core.lock().unwrap().log = vec![entry(1, 1), entry(2, 2)];
+
+        // A log that ends in an older term, or is shorter, loses.
+        assert!(!b.handle_vote(vote(3, "a", 5, 1)).unwrap().granted);
+        assert!(!b.handle_vote(vote(3, "a", 1, 2)).unwrap().granted);
+        // Either way the voter moved on to the newer term.
+        assert_eq!(b.core.lock().unwrap().term, 3);
+
+        assert!(b.handle_vote(vote(3, "a", 2, 2)).unwrap().granted);
+        assert!(b.handle_vote(vote(3, "a", 2, 2)).unwrap().granted);
+        assert!(!b.handle_vote(vote(3, "c", 3, 2)).unwrap().granted);
+        assert!(!b.handle_vote(vote(2, "c", 3, 2)).unwrap().granted);
+        let reply = b.handle_vote(vote(4, "c", 3, 2)).unwrap();
+        assert!(reply.granted);
+        assert_eq!(reply.term, 4);
+    }
+
+    #[test]
+    fn appends_extend_the_log_and_commit() {
+        let b = node("b", &["a", "b"]);
+        let reply = b.handle_append(append(1, 0, 0, vec![entry(1, 1), entry(2, 1)], 1)).unwrap();
+        assert!(reply.success);
+        assert_eq!(reply.last_index, 2);
+        assert_eq!(terms(&b), [1, 1]);
+        assert_eq!(b.core.lock().unwrap().commit_index, 1);
+        assert_eq!(b.core.lock().unwrap().leader.as_deref(), Some("a"));
+
+        // A repeated append changes nothing; the commit follows the leader's
+        // but never past what this request covered.
+        let reply = b.handle_append(append(1, 0, 0, vec![entry(1, 1)], 5)).unwrap();
+        assert!(reply.success);
+        assert_eq!(terms(&b), [1, 1]);
+        assert_eq!(b.core.lock().unwrap().commit_index, 1);
+        b.handle_append(append(1, 2, 1, Vec::new(), 5)).unwrap();
+        assert_eq!(b.core.lock().unwrap().commit_index, 2);
+    }
+
+    #[test]
+    fn conflicting_entries_are_truncated() {
+        let b = node("b", &["a", "b", "c"]);
+        b.core.lock().unwrap().log = vec![entry(1, 1), entry(2, 1), entry(3, 1), entry(4, 1)];
+        b.core.lock().unwrap().term = 1;
+
+        // The leader's entry 3 comes from another term: 3 and 4 go.
+        let reply = b.handle_append(append(2, 2, 1, vec![entry(3, 2)], 0)).unwrap();
+        assert!(reply.success);
+        assert_eq!(terms(&b), [1, 1, 2]);
+
+        // A mismatch at the previous entry drops it and points the leader
+        // one back.
+        let reply = b.handle_append(append(3, 3, 3, vec![entry(4, 3)], 0)).unwrap();
+        assert!(!reply.success);
+        assert_eq!(reply.last_index, 2);
+        assert_eq!(terms(&b), [1, 1]);
+
+        // A gap is refused with where the log ends.
+        let reply = b.handle_append(append(3, 5, 3, vec![entry(6, 3)], 0)).unwrap();
+        assert!(!reply.success);
+        assert_eq!(reply.last_index, 2);
+
+        // So is a deposed leader, without touching the log.
+        let reply = b.handle_append(append(2, 0, 0, vec![entry(1, 2)], 0)).unwrap();
+        assert!(!reply.success);
+        assert_eq!(reply.term, 3);
+        assert_eq!(terms(&b), [1, 1]);
+    }
+
+    #[test]
+    fn restarted_nodes_remember_their_vote() {
+        let dir = scratch("raft-vote");
+        let b = durable("b", &["a", "b", "c"], &dir);
+        assert!(b.handle_vote(vote(3, "a", 0, 0)).unwrap().granted);
+        drop(b);
+
+        let b = durable("b", &["a", "b", "c"], &dir);
+        assert_eq!(b.core.lock().unwrap().term, 3);
+        assert!(!b.handle_vote(vote(3, "c", 0, 0)).unwrap().granted);
+        assert!(b.handle_vote(vote(3, "a", 0, 0)).unwrap().granted);
+        // A candidate's vote for itself is kept too.
+        b.core.lock().unwrap().election_deadline = Instant::now();
+        b.tick();
+        drop(b);
+        let b = durable("b", &["a", "b", "c"], &dir);
+        assert!(!b.handle_vote(vote(4, "a", 0, 0)).unwrap().granted);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn restarted_nodes_keep_their_log() {
+        let dir = scratch("raft-log");
+        let b = durable("b", &["a", "b", "c"], &dir);
+        b.handle_append(append(1, 0, 0, vec![entry(1, 1), entry(2, 1), entry(3, 1)], 0)).unwrap();
+        b.handle_append(append(2, 1, 1, vec![entry(2, 2)], 0)).unwrap();
+        b.handle_append(append(2, 2, 2, vec![entry(3, 2), entry(4, 2)], 0)).unwrap();
+        drop(b);
+
+        let b = durable("b", &["a", "b", "c"], &dir);
+        assert_eq!(terms(&b), [1, 2, 2, 2]);
+        {
+            let mut core = b.core.lock().unwrap();
+            core.snapshot = Snapshot {
+                last_index: 2,
+                last_term: 2,
+                members: core.members().clone(),
+                books: Vec::new(),
+                next_id: 7,
+            };
+            core.log.drain(..2);
+            core.save().unwrap();
+        }
+        b.handle_append(append(2, 4, 2, vec![entry(5, 2)], 0)).unwrap();
+        drop(b);
+
+        // A torn write at the end is dropped.
+        OpenOptions::new().append(true).open(dir.join("log.jsonl")).unwrap().write_all(b"{\"index\":6").unwrap();
+        let b = durable("b", &["a", "b", "c"], &dir);
+        let core = b.core.lock().unwrap();
+        assert_eq!((core.snapshot.last_index, core.snapshot.next_id), (2, 7));
+        assert_eq!((core.commit_index, core.last_applied), (2, 2));
+        assert_eq!(core.log.iter().map(|entry| entry.index).collect::<Vec<_>>(), [3, 4, 5]);
+        drop(core);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn peers_prove_themselves_with_the_secret() {
+        let a = node("a", &["a"]);
+        let headers = |value: &str| HeaderMap::from_iter([(header::AUTHORIZATION, value.parse().unwrap())]);
+        assert!(a.authorized(&headers("Bearer shared")));
+        assert!(!a.authorized(&headers("Bearer other")));
+        assert!(!a.authorized(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,294 @@
+use hyper::{header, Request, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use crate::{
+    auth,
+    body::{self, Body},
+    problem,
+    proxy::ClientIp,
+    redis::{Address, Conn, Reply},
+    stack::Next,
+    SharedState,
+};
+
+// Refills the bucket for the time since it was last touched, then takes a
+// token. Redis' own clock is used so replicas with skewed clocks agree.
+// Returns {allowed, milliseconds until a token is available}.
+const TOKEN_BUCKET: &str = r#"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or burst
+local at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - at) * rate / 1000)
+local wait = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+else
+  wait = math.ceil((1 - tokens) * 1000 / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / rate) + 1000)
+if wait == 0 then return {1, 0} end
+return {0, wait}
+"#;
+
+// How long a failed Redis stays out of the picture before it's tried again,
+// so an outage doesn't add a connect timeout to every request.
+const REDIS_RETRY: Duration = Duration::from_secs(5);
+const REDIS_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+}
+
+// Whose bucket a request draws from. Keys are held by their configured
+// name, so the secret itself never reaches Redis.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Key(String),
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Client::Ip(ip) => write!(f, "{}", ip),
+            Client::Key(name) => write!(f, "key:{}", name),
+        }
+    }
+}
+
+struct Redis {
+    address: Address,
+    prefix: String,
+    // Idle connections; each request takes one or opens a new one.
+    idle: Mutex<Vec<Conn>>,
+    down_since: Mutex<Option<Instant>>,
+}
+
+// Token buckets keyed by client IP. DOJO_RATE_LIMIT_RPS sets the refill
+// rate and DOJO_RATE_LIMIT_BURST the bucket size (the rate, rounded up, by
+// default). DOJO_RATE_LIMIT_BY=api-key gives each configured API key its
+// own bucket, wherever its requests come from; requests without a valid
+// one are still keyed by IP, so made-up keys can't each get a fresh
+// bucket. With DOJO_RATE_LIMIT_REDIS=redis://[:password@]host:port the buckets
+// live in Redis and are shared by every replica; while Redis is unreachable
+// each replica falls back to its own buckets.
+pub struct Limiter {
+    rate: f64,
+    burst: f64,
+    by_key: bool,
+    local: Mutex<HashMap<Client, Bucket>>,
+    redis: Option<Redis>,
+}
+
+impl Limiter {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(rate) = std::env::var("DOJO_RATE_LIMIT_RPS") else {
+            return Ok(None);
+        };
+        let rate: f64 = rate
+            .parse()
+            .ok()
+            .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+            .ok_or_else(|| format!("DOJO_RATE_LIMIT_RPS must be a positive number, got {:?}", rate))?;
+        let burst = match std::env::var("DOJO_RATE_LIMIT_BURST") {
+            Ok(burst) => burst
+                .parse::<u32>()
+                .ok()
+                .filter(|burst| *burst > 0)
+                .ok_or_else(|| format!("DOJO_RATE_LIMIT_BURST must be a positive integer, got {:?}", burst))?
+                as f64,
+            Err(_) => rate.ceil(),
+        };
+        let by_key = match std::env::var("DOJO_RATE_LIMIT_BY").as_deref() {
+            Err(_) | Ok("ip") => false,
+            Ok("api-key") => true,
+            Ok(other) => return Err(format!("DOJO_RATE_LIMIT_BY must be ip or api-key, got {:?}", other)),
+        };
+        let redis = match std::env::var("DOJO_RATE_LIMIT_REDIS") {
+            Ok(url) => Some(Redis::parse(&url)?),
+            Err(_) => None,
+        };
+        Ok(Some(Limiter {
+            rate,
+            burst,
+            by_key,
+            local: Mutex::new(HashMap::new()),
+            redis,
+        }))
+    }
+
+    fn client(&self, req: &Request<Body>, keys: &auth::ApiKeys) -> Option<Client> {
+        let key = keys.name(req.headers()).filter(|_| self.by_key);
+        match key {
+            Some(name) => Some(Client::Key(name.to_string())),
+            None => req.extensions().get::<ClientIp>().map(|ip| Client::Ip(ip.0)),
+        }
+    }
+
+    // Ok(()) admits the request; Err carries how long to wait.
+    async fn take(&self, client: Client) -> Result<(), Duration> {
+        if let Some(redis) = &self.redis {
+            if let Some(result) = redis.take(&client, self.rate, self.burst).await {
+                return result;
+            }
+        }
+        self.take_local(client)
+    }
+
+    fn take_local(&self, client: Client) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.local.lock().unwrap();
+        // Idle buckets are full again, so they can go.
+        let full_after = Duration::from_secs_f64(self.burst / self.rate);
+        if buckets.len() > 10_000 {
+            buckets.retain(|_, bucket| now.duration_since(bucket.at) < full_after);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            at: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.at).as_secs_f64() * self.rate).min(self.burst);
+        bucket.at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+impl Redis {
+    fn parse(url: &str) -> Result<Self, String> {
+        Ok(Redis {
+            address: Address::parse("DOJO_RATE_LIMIT_REDIS", url)?,
+            prefix: std::env::var("DOJO_RATE_LIMIT_PREFIX").unwrap_or_else(|_| "dojo:ratelimit:".to_string()),
+            idle: Mutex::new(Vec::new()),
+            down_since: Mutex::new(None),
+        })
+    }
+
+    // None means Redis couldn't answer and the caller should decide locally.
+    async fn take(&self, client: &Client, rate: f64, burst: f64) -> Option<Result<(), Duration>> {
+        if self.down_since.lock().unwrap().is_some_and(|since| since.elapsed() < REDIS_RETRY) {
+            return None;
+        }
+        let key = format!("{}{}", self.prefix, client);
+        let args = ["EVAL", TOKEN_BUCKET, "1", &key, &rate.to_string(), &burst.to_string()];
+        let idle = self.idle.lock().unwrap().pop();
+        let exchange = async {
+            let mut conn = match idle {
+                Some(conn) => conn,
+                None => Conn::connect(&self.address).await?,
+            };
+            let reply = command(&mut conn, &args).await?;
+            Ok::<_, String>((conn, reply))
+        };
+        // A connection that failed or timed out may be mid-reply, so it's
+        // dropped rather than put back.
+        let (conn, reply) = match tokio::time::timeout(REDIS_TIMEOUT, exchange).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => return self.failed(e),
+            Err(_) => return self.failed("timed out".to_string()),
+        };
+        let result = match reply.as_slice() {
+            [1, _] => Ok(()),
+            [0, wait] => Err(Duration::from_millis(*wait as u64)),
+            _ => return self.failed(format!("unexpected reply {:?}", reply)),
+        };
+        self.idle.lock().unwrap().push(conn);
+        if self.down_since.lock().unwrap().take().is_some() {
+            tracing::info!("rate limiter reconnected to redis at {}", self.address.addr);
+        }
+        Some(result)
+    }
+
+    fn failed(&self, error: String) -> Option<Result<(), Duration>> {
+        let mut down_since = self.down_since.lock().unwrap();
+        if down_since.is_none() {
+            tracing::warn!("rate limiter falling back to local buckets, redis at {} failed: {}", self.address.addr, error);
+        }
+        *down_since = Some(Instant::now());
+        None
+    }
+}
+
+async fn command(conn: &mut Conn, args: &[&str]) -> Result<Vec<i64>, String> {
+    match conn.call(args).await? {
+        Reply::Array(Some(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                Reply::Integer(n) => Ok(n),
+                other => Err(format!("expected an integer, got {:?}", other)),
+            })
+            .collect(),
+        other => Err(format!("expected an array, got {:?}", other)),
+    }
+}
+
+fn too_many_requests(wait: Duration) -> Response<Body> {
+    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = problem::respond(StatusCode::TOO_MANY_REQUESTS, format!("Retry in {}s", secs));
+    response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+    response
+}
+
+// Requests without a known client address or API key, e.g. over a unix
+// socket, aren't limited.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let limited =
+        state.rate_limiter.as_ref().and_then(|limiter| Some((limiter, limiter.client(&req, &state.api_keys)?)));
+    if let Some((limiter, client)) = limited {
+        if let Err(wait) = limiter.take(client).await {
+            return Ok(too_many_requests(wait));
+        }
+    }
+    next.run(req, state).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn limiter() -> Limiter {
+        Limiter {
+            rate: 1.0,
+            burst: 1.0,
+            by_key: true,
+            local: Mutex::new(HashMap::new()),
+            redis: None,
+        }
+    }
+
+    fn request(key: &str) -> Request<Body> {
+        let mut req = Request::builder().header(auth::API_KEY_HEADER, key).body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ClientIp(IpAddr::from(Ipv4Addr::LOCALHOST)));
+        req
+    }
+
+    // A key nobody configured is no key at all, or each made-up one would
+    // start with a full bucket.
+    #[test]
+    fn unknown_keys_share_the_address_bucket() {
+        let limiter = limiter();
+        let keys = auth::ApiKeys::default();
+        let first = limiter.client(&request("made-up-1"), &keys).unwrap();
+        let second = limiter.client(&request("made-up-2"), &keys).unwrap();
+        assert_eq!(first, Client::Ip(IpAddr::from(Ipv4Addr::LOCALHOST)));
+        assert_eq!(first, second);
+        assert!(limiter.take_local(first).is_ok());
+        assert!(limiter.take_local(second).is_err());
+    }
+}
@@ -0,0 +1,1227 @@
+use books_model::{Author, Book, CreateBookRequest, Edition};
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::BuildHasher,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::{Semaphore, SemaphorePermit},
+};
+
+use crate::{
+    conditional::now_ms,
+    isbn, migrate,
+    store::{AuthorStore, BookStore},
+    StorageError,
+};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(5);
+// How often an update or delete retries after another instance changed the
+// book under it.
+const MAX_ATTEMPTS: usize = 20;
+
+// The migrations, oldest first; <prefix>schema_version counts those a
+// catalog has had. Each is safe to repeat, as instances starting together
+// may both run one before either records it.
+#[derive(Clone, Copy)]
+enum Migration {
+    IndexIsbns,
+    RekeyIsbns,
+}
+
+const MIGRATIONS: [Migration; 2] = [Migration::IndexIsbns, Migration::RekeyIsbns];
+
+// A server given as redis://[:password@]host[:port].
+pub struct Address {
+    pub addr: String,
+    password: Option<String>,
+}
+
+impl Address {
+    pub fn parse(var: &str, url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| format!("{} must look like redis://host:port, got {:?}", var, url))?;
+        let (password, addr) = match rest.rsplit_once('@') {
+            Some((auth, addr)) => (Some(auth.trim_start_matches(':').to_string()), addr),
+            None => (None, rest),
+        };
+        let addr = addr.trim_end_matches('/');
+        let addr = match addr.contains(':') {
+            true => addr.to_string(),
+            false => format!("{}:6379", addr),
+        };
+        Ok(Address { addr, password })
+    }
+}
+
+#[derive(Debug)]
+pub enum Reply {
+    Status,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    // None is the nil array EXEC answers with when a watched key changed.
+    Array(Option<Vec<Reply>>),
+}
+
+pub struct Conn {
+    stream: BufStream<TcpStream>,
+}
+
+impl Conn {
+    pub async fn connect(address: &Address) -> Result<Self, String> {
+        let stream = TcpStream::connect(&address.addr)
+            .await
+            .map_err(|e| format!("connecting to {}: {}", address.addr, e))?;
+        let mut conn = Conn {
+            stream: BufStream::new(stream),
+        };
+        if let Some(password) = &address.password {
+            conn.call(&["AUTH", password]).await?;
+        }
+        Ok(conn)
+    }
+
+    // Buffers a command; `flush` sends everything queued, so several can go
+    // out in one round trip.
+    pub async fn queue(&mut self, args: &[&str]) -> Result<(), String> {
+        let mut out = format!("*{}\r\n", args.len());
+        for arg in args {
+            out.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.stream.write_all(out.as_bytes()).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), String> {
+        self.stream.flush().await.map_err(|e| e.to_string())
+    }
+
+    pub async fn call(&mut self, args: &[&str]) -> Result<Reply, String> {
+        self.queue(args).await?;
+        self.flush().await?;
+        self.read().await
+    }
+
+    // Just enough of RESP2 for the replies this server gets back.
+    pub async fn read(&mut self) -> Result<Reply, String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+            return Err("connection closed".to_string());
+        }
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at_checked(1).ok_or("empty reply")?;
+        let number = || rest.parse::<i64>().map_err(|_| format!("malformed reply {:?}", line));
+        match kind {
+            "+" => Ok(Reply::Status),
+            "-" => Err(rest.to_string()),
+            ":" => Ok(Reply::Integer(number()?)),
+            "$" => {
+                let len = number()?;
+                if len < 0 {
+                    return Ok(Reply::Bulk(None));
+                }
+                let mut data = vec![0; len as usize + 2];
+                self.stream.read_exact(&mut data).await.map_err(|e| e.to_string())?;
+                data.truncate(len as usize);
+                Ok(Reply::Bulk(Some(data)))
+            }
+            "*" => {
+                let len = number()?;
+                if len < 0 {
+                    return Ok(Reply::Array(None));
+                }
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    items.push(Box::pin(self.read()).await?);
+                }
+                Ok(Reply::Array(Some(items)))
+            }
+            _ => Err(format!("unknown reply {:?}", line)),
+        }
+    }
+}
+
+// A catalog in Redis, shared by every instance pointed at the same server.
+// DOJO_STORAGE=redis://[:password@]host[:port] selects it. Each book is a
+// hash under <prefix>book:<id>, <prefix>books is the set of ids and
+// <prefix>next_book_id hands them out; authors are kept the same way under
+// <prefix>author:<id>, <prefix>authors and <prefix>next_author_id.
+// <prefix>isbns maps each ISBN in the catalog, in its `isbn::key` form, to
+// the book that has it, and
+// <prefix>schema_version counts the migrations run on the catalog.
+// DOJO_REDIS_PREFIX is `dojo:` by default. DOJO_REDIS_POOL_SIZE caps the open connections (10 by default).
+pub struct Redis {
+    address: Address,
+    prefix: String,
+    idle: Mutex<Vec<Conn>>,
+    slots: Semaphore,
+}
+
+impl Redis {
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        let size = match std::env::var("DOJO_REDIS_POOL_SIZE") {
+            Ok(size) => size
+                .parse()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| format!("DOJO_REDIS_POOL_SIZE must be a positive integer, got {:?}", size))?,
+            Err(_) => 10,
+        };
+        Ok(Redis {
+            address: Address::parse("DOJO_STORAGE", url)?,
+            prefix: std::env::var("DOJO_REDIS_PREFIX").unwrap_or_else(|_| "dojo:".to_string()),
+            idle: Mutex::new(Vec::new()),
+            slots: Semaphore::new(size),
+        })
+    }
+
+    pub fn describe(&self) -> &str {
+        &self.address.addr
+    }
+
+    // Checks the server answers, so a wrong address fails at startup.
+    pub async fn ping(&self) -> Result<(), String> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            conn.call(&["PING"]).await?;
+            conn.release();
+            Ok::<_, String>(())
+        })
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    pub async fn migrate(&self) -> Result<(), String> {
+        let key = self.schema_version_key();
+        let reply = timed(async {
+            let mut conn = self.checkout().await?;
+            let reply = conn.call(&["GET", &key]).await?;
+            conn.release();
+            Ok::<_, String>(reply)
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        let version = match reply {
+            Reply::Bulk(None) => 0,
+            reply => {
+                let version = text(reply)?;
+                version.parse().map_err(|_| format!("malformed schema version {:?}", version))?
+            }
+        };
+        for index in migrate::pending("the catalog", version, MIGRATIONS.len())? {
+            match MIGRATIONS[index] {
+                Migration::IndexIsbns => self.index_isbns().await?,
+                Migration::RekeyIsbns => self.rekey_isbns().await?,
+            }
+            let version = (index + 1).to_string();
+            timed(async {
+                let mut conn = self.checkout().await?;
+                conn.call(&["SET", &key, &version]).await?;
+                conn.release();
+                Ok::<_, String>(())
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Builds the ISBN index for a catalog written before there was one, or
+    // whose books have none yet. Where several books share an ISBN the
+    // first by id keeps it.
+    async fn index_isbns(&self) -> Result<(), String> {
+        let isbns = self.isbns_key();
+        timed(async {
+            let mut conn = self.checkout().await?;
+            if let Reply::Integer(1) = conn.call(&["EXISTS", &isbns]).await? {
+                conn.release();
+                return Ok(());
+            }
+            conn.release();
+            let books = self.fetch(0, usize::MAX, false).await?;
+            let mut conn = self.checkout().await?;
+            let mut queued = 0;
+            for book in &books {
+                if let Some(isbn) = &book.isbn {
+                    conn.queue(&["HSETNX", &isbns, &isbn::key(isbn), &book.id.to_string()]).await?;
+                    queued += 1;
+                }
+            }
+            conn.flush().await?;
+            for _ in 0..queued {
+                conn.read().await?;
+            }
+            conn.release();
+            Ok::<_, String>(())
+        })
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    // Rebuilds an ISBN index written before it was keyed by `isbn::key`,
+    // when it still held ISBN-10s apart from their ISBN-13s.
+    async fn rekey_isbns(&self) -> Result<(), String> {
+        let isbns = self.isbns_key();
+        timed(async {
+            let mut conn = self.checkout().await?;
+            conn.call(&["DEL", &isbns]).await?;
+            conn.release();
+            Ok::<_, String>(())
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        self.index_isbns().await
+    }
+
+    fn book_key(&self, id: u64) -> String {
+        format!("{}book:{}", self.prefix, id)
+    }
+
+    fn index_key(&self) -> String {
+        format!("{}books", self.prefix)
+    }
+
+    // A deleted book's hash is renamed to this, and its id moves from the
+    // index to `trash_key`.
+    fn deleted_key(&self, id: u64) -> String {
+        format!("{}deleted:{}", self.prefix, id)
+    }
+
+    fn trash_key(&self) -> String {
+        format!("{}deleted", self.prefix)
+    }
+
+    fn author_key(&self, id: u64) -> String {
+        format!("{}author:{}", self.prefix, id)
+    }
+
+    fn authors_key(&self) -> String {
+        format!("{}authors", self.prefix)
+    }
+
+    // When anything in the catalog last changed. Each book's hash keeps its
+    // own time under `modified`.
+    fn modified_key(&self) -> String {
+        format!("{}modified", self.prefix)
+    }
+
+    fn schema_version_key(&self) -> String {
+        format!("{}schema_version", self.prefix)
+    }
+
+    fn isbns_key(&self) -> String {
+        format!("{}isbns", self.prefix)
+    }
+
+    // Which book has each uid. Deleted books keep theirs, so the trash can
+    // be reached by uid too.
+    fn uids_key(&self) -> String {
+        format!("{}uids", self.prefix)
+    }
+
+    async fn checkout(&self) -> Result<Pooled<'_>, String> {
+        let slot = tokio::time::timeout(CHECKOUT_TIMEOUT, self.slots.acquire())
+            .await
+            .map_err(|_| "timed out waiting for a Redis connection".to_string())?
+            .map_err(|e| e.to_string())?;
+        let idle = self.idle.lock().unwrap().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => Conn::connect(&self.address).await?,
+        };
+        Ok(Pooled {
+            pool: self,
+            conn: Some(conn),
+            reusable: false,
+            _slot: slot,
+        })
+    }
+
+    // WATCHes the book and reads it, so the EXEC that follows only goes
+    // through if nobody changed it meanwhile.
+    async fn watch(&self, conn: &mut Conn, id: u64) -> Result<Option<Book>, String> {
+        let key = self.book_key(id);
+        conn.call(&["WATCH", &key]).await?;
+        let book = book_from_hash(id, conn.call(&["HGETALL", &key]).await?)?;
+        if book.is_none() {
+            conn.call(&["UNWATCH"]).await?;
+        }
+        Ok(book)
+    }
+
+    // WATCHes the ISBN index and reads which book has each ISBN, in either
+    // form, so the EXEC that follows fails if another write claims one
+    // meanwhile.
+    async fn isbn_holders(&self, conn: &mut Conn, isbns: &[&str]) -> Result<Vec<Option<u64>>, String> {
+        if isbns.is_empty() {
+            return Ok(Vec::new());
+        }
+        let key = self.isbns_key();
+        conn.call(&["WATCH", &key]).await?;
+        let keys: Vec<String> = isbns.iter().map(|isbn| isbn::key(isbn)).collect();
+        let mut hmget = vec!["HMGET", key.as_str()];
+        hmget.extend(keys.iter().map(String::as_str));
+        let reply = conn.call(&hmget).await?;
+        let Reply::Array(Some(holders)) = reply else {
+            return Err(format!("expected an array from HMGET, got {:?}", reply));
+        };
+        let mut ids = Vec::with_capacity(holders.len());
+        for holder in holders {
+            ids.push(match holder {
+                Reply::Bulk(None) => None,
+                holder => {
+                    let id = text(holder)?;
+                    Some(id.parse().map_err(|_| format!("malformed book id {:?}", id))?)
+                }
+            });
+        }
+        Ok(ids)
+    }
+
+    // The first of the ISBNs that a book other than `id` has, as the error
+    // that refuses the write. The watches are dropped with it.
+    async fn isbn_taken(&self, conn: &mut Conn, isbns: &[&str], id: u64) -> Result<Option<StorageError>, String> {
+        let holders = self.isbn_holders(conn, isbns).await?;
+        let taken = isbns.iter().zip(holders).find_map(|(isbn, holder)| {
+            holder.filter(|holder| *holder != id).map(|holder| StorageError::DuplicateIsbn {
+                isbn: isbn.to_string(),
+                id: holder,
+            })
+        });
+        if taken.is_some() {
+            conn.call(&["UNWATCH"]).await?;
+        }
+        Ok(taken)
+    }
+
+    // Takes book `id`, leaving the catalog with the ISBN keyed `isbn`, out
+    // of the ISBN index, unless it shares the ISBN with one that holds it.
+    async fn release_isbn<'a>(
+        &self,
+        conn: &mut Conn,
+        id: u64,
+        isbn: Option<&'a str>,
+        isbns: &'a str,
+        commands: &mut Vec<Vec<&'a str>>,
+    ) -> Result<(), String> {
+        if let Some(isbn) = isbn {
+            if self.isbn_holders(conn, &[isbn]).await? == [Some(id)] {
+                commands.push(vec!["HDEL", isbns, isbn]);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the commands in MULTI/EXEC. False means a watched key changed
+    // and nothing was applied.
+    async fn transaction(&self, conn: &mut Conn, commands: &[Vec<&str>]) -> Result<bool, String> {
+        conn.queue(&["MULTI"]).await?;
+        for command in commands {
+            conn.queue(command).await?;
+        }
+        conn.queue(&["EXEC"]).await?;
+        conn.flush().await?;
+        for _ in 0..commands.len() + 1 {
+            conn.read().await?;
+        }
+        match conn.read().await? {
+            Reply::Array(Some(_)) => Ok(true),
+            Reply::Array(None) => Ok(false),
+            other => Err(format!("unexpected reply to EXEC: {:?}", other)),
+        }
+    }
+
+    // Up to `limit` books with ids above `after`, in id order, from the
+    // catalog or the trash.
+    async fn fetch(&self, after: u64, limit: usize, trash: bool) -> Result<Vec<Book>, String> {
+        let mut conn = self.checkout().await?;
+        let (index, key) = match trash {
+            true => (self.trash_key(), Self::deleted_key as fn(&Self, u64) -> String),
+            false => (self.index_key(), Self::book_key as fn(&Self, u64) -> String),
+        };
+        let Reply::Array(Some(members)) = conn.call(&["SMEMBERS", &index]).await? else {
+            return Err("expected an array from SMEMBERS".to_string());
+        };
+        let mut ids = Vec::new();
+        for member in members {
+            let member = text(member)?;
+            ids.push(member.parse::<u64>().map_err(|_| format!("malformed book id {:?}", member))?);
+        }
+        ids.retain(|id| *id > after);
+        ids.sort_unstable();
+        ids.truncate(limit);
+        let keys: Vec<String> = ids.iter().map(|id| key(self, *id)).collect();
+        for key in &keys {
+            conn.queue(&["HGETALL", key]).await?;
+        }
+        conn.flush().await?;
+        // A book deleted since SMEMBERS comes back empty and is skipped.
+        let mut books = Vec::new();
+        for id in ids {
+            books.extend(book_from_hash(id, conn.read().await?)?);
+        }
+        conn.release();
+        Ok(books)
+    }
+}
+
+async fn timed<T, E: Into<StorageError>>(command: impl Future<Output = Result<T, E>>) -> Result<T, StorageError> {
+    match tokio::time::timeout(COMMAND_TIMEOUT, command).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(StorageError::Failed("redis command timed out".to_string())),
+    }
+}
+
+// Spreads out writers that collided on the same book, so they don't keep
+// colliding.
+async fn back_off(attempt: usize) {
+    let ceiling = 2u64 << attempt.min(6);
+    let millis = RandomState::new().hash_one(Instant::now()) % ceiling;
+    tokio::time::sleep(Duration::from_millis(millis)).await;
+}
+
+fn text(reply: Reply) -> Result<String, String> {
+    match reply {
+        Reply::Bulk(Some(data)) => String::from_utf8(data).map_err(|e| e.to_string()),
+        other => Err(format!("expected a string, got {:?}", other)),
+    }
+}
+
+// HGETALL answers with an empty array for a missing key.
+fn book_from_hash(id: u64, reply: Reply) -> Result<Option<Book>, String> {
+    let Reply::Array(Some(items)) = reply else {
+        return Err(format!("expected an array, got {:?}", reply));
+    };
+    if items.is_empty() {
+        return Ok(None);
+    }
+    let mut book = Book {
+        id,
+        title: String::new(),
+        author: String::new(),
+        isbn: None,
+        author_id: None,
+        tags: Vec::new(),
+        edition: Edition::default(),
+        uid: None,
+        nft: None,
+        anchor: None,
+        deleted_at: None,
+    };
+    let mut items = items.into_iter();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        let value = text(value)?;
+        match text(field)?.as_str() {
+            "title" => book.title = value,
+            "author" => book.author = value,
+            "isbn" => book.isbn = Some(value),
+            "author_id" => book.author_id = value.parse().ok(),
+            "tags" => book.tags = serde_json::from_str(&value).map_err(|_| format!("malformed tags {:?}", value))?,
+            "edition" => {
+                book.edition = serde_json::from_str(&value).map_err(|_| format!("malformed edition {:?}", value))?
+            }
+            "uid" => book.uid = Some(value),
+            "nft" => book.nft = Some(serde_json::from_str(&value).map_err(|_| format!("malformed nft {:?}", value))?),
+            "anchor" => {
+                book.anchor = Some(serde_json::from_str(&value).map_err(|_| format!("malformed anchor {:?}", value))?)
+            }
+            "deleted_at" => book.deleted_at = value.parse().ok(),
+            _ => {}
+        }
+    }
+    Ok(Some(book))
+}
+
+// The author id and the tags, edition, NFT and anchor, as JSON, are
+// formatted into `formatted`, which has to outlive the fields.
+fn book_fields<'a>(book: &'a Book, formatted: &'a mut [String; 5]) -> Vec<&'a str> {
+    let [author_id, tags, edition, nft, anchor] = formatted;
+    let mut fields = vec!["title", book.title.as_str(), "author", book.author.as_str()];
+    if let Some(isbn) = &book.isbn {
+        fields.extend(["isbn", isbn.as_str()]);
+    }
+    if let Some(id) = book.author_id {
+        *author_id = id.to_string();
+        fields.extend(["author_id", author_id.as_str()]);
+    }
+    if !book.tags.is_empty() {
+        *tags = serde_json::to_string(&book.tags).unwrap_or_default();
+        fields.extend(["tags", tags.as_str()]);
+    }
+    if book.edition != Edition::default() {
+        *edition = serde_json::to_string(&book.edition).unwrap_or_default();
+        fields.extend(["edition", edition.as_str()]);
+    }
+    if let Some(uid) = &book.uid {
+        fields.extend(["uid", uid.as_str()]);
+    }
+    if let Some(minted) = &book.nft {
+        *nft = serde_json::to_string(minted).unwrap_or_default();
+        fields.extend(["nft", nft.as_str()]);
+    }
+    if let Some(anchored) = &book.anchor {
+        *anchor = serde_json::to_string(anchored).unwrap_or_default();
+        fields.extend(["anchor", anchor.as_str()]);
+    }
+    fields
+}
+
+fn author_from_hash(id: u64, reply: Reply) -> Result<Option<Author>, String> {
+    let Reply::Array(Some(items)) = reply else {
+        return Err(format!("expected an array, got {:?}", reply));
+    };
+    let mut author = None;
+    let mut items = items.into_iter();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        if text(field)? == "name" {
+            author = Some(Author { id, name: text(value)? });
+        }
+    }
+    Ok(author)
+}
+
+impl BookStore for Redis {
+    async fn get(&self, id: u64) -> Result<Option<Book>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let book = book_from_hash(id, conn.call(&["HGETALL", &self.book_key(id)]).await?)?;
+            conn.release();
+            Ok::<_, String>(book)
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<Book>, StorageError> {
+        timed(self.fetch(0, usize::MAX, false)).await
+    }
+
+    async fn count(&self) -> Result<usize, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let Reply::Integer(count) = conn.call(&["SCARD", &self.index_key()]).await? else {
+                return Err("expected an integer from SCARD".to_string());
+            };
+            conn.release();
+            Ok(count as usize)
+        })
+        .await
+    }
+
+    // The id set isn't ordered, so paging sorts all of it and fetches only
+    // the books on the page.
+    async fn list_after(&self, after: u64, limit: usize) -> Result<Vec<Book>, StorageError> {
+        timed(self.fetch(after, limit, false)).await
+    }
+    // Books and catalogs written before times were kept count as changed
+    // now, so they're never reported unchanged by mistake.
+    async fn modified(&self, id: Option<u64>) -> Result<Option<u64>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let reply = match id {
+                Some(id) => {
+                    let reply = conn.call(&["HMGET", &self.book_key(id), "title", "modified"]).await?;
+                    let Reply::Array(Some(mut values)) = reply else {
+                        return Err(format!("expected an array from HMGET, got {:?}", reply));
+                    };
+                    let modified = values.pop();
+                    if matches!(values.pop(), None | Some(Reply::Bulk(None))) {
+                        conn.release();
+                        return Ok(None);
+                    }
+                    modified
+                }
+                None => Some(conn.call(&["GET", &self.modified_key()]).await?),
+            };
+            conn.release();
+            let stamp = match reply {
+                Some(reply @ Reply::Bulk(Some(_))) => text(reply)?,
+                _ => return Ok(Some(now_ms())),
+            };
+            stamp.parse().map(Some).map_err(|_| format!("malformed timestamp {:?}", stamp))
+        })
+        .await
+    }
+
+    async fn insert(&self, book: CreateBookRequest) -> Result<Book, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let Reply::Integer(id) = conn.call(&["INCR", &format!("{}next_book_id", self.prefix)]).await? else {
+                return Err("expected an integer from INCR".to_string().into());
+            };
+            let book = Book {
+                id: id as u64,
+                title: book.title,
+                author: book.author,
+                isbn: book.isbn,
+                author_id: book.author_id,
+                tags: book.tags,
+                edition: book.edition,
+                uid: book.uid,
+                nft: None,
+                anchor: None,
+                deleted_at: None,
+            };
+            let key = self.book_key(book.id);
+            let id = book.id.to_string();
+            let (index, isbns) = (self.index_key(), self.isbns_key());
+            let (modified, now) = (self.modified_key(), now_ms().to_string());
+            let mut formatted = Default::default();
+            let mut hset = vec!["HSET", key.as_str()];
+            hset.extend(book_fields(&book, &mut formatted));
+            hset.extend(["modified", now.as_str()]);
+            let mut commands = vec![hset, vec!["SADD", &index, &id], vec!["SET", &modified, &now]];
+            let wanted: Vec<&str> = book.isbn.as_deref().into_iter().collect();
+            let claimed = book.isbn.as_deref().map(isbn::key);
+            if let Some(claimed) = &claimed {
+                commands.push(vec!["HSET", &isbns, claimed, &id]);
+            }
+            let uids = self.uids_key();
+            if let Some(uid) = &book.uid {
+                commands.push(vec!["HSET", &uids, uid, &id]);
+            }
+            // Only the ISBN index is watched, so only a write claiming an
+            // ISBN at the same time sends this round again.
+            for attempt in 0..MAX_ATTEMPTS {
+                if let Some(taken) = self.isbn_taken(&mut conn, &wanted, 0).await? {
+                    conn.release();
+                    return Err(taken);
+                }
+                if self.transaction(&mut conn, &commands).await? {
+                    conn.release();
+                    return Ok(book);
+                }
+                back_off(attempt).await;
+            }
+            Err(StorageError::Failed("the ISBN index kept changing during the insert".to_string()))
+        })
+        .await
+    }
+
+    // The ids are reserved in one INCRBY and the books written in one
+    // MULTI/EXEC. A failure in between leaves a gap in the ids and no books.
+    async fn insert_many(&self, books: Vec<CreateBookRequest>) -> Result<Vec<Book>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            if books.is_empty() {
+                conn.release();
+                return Ok(Vec::new());
+            }
+            let count = books.len().to_string();
+            let Reply::Integer(last) = conn.call(&["INCRBY", &format!("{}next_book_id", self.prefix), &count]).await?
+            else {
+                return Err("expected an integer from INCRBY".to_string().into());
+            };
+            let first = last as u64 + 1 - books.len() as u64;
+            let books: Vec<Book> = books
+                .into_iter()
+                .zip(first..)
+                .map(|(book, id)| Book {
+                    id,
+                    title: book.title,
+                    author: book.author,
+                    isbn: book.isbn,
+                    author_id: book.author_id,
+                    tags: book.tags,
+                    edition: book.edition,
+                    uid: book.uid,
+                    nft: None,
+                    anchor: None,
+                    deleted_at: None,
+                })
+                .collect();
+            let keys: Vec<String> = books.iter().map(|book| self.book_key(book.id)).collect();
+            let ids: Vec<String> = books.iter().map(|book| book.id.to_string()).collect();
+            let index = self.index_key();
+            let (modified, now) = (self.modified_key(), now_ms().to_string());
+            let mut formatted = vec![<[String; 5]>::default(); books.len()];
+            let mut commands = Vec::with_capacity(books.len() + 2);
+            for ((book, key), formatted) in books.iter().zip(&keys).zip(&mut formatted) {
+                let mut hset = vec!["HSET", key.as_str()];
+                hset.extend(book_fields(book, formatted));
+                hset.extend(["modified", now.as_str()]);
+                commands.push(hset);
+            }
+            let mut sadd = vec!["SADD", index.as_str()];
+            sadd.extend(ids.iter().map(String::as_str));
+            commands.push(sadd);
+            commands.push(vec!["SET", &modified, &now]);
+            let isbns = self.isbns_key();
+            let claimed: Vec<Option<String>> = books.iter().map(|book| book.isbn.as_deref().map(isbn::key)).collect();
+            let mut claim = vec!["HSET", isbns.as_str()];
+            let mut wanted = Vec::new();
+            for ((book, id), claimed) in books.iter().zip(&ids).zip(&claimed) {
+                if let (Some(isbn), Some(claimed)) = (&book.isbn, claimed) {
+                    claim.extend([claimed.as_str(), id.as_str()]);
+                    wanted.push(isbn.as_str());
+                }
+            }
+            if !wanted.is_empty() {
+                commands.push(claim);
+            }
+            let uids = self.uids_key();
+            let mut named = vec!["HSET", uids.as_str()];
+            for (book, id) in books.iter().zip(&ids) {
+                if let Some(uid) = &book.uid {
+                    named.extend([uid.as_str(), id.as_str()]);
+                }
+            }
+            if named.len() > 2 {
+                commands.push(named);
+            }
+            for attempt in 0..MAX_ATTEMPTS {
+                if let Some(taken) = self.isbn_taken(&mut conn, &wanted, 0).await? {
+                    conn.release();
+                    return Err(taken);
+                }
+                if self.transaction(&mut conn, &commands).await? {
+                    conn.release();
+                    return Ok(books);
+                }
+                back_off(attempt).await;
+            }
+            Err(StorageError::Failed("the ISBN index kept changing during the insert".to_string()))
+        })
+        .await
+    }
+
+    async fn modify<E: Send, F: FnMut(&Book) -> Result<Book, E> + Send>(
+        &self,
+        id: u64,
+        mut change: F,
+    ) -> Result<Option<Result<Book, E>>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let key = self.book_key(id);
+            let (modified, isbns) = (self.modified_key(), self.isbns_key());
+            let member = id.to_string();
+            for attempt in 0..MAX_ATTEMPTS {
+                let Some(current) = self.watch(&mut conn, id).await? else {
+                    conn.release();
+                    return Ok(None);
+                };
+                let book = match change(&current) {
+                    Ok(book) => book,
+                    Err(rejected) => {
+                        conn.call(&["UNWATCH"]).await?;
+                        conn.release();
+                        return Ok(Some(Err(rejected)));
+                    }
+                };
+                let now = now_ms().to_string();
+                let mut formatted = Default::default();
+                let mut hset = vec!["HSET", key.as_str()];
+                hset.extend(book_fields(&book, &mut formatted));
+                hset.extend(["modified", now.as_str()]);
+                let (claimed, released) = (book.isbn.as_deref().map(isbn::key), current.isbn.as_deref().map(isbn::key));
+                let mut commands = vec![hset, vec!["SET", &modified, &now]];
+                if claimed != released {
+                    if let (Some(isbn), Some(claimed)) = (&book.isbn, &claimed) {
+                        if let Some(taken) = self.isbn_taken(&mut conn, &[isbn], id).await? {
+                            conn.release();
+                            return Err(taken);
+                        }
+                        commands.push(vec!["HSET", &isbns, claimed, &member]);
+                    }
+                    self.release_isbn(&mut conn, id, released.as_deref(), &isbns, &mut commands).await?;
+                }
+                if book.isbn.is_none() {
+                    commands.push(vec!["HDEL", key.as_str(), "isbn"]);
+                }
+                if book.author_id.is_none() {
+                    commands.push(vec!["HDEL", key.as_str(), "author_id"]);
+                }
+                if book.tags.is_empty() {
+                    commands.push(vec!["HDEL", key.as_str(), "tags"]);
+                }
+                if book.edition == Edition::default() {
+                    commands.push(vec!["HDEL", key.as_str(), "edition"]);
+                }
+                if book.nft.is_none() {
+                    commands.push(vec!["HDEL", key.as_str(), "nft"]);
+                }
+                if book.anchor.is_none() {
+                    commands.push(vec!["HDEL", key.as_str(), "anchor"]);
+                }
+                if self.transaction(&mut conn, &commands).await? {
+                    conn.release();
+                    return Ok(Some(Ok(book)));
+                }
+                back_off(attempt).await;
+            }
+            Err(format!("book {} kept changing during the update", id).into())
+        })
+        .await
+    }
+
+    async fn delete(&self, id: u64) -> Result<Option<Book>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let (key, trashed) = (self.book_key(id), self.deleted_key(id));
+            let (index, trash, isbns) = (self.index_key(), self.trash_key(), self.isbns_key());
+            let member = id.to_string();
+            let modified = self.modified_key();
+            for attempt in 0..MAX_ATTEMPTS {
+                let Some(book) = self.watch(&mut conn, id).await? else {
+                    conn.release();
+                    return Ok(None);
+                };
+                let at = now_ms();
+                let now = at.to_string();
+                let released = book.isbn.as_deref().map(isbn::key);
+                let mut commands = vec![
+                    vec!["RENAME", &key, &trashed],
+                    vec!["HSET", &trashed, "deleted_at", &now],
+                    vec!["SREM", &index, &member],
+                    vec!["SADD", &trash, &member],
+                    vec!["SET", &modified, &now],
+                ];
+                self.release_isbn(&mut conn, id, released.as_deref(), &isbns, &mut commands).await?;
+                if self.transaction(&mut conn, &commands).await? {
+                    conn.release();
+                    return Ok(Some(Book { deleted_at: Some(at), ..book }));
+                }
+                back_off(attempt).await;
+            }
+            Err(format!("book {} kept changing during the delete", id))
+        })
+        .await
+    }
+
+    async fn delete_if<E: Send, F: FnMut(&Book) -> Result<(), E> + Send>(
+        &self,
+        id: u64,
+        mut check: F,
+    ) -> Result<Option<Result<Book, E>>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let (key, trashed) = (self.book_key(id), self.deleted_key(id));
+            let (index, trash, isbns) = (self.index_key(), self.trash_key(), self.isbns_key());
+            let member = id.to_string();
+            let modified = self.modified_key();
+            for attempt in 0..MAX_ATTEMPTS {
+                let Some(book) = self.watch(&mut conn, id).await? else {
+                    conn.release();
+                    return Ok(None);
+                };
+                if let Err(rejected) = check(&book) {
+                    conn.call(&["UNWATCH"]).await?;
+                    conn.release();
+                    return Ok(Some(Err(rejected)));
+                }
+                let at = now_ms();
+                let now = at.to_string();
+                let released = book.isbn.as_deref().map(isbn::key);
+                let mut commands = vec![
+                    vec!["RENAME", &key, &trashed],
+                    vec!["HSET", &trashed, "deleted_at", &now],
+                    vec!["SREM", &index, &member],
+                    vec!["SADD", &trash, &member],
+                    vec!["SET", &modified, &now],
+                ];
+                self.release_isbn(&mut conn, id, released.as_deref(), &isbns, &mut commands).await?;
+                if self.transaction(&mut conn, &commands).await? {
+                    conn.release();
+                    return Ok(Some(Ok(Book { deleted_at: Some(at), ..book })));
+                }
+                back_off(attempt).await;
+            }
+            Err(format!("book {} kept changing during the delete", id))
+        })
+        .await
+    }
+
+    async fn deleted(&self) -> Result<Vec<Book>, StorageError> {
+        timed(self.fetch(0, usize::MAX, true)).await
+    }
+
+    async fn resolve(&self, uid: String) -> Result<Option<u64>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let id = match conn.call(&["HGET", &self.uids_key(), &uid]).await? {
+                Reply::Bulk(None) => None,
+                id => Some(text(id)?),
+            };
+            conn.release();
+            id.map(|id| id.parse().map_err(|_| format!("malformed book id {:?}", id))).transpose()
+        })
+        .await
+    }
+
+    async fn restore(&self, id: u64) -> Result<Option<Book>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let (key, trashed) = (self.book_key(id), self.deleted_key(id));
+            let (index, trash, isbns) = (self.index_key(), self.trash_key(), self.isbns_key());
+            let member = id.to_string();
+            let modified = self.modified_key();
+            for attempt in 0..MAX_ATTEMPTS {
+                conn.call(&["WATCH", &trashed]).await?;
+                let Some(book) = book_from_hash(id, conn.call(&["HGETALL", &trashed]).await?)? else {
+                    conn.call(&["UNWATCH"]).await?;
+                    conn.release();
+                    return Ok(None);
+                };
+                let now = now_ms().to_string();
+                let claimed = book.isbn.as_deref().map(isbn::key);
+                let mut commands = vec![
+                    vec!["RENAME", &trashed, &key],
+                    vec!["HDEL", &key, "deleted_at"],
+                    vec!["HSET", &key, "modified", &now],
+                    vec!["SREM", &trash, &member],
+                    vec!["SADD", &index, &member],
+                    vec!["SET", &modified, &now],
+                ];
+                if let (Some(isbn), Some(claimed)) = (&book.isbn, &claimed) {
+                    if let Some(taken) = self.isbn_taken(&mut conn, &[isbn], id).await? {
+                        conn.release();
+                        return Err(taken);
+                    }
+                    commands.push(vec!["HSET", &isbns, claimed, &member]);
+                }
+                if self.transaction(&mut conn, &commands).await? {
+                    conn.release();
+                    return Ok(Some(Book { deleted_at: None, ..book }));
+                }
+                back_off(attempt).await;
+            }
+            Err(format!("book {} kept changing during the restore", id).into())
+        })
+        .await
+    }
+}
+
+impl AuthorStore for Redis {
+    async fn author(&self, id: u64) -> Result<Option<Author>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let author = author_from_hash(id, conn.call(&["HGETALL", &self.author_key(id)]).await?)?;
+            conn.release();
+            Ok::<_, String>(author)
+        })
+        .await
+    }
+
+    async fn authors(&self) -> Result<Vec<Author>, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let Reply::Array(Some(members)) = conn.call(&["SMEMBERS", &self.authors_key()]).await? else {
+                return Err("expected an array from SMEMBERS".to_string());
+            };
+            let mut ids = Vec::new();
+            for member in members {
+                let member = text(member)?;
+                ids.push(member.parse::<u64>().map_err(|_| format!("malformed author id {:?}", member))?);
+            }
+            ids.sort_unstable();
+            for id in &ids {
+                conn.queue(&["HGETALL", &self.author_key(*id)]).await?;
+            }
+            conn.flush().await?;
+            let mut authors = Vec::new();
+            for id in ids {
+                authors.extend(author_from_hash(id, conn.read().await?)?);
+            }
+            conn.release();
+            Ok(authors)
+        })
+        .await
+    }
+
+    async fn insert_author(&self, name: String) -> Result<Author, StorageError> {
+        timed(async {
+            let mut conn = self.checkout().await?;
+            let Reply::Integer(id) = conn.call(&["INCR", &format!("{}next_author_id", self.prefix)]).await? else {
+                return Err("expected an integer from INCR".to_string());
+            };
+            let author = Author { id: id as u64, name };
+            let (key, index, member) = (self.author_key(author.id), self.authors_key(), author.id.to_string());
+            let commands = [vec!["HSET", &key, "name", &author.name], vec!["SADD", &index, &member]];
+            self.transaction(&mut conn, &commands).await?;
+            conn.release();
+            Ok(author)
+        })
+        .await
+    }
+
+    // Redis can't check the books and delete the author in one step, so a
+    // book filed under the author while it's being deleted can be left
+    // naming an author that's gone.
+    async fn delete_author(&self, id: u64) -> Result<Option<Result<Author, usize>>, StorageError> {
+        timed(async {
+            let mut books = self.fetch(0, usize::MAX, false).await?;
+            books.extend(self.fetch(0, usize::MAX, true).await?);
+            let filed = books.iter().filter(|book| book.author_id == Some(id)).count();
+            let mut conn = self.checkout().await?;
+            let key = self.author_key(id);
+            conn.call(&["WATCH", &key]).await?;
+            let Some(author) = author_from_hash(id, conn.call(&["HGETALL", &key]).await?)? else {
+                conn.call(&["UNWATCH"]).await?;
+                conn.release();
+                return Ok::<_, String>(None);
+            };
+            if filed > 0 {
+                conn.call(&["UNWATCH"]).await?;
+                conn.release();
+                return Ok(Some(Err(filed)));
+            }
+            let (index, member) = (self.authors_key(), id.to_string());
+            // Authors never change, so a failed EXEC means another instance
+            // deleted this one first.
+            let deleted = self.transaction(&mut conn, &[vec!["DEL", &key], vec!["SREM", &index, &member]]).await?;
+            conn.release();
+            Ok(deleted.then_some(Ok(author)))
+        })
+        .await
+    }
+}
+
+// A checked-out connection. It only goes back to the pool once released,
+// so one dropped by an error or a timeout, possibly with a reply still
+// unread, is closed instead.
+struct Pooled<'a> {
+    pool: &'a Redis,
+    conn: Option<Conn>,
+    reusable: bool,
+    _slot: SemaphorePermit<'a>,
+}
+
+impl Pooled<'_> {
+    fn release(&mut self) {
+        self.reusable = true;
+    }
+}
+
+impl Deref for Pooled<'_> {
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Pooled<'_> {
+    fn deref_mut(&mut self) -> &mut Conn {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if self.reusable {
+                self.pool.idle.lock().unwrap().push(conn);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // A connection to a server that answers with `replies` whatever it is
+    // sent, then hangs up.
+    async fn serve(replies: &'static [u8]) -> (Conn, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address::parse("DOJO_STORAGE", &format!("redis://{}", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(replies).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        (Conn::connect(&address).await.unwrap(), server)
+    }
+
+    async fn replies(wire: &'static [u8], count: usize) -> Vec<Result<String, String>> {
+        let (mut conn, _) = serve(wire).await;
+        let mut replies = Vec::new();
+        for _ in 0..count {
+            replies.push(conn.read().await.map(|reply| format!("{:?}", reply)));
+        }
+        replies
+    }
+
+    #[tokio::test]
+    async fn commands_go_out_as_arrays_of_bulk_strings() {
+        let (mut conn, server) = serve(b"+OK\r\n").await;
+        conn.queue(&["HSET", "dojo:book:1", "title", "Sól"]).await.unwrap();
+        assert!(matches!(conn.call(&["EXEC"]).await, Ok(Reply::Status)));
+        drop(conn);
+        assert_eq!(
+            server.await.unwrap(),
+            b"*4\r\n$4\r\nHSET\r\n$11\r\ndojo:book:1\r\n$5\r\ntitle\r\n$4\r\nS\xc3\xb3l\r\n*1\r\n$4\r\nEXEC\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_parse() {
+        let wire = b"+OK\r\n:-42\r\n$5\r\nhe\r\no\r\n$0\r\n\r\n$-1\r\n*-1\r\n*0\r\n*2\r\n*1\r\n:1\r\n$1\r\na\r\n-WRONGTYPE no hash\r\n";
+        let parsed: Vec<Result<String, String>> = replies(wire, 9).await;
+        let expected = [
+            Ok("Status"),
+            Ok("Integer(-42)"),
+            // Lengths, not line ends, delimit bulk strings.
+            Ok("Bulk(Some([104, 101, 13, 10, 111]))"),
+            Ok("Bulk(Some([]))"),
+            Ok("Bulk(None)"),
+            Ok("Array(None)"),
+            Ok("Array(Some([]))"),
+            Ok("Array(Some([Array(Some([Integer(1)])), Bulk(Some([97]))]))"),
+            Err("WRONGTYPE no hash"),
+        ];
+        let expected: Vec<Result<String, String>> =
+            expected.iter().map(|reply| reply.map(str::to_string).map_err(str::to_string)).collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[tokio::test]
+    async fn malformed_replies_are_errors() {
+        assert_eq!(replies(b":forty-two\r\n", 1).await, [Err("malformed reply \":forty-two\"".to_string())]);
+        assert_eq!(replies(b"$x\r\n", 1).await, [Err("malformed reply \"$x\"".to_string())]);
+        assert_eq!(replies(b"%1\r\n", 1).await, [Err("unknown reply \"%1\"".to_string())]);
+        assert_eq!(replies(b"\r\n", 1).await, [Err("empty reply".to_string())]);
+        assert_eq!(replies(b"", 1).await, [Err("connection closed".to_string())]);
+        // Cut short inside a bulk string, and inside an array.
+        assert!(replies(b"$10\r\nshort\r\n", 1).await[0].is_err());
+        assert_eq!(replies(b"*2\r\n:1\r\n", 1).await, [Err("connection closed".to_string())]);
+    }
+
+    #[test]
+    fn books_round_trip_through_hashes() {
+        let book: Book = serde_json::from_value(serde_json::json!({
+            "id": 7,
+            "title": "Solaris",
+            "author": "Lem",
+            "isbn": "9780156027601",
+            "author_id": 3,
+            "tags": ["sf"],
+            "edition": { "published_year": 1961 },
+        }))
+        .unwrap();
+        let mut formatted = Default::default();
+        let fields = book_fields(&book, &mut formatted);
+        let hash = Reply::Array(Some(fields.iter().map(|field| Reply::Bulk(Some(field.as_bytes().to_vec()))).collect()));
+        assert_eq!(book_from_hash(7, hash).unwrap(), Some(book));
+        assert_eq!(book_from_hash(7, Reply::Array(Some(Vec::new()))).unwrap(), None);
+        assert!(book_from_hash(7, Reply::Integer(0)).is_err());
+    }
+
+    #[test]
+    fn addresses_parse() {
+        let address = Address::parse("DOJO_STORAGE", "redis://:s3cret@cache:6380/").unwrap();
+        assert_eq!((address.addr.as_str(), address.password.as_deref()), ("cache:6380", Some("s3cret")));
+        let address = Address::parse("DOJO_STORAGE", "redis://cache").unwrap();
+        assert_eq!((address.addr.as_str(), address.password), ("cache:6379", None));
+        assert!(Address::parse("DOJO_STORAGE", "rediss://cache").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use hyper::{
+    header::{self, HeaderValue},
+    Request, Response,
+};
+use serde_json::Value;
+use std::future::Future;
+use uuid::Uuid;
+
+use crate::{
+    body::{self, Body},
+    federation, problem,
+};
+
+pub const HEADER: &str = "x-request-id";
+
+// Set on every request before the layers run.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub HeaderValue);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().unwrap_or_default()
+    }
+}
+
+// A caller's ID is kept when it's up to 128 letters, digits, `-`, `_`, `.`
+// or `:`, so one ID can follow a request through proxies and shards.
+// Anything else is replaced with a new v4 UUID rather than echoed.
+fn acceptable(value: &HeaderValue) -> bool {
+    let bytes = value.as_bytes();
+    !bytes.is_empty()
+        && bytes.len() <= 128
+        && bytes.iter().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+pub fn assign(req: &mut Request<Body>) -> RequestId {
+    let id = match req.headers().get(HEADER).filter(|value| acceptable(value)) {
+        Some(value) => value.clone(),
+        None => HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap(),
+    };
+    let id = RequestId(id);
+    req.headers_mut().insert(HEADER, id.0.clone());
+    req.extensions_mut().insert(id.clone());
+    id
+}
+
+// Echoes the ID in X-Request-Id, and in a `request_id` member of problem
+// bodies so a pasted error names the request it came from. Signed
+// federation replies are left as they are.
+pub async fn stamp(
+    id: RequestId,
+    response: impl Future<Output = Result<Response<Body>, body::Error>>,
+) -> Result<Response<Body>, body::Error> {
+    let (mut parts, body) = response.await?.into_parts();
+    parts.headers.insert(HEADER, id.0.clone());
+    let is_problem = parts.headers.get(header::CONTENT_TYPE).is_some_and(|value| value == problem::MEDIA_TYPE);
+    if !is_problem || parts.headers.contains_key(federation::SIGNATURE_HEADER) {
+        return Ok(Response::from_parts(parts, body));
+    }
+    let bytes = body::to_bytes(body).await?;
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut object)) => {
+            object.insert("request_id".to_string(), Value::from(id.as_str()));
+            let bytes = serde_json::to_vec(&object).unwrap();
+            parts.headers.insert(header::CONTENT_LENGTH, bytes.len().into());
+            Body::from(bytes)
+        }
+        _ => Body::from(bytes),
+    };
+    Ok(Response::from_parts(parts, body))
+}
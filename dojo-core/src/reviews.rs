@@ -0,0 +1,124 @@
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, Page},
+    body::Body,
+    conditional::now_ms,
+    error::ApiError,
+    extract::{Json, Path, Query, State},
+    json_response,
+    links::Links,
+    problem::FieldError,
+    store::{BookStore, Store},
+    validate::{self, Validate, Violations},
+    SharedState, Storage,
+};
+
+const MAX_TEXT: usize = 5000;
+
+// Reviews are kept by the instance that took them, beside loans and
+// acquisition requests, whichever backend holds the books. They go when
+// their book does, and aren't brought back if it's restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Review {
+    pub id: u64,
+    pub book_id: u64,
+    pub rating: u8,
+    pub text: String,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReviewRequest {
+    // Wider than a rating, so 0 or 6 is reported as out of range rather
+    // than as malformed JSON.
+    pub rating: i64,
+    pub text: String,
+}
+
+impl Validate for ReviewRequest {
+    fn validate(&mut self, violations: &mut Violations) {
+        violations.text("text", &mut self.text, MAX_TEXT);
+    }
+}
+
+// A book's average rating, to two places, and when it was last reviewed.
+pub struct Rating {
+    pub average: f64,
+    pub at: u64,
+}
+
+pub fn rating(storage: &Storage, id: u64) -> Option<Rating> {
+    let reviews = storage.reviews.get(&id).filter(|reviews| !reviews.is_empty())?;
+    let sum: u64 = reviews.iter().map(|review| u64::from(review.rating)).sum();
+    let average = sum as f64 / reviews.len() as f64;
+    Some(Rating {
+        average: (average * 100.0).round() / 100.0,
+        at: reviews.last().map_or(0, |review| review.created_at),
+    })
+}
+
+pub async fn create<S: BookStore>(
+    Path(id): Path<u64>,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+    Json(mut request): Json<ReviewRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let mut errors = validate::errors(&mut request);
+    if !(1..=5).contains(&request.rating) {
+        errors.insert(
+            0,
+            FieldError {
+                field: "rating".to_string(),
+                message: "must be between 1 and 5".to_string(),
+            },
+        );
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            errors,
+        });
+    }
+    store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    let review = state
+        .with_storage("review_insert", || format!("id={}", id), |storage| {
+            let review = Review {
+                id: storage.next_review_id,
+                book_id: id,
+                rating: request.rating as u8,
+                text: request.text,
+                created_at: now_ms(),
+            };
+            storage.next_review_id += 1;
+            storage.reviews.entry(id).or_default().push(review.clone());
+            review
+        })
+        .await?;
+    Ok(json_response(StatusCode::CREATED, &review)?)
+}
+
+// Oldest first, paged like the audit log.
+pub async fn list<S: BookStore>(
+    Path(id): Path<u64>,
+    Query(page): Query<Page>,
+    links: Links,
+    State(state): State<SharedState>,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    let (after, limit) = page.bounds().map_err(ApiError::BadRequest)?;
+    let (reviews, more) = state
+        .read_storage("review_list", || format!("id={}", id), |storage| {
+            let reviews = storage.reviews.get(&id).map_or(&[][..], Vec::as_slice);
+            let start = reviews.partition_point(|review| review.id <= after);
+            let page: Vec<Review> = reviews[start..].iter().take(limit).cloned().collect();
+            let more = reviews.len() > start + limit;
+            (page, more)
+        })
+        .await?;
+    if reviews.is_empty() && after == 0 {
+        store.get(id).await?.ok_or_else(ApiError::not_found)?;
+    }
+    Ok(audit::respond(&links, &page, reviews, more, |review| review.id)?)
+}
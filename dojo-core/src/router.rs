@@ -0,0 +1,364 @@
+use http_body::Body as HttpBody;
+use hyper::{header, Method, Response, StatusCode};
+use std::borrow::Cow;
+use tracing::Instrument;
+
+use crate::{
+    auth, body::Body, extract::RequestContext, ids, not_found, problem, stack::ResponseFuture, storage_error,
+    store::BookStore,
+};
+
+pub type Handler = fn(RequestContext) -> ResponseFuture;
+
+// One segment of a route pattern. `{label}` takes the segment as the path
+// parameter, `label` being how errors name it. Text after the brace, as in
+// `{schema name}.json`, is dropped from the value when it's there.
+enum Segment {
+    Literal(&'static str),
+    Param { label: &'static str, suffix: &'static str },
+}
+
+impl Segment {
+    fn parse(segment: &'static str) -> Self {
+        match segment.strip_prefix('{').and_then(|rest| rest.split_once('}')) {
+            Some((label, suffix)) => Segment::Param { label, suffix },
+            None => Segment::Literal(segment),
+        }
+    }
+}
+
+type Param<'a> = Option<(&'a str, &'static str)>;
+
+struct Route {
+    method: Method,
+    pattern: &'static str,
+    segments: Vec<Segment>,
+    handler: Handler,
+    // Checks credentials of its own, so API keys don't apply.
+    authenticated: bool,
+    // Only an admin may call it.
+    admin: bool,
+}
+
+impl Route {
+    fn matches<'a>(&self, segments: &[&'a str]) -> Option<Param<'a>> {
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+        let mut param = None;
+        for (segment, pattern) in segments.iter().zip(&self.segments) {
+            match pattern {
+                Segment::Literal(literal) if literal == segment => {}
+                Segment::Literal(_) => return None,
+                // An empty segment is a trailing slash, not a value.
+                Segment::Param { .. } if segment.is_empty() => return None,
+                Segment::Param { label, suffix } => param = Some((segment.strip_suffix(suffix).unwrap_or(segment), *label)),
+            }
+        }
+        Some(param)
+    }
+}
+
+// Operator and debug endpoints are for admins whatever registers them, so
+// a new one can't be left open by mistake. The admin UI's pages are public;
+// their calls carry the token themselves.
+fn admin_only(pattern: &str) -> bool {
+    (pattern.starts_with("/admin/") && !pattern.starts_with("/admin/ui")) || pattern.starts_with("/debug/")
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.strip_prefix('/').unwrap_or(path).split('/').collect()
+}
+
+// Routes with at most one path parameter each.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn route(mut self, method: Method, pattern: &'static str, handler: Handler) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern,
+            segments: segments(pattern).into_iter().map(Segment::parse).collect(),
+            handler,
+            authenticated: false,
+            admin: admin_only(pattern),
+        });
+        self
+    }
+
+    // A route elsewhere that only an admin may call, like a librarian's.
+    pub fn admin(self, method: Method, pattern: &'static str, handler: Handler) -> Self {
+        let mut router = self.route(method, pattern, handler);
+        if let Some(route) = router.routes.last_mut() {
+            route.admin = true;
+        }
+        router
+    }
+
+    // A route whose handler checks a token of its own, like the kiosk's.
+    pub fn authenticated(self, method: Method, pattern: &'static str, handler: Handler) -> Self {
+        let mut router = self.route(method, pattern, handler);
+        if let Some(route) = router.routes.last_mut() {
+            route.authenticated = true;
+        }
+        router
+    }
+
+    // A route without a parameter wins, so /books/search isn't read as a
+    // book ID. HEAD is served by the GET route unless it has one of its own.
+    fn find<'a>(&self, method: &Method, path: &'a str) -> Option<(&Route, Param<'a>)> {
+        let segments = segments(path);
+        let head = *method == Method::HEAD;
+        self.routes
+            .iter()
+            .filter(|route| route.method == *method || (head && route.method == Method::GET))
+            .filter_map(|route| Some((route, route.matches(&segments)?)))
+            .min_by_key(|(route, param)| (param.is_some(), route.method != *method))
+    }
+
+    fn recognizes(&self, path: &str) -> bool {
+        let segments = segments(path);
+        self.routes.iter().any(|route| route.matches(&segments).is_some())
+    }
+
+    // The methods some route takes the path with.
+    fn allowed(&self, path: &str) -> Vec<Method> {
+        let segments = segments(path);
+        let matching = self.routes.iter().filter(|route| route.matches(&segments).is_some());
+        matching.map(|route| route.method.clone()).collect()
+    }
+}
+
+// What a request's method and path lead to.
+enum Lookup<'r, 'p> {
+    Route(&'r Route, Param<'p>, Option<&'static str>),
+    // The path is known, but not with this method.
+    Allowed(Vec<Method>),
+    Missing,
+}
+
+// The Allow header for a path the routes take with `allowed`. OPTIONS is
+// answered for every known path, so it's always among them, and HEAD
+// wherever GET is.
+fn allow(allowed: &[Method]) -> String {
+    let mut methods = Vec::new();
+    for method in allowed.iter().filter(|method| **method != Method::OPTIONS && **method != Method::HEAD) {
+        methods.push(method.as_str());
+        if *method == Method::GET {
+            methods.push("HEAD");
+        }
+    }
+    methods.push("OPTIONS");
+    methods.join(", ")
+}
+
+// A GET response answering a HEAD: the same status and headers, with the
+// length the body would have had, and no body. A streamed body's length
+// isn't known, so it goes without.
+fn headless(response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    if let Some(length) = body.size_hint().exact() {
+        parts.headers.entry(header::CONTENT_LENGTH).or_insert_with(|| header::HeaderValue::from(length));
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+fn with_allow(mut response: Response<Body>, allow: &str) -> Response<Body> {
+    if let Ok(allow) = header::HeaderValue::from_str(allow) {
+        response.headers_mut().insert(header::ALLOW, allow);
+    }
+    response
+}
+
+fn method_not_allowed(method: &Method, allowed: &[Method]) -> Response<Body> {
+    let allow = allow(allowed);
+    let detail = format!("{} isn't allowed here, only {}", method, allow);
+    with_allow(problem::respond(StatusCode::METHOD_NOT_ALLOWED, detail), &allow)
+}
+
+// OPTIONS on a known path lists what it takes. A CORS preflight never gets
+// here, the cors middleware answers it.
+fn options(allowed: &[Method]) -> Response<Body> {
+    let response = Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap();
+    with_allow(response, &allow(allowed))
+}
+
+// The public API, one router per version served under its prefix
+// (/v1/books), next to the operator and cluster endpoints, which aren't
+// versioned. The legacy version also answers without a prefix, as the API
+// did before it had versions.
+pub struct Api {
+    unversioned: Router,
+    versions: Vec<(&'static str, Router)>,
+    legacy: Option<usize>,
+}
+
+impl Api {
+    pub fn new(unversioned: Router) -> Self {
+        Api {
+            unversioned,
+            versions: Vec::new(),
+            legacy: None,
+        }
+    }
+
+    pub fn version(mut self, name: &'static str, router: Router) -> Self {
+        self.versions.push((name, router));
+        self
+    }
+
+    // Serves unprefixed paths with a version added earlier.
+    pub fn legacy(mut self, name: &'static str) -> Self {
+        self.legacy = self.versions.iter().position(|(version, _)| *version == name);
+        self
+    }
+
+    // The version's router and the rest of the path, for a path under a
+    // version prefix.
+    fn split<'a>(&self, path: &'a str) -> Option<(&'static str, &Router, &'a str)> {
+        let rest = path.strip_prefix('/')?;
+        let (name, rest) = rest.find('/').map_or((rest, ""), |slash| rest.split_at(slash));
+        self.versions
+            .iter()
+            .find(|(version, _)| *version == name)
+            .map(|(version, router)| (*version, router, rest))
+    }
+
+    fn legacy_router(&self) -> Option<&(&'static str, Router)> {
+        self.versions.get(self.legacy?)
+    }
+
+    fn lookup<'p>(&self, method: &Method, path: &'p str) -> Lookup<'_, 'p> {
+        let candidates = match self.split(path) {
+            Some((version, router, rest)) => vec![(Some(version), router, rest)],
+            None => {
+                let legacy = self.legacy_router().map(|(version, router)| (Some(*version), router, path));
+                [(None, &self.unversioned, path)].into_iter().chain(legacy).collect()
+            }
+        };
+        for (version, router, path) in &candidates {
+            if let Some((route, param)) = router.find(method, path) {
+                return Lookup::Route(route, param, *version);
+            }
+        }
+        let mut allowed: Vec<Method> = Vec::new();
+        for method in candidates.iter().flat_map(|(_, router, path)| router.allowed(path)) {
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+        if allowed.is_empty() {
+            Lookup::Missing
+        } else {
+            Lookup::Allowed(allowed)
+        }
+    }
+
+    // Whether a router has the route, as it was registered.
+    pub fn has_route(&self, method: &Method, pattern: &str) -> bool {
+        let routers = [&self.unversioned].into_iter().chain(self.versions.iter().map(|(_, router)| router));
+        routers.flat_map(|router| &router.routes).any(|route| route.method == method && route.pattern == pattern)
+    }
+
+    pub fn dispatch(&self, ctx: RequestContext) -> ResponseFuture {
+        let method = ctx.parts().method.clone();
+        let path = ctx.parts().uri.path().to_string();
+        let state = ctx.state().clone();
+        let (route, param, version) = match self.lookup(&method, &path) {
+            Lookup::Route(route, param, version) => (route, param, version),
+            Lookup::Allowed(allowed) if method == Method::OPTIONS => return Box::pin(async move { Ok(options(&allowed)) }),
+            Lookup::Allowed(allowed) => return Box::pin(async move { Ok(method_not_allowed(&method, &allowed)) }),
+            // A path with a trailing slash is sent where it'd be without.
+            // 308 keeps the method and body.
+            Lookup::Missing => {
+                let trimmed = path.trim_end_matches('/');
+                if trimmed.is_empty() || trimmed == path || matches!(self.lookup(&method, trimmed), Lookup::Missing) {
+                    return Box::pin(async { Ok(not_found()) });
+                }
+                let location = match ctx.parts().uri.query() {
+                    Some(query) => format!("{}?{}", trimmed, query),
+                    None => trimmed.to_string(),
+                };
+                return Box::pin(async move {
+                    Ok(Response::builder()
+                        .status(StatusCode::PERMANENT_REDIRECT)
+                        .header(header::LOCATION, location)
+                        .body(Body::empty())
+                        .unwrap())
+                });
+            }
+        };
+        // Admin routes are hidden entirely until an admin token or JWTs are
+        // configured. API keys and reader or editor tokens guard the rest of
+        // the catalog.
+        if route.admin {
+            if let Some(rejected) = auth::reject_non_admin(ctx.headers(), ctx.state()) {
+                return Box::pin(async { Ok(rejected) });
+            }
+        }
+        let ctx = match version {
+            Some(version) if !route.authenticated && !route.admin => {
+                if let Some(rejected) = auth::authorize(&method, ctx.headers(), ctx.state()) {
+                    return Box::pin(async { Ok(rejected) });
+                }
+                ctx.with_version(version)
+            }
+            Some(version) => ctx.with_version(version),
+            None => ctx,
+        };
+        let response = match param {
+            // A book can be named by its uid as well as its id. The uid is
+            // looked up here, so handlers only ever see ids.
+            Some((value, label @ "book ID")) if value.parse::<u64>().is_err() => match ids::parse(value) {
+                None => (route.handler)(ctx.with_param(value, label)),
+                Some(uid) => {
+                    let handler = route.handler;
+                    Box::pin(async move {
+                        match ctx.state().resolve(uid).await {
+                            Ok(Some(id)) => handler(ctx.with_param(&id.to_string(), label)).await,
+                            Ok(None) => Ok(not_found()),
+                            Err(e) => Ok(storage_error(e)),
+                        }
+                    })
+                }
+            },
+            Some((value, label)) => (route.handler)(ctx.with_param(value, label)),
+            None => (route.handler)(ctx),
+        };
+        // The handler's span, between the request's and its storage calls'.
+        let response = response.instrument(tracing::info_span!("handler", route = route.pattern));
+        let response = state.concurrency.run(&route.method, route.pattern, response);
+        match method == Method::HEAD && route.method == Method::GET {
+            true => Box::pin(async move { response.await.map(headless) }),
+            false => Box::pin(response),
+        }
+    }
+
+    // The path as the spec documents it: an unprefixed path the legacy
+    // version serves gets that version's prefix.
+    pub fn canonical<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.split(path).is_some() || self.unversioned.recognizes(path) {
+            return Cow::Borrowed(path);
+        }
+        match self.legacy_router() {
+            Some((version, router)) if router.recognizes(path) => Cow::Owned(format!("/{}{}", version, path)),
+            _ => Cow::Borrowed(path),
+        }
+    }
+
+    // Whether a version serves the path, under its prefix or as the legacy
+    // version.
+    pub fn in_catalog(&self, path: &str) -> bool {
+        self.split(path).is_some()
+            || (!self.unversioned.recognizes(path) && self.legacy_router().is_some_and(|(_, router)| router.recognizes(path)))
+    }
+
+    // The path without its version prefix, for code that reads catalog
+    // paths before they're routed.
+    pub fn unversioned<'a>(&self, path: &'a str) -> &'a str {
+        self.split(path).map_or(path, |(_, _, rest)| rest)
+    }
+}
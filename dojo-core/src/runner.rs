@@ -0,0 +1,442 @@
+use hyper::{body::Incoming as Received, service::service_fn, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use std::{fmt::Display, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    sync::watch,
+    task::JoinSet,
+};
+
+use crate::{
+    body::Body,
+    listen::{Bind, Https, Listener, PeerAddr},
+    systemd,
+    tls::{Tls, TlsIncoming},
+    SharedState,
+};
+
+// Every runner mounts each listener's middleware stack, so routing, handlers
+// and middleware are shared and only the serving layer differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runner {
+    Hyper,
+    #[cfg(feature = "axum")]
+    Axum,
+    #[cfg(feature = "actix")]
+    Actix,
+}
+
+impl Runner {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("DOJO_RUNNER").ok().as_deref() {
+            None | Some("hyper") => Ok(Runner::Hyper),
+            #[cfg(feature = "axum")]
+            Some("axum") => Ok(Runner::Axum),
+            #[cfg(feature = "actix")]
+            Some("actix") => Ok(Runner::Actix),
+            #[allow(unreachable_patterns)]
+            Some(name @ ("axum" | "actix")) => Err(format!("built without the `{}` feature", name)),
+            Some(other) => Err(format!("unknown runner {:?}, expected hyper, axum or actix", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Runner::Hyper => "hyper",
+            #[cfg(feature = "axum")]
+            Runner::Axum => "axum",
+            #[cfg(feature = "actix")]
+            Runner::Actix => "actix",
+        }
+    }
+
+    // Returns once every server has stopped, which after `shutdown` fires is
+    // when their open connections have finished.
+    pub async fn serve(
+        self,
+        listeners: Vec<Listener>,
+        state: SharedState,
+        shutdown: Shutdown,
+        protocols: Protocols,
+    ) -> Result<(), String> {
+        match self {
+            Runner::Hyper => serve_hyper(listeners, state, shutdown, protocols).await,
+            #[cfg(feature = "axum")]
+            Runner::Axum => serve_axum(listeners, state, shutdown, protocols).await,
+            #[cfg(feature = "actix")]
+            Runner::Actix => serve_actix(listeners, state, shutdown, protocols).await,
+        }
+    }
+}
+
+// Fires once, when the process is asked to stop. Every server then stops
+// accepting connections and lets the requests it has finish.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn new() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Shutdown(receiver))
+    }
+
+    pub async fn wait(mut self) {
+        // A dropped sender means nothing will ask for a shutdown.
+        if self.0.wait_for(|stop| *stop).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+// How connections are spoken to, the same on every listener: HTTP/1.1,
+// or HTTP/2 when a client opens with its preface (h2c) or picks it
+// through ALPN over TLS.
+//
+// DOJO_KEEP_ALIVE_SECS (30 by default) is how long an HTTP/1.1 connection
+// may wait for the next request's headers; 0 closes it after each
+// response. DOJO_HTTP2_PING_SECS has idle HTTP/2 connections pinged that
+// often and closed if a ping goes unanswered for as long. Each HTTP/2
+// connection carries up to DOJO_HTTP2_MAX_STREAMS (200) requests at once.
+#[derive(Debug, Clone)]
+pub struct Protocols {
+    keep_alive: Option<Duration>,
+    ping: Option<Duration>,
+    max_streams: u32,
+}
+
+impl Protocols {
+    pub fn from_env() -> Result<Self, String> {
+        let number = |key: &str, default: u64| match std::env::var(key) {
+            Ok(value) => value.parse::<u64>().map_err(|_| format!("{} must be a whole number, got {:?}", key, value)),
+            Err(_) => Ok(default),
+        };
+        let secs = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        let max_streams = number("DOJO_HTTP2_MAX_STREAMS", 200)?;
+        Ok(Protocols {
+            keep_alive: secs(number("DOJO_KEEP_ALIVE_SECS", 30)?),
+            ping: secs(number("DOJO_HTTP2_PING_SECS", 0)?),
+            max_streams: u32::try_from(max_streams)
+                .ok()
+                .filter(|max| *max > 0)
+                .ok_or_else(|| format!("DOJO_HTTP2_MAX_STREAMS must be a positive number, got {}", max_streams))?,
+        })
+    }
+
+    fn builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive.is_some())
+            .header_read_timeout(self.keep_alive);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.max_streams)
+            .keep_alive_interval(self.ping)
+            .keep_alive_timeout(self.ping.unwrap_or(Duration::from_secs(20)));
+        builder
+    }
+}
+
+enum Incoming {
+    Tcp(TcpListener),
+    Tls(TlsIncoming),
+    Unix(UnixListener),
+}
+
+trait Io: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Io for T {}
+
+impl Incoming {
+    // The next connection, and the client's address unless it came over a
+    // Unix socket.
+    async fn accept(&mut self) -> io::Result<(Box<dyn Io>, Option<SocketAddr>)> {
+        match self {
+            Incoming::Tcp(listener) => {
+                let (tcp, peer) = listener.accept().await?;
+                Ok((Box::new(tcp), Some(peer)))
+            }
+            Incoming::Tls(incoming) => {
+                let conn = incoming.accept().await.ok_or_else(|| io::Error::other("the TLS listener stopped"))?;
+                let peer = conn.peer;
+                Ok((Box::new(conn), Some(peer)))
+            }
+            Incoming::Unix(listener) => {
+                let (unix, _) = listener.accept().await?;
+                Ok((Box::new(unix), None))
+            }
+        }
+    }
+}
+
+impl Bind {
+    fn open(self, tls: Option<&Tls>) -> Result<Incoming, String> {
+        let label = self.to_string();
+        let error = |e: io::Error| format!("{}: {}", label, e);
+        match self {
+            Bind::Unix(unix) => unix.bind().map(Incoming::Unix).map_err(error),
+            Bind::Tcp(_) | Bind::InheritedTcp(_) => {
+                let listener = match self {
+                    Bind::Tcp(tcp) => tcp.bind().map_err(error)?,
+                    Bind::InheritedTcp(listener) => listener,
+                    _ => unreachable!(),
+                };
+                listener.set_nonblocking(true).map_err(error)?;
+                let listener = TcpListener::from_std(listener).map_err(error)?;
+                match tls {
+                    Some(tls) => Ok(Incoming::Tls(tls.incoming(listener))),
+                    None => Ok(Incoming::Tcp(listener)),
+                }
+            }
+            Bind::InheritedUnix(listener) => {
+                listener.set_nonblocking(true).map_err(error)?;
+                UnixListener::from_std(listener).map(Incoming::Unix).map_err(error)
+            }
+        }
+    }
+}
+
+// Takes connections until `shutdown` fires, then waits for the open ones
+// to finish the requests they have. Each connection gets the service
+// `service` makes for it, knowing the client's address.
+async fn accept<S, F>(mut incoming: Incoming, protocols: Protocols, shutdown: Shutdown, service: F) -> Result<(), String>
+where
+    F: Fn(Option<SocketAddr>) -> S,
+    S: hyper::service::Service<Request<Received>, Response = hyper::Response<Body>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let builder = Arc::new(protocols.builder());
+    let graceful = GracefulShutdown::new();
+    let stopping = shutdown.wait();
+    tokio::pin!(stopping);
+    loop {
+        let accepted = tokio::select! {
+            accepted = incoming.accept() => accepted,
+            _ = &mut stopping => break,
+        };
+        let (io, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Typically out of file descriptors; waiting beats spinning.
+                tracing::warn!("accepting a connection failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        let service = service(peer);
+        tokio::spawn(async move {
+            // WebSocket handshakes take the connection over once answered.
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+            if let Err(e) = watcher.watch(connection).await {
+                tracing::debug!("connection from {:?} failed: {}", peer, e);
+            }
+        });
+    }
+    drop(incoming);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+// Each listener runs as its own server; the first one to fail stops the rest.
+async fn run_all<E: Display + 'static>(mut servers: JoinSet<Result<(), E>>) -> Result<(), String> {
+    systemd::notify_ready();
+    while let Some(result) = servers.join_next().await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(())
+}
+
+async fn serve_hyper(
+    listeners: Vec<Listener>,
+    state: SharedState,
+    shutdown: Shutdown,
+    protocols: Protocols,
+) -> Result<(), String> {
+    let mut servers = JoinSet::new();
+    for Listener { bind, stack, tls } in listeners {
+        let https = tls.is_some();
+        let incoming = bind.open(tls.as_ref())?;
+        let state = state.clone();
+        let service = move |peer: Option<SocketAddr>| {
+            let stack = stack.clone();
+            let state = state.clone();
+            service_fn(move |req: Request<Received>| {
+                let mut req = req.map(Body::from);
+                if let Some(peer) = peer {
+                    req.extensions_mut().insert(PeerAddr(peer));
+                }
+                if https {
+                    req.extensions_mut().insert(Https);
+                }
+                stack.call(req, state.clone())
+            })
+        };
+        servers.spawn(accept(incoming, protocols.clone(), shutdown.clone(), service));
+    }
+    run_all(servers).await
+}
+
+// axum routes every request to the listener's stack, and is served over
+// the same connections as the hyper runner.
+#[cfg(feature = "axum")]
+async fn serve_axum(
+    listeners: Vec<Listener>,
+    state: SharedState,
+    shutdown: Shutdown,
+    protocols: Protocols,
+) -> Result<(), String> {
+    use axum::{
+        extract::{ConnectInfo, Request},
+        response::IntoResponse,
+    };
+    use hyper::StatusCode;
+    use tower_service::Service;
+
+    let mut servers = JoinSet::new();
+    for Listener { bind, stack, tls } in listeners {
+        let state = state.clone();
+        let https = tls.is_some();
+        let app = axum::Router::new().fallback(move |peer: Option<ConnectInfo<SocketAddr>>, req: Request| {
+            let mut req = req.map(Body::wrap);
+            if let Some(ConnectInfo(peer)) = peer {
+                req.extensions_mut().insert(PeerAddr(peer));
+            }
+            if https {
+                req.extensions_mut().insert(Https);
+            }
+            let response = stack.call(req, state.clone());
+            async move {
+                match response.await {
+                    Ok(response) => response.into_response(),
+                    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+                }
+            }
+        });
+        let service = move |peer: Option<SocketAddr>| {
+            let app = app.clone();
+            service_fn(move |mut req: Request<Received>| {
+                if let Some(peer) = peer {
+                    req.extensions_mut().insert(ConnectInfo(peer));
+                }
+                let mut app = app.clone();
+                async move {
+                    let response = app.call(req).await?;
+                    Ok::<_, std::convert::Infallible>(response.map(Body::wrap))
+                }
+            })
+        };
+        servers.spawn(accept(bind.open(tls.as_ref())?, protocols.clone(), shutdown.clone(), service));
+    }
+    run_all(servers).await
+}
+
+// actix-web has its own request and body types, and its own version of
+// the http crate, so requests are rebuilt as hyper requests and responses
+// are buffered back into actix responses.
+#[cfg(feature = "actix")]
+async fn serve_actix(
+    listeners: Vec<Listener>,
+    state: SharedState,
+    shutdown: Shutdown,
+    protocols: Protocols,
+) -> Result<(), String> {
+    use actix_web::{http::KeepAlive, web, App, HttpRequest, HttpResponse, HttpServer};
+    use hyper::StatusCode;
+
+    use crate::{body, stack::Stack};
+
+    async fn handle(
+        req: HttpRequest,
+        body: web::Bytes,
+        state: web::Data<SharedState>,
+        stack: web::Data<Stack>,
+    ) -> HttpResponse {
+        let mut builder = Request::builder().method(req.method().as_str()).uri(req.uri().to_string());
+        for (name, value) in req.headers() {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+        if let Some(peer) = req.peer_addr() {
+            builder = builder.extension(PeerAddr(peer));
+        }
+        let request = builder.body(Body::from(body)).unwrap();
+
+        let (parts, body) = match stack.call(request, state.get_ref().clone()).await {
+            Ok(response) => response.into_parts(),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+        let body = match body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+        let status = actix_web::http::StatusCode::from_u16(parts.status.as_u16());
+        let mut response = HttpResponse::build(status.unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR));
+        for (name, value) in &parts.headers {
+            response.append_header((name.as_str(), value.as_bytes()));
+        }
+        if parts.status == StatusCode::NO_CONTENT {
+            return response.finish();
+        }
+        response.body(body)
+    }
+
+    // actix-web shares one app across a server's sockets, so each listener
+    // gets its own server to carry its own stack.
+    let state = web::Data::new(state);
+    let mut servers = JoinSet::new();
+    for Listener { bind, stack, tls } in listeners {
+        // actix-web terminates TLS itself, through features not built here.
+        if tls.is_some() {
+            return Err(format!("{}: HTTPS needs the hyper or axum runner", bind));
+        }
+        let state = state.clone();
+        let stack = web::Data::new(stack);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .app_data(stack.clone())
+                // The hyper runner does not cap request bodies either.
+                .app_data(web::PayloadConfig::new(usize::MAX))
+                .default_service(web::to(handle))
+        })
+        .keep_alive(protocols.keep_alive.map_or(KeepAlive::Disabled, KeepAlive::Timeout))
+        // Signals are handled once for every runner, in main.
+        .disable_signals();
+        let server = match bind {
+            Bind::Tcp(tcp) => {
+                let listener = tcp.bind().map_err(|e| format!("{}: {}", tcp.addr, e))?;
+                server.listen(listener).map_err(|e| e.to_string())?
+            }
+            Bind::Unix(unix) => {
+                let error = |e: io::Error| format!("{}: {}", unix.path.display(), e);
+                unix.remove_stale().map_err(error)?;
+                let server = server.bind_uds(&unix.path).map_err(error)?;
+                unix.apply_mode().map_err(error)?;
+                server
+            }
+            Bind::InheritedTcp(listener) => server.listen(listener).map_err(|e| e.to_string())?,
+            Bind::InheritedUnix(listener) => server.listen_uds(listener).map_err(|e| e.to_string())?,
+        };
+        let server = server.run();
+        let handle = server.handle();
+        let stopping = shutdown.clone();
+        tokio::spawn(async move {
+            stopping.wait().await;
+            handle.stop(true).await;
+        });
+        servers.spawn(server);
+    }
+    run_all(servers).await
+}
@@ -0,0 +1,141 @@
+use hyper::{Response, StatusCode};
+use schemars::{schema_for, Schema};
+
+use crate::{
+    body::{self, Body},
+    check::CheckReport,
+    compact::CompactionReport,
+    error::ApiError,
+    extract::Path,
+    json_response,
+    tasks::Task,
+    Author, Book, CreateBookRequest, UpdateBookRequest,
+};
+use books_model::CreateAuthorRequest;
+
+const NAMES: &[&str] = &[
+    "Book",
+    "CreateBookRequest",
+    "UpdateBookRequest",
+    "Author",
+    "CreateAuthorRequest",
+    "Task",
+    "CheckReport",
+    "CompactionReport",
+];
+
+fn schema(name: &str) -> Option<Schema> {
+    match name {
+        "Book" => Some(schema_for!(Book)),
+        "CreateBookRequest" => Some(schema_for!(CreateBookRequest)),
+        "UpdateBookRequest" => Some(schema_for!(UpdateBookRequest)),
+        "Author" => Some(schema_for!(Author)),
+        "CreateAuthorRequest" => Some(schema_for!(CreateAuthorRequest)),
+        "Task" => Some(schema_for!(Task)),
+        "CheckReport" => Some(schema_for!(CheckReport)),
+        "CompactionReport" => Some(schema_for!(CompactionReport)),
+        _ => None,
+    }
+}
+
+pub async fn list_schemas() -> Result<Response<Body>, body::Error> {
+    json_response(StatusCode::OK, &NAMES)
+}
+
+pub async fn get_schema(Path(name): Path<String>) -> Result<Response<Body>, ApiError> {
+    let schema = schema(&name).ok_or_else(ApiError::not_found)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/schema+json")
+        .body(Body::from(serde_json::to_string(&schema).unwrap()))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    // Fields the server adds to a book as it's read, which the Rust type
+    // doesn't have.
+    const VIEW_ONLY: &[(&str, &str)] = &[("Book", "average_rating"), ("Book", "available")];
+
+    // A property's type and allowed values, with `null` left out as the
+    // derived schemas spell it as a type and openapi.json as `nullable`.
+    fn shape(property: &Value, defs: &Value) -> (Option<String>, Option<BTreeSet<String>>) {
+        let property = match property["$ref"].as_str().and_then(|name| name.strip_prefix("#/$defs/")) {
+            Some(name) => &defs[name],
+            None => property,
+        };
+        let kind = match &property["type"] {
+            Value::String(kind) => Some(kind.clone()),
+            Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).find(|kind| *kind != "null").map(str::to_string),
+            _ => None,
+        };
+        let values = property["enum"].as_array().map(|values| values.iter().filter(|value| !value.is_null()).map(Value::to_string).collect());
+        (kind, values)
+    }
+
+    fn names(schema: &Value, key: &str) -> BTreeSet<String> {
+        match &schema[key] {
+            Value::Object(properties) => properties.keys().cloned().collect(),
+            Value::Array(required) => required.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            _ => BTreeSet::new(),
+        }
+    }
+
+    // openapi.json is written by hand; this fails when a type it documents
+    // gains, loses or retypes a field without the spec following. Types
+    // nested in the derived schemas are checked against the spec's own
+    // component of the same name.
+    #[test]
+    fn openapi_matches_the_derived_schemas() {
+        let spec: Value = serde_json::from_str(crate::contract::SPEC).unwrap();
+        let components = &spec["components"]["schemas"];
+        let mut checked = Vec::new();
+        for name in NAMES {
+            let derived = serde_json::to_value(schema(name).unwrap()).unwrap();
+            let defs = derived["$defs"].clone();
+            checked.push((name.to_string(), derived, defs.clone()));
+            for (nested, schema) in defs.as_object().into_iter().flatten() {
+                if schema["properties"].is_object() && components[nested].is_object() {
+                    checked.push((nested.clone(), schema.clone(), defs.clone()));
+                }
+            }
+        }
+
+        let mut drift = Vec::new();
+        for (name, derived, defs) in &checked {
+            let documented = &components[name];
+            assert!(documented.is_object(), "openapi.json has no {} schema", name);
+            let extra: BTreeSet<String> =
+                VIEW_ONLY.iter().filter(|(of, _)| of == name).map(|(_, field)| field.to_string()).collect();
+            let (ours, theirs) = (names(derived, "properties"), names(documented, "properties"));
+            for field in ours.difference(&theirs) {
+                drift.push(format!("{}.{} isn't documented", name, field));
+            }
+            for field in theirs.difference(&ours).filter(|field| !extra.contains(*field)) {
+                drift.push(format!("{}.{} is documented but doesn't exist", name, field));
+            }
+            // The spec may require more, such as options that are always
+            // sent as null, but never less.
+            for field in names(derived, "required").difference(&names(documented, "required")) {
+                drift.push(format!("{}.{} is required but documented as optional", name, field));
+            }
+            for field in ours.intersection(&theirs) {
+                let ours = shape(&derived["properties"][field], defs);
+                let theirs = shape(&documented["properties"][field], components);
+                if ours.0.is_some() && theirs.0.is_some() && ours.0 != theirs.0 {
+                    drift.push(format!("{}.{} is {:?} but documented as {:?}", name, field, ours.0, theirs.0));
+                }
+                if let (Some(ours), Some(theirs)) = (ours.1, theirs.1) {
+                    if ours != theirs {
+                        drift.push(format!("{}.{} takes {:?} but documented as {:?}", name, field, ours, theirs));
+                    }
+                }
+            }
+        }
+        assert!(drift.is_empty(), "openapi.json has drifted:\n{}", drift.join("\n"));
+    }
+}
@@ -0,0 +1,331 @@
+use books_model::Book;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    extract::Query,
+    fields::{Fields, Projected},
+    isbn, json_response,
+    links::{Linked, Links},
+    stats::Tally,
+    store::{BookStore, Store},
+};
+
+// How much a token counts for in each field. An ISBN is an exact identifier,
+// so matching one beats any number of words.
+const TITLE: f64 = 3.0;
+const AUTHOR: f64 = 2.0;
+const ISBN: f64 = 10.0;
+
+// What a hit can be trimmed down to with ?fields=.
+pub const HIT_FIELDS: &[&str] = &["id", "title", "author", "isbn", "score"];
+
+// Lowercased words made of letters and digits. Hyphens and apostrophes join
+// rather than split, so "978-0-261" and "9780261" are the same token.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '\''))
+        .map(|word| word.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect::<String>())
+        .filter(|token| !token.is_empty())
+}
+
+// An inverted index over titles, authors and ISBNs, one over tags and ones
+// from each ISBN and uid to its book, kept up to date by every write to
+// the in-memory catalog.
+#[derive(Default)]
+pub struct Index {
+    // Token to the books it appears in, with its weight in each.
+    postings: HashMap<String, HashMap<u64, f64>>,
+    // The tokens each book was indexed under, to take it out again.
+    tokens: HashMap<u64, Vec<String>>,
+    // Tag to the books that have it. Tags are matched whole, not
+    // tokenized.
+    tagged: BTreeMap<String, BTreeSet<u64>>,
+    tags: HashMap<u64, Vec<String>>,
+    // Keyed by `isbn::key`. Several books only share an ISBN if they did
+    // before it had to be unique, or arrived from another node.
+    by_isbn: HashMap<String, BTreeSet<u64>>,
+    isbns: HashMap<u64, String>,
+    by_uid: HashMap<String, u64>,
+    uids: HashMap<u64, String>,
+    // How many books each author has, for the stats.
+    by_author: BTreeMap<String, usize>,
+    authors: HashMap<u64, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hit {
+    #[serde(flatten)]
+    pub book: Book,
+    pub score: f64,
+}
+
+impl Index {
+    pub fn build<'a>(books: impl IntoIterator<Item = &'a Book>) -> Self {
+        let mut index = Index::default();
+        for book in books {
+            index.insert(book);
+        }
+        index
+    }
+
+    // Indexes a new book, or replaces what was indexed for it before.
+    pub fn insert(&mut self, book: &Book) {
+        self.remove(book.id);
+        let mut weights: HashMap<String, f64> = HashMap::new();
+        let fields = [(book.title.as_str(), TITLE), (book.author.as_str(), AUTHOR)];
+        for (text, weight) in fields.into_iter().chain(book.isbn.as_deref().map(|isbn| (isbn, ISBN))) {
+            for token in tokenize(text) {
+                *weights.entry(token).or_default() += weight;
+            }
+        }
+        let mut tokens = Vec::with_capacity(weights.len());
+        for (token, weight) in weights {
+            self.postings.entry(token.clone()).or_default().insert(book.id, weight);
+            tokens.push(token);
+        }
+        self.tokens.insert(book.id, tokens);
+        for tag in &book.tags {
+            self.tagged.entry(tag.clone()).or_default().insert(book.id);
+        }
+        if !book.tags.is_empty() {
+            self.tags.insert(book.id, book.tags.clone());
+        }
+        if let Some(isbn) = &book.isbn {
+            let key = isbn::key(isbn);
+            self.by_isbn.entry(key.clone()).or_default().insert(book.id);
+            self.isbns.insert(book.id, key);
+        }
+        if let Some(uid) = &book.uid {
+            self.by_uid.insert(uid.clone(), book.id);
+            self.uids.insert(book.id, uid.clone());
+        }
+        *self.by_author.entry(book.author.clone()).or_default() += 1;
+        self.authors.insert(book.id, book.author.clone());
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        for token in self.tokens.remove(&id).unwrap_or_default() {
+            if let Some(books) = self.postings.get_mut(&token) {
+                books.remove(&id);
+                if books.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+        for tag in self.tags.remove(&id).unwrap_or_default() {
+            if let Some(books) = self.tagged.get_mut(&tag) {
+                books.remove(&id);
+                if books.is_empty() {
+                    self.tagged.remove(&tag);
+                }
+            }
+        }
+        if let Some(isbn) = self.isbns.remove(&id) {
+            if let Some(books) = self.by_isbn.get_mut(&isbn) {
+                books.remove(&id);
+                if books.is_empty() {
+                    self.by_isbn.remove(&isbn);
+                }
+            }
+        }
+        if let Some(uid) = self.uids.remove(&id) {
+            self.by_uid.remove(&uid);
+        }
+        if let Some(author) = self.authors.remove(&id) {
+            if let Some(books) = self.by_author.get_mut(&author) {
+                *books -= 1;
+                if *books == 0 {
+                    self.by_author.remove(&author);
+                }
+            }
+        }
+    }
+
+    pub fn uid(&self, uid: &str) -> Option<u64> {
+        self.by_uid.get(uid).copied()
+    }
+
+    // The first book other than `id` with the ISBN.
+    pub fn isbn_holder(&self, isbn: &str, id: u64) -> Option<u64> {
+        self.isbn_holders(isbn).find(|holder| *holder != id)
+    }
+
+    // Every book with the ISBN, in either form, in id order.
+    pub fn isbn_holders(&self, isbn: &str) -> impl Iterator<Item = u64> + '_ {
+        self.by_isbn.get(&isbn::key(isbn)).into_iter().flatten().copied()
+    }
+
+    pub fn tally(&self) -> Tally {
+        Tally {
+            books: self.authors.len(),
+            with_isbn: self.isbns.len(),
+            by_author: self.by_author.clone(),
+        }
+    }
+
+    // Every tag in use and how many books have it, by tag.
+    pub fn tag_counts(&self) -> Vec<(String, usize)> {
+        self.tagged.iter().map(|(tag, books)| (tag.clone(), books.len())).collect()
+    }
+
+    // The ids of the books with the tag, in order.
+    pub fn tagged(&self, tag: &str) -> Vec<u64> {
+        self.tagged.get(tag).map_or_else(Vec::new, |books| books.iter().copied().collect())
+    }
+
+    // Books matching any token of the query, best first and then by id.
+    // Tokens found in fewer books count for more.
+    pub fn search(&self, query: &str) -> Vec<(u64, f64)> {
+        let total = self.tokens.len() as f64;
+        let mut scores: HashMap<u64, f64> = HashMap::new();
+        let mut seen = HashSet::new();
+        for token in tokenize(query).filter(|token| seen.insert(token.clone())) {
+            let Some(books) = self.postings.get(&token) else {
+                continue;
+            };
+            let rarity = (1.0 + total / books.len() as f64).ln();
+            for (id, weight) in books {
+                *scores.entry(*id).or_default() += weight * rarity;
+            }
+        }
+        let mut ranked: Vec<(u64, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+
+    // The best `limit` matches, looking each book up with `book`.
+    pub fn hits(&self, query: &str, limit: usize, book: impl Fn(u64) -> Option<Book>) -> Vec<Hit> {
+        self.search(query)
+            .into_iter()
+            .filter_map(|(id, score)| {
+                Some(Hit {
+                    book: book(id)?,
+                    score: (score * 1000.0).round() / 1000.0,
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+}
+
+// Puts hits gathered from several indexes in one order.
+pub fn rank(hits: &mut Vec<Hit>, limit: usize) {
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.book.id.cmp(&b.book.id)));
+    hits.truncate(limit);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Params {
+    pub q: String,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+impl Params {
+    pub fn check(&self, fields: &Fields) -> Result<(), ApiError> {
+        if tokenize(&self.q).next().is_none() {
+            return Err(ApiError::bad_request("q has nothing to search for"));
+        }
+        fields.check(HIT_FIELDS).map_err(ApiError::BadRequest)
+    }
+}
+
+// Hits with their books' links, trimmed to ?fields= first so the links
+// stay.
+pub fn linked<'a>(hits: &'a [Hit], fields: &Fields, links: &Links) -> Vec<Linked<Projected<'a, Hit>>> {
+    hits.iter().map(|hit| links.book(fields.project(hit), &hit.book)).collect()
+}
+
+pub async fn search_books<S: BookStore>(
+    Query(params): Query<Params>,
+    Query(fields): Query<Fields>,
+    links: Links,
+    Store(store): Store<S>,
+) -> Result<Response<Body>, ApiError> {
+    params.check(&fields)?;
+    let hits = store.search(params.q, params.limit).await?;
+    Ok(json_response(StatusCode::OK, &linked(&hits, &fields, &links))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(id: u64, title: &str, author: &str, isbn: Option<&str>) -> Book {
+        serde_json::from_value(serde_json::json!({ "id": id, "title": title, "author": author, "isbn": isbn })).unwrap()
+    }
+
+    fn ids(ranked: Vec<(u64, f64)>) -> Vec<u64> {
+        ranked.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn tokens_are_lowercased_words() {
+        let tokens: Vec<String> = tokenize("The Hitchhiker's GUIDE, 978-0-345-39180-3!").collect();
+        assert_eq!(tokens, ["the", "hitchhikers", "guide", "9780345391803"]);
+        assert_eq!(tokenize(" -- , ").count(), 0);
+    }
+
+    // A word in the title beats one in the author, and an ISBN beats both.
+    #[test]
+    fn matches_rank_by_field_then_id() {
+        let index = Index::build(&[
+            book(1, "The Herbert Notebook", "Someone", None),
+            book(2, "Dune", "Frank Herbert", Some("9780441172719")),
+            book(3, "Dune Messiah", "Frank Herbert", None),
+            book(4, "Solaris", "Stanislaw Lem", None),
+        ]);
+        assert_eq!(ids(index.search("herbert")), [1, 2, 3]);
+        assert_eq!(ids(index.search("DUNE")), [2, 3]);
+        assert_eq!(ids(index.search("978-0-441-17271-9 messiah")), [2, 3]);
+        assert_eq!(ids(index.search("herbert herbert")), ids(index.search("herbert")));
+        assert!(index.search("asimov").is_empty());
+    }
+
+    // A token few books have counts for more than one most of them have.
+    #[test]
+    fn rare_tokens_weigh_more() {
+        let index = Index::build(&[
+            book(1, "Dune", "Frank Herbert", None),
+            book(2, "Emma", "Jane Austen", None),
+            book(3, "Persuasion", "Jane Austen", None),
+            book(4, "Sanditon", "Jane Austen", None),
+        ]);
+        let scores: HashMap<u64, f64> = index.search("frank jane").into_iter().collect();
+        assert!(scores[&1] > scores[&2], "{:?}", scores);
+        assert_eq!(scores[&2], scores[&3]);
+    }
+
+    #[test]
+    fn writes_keep_the_index_current() {
+        let mut index = Index::build(&[book(1, "Dune", "Frank Herbert", Some("0441172717"))]);
+        index.insert(&book(1, "Solaris", "Stanislaw Lem", Some("0441172717")));
+        assert!(index.search("dune").is_empty());
+        assert_eq!(ids(index.search("solaris")), [1]);
+        assert_eq!(index.isbn_holders("9780441172719").collect::<Vec<_>>(), [1]);
+        assert_eq!(index.isbn_holder("0441172717", 1), None);
+        index.remove(1);
+        assert!(index.search("solaris").is_empty());
+        assert_eq!(index.isbn_holders("0441172717").count(), 0);
+    }
+
+    #[test]
+    fn hits_are_limited_and_skip_missing_books() {
+        let books = [book(1, "Dune", "Frank Herbert", None), book(2, "Dune Messiah", "Frank Herbert", None), book(3, "Children of Dune", "Frank Herbert", None)];
+        let index = Index::build(&books);
+        let stored = |id: u64| books.iter().find(|book| book.id == id && id != 2).cloned();
+        let hits = index.hits("dune", 2, stored);
+        assert_eq!(hits.iter().map(|hit| hit.book.id).collect::<Vec<_>>(), [1, 3]);
+        let mut merged = index.hits("dune", 3, |id| books.iter().find(|book| book.id == id).cloned());
+        merged.reverse();
+        rank(&mut merged, 1);
+        assert_eq!(merged.iter().map(|hit| hit.book.id).collect::<Vec<_>>(), [1]);
+    }
+}
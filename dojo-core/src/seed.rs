@@ -0,0 +1,120 @@
+use books_model::CreateBookRequest;
+use serde::Deserialize;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    env,
+};
+
+use crate::{authors, error::ApiError, problem::FieldError, validate, SharedState};
+
+// A book of the seed file: what POST /books takes, plus the id to keep.
+// Books without one are numbered after the highest id given.
+#[derive(Deserialize)]
+struct Seeded {
+    id: Option<u64>,
+    #[serde(flatten)]
+    book: CreateBookRequest,
+}
+
+// Fills an empty catalog from DOJO_SEED_FILE, a JSON array of books, so a
+// demo or a dojo session starts with something to look at. A catalog that
+// has books, in the trash or not, is left alone, so restarting on storage
+// that keeps them doesn't seed it twice. Any invalid book fails the whole
+// file, as a partial catalog would be hard to notice.
+pub async fn load(state: &SharedState) -> Result<(), String> {
+    let Ok(path) = env::var("DOJO_SEED_FILE") else {
+        return Ok(());
+    };
+    // Ids are kept by writing the books into this instance's own catalog,
+    // which these keep elsewhere or give ids of their own.
+    if state.postgres.is_some()
+        || state.redis.is_some()
+        || state.cluster.is_some()
+        || state.raft.is_some()
+        || state.shards.is_some()
+    {
+        return Err("DOJO_SEED_FILE can't be combined with DATABASE_URL, DOJO_STORAGE=redis, DOJO_CLUSTER_NODE, DOJO_RAFT_NODE or DOJO_SHARD_NODE".to_string());
+    }
+    let bytes = std::fs::read(&path).map_err(|e| format!("reading {}: {}", path, e))?;
+    let seeded: Vec<Seeded> = serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let (ids, mut books): (Vec<Option<u64>>, Vec<CreateBookRequest>) =
+        seeded.into_iter().map(|seeded| (seeded.id, seeded.book)).unzip();
+
+    let mut errors = distinct_ids(&ids);
+    for (index, book) in books.iter_mut().enumerate() {
+        let Some(id) = book.author_id else {
+            continue;
+        };
+        match authors::name(&**state, format!("[{}].author_id", index), id).await {
+            Ok(name) => book.author = name,
+            Err(e) => errors.extend(field_errors(e)?),
+        }
+    }
+    if let Err(e) = validate::check_all(&mut books).and_then(|()| validate::distinct_isbns(&books)) {
+        errors.extend(field_errors(e)?);
+    }
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors.iter().map(|e| format!("{}: {}", e.field, e.message)).collect();
+        return Err(format!("{}: {}", path, errors.join("; ")));
+    }
+
+    for book in &mut books {
+        book.uid = state.ids.mint();
+    }
+    let count = books.len();
+    let seeded = state
+        .with_storage("seed", || format!("count={}", count), |storage| {
+            if !storage.books.is_empty() || !storage.deleted.is_empty() {
+                return false;
+            }
+            // The given ids go first, so none is handed out before its book
+            // claims it.
+            let (given, rest): (Vec<_>, Vec<_>) = ids.into_iter().zip(books).partition(|(id, _)| id.is_some());
+            for (id, book) in given.into_iter().chain(rest) {
+                match id {
+                    Some(id) => storage.place_book(id, book),
+                    None => storage.insert_book(book),
+                };
+            }
+            true
+        })
+        .await
+        .map_err(|e| format!("storing the books of {} failed: {}", path, e))?;
+    match seeded {
+        true => tracing::info!("seeded the catalog with {} books from {}", count, path),
+        false => tracing::info!("the catalog already has books, not seeding it from {}", path),
+    }
+    Ok(())
+}
+
+// Two books can't be given the same id, and ids start at 1.
+fn distinct_ids(ids: &[Option<u64>]) -> Vec<FieldError> {
+    let mut first = HashMap::new();
+    let mut errors = Vec::new();
+    for (index, id) in ids.iter().enumerate() {
+        let Some(id) = *id else {
+            continue;
+        };
+        let message = match first.entry(id) {
+            _ if id == 0 => "must be at least 1".to_string(),
+            Entry::Occupied(earlier) => format!("is also the id of [{}]", earlier.get()),
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+                continue;
+            }
+        };
+        errors.push(FieldError {
+            field: format!("[{}].id", index),
+            message,
+        });
+    }
+    errors
+}
+
+// The fields a check found wrong; the storage failing isn't one.
+fn field_errors(e: ApiError) -> Result<Vec<FieldError>, String> {
+    match e {
+        ApiError::Validation { errors, .. } => Ok(errors),
+        e => Err(format!("checking the seed file failed: {}", e)),
+    }
+}
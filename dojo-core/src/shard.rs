@@ -0,0 +1,511 @@
+use books_model::Book;
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Method, Request, Response, StatusCode, Uri,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::de::DeserializeOwned;
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{
+    auth,
+    body::{self, Body},
+    conditional::Conditional,
+    error::ApiError,
+    etag::IfMatch,
+    export::{self, Export},
+    extract::{FromRequest, Json, Query, RequestContext},
+    fields::{self, Fields},
+    html::{self, Format},
+    json_response,
+    links::Links,
+    listing::{self, Filter, Page, TOTAL_COUNT},
+    outbound::OutboundError,
+    request_id, search,
+    store::BookStore,
+    tags::TagCount,
+    BulkDelete, Deleted, SharedState,
+};
+
+// Marks a request one shard sends another, so it's served locally instead
+// of being routed or fanned out again.
+pub const FORWARDED: &str = "x-shard-forwarded";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Routing {
+    Proxy,
+    Redirect,
+}
+
+// Which ids this node stores. Every shard computes the same owner for an
+// id since they all sort the member list the same way.
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    index: usize,
+    count: usize,
+}
+
+impl Partition {
+    pub fn owner(&self, id: u64) -> usize {
+        // splitmix64, so neighbouring ids spread across shards.
+        let mut x = id.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        ((x ^ (x >> 31)) % self.count as u64) as usize
+    }
+
+    pub fn owns(&self, id: u64) -> bool {
+        self.owner(id) == self.index
+    }
+}
+
+// DOJO_SHARD_NODE names this node and DOJO_SHARD_MEMBERS lists every shard,
+// this node included, as comma-separated `name=url` pairs. Membership is
+// static: changing it moves most ids to a different owner, and nothing
+// rebalances the data.
+pub struct Shards {
+    node: String,
+    // Sorted by name, so `partition.index` points at this node.
+    members: Vec<(String, String)>,
+    partition: Partition,
+    routing: Routing,
+    client: Client<HttpConnector, Body>,
+}
+
+impl Shards {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let node = match std::env::var("DOJO_SHARD_NODE") {
+            Ok(node) if !node.is_empty() => node,
+            _ => return Ok(None),
+        };
+        let mut members: Vec<(String, String)> = Vec::new();
+        let list = std::env::var("DOJO_SHARD_MEMBERS").unwrap_or_default();
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, url) = entry
+                .split_once('=')
+                .filter(|(name, url)| !name.is_empty() && url.starts_with("http://"))
+                .ok_or_else(|| format!("invalid shard entry {:?}, expected name=http://host:port", entry))?;
+            if members.iter().any(|(member, _)| member == name) {
+                return Err(format!("shard {:?} is listed twice", name));
+            }
+            members.push((name.to_string(), url.trim_end_matches('/').to_string()));
+        }
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        let index = members
+            .iter()
+            .position(|(name, _)| *name == node)
+            .ok_or_else(|| format!("DOJO_SHARD_MEMBERS doesn't list this node {:?}", node))?;
+        let routing = match std::env::var("DOJO_SHARD_ROUTING").as_deref() {
+            Ok("proxy") | Err(_) => Routing::Proxy,
+            Ok("redirect") => Routing::Redirect,
+            Ok(other) => return Err(format!("DOJO_SHARD_ROUTING must be proxy or redirect, got {:?}", other)),
+        };
+        Ok(Some(Shards {
+            partition: Partition {
+                index,
+                count: members.len(),
+            },
+            node,
+            members,
+            routing,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }))
+    }
+
+    pub fn partition(&self) -> Partition {
+        self.partition
+    }
+
+    fn peers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.members
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != self.partition.index)
+            .map(|(_, (name, url))| (name.as_str(), url.as_str()))
+    }
+
+    // Sends requests for a single book to the shard that owns it. Anything
+    // else, and requests another shard already routed, is served here.
+    pub async fn route(&self, req: Request<Body>) -> Result<Request<Body>, Response<Body>> {
+        if req.headers().contains_key(FORWARDED) {
+            return Ok(req);
+        }
+        let owner = crate::api()
+            .unversioned(req.uri().path())
+            .strip_prefix("/books/")
+            .and_then(|rest| rest.split('/').next())
+            .and_then(|id| id.parse().ok())
+            .map(|id| self.partition.owner(id));
+        let url = match owner {
+            Some(owner) if owner != self.partition.index => &self.members[owner].1,
+            _ => return Ok(req),
+        };
+
+        let target = format!("{}{}", url, req.uri().path_and_query().map_or("", |pq| pq.as_str()));
+        if self.routing == Routing::Redirect {
+            // 307 keeps the method and body.
+            return Err(Response::builder()
+                .status(StatusCode::TEMPORARY_REDIRECT)
+                .header(header::LOCATION, target)
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        let (mut parts, body) = req.into_parts();
+        parts.uri = match target.parse() {
+            Ok(uri) => uri,
+            Err(_) => {
+                let invalid = OutboundError::Unreachable("the url is invalid".to_string());
+                return Err(ApiError::upstream(format!("shard at {}", url))(invalid).response());
+            }
+        };
+        parts.headers.remove(header::HOST);
+        parts.headers.insert(FORWARDED, self.node_header());
+        match self.client.request(Request::from_parts(parts, body)).await {
+            Ok(response) => Err(response.map(Body::from)),
+            Err(e) => {
+                let unreachable = OutboundError::Unreachable(e.to_string());
+                Err(ApiError::upstream(format!("shard at {}", url))(unreachable).response())
+            }
+        }
+    }
+
+    fn node_header(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.node).unwrap_or(HeaderValue::from_static("shard"))
+    }
+}
+
+// A fanned-out request's state, with the headers it sends the other
+// shards: which node is asking, the caller's API key or bearer token so
+// each shard checks it as this one did, If-Match for the writes that need
+// it, and the request ID so their logs line up.
+pub struct Fanout {
+    state: SharedState,
+    headers: HeaderMap,
+}
+
+impl FromRequest for Fanout {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let mut headers = HeaderMap::new();
+        if let Some(shards) = &ctx.state().shards {
+            headers.insert(FORWARDED, shards.node_header());
+        }
+        if let Some(key) = ctx.headers().get(auth::API_KEY_HEADER) {
+            headers.insert(auth::API_KEY_HEADER, key.clone());
+        }
+        if let Some(token) = ctx.headers().get(header::AUTHORIZATION) {
+            headers.insert(header::AUTHORIZATION, token.clone());
+        }
+        for tags in ctx.headers().get_all(header::IF_MATCH) {
+            headers.append(header::IF_MATCH, tags.clone());
+        }
+        if let Some(id) = ctx.headers().get(request_id::HEADER) {
+            headers.insert(request_id::HEADER, id.clone());
+        }
+        Ok(Fanout {
+            state: ctx.state().clone(),
+            headers,
+        })
+    }
+}
+
+// Searches every shard and merges the hits by score. Each shard weighs
+// tokens by how rare they are in its own part of the catalog, so scores from
+// different shards are close but not exactly comparable.
+pub async fn search_books(
+    uri: Uri,
+    Query(params): Query<search::Params>,
+    Query(fields): Query<Fields>,
+    links: Links,
+    Fanout { state, headers }: Fanout,
+) -> Result<Response<Body>, ApiError> {
+    let shards = state.shards.as_ref().ok_or_else(ApiError::not_found)?;
+    params.check(&fields)?;
+    let query = fields::strip(uri.query().unwrap_or(""));
+    let mut remote = tokio::task::JoinSet::new();
+    for (name, url) in shards.peers() {
+        let fetch = fetch(shards.client.clone(), headers.clone(), url.to_string(), "/v1/books/search", query.clone());
+        let name = name.to_string();
+        remote.spawn(async move { (name, fetch.await) });
+    }
+    let mut hits: Vec<search::Hit> = state.search(params.q, params.limit).await?;
+    while let Some(joined) = remote.join_next().await {
+        match joined {
+            Ok((_, Ok((part, _)))) => hits.extend(part),
+            Ok((name, Err(e))) => return Err(ApiError::upstream(format!("shard {}", name))(e)),
+            Err(e) => return Err(ApiError::upstream("a shard")(OutboundError::Unreachable(e.to_string()))),
+        }
+    }
+    search::rank(&mut hits, params.limit);
+    Ok(json_response(StatusCode::OK, &search::linked(&hits, &fields, &links))?)
+}
+
+// Every shard's tags, with the counts added up.
+pub async fn list_tags(Fanout { state, headers }: Fanout) -> Result<Response<Body>, ApiError> {
+    let shards = state.shards.as_ref().ok_or_else(ApiError::not_found)?;
+    let mut remote = tokio::task::JoinSet::new();
+    for (name, url) in shards.peers() {
+        let fetch = fetch(shards.client.clone(), headers.clone(), url.to_string(), "/v1/tags", String::new());
+        let name = name.to_string();
+        remote.spawn(async move { (name, fetch.await) });
+    }
+    let mut counts: BTreeMap<String, usize> = state.tags().await?.into_iter().collect();
+    while let Some(joined) = remote.join_next().await {
+        match joined {
+            Ok((_, Ok((part, _)))) => {
+                for TagCount { tag, count } in part {
+                    *counts.entry(tag).or_default() += count;
+                }
+            }
+            Ok((name, Err(e))) => return Err(ApiError::upstream(format!("shard {}", name))(e)),
+            Err(e) => return Err(ApiError::upstream("a shard")(OutboundError::Unreachable(e.to_string()))),
+        }
+    }
+    let tags: Vec<TagCount> = counts.into_iter().map(|(tag, count)| TagCount { tag, count }).collect();
+    Ok(json_response(StatusCode::OK, &tags)?)
+}
+
+// Returns the shard's books along with its total count.
+async fn fetch_books(
+    client: Client<HttpConnector, Body>,
+    headers: HeaderMap,
+    url: String,
+    query: String,
+) -> Result<(Vec<Book>, usize), OutboundError> {
+    let (books, total) = fetch(client, headers, url, "/v1/books", query).await?;
+    let total = total.unwrap_or(books.len());
+    Ok((books, total))
+}
+
+// GETs a JSON list from a shard, with its X-Total-Count if it sent one.
+async fn fetch<T: DeserializeOwned>(
+    client: Client<HttpConnector, Body>,
+    headers: HeaderMap,
+    url: String,
+    path: &str,
+    query: String,
+) -> Result<(Vec<T>, Option<usize>), OutboundError> {
+    let uri = match query.is_empty() {
+        true => format!("{}{}", url, path),
+        false => format!("{}{}?{}", url, path, query),
+    };
+    let mut req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::ACCEPT, "application/json")
+        .body(Body::empty())
+        .map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+    req.headers_mut().extend(headers);
+    let fetch = async {
+        let response = client.request(req).await.map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+        if response.status() != StatusCode::OK {
+            return Err(OutboundError::Status(response.status()));
+        }
+        let total = response.headers().get(TOTAL_COUNT).and_then(|v| v.to_str().ok()?.parse().ok());
+        let body = body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+        let items: Vec<T> = serde_json::from_slice(&body).map_err(|e| OutboundError::Malformed(e.to_string()))?;
+        Ok((items, total))
+    };
+    tokio::time::timeout(Duration::from_secs(10), fetch)
+        .await
+        .map_err(|_| OutboundError::TimedOut)?
+}
+
+// Scatter-gather: the listing asks every shard for its part, passing the
+// query string along, and fails as a whole if any shard doesn't answer.
+pub async fn list_books(
+    Query(page): Query<Page>,
+    Query(filter): Query<Filter>,
+    Query(fields): Query<Fields>,
+    format: Format,
+    links: Links,
+    Fanout { state, headers }: Fanout,
+    conditional: Conditional,
+) -> Result<Response<Body>, ApiError> {
+    let shards = state.shards.as_ref().ok_or_else(ApiError::not_found)?;
+    let after = page.after().map_err(ApiError::bad_request)?;
+    fields.check(listing::BOOK_FIELDS).map_err(ApiError::bad_request)?;
+    let query = fields::strip(&listing::with_page(links.uri(), &page.for_shards()));
+    let mut remote = tokio::task::JoinSet::new();
+    for (name, url) in shards.peers() {
+        let fetch = fetch_books(shards.client.clone(), headers.clone(), url.to_string(), query.clone());
+        let name = name.to_string();
+        remote.spawn(async move { (name, fetch.await) });
+    }
+    let mut books = match crate::with_deleted(&*state, &filter).await {
+        Ok(books) => books,
+        Err(e) => return Ok(html::vary_accept(ApiError::from(e).response())),
+    };
+    books.retain(|book| filter.matches(book));
+    let mut total = books.len();
+    while let Some(joined) = remote.join_next().await {
+        match joined {
+            Ok((_, Ok((part, count)))) => {
+                books.extend(part);
+                total += count;
+            }
+            Ok((name, Err(e))) => return Err(ApiError::upstream(format!("shard {}", name))(e)),
+            Err(e) => return Err(ApiError::upstream("a shard")(OutboundError::Unreachable(e.to_string()))),
+        }
+    }
+    let listed = page.apply(books, after);
+    let total = page.counts_total().then_some(total);
+    // The shards change independently, so the merged listing is only
+    // validated by its ETag.
+    let response = listing::respond(format, &links, &page, &fields, listed, total)?;
+    Ok(conditional.respond(response, None).await)
+}
+
+// Every shard's part of the catalog as one CSV in id order. The parts are
+// gathered as JSON listings first, so unlike a single node's export this
+// one is held in memory.
+pub async fn export_books(
+    uri: Uri,
+    Query(export): Query<Export>,
+    Query(filter): Query<Filter>,
+    Fanout { state, headers }: Fanout,
+) -> Result<Response<Body>, ApiError> {
+    let shards = state.shards.as_ref().ok_or_else(ApiError::not_found)?;
+    export.check().map_err(ApiError::BadRequest)?;
+    let query = uri.query().unwrap_or("").to_string();
+    let mut remote = tokio::task::JoinSet::new();
+    for (name, url) in shards.peers() {
+        let fetch = fetch_books(shards.client.clone(), headers.clone(), url.to_string(), query.clone());
+        let name = name.to_string();
+        remote.spawn(async move { (name, fetch.await) });
+    }
+    let mut books = state.list().await?;
+    books.retain(|book| filter.matches(book));
+    while let Some(joined) = remote.join_next().await {
+        match joined {
+            Ok((_, Ok((part, _)))) => books.extend(part),
+            Ok((name, Err(e))) => return Err(ApiError::upstream(format!("shard {}", name))(e)),
+            Err(e) => return Err(ApiError::upstream("a shard")(OutboundError::Unreachable(e.to_string()))),
+        }
+    }
+    books.sort_by_key(|book| book.id);
+    let mut csv = export::header_row();
+    books.iter().for_each(|book| csv.push_str(&export::row(book)));
+    Ok(export::csv_response(Body::from(csv)))
+}
+
+// Every shard deletes what it holds of the selection. A shard that fails
+// doesn't undo the others, so the answer is 502 with some books gone.
+pub async fn delete_books(
+    Fanout { state, headers }: Fanout,
+    if_match: IfMatch,
+    Json(request): Json<BulkDelete>,
+) -> Result<Response<Body>, ApiError> {
+    let shards = state.shards.as_ref().ok_or_else(ApiError::not_found)?;
+    let selection = request.selection().map_err(ApiError::BadRequest)?;
+    let body = serde_json::to_vec(&request).unwrap_or_default();
+    let mut remote = tokio::task::JoinSet::new();
+    for (name, url) in shards.peers() {
+        let remove = remove(shards.client.clone(), headers.clone(), url.to_string(), body.clone());
+        let name = name.to_string();
+        remote.spawn(async move { (name, remove.await) });
+    }
+    let mut deleted = state.delete_where(move |book| selection.matches(book) && if_match.holds(book)).await?;
+    while let Some(joined) = remote.join_next().await {
+        match joined {
+            Ok((_, Ok(count))) => deleted += count,
+            Ok((name, Err(e))) => return Err(ApiError::upstream(format!("shard {}", name))(e)),
+            Err(e) => return Err(ApiError::upstream("a shard")(OutboundError::Unreachable(e.to_string()))),
+        }
+    }
+    Ok(json_response(StatusCode::OK, &Deleted { deleted })?)
+}
+
+async fn remove(
+    client: Client<HttpConnector, Body>,
+    headers: HeaderMap,
+    url: String,
+    body: Vec<u8>,
+) -> Result<usize, OutboundError> {
+    let mut req = Request::builder()
+        .method(Method::DELETE)
+        .uri(format!("{}/books", url))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+    req.headers_mut().extend(headers);
+    let remove = async {
+        let response = client.request(req).await.map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+        if response.status() != StatusCode::OK {
+            return Err(OutboundError::Status(response.status()));
+        }
+        let body = body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| OutboundError::Unreachable(e.to_string()))?;
+        let answer: Deleted = serde_json::from_slice(&body).map_err(|e| OutboundError::Malformed(e.to_string()))?;
+        Ok(answer.deleted)
+    };
+    tokio::time::timeout(Duration::from_secs(10), remove)
+        .await
+        .map_err(|_| OutboundError::TimedOut)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shards(node: usize, routing: Routing) -> Shards {
+        let members: Vec<(String, String)> =
+            ["a", "b", "c"].iter().map(|name| (name.to_string(), format!("http://{}.shards.test:8080", name))).collect();
+        Shards {
+            node: members[node].0.clone(),
+            partition: Partition { index: node, count: members.len() },
+            members,
+            routing,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+
+    // The owners splitmix64 gives, worked out apart. Every node has to
+    // agree on them, whichever build it runs.
+    #[test]
+    fn owners_are_fixed_and_spread() {
+        let partition = Partition { index: 0, count: 3 };
+        let owners: Vec<usize> = (1..=12).map(|id| partition.owner(id)).collect();
+        assert_eq!(owners, [2, 1, 0, 1, 2, 2, 0, 1, 1, 1, 0, 0]);
+        let mut counts = [0; 3];
+        for id in 1..=3000 {
+            counts[partition.owner(id)] += 1;
+        }
+        assert_eq!(counts, [999, 985, 1016]);
+        assert!(partition.owns(3) && !partition.owns(1));
+        assert_eq!(Partition { index: 0, count: 1 }.owner(u64::MAX), 0);
+    }
+
+    #[test]
+    fn peers_are_the_other_members() {
+        let shards = shards(1, Routing::Proxy);
+        let peers: Vec<&str> = shards.peers().map(|(name, _)| name).collect();
+        assert_eq!(peers, ["a", "c"]);
+    }
+
+    fn request(path: &str, forwarded: bool) -> Request<Body> {
+        let mut req = Request::builder().uri(path);
+        if forwarded {
+            req = req.header(FORWARDED, "b");
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    // Book 1 lives on c; this node is a. Redirects keep the query.
+    #[tokio::test]
+    async fn single_book_requests_go_to_the_owner() {
+        let shards = shards(0, Routing::Redirect);
+        let redirect = shards.route(request("/v1/books/1/reviews?limit=5", false)).await.unwrap_err();
+        assert_eq!(redirect.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(redirect.headers()[header::LOCATION], "http://c.shards.test:8080/v1/books/1/reviews?limit=5");
+        for (path, forwarded) in [("/books/3", false), ("/books/1", true), ("/books", false), ("/books/search?q=dune", false)] {
+            assert!(shards.route(request(path, forwarded)).await.is_ok(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,1036 @@
+use hyper::{Method, Request, StatusCode};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    audit::Audit,
+    auth::{self, ApiKeys},
+    body::{self, Body},
+    compact,
+    contract::{Contract, Violations},
+    federation::Federation,
+    hosts::Hosts,
+    jwt::Jwt,
+    kiosk::Devices,
+    proxy::TrustedProxies,
+    stack::Stack,
+    tenants::Tenants,
+    AppState, RequestTracker, SharedState, Storage, StorageMetrics, TaskRegistry,
+};
+
+// Drives the full request pipeline in-process. Tests run on a paused
+// current-thread runtime, so timers resolve instantly in virtual time and
+// task interleaving depends only on the order work is submitted.
+pub struct Sim {
+    pub state: SharedState,
+    rng: SimRng,
+}
+
+impl Sim {
+    pub fn new(seed: u64) -> Self {
+        Sim {
+            state: Arc::new(test_state(seed)),
+            rng: SimRng(seed),
+        }
+    }
+
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(seed: u64, chaos: crate::chaos::Chaos) -> Self {
+        let mut state = test_state(seed);
+        state.chaos = chaos;
+        Sim {
+            state: Arc::new(state),
+            rng: SimRng(seed),
+        }
+    }
+
+    pub fn with_tenants(seed: u64, max: usize) -> Self {
+        let mut state = test_state(seed);
+        state.tenants = Tenants::new(max);
+        Sim {
+            state: Arc::new(state),
+            rng: SimRng(seed),
+        }
+    }
+
+    pub fn with_jwt(seed: u64, jwt: Jwt) -> Self {
+        let mut state = test_state(seed);
+        state.jwt = Some(jwt);
+        Sim {
+            state: Arc::new(state),
+            rng: SimRng(seed),
+        }
+    }
+
+    pub fn with_admin_token(seed: u64, token: &str) -> Self {
+        let mut state = test_state(seed);
+        state.admin_token = Some(auth::digest(token));
+        Sim {
+            state: Arc::new(state),
+            rng: SimRng(seed),
+        }
+    }
+
+    // Fails the test if the response diverges from openapi.json.
+    pub async fn request(&self, method: Method, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+        self.send(method, path, &[], body).await
+    }
+
+    pub async fn request_if_match(
+        &self,
+        method: Method,
+        path: &str,
+        if_match: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        match if_match {
+            Some(tag) => self.send(method, path, &[(hyper::header::IF_MATCH, tag)], body).await,
+            None => self.send(method, path, &[], body).await,
+        }
+    }
+
+    pub async fn request_as(&self, token: &str, method: Method, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let authorization = format!("Bearer {}", token);
+        self.send(method, path, &[(hyper::header::AUTHORIZATION, &authorization)], body).await
+    }
+
+    // `request` on behalf of a tenant named by X-Tenant-Id.
+    pub async fn request_for(&self, tenant: &str, method: Method, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let name = hyper::header::HeaderName::from_static(crate::tenants::HEADER);
+        self.send(method, path, &[(name, tenant)], body).await
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(hyper::header::HeaderName, &str)],
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let (status, _, value) = self.exchange(method, path, headers, body).await;
+        (status, value)
+    }
+
+    // `request`, with the response's headers too.
+    pub async fn request_with_headers(&self, method: Method, path: &str) -> (StatusCode, hyper::HeaderMap, Value) {
+        self.exchange(method, path, &[], None).await
+    }
+
+    async fn exchange(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(hyper::header::HeaderName, &str)],
+        body: Option<Value>,
+    ) -> (StatusCode, hyper::HeaderMap, Value) {
+        let mut req = Request::builder().method(method.clone()).uri(path);
+        if body.is_some() && !headers.iter().any(|(name, _)| *name == hyper::header::CONTENT_TYPE) {
+            req = req.header(hyper::header::CONTENT_TYPE, "application/json");
+        }
+        let body = body.map_or_else(Body::empty, |v| Body::from(v.to_string()));
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        let req = req.body(body).unwrap();
+        let response = Stack::default().call(req, self.state.clone()).await.unwrap();
+
+        let violations = &response.extensions().get::<Violations>().unwrap().0;
+        assert!(violations.is_empty(), "{} {} broke the contract: {:?}", method, path, violations);
+
+        let (parts, body) = response.into_parts();
+        let bytes = body::to_bytes(body).await.unwrap();
+        let value = serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+        (parts.status, parts.headers, value)
+    }
+
+    pub async fn advance(&self, by: Duration) {
+        tokio::time::advance(by).await;
+        // Let tasks woken by the timer run before the test inspects state.
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    pub fn storage_op_count(&self, op: &str) -> u64 {
+        self.state.storage_metrics.snapshot().get(op).map_or(0, |stats| stats.count)
+    }
+
+    // Applies `steps` random CRUD operations, checking every response
+    // against an in-memory model of what the store should contain.
+    pub async fn run_workload(&mut self, steps: usize) -> BTreeMap<u64, Value> {
+        let mut model: BTreeMap<u64, Value> = BTreeMap::new();
+
+        for step in 0..steps {
+            let existing: Vec<u64> = model.keys().copied().collect();
+            let target = if existing.is_empty() || self.rng.below(4) == 0 {
+                self.rng.below(50) + 1
+            } else {
+                existing[self.rng.below(existing.len() as u64) as usize]
+            };
+
+            // Writes send the ETag of the version the model holds.
+            let tag = model.get(&target).map_or_else(
+                || "*".to_string(),
+                |book| crate::etag::of(&serde_json::from_value(book.clone()).unwrap()),
+            );
+
+            match self.rng.below(4) {
+                0 => {
+                    let body = serde_json::json!({
+                        "title": format!("Book {}", step),
+                        "author": format!("Author {}", self.rng.below(5)),
+                    });
+                    let (status, book) = self.request(Method::POST, "/books", Some(body)).await;
+                    assert_eq!(status, StatusCode::CREATED);
+                    model.insert(book["id"].as_u64().unwrap(), book);
+                }
+                1 => {
+                    let (status, book) = self.request(Method::GET, &format!("/books/{}", target), None).await;
+                    match model.get(&target) {
+                        Some(expected) => {
+                            // A single book also says whether it's on loan,
+                            // and nothing here lends books out.
+                            let mut expected = expected.clone();
+                            expected["available"] = Value::Bool(true);
+                            assert_eq!(status, StatusCode::OK);
+                            assert_eq!(book, expected);
+                        }
+                        None => assert_eq!(status, StatusCode::NOT_FOUND),
+                    }
+                }
+                2 => {
+                    let body = serde_json::json!({ "title": format!("Retitled {}", step) });
+                    let (status, book) =
+                        self.request_if_match(Method::PUT, &format!("/books/{}", target), Some(&tag), Some(body)).await;
+                    match model.get_mut(&target) {
+                        Some(expected) => {
+                            assert_eq!(status, StatusCode::OK);
+                            expected["title"] = Value::String(format!("Retitled {}", step));
+                            assert_eq!(&book, expected);
+                        }
+                        None => assert_eq!(status, StatusCode::NOT_FOUND),
+                    }
+                }
+                _ => {
+                    let (status, _) = self.request_if_match(Method::DELETE, &format!("/books/{}", target), Some(&tag), None).await;
+                    let expected = if model.remove(&target).is_some() {
+                        StatusCode::NO_CONTENT
+                    } else {
+                        StatusCode::NOT_FOUND
+                    };
+                    assert_eq!(status, expected);
+                }
+            }
+        }
+
+        model
+    }
+}
+
+fn test_state(_seed: u64) -> AppState {
+    let audit = Arc::new(Audit::default());
+    let mut storage = Storage::new();
+    storage.audit = Some(audit.clone());
+    AppState {
+        storage: RwLock::new(storage),
+        storage_metrics: StorageMetrics::new(Duration::from_secs(60)),
+        tasks: Mutex::new(TaskRegistry::new()),
+        requests: RequestTracker::new(100),
+        admin_token: None,
+        api_keys: ApiKeys::default(),
+        jwt: None,
+        pprof_enabled: false,
+        jsonapi: false,
+        recorder: None,
+        access_log: None,
+        contract: Some(Contract::load()),
+        trusted_proxies: TrustedProxies::default(),
+        hosts: Hosts::default(),
+        kiosk_devices: Devices::default(),
+        federation: Federation::default(),
+        webhooks: Default::default(),
+        live: Default::default(),
+        audit,
+        covers: Default::default(),
+        openlibrary: Default::default(),
+        multiversx: Default::default(),
+        ids: Default::default(),
+        cluster: None,
+        raft: None,
+        shards: None,
+        rate_limiter: None,
+        cors: None,
+        compression: Default::default(),
+        request_timeout: None,
+        concurrency: Default::default(),
+        events: None,
+        postgres: None,
+        redis: None,
+        created: Default::default(),
+        cache: Default::default(),
+        snapshots: None,
+        leadership: Default::default(),
+        merkle: Default::default(),
+        public_url: None,
+        tenants: Default::default(),
+        maintenance: Default::default(),
+        #[cfg(feature = "chaos")]
+        chaos: crate::chaos::Chaos::new(_seed, 0.0, Duration::ZERO, 0.0),
+    }
+}
+
+// splitmix64, kept separate from the chaos generator so workloads don't
+// depend on which features are enabled.
+struct SimRng(u64);
+
+impl SimRng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn workload_matches_model() {
+    for seed in 0..20 {
+        let mut sim = Sim::new(seed);
+        let model = sim.run_workload(200).await;
+
+        let (status, books) = sim.request(Method::GET, "/books", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut listed: Vec<Value> = books.as_array().unwrap().clone();
+        listed.sort_by_key(|book| book["id"].as_u64());
+        assert_eq!(listed, model.into_values().collect::<Vec<_>>(), "seed {}", seed);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_seed_replays_identically() {
+    let first = Sim::new(42).run_workload(300).await;
+    let second = Sim::new(42).run_workload(300).await;
+    assert_eq!(first, second);
+}
+
+#[tokio::test(start_paused = true)]
+async fn scheduled_compaction_follows_virtual_time() {
+    let sim = Sim::new(1);
+    tokio::spawn(compact::run_scheduled(sim.state.clone(), Duration::from_secs(3600)));
+    sim.advance(Duration::ZERO).await;
+
+    sim.advance(Duration::from_secs(3599)).await;
+    assert_eq!(sim.storage_op_count("compact"), 0);
+
+    sim.advance(Duration::from_secs(1)).await;
+    assert_eq!(sim.storage_op_count("compact"), 1);
+
+    sim.advance(Duration::from_secs(3 * 3600)).await;
+    assert_eq!(sim.storage_op_count("compact"), 4);
+}
+
+#[tokio::test(start_paused = true)]
+async fn migration_task_completes_in_background() {
+    let sim = Sim::with_admin_token(3, "root");
+    // Written straight to storage, as the API now trims titles itself.
+    for i in 0..250 {
+        let book = books_model::CreateBookRequest {
+            title: format!(" Book {} ", i),
+            author: "A".to_string(),
+            isbn: None,
+            author_id: None,
+            tags: Vec::new(),
+            edition: books_model::Edition::default(),
+            uid: None,
+        };
+        sim.state.with_storage("insert", String::new, |storage| storage.insert_book(book)).await.unwrap();
+    }
+
+    let (status, task) = sim.request_as("root", Method::POST, "/admin/migrate-data", None).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    sim.advance(Duration::ZERO).await;
+
+    let (_, task) = sim.request_as("root", Method::GET, &format!("/admin/tasks/{}", task["id"]), None).await;
+    assert_eq!(task["status"], "completed");
+    assert_eq!(task["processed"], 250);
+    assert_eq!(task["changed"], 250);
+}
+
+#[tokio::test(start_paused = true)]
+async fn admin_and_error_responses_match_contract() {
+    let sim = Sim::with_admin_token(4, "root");
+    sim.request(Method::POST, "/books", Some(serde_json::json!({ "title": "Dune", "author": "Herbert" }))).await;
+
+    assert_eq!(sim.request(Method::GET, "/books/abc", None).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(sim.request(Method::POST, "/books", Some(Value::from(1))).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(sim.request_as("root", Method::POST, "/admin/check?fix=true", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request_as("root", Method::POST, "/admin/compact", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request_as("root", Method::GET, "/admin/storage-metrics", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request_as("root", Method::GET, "/admin/tasks/99", None).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(sim.request(Method::GET, "/metrics", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request(Method::GET, "/schemas", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request(Method::GET, "/schemas/Book.json", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request(Method::GET, "/schemas/Nope.json", None).await.0, StatusCode::NOT_FOUND);
+}
+
+// Every operator endpoint is refused without an admin, and hidden while
+// no admin token or JWTs are configured, whether or not its handler
+// checks anything itself.
+#[tokio::test(start_paused = true)]
+async fn admin_endpoints_are_denied_by_default() {
+    let endpoints = [
+        (Method::POST, "/admin/check?fix=true"),
+        (Method::POST, "/admin/compact"),
+        (Method::POST, "/admin/migrate-data"),
+        (Method::GET, "/admin/storage-metrics"),
+        (Method::GET, "/admin/tasks/1"),
+        (Method::POST, "/admin/backup"),
+        (Method::GET, "/admin/stats"),
+        (Method::GET, "/admin/log-level"),
+        (Method::GET, "/admin/maintenance"),
+        (Method::GET, "/debug/requests"),
+    ];
+    let open = Sim::new(4);
+    let guarded = Sim::with_admin_token(4, "root");
+    for (method, path) in endpoints {
+        assert_eq!(open.request(method.clone(), path, None).await.0, StatusCode::NOT_FOUND, "{} {}", method, path);
+        assert_eq!(guarded.request(method.clone(), path, None).await.0, StatusCode::UNAUTHORIZED, "{} {}", method, path);
+        assert_eq!(guarded.request_as("nope", method.clone(), path, None).await.0, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(guarded.request(Method::GET, "/admin/ui", None).await.0, StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn known_paths_answer_405_and_options_and_trailing_slashes_redirect() {
+    let sim = Sim::new(5);
+
+    let (status, headers, _) = sim.request_with_headers(Method::PATCH, "/books").await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(headers[hyper::header::ALLOW], "GET, HEAD, POST, DELETE, OPTIONS");
+    let (status, headers, _) = sim.request_with_headers(Method::PUT, "/v2/books/1/reviews").await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(headers[hyper::header::ALLOW], "GET, HEAD, POST, OPTIONS");
+    let (status, headers, _) = sim.request_with_headers(Method::OPTIONS, "/books/1").await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(headers[hyper::header::ALLOW], "GET, HEAD, PUT, PATCH, DELETE, OPTIONS");
+    assert_eq!(sim.request_with_headers(Method::OPTIONS, "/nope").await.0, StatusCode::NOT_FOUND);
+
+    let (status, headers, _) = sim.request_with_headers(Method::GET, "/v1/books/?limit=2").await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(headers[hyper::header::LOCATION], "/v1/books?limit=2");
+    assert_eq!(sim.request(Method::GET, "/books/1/", None).await.0, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(sim.request(Method::GET, "/nope/", None).await.0, StatusCode::NOT_FOUND);
+}
+
+const TEST_JWT_SECRET: &str = "sim-test-secret-at-least-32-bytes-long";
+
+fn test_token(role: &str, expires_in: i64) -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+    Jwt::new(TEST_JWT_SECRET).sign(&serde_json::json!({ "sub": role, "role": role, "exp": now + expires_in }))
+}
+
+#[tokio::test(start_paused = true)]
+async fn jwt_roles_gate_writes_and_admin_endpoints() {
+    let sim = Sim::with_jwt(6, Jwt::new(TEST_JWT_SECRET));
+    let (reader, editor, admin) = (test_token("reader", 3600), test_token("editor", 3600), test_token("admin", 3600));
+    let book = || Some(serde_json::json!({ "title": "Dune", "author": "Herbert" }));
+
+    assert_eq!(sim.request(Method::GET, "/books", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request(Method::POST, "/books", book()).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(sim.request_as(&reader, Method::POST, "/books", book()).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(sim.request_as(&editor, Method::POST, "/books", book()).await.0, StatusCode::CREATED);
+    assert_eq!(sim.request_as(&admin, Method::POST, "/books", book()).await.0, StatusCode::CREATED);
+
+    let expired = test_token("editor", -3600);
+    let (status, problem) = sim.request_as(&expired, Method::POST, "/books", book()).await;
+    assert_eq!((status, problem["detail"].as_str()), (StatusCode::UNAUTHORIZED, Some("the token has expired")));
+    let forged = Jwt::new("some-other-secret-at-least-32-bytes").sign(&serde_json::json!({ "role": "admin", "exp": u32::MAX }));
+    let (status, problem) = sim.request_as(&forged, Method::POST, "/books", book()).await;
+    assert_eq!((status, problem["detail"].as_str()), (StatusCode::UNAUTHORIZED, Some("the token's signature is invalid")));
+
+    assert_eq!(sim.request(Method::POST, "/admin/compact", None).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(sim.request_as(&editor, Method::POST, "/admin/compact", None).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(sim.request_as(&admin, Method::POST, "/admin/compact", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request_as(&editor, Method::GET, "/debug/requests", None).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(sim.request_as(&admin, Method::GET, "/debug/requests", None).await.0, StatusCode::OK);
+}
+
+// The audit log names who made each change, so only an admin reads it.
+#[tokio::test(start_paused = true)]
+async fn the_audit_log_is_for_admins() {
+    let sim = Sim::with_jwt(16, Jwt::new(TEST_JWT_SECRET));
+    let (editor, admin) = (test_token("editor", 3600), test_token("admin", 3600));
+    let book = Some(serde_json::json!({ "title": "Dune", "author": "Herbert" }));
+    let (_, created) = sim.request_as(&editor, Method::POST, "/books", book).await;
+    let history = format!("/books/{}/history", created["id"]);
+
+    for path in ["/audit", history.as_str()] {
+        assert_eq!(sim.request(Method::GET, path, None).await.0, StatusCode::UNAUTHORIZED, "{}", path);
+        assert_eq!(sim.request_as(&editor, Method::GET, path, None).await.0, StatusCode::FORBIDDEN, "{}", path);
+        let (status, entries) = sim.request_as(&admin, Method::GET, path, None).await;
+        assert_eq!((status, entries.as_array().map(Vec::len)), (StatusCode::OK, Some(1)), "{}", path);
+    }
+    assert_eq!(Sim::new(16).request(Method::GET, "/audit", None).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(start_paused = true)]
+async fn admin_token_guards_admin_endpoints() {
+    let sim = Sim::with_admin_token(15, "sim-admin-token");
+    for (method, path) in [
+        (Method::POST, "/admin/migrate-data"),
+        (Method::GET, "/admin/tasks/1"),
+        (Method::POST, "/admin/check?fix=true"),
+        (Method::POST, "/admin/compact"),
+        (Method::GET, "/admin/storage-metrics"),
+    ] {
+        assert_eq!(sim.request(method.clone(), path, None).await.0, StatusCode::UNAUTHORIZED, "{}", path);
+        assert_eq!(sim.request_as("wrong-token", method, path, None).await.0, StatusCode::UNAUTHORIZED, "{}", path);
+    }
+    assert_eq!(sim.request_as("sim-admin-token", Method::POST, "/admin/compact", None).await.0, StatusCode::OK);
+    assert_eq!(sim.request(Method::GET, "/books", None).await.0, StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn backups_restore_into_another_instance() {
+    let admin = test_token("admin", 3600);
+    let source = Sim::with_jwt(7, Jwt::new(TEST_JWT_SECRET));
+    for title in ["Dune", "Solaris", "Neuromancer"] {
+        let book = Some(serde_json::json!({ "title": title, "author": "Someone" }));
+        source.request_as(&admin, Method::POST, "/books", book).await;
+    }
+    let authorization = format!("Bearer {}", admin);
+    let headers = [(hyper::header::AUTHORIZATION, authorization.as_str()), (hyper::header::IF_MATCH, "*")];
+    assert_eq!(source.send(Method::DELETE, "/books/2", &headers, None).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(source.request(Method::POST, "/admin/backup", None).await.0, StatusCode::UNAUTHORIZED);
+    let (status, backup) = source.request_as(&admin, Method::POST, "/admin/backup", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(backup["next_id"], 4);
+
+    let target = Sim::with_jwt(8, Jwt::new(TEST_JWT_SECRET));
+    let stray = Some(serde_json::json!({ "title": "Stray", "author": "Nobody" }));
+    target.request_as(&admin, Method::POST, "/books", stray).await;
+    let (status, report) = target.request_as(&admin, Method::POST, "/admin/restore", Some(backup.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((report["books"].as_u64(), report["next_id"].as_u64()), (Some(2), Some(4)));
+    let (_, books) = target.request(Method::GET, "/books", None).await;
+    let titles: Vec<&str> = books.as_array().unwrap().iter().filter_map(|book| book["title"].as_str()).collect();
+    assert_eq!(titles, ["Dune", "Neuromancer"]);
+    assert_eq!(target.request_as(&admin, Method::POST, "/books/2/restore", None).await.0, StatusCode::OK);
+
+    let mut newer = backup;
+    newer["format"] = Value::from(3);
+    let (status, _) = target.request_as(&admin, Method::POST, "/admin/restore", Some(newer)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+// Written while format 1 was current: authors without their counter, no
+// reviews key, no log position, and a book from before tags and uids.
+#[tokio::test(start_paused = true)]
+async fn format_1_backups_are_upgraded() {
+    let admin = test_token("admin", 3600);
+    let sim = Sim::with_jwt(10, Jwt::new(TEST_JWT_SECRET));
+    let backup = serde_json::json!({
+        "format": 1,
+        "next_id": 3,
+        "books": [{ "id": 2, "title": "Dune", "author": "Frank Herbert", "isbn": null, "author_id": 7 }],
+        "authors": [{ "id": 7, "name": "Frank Herbert" }],
+        "loans": {},
+        "kiosk_events": [],
+        "next_acquisition_id": 1,
+        "acquisitions": {},
+        "next_interlibrary_loan_id": 1,
+        "interlibrary_loans": {},
+    });
+    let (status, report) = sim.request_as(&admin, Method::POST, "/admin/restore", Some(backup)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((report["books"].as_u64(), report["authors"].as_u64()), (Some(1), Some(1)));
+
+    let (_, book) = sim.request(Method::GET, "/books/2", None).await;
+    assert_eq!((book["title"].as_str(), book["author_id"].as_u64()), (Some("Dune"), Some(7)));
+    let author = Some(serde_json::json!({ "name": "Ursula K. Le Guin" }));
+    let (status, author) = sim.request_as(&admin, Method::POST, "/authors", author).await;
+    assert_eq!((status, author["id"].as_u64()), (StatusCode::CREATED, Some(8)));
+    let (_, backup) = sim.request_as(&admin, Method::POST, "/admin/backup", None).await;
+    assert_eq!((backup["format"].as_u64(), backup["wal_seq"].as_u64()), (Some(2), Some(0)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn tenants_keep_their_catalogs_apart() {
+    let sim = Sim::with_tenants(11, 2);
+    let book = |title: &str| Some(serde_json::json!({ "title": title, "author": "Someone" }));
+    let (_, shared) = sim.request(Method::POST, "/books", book("Default")).await;
+    assert_eq!(shared["id"], 1);
+    sim.request(Method::POST, "/books", book("Default 2")).await;
+
+    // Each tenant numbers its books from 1, whichever way it's named.
+    let (status, acme) = sim.request_for("acme", Method::POST, "/books", book("Acme")).await;
+    assert_eq!((status, acme["id"].as_u64()), (StatusCode::CREATED, Some(1)));
+    let (status, listed) = sim.request(Method::GET, "/tenants/acme/books", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(listed.as_array().map(|books| books.len()), Some(1));
+    assert_eq!(listed[0]["title"], "Acme");
+    let (_, globex) = sim.request(Method::POST, "/tenants/globex/books", book("Globex")).await;
+    assert_eq!(globex["id"], 1);
+    let (_, fetched) = sim.request_for("globex", Method::GET, "/books/1", None).await;
+    assert_eq!(fetched["title"], "Globex");
+    let (_, fetched) = sim.request(Method::GET, "/books/1", None).await;
+    assert_eq!(fetched["title"], "Default");
+    let (status, _) = sim.request_for("acme", Method::GET, "/books/2", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = sim.request_for("acme", Method::GET, "/tenants/globex/books", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = sim.request_for("no spaces", Method::GET, "/books", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = sim.request(Method::GET, "/tenants/acme/metrics", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = sim.request_for("initech", Method::GET, "/books", None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+
+    // Without tenants, naming one is a mistake rather than the default.
+    let (status, _) = Sim::new(11).request_for("acme", Method::GET, "/books", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(start_paused = true)]
+async fn stats_follow_the_catalog() {
+    let sim = Sim::with_admin_token(12, "sim-admin-token");
+    for (title, author, isbn) in [("Dune", "Herbert", Some("9780441172719")), ("Children of Dune", "Herbert", None), ("Solaris", "Lem", None)] {
+        let book = Some(serde_json::json!({ "title": title, "author": author, "isbn": isbn }));
+        assert_eq!(sim.request(Method::POST, "/books", book).await.0, StatusCode::CREATED);
+    }
+    let headers = [(hyper::header::IF_MATCH, "*")];
+    assert_eq!(sim.send(Method::DELETE, "/books/3", &headers, None).await.0, StatusCode::NO_CONTENT);
+
+    assert_eq!(sim.request(Method::GET, "/admin/stats", None).await.0, StatusCode::UNAUTHORIZED);
+    let (status, stats) = sim.request_as("sim-admin-token", Method::GET, "/admin/stats", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((stats["books"].as_u64(), stats["isbn_percent"].as_f64()), (Some(2), Some(50.0)));
+    assert_eq!(stats["books_per_author"], serde_json::json!({ "Herbert": 2 }));
+    let created: u64 = stats["created_per_day"].as_object().unwrap().values().filter_map(Value::as_u64).sum();
+    assert_eq!(created, 3);
+    assert_eq!(stats["storage"]["status"], "ok");
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_isbn_10_and_its_isbn_13_are_one_isbn() {
+    let sim = Sim::new(43);
+    let book = |isbn: &str| Some(serde_json::json!({ "title": "Ocean", "author": "Someone", "isbn": isbn }));
+    assert_eq!(sim.request(Method::POST, "/books", book("0-306-40615-2")).await.0, StatusCode::CREATED);
+    let (status, problem) = sim.request(Method::POST, "/books", book("9780306406157")).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(problem["book_id"], 1);
+    assert_eq!(sim.request(Method::POST, "/books", book("9780306406164")).await.0, StatusCode::CREATED);
+    let headers = [(hyper::header::IF_MATCH, "*")];
+    assert_eq!(sim.send(Method::PUT, "/books/2", &headers, book("0306406152")).await.0, StatusCode::CONFLICT);
+}
+
+#[tokio::test(start_paused = true)]
+async fn duplicates_merge_into_one() {
+    let sim = Sim::new(13);
+    let books = [
+        serde_json::json!({ "title": "Les Misérables", "author": "Victor Hugo", "tags": ["classic"] }),
+        serde_json::json!({ "title": "les miserables", "author": "VICTOR  HUGO", "isbn": "9780140444308", "tags": ["french"] }),
+        serde_json::json!({ "title": "Solaris", "author": "Stanislaw Lem" }),
+    ];
+    for book in books {
+        assert_eq!(sim.request(Method::POST, "/books", Some(book)).await.0, StatusCode::CREATED);
+    }
+    let review = Some(serde_json::json!({ "rating": 5, "text": "Long." }));
+    assert_eq!(sim.request(Method::POST, "/books/2/reviews", review).await.0, StatusCode::CREATED);
+
+    let (status, clusters) = sim.request(Method::GET, "/books/duplicates", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(clusters.as_array().map(Vec::len), Some(1));
+    assert_eq!(clusters[0]["matched_on"], serde_json::json!(["title_and_author"]));
+
+    let merge = Some(serde_json::json!({ "from": 2, "into": 1 }));
+    let (status, merged) = sim.request(Method::POST, "/books/merge", merge).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(merged["isbn"], "9780140444308");
+    assert_eq!(merged["tags"], serde_json::json!(["classic", "french"]));
+    assert_eq!(sim.request(Method::GET, "/books/2", None).await.0, StatusCode::NOT_FOUND);
+    let (_, reviews) = sim.request(Method::GET, "/books/1/reviews", None).await;
+    assert_eq!(reviews.as_array().map(Vec::len), Some(1));
+    let (_, clusters) = sim.request(Method::GET, "/books/duplicates", None).await;
+    assert_eq!(clusters, serde_json::json!([]));
+    let itself = Some(serde_json::json!({ "from": 1, "into": 1 }));
+    assert_eq!(sim.request(Method::POST, "/books/merge", itself).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn books_with_uids_can_be_patched() {
+    let mut sim = Sim::new(20);
+    Arc::get_mut(&mut sim.state).unwrap().ids = crate::ids::Strategy::Uuid;
+    let book = Some(serde_json::json!({ "title": "Solaris", "author": "Stanislaw Lem" }));
+    let (status, created) = sim.request(Method::POST, "/books", book).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let uid = created["uid"].as_str().unwrap().to_string();
+
+    let headers = [(hyper::header::CONTENT_TYPE, crate::patch::MEDIA_TYPE), (hyper::header::IF_MATCH, "*")];
+    let retitle = serde_json::json!([{ "op": "replace", "path": "/title", "value": "Fiasco" }]);
+    let (status, patched) = sim.send(Method::PATCH, "/books/1", &headers, Some(retitle)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((patched["title"].as_str(), patched["uid"].as_str()), (Some("Fiasco"), Some(uid.as_str())));
+
+    let reuid = serde_json::json!([{ "op": "remove", "path": "/uid" }]);
+    let (status, problem) = sim.send(Method::PATCH, "/books/1", &headers, Some(reuid)).await;
+    assert_eq!((status, problem["detail"].as_str()), (StatusCode::UNPROCESSABLE_ENTITY, Some("uid is read-only")));
+}
+
+#[tokio::test(start_paused = true)]
+async fn patch_and_bulk_delete_need_if_match() {
+    let sim = Sim::new(44);
+    for title in ["Dune", "Ubik"] {
+        let book = Some(serde_json::json!({ "title": title, "author": "Someone" }));
+        assert_eq!(sim.request(Method::POST, "/books", book).await.0, StatusCode::CREATED);
+    }
+    let (_, headers, _) = sim.request_with_headers(Method::GET, "/books/1").await;
+    let dune = headers[hyper::header::ETAG].to_str().unwrap().to_string();
+
+    let retitle = serde_json::json!([{ "op": "replace", "path": "/title", "value": "Dune Messiah" }]);
+    let patch = |if_match| [(hyper::header::CONTENT_TYPE, crate::patch::MEDIA_TYPE), (hyper::header::IF_MATCH, if_match)];
+    let unconditional = [(hyper::header::CONTENT_TYPE, crate::patch::MEDIA_TYPE)];
+    assert_eq!(sim.send(Method::PATCH, "/books/1", &unconditional, Some(retitle.clone())).await.0, StatusCode::PRECONDITION_REQUIRED);
+    assert_eq!(sim.send(Method::PATCH, "/books/1", &patch(&dune), Some(retitle.clone())).await.0, StatusCode::OK);
+    assert_eq!(sim.send(Method::PATCH, "/books/1", &patch(&dune), Some(retitle)).await.0, StatusCode::PRECONDITION_FAILED);
+
+    // Dune has changed since its ETag was read, so only Ubik goes.
+    let (_, headers, _) = sim.request_with_headers(Method::GET, "/books/2").await;
+    let ubik = headers[hyper::header::ETAG].to_str().unwrap().to_string();
+    let both = Some(serde_json::json!({ "ids": [1, 2] }));
+    assert_eq!(sim.request(Method::DELETE, "/books", both.clone()).await.0, StatusCode::PRECONDITION_REQUIRED);
+    let tags = format!("{}, {}", dune, ubik);
+    let (status, deleted) = sim.send(Method::DELETE, "/books", &[(hyper::header::IF_MATCH, tags.as_str())], both).await;
+    assert_eq!((status, deleted["deleted"].as_u64()), (StatusCode::OK, Some(1)));
+    assert_eq!(sim.request(Method::GET, "/books/1", None).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn listings_keep_id_order() {
+    let sim = Sim::new(18);
+    for title in ["Ubik", "Dune", "Solaris", "Anathem", "Neuromancer"] {
+        let book = Some(serde_json::json!({ "title": title, "author": "Someone", "tags": ["sf"] }));
+        assert_eq!(sim.request(Method::POST, "/books", book).await.0, StatusCode::CREATED);
+    }
+    // Changes, and a trip through the trash, don't move a book.
+    let headers = [(hyper::header::IF_MATCH, "*")];
+    let retitled = Some(serde_json::json!({ "title": "Ubik (reissue)", "author": "Someone" }));
+    assert_eq!(sim.send(Method::PUT, "/books/1", &headers, retitled).await.0, StatusCode::OK);
+    assert_eq!(sim.send(Method::DELETE, "/books/3", &headers, None).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(sim.request(Method::POST, "/books/3/restore", None).await.0, StatusCode::OK);
+    assert_eq!(sim.send(Method::DELETE, "/books/4", &headers, None).await.0, StatusCode::NO_CONTENT);
+
+    let ids = |listed: &Value| listed.as_array().map(|books| books.iter().filter_map(|book| book["id"].as_u64()).collect::<Vec<_>>());
+    for uri in ["/books", "/books?tag=sf", "/books?title_contains=e", "/books?sort=author"] {
+        let (_, first) = sim.request(Method::GET, uri, None).await;
+        let (_, again) = sim.request(Method::GET, uri, None).await;
+        assert_eq!(first, again, "{}", uri);
+        let listed = ids(&first).unwrap();
+        assert!(listed.windows(2).all(|pair| pair[0] < pair[1]), "{} listed {:?}", uri, listed);
+    }
+    let (_, all) = sim.request(Method::GET, "/books?include_deleted=true", None).await;
+    assert_eq!(ids(&all), Some(vec![1, 2, 3, 4, 5]));
+}
+
+#[tokio::test]
+async fn long_listings_are_streamed() {
+    let sim = Sim::new(19);
+    for i in 1..=1200 {
+        let book = books_model::CreateBookRequest {
+            title: format!("Book {}", i),
+            author: "A".to_string(),
+            isbn: None,
+            author_id: None,
+            tags: vec!["bulk".to_string()],
+            edition: books_model::Edition::default(),
+            uid: None,
+        };
+        sim.state.with_storage("insert", String::new, |storage| storage.insert_book(book)).await.unwrap();
+    }
+    let (status, headers, all) = sim.request_with_headers(Method::GET, "/books?fields=id,title").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers.get(hyper::header::ETAG).is_none() && headers.contains_key(hyper::header::LAST_MODIFIED));
+    let books = all.as_array().unwrap();
+    assert_eq!(books.len(), 1200);
+    assert_eq!(books[1199], serde_json::json!({ "id": 1200, "title": "Book 1200" }));
+
+    let (_, page) = sim.request(Method::GET, "/v2/books?limit=1100", None).await;
+    assert_eq!(page["books"].as_array().map(Vec::len), Some(1100));
+    assert!(page["books"][0]["_links"]["self"]["href"].as_str().is_some_and(|href| href.ends_with("/v2/books/1")));
+    assert!(page["_links"]["next"]["href"].as_str().is_some_and(|href| href.contains("limit=1100")));
+    let (_, few) = sim.request(Method::GET, "/books?limit=3", None).await;
+    assert_eq!(few.as_array().map(Vec::len), Some(3));
+}
+
+#[tokio::test]
+async fn editions_are_checked_sorted_and_filtered() {
+    let sim = Sim::new(17);
+    let books = [
+        serde_json::json!({ "title": "Dune", "author": "Frank Herbert", "published_year": 1965, "pages": 412, "language": "EN" }),
+        serde_json::json!({ "title": "Solaris", "author": "Stanislaw Lem", "published_year": 1961, "publisher": " MON ", "language": "pol" }),
+        serde_json::json!({ "title": "Ubik", "author": "Philip K. Dick" }),
+    ];
+    for book in books {
+        assert_eq!(sim.request(Method::POST, "/books", Some(book)).await.0, StatusCode::CREATED);
+    }
+    let (_, solaris) = sim.request(Method::GET, "/books/2", None).await;
+    assert_eq!((&solaris["publisher"], &solaris["language"]), (&Value::from("MON"), &Value::from("pol")));
+
+    let (_, sorted) = sim.request(Method::GET, "/books?sort=-published_year&fields=id", None).await;
+    assert_eq!(sorted, serde_json::json!([{ "id": 1 }, { "id": 2 }, { "id": 3 }]));
+    let (_, filtered) = sim.request(Method::GET, "/books?filter=language%20eq%20%22en%22%20or%20pages%20gt%20500", None).await;
+    assert_eq!(filtered.as_array().map(|books| books.iter().map(|book| book["id"].clone()).collect()), Some(vec![Value::from(1)]));
+
+    let invalid = Some(serde_json::json!({ "title": "X", "author": "Y", "published_year": 1200, "pages": 0, "language": "english" }));
+    let (status, problem) = sim.request(Method::POST, "/books", invalid).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let fields: Vec<&str> = problem["errors"].as_array().unwrap().iter().filter_map(|error| error["field"].as_str()).collect();
+    assert_eq!(fields, ["published_year", "pages", "language"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn as_of_undoes_later_changes() {
+    let sim = Sim::new(14);
+    let dune = Some(serde_json::json!({ "title": "Dune", "author": "Frank Herbert" }));
+    assert_eq!(sim.request(Method::POST, "/books", dune).await.0, StatusCode::CREATED);
+    let then = crate::conditional::now_ms();
+    sim.advance(Duration::from_millis(5)).await;
+
+    let headers = [(hyper::header::IF_MATCH, "*")];
+    let retitled = Some(serde_json::json!({ "title": "Dune Messiah", "author": "Frank Herbert" }));
+    assert_eq!(sim.send(Method::PUT, "/books/1", &headers, retitled).await.0, StatusCode::OK);
+    let solaris = Some(serde_json::json!({ "title": "Solaris", "author": "Stanislaw Lem" }));
+    assert_eq!(sim.request(Method::POST, "/books", solaris).await.0, StatusCode::CREATED);
+    assert_eq!(sim.send(Method::DELETE, "/books/1", &headers, None).await.0, StatusCode::NO_CONTENT);
+
+    let (status, listed) = sim.request(Method::GET, &format!("/books?as_of={}", then), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let titles: Vec<&str> = listed.as_array().unwrap().iter().filter_map(|book| book["title"].as_str()).collect();
+    assert_eq!(titles, ["Dune"]);
+    let (_, listed) = sim.request(Method::GET, "/books?as_of=0", None).await;
+    assert_eq!(listed, serde_json::json!([]));
+    let (_, listed) = sim.request(Method::GET, "/books", None).await;
+    assert_eq!(listed[0]["title"], "Solaris");
+
+    let (status, _) = sim.request(Method::GET, &format!("/books?as_of={}&include_deleted=true", then), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_change_feed_follows_from_since() {
+    let sim = Sim::new(15);
+    let (_, start) = sim.request(Method::GET, "/changes", None).await;
+    assert_eq!(start, serde_json::json!({ "changes": [], "next": 0, "more": false }));
+    for title in ["Dune", "Solaris", "Ubik"] {
+        let book = Some(serde_json::json!({ "title": title, "author": "Someone" }));
+        assert_eq!(sim.request(Method::POST, "/books", book).await.0, StatusCode::CREATED);
+    }
+    let headers = [(hyper::header::IF_MATCH, "*")];
+    assert_eq!(sim.send(Method::DELETE, "/books/2", &headers, None).await.0, StatusCode::NO_CONTENT);
+
+    let (status, feed) = sim.request(Method::GET, "/changes?since=0&limit=3", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((feed["next"].as_u64(), feed["more"].as_bool()), (Some(3), Some(true)));
+    assert_eq!(feed["changes"][2]["book"]["title"], "Ubik");
+    let (_, feed) = sim.request(Method::GET, "/changes?since=3", None).await;
+    assert_eq!(feed["changes"][0]["action"], "book.deleted");
+    assert_eq!((feed["changes"][0]["book_id"].as_u64(), &feed["changes"][0]["book"]), (Some(2), &Value::Null));
+    assert_eq!((feed["next"].as_u64(), feed["more"].as_bool()), (Some(4), Some(false)));
+
+    assert_eq!(sim.request(Method::GET, "/changes?since=9", None).await.0, StatusCode::GONE);
+}
+
+#[tokio::test(start_paused = true)]
+async fn polls_wait_for_the_next_change() {
+    let sim = Sim::new(16);
+    let dune = Some(serde_json::json!({ "title": "Dune", "author": "Frank Herbert" }));
+    let create = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        sim.request(Method::POST, "/books", dune).await
+    };
+    let ((status, feed), (created, _)) = tokio::join!(sim.request(Method::GET, "/books/poll?timeout=30", None), create);
+    assert_eq!((status, created), (StatusCode::OK, StatusCode::CREATED));
+    assert_eq!(feed["changes"][0]["book"]["title"], "Dune");
+    assert_eq!(feed["next"], 1);
+
+    let (status, feed) = sim.request(Method::GET, "/books/poll?since=1&timeout=1", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(feed, serde_json::json!({ "changes": [], "next": 1, "more": false }));
+    assert_eq!(sim.request(Method::GET, "/books/poll?timeout=301", None).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(start_paused = true)]
+async fn json_api_documents_wrap_the_catalog() {
+    let sim = Sim::new(13);
+    let (_, author) = sim.request(Method::POST, "/authors", Some(serde_json::json!({ "name": "Frank Herbert" }))).await;
+    let accept = [(hyper::header::ACCEPT, crate::jsonapi::MEDIA_TYPE)];
+    let sent = [accept[0].clone(), (hyper::header::CONTENT_TYPE, crate::jsonapi::MEDIA_TYPE)];
+    let doc = serde_json::json!({ "data": {
+        "type": "books",
+        "attributes": { "title": "Dune", "author": "" },
+        "relationships": { "author": { "data": { "type": "authors", "id": author["id"].to_string() } } },
+    } });
+
+    let (status, created) = sim.send(Method::POST, "/books", &sent, Some(doc)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!((created["data"]["type"].as_str(), created["data"]["id"].as_str()), (Some("books"), Some("1")));
+    assert_eq!(created["data"]["attributes"]["author"], "Frank Herbert");
+    assert_eq!(created["data"]["relationships"]["author"]["data"]["id"], author["id"].to_string());
+
+    let (_, listed) = sim.send(Method::GET, "/books?limit=1", &accept, None).await;
+    assert_eq!(listed["data"].as_array().map(Vec::len), Some(1));
+    assert_eq!(listed["links"]["self"], "/books?limit=1");
+    let (_, listed) = sim.send(Method::GET, "/v2/books", &accept, None).await;
+    assert_eq!(listed["data"][0]["links"]["self"].as_str().map(|href| href.ends_with("/v2/books/1")), Some(true));
+
+    let (status, missing) = sim.send(Method::GET, "/books/99", &accept, None).await;
+    assert_eq!((status, missing["errors"][0]["status"].as_str()), (StatusCode::NOT_FOUND, Some("404")));
+    let invalid = serde_json::json!({ "data": { "type": "books", "attributes": { "title": "Dune" } } });
+    let (status, invalid) = sim.send(Method::POST, "/books", &sent, Some(invalid)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(invalid["errors"][0]["source"]["pointer"], "/data/attributes/author");
+
+    let (_, plain) = sim.request(Method::GET, "/books/1", None).await;
+    assert_eq!(plain["title"], "Dune");
+}
+
+#[test]
+fn contract_reports_divergence() {
+    let contract = Contract::load();
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+    headers.insert(hyper::header::ETAG, "\"0\"".parse().unwrap());
+    headers.insert(hyper::header::LAST_MODIFIED, "Thu, 01 Jan 1970 00:00:00 GMT".parse().unwrap());
+    let body = br#"{"id":1,"title":"Dune","isbn":7,"extra":true}"#;
+
+    let violations = contract.check(&Method::GET, "/books/1", StatusCode::OK, &headers, body);
+    assert_eq!(
+        violations,
+        [
+            "body.author: required field is missing",
+            "body.extra: field is not documented",
+            "body.isbn: expected string, got 7",
+        ]
+    );
+
+    let violations = contract.check(&Method::POST, "/books/1", StatusCode::OK, &headers, body);
+    assert_eq!(violations, ["POST is not documented for this path"]);
+}
+
+#[cfg(feature = "chaos")]
+#[tokio::test(start_paused = true)]
+async fn injected_latency_uses_virtual_time() {
+    use crate::chaos::Chaos;
+
+    let sim = Sim::with_chaos(9, Chaos::new(9, 1.0, Duration::from_secs(30), 0.0));
+    let start = tokio::time::Instant::now();
+    let (status, _) = sim.request(Method::GET, "/books", None).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(start.elapsed(), Duration::from_secs(30));
+}
+
+#[cfg(feature = "chaos")]
+#[tokio::test(start_paused = true)]
+async fn injected_storage_errors_are_reproducible() {
+    use crate::chaos::Chaos;
+
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let sim = Sim::with_chaos(5, Chaos::new(5, 0.0, Duration::ZERO, 0.3));
+        let mut statuses = Vec::new();
+        for _ in 0..50 {
+            statuses.push(sim.request(Method::GET, "/books", None).await.0);
+        }
+        assert!(statuses.contains(&StatusCode::INTERNAL_SERVER_ERROR));
+        runs.push(statuses);
+    }
+    assert_eq!(runs[0], runs[1]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn websocket_handshakes_are_checked_and_accepted() {
+    use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
+    let sim = Sim::new(31);
+    // The example handshake of RFC 6455, section 1.3.
+    let handshake = [
+        (UPGRADE, "websocket"),
+        (CONNECTION, "keep-alive, Upgrade"),
+        (SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="),
+        (SEC_WEBSOCKET_VERSION, "13"),
+    ];
+    let (status, headers, _) = sim.exchange(Method::GET, "/ws", &handshake, None).await;
+    assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(headers[SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    let (status, headers, _) = sim.exchange(Method::GET, "/ws", &handshake[2..], None).await;
+    assert_eq!(status, StatusCode::UPGRADE_REQUIRED);
+    assert_eq!(headers[UPGRADE], "websocket");
+    let mut old = handshake.clone();
+    old[3].1 = "8";
+    let (status, headers, _) = sim.exchange(Method::GET, "/ws", &old, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(headers[SEC_WEBSOCKET_VERSION], "13");
+    let unkeyed = [handshake[0].clone(), handshake[1].clone(), handshake[3].clone()];
+    assert_eq!(sim.exchange(Method::GET, "/ws", &unkeyed, None).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(start_paused = true)]
+async fn graphql_runs_queries_and_mutations() {
+    let sim = Sim::new(37);
+    let graphql = |query: &str, variables: Value| {
+        let body = serde_json::json!({ "query": query, "variables": variables });
+        sim.request(Method::POST, "/graphql", Some(body))
+    };
+
+    let create = "mutation ($input: CreateBookInput!) { added: createBook(input: $input) { id title } }";
+    let (status, body) = graphql(create, serde_json::json!({ "input": { "title": "Solaris", "author": "Lem" } })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, serde_json::json!({ "data": { "added": { "id": "1", "title": "Solaris" } } }));
+    graphql(create, serde_json::json!({ "input": { "title": "Eden", "author": "Lem" } })).await;
+
+    let query = "query ($skip: Boolean = true) {
+        first: books(first: 1) { ...Names etag @skip(if: $skip) }
+        book(id: 2) { __typename id ... on Book { author } }
+        missing: book(id: 9) { id }
+    }
+    fragment Names on Book { title }";
+    let (status, body) = graphql(query, Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body["data"],
+        serde_json::json!({
+            "first": [{ "title": "Solaris" }],
+            "book": { "__typename": "Book", "id": "2", "author": "Lem" },
+            "missing": null,
+        })
+    );
+
+    // A stale ETag fails the field, not the request.
+    let delete = r#"mutation { deleteBook(id: 1, ifMatch: "\"stale\"") { id } }"#;
+    let (status, body) = graphql(delete, Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["data"], serde_json::json!({ "deleteBook": null }));
+    assert_eq!(body["errors"][0]["extensions"]["code"], "PRECONDITION_FAILED");
+    assert_eq!(body["errors"][0]["path"], serde_json::json!(["deleteBook"]));
+
+    let (status, body) = graphql("{ books { id ", Value::Null).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errors"][0]["locations"], serde_json::json!([{ "line": 1, "column": 14 }]));
+    assert!(body.get("data").is_none());
+    let (status, body) = graphql(create, serde_json::json!({ "input": { "title": "Fiasco" } })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errors"][0]["message"], "variable $input.author is required");
+}
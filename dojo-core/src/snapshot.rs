@@ -0,0 +1,242 @@
+use books_model::{Author, Book};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    acquisitions::Acquisition, federation::InterlibraryLoan, kiosk::Loan, reviews::Review, wal::Wal, SharedState, Storage,
+};
+
+// Bumped when the file layout changes. Images in an older format are
+// brought up to this one as they're read, so a field Image gains comes
+// with an upgrade saying what old files had instead.
+const FORMAT: u64 = 2;
+
+// UPGRADES[n] takes an image from format n + 1 to n + 2.
+const UPGRADES: [fn(&mut Map<String, Value>); 1] = [from_format_1];
+
+// Everything the in-memory storage holds, as the snapshot file and
+// /admin/backup write it.
+#[derive(Serialize, Deserialize)]
+pub struct Image {
+    format: u64,
+    next_id: u64,
+    books: Vec<Book>,
+    authors: Vec<Author>,
+    next_author_id: u64,
+    loans: HashMap<u64, Loan>,
+    reviews: HashMap<u64, Vec<Review>>,
+    next_review_id: u64,
+    kiosk_events: HashSet<(String, String)>,
+    next_acquisition_id: u64,
+    acquisitions: BTreeMap<u64, Acquisition>,
+    next_interlibrary_loan_id: u64,
+    interlibrary_loans: BTreeMap<u64, InterlibraryLoan>,
+    // The last write-ahead log record this holds.
+    wal_seq: u64,
+}
+
+impl Image {
+    pub fn capture(storage: &Storage) -> Self {
+        Image {
+            format: FORMAT,
+            next_id: storage.next_id,
+            books: storage.all_books(),
+            authors: storage.authors.values().cloned().collect(),
+            next_author_id: storage.next_author_id,
+            loans: storage.loans.clone(),
+            reviews: storage.reviews.clone(),
+            next_review_id: storage.next_review_id,
+            kiosk_events: storage.kiosk_events.clone(),
+            next_acquisition_id: storage.next_acquisition_id,
+            acquisitions: storage.acquisitions.clone(),
+            next_interlibrary_loan_id: storage.next_interlibrary_loan_id,
+            interlibrary_loans: storage.interlibrary_loans.clone(),
+            wal_seq: storage.wal.as_ref().map_or(0, Wal::seq),
+        }
+    }
+
+    // Brings an image in any format up to FORMAT before it's read. One a
+    // newer build wrote is refused, as it may hold what this one would drop.
+    pub fn upgrade(image: &mut Value) -> Result<(), String> {
+        let Some(fields) = image.as_object_mut() else {
+            return Err("no snapshot format".to_string());
+        };
+        match fields.get("format").and_then(Value::as_u64) {
+            Some(format @ 1..=FORMAT) => {
+                for upgrade in &UPGRADES[format as usize - 1..] {
+                    upgrade(fields);
+                }
+                fields.insert("format".to_string(), FORMAT.into());
+                Ok(())
+            }
+            Some(format) => Err(format!("snapshot format {}, expected {} or older", format, FORMAT)),
+            None => Err("no snapshot format".to_string()),
+        }
+    }
+
+    // Replaces what `storage` holds with the image. Ids carry on from the
+    // higher of the two, so none is handed out twice.
+    pub fn apply(self, storage: &mut Storage) -> Restored {
+        storage.set_books(self.books);
+        storage.next_id = storage.next_id.max(self.next_id);
+        storage.set_authors(self.authors);
+        storage.next_author_id = storage.next_author_id.max(self.next_author_id);
+        storage.loans = self.loans;
+        storage.lent.clear();
+        storage.reviews = self.reviews;
+        storage.next_review_id = storage.next_review_id.max(self.next_review_id);
+        storage.kiosk_events = self.kiosk_events;
+        storage.next_acquisition_id = self.next_acquisition_id;
+        storage.acquisitions = self.acquisitions;
+        storage.next_interlibrary_loan_id = self.next_interlibrary_loan_id;
+        storage.interlibrary_loans = self.interlibrary_loans;
+        Restored {
+            books: storage.books.len(),
+            wal_seq: self.wal_seq,
+        }
+    }
+}
+
+// DOJO_SNAPSHOT_FILE keeps the in-memory storage in a JSON file. It's read
+// on startup, rewritten every DOJO_SNAPSHOT_INTERVAL_SECS (60 by default)
+// if anything changed, and once more on shutdown. Changes since the last
+// write are lost if the process dies without shutting down, unless
+// DOJO_WAL_FILE keeps a log of them.
+pub struct Snapshots {
+    path: PathBuf,
+    interval: Duration,
+    // Digest of what was last written, to skip rewriting an unchanged file.
+    written: Mutex<Option<[u8; 32]>>,
+}
+
+impl Snapshots {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let path = match std::env::var("DOJO_SNAPSHOT_FILE") {
+            Ok(path) if !path.is_empty() => PathBuf::from(path),
+            _ => return Ok(None),
+        };
+        let interval = match std::env::var("DOJO_SNAPSHOT_INTERVAL_SECS") {
+            Ok(secs) => secs
+                .parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("DOJO_SNAPSHOT_INTERVAL_SECS must be a positive integer, got {:?}", secs))?,
+            Err(_) => Duration::from_secs(60),
+        };
+        Ok(Some(Snapshots {
+            path,
+            interval,
+            written: Mutex::new(None),
+        }))
+    }
+
+    // Fills `storage` from the file. A missing file is a first run, not an
+    // error.
+    pub fn load(&self, storage: &mut Storage) -> Result<Option<Restored>, String> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", self.path.display(), e)),
+        };
+        let mut image: Value = serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        Image::upgrade(&mut image).map_err(|e| format!("{} has {}", self.path.display(), e))?;
+        let image: Image = serde_json::from_value(image).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        *self.written.lock().unwrap() = Some(Sha256::digest(&bytes).into());
+        Ok(Some(image.apply(storage)))
+    }
+}
+
+// Format 1 grew authors, reviews and the log position without a bump, so
+// its files may lack them. The counters carry on from the highest id they
+// have.
+fn from_format_1(image: &mut Map<String, Value>) {
+    let authors = image.entry("authors").or_insert_with(|| Value::Array(Vec::new()));
+    let next_author_id = next_id(authors.as_array().into_iter().flatten());
+    image.entry("next_author_id").or_insert_with(|| next_author_id.into());
+
+    let reviews = image.entry("reviews").or_insert_with(|| Value::Object(Map::new()));
+    let by_book = reviews.as_object().into_iter().flat_map(Map::values);
+    let next_review_id = next_id(by_book.filter_map(Value::as_array).flatten());
+    image.entry("next_review_id").or_insert_with(|| next_review_id.into());
+
+    image.entry("wal_seq").or_insert_with(|| 0.into());
+}
+
+fn next_id<'a>(items: impl Iterator<Item = &'a Value>) -> u64 {
+    items.filter_map(|item| item["id"].as_u64()).max().map_or(1, |id| id + 1)
+}
+
+pub struct Restored {
+    pub books: usize,
+    pub wal_seq: u64,
+}
+
+// Writes the file if the storage changed since the last write. The new
+// contents go to a temporary file that's renamed over the old one, so a
+// crash mid-write leaves the previous snapshot intact.
+pub async fn save(state: &SharedState) -> Result<bool, String> {
+    let Some(snapshots) = &state.snapshots else {
+        return Ok(false);
+    };
+    let image = state
+        .read_storage("snapshot", String::new, Image::capture)
+        .await
+        .map_err(|e| e.to_string())?;
+    let wal_seq = image.wal_seq;
+    let bytes = serde_json::to_vec_pretty(&image).map_err(|e| e.to_string())?;
+    let digest: [u8; 32] = Sha256::digest(&bytes).into();
+    if *snapshots.written.lock().unwrap() == Some(digest) {
+        return Ok(false);
+    }
+
+    let path = snapshots.path.clone();
+    let write = move || {
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)
+    };
+    tokio::task::spawn_blocking(write)
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("{}: {}", snapshots.path.display(), e))?;
+    *snapshots.written.lock().unwrap() = Some(digest);
+
+    // A failed checkpoint only leaves records in the log that replay skips.
+    let checkpoint = state
+        .with_storage("wal_checkpoint", || format!("seq={}", wal_seq), |storage| {
+            storage.wal.as_mut().map_or(Ok(()), |wal| wal.checkpoint(wal_seq))
+        })
+        .await;
+    if let Ok(Err(e)) = checkpoint {
+        tracing::warn!("truncating the write-ahead log failed: {}", e);
+    }
+    Ok(true)
+}
+
+pub async fn run_scheduled(state: SharedState) {
+    let Some(snapshots) = &state.snapshots else {
+        return;
+    };
+    let mut interval = tokio::time::interval(snapshots.interval);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = save(&state).await {
+            tracing::warn!("writing the snapshot failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{de, Deserialize, Deserializer};
+use std::{cmp::Ordering, marker::PhantomData};
+
+// Something a listing can be ordered by. KEYS names what it can be sorted
+// on, and `compare` orders two of them by one of those keys.
+pub trait Sortable {
+    const KEYS: &'static [&'static str];
+
+    fn compare(&self, other: &Self, key: &str) -> Ordering;
+}
+
+// ?sort=author,-title: keys in order of precedence, a leading '-' reversing
+// one. Items equal on every key keep the order they came in.
+pub struct Sort<T> {
+    keys: Vec<(&'static str, bool)>,
+    of: PhantomData<fn(&T)>,
+}
+
+impl<T> Default for Sort<T> {
+    fn default() -> Self {
+        Sort {
+            keys: Vec::new(),
+            of: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Sort<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(&self.keys).finish()
+    }
+}
+
+impl<T: Sortable> Sort<T> {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut sort = Sort::default();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let (name, descending) = match part.strip_prefix('-') {
+                Some(name) => (name, true),
+                None => (part, false),
+            };
+            let key = T::KEYS.iter().find(|key| **key == name).ok_or_else(|| {
+                format!("unknown sort key {:?}, expected one of {}", name, T::KEYS.join(", "))
+            })?;
+            if sort.keys.iter().any(|(seen, _)| seen == key) {
+                return Err(format!("sort key {:?} is given twice", name));
+            }
+            sort.keys.push((key, descending));
+        }
+        Ok(sort)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn apply(&self, items: &mut [T]) {
+        if self.keys.is_empty() {
+            return;
+        }
+        items.sort_by(|a, b| {
+            for (key, descending) in &self.keys {
+                let order = a.compare(b, key);
+                let order = if *descending { order.reverse() } else { order };
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+            Ordering::Equal
+        });
+    }
+}
+
+// For `#[serde(deserialize_with = "sort::deserialize")]` on a query field.
+pub fn deserialize<'de, T: Sortable, D: Deserializer<'de>>(deserializer: D) -> Result<Sort<T>, D::Error> {
+    let spec = String::deserialize(deserializer)?;
+    Sort::parse(&spec).map_err(de::Error::custom)
+}
+
+// Text keys ignore case, so "apple" comes before "Banana" and "Amy" and
+// "amy" are left to the next key.
+pub fn text(a: &str, b: &str) -> Ordering {
+    a.to_lowercase().cmp(&b.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Row {
+        name: &'static str,
+        rank: u32,
+        seen: u32,
+    }
+
+    impl Sortable for Row {
+        const KEYS: &'static [&'static str] = &["name", "rank"];
+
+        fn compare(&self, other: &Self, key: &str) -> Ordering {
+            match key {
+                "name" => text(self.name, other.name),
+                _ => self.rank.cmp(&other.rank),
+            }
+        }
+    }
+
+    fn rows() -> Vec<Row> {
+        [("banana", 1), ("Apple", 2), ("apple", 1), ("cherry", 2), ("Banana", 1)]
+            .into_iter()
+            .enumerate()
+            .map(|(seen, (name, rank))| Row { name, rank, seen: seen as u32 })
+            .collect()
+    }
+
+    fn sorted(spec: &str) -> Vec<u32> {
+        let mut rows = rows();
+        Sort::parse(spec).unwrap().apply(&mut rows);
+        rows.iter().map(|row| row.seen).collect()
+    }
+
+    #[test]
+    fn later_keys_break_ties_and_minus_reverses() {
+        assert_eq!(sorted("name"), [1, 2, 0, 4, 3]);
+        assert_eq!(sorted("name,-rank"), [1, 2, 0, 4, 3]);
+        assert_eq!(sorted("name,rank"), [2, 1, 0, 4, 3]);
+        assert_eq!(sorted("-rank,name"), [1, 3, 2, 0, 4]);
+        assert_eq!(sorted("-name"), [3, 0, 4, 1, 2]);
+    }
+
+    // Without keys, or with rows equal on all of them, the order stays.
+    #[test]
+    fn ties_keep_their_order() {
+        assert!(Sort::<Row>::parse(" , ").unwrap().is_empty());
+        assert_eq!(sorted(""), [0, 1, 2, 3, 4]);
+        assert_eq!(sorted("rank"), [0, 2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn unknown_and_repeated_keys_are_refused() {
+        assert_eq!(
+            Sort::<Row>::parse("name,colour").unwrap_err(),
+            "unknown sort key \"colour\", expected one of name, rank"
+        );
+        assert_eq!(Sort::<Row>::parse("rank,-rank").unwrap_err(), "sort key \"rank\" is given twice");
+        assert!(Sort::<Row>::parse("--name").is_err());
+    }
+
+    #[test]
+    fn query_values_deserialize() {
+        #[derive(Deserialize)]
+        struct Query {
+            #[serde(deserialize_with = "deserialize")]
+            sort: Sort<Row>,
+        }
+        let query: Query = serde_json::from_value(serde_json::json!({ "sort": "-rank" })).unwrap();
+        assert_eq!(format!("{:?}", query.sort), "[(\"rank\", true)]");
+        assert!(serde_json::from_value::<Query>(serde_json::json!({ "sort": "size" })).is_err());
+    }
+}
@@ -0,0 +1,595 @@
+use books_model::{Author, Book};
+
+use crate::events::Event;
+
+// A change made during one storage update. They're written to disk
+// together once the update finishes.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+#[derive(Debug)]
+pub enum Write {
+    Created(Book),
+    Updated(Book),
+    // Moved to the trash, or back out of it.
+    Trashed(Book),
+    Restored(Book),
+    AuthorCreated(Author),
+    AuthorDeleted(u64),
+    Event(Event),
+    // The relay got every event up to and including this one to the broker.
+    Delivered(String),
+    // An event for the webhooks, kept until every one that wanted it has
+    // it or has given up on it.
+    Hook(Event),
+    Hooked(String),
+}
+
+pub struct Snapshot {
+    // The trash too, with `deleted_at` set.
+    pub books: Vec<Book>,
+    pub next_id: u64,
+    pub authors: Vec<Author>,
+    pub next_author_id: u64,
+    pub events: Vec<Event>,
+    pub hooks: Vec<Event>,
+}
+
+#[cfg(feature = "sqlite")]
+pub use imp::Db;
+
+// Without the feature the type still exists, so storage can hold an
+// Option<Db>, but it can never be opened.
+#[cfg(not(feature = "sqlite"))]
+pub enum Db {}
+
+#[cfg(not(feature = "sqlite"))]
+impl Db {
+    pub fn open(_path: &str) -> Result<Self, String> {
+        Err("this build has no SQLite support, rebuild with --features sqlite".to_string())
+    }
+
+    pub fn load(&self) -> Result<Snapshot, String> {
+        match *self {}
+    }
+
+    pub fn commit(&self, _writes: &[Write], _next_id: u64) -> Result<(), String> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod imp {
+    use books_model::{Author, Book};
+    use std::{
+        ffi::{c_char, c_int, c_void, CStr, CString},
+        ptr,
+        sync::Mutex,
+    };
+
+    use super::{Snapshot, Write};
+    use crate::{
+        events::{Event, Topic},
+        migrate,
+    };
+
+    #[allow(non_camel_case_types)]
+    enum sqlite3 {}
+    #[allow(non_camel_case_types)]
+    enum sqlite3_stmt {}
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        fn sqlite3_open_v2(filename: *const c_char, db: *mut *mut sqlite3, flags: c_int, vfs: *const c_char) -> c_int;
+        fn sqlite3_close(db: *mut sqlite3) -> c_int;
+        fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+        fn sqlite3_busy_timeout(db: *mut sqlite3, ms: c_int) -> c_int;
+        fn sqlite3_prepare_v2(
+            db: *mut sqlite3,
+            sql: *const c_char,
+            len: c_int,
+            stmt: *mut *mut sqlite3_stmt,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        fn sqlite3_bind_int64(stmt: *mut sqlite3_stmt, index: c_int, value: i64) -> c_int;
+        fn sqlite3_bind_text(
+            stmt: *mut sqlite3_stmt,
+            index: c_int,
+            value: *const c_char,
+            len: c_int,
+            destructor: Option<unsafe extern "C" fn(*mut c_void)>,
+        ) -> c_int;
+        fn sqlite3_bind_null(stmt: *mut sqlite3_stmt, index: c_int) -> c_int;
+        fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+        fn sqlite3_column_int64(stmt: *mut sqlite3_stmt, column: c_int) -> i64;
+        fn sqlite3_column_text(stmt: *mut sqlite3_stmt, column: c_int) -> *const u8;
+        fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, column: c_int) -> c_int;
+        fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+    }
+
+    const SQLITE_OK: c_int = 0;
+    const SQLITE_ROW: c_int = 100;
+    const SQLITE_DONE: c_int = 101;
+    const SQLITE_OPEN_READWRITE: c_int = 0x2;
+    const SQLITE_OPEN_CREATE: c_int = 0x4;
+    const SQLITE_OPEN_FULLMUTEX: c_int = 0x10000;
+
+    const PRAGMAS: &str = "
+        PRAGMA journal_mode = WAL;
+        PRAGMA synchronous = NORMAL;
+    ";
+
+    // The migrations, oldest first; PRAGMA user_version holds how many a
+    // file has had. Files from before it was kept are at 0 with some of the
+    // columns already added, which is all the first ones tolerate.
+    const MIGRATIONS: [&str; 8] = [
+        "CREATE TABLE IF NOT EXISTS books (
+            id INTEGER PRIMARY KEY,
+            title TEXT NOT NULL,
+            author TEXT NOT NULL,
+            isbn TEXT
+        );
+        CREATE TABLE IF NOT EXISTS deleted_books (
+            id INTEGER PRIMARY KEY,
+            title TEXT NOT NULL,
+            author TEXT NOT NULL,
+            isbn TEXT,
+            deleted_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS authors (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS counters (
+            name TEXT PRIMARY KEY,
+            value INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS outbox (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            id TEXT NOT NULL UNIQUE,
+            topic TEXT NOT NULL,
+            type TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            source TEXT NOT NULL,
+            occurred_at_ms INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            data TEXT NOT NULL
+        );",
+        "ALTER TABLE books ADD COLUMN author_id INTEGER;
+        ALTER TABLE deleted_books ADD COLUMN author_id INTEGER",
+        "ALTER TABLE books ADD COLUMN tags TEXT;
+        ALTER TABLE deleted_books ADD COLUMN tags TEXT",
+        "ALTER TABLE books ADD COLUMN uid TEXT;
+        ALTER TABLE deleted_books ADD COLUMN uid TEXT",
+        "ALTER TABLE books ADD COLUMN nft TEXT;
+        ALTER TABLE deleted_books ADD COLUMN nft TEXT",
+        "ALTER TABLE books ADD COLUMN anchor TEXT;
+        ALTER TABLE deleted_books ADD COLUMN anchor TEXT",
+        "ALTER TABLE books ADD COLUMN edition TEXT;
+        ALTER TABLE deleted_books ADD COLUMN edition TEXT",
+        "CREATE TABLE webhook_outbox (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            id TEXT NOT NULL UNIQUE,
+            topic TEXT NOT NULL,
+            type TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            source TEXT NOT NULL,
+            occurred_at_ms INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            data TEXT NOT NULL
+        )",
+    ];
+
+    enum Param<'a> {
+        Int(i64),
+        Text(&'a str),
+        Null,
+    }
+
+    struct Row(*mut sqlite3_stmt);
+
+    impl Row {
+        fn int(&self, column: c_int) -> i64 {
+            unsafe { sqlite3_column_int64(self.0, column) }
+        }
+
+        fn text(&self, column: c_int) -> Option<String> {
+            unsafe {
+                let text = sqlite3_column_text(self.0, column);
+                if text.is_null() {
+                    return None;
+                }
+                let len = sqlite3_column_bytes(self.0, column) as usize;
+                Some(String::from_utf8_lossy(std::slice::from_raw_parts(text, len)).into_owned())
+            }
+        }
+    }
+
+    struct Connection(*mut sqlite3);
+
+    // Used behind a mutex only, and opened in serialized mode regardless.
+    unsafe impl Send for Connection {}
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            unsafe { sqlite3_close(self.0) };
+        }
+    }
+
+    impl Connection {
+        fn error(&self) -> String {
+            unsafe { CStr::from_ptr(sqlite3_errmsg(self.0)).to_string_lossy().into_owned() }
+        }
+
+        fn batch(&self, sql: &str) -> Result<(), String> {
+            for statement in sql.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+                self.query(statement, &[], |_| ())?;
+            }
+            Ok(())
+        }
+
+        // Each migration and the version it leaves behind are written
+        // together, so one that fails is tried again on the next start.
+        fn migrate(&self) -> Result<(), String> {
+            let version = self.query("PRAGMA user_version", &[], |row| row.int(0))?;
+            let version = version.first().copied().unwrap_or(0) as u64;
+            let untracked = version == 0;
+            for index in migrate::pending("the file", version, MIGRATIONS.len())? {
+                self.execute("BEGIN IMMEDIATE", &[])?;
+                let result = (|| {
+                    for statement in MIGRATIONS[index].split(';').map(str::trim).filter(|s| !s.is_empty()) {
+                        match self.execute(statement, &[]) {
+                            // SQLite can't add a column only if it's missing.
+                            Err(e) if untracked && e.contains("duplicate column") => {}
+                            result => result?,
+                        }
+                    }
+                    self.execute(&format!("PRAGMA user_version = {}", index + 1), &[])
+                })();
+                match result {
+                    Ok(()) => self.execute("COMMIT", &[])?,
+                    Err(e) => {
+                        let _ = self.execute("ROLLBACK", &[]);
+                        return Err(format!("migration {}: {}", index + 1, e));
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn execute(&self, sql: &str, params: &[Param]) -> Result<(), String> {
+            self.query(sql, params, |_| ()).map(|_| ())
+        }
+
+        // Runs one statement and maps every row it returns. Text parameters
+        // are bound without copying; they outlive the statement.
+        fn query<T>(&self, sql: &str, params: &[Param], mut row: impl FnMut(&Row) -> T) -> Result<Vec<T>, String> {
+            let sql = CString::new(sql).map_err(|e| e.to_string())?;
+            let mut stmt = ptr::null_mut();
+            if unsafe { sqlite3_prepare_v2(self.0, sql.as_ptr(), -1, &mut stmt, ptr::null_mut()) } != SQLITE_OK {
+                return Err(self.error());
+            }
+            let result = (|| {
+                for (index, param) in params.iter().enumerate() {
+                    let index = index as c_int + 1;
+                    let rc = unsafe {
+                        match param {
+                            Param::Int(value) => sqlite3_bind_int64(stmt, index, *value),
+                            Param::Text(value) => {
+                                sqlite3_bind_text(stmt, index, value.as_ptr().cast(), value.len() as c_int, None)
+                            }
+                            Param::Null => sqlite3_bind_null(stmt, index),
+                        }
+                    };
+                    if rc != SQLITE_OK {
+                        return Err(self.error());
+                    }
+                }
+                let mut rows = Vec::new();
+                loop {
+                    match unsafe { sqlite3_step(stmt) } {
+                        SQLITE_ROW => rows.push(row(&Row(stmt))),
+                        SQLITE_DONE => return Ok(rows),
+                        _ => return Err(self.error()),
+                    }
+                }
+            })();
+            unsafe { sqlite3_finalize(stmt) };
+            result
+        }
+    }
+
+    // Books, the id counter and undelivered events in a SQLite file. The
+    // in-memory storage stays the working copy; this is what it's rebuilt
+    // from on startup.
+    pub struct Db {
+        conn: Mutex<Connection>,
+    }
+
+    impl Db {
+        pub fn open(path: &str) -> Result<Self, String> {
+            let filename = CString::new(path).map_err(|e| e.to_string())?;
+            let mut db = ptr::null_mut();
+            let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_FULLMUTEX;
+            let rc = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) };
+            let conn = Connection(db);
+            if rc != SQLITE_OK {
+                return Err(format!("failed to open {}: {}", path, conn.error()));
+            }
+            unsafe { sqlite3_busy_timeout(db, 5000) };
+            conn.batch(PRAGMAS).map_err(|e| format!("failed to set up {}: {}", path, e))?;
+            conn.migrate().map_err(|e| format!("failed to migrate {}: {}", path, e))?;
+            Ok(Db { conn: Mutex::new(conn) })
+        }
+
+        pub fn load(&self) -> Result<Snapshot, String> {
+            let conn = self.conn.lock().unwrap();
+            let books = conn.query(
+                "SELECT id, title, author, isbn, NULL, author_id, tags, uid, nft, anchor, edition FROM books
+                 UNION ALL SELECT id, title, author, isbn, deleted_at, author_id, tags, uid, nft, anchor, edition FROM deleted_books
+                 ORDER BY id",
+                &[],
+                |row| Book {
+                    id: row.int(0) as u64,
+                    title: row.text(1).unwrap_or_default(),
+                    author: row.text(2).unwrap_or_default(),
+                    isbn: row.text(3),
+                    author_id: row.text(5).and_then(|id| id.parse().ok()),
+                    tags: row.text(6).and_then(|tags| serde_json::from_str(&tags).ok()).unwrap_or_default(),
+                    edition: row.text(10).and_then(|edition| serde_json::from_str(&edition).ok()).unwrap_or_default(),
+                    uid: row.text(7),
+                    nft: row.text(8).and_then(|nft| serde_json::from_str(&nft).ok()),
+                    anchor: row.text(9).and_then(|anchor| serde_json::from_str(&anchor).ok()),
+                    deleted_at: row.text(4).and_then(|at| at.parse().ok()),
+                },
+            )?;
+            let counter = |name: &str| -> Result<u64, String> {
+                let values = conn.query("SELECT value FROM counters WHERE name = ?1", &[Param::Text(name)], |row| {
+                    row.int(0) as u64
+                })?;
+                Ok(values.first().copied().unwrap_or(1))
+            };
+            let next_id = counter("next_book_id")?;
+            let next_author_id = counter("next_author_id")?;
+            let authors = conn.query("SELECT id, name FROM authors ORDER BY id", &[], |row| Author {
+                id: row.int(0) as u64,
+                name: row.text(1).unwrap_or_default(),
+            })?;
+            let queued = |table: &str| {
+                conn.query(
+                    &format!("SELECT id, topic, type, version, source, occurred_at_ms, key, data FROM {} ORDER BY seq", table),
+                    &[],
+                    |row| {
+                        let topic = row.text(1).unwrap_or_default();
+                        Event {
+                            id: row.text(0).unwrap_or_default(),
+                            kind: row.text(2).unwrap_or_default(),
+                            version: row.int(3) as u32,
+                            source: row.text(4).unwrap_or_default(),
+                            occurred_at_ms: row.int(5) as u64,
+                            key: row.text(6).unwrap_or_default(),
+                            data: serde_json::from_str(&row.text(7).unwrap_or_default()).unwrap_or_default(),
+                            topic: Topic::from_name(&topic).unwrap_or(Topic::Books),
+                        }
+                    },
+                )
+            };
+            let (events, hooks) = (queued("outbox")?, queued("webhook_outbox")?);
+            Ok(Snapshot {
+                books,
+                next_id,
+                authors,
+                next_author_id,
+                events,
+                hooks,
+            })
+        }
+
+        // All of an update's changes land in one transaction, so a book and
+        // the event announcing it are never written without each other.
+        pub fn commit(&self, writes: &[Write], next_id: u64) -> Result<(), String> {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("BEGIN IMMEDIATE", &[])?;
+            let result = (|| {
+                for write in writes {
+                    match write {
+                        Write::Created(book) | Write::Updated(book) | Write::Restored(book) => {
+                            conn.execute("DELETE FROM deleted_books WHERE id = ?1", &[Param::Int(book.id as i64)])?;
+                            let tags = serde_json::to_string(&book.tags).map_err(|e| e.to_string())?;
+                            let edition = serde_json::to_string(&book.edition).map_err(|e| e.to_string())?;
+                            let nft = book.nft.as_ref().map(serde_json::to_string).transpose().map_err(|e| e.to_string())?;
+                            let anchor =
+                                book.anchor.as_ref().map(serde_json::to_string).transpose().map_err(|e| e.to_string())?;
+                            conn.execute(
+                                "INSERT INTO books (id, title, author, isbn, author_id, tags, uid, nft, anchor, edition)
+                                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                                 ON CONFLICT (id) DO UPDATE SET title = ?2, author = ?3, isbn = ?4, author_id = ?5, tags = ?6,
+                                     uid = ?7, nft = ?8, anchor = ?9, edition = ?10",
+                                &[
+                                    Param::Int(book.id as i64),
+                                    Param::Text(&book.title),
+                                    Param::Text(&book.author),
+                                    book.isbn.as_deref().map_or(Param::Null, Param::Text),
+                                    book.author_id.map_or(Param::Null, |id| Param::Int(id as i64)),
+                                    Param::Text(&tags),
+                                    book.uid.as_deref().map_or(Param::Null, Param::Text),
+                                    nft.as_deref().map_or(Param::Null, Param::Text),
+                                    anchor.as_deref().map_or(Param::Null, Param::Text),
+                                    Param::Text(&edition),
+                                ],
+                            )?
+                        }
+                        Write::Trashed(book) => {
+                            conn.execute("DELETE FROM books WHERE id = ?1", &[Param::Int(book.id as i64)])?;
+                            let tags = serde_json::to_string(&book.tags).map_err(|e| e.to_string())?;
+                            let edition = serde_json::to_string(&book.edition).map_err(|e| e.to_string())?;
+                            let nft = book.nft.as_ref().map(serde_json::to_string).transpose().map_err(|e| e.to_string())?;
+                            let anchor =
+                                book.anchor.as_ref().map(serde_json::to_string).transpose().map_err(|e| e.to_string())?;
+                            conn.execute(
+                                "INSERT OR REPLACE INTO deleted_books (id, title, author, isbn, deleted_at, author_id, tags, uid, nft, anchor, edition)
+                                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                                &[
+                                    Param::Int(book.id as i64),
+                                    Param::Text(&book.title),
+                                    Param::Text(&book.author),
+                                    book.isbn.as_deref().map_or(Param::Null, Param::Text),
+                                    Param::Int(book.deleted_at.unwrap_or_default() as i64),
+                                    book.author_id.map_or(Param::Null, |id| Param::Int(id as i64)),
+                                    Param::Text(&tags),
+                                    book.uid.as_deref().map_or(Param::Null, Param::Text),
+                                    nft.as_deref().map_or(Param::Null, Param::Text),
+                                    anchor.as_deref().map_or(Param::Null, Param::Text),
+                                    Param::Text(&edition),
+                                ],
+                            )?
+                        }
+                        Write::AuthorCreated(author) => {
+                            conn.execute(
+                                "INSERT OR REPLACE INTO authors (id, name) VALUES (?1, ?2)",
+                                &[Param::Int(author.id as i64), Param::Text(&author.name)],
+                            )?;
+                            // Ids aren't handed out again once the author is deleted.
+                            conn.execute(
+                                "INSERT INTO counters (name, value) VALUES ('next_author_id', ?1)
+                                 ON CONFLICT (name) DO UPDATE SET value = max(value, ?1)",
+                                &[Param::Int(author.id as i64 + 1)],
+                            )?
+                        }
+                        Write::AuthorDeleted(id) => {
+                            conn.execute("DELETE FROM authors WHERE id = ?1", &[Param::Int(*id as i64)])?
+                        }
+                        Write::Event(event) | Write::Hook(event) => {
+                            let table = match write {
+                                Write::Event(_) => "outbox",
+                                _ => "webhook_outbox",
+                            };
+                            let data = event.data.to_string();
+                            conn.execute(
+                                &format!(
+                                    "INSERT OR IGNORE INTO {} (id, topic, type, version, source, occurred_at_ms, key, data)
+                                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                                    table
+                                ),
+                                &[
+                                    Param::Text(&event.id),
+                                    Param::Text(event.topic.name()),
+                                    Param::Text(&event.kind),
+                                    Param::Int(event.version as i64),
+                                    Param::Text(&event.source),
+                                    Param::Int(event.occurred_at_ms as i64),
+                                    Param::Text(&event.key),
+                                    Param::Text(&data),
+                                ],
+                            )?
+                        }
+                        Write::Delivered(id) => conn.execute(
+                            "DELETE FROM outbox WHERE seq <= (SELECT seq FROM outbox WHERE id = ?1)",
+                            &[Param::Text(id)],
+                        )?,
+                        Write::Hooked(id) => {
+                            conn.execute("DELETE FROM webhook_outbox WHERE id = ?1", &[Param::Text(id)])?
+                        }
+                    }
+                }
+                conn.execute(
+                    "INSERT INTO counters (name, value) VALUES ('next_book_id', ?1)
+                     ON CONFLICT (name) DO UPDATE SET value = ?1",
+                    &[Param::Int(next_id as i64)],
+                )
+            })();
+            match result {
+                Ok(()) => conn.execute("COMMIT", &[]),
+                Err(e) => {
+                    let _ = conn.execute("ROLLBACK", &[]);
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn open_raw(path: &str) -> Connection {
+            let filename = CString::new(path).unwrap();
+            let mut db = ptr::null_mut();
+            let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+            assert_eq!(unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) }, SQLITE_OK);
+            Connection(db)
+        }
+
+        fn user_version(path: &str) -> i64 {
+            open_raw(path).query("PRAGMA user_version", &[], |row| row.int(0)).unwrap()[0]
+        }
+
+        // A file as it was before the version was kept: tags had been added,
+        // the later columns not yet.
+        #[test]
+        fn untracked_files_are_migrated() {
+            let path = std::env::temp_dir().join(format!("book-api-migrate-{}.db", std::process::id()));
+            let path = path.to_str().unwrap();
+            let _ = std::fs::remove_file(path);
+            open_raw(path)
+                .batch(
+                    "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT NOT NULL, author TEXT NOT NULL, isbn TEXT);
+                     CREATE TABLE deleted_books (id INTEGER PRIMARY KEY, title TEXT NOT NULL, author TEXT NOT NULL, isbn TEXT, deleted_at INTEGER NOT NULL);
+                     CREATE TABLE counters (name TEXT PRIMARY KEY, value INTEGER NOT NULL);
+                     ALTER TABLE books ADD COLUMN author_id INTEGER;
+                     ALTER TABLE books ADD COLUMN tags TEXT;
+                     INSERT INTO books (id, title, author, isbn, tags) VALUES (4, 'Dune', 'Frank Herbert', NULL, '[\"sf\"]');
+                     INSERT INTO counters (name, value) VALUES ('next_book_id', 5)",
+                )
+                .unwrap();
+
+            let snapshot = Db::open(path).unwrap().load().unwrap();
+            assert_eq!(user_version(path), MIGRATIONS.len() as i64);
+            assert_eq!(snapshot.next_id, 5);
+            let book = &snapshot.books[0];
+            assert_eq!((book.id, book.title.as_str(), book.tags.clone()), (4, "Dune", vec!["sf".to_string()]));
+            assert_eq!((book.uid.as_ref(), book.nft.as_ref()), (None, None));
+            Db::open(path).unwrap();
+
+            open_raw(path).execute("PRAGMA user_version = 99", &[]).unwrap();
+            let refused = Db::open(path).err().unwrap();
+            assert!(refused.contains("schema version 99"), "{}", refused);
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+            }
+        }
+        // A webhook event is stored with the change it announces and stays
+        // until it's been delivered, apart from the broker's outbox.
+        #[test]
+        fn webhook_events_wait_in_the_file_until_delivered() {
+            let path = std::env::temp_dir().join(format!("book-api-hooks-{}.db", std::process::id()));
+            let path = path.to_str().unwrap();
+            let _ = std::fs::remove_file(path);
+            let book = Book {
+                id: 1,
+                title: "Dune".to_string(),
+                author: "Frank Herbert".to_string(),
+                isbn: None,
+                author_id: None,
+                tags: Vec::new(),
+                edition: Default::default(),
+                uid: None,
+                nft: None,
+                anchor: None,
+                deleted_at: None,
+            };
+            let event = Event::new("test", 1, Topic::Books, "book.created", 1, &book);
+            let db = Db::open(path).unwrap();
+            db.commit(&[Write::Created(book), Write::Hook(event.clone())], 2).unwrap();
+
+            let snapshot = Db::open(path).unwrap().load().unwrap();
+            assert_eq!(snapshot.hooks.iter().map(|hook| hook.id.as_str()).collect::<Vec<_>>(), [event.id.as_str()]);
+            assert_eq!(snapshot.hooks[0].data["title"], "Dune");
+            assert!(snapshot.events.is_empty());
+
+            db.commit(&[Write::Hooked(event.id.clone())], 2).unwrap();
+            assert!(Db::open(path).unwrap().load().unwrap().hooks.is_empty());
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use hyper::{Request, Response};
+use std::{future::Future, pin::Pin, sync::Arc};
+use tracing::Instrument;
+
+use crate::{
+    accesslog, audit, auth,
+    body::{self, Body},
+    capture, compress, contract, cors, handle_request, i18n, inspect, jsonapi, logging, maintenance, negotiate,
+    not_found,
+    proxy::ClientIp,
+    ratelimit, request_id, tenants, timeout, SharedState,
+};
+
+// A middleware takes the request before the next one does and can answer
+// it itself, pass it on with `next.run`, or change what comes back.
+type Handle = fn(Request<Body>, SharedState, Next) -> ResponseFuture;
+
+#[derive(Debug)]
+struct Layer {
+    name: &'static str,
+    handle: Handle,
+}
+
+// Every middleware, outermost first, which is also the order a stack runs
+// them in by default. Adding one is adding it here.
+static LAYERS: [Layer; 11] = [
+    Layer { name: "compress", handle: |req, state, next| Box::pin(compress::handle(req, state, next)) },
+    Layer { name: "inspect", handle: |req, state, next| Box::pin(inspect::track(req, state, next)) },
+    Layer { name: "cors", handle: |req, state, next| Box::pin(cors::handle(req, state, next)) },
+    Layer { name: "ratelimit", handle: |req, state, next| Box::pin(ratelimit::handle(req, state, next)) },
+    Layer { name: "maintenance", handle: |req, state, next| Box::pin(maintenance::handle(req, state, next)) },
+    Layer { name: "capture", handle: |req, state, next| Box::pin(capture::handle(req, state, next)) },
+    Layer { name: "timeout", handle: |req, state, next| Box::pin(timeout::handle(req, state, next)) },
+    Layer { name: "negotiate", handle: |req, state, next| Box::pin(negotiate::handle(req, state, next)) },
+    Layer { name: "jsonapi", handle: |req, state, next| Box::pin(jsonapi::handle(req, state, next)) },
+    Layer { name: "i18n", handle: |req, state, next| Box::pin(i18n::handle(req, state, next)) },
+    Layer { name: "contract", handle: |req, state, next| Box::pin(contract::handle(req, state, next)) },
+];
+
+impl Layer {
+    fn parse(name: &str) -> Result<&'static Self, String> {
+        LAYERS.iter().find(|layer| layer.name == name).ok_or_else(|| {
+            let names: Vec<&str> = LAYERS.iter().map(|layer| layer.name).collect();
+            format!("unknown middleware {:?}, expected one of {}", name, names.join(", "))
+        })
+    }
+}
+
+// Which part of the API a listener exposes. Operator endpoints are the
+// /admin and /debug trees plus /metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routes {
+    All,
+    Public,
+    Admin,
+}
+
+impl Routes {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "all" => Ok(Routes::All),
+            "public" => Ok(Routes::Public),
+            "admin" => Ok(Routes::Admin),
+            _ => Err(format!("unknown routes {:?}, expected all, public or admin", name)),
+        }
+    }
+
+    fn allows(self, path: &str) -> bool {
+        let admin = path == "/metrics" || path.starts_with("/admin/") || path.starts_with("/debug/");
+        match self {
+            Routes::All => true,
+            Routes::Public => !admin,
+            Routes::Admin => admin,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stack {
+    layers: Arc<[&'static Layer]>,
+    routes: Routes,
+    require_admin: bool,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack {
+            layers: LAYERS.iter().collect(),
+            routes: Routes::All,
+            require_admin: false,
+        }
+    }
+}
+
+impl Stack {
+    // Option keys shared by listeners and virtual hosts:
+    // `middleware=a,b`, `routes=all|public|admin` and `auth=none|admin`.
+    // Returns false for keys that aren't stack options.
+    pub fn set_option(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "middleware" => {
+                let layers = value
+                    .split(',')
+                    .filter(|name| !name.is_empty())
+                    .map(Layer::parse)
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.layers = layers.into();
+            }
+            "routes" => self.routes = Routes::parse(value)?,
+            "auth" => {
+                self.require_admin = match value {
+                    "none" => false,
+                    "admin" => true,
+                    _ => return Err(format!("unknown auth {:?}, expected none or admin", value)),
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // A request whose Host matches a configured virtual host runs that host's
+    // stack instead of the listener's.
+    pub fn call(&self, mut req: Request<Body>, state: SharedState) -> ResponseFuture {
+        if let Some(ip) = state.trusted_proxies.client_ip(&req) {
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+        let id = request_id::assign(&mut req);
+        let stack = state.hosts.select(&req).unwrap_or(self).clone();
+        let span = logging::span(&req, &id);
+        let tenant = state.tenants.select(&mut req).unwrap_or_else(|refused| {
+            req.extensions_mut().insert(refused);
+            None
+        });
+        let prefix = req.extensions().get::<tenants::Prefix>().cloned();
+        let actor = audit::Actor::of(&req, &state);
+        let access = accesslog::Pending::of(&req, &id, &state);
+        let response = request_id::stamp(id, logging::finish(Next { stack, index: 0 }.run(req, state)));
+        let response = async move {
+            let mut response = response.await?;
+            if let Some(prefix) = &prefix {
+                tenants::relocate(prefix, &mut response);
+            }
+            Ok(response)
+        };
+        let response = accesslog::logged(access, response);
+        Box::pin(tenants::within(tenant, audit::acting(actor, response)).instrument(span))
+    }
+}
+
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, body::Error>> + Send>>;
+
+// The remaining layers of a stack, handed to each middleware so it can pass
+// the request on.
+pub struct Next {
+    stack: Stack,
+    index: usize,
+}
+
+impl Next {
+    pub fn run(self, req: Request<Body>, state: SharedState) -> ResponseFuture {
+        let next = Next {
+            stack: self.stack.clone(),
+            index: self.index + 1,
+        };
+        match self.stack.layers.get(self.index) {
+            Some(layer) => (layer.handle)(req, state, next),
+            None if req.extensions().get::<tenants::Refused>().is_some() => {
+                let response = req.extensions().get::<tenants::Refused>().map_or_else(not_found, tenants::Refused::respond);
+                Box::pin(async { Ok(response) })
+            }
+            None if !self.stack.routes.allows(req.uri().path()) => Box::pin(async { Ok(not_found()) }),
+            None if self.stack.require_admin => match auth::reject_non_admin(req.headers(), &state) {
+                Some(response) => Box::pin(async { Ok(response) }),
+                None => Box::pin(handle_request(req, state)),
+            },
+            None => Box::pin(handle_request(req, state)),
+        }
+    }
+}
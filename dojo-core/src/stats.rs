@@ -0,0 +1,107 @@
+use books_model::Book;
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{body::Body, error::ApiError, extract::State, health, json_response, store::BookStore, SharedState};
+
+// What the catalog holds, counted by the backend: books, those with an
+// ISBN and each author's.
+#[derive(Debug, Default)]
+pub struct Tally {
+    pub books: usize,
+    pub with_isbn: usize,
+    pub by_author: BTreeMap<String, usize>,
+}
+
+impl Tally {
+    pub fn of<'a>(books: impl IntoIterator<Item = &'a Book>) -> Self {
+        let mut tally = Tally::default();
+        for book in books {
+            tally.books += 1;
+            tally.with_isbn += usize::from(book.isbn.is_some());
+            *tally.by_author.entry(book.author.clone()).or_default() += 1;
+        }
+        tally
+    }
+}
+
+// How many books were created on each UTC day, by days since 1970-01-01.
+// Books don't record when they were made, so these only count what this
+// instance created since it started.
+#[derive(Debug, Default)]
+pub struct Days(BTreeMap<u64, u64>);
+
+impl Days {
+    pub fn count(&mut self, created: usize) {
+        let today = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) / 86_400;
+        *self.0.entry(today).or_default() += created as u64;
+    }
+
+    fn dated(&self) -> BTreeMap<String, u64> {
+        self.0.iter().map(|(day, count)| (date(*day), *count)).collect()
+    }
+}
+
+// YYYY-MM-DD for a number of days since 1970-01-01.
+fn date(day: u64) -> String {
+    // The inverse of lending's day_number, counting years from March.
+    let days = day as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    books: usize,
+    // Rounded to a tenth of a percent; 0 for an empty catalog.
+    isbn_percent: f64,
+    books_per_author: BTreeMap<String, usize>,
+    created_per_day: BTreeMap<String, u64>,
+    storage: health::Check,
+}
+
+// GET /admin/stats: aggregates over the catalog. The in-memory catalog
+// keeps its counts up to date with every write and Postgres counts in one
+// query, so neither reads every book; Redis has to.
+pub async fn stats(State(state): State<SharedState>) -> Result<Response<Body>, ApiError> {
+    let tally = state.tally().await?;
+    let created = match state.postgres.is_some() || state.redis.is_some() {
+        true => state.created.lock().unwrap().dated(),
+        false => state.read_storage("created", String::new, |storage| storage.created.dated()).await?,
+    };
+    let isbn_percent = match tally.books {
+        0 => 0.0,
+        books => (tally.with_isbn as f64 * 1000.0 / books as f64).round() / 10.0,
+    };
+    let stats = Stats {
+        books: tally.books,
+        isbn_percent,
+        books_per_author: tally.by_author,
+        created_per_day: created,
+        storage: health::storage(&state).await,
+    };
+    Ok(json_response(StatusCode::OK, &stats)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::date;
+
+    #[test]
+    fn days_are_dated() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(20_740), "2026-10-14");
+    }
+}
@@ -0,0 +1,678 @@
+use books_model::{Author, Book, CreateBookRequest};
+use hyper::Response;
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::Arc,
+};
+
+use crate::{
+    body::Body,
+    conditional::now_ms,
+    eventlog,
+    extract::{FromRequest, RequestContext},
+    isbn, redis, search, sqlite, AppState, StorageError,
+};
+
+// Types that show up in `BookStore` signatures, so a store written in
+// another crate can name them.
+pub use crate::{events::Topic, search::Hit, stats::Tally};
+
+pub(crate) enum Backend {
+    Memory,
+    Sqlite(sqlite::Db),
+    Redis(redis::Redis),
+    Events(eventlog::EventLog),
+}
+
+// DOJO_STORAGE picks where the catalog is kept: `memory`, the default,
+// `sqlite:<path>` to keep it in a SQLite file that's created on first run
+// (plain `sqlite` is `sqlite:books.db`), `events:<path>` to keep it as a
+// log of events (plain `events` is `events:events.jsonl`), or
+// `redis://host:port` to share it through Redis.
+pub(crate) fn backend_from_env() -> Result<Backend, String> {
+    let Ok(spec) = std::env::var("DOJO_STORAGE") else {
+        return Ok(Backend::Memory);
+    };
+    if spec == "memory" {
+        return Ok(Backend::Memory);
+    }
+    if spec == "sqlite" {
+        return sqlite::Db::open("books.db").map(Backend::Sqlite);
+    }
+    if spec == "events" {
+        return eventlog::EventLog::open("events.jsonl").map(Backend::Events);
+    }
+    if spec.starts_with("redis://") {
+        return redis::Redis::from_url(&spec).map(Backend::Redis);
+    }
+    if let Some(path) = spec.strip_prefix("events:").filter(|path| !path.is_empty()) {
+        return eventlog::EventLog::open(path).map(Backend::Events);
+    }
+    match spec.strip_prefix("sqlite:") {
+        Some(path) if !path.is_empty() => sqlite::Db::open(path).map(Backend::Sqlite),
+        _ => Err(format!(
+            "DOJO_STORAGE must be memory, sqlite, sqlite:<path>, events, events:<path> or redis://host:port, got {:?}",
+            spec
+        )),
+    }
+}
+
+// What the catalog handlers need from wherever books are kept. `delete`
+// returns None for an unknown id. Deleting moves a book to the trash,
+// where no read but `deleted` sees it until it's restored.
+pub trait BookStore: Send + Sync + 'static {
+    fn get(&self, id: u64) -> impl Future<Output = Result<Option<Book>, StorageError>> + Send;
+
+    // Every book, in id order. Listings are built on this, so the same
+    // catalog always lists the same way.
+    fn list(&self) -> impl Future<Output = Result<Vec<Book>, StorageError>> + Send;
+
+    // Up to `limit` books with ids above `after`, in id order.
+    fn list_after(&self, after: u64, limit: usize) -> impl Future<Output = Result<Vec<Book>, StorageError>> + Send;
+
+    // How many books there are, without reading them where the backend can.
+    fn count(&self) -> impl Future<Output = Result<usize, StorageError>> + Send {
+        async { self.list().await.map(|books| books.len()) }
+    }
+
+    // The catalog's books, those with an ISBN and each author's, counted
+    // without reading every book where the backend can.
+    fn tally(&self) -> impl Future<Output = Result<Tally, StorageError>> + Send {
+        async { self.list().await.map(|books| Tally::of(&books)) }
+    }
+
+    // When the book, or with None the catalog as a whole, last changed, in
+    // milliseconds since the epoch. None for an unknown book.
+    fn modified(&self, id: Option<u64>) -> impl Future<Output = Result<Option<u64>, StorageError>> + Send;
+
+    fn insert(&self, book: CreateBookRequest) -> impl Future<Output = Result<Book, StorageError>> + Send;
+
+    // Stores every book or, if any of them can't be, none.
+    fn insert_many(&self, books: Vec<CreateBookRequest>) -> impl Future<Output = Result<Vec<Book>, StorageError>> + Send;
+
+    fn delete(&self, id: u64) -> impl Future<Output = Result<Option<Book>, StorageError>> + Send;
+
+    // The books in the trash, in id order, with `deleted_at` set.
+    fn deleted(&self) -> impl Future<Output = Result<Vec<Book>, StorageError>> + Send;
+
+    // Puts the book back as it was, under the same id. None when it isn't
+    // in the trash.
+    fn restore(&self, id: u64) -> impl Future<Output = Result<Option<Book>, StorageError>> + Send;
+
+    // Deletes every book `matches` picks and says how many went. Unlike a
+    // batch insert this isn't all-or-nothing where the backend deletes
+    // book by book.
+    fn delete_where<F: Fn(&Book) -> bool + Send + Sync>(
+        &self,
+        matches: F,
+    ) -> impl Future<Output = Result<usize, StorageError>> + Send {
+        async move {
+            let mut deleted = 0;
+            for book in self.list().await?.into_iter().filter(|book| matches(book)) {
+                if self.delete(book.id).await?.is_some() {
+                    deleted += 1;
+                }
+            }
+            Ok(deleted)
+        }
+    }
+
+    // Replaces the book with what `change` makes of it, with nothing else
+    // writing it in between. `change` may be called again if the book
+    // changed meanwhile, and can refuse, which leaves the book as it was.
+    fn modify<E: Send, F: FnMut(&Book) -> Result<Book, E> + Send>(
+        &self,
+        id: u64,
+        change: F,
+    ) -> impl Future<Output = Result<Option<Result<Book, E>>, StorageError>> + Send;
+
+    // Deletes the book if `check` passes, again with nothing writing it in
+    // between. A refusal leaves it in place.
+    fn delete_if<E: Send, F: FnMut(&Book) -> Result<(), E> + Send>(
+        &self,
+        id: u64,
+        check: F,
+    ) -> impl Future<Output = Result<Option<Result<Book, E>>, StorageError>> + Send;
+
+    // The best `limit` matches for `query`. Backends without an index of
+    // their own build one from the whole catalog each time.
+    fn search(&self, query: String, limit: usize) -> impl Future<Output = Result<Vec<Hit>, StorageError>> + Send {
+        async move {
+            let books: BTreeMap<u64, Book> = self.list().await?.into_iter().map(|book| (book.id, book)).collect();
+            let index = search::Index::build(books.values());
+            Ok(index.hits(&query, limit, |id| books.get(&id).cloned()))
+        }
+    }
+
+    // Every tag in use with how many books have it, by tag.
+    fn tags(&self) -> impl Future<Output = Result<Vec<(String, usize)>, StorageError>> + Send {
+        async move { Ok(search::Index::build(&self.list().await?).tag_counts()) }
+    }
+
+    // The id of the book with the uid, in the catalog or the trash.
+    fn resolve(&self, uid: String) -> impl Future<Output = Result<Option<u64>, StorageError>> + Send {
+        async move {
+            let mut books = self.list().await?;
+            books.extend(self.deleted().await?);
+            Ok(books.into_iter().find(|book| book.uid.as_ref() == Some(&uid)).map(|book| book.id))
+        }
+    }
+
+    // The books with the tag, in id order.
+    fn tagged(&self, tag: String) -> impl Future<Output = Result<Vec<Book>, StorageError>> + Send {
+        async move {
+            let mut books = self.list().await?;
+            books.retain(|book| book.tags.contains(&tag));
+            Ok(books)
+        }
+    }
+
+    // Which book other than `except` has each of the ISBNs, as a write would
+    // find it, without writing anything.
+    fn isbn_holders(
+        &self,
+        isbns: Vec<String>,
+        except: u64,
+    ) -> impl Future<Output = Result<Vec<Option<u64>>, StorageError>> + Send {
+        async move { Ok(holders(&self.list().await?, &isbns, except)) }
+    }
+
+    // The catalog, without the trash, as it was at `at` in milliseconds
+    // since the epoch, in id order, or why that can't be told.
+    fn as_of(&self, _at: u64) -> impl Future<Output = Result<Result<Vec<Book>, String>, StorageError>> + Send {
+        async { Ok(Err("this backend keeps no history".to_string())) }
+    }
+}
+
+fn holders(books: &[Book], isbns: &[String], except: u64) -> Vec<Option<u64>> {
+    let by_isbn: HashMap<String, u64> = books
+        .iter()
+        .filter(|book| book.id != except)
+        .filter_map(|book| Some((isbn::key(book.isbn.as_deref()?), book.id)))
+        .collect();
+    isbns.iter().map(|isbn| by_isbn.get(&isbn::key(isbn)).copied()).collect()
+}
+
+// The authors books are filed under by `author_id`, kept beside the
+// catalog in the same backend. `delete_author` refuses, with how many
+// there are, while any book names the author, including books in the
+// trash.
+pub trait AuthorStore: Send + Sync + 'static {
+    fn author(&self, id: u64) -> impl Future<Output = Result<Option<Author>, StorageError>> + Send;
+
+    // In id order.
+    fn authors(&self) -> impl Future<Output = Result<Vec<Author>, StorageError>> + Send;
+
+    fn insert_author(&self, name: String) -> impl Future<Output = Result<Author, StorageError>> + Send;
+
+    fn delete_author(&self, id: u64) -> impl Future<Output = Result<Option<Result<Author, usize>>, StorageError>> + Send;
+}
+
+// The in-memory catalog, or Postgres or Redis when configured. In memory
+// books share a lock with loans and the event outbox, so a deleted book's
+// loans and its event go in the same update. Single books and first pages
+// read from Postgres or Redis go through the cache, which their writes
+// then clear.
+impl BookStore for AppState {
+    async fn get(&self, id: u64) -> Result<Option<Book>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.cache.book(id, self.with_database("get", || format!("id={}", id), postgres.get(id))).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.cache.book(id, self.with_database("get", || format!("id={}", id), redis.get(id))).await;
+        }
+        self.read_storage("get", || format!("id={}", id), |storage| storage.books.get(&id).cloned())
+            .await
+    }
+
+    async fn list(&self) -> Result<Vec<Book>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("list", String::new, postgres.list()).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("list", String::new, redis.list()).await;
+        }
+        self.read_storage("list", String::new, |storage| storage.books.values().cloned().collect())
+            .await
+    }
+
+    async fn count(&self) -> Result<usize, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("count", String::new, postgres.count()).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("count", String::new, redis.count()).await;
+        }
+        self.read_storage("count", String::new, |storage| storage.books.len()).await
+    }
+
+    async fn tally(&self) -> Result<Tally, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("tally", String::new, postgres.tally()).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("tally", String::new, redis.tally()).await;
+        }
+        self.read_storage("tally", String::new, |storage| storage.index.tally()).await
+    }
+
+    async fn list_after(&self, after: u64, limit: usize) -> Result<Vec<Book>, StorageError> {
+        let params = || format!("after={} limit={}", after, limit);
+        if let Some(postgres) = &self.postgres {
+            let page = self.with_database("list_after", params, postgres.list_after(after, limit));
+            return match after {
+                0 => self.cache.first_page(limit, page).await,
+                _ => page.await,
+            };
+        }
+        if let Some(redis) = &self.redis {
+            let page = self.with_database("list_after", params, redis.list_after(after, limit));
+            return match after {
+                0 => self.cache.first_page(limit, page).await,
+                _ => page.await,
+            };
+        }
+        self.read_storage("list_after", params, |storage| {
+            let from = after.saturating_add(1);
+            storage.books.range(from..).take(limit).map(|(_, book)| book.clone()).collect()
+        })
+        .await
+    }
+
+    async fn modified(&self, id: Option<u64>) -> Result<Option<u64>, StorageError> {
+        let params = || id.map_or_else(String::new, |id| format!("id={}", id));
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("modified", params, postgres.modified(id)).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("modified", params, redis.modified(id)).await;
+        }
+        self.read_storage("modified", params, |storage| match id {
+            Some(id) => storage.books.contains_key(&id).then(|| storage.modified.book(id)),
+            None => Some(storage.modified.catalog()),
+        })
+        .await
+    }
+
+    async fn insert(&self, mut book: CreateBookRequest) -> Result<Book, StorageError> {
+        book.uid = self.ids.mint();
+        let params = format!("title={:?}", book.title);
+        if let Some(postgres) = &self.postgres {
+            let book = self.cache.invalidating(None, self.with_database("insert", || params, postgres.insert(book))).await?;
+            self.created.lock().unwrap().count(1);
+            self.notify("book.created", &book);
+            self.audit.record("book.created", book.id, None, Some(book.clone()));
+            return Ok(book);
+        }
+        if let Some(redis) = &self.redis {
+            let book = self.cache.invalidating(None, self.with_database("insert", || params, redis.insert(book))).await?;
+            self.created.lock().unwrap().count(1);
+            self.announce("book.created", &book, false).await;
+            self.audit.record("book.created", book.id, None, Some(book.clone()));
+            return Ok(book);
+        }
+        self.with_storage("insert", || params, |storage| {
+            storage.check_isbn(book.isbn.as_deref(), 0)?;
+            Ok(storage.insert_book(book))
+        })
+        .await?
+    }
+
+    async fn insert_many(&self, mut books: Vec<CreateBookRequest>) -> Result<Vec<Book>, StorageError> {
+        for book in &mut books {
+            book.uid = self.ids.mint();
+        }
+        let params = format!("count={}", books.len());
+        if let Some(postgres) = &self.postgres {
+            let books = self.cache.invalidating(None, self.with_database("insert_many", || params, postgres.insert_many(books))).await?;
+            self.created.lock().unwrap().count(books.len());
+            for book in &books {
+                self.notify("book.created", book);
+            }
+            self.audit_created(&books);
+            return Ok(books);
+        }
+        if let Some(redis) = &self.redis {
+            let books = self.cache.invalidating(None, self.with_database("insert_many", || params, redis.insert_many(books))).await?;
+            self.created.lock().unwrap().count(books.len());
+            for book in &books {
+                self.announce("book.created", book, false).await;
+            }
+            self.audit_created(&books);
+            return Ok(books);
+        }
+        // One update, so the disk or the log gets the whole batch in one
+        // write.
+        self.with_storage("insert_many", || params, |storage| {
+            for book in &books {
+                storage.check_isbn(book.isbn.as_deref(), 0)?;
+            }
+            Ok(books.into_iter().map(|book| storage.insert_book(book)).collect())
+        })
+        .await?
+    }
+
+    async fn delete(&self, id: u64) -> Result<Option<Book>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            let deleted = self.cache.invalidating(Some(id), self.with_database("delete", || format!("id={}", id), postgres.delete(id))).await?;
+            // Loans and reviews are still kept in memory.
+            if let Some(book) = &deleted {
+                self.notify("book.deleted", book);
+                self.audit_deleted(book);
+                self.with_storage("forget", || format!("id={}", id), |storage| storage.forget(id)).await?;
+            }
+            return Ok(deleted);
+        }
+        if let Some(redis) = &self.redis {
+            let deleted = self.cache.invalidating(Some(id), self.with_database("delete", || format!("id={}", id), redis.delete(id))).await?;
+            if let Some(book) = &deleted {
+                self.announce("book.deleted", book, true).await;
+                self.audit_deleted(book);
+            }
+            return Ok(deleted);
+        }
+        self.with_storage("delete", || format!("id={}", id), |storage| storage.remove_book(id, now_ms()))
+            .await
+    }
+
+    async fn deleted(&self) -> Result<Vec<Book>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("deleted", String::new, postgres.deleted()).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("deleted", String::new, redis.deleted()).await;
+        }
+        self.read_storage("deleted", String::new, |storage| storage.deleted.values().cloned().collect())
+            .await
+    }
+
+    async fn restore(&self, id: u64) -> Result<Option<Book>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            let restored = self.cache.invalidating(Some(id), self.with_database("restore", || format!("id={}", id), postgres.restore(id))).await?;
+            if let Some(book) = &restored {
+                self.notify("book.restored", book);
+                self.audit.record("book.restored", id, None, Some(book.clone()));
+            }
+            return Ok(restored);
+        }
+        if let Some(redis) = &self.redis {
+            let restored = self.cache.invalidating(Some(id), self.with_database("restore", || format!("id={}", id), redis.restore(id))).await?;
+            if let Some(book) = &restored {
+                self.announce("book.restored", book, false).await;
+                self.audit.record("book.restored", id, None, Some(book.clone()));
+            }
+            return Ok(restored);
+        }
+        self.with_storage("restore", || format!("id={}", id), |storage| {
+            if let Some(book) = storage.deleted.get(&id) {
+                storage.check_isbn(book.isbn.as_deref(), id)?;
+            }
+            Ok(storage.restore_book(id))
+        })
+        .await?
+    }
+
+    // In memory this is one update. Postgres and Redis delete book by book
+    // through `delete`, so each one's loans and event are handled as usual.
+    async fn delete_where<F: Fn(&Book) -> bool + Send + Sync>(&self, matches: F) -> Result<usize, StorageError> {
+        if self.postgres.is_some() || self.redis.is_some() {
+            let mut deleted = 0;
+            for book in self.list().await?.into_iter().filter(|book| matches(book)) {
+                if self.delete(book.id).await?.is_some() {
+                    deleted += 1;
+                }
+            }
+            return Ok(deleted);
+        }
+        self.with_storage("delete_where", String::new, |storage| {
+            let ids: Vec<u64> = storage.books.values().filter(|book| matches(book)).map(|book| book.id).collect();
+            let at = now_ms();
+            ids.into_iter().filter_map(|id| storage.remove_book(id, at)).count()
+        })
+        .await
+    }
+
+    async fn modify<E: Send, F: FnMut(&Book) -> Result<Book, E> + Send>(
+        &self,
+        id: u64,
+        mut change: F,
+    ) -> Result<Option<Result<Book, E>>, StorageError> {
+        // The version `change` saw last is the one that was replaced.
+        let mut before = None;
+        let seen = |book: &Book| {
+            before = Some(book.clone());
+            change(book)
+        };
+        if let Some(postgres) = &self.postgres {
+            let modified = self.cache.invalidating(Some(id), self.with_database("modify", || format!("id={}", id), postgres.modify(id, seen))).await?;
+            if let Some(Ok(book)) = &modified {
+                self.notify("book.updated", book);
+                self.audit.record("book.updated", id, before, Some(book.clone()));
+            }
+            return Ok(modified);
+        }
+        if let Some(redis) = &self.redis {
+            let modified = self.cache.invalidating(Some(id), self.with_database("modify", || format!("id={}", id), redis.modify(id, seen))).await?;
+            if let Some(Ok(book)) = &modified {
+                self.announce("book.updated", book, false).await;
+                self.audit.record("book.updated", id, before, Some(book.clone()));
+            }
+            return Ok(modified);
+        }
+        self.with_storage("modify", || format!("id={}", id), |storage| {
+            let Some(current) = storage.books.get(&id) else {
+                return Ok(None);
+            };
+            match change(current) {
+                Ok(book) => {
+                    storage.check_isbn(book.isbn.as_deref(), id)?;
+                    Ok(Some(Ok(storage.replace_book(book))))
+                }
+                Err(rejected) => Ok(Some(Err(rejected))),
+            }
+        })
+        .await?
+    }
+
+    async fn delete_if<E: Send, F: FnMut(&Book) -> Result<(), E> + Send>(
+        &self,
+        id: u64,
+        mut check: F,
+    ) -> Result<Option<Result<Book, E>>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            let deleted = self.cache.invalidating(Some(id), self.with_database("delete_if", || format!("id={}", id), postgres.delete_if(id, check))).await?;
+            if let Some(Ok(book)) = &deleted {
+                self.notify("book.deleted", book);
+                self.audit_deleted(book);
+                self.with_storage("forget", || format!("id={}", id), |storage| storage.forget(id)).await?;
+            }
+            return Ok(deleted);
+        }
+        if let Some(redis) = &self.redis {
+            let deleted = self.cache.invalidating(Some(id), self.with_database("delete_if", || format!("id={}", id), redis.delete_if(id, check))).await?;
+            if let Some(Ok(book)) = &deleted {
+                self.announce("book.deleted", book, true).await;
+                self.audit_deleted(book);
+            }
+            return Ok(deleted);
+        }
+        self.with_storage("delete_if", || format!("id={}", id), |storage| match check(storage.books.get(&id)?) {
+            Ok(()) => storage.remove_book(id, now_ms()).map(Ok),
+            Err(rejected) => Some(Err(rejected)),
+        })
+        .await
+    }
+
+    async fn search(&self, query: String, limit: usize) -> Result<Vec<Hit>, StorageError> {
+        let params = || format!("q={:?} limit={}", query, limit);
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("search", params, postgres.search(query.clone(), limit)).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("search", params, redis.search(query.clone(), limit)).await;
+        }
+        self.read_storage("search", params, |storage| {
+            storage.index.hits(&query, limit, |id| storage.books.get(&id).cloned())
+        })
+        .await
+    }
+
+    async fn tags(&self) -> Result<Vec<(String, usize)>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("tags", String::new, postgres.tags()).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("tags", String::new, redis.tags()).await;
+        }
+        self.read_storage("tags", String::new, |storage| storage.index.tag_counts()).await
+    }
+
+    async fn tagged(&self, tag: String) -> Result<Vec<Book>, StorageError> {
+        let params = || format!("tag={:?}", tag);
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("tagged", params, postgres.tagged(tag.clone())).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("tagged", params, redis.tagged(tag.clone())).await;
+        }
+        self.read_storage("tagged", params, |storage| {
+            storage.index.tagged(&tag).into_iter().filter_map(|id| storage.books.get(&id).cloned()).collect()
+        })
+        .await
+    }
+
+    async fn resolve(&self, uid: String) -> Result<Option<u64>, StorageError> {
+        let params = || format!("uid={}", uid);
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("resolve", params, postgres.resolve(uid.clone())).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("resolve", params, redis.resolve(uid.clone())).await;
+        }
+        self.read_storage("resolve", params, |storage| storage.resolve(&uid)).await
+    }
+
+    // In memory through the ISBN index the writes check.
+    async fn isbn_holders(&self, isbns: Vec<String>, except: u64) -> Result<Vec<Option<u64>>, StorageError> {
+        if self.postgres.is_some() || self.redis.is_some() {
+            return Ok(holders(&self.list().await?, &isbns, except));
+        }
+        self.read_storage("isbn_holders", || format!("count={}", isbns.len()), |storage| {
+            isbns.iter().map(|isbn| storage.index.isbn_holder(isbn, except)).collect()
+        })
+        .await
+    }
+
+    // Replays the event log up to `at` where the catalog is kept as one,
+    // and otherwise undoes the audit log's later changes, so only where it
+    // has them all. Changes merged from gossip peers never reach this instance's log, and
+    // a shard's only covers its own books.
+    async fn as_of(&self, at: u64) -> Result<Result<Vec<Book>, String>, StorageError> {
+        if self.postgres.is_some() || self.redis.is_some() || self.cluster.is_some() || self.shards.is_some() {
+            return Ok(Err(
+                "as_of isn't supported with DATABASE_URL, DOJO_STORAGE=redis, DOJO_CLUSTER_NODE or DOJO_SHARD_NODE yet".to_string(),
+            ));
+        }
+        let replayed = self
+            .read_storage("as_of", || format!("at={}", at), |storage| storage.history.as_ref().map(|history| history.as_of(at)))
+            .await?;
+        if let Some(books) = replayed {
+            return Ok(Ok(books?));
+        }
+        let books = self.list().await?;
+        Ok(self.audit().as_of(at, books))
+    }
+}
+
+impl AuthorStore for AppState {
+    async fn author(&self, id: u64) -> Result<Option<Author>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("author", || format!("id={}", id), postgres.author(id)).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("author", || format!("id={}", id), redis.author(id)).await;
+        }
+        self.read_storage("author", || format!("id={}", id), |storage| storage.authors.get(&id).cloned())
+            .await
+    }
+
+    async fn authors(&self) -> Result<Vec<Author>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("authors", String::new, postgres.authors()).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("authors", String::new, redis.authors()).await;
+        }
+        self.read_storage("authors", String::new, |storage| storage.authors.values().cloned().collect())
+            .await
+    }
+
+    async fn insert_author(&self, name: String) -> Result<Author, StorageError> {
+        let params = format!("name={:?}", name);
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("insert_author", || params, postgres.insert_author(name)).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("insert_author", || params, redis.insert_author(name)).await;
+        }
+        self.with_storage("insert_author", || params, |storage| storage.insert_author(name)).await
+    }
+
+    async fn delete_author(&self, id: u64) -> Result<Option<Result<Author, usize>>, StorageError> {
+        if let Some(postgres) = &self.postgres {
+            return self.with_database("delete_author", || format!("id={}", id), postgres.delete_author(id)).await;
+        }
+        if let Some(redis) = &self.redis {
+            return self.with_database("delete_author", || format!("id={}", id), redis.delete_author(id)).await;
+        }
+        self.with_storage("delete_author", || format!("id={}", id), |storage| storage.remove_author(id))
+            .await
+    }
+}
+
+impl AppState {
+    // Postgres changes don't go through `Storage`, so they reach the
+    // webhooks and live streams from here, once committed.
+    fn notify(&self, kind: &'static str, book: &Book) {
+        self.webhooks.announce(kind, book);
+        self.live.announce(kind, book);
+    }
+
+    // The changes Postgres and Redis made, once they're stored. A deleted
+    // book comes back from the trash with `deleted_at`, which the log
+    // leaves out as it was never part of the live book.
+    fn audit_created(&self, books: &[Book]) {
+        self.audit.append(books.iter().map(|book| self.audit.entry("book.created", book.id, None, Some(book.clone()))).collect());
+    }
+
+    fn audit_deleted(&self, book: &Book) {
+        let before = Book { deleted_at: None, ..book.clone() };
+        self.audit.record("book.deleted", book.id, Some(before), None);
+    }
+
+    // Redis keeps no outbox, so its changes go out through this instance's.
+    // They're already stored, so a failure here can only be reported.
+    async fn announce(&self, kind: &'static str, book: &Book, deleted: bool) {
+        let result = self
+            .with_storage("announce", || format!("id={}", book.id), |storage| {
+                if deleted {
+                    storage.forget(book.id);
+                }
+                storage.emit(Topic::Books, kind, book.id, book);
+            })
+            .await;
+        if let Err(e) = result {
+            tracing::warn!("announcing {} for book {} failed: {}", kind, book.id, e);
+        }
+    }
+}
+
+// The backend a catalog handler stores books in.
+pub struct Store<S>(pub Arc<S>);
+
+impl FromRequest for Store<AppState> {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        Ok(Store(ctx.state().clone()))
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use crate::listen::Bind;
+
+// Sockets passed in through LISTEN_FDS replace the configured listeners, so
+// systemd can keep them open while the service restarts.
+pub fn listeners() -> Result<Vec<Bind>, String> {
+    let fds = sd_notify::listen_fds().map_err(|e| format!("invalid LISTEN_FDS: {}", e))?;
+    // systemd hands over ownership of every descriptor counted by
+    // LISTEN_FDS.
+    fds.map(Bind::from_fd).collect()
+}
+
+// Shown by `systemctl status` while the service is still starting, such as
+// when a large catalog takes a while to load.
+pub fn notify_status(status: &str) {
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Status(status)]) {
+        tracing::warn!("sd_notify STATUS failed: {}", e);
+    }
+}
+
+// Called once every listener is bound, which is after storage has loaded.
+// Outside systemd NOTIFY_SOCKET is unset and this does nothing.
+pub fn notify_ready() {
+    let ready = [sd_notify::NotifyState::Ready, sd_notify::NotifyState::Status("serving")];
+    if let Err(e) = sd_notify::notify(false, &ready) {
+        tracing::warn!("sd_notify READY failed: {}", e);
+    }
+    spawn_watchdog();
+}
+
+pub fn notify_stopping() {
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]) {
+        tracing::warn!("sd_notify STOPPING failed: {}", e);
+    }
+}
+
+// Pings at half the configured interval from a runtime task, so a stalled
+// runtime stops the pings and systemd restarts the service.
+fn spawn_watchdog() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+        return;
+    }
+    let period = Duration::from_micros(usec) / 2;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                tracing::warn!("sd_notify WATCHDOG failed: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,85 @@
+use books_model::Book;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    body::Body,
+    error::ApiError,
+    etag,
+    extract::{Json, Path},
+    json_response,
+    links::Links,
+    store::{BookStore, Store},
+    validate::{self, Validate, Violations},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: usize,
+}
+
+// The tags to add to or take off a book, normalized as they're stored.
+#[derive(Debug, Deserialize)]
+pub struct TagsRequest {
+    pub tags: Vec<String>,
+}
+
+impl Validate for TagsRequest {
+    fn validate(&mut self, violations: &mut Violations) {
+        violations.tags(&mut self.tags);
+    }
+}
+
+pub async fn list<S: BookStore>(Store(store): Store<S>) -> Result<Response<Body>, ApiError> {
+    let tags: Vec<TagCount> = store.tags().await?.into_iter().map(|(tag, count)| TagCount { tag, count }).collect();
+    Ok(json_response(StatusCode::OK, &tags)?)
+}
+
+// Adding and removing only touch the tags named, so unlike PUT they don't
+// need If-Match: two clients tagging a book at once both get their way.
+pub async fn add<S: BookStore>(
+    Path(id): Path<u64>,
+    links: Links,
+    Store(store): Store<S>,
+    Json(request): Json<TagsRequest>,
+) -> Result<Response<Body>, ApiError> {
+    change(id, links, store, request, |tags, added| tags.extend(added.iter().cloned())).await
+}
+
+// Tags the book doesn't have are ignored.
+pub async fn remove<S: BookStore>(
+    Path(id): Path<u64>,
+    links: Links,
+    Store(store): Store<S>,
+    Json(request): Json<TagsRequest>,
+) -> Result<Response<Body>, ApiError> {
+    change(id, links, store, request, |tags, removed| tags.retain(|tag| !removed.contains(tag))).await
+}
+
+async fn change<S: BookStore>(
+    id: u64,
+    links: Links,
+    store: Arc<S>,
+    mut request: TagsRequest,
+    edit: impl Fn(&mut Vec<String>, &[String]) + Sync,
+) -> Result<Response<Body>, ApiError> {
+    validate::check(&mut request)?;
+    let change = |book: &Book| {
+        let mut book = book.clone();
+        edit(&mut book.tags, &request.tags);
+        let mut violations = Violations::default();
+        violations.tags(&mut book.tags);
+        let errors = violations.into_errors();
+        match errors.is_empty() {
+            true => Ok(book),
+            false => Err(ApiError::Validation {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                errors,
+            }),
+        }
+    };
+    let book = store.modify(id, change).await?.ok_or_else(ApiError::not_found)??;
+    Ok(etag::tagged(json_response(StatusCode::OK, &links.book(&book, &book))?, &book))
+}
@@ -0,0 +1,178 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{events::Event, federation::LoanStatus};
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Clone, JsonSchema)]
+pub struct Task {
+    pub id: u64,
+    pub kind: String,
+    pub status: TaskStatus,
+    pub total: usize,
+    pub processed: usize,
+    pub changed: usize,
+    pub error: Option<String>,
+}
+
+// Background work that can be run again from the dead-letter store.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    // The books a failed migration hadn't reached yet.
+    MigrateData { ids: Vec<u64> },
+    PushLoanStatus { peer: String, remote_id: u64, status: LoanStatus },
+    DeliverWebhook { webhook: String, event: Event },
+}
+
+impl Job {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Job::MigrateData { .. } => "migrate_data",
+            Job::PushLoanStatus { .. } => "push_loan_status",
+            Job::DeliverWebhook { .. } => "deliver_webhook",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Failure {
+    pub at: u64,
+    pub error: String,
+}
+
+impl Failure {
+    pub fn now(error: String) -> Self {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Failure { at, error }
+    }
+}
+
+// A job that gave up. Failures accumulate across retries, oldest first.
+#[derive(Debug, Serialize, Clone)]
+pub struct DeadJob {
+    pub id: u64,
+    #[serde(flatten)]
+    pub job: Job,
+    pub failures: Vec<Failure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadJobSummary {
+    pub id: u64,
+    pub kind: &'static str,
+    pub attempts: usize,
+    pub last_failed_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl From<&DeadJob> for DeadJobSummary {
+    fn from(dead: &DeadJob) -> Self {
+        let last = dead.failures.last();
+        DeadJobSummary {
+            id: dead.id,
+            kind: dead.job.kind(),
+            attempts: dead.failures.len(),
+            last_failed_at: last.map(|failure| failure.at),
+            last_error: last.map(|failure| failure.error.clone()),
+        }
+    }
+}
+
+pub struct TaskRegistry {
+    tasks: HashMap<u64, Task>,
+    next_id: u64,
+    dead: BTreeMap<u64, DeadJob>,
+    next_dead_id: u64,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        TaskRegistry {
+            tasks: HashMap::new(),
+            next_id: 1,
+            dead: BTreeMap::new(),
+            next_dead_id: 1,
+        }
+    }
+
+    pub fn start(&mut self, kind: &str, total: usize) -> Task {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let task = Task {
+            id,
+            kind: kind.to_string(),
+            status: TaskStatus::Running,
+            total,
+            processed: 0,
+            changed: 0,
+            error: None,
+        };
+
+        self.tasks.insert(id, task.clone());
+        task
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Task> {
+        self.tasks.get(&id)
+    }
+
+    pub fn approx_bytes(&self) -> usize {
+        self.tasks.capacity() * mem::size_of::<(u64, Task)>()
+            + self.tasks.values().map(|task| task.kind.capacity()).sum::<usize>()
+            + self.dead.len() * mem::size_of::<(u64, DeadJob)>()
+    }
+
+    pub fn record_progress(&mut self, id: u64, processed: usize, changed: usize) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.processed += processed;
+            task.changed += changed;
+        }
+    }
+
+    pub fn complete(&mut self, id: u64) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.status = TaskStatus::Completed;
+        }
+    }
+
+    pub fn fail(&mut self, id: u64, error: String) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.status = TaskStatus::Failed;
+            task.error = Some(error);
+        }
+    }
+
+    pub fn bury(&mut self, job: Job, failures: Vec<Failure>) -> u64 {
+        let id = self.next_dead_id;
+        self.next_dead_id += 1;
+        self.dead.insert(id, DeadJob { id, job, failures });
+        id
+    }
+
+    pub fn dead(&self) -> impl Iterator<Item = &DeadJob> {
+        self.dead.values()
+    }
+
+    pub fn dead_job(&self, id: u64) -> Option<&DeadJob> {
+        self.dead.get(&id)
+    }
+
+    // Takes a dead job out of the store, to retry or discard it.
+    pub fn exhume(&mut self, id: u64) -> Option<DeadJob> {
+        self.dead.remove(&id)
+    }
+}
@@ -0,0 +1,212 @@
+use hyper::{
+    header,
+    http::uri::{PathAndQuery, Uri},
+    Request, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::RwLock;
+
+use crate::{audit::Audit, body::Body, covers::Covers, live::Live, problem, Storage};
+
+pub const HEADER: &str = "x-tenant-id";
+const PREFIX: &str = "/tenants/";
+const MAX_ID_LEN: usize = 64;
+
+// What every tenant shares, or keeps track of books by id outside their
+// catalog, where a tenant's book would be taken for the default one's.
+const SHARED: [&str; 8] = ["/admin", "/debug", "/metrics", "/cluster", "/raft", "/federation", "/merkle", "/mx"];
+const SHARED_PER_BOOK: [&str; 3] = ["/mint", "/anchor", "/proof"];
+
+// A tenant's own catalog: books, authors, the trash, loans and reviews,
+// numbered from 1, and the audit log, live stream and covers that go with
+// them. It's kept in memory only.
+pub struct Collection {
+    pub storage: RwLock<Storage>,
+    pub live: Arc<Live>,
+    pub audit: Arc<Audit>,
+    pub covers: Arc<Covers>,
+}
+
+impl Collection {
+    fn new() -> Self {
+        let live = Arc::new(Live::default());
+        let audit = Arc::new(Audit::default());
+        let mut storage = Storage::new();
+        storage.live = Some(live.clone());
+        storage.audit = Some(audit.clone());
+        Collection {
+            storage: RwLock::new(storage),
+            live,
+            audit,
+            covers: Arc::new(Covers::default()),
+        }
+    }
+}
+
+// Why a request's tenant couldn't be picked, answered once the request has
+// been through the middleware like any other.
+#[derive(Debug, Clone)]
+pub struct Refused(pub StatusCode, pub String);
+
+impl Refused {
+    pub fn respond(&self) -> Response<Body> {
+        problem::respond(self.0, self.1.clone())
+    }
+}
+
+// The /tenants/{id} a request's path started with, for links and
+// redirects to keep.
+#[derive(Debug, Clone)]
+pub struct Prefix(pub String);
+
+tokio::task_local! {
+    static CURRENT: Option<Arc<Collection>>;
+}
+
+// The collection of the tenant the running request is for, or None for
+// the default catalog.
+pub fn current() -> Option<Arc<Collection>> {
+    CURRENT.try_with(Clone::clone).ok().flatten()
+}
+
+pub fn within<F: Future>(collection: Option<Arc<Collection>>, work: F) -> impl Future<Output = F::Output> {
+    CURRENT.scope(collection, work)
+}
+
+// For work a request spawns, which would otherwise run against the
+// default catalog.
+pub fn carry<F: Future>(work: F) -> impl Future<Output = F::Output> {
+    within(current(), work)
+}
+
+// DOJO_MAX_TENANTS lets up to that many tenants keep a catalog apart from
+// the default one and each other, so several people can share a server.
+// A request picks its tenant with an X-Tenant-Id header or by starting its
+// path with /tenants/{id}, and a tenant's catalog is made on its first
+// request. Off unless set; tenants' catalogs are lost on restart.
+#[derive(Default)]
+pub struct Tenants {
+    max: usize,
+    collections: Mutex<HashMap<String, Arc<Collection>>>,
+}
+
+impl Tenants {
+    pub fn from_env() -> Result<Self, String> {
+        let max = match std::env::var("DOJO_MAX_TENANTS") {
+            Ok(max) => max
+                .parse()
+                .map_err(|_| format!("DOJO_MAX_TENANTS must be a non-negative integer, got {:?}", max))?,
+            Err(_) => 0,
+        };
+        Ok(Tenants::new(max))
+    }
+
+    pub fn new(max: usize) -> Self {
+        Tenants {
+            max,
+            collections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max > 0
+    }
+
+    pub fn close(&self) {
+        for collection in self.collections.lock().unwrap().values() {
+            collection.live.close();
+        }
+    }
+
+    // The tenant `req` is for, if any, with the /tenants/{id} prefix taken
+    // off its path so it routes like any other request.
+    pub fn select(&self, req: &mut Request<Body>) -> Result<Option<Arc<Collection>>, Refused> {
+        let named = req.headers().get(HEADER).map(|value| value.to_str().unwrap_or_default().to_string());
+        if !self.enabled() {
+            return match named {
+                Some(_) => Err(Refused(StatusCode::BAD_REQUEST, "X-Tenant-Id needs DOJO_MAX_TENANTS to be set".to_string())),
+                None => Ok(None),
+            };
+        }
+        let id = match (named, take_prefix(req)) {
+            (None, None) => return Ok(None),
+            (Some(named), Some(prefixed)) if named != prefixed => {
+                return Err(Refused(
+                    StatusCode::BAD_REQUEST,
+                    "X-Tenant-Id names a different tenant than the path".to_string(),
+                ));
+            }
+            (Some(id), _) | (None, Some(id)) => id,
+        };
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if id.is_empty() || id.len() > MAX_ID_LEN || !id.chars().all(valid) {
+            return Err(Refused(
+                StatusCode::BAD_REQUEST,
+                format!("tenant ids are 1 to {} letters, digits, '-' or '_'", MAX_ID_LEN),
+            ));
+        }
+        let path = req.uri().path();
+        if shared(path) {
+            return Err(Refused(
+                StatusCode::BAD_REQUEST,
+                format!("{} is shared by every tenant", path),
+            ));
+        }
+
+        let mut collections = self.collections.lock().unwrap();
+        if let Some(collection) = collections.get(&id) {
+            return Ok(Some(collection.clone()));
+        }
+        if collections.len() >= self.max {
+            return Err(Refused(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("all {} tenant catalogs are taken", self.max),
+            ));
+        }
+        tracing::info!("making a catalog for tenant {}", id);
+        Ok(Some(collections.entry(id).or_insert_with(|| Arc::new(Collection::new())).clone()))
+    }
+}
+
+// Takes /tenants/{id} off the path, returning the id.
+fn take_prefix(req: &mut Request<Body>) -> Option<String> {
+    let rest = req.uri().path().strip_prefix(PREFIX)?;
+    let (id, path) = match rest.find('/') {
+        Some(end) => (&rest[..end], &rest[end..]),
+        None => (rest, "/"),
+    };
+    if id.is_empty() {
+        return None;
+    }
+    let id = id.to_string();
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    *req.uri_mut() = Uri::from_parts(parts).ok()?;
+    req.extensions_mut().insert(Prefix(format!("{}{}", PREFIX, id)));
+    Some(id)
+}
+
+fn shared(path: &str) -> bool {
+    let under = |tree: &&str| path.strip_prefix(*tree).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+    SHARED.iter().any(under) || (path.contains("/books/") && SHARED_PER_BOOK.iter().any(|suffix| path.ends_with(suffix)))
+}
+
+// Redirects within the API keep the tenant's prefix.
+pub fn relocate(prefix: &Prefix, response: &mut Response<Body>) {
+    let Some(location) = response.headers().get(header::LOCATION).and_then(|value| value.to_str().ok()) else {
+        return;
+    };
+    if location.starts_with('/') && !location.starts_with("//") {
+        if let Ok(value) = format!("{}{}", prefix.0, location).parse() {
+            response.headers_mut().insert(header::LOCATION, value);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use hyper::{Request, Response, StatusCode};
+use std::time::Duration;
+
+use crate::{
+    body::{self, Body},
+    problem,
+    stack::Next,
+    SharedState,
+};
+
+// DOJO_REQUEST_TIMEOUT_SECS bounds how long a handler may take to answer,
+// 30 seconds by default; 0 turns the limit off. Streamed bodies are
+// answered once they start, so a long export isn't cut short.
+pub fn from_env() -> Result<Option<Duration>, String> {
+    match std::env::var("DOJO_REQUEST_TIMEOUT_SECS") {
+        Ok(secs) => match secs.parse() {
+            Ok(0) => Ok(None),
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => Err(format!("DOJO_REQUEST_TIMEOUT_SECS must be a number of seconds, got {:?}", secs)),
+        },
+        Err(_) => Ok(Some(Duration::from_secs(30))),
+    }
+}
+
+// A request past its deadline is dropped where it is waiting and answered
+// with a 503. Storage connections cut off mid-call aren't reused, but a
+// write may or may not have been applied, which the detail says.
+pub async fn handle(req: Request<Body>, state: SharedState, next: Next) -> Result<Response<Body>, body::Error> {
+    let Some(limit) = state.request_timeout else {
+        return next.run(req, state).await;
+    };
+    let write = !req.method().is_safe();
+    match tokio::time::timeout(limit, next.run(req, state.clone())).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!(timeout_secs = limit.as_secs(), "request timed out");
+            let mut detail = format!("the request took longer than {}s", limit.as_secs());
+            if write {
+                detail.push_str("; the change may or may not have been made");
+            }
+            Ok(problem::respond(StatusCode::SERVICE_UNAVAILABLE, detail))
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+
+// A client gets this long to finish the handshake before it's dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A certificate chain and private key, both PEM, loaded once at startup so
+// a bad path or file stops the server before it listens.
+#[derive(Clone)]
+pub struct Tls {
+    cert: PathBuf,
+    acceptor: TlsAcceptor,
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tls").field("cert", &self.cert).finish()
+    }
+}
+
+impl Tls {
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, String> {
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("reading the certificate {}: {}", cert.display(), e))?;
+        if chain.is_empty() {
+            return Err(format!("{} holds no certificate", cert.display()));
+        }
+        let private_key =
+            PrivateKeyDer::from_pem_file(key).map_err(|e| format!("reading the private key {}: {}", key.display(), e))?;
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, private_key))
+            .map_err(|e| format!("{} and {} don't make a TLS identity: {}", cert.display(), key.display(), e))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Tls {
+            cert: cert.to_path_buf(),
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    // Accepts connections and runs each handshake in its own task, so a
+    // slow client doesn't hold up the others. Connections are handed to the
+    // server once they're encrypted, and the listener closes once the server
+    // stops accepting.
+    pub fn incoming(&self, listener: TcpListener) -> TlsIncoming {
+        let (sender, receiver) = mpsc::channel(64);
+        let acceptor = self.acceptor.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, peer) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // Typically out of file descriptors; waiting
+                            // beats spinning.
+                            tracing::warn!("accepting a TLS connection failed: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                    _ = sender.closed() => return,
+                };
+                let _ = tcp.set_nodelay(true);
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send(TlsConn { stream, peer }).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => tracing::debug!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        TlsIncoming(receiver)
+    }
+}
+
+pub struct TlsIncoming(mpsc::Receiver<TlsConn>);
+
+impl TlsIncoming {
+    pub async fn accept(&mut self) -> Option<TlsConn> {
+        self.0.recv().await
+    }
+}
+
+// A connection after its handshake, with the client's address, which the
+// TLS stream itself doesn't expose.
+pub struct TlsConn {
+    stream: TlsStream<TcpStream>,
+    pub peer: SocketAddr,
+}
+
+impl AsyncRead for TlsConn {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConn {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
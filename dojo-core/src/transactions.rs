@@ -0,0 +1,320 @@
+use books_model::{Book, CreateBookRequest, UpdateBookRequest};
+use hyper::{Response, StatusCode};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    authors,
+    body::Body,
+    conditional::now_ms,
+    error::ApiError,
+    etag::IfMatch,
+    extract::{Json, State},
+    isbn, json_response,
+    links::Links,
+    new_book, problem,
+    problem::FieldError,
+    updated, validate, SharedState, Storage, StorageError, MAX_BATCH,
+};
+
+// One step of POST /books/transactions. A book is named by its id, or as
+// `"$2"` for the book operation 2 of the same transaction created or
+// changed, whose id the client can't know yet. Updates and deletes need
+// `if_match`, as PUT and DELETE need If-Match, and it has to match the
+// book's ETag as the operations before leave it; `*` matches any version,
+// and is what a book created earlier in the transaction takes.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Create {
+        book: CreateBookRequest,
+    },
+    Update {
+        id: Target,
+        #[serde(default)]
+        if_match: Option<String>,
+        changes: UpdateBookRequest,
+    },
+    Delete {
+        id: Target,
+        #[serde(default)]
+        if_match: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    Id(u64),
+    Result(usize),
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Id(u64),
+            Result(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Id(id) => Ok(Target::Id(id)),
+            Raw::Result(name) => name
+                .strip_prefix('$')
+                .and_then(|index| index.parse().ok())
+                .map(Target::Result)
+                .ok_or_else(|| de::Error::custom(format!("expected a book id or an earlier operation like \"$0\", got {:?}", name))),
+        }
+    }
+}
+
+// A book as the transaction sees it: one already stored, or the one an
+// earlier operation creates, which only gets its id as it's applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Stored(u64),
+    Created(usize),
+}
+
+enum Step {
+    Create(CreateBookRequest),
+    Update(Key, Book),
+    Delete(Key),
+}
+
+// The operations checked one after the other against the catalog as the
+// ones before them would leave it, without changing it yet.
+struct Plan<'a> {
+    storage: &'a Storage,
+    // What the operations so far made of each book they touched, None once
+    // deleted.
+    touched: BTreeMap<Key, Option<Book>>,
+    // The book each operation was about, for the ones after to refer to.
+    results: Vec<Key>,
+    steps: Vec<Step>,
+}
+
+impl Plan<'_> {
+    fn key(&self, index: usize, target: Target) -> Result<Key, ApiError> {
+        match target {
+            Target::Id(id) => Ok(Key::Stored(id)),
+            Target::Result(earlier) if earlier < index => Ok(self.results[earlier]),
+            Target::Result(earlier) => Err(ApiError::invalid(
+                StatusCode::BAD_REQUEST,
+                format!("[{}].id", index),
+                format!("${} isn't an earlier operation", earlier),
+            )),
+        }
+    }
+
+    fn current(&self, index: usize, key: Key, if_match: Option<&str>) -> Result<Book, ApiError> {
+        let if_match = if_match.and_then(IfMatch::parse).ok_or_else(|| {
+            ApiError::invalid(
+                StatusCode::PRECONDITION_REQUIRED,
+                format!("[{}].if_match", index),
+                "send the book's ETag, or * for any version",
+            )
+        })?;
+        let book = match self.touched.get(&key) {
+            Some(book) => book.clone(),
+            None => match key {
+                Key::Stored(id) => self.storage.books.get(&id).cloned(),
+                Key::Created(_) => None,
+            },
+        };
+        let book = book.ok_or_else(|| match key {
+            Key::Stored(id) => ApiError::NotFound(format!("[{}]: book {} not found", index, id)),
+            Key::Created(earlier) => ApiError::NotFound(format!("[{}]: the book [{}] created is deleted", index, earlier)),
+        })?;
+        if_match.check(&book)?;
+        Ok(book)
+    }
+
+    // The ISBN can't be another book's, whether that book is stored or one
+    // of the transaction's.
+    fn check_isbn(&self, key: Key, isbn: Option<&str>, field: String) -> Result<(), ApiError> {
+        let Some(isbn) = isbn else {
+            return Ok(());
+        };
+        let duplicate = |id| {
+            ApiError::Storage(StorageError::DuplicateIsbn {
+                isbn: isbn.to_string(),
+                id,
+            })
+        };
+        for (other, book) in &self.touched {
+            let other_isbn = book.as_ref().and_then(|book| book.isbn.as_deref());
+            if *other == key || other_isbn.map(isbn::key) != Some(isbn::key(isbn)) {
+                continue;
+            }
+            return Err(match other {
+                Key::Stored(id) => duplicate(*id),
+                Key::Created(earlier) => ApiError::invalid(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    field,
+                    format!("is also the ISBN of the book [{}] creates", earlier),
+                ),
+            });
+        }
+        let stored = self
+            .storage
+            .index
+            .isbn_holders(isbn)
+            .find(|id| Key::Stored(*id) != key && !self.touched.contains_key(&Key::Stored(*id)));
+        match stored {
+            Some(id) => Err(duplicate(id)),
+            None => Ok(()),
+        }
+    }
+
+    fn add(&mut self, index: usize, operation: Operation) -> Result<(), ApiError> {
+        let (key, step) = match operation {
+            Operation::Create { book } => {
+                let key = Key::Created(index);
+                self.check_isbn(key, book.isbn.as_deref(), format!("[{}].book.isbn", index))?;
+                self.touched.insert(key, Some(new_book(0, book.clone())));
+                (key, Step::Create(book))
+            }
+            Operation::Update { id, if_match, changes } => {
+                let key = self.key(index, id)?;
+                let book = updated(&self.current(index, key, if_match.as_deref())?, changes);
+                self.check_isbn(key, book.isbn.as_deref(), format!("[{}].changes.isbn", index))?;
+                self.touched.insert(key, Some(book.clone()));
+                (key, Step::Update(key, book))
+            }
+            Operation::Delete { id, if_match } => {
+                let key = self.key(index, id)?;
+                self.current(index, key, if_match.as_deref())?;
+                self.touched.insert(key, None);
+                (key, Step::Delete(key))
+            }
+        };
+        self.results.push(key);
+        self.steps.push(step);
+        Ok(())
+    }
+}
+
+// What became of each operation, in order.
+#[derive(Debug, Serialize)]
+struct Outcome<T> {
+    op: &'static str,
+    status: u16,
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book: Option<T>,
+}
+
+#[derive(Debug, Serialize)]
+struct Committed<T> {
+    results: Vec<Outcome<T>>,
+}
+
+// Every step passed its checks, so none of these can fail; the update
+// that runs them is written to disk or the log as one.
+fn apply(storage: &mut Storage, steps: Vec<Step>) -> Vec<Outcome<Book>> {
+    let at = now_ms();
+    let mut created = HashMap::new();
+    let mut outcomes = Vec::with_capacity(steps.len());
+    for (index, step) in steps.into_iter().enumerate() {
+        let id = |key| match key {
+            Key::Stored(id) => id,
+            Key::Created(index) => created[&index],
+        };
+        let outcome = match step {
+            Step::Create(book) => {
+                let book = storage.insert_book(book);
+                created.insert(index, book.id);
+                Outcome { op: "create", status: StatusCode::CREATED.as_u16(), id: book.id, book: Some(book) }
+            }
+            Step::Update(key, book) => {
+                let book = storage.replace_book(Book { id: id(key), ..book });
+                Outcome { op: "update", status: StatusCode::OK.as_u16(), id: book.id, book: Some(book) }
+            }
+            Step::Delete(key) => {
+                let id = id(key);
+                storage.remove_book(id, at);
+                Outcome { op: "delete", status: StatusCode::NO_CONTENT.as_u16(), id, book: None }
+            }
+        };
+        outcomes.push(outcome);
+    }
+    outcomes
+}
+
+// POST /books/transactions runs a list of creates, updates and deletes as
+// one: either all of them apply, answered with what became of each, or
+// none do, answered with the problem of the first that couldn't, its
+// fields named like `[2].book.title`. Only the in-memory catalog, with or
+// without a disk or log behind it, can take them back.
+pub async fn run(
+    links: Links,
+    State(state): State<SharedState>,
+    Json(mut operations): Json<Vec<Operation>>,
+) -> Result<Response<Body>, ApiError> {
+    if state.postgres.is_some() || state.redis.is_some() {
+        return Ok(problem::respond(
+            StatusCode::NOT_IMPLEMENTED,
+            "transactions aren't supported with DATABASE_URL or DOJO_STORAGE=redis yet",
+        ));
+    }
+    if operations.is_empty() || operations.len() > MAX_BATCH {
+        return Err(ApiError::BadRequest(format!("A transaction holds 1 to {} operations", MAX_BATCH)));
+    }
+
+    let mut errors = Vec::new();
+    for (index, operation) in operations.iter_mut().enumerate() {
+        let (name, found) = match operation {
+            Operation::Create { book } => {
+                if let Some(id) = book.author_id {
+                    book.author = authors::name(&*state, format!("[{}].book.author_id", index), id).await?;
+                }
+                book.uid = state.ids.mint();
+                ("book", validate::errors(book))
+            }
+            Operation::Update { changes, .. } => {
+                if let Some(id) = changes.author_id {
+                    changes.author = Some(authors::name(&*state, format!("[{}].changes.author_id", index), id).await?);
+                }
+                ("changes", validate::errors(changes))
+            }
+            Operation::Delete { .. } => continue,
+        };
+        errors.extend(found.into_iter().map(|error| FieldError {
+            field: format!("[{}].{}.{}", index, name, error.field),
+            message: error.message,
+        }));
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            errors,
+        });
+    }
+
+    let count = operations.len();
+    let outcomes = state
+        .with_storage("transaction", || format!("operations={}", count), |storage| {
+            let mut plan = Plan {
+                storage,
+                touched: BTreeMap::new(),
+                results: Vec::with_capacity(count),
+                steps: Vec::with_capacity(count),
+            };
+            for (index, operation) in operations.into_iter().enumerate() {
+                plan.add(index, operation)?;
+            }
+            let steps = plan.steps;
+            Ok::<_, ApiError>(apply(storage, steps))
+        })
+        .await??;
+    let results = outcomes
+        .into_iter()
+        .map(|outcome| Outcome {
+            op: outcome.op,
+            status: outcome.status,
+            id: outcome.id,
+            book: outcome.book.map(|book| links.book(book.clone(), &book)),
+        })
+        .collect();
+    Ok(json_response(StatusCode::OK, &Committed { results })?)
+}
@@ -0,0 +1,18 @@
+use hyper::{header, Response, StatusCode};
+use rust_embed::RustEmbed;
+
+use crate::{body::Body, error::ApiError, extract::Path};
+
+#[derive(RustEmbed)]
+#[folder = "admin-ui/"]
+struct Assets;
+
+pub async fn asset(Path(name): Path<String>) -> Result<Response<Body>, ApiError> {
+    let file = Assets::get(&name).ok_or_else(ApiError::not_found)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, file.metadata.mimetype())
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(file.data.into_owned()))
+        .unwrap())
+}
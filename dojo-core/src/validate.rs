@@ -0,0 +1,194 @@
+use books_model::{CreateBookRequest, Edition, UpdateBookRequest};
+use hyper::StatusCode;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::SystemTime,
+};
+
+use crate::{accesslog, error::ApiError, isbn, problem::FieldError};
+
+pub const MAX_TITLE: usize = 300;
+pub const MAX_AUTHOR: usize = 200;
+pub const MAX_TAG: usize = 50;
+pub const MAX_TAGS: usize = 20;
+pub const MAX_PUBLISHER: usize = 200;
+// About when printing with movable type began in Europe. The latest year
+// is next year's, for books announced ahead of time.
+pub const MIN_PUBLISHED_YEAR: i32 = 1450;
+
+// What's wrong with a request's fields, gathered so every violation is
+// reported at once instead of only the first.
+#[derive(Default)]
+pub struct Violations {
+    prefix: String,
+    errors: Vec<FieldError>,
+}
+
+impl Violations {
+    fn add(&mut self, field: &str, message: String) {
+        self.errors.push(FieldError {
+            field: format!("{}{}", self.prefix, field),
+            message,
+        });
+    }
+
+    // Trims a title or author, which then has to have some text left and
+    // at most `max` characters.
+    pub fn text(&mut self, field: &str, value: &mut String, max: usize) {
+        if value.trim().len() != value.len() {
+            *value = value.trim().to_string();
+        }
+        if value.is_empty() {
+            self.add(field, "must not be empty".to_string());
+        } else if value.chars().count() > max {
+            self.add(field, format!("must be at most {} characters", max));
+        }
+    }
+
+    pub fn isbn(&mut self, value: &mut Option<String>) {
+        if let Err(message) = isbn::validate(value) {
+            self.add("isbn", message);
+        }
+    }
+
+    // Lowercases and trims each tag, then sorts them and drops duplicates,
+    // so the same set of tags is always stored the same way.
+    pub fn tags(&mut self, tags: &mut Vec<String>) {
+        for (i, tag) in tags.iter_mut().enumerate() {
+            *tag = tag.trim().to_lowercase();
+            let field = format!("tags[{}]", i);
+            if tag.is_empty() {
+                self.add(&field, "must not be empty".to_string());
+            } else if tag.chars().count() > MAX_TAG {
+                self.add(&field, format!("must be at most {} characters", MAX_TAG));
+            } else if tag.contains(',') {
+                self.add(&field, "must not contain a comma".to_string());
+            }
+        }
+        tags.sort();
+        tags.dedup();
+        if tags.len() > MAX_TAGS {
+            self.add("tags", format!("a book can have at most {} tags", MAX_TAGS));
+        }
+    }
+
+    // Trims the publisher and lowercases the language, which has to be
+    // an ISO 639-1 or 639-2 code.
+    pub fn edition(&mut self, edition: &mut Edition) {
+        if let Some(year) = edition.published_year {
+            let latest = accesslog::civil(SystemTime::now()).0 as i32 + 1;
+            if !(MIN_PUBLISHED_YEAR..=latest).contains(&year) {
+                self.add("published_year", format!("must be between {} and {}", MIN_PUBLISHED_YEAR, latest));
+            }
+        }
+        if let Some(publisher) = &mut edition.publisher {
+            self.text("publisher", publisher, MAX_PUBLISHER);
+        }
+        if edition.pages == Some(0) {
+            self.add("pages", "must be positive".to_string());
+        }
+        if let Some(language) = &mut edition.language {
+            *language = language.trim().to_lowercase();
+            if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_lowercase()) {
+                self.add("language", format!("{:?} isn't an ISO 639 language code like \"en\" or \"fra\"", language));
+            }
+        }
+    }
+
+    pub fn into_errors(self) -> Vec<FieldError> {
+        self.errors
+    }
+}
+
+pub trait Validate {
+    fn validate(&mut self, violations: &mut Violations);
+}
+
+impl Validate for CreateBookRequest {
+    fn validate(&mut self, violations: &mut Violations) {
+        violations.text("title", &mut self.title, MAX_TITLE);
+        violations.text("author", &mut self.author, MAX_AUTHOR);
+        violations.isbn(&mut self.isbn);
+        violations.tags(&mut self.tags);
+        violations.edition(&mut self.edition);
+    }
+}
+
+// Only the fields being changed are checked.
+impl Validate for UpdateBookRequest {
+    fn validate(&mut self, violations: &mut Violations) {
+        if let Some(title) = &mut self.title {
+            violations.text("title", title, MAX_TITLE);
+        }
+        if let Some(author) = &mut self.author {
+            violations.text("author", author, MAX_AUTHOR);
+        }
+        violations.isbn(&mut self.isbn);
+        if let Some(tags) = &mut self.tags {
+            violations.tags(tags);
+        }
+        violations.edition(&mut self.edition);
+    }
+}
+
+pub fn errors<T: Validate>(value: &mut T) -> Vec<FieldError> {
+    let mut violations = Violations::default();
+    value.validate(&mut violations);
+    violations.into_errors()
+}
+
+fn respond(errors: Vec<FieldError>) -> Result<(), ApiError> {
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ApiError::Validation {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            errors,
+        }),
+    }
+}
+
+// Trims a request body's fields. Fails with everything wrong with them, if
+// anything is.
+pub fn check<T: Validate>(value: &mut T) -> Result<(), ApiError> {
+    respond(errors(value))
+}
+
+// The same for a batch, naming fields by their item, like `[2].title`.
+pub fn check_all<T: Validate>(values: &mut [T]) -> Result<(), ApiError> {
+    respond(errors_all(values))
+}
+
+pub fn errors_all<T: Validate>(values: &mut [T]) -> Vec<FieldError> {
+    let mut violations = Violations::default();
+    for (index, value) in values.iter_mut().enumerate() {
+        violations.prefix = format!("[{}].", index);
+        value.validate(&mut violations);
+    }
+    violations.into_errors()
+}
+
+// Two books of one batch can't share an ISBN, which the storage only checks
+// against the books it already has. Run after `check_all`, which
+// normalizes the ISBNs.
+pub fn distinct_isbns(books: &[CreateBookRequest]) -> Result<(), ApiError> {
+    respond(shared_isbns(books))
+}
+
+pub fn shared_isbns(books: &[CreateBookRequest]) -> Vec<FieldError> {
+    let mut first = HashMap::new();
+    let mut violations = Violations::default();
+    for (index, book) in books.iter().enumerate() {
+        let Some(isbn) = &book.isbn else {
+            continue;
+        };
+        match first.entry(isbn::key(isbn)) {
+            Entry::Occupied(earlier) => {
+                violations.add(&format!("[{}].isbn", index), format!("is also the ISBN of [{}]", earlier.get()))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+            }
+        }
+    }
+    violations.into_errors()
+}
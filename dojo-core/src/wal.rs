@@ -0,0 +1,180 @@
+use books_model::{Author, Book};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write as _},
+    path::PathBuf,
+};
+
+use crate::{sqlite::Write, Storage};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Change {
+    Create { book: Book },
+    Update { book: Book },
+    // Moved to the trash, or back out of it.
+    Trash { book: Book },
+    Restore { book: Book },
+    // Written before deleted books went to the trash.
+    Delete { id: u64 },
+    CreateAuthor { author: Author },
+    DeleteAuthor { id: u64 },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    seq: u64,
+    #[serde(flatten)]
+    change: Change,
+}
+
+// DOJO_WAL_FILE appends every change to the catalog to a file, one JSON
+// record per line, before the request that made it is answered. On startup
+// the records are replayed on top of the snapshot, if there is one, and the
+// snapshot cuts the log down to what it doesn't cover yet.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    // Sequence number of the last record written.
+    seq: u64,
+}
+
+impl Wal {
+    pub fn from_env() -> Result<Option<(Self, Vec<Record>)>, String> {
+        match std::env::var("DOJO_WAL_FILE") {
+            Ok(path) if !path.is_empty() => Wal::open(PathBuf::from(path)).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // Opens the log and reads back its records. A line without its newline
+    // was cut short by a crash before it was acknowledged, so it's dropped.
+    fn open(path: PathBuf) -> Result<(Self, Vec<Record>), String> {
+        let describe = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| describe(&e))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).map_err(|e| describe(&e))?;
+
+        let complete = contents.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+        if complete < contents.len() {
+            tracing::warn!(
+                "dropping {} bytes of an unfinished record at the end of {}",
+                contents.len() - complete,
+                path.display()
+            );
+            file.set_len(complete as u64).map_err(|e| describe(&e))?;
+        }
+        let mut records = Vec::new();
+        for (number, line) in contents[..complete].split(|b| *b == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let record: Record =
+                serde_json::from_slice(line).map_err(|e| describe(&format!("line {}: {}", number + 1, e)))?;
+            records.push(record);
+        }
+        let seq = records.last().map_or(0, |record| record.seq);
+        Ok((Wal { path, file, seq }, records))
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // Applies the records a snapshot taken at `after` doesn't already hold.
+    pub fn replay(&mut self, records: Vec<Record>, after: u64, storage: &mut Storage) -> usize {
+        self.seq = self.seq.max(after);
+        let mut applied = 0;
+        for record in records.into_iter().filter(|record| record.seq > after) {
+            match record.change {
+                Change::Create { book } | Change::Update { book } | Change::Restore { book } => {
+                    storage.next_id = storage.next_id.max(book.id + 1);
+                    storage.index.insert(&book);
+                    storage.modified.touch(book.id);
+                    storage.deleted.remove(&book.id);
+                    storage.books.insert(book.id, book);
+                }
+                Change::Trash { book } => {
+                    let id = book.id;
+                    storage.next_id = storage.next_id.max(id + 1);
+                    storage.books.remove(&id);
+                    storage.index.remove(id);
+                    storage.modified.forget(id);
+                    storage.forget(id);
+                    storage.deleted.insert(id, book);
+                }
+                Change::Delete { id } => {
+                    storage.books.remove(&id);
+                    storage.index.remove(id);
+                    storage.modified.forget(id);
+                    storage.forget(id);
+                }
+                Change::CreateAuthor { author } => {
+                    storage.next_author_id = storage.next_author_id.max(author.id + 1);
+                    storage.authors.insert(author.id, author);
+                }
+                Change::DeleteAuthor { id } => {
+                    storage.authors.remove(&id);
+                }
+            }
+            applied += 1;
+        }
+        applied
+    }
+
+    // Writes an update's changes to books and authors and waits for them to reach the
+    // disk.
+    pub fn append(&mut self, writes: &[Write]) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for write in writes {
+            let change = match write {
+                Write::Created(book) => Change::Create { book: book.clone() },
+                Write::Updated(book) => Change::Update { book: book.clone() },
+                Write::Trashed(book) => Change::Trash { book: book.clone() },
+                Write::Restored(book) => Change::Restore { book: book.clone() },
+                Write::AuthorCreated(author) => Change::CreateAuthor { author: author.clone() },
+                Write::AuthorDeleted(id) => Change::DeleteAuthor { id: *id },
+                Write::Event(_) | Write::Delivered(_) | Write::Hook(_) | Write::Hooked(_) => continue,
+            };
+            self.seq += 1;
+            serde_json::to_writer(&mut lines, &Record { seq: self.seq, change })?;
+            lines.push(b'\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&lines)?;
+        self.file.sync_data()
+    }
+
+    // Drops the records up to `seq` once a snapshot holds them. The rest go
+    // to a new file that replaces the log, and appends continue there.
+    pub fn checkpoint(&mut self, seq: u64) -> io::Result<()> {
+        let mut contents = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut contents)?;
+        let mut kept = Vec::new();
+        for line in contents.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            let record: Record = serde_json::from_slice(line)?;
+            if record.seq > seq {
+                kept.extend_from_slice(line);
+                kept.push(b'\n');
+            }
+        }
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut file = OpenOptions::new().append(true).create(true).truncate(false).open(&temp)?;
+        file.set_len(0)?;
+        file.write_all(&kept)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        self.file = file;
+        Ok(())
+    }
+}
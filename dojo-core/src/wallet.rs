@@ -0,0 +1,403 @@
+use hmac::{Hmac, Mac};
+use ring::{
+    pbkdf2,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::num::NonZeroU32;
+
+use crate::{
+    codec::{base64_decode, hex, unhex},
+    multiversx::bech32_encode,
+};
+
+// scrypt needs 128 * r * n bytes; wallets' own keystores take 4 MiB.
+const MAX_SCRYPT_MEMORY: usize = 256 << 20;
+
+// The wallet MultiversX transactions are signed with, locally, so sending
+// them needs nothing but the gateway. It's loaded at startup from either
+// DOJO_MX_PEM, a PEM file as wallets export them, or DOJO_MX_KEYSTORE, a
+// JSON keystore as the web wallet creates them, with its password in
+// DOJO_MX_KEYSTORE_PASSWORD.
+pub struct Wallet {
+    key: Ed25519KeyPair,
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct Keystore {
+    // secretKey, or mnemonic for keystores that hold a whole seed phrase.
+    #[serde(default)]
+    kind: Option<String>,
+    crypto: Crypto,
+}
+
+#[derive(Deserialize)]
+struct Crypto {
+    ciphertext: String,
+    cipherparams: CipherParams,
+    cipher: String,
+    kdf: String,
+    kdfparams: KdfParams,
+    mac: String,
+}
+
+#[derive(Deserialize)]
+struct CipherParams {
+    iv: String,
+}
+
+#[derive(Deserialize)]
+struct KdfParams {
+    dklen: usize,
+    salt: String,
+    n: usize,
+    r: usize,
+    p: usize,
+}
+
+impl Wallet {
+    // None when neither is set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match (std::env::var("DOJO_MX_PEM"), std::env::var("DOJO_MX_KEYSTORE")) {
+            (Ok(path), Err(_)) => Wallet::load_pem(&path).map(Some),
+            (Err(_), Ok(path)) => {
+                let password = std::env::var("DOJO_MX_KEYSTORE_PASSWORD")
+                    .map_err(|_| "DOJO_MX_KEYSTORE needs its password in DOJO_MX_KEYSTORE_PASSWORD".to_string())?;
+                Wallet::load_keystore(&path, &password).map(Some)
+            }
+            (Err(_), Err(_)) => Ok(None),
+            (Ok(_), Ok(_)) => Err("only one of DOJO_MX_PEM and DOJO_MX_KEYSTORE can be set".to_string()),
+        }
+    }
+
+    // The PEM files wallets are exported as hold the hex of the secret
+    // key's seed and then the public key, base64-encoded.
+    pub fn load_pem(path: &str) -> Result<Self, String> {
+        let source = format!("DOJO_MX_PEM {}", path);
+        let pem = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", source, e))?;
+        let encoded: String = pem.lines().filter(|line| !line.starts_with("-----")).collect();
+        let decoded = base64_decode(encoded.trim()).map_err(|e| format!("{} isn't a wallet PEM: {}", source, e))?;
+        let key = String::from_utf8(decoded)
+            .ok()
+            .and_then(|key| unhex(key.trim()))
+            .ok_or_else(|| format!("{} doesn't hold an Ed25519 key", source))?;
+        Wallet::from_secret(&key, &source)
+    }
+
+    // Keystores hold the secret key encrypted with AES-128-CTR under the
+    // first half of a key scrypt derives from the password; the second
+    // half authenticates the ciphertext with HMAC-SHA256.
+    pub fn load_keystore(path: &str, password: &str) -> Result<Self, String> {
+        let source = format!("DOJO_MX_KEYSTORE {}", path);
+        let json = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", source, e))?;
+        let keystore: Keystore =
+            serde_json::from_str(&json).map_err(|e| format!("{} isn't a wallet keystore: {}", source, e))?;
+        if let Some(kind) = keystore.kind.as_deref().filter(|kind| *kind != "secretKey") {
+            return Err(format!("{} holds a {}; only secret key keystores can sign", source, kind));
+        }
+        let crypto = keystore.crypto;
+        if crypto.cipher != "aes-128-ctr" || crypto.kdf != "scrypt" {
+            return Err(format!("{} uses {} with {}; only aes-128-ctr with scrypt is supported", source, crypto.cipher, crypto.kdf));
+        }
+        let unhex_field = |name: &str, value: &str| {
+            unhex(value).ok_or_else(|| format!("{} has a {} that isn't hex", source, name))
+        };
+        let ciphertext = unhex_field("ciphertext", &crypto.ciphertext)?;
+        let iv = unhex_field("iv", &crypto.cipherparams.iv)?;
+        let salt = unhex_field("salt", &crypto.kdfparams.salt)?;
+        let mac = unhex_field("mac", &crypto.mac)?;
+        let iv: [u8; 16] = iv.try_into().map_err(|_| format!("{} has an iv that isn't 16 bytes", source))?;
+        let params = &crypto.kdfparams;
+        if params.dklen < 32 {
+            return Err(format!("{} derives a key shorter than 32 bytes", source));
+        }
+        let derived = scrypt(password.as_bytes(), &salt, params.n, params.r, params.p, params.dklen)
+            .map_err(|e| format!("{} {}", source, e))?;
+        let mut check = Hmac::<Sha256>::new_from_slice(&derived[16..32]).expect("HMAC takes keys of any length");
+        check.update(&ciphertext);
+        if check.verify_slice(&mac).is_err() {
+            return Err(format!("DOJO_MX_KEYSTORE_PASSWORD isn't the password of {}", source));
+        }
+        let key: [u8; 16] = derived[..16].try_into().unwrap_or_default();
+        Wallet::from_secret(&aes128_ctr(&key, iv, &ciphertext), &source)
+    }
+
+    // A secret key is the 32-byte seed, optionally followed by the public
+    // key, which then has to match.
+    fn from_secret(secret: &[u8], source: &str) -> Result<Self, String> {
+        if secret.len() != 32 && secret.len() != 64 {
+            return Err(format!("{} doesn't hold an Ed25519 key", source));
+        }
+        let key = Ed25519KeyPair::from_seed_unchecked(&secret[..32])
+            .map_err(|_| format!("{} doesn't hold an Ed25519 key", source))?;
+        if secret.len() == 64 && key.public_key().as_ref() != &secret[32..] {
+            return Err(format!("the public key in {} doesn't match its secret key", source));
+        }
+        Ok(Wallet {
+            address: bech32_encode("erd", key.public_key().as_ref()),
+            key,
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    // The hex of the Ed25519 signature over `message`, as the gateway
+    // takes it.
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex(self.key.sign(message).as_ref())
+    }
+}
+
+fn scrypt(password: &[u8], salt: &[u8], n: usize, r: usize, p: usize, len: usize) -> Result<Vec<u8>, String> {
+    if n < 2 || !n.is_power_of_two() || r == 0 || p == 0 {
+        return Err(format!("has scrypt parameters n={} r={} p={} that aren't valid", n, r, p));
+    }
+    if n.saturating_mul(r).saturating_mul(128) > MAX_SCRYPT_MEMORY || r.saturating_mul(p) > 1 << 16 {
+        return Err(format!("has scrypt parameters n={} r={} p={} that need too much memory", n, r, p));
+    }
+    let once = NonZeroU32::new(1).unwrap();
+    let mut blocks = vec![0u8; 128 * r * p];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, once, salt, password, &mut blocks);
+    for block in blocks.chunks_mut(128 * r) {
+        romix(block, n, r);
+    }
+    let mut derived = vec![0u8; len];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, once, &blocks, password, &mut derived);
+    Ok(derived)
+}
+
+fn romix(block: &mut [u8], n: usize, r: usize) {
+    let words = 32 * r;
+    let mut x: Vec<u32> = block.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect();
+    let mut v = vec![0u32; words * n];
+    for i in 0..n {
+        v[i * words..(i + 1) * words].copy_from_slice(&x);
+        block_mix(&mut x, r);
+    }
+    for _ in 0..n {
+        let j = x[(2 * r - 1) * 16] as usize & (n - 1);
+        x.iter_mut().zip(&v[j * words..(j + 1) * words]).for_each(|(x, v)| *x ^= v);
+        block_mix(&mut x, r);
+    }
+    for (bytes, word) in block.chunks_mut(4).zip(x) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+// Even 64-byte blocks of the output come first, then the odd ones.
+fn block_mix(b: &mut [u32], r: usize) {
+    let mut x: [u32; 16] = b[(2 * r - 1) * 16..].try_into().unwrap();
+    let mut y = vec![0u32; 32 * r];
+    for i in 0..2 * r {
+        x.iter_mut().zip(&b[i * 16..(i + 1) * 16]).for_each(|(x, b)| *x ^= b);
+        salsa20_8(&mut x);
+        let at = if i % 2 == 0 { i / 2 } else { r + i / 2 };
+        y[at * 16..(at + 1) * 16].copy_from_slice(&x);
+    }
+    b.copy_from_slice(&y);
+}
+
+fn salsa20_8(b: &mut [u32; 16]) {
+    fn quarter(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    }
+    let mut x = *b;
+    for _ in 0..4 {
+        quarter(&mut x, 0, 4, 8, 12);
+        quarter(&mut x, 5, 9, 13, 1);
+        quarter(&mut x, 10, 14, 2, 6);
+        quarter(&mut x, 15, 3, 7, 11);
+        quarter(&mut x, 0, 1, 2, 3);
+        quarter(&mut x, 5, 6, 7, 4);
+        quarter(&mut x, 10, 11, 8, 9);
+        quarter(&mut x, 15, 12, 13, 14);
+    }
+    b.iter_mut().zip(x).for_each(|(b, x)| *b = b.wrapping_add(x));
+}
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76, 0xca, 0x82, 0xc9,
+    0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0, 0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f,
+    0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15, 0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07,
+    0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75, 0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3,
+    0x29, 0xe3, 0x2f, 0x84, 0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58,
+    0xcf, 0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8, 0x51, 0xa3,
+    0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2, 0xcd, 0x0c, 0x13, 0xec, 0x5f,
+    0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73, 0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88,
+    0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb, 0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac,
+    0x62, 0x91, 0x95, 0xe4, 0x79, 0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a,
+    0xae, 0x08, 0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a, 0x70,
+    0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e, 0xe1, 0xf8, 0x98, 0x11,
+    0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf, 0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42,
+    0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+// AES-128 in counter mode, with the whole IV as a big-endian counter.
+// Only the cipher's forward direction is needed, for both ways.
+fn aes128_ctr(key: &[u8; 16], iv: [u8; 16], data: &[u8]) -> Vec<u8> {
+    let round_keys = expand_key(key);
+    let mut counter = iv;
+    let mut out = Vec::with_capacity(data.len());
+    for chunk in data.chunks(16) {
+        let stream = encrypt_block(&round_keys, counter);
+        out.extend(chunk.iter().zip(stream).map(|(byte, stream)| byte ^ stream));
+        for byte in counter.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+    out
+}
+
+fn expand_key(key: &[u8; 16]) -> [[u8; 16]; 11] {
+    const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+    let mut keys = [[0u8; 16]; 11];
+    keys[0] = *key;
+    for round in 1..11 {
+        let previous = keys[round - 1];
+        let mut word = [previous[13], previous[14], previous[15], previous[12]].map(|b| SBOX[b as usize]);
+        word[0] ^= RCON[round - 1];
+        for i in 0..16 {
+            let prior = if i < 4 { word[i] } else { keys[round][i - 4] };
+            keys[round][i] = previous[i] ^ prior;
+        }
+    }
+    keys
+}
+
+fn encrypt_block(keys: &[[u8; 16]; 11], block: [u8; 16]) -> [u8; 16] {
+    let xtime = |b: u8| (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 };
+    let mut state: [u8; 16] = std::array::from_fn(|i| block[i] ^ keys[0][i]);
+    for (round, key) in keys.iter().enumerate().skip(1) {
+        // Bytes go down columns; row r shifts left by r.
+        let shifted: [u8; 16] = std::array::from_fn(|i| SBOX[state[(i + 4 * (i % 4)) % 16] as usize]);
+        state = shifted;
+        if round < 10 {
+            for column in state.chunks_mut(4) {
+                let [a, b, c, d] = [column[0], column[1], column[2], column[3]];
+                let all = a ^ b ^ c ^ d;
+                column[0] ^= all ^ xtime(a ^ b);
+                column[1] ^= all ^ xtime(b ^ c);
+                column[2] ^= all ^ xtime(c ^ d);
+                column[3] ^= all ^ xtime(d ^ a);
+            }
+        }
+        state.iter_mut().zip(key).for_each(|(byte, key)| *byte ^= key);
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        unhex(&hex.replace(' ', "")).unwrap()
+    }
+
+    // FIPS-197 appendices A.1 and C.1.
+    #[test]
+    fn aes_matches_fips_197() {
+        let keys = expand_key(&bytes("2b7e151628aed2a6abf7158809cf4f3c").try_into().unwrap());
+        assert_eq!(hex(&keys[10]), "d014f9a8c9ee2589e13f0cc8b6630ca6");
+        let keys = expand_key(&bytes("000102030405060708090a0b0c0d0e0f").try_into().unwrap());
+        let block = encrypt_block(&keys, bytes("00112233445566778899aabbccddeeff").try_into().unwrap());
+        assert_eq!(hex(&block), "69c4e0d86a7b0430d8cdb78070b4c55a");
+    }
+
+    // SP 800-38A F.5.1, whose counter carries past its last byte.
+    #[test]
+    fn aes_ctr_matches_sp_800_38a() {
+        let key = bytes("2b7e151628aed2a6abf7158809cf4f3c").try_into().unwrap();
+        let iv = bytes("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap();
+        let plaintext = bytes(
+            "6bc1bee22e409f96e93d7e117393172a ae2d8a571e03ac9c9eb76fac45af8e51 \
+             30c81c46a35ce411e5fbc1191a0a52ef f69f2445df4f9b17ad2b417be66c3710",
+        );
+        let ciphertext = aes128_ctr(&key, iv, &plaintext);
+        assert_eq!(
+            hex(&ciphertext),
+            "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff\
+             5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee"
+        );
+        assert_eq!(aes128_ctr(&key, iv, &ciphertext[..20]), plaintext[..20]);
+    }
+
+    // RFC 7914 section 8.
+    #[test]
+    fn salsa20_8_matches_rfc_7914() {
+        let words = |hex: &str| -> [u32; 16] {
+            let bytes = bytes(hex);
+            std::array::from_fn(|i| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap()))
+        };
+        let mut block = words(
+            "7e879a214f3ec9867ca940e641718f26baee555b8c61c1b50df846116dcd3b1d\
+             ee24f319df9b3d8514121e4b5ac5aa3276021d2909c74829edebc68db8b8c25e",
+        );
+        salsa20_8(&mut block);
+        let expected = words(
+            "a41f859c6608cc993b81cacb020cef05044b2181a2fd337dfd7b1c6396682f29\
+             b4393168e3c9e6bcfe6bc5b7a06d96bae424cc102c91745c24ad673dc7618f81",
+        );
+        assert_eq!(block, expected);
+    }
+
+    // RFC 7914 section 12.
+    #[test]
+    fn scrypt_matches_rfc_7914() {
+        let derived = scrypt(b"", b"", 16, 1, 1, 64).unwrap();
+        assert_eq!(
+            hex(&derived),
+            "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442\
+             fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+        );
+        let derived = scrypt(b"password", b"NaCl", 1024, 8, 16, 64).unwrap();
+        assert_eq!(
+            hex(&derived),
+            "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+             2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+        );
+        assert!(scrypt(b"", b"", 15, 1, 1, 64).is_err());
+        assert!(scrypt(b"", b"", 1 << 20, 8, 1, 64).is_err());
+    }
+
+    // The test wallet alice's key, in a keystore made as the web wallet
+    // makes them, with password "password".
+    const ALICE_KEYSTORE: &str = r#"{
+        "version": 4,
+        "id": "0dc10c02-b59b-4bac-9710-6b2cfa4284ba",
+        "kind": "secretKey",
+        "address": "0139472eff6886771a982f3083da5d421f24c29181e63888228dc81ca60d69e1",
+        "bech32": "erd1qyu5wthldzr8wx5c9ucg8kjagg0jfs53s8nr3zpz3hypefsdd8ssycr6th",
+        "crypto": {
+            "ciphertext": "da49ecb5a3c4b1e46e2ce99b5af1ed77d4834b591805fe8d2e1e74a1092bb94ee79e01748c406afd7e70d16209b9ffb722ac596bb9321f8bf3b9e5889520aa95",
+            "cipherparams": { "iv": "2da5620906634972d9a623bc249d63d4" },
+            "cipher": "aes-128-ctr",
+            "kdf": "scrypt",
+            "kdfparams": { "dklen": 32, "salt": "4903bd0e7880baa04fc4f886518ac5c672cdc745a6bd13dcec2b6c12e9bffe8d", "n": 4096, "r": 8, "p": 1 },
+            "mac": "4ede751aa40d1c6bac343cb4d7befcf01f7e60768cbaaecb86efd09bbfda4e2f"
+        }
+    }"#;
+
+    #[test]
+    fn keystores_decrypt_to_their_address() {
+        let path = std::env::temp_dir().join(format!("dojo-alice-{}.json", std::process::id()));
+        std::fs::write(&path, ALICE_KEYSTORE).unwrap();
+        let path = path.to_str().unwrap();
+
+        let wallet = Wallet::load_keystore(path, "password").unwrap();
+        assert_eq!(wallet.address(), "erd1qyu5wthldzr8wx5c9ucg8kjagg0jfs53s8nr3zpz3hypefsdd8ssycr6th");
+        let error = Wallet::load_keystore(path, "passw0rd").err().unwrap();
+        assert!(error.starts_with("DOJO_MX_KEYSTORE_PASSWORD isn't the password"), "{}", error);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,404 @@
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+use books_model::Book;
+
+use crate::{
+    body::{self, Body},
+    error::ApiError,
+    events::{Event, Topic},
+    extract::{Json, Path, State},
+    federation, json_response,
+    sqlite::Write,
+    tasks::{Failure, Job},
+    SharedState,
+};
+
+pub const EVENT_HEADER: &str = "x-dojo-event";
+pub const DELIVERY_HEADER: &str = "x-dojo-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-dojo-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-dojo-signature";
+
+// The update that takes an event out of the stored queue, which a full
+// outbox lets through as well.
+pub const ACK: &str = "webhooks_ack";
+
+const KINDS: [&str; 4] = ["book.created", "book.updated", "book.deleted", "book.restored"];
+const ATTEMPTS: u32 = 5;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+// Deliveries kept per webhook for the log, newest last.
+const LOG_SIZE: usize = 50;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub event_id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub attempt: u32,
+    pub at: u64,
+    pub duration_ms: u64,
+    // The receiver's status, when it answered.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+struct Webhook {
+    url: String,
+    secret: String,
+    // The event types it wants; all of them when empty.
+    events: Vec<String>,
+    log: VecDeque<Delivery>,
+}
+
+impl Webhook {
+    fn new(url: &str, secret: &str, events: Vec<String>) -> Result<Self, String> {
+        let url = url.trim_end_matches('/');
+        if !url.starts_with("http://") {
+            return Err(format!("url must start with http://, got {:?}", url));
+        }
+        if secret.is_empty() {
+            return Err("secret must not be empty".to_string());
+        }
+        if let Some(unknown) = events.iter().find(|kind| !KINDS.contains(&kind.as_str())) {
+            return Err(format!("unknown event type {:?}, expected one of {}", unknown, KINDS.join(", ")));
+        }
+        Ok(Webhook {
+            url: url.to_string(),
+            secret: secret.to_string(),
+            events,
+            log: VecDeque::new(),
+        })
+    }
+
+    fn wants(&self, kind: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|wanted| wanted == kind)
+    }
+}
+
+// Callback URLs told about every change to a book. DOJO_WEBHOOKS seeds
+// them with comma-separated `name=url=secret` entries, and
+// /admin/webhooks adds and removes them while the server runs; those
+// aren't kept across restarts.
+//
+// Each event is POSTed as JSON with the same envelope the broker gets,
+// signed with the webhook's secret: X-Dojo-Signature is `sha256=` and the
+// hex HMAC-SHA256 of the X-Dojo-Timestamp value, a newline and the body.
+// Failed deliveries are retried with backoff and then left in the
+// dead-letter store, so a receiver may see an event twice, and events may
+// arrive out of order; `id` and `occurred_at_ms` tell them apart. With
+// DOJO_STORAGE=sqlite each event is stored with the change it describes
+// and sent again after a restart until every webhook has had it.
+pub struct Webhooks {
+    hooks: Mutex<BTreeMap<String, Webhook>>,
+    source: String,
+    seq: AtomicU64,
+    sender: mpsc::UnboundedSender<Event>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Event>>>,
+    client: Client<HttpConnector, Body>,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Webhooks {
+            hooks: Mutex::new(BTreeMap::new()),
+            source: crate::leader::node_name(),
+            seq: AtomicU64::new(0),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+}
+
+impl Webhooks {
+    pub fn from_env() -> Result<Self, String> {
+        let webhooks = Webhooks::default();
+        let list = std::env::var("DOJO_WEBHOOKS").unwrap_or_default();
+        let mut hooks = webhooks.hooks.lock().unwrap();
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let invalid = || format!("invalid webhook entry {:?}, expected name=url=secret", entry);
+            let (name, rest) = entry.split_once('=').ok_or_else(invalid)?;
+            let (url, secret) = rest.rsplit_once('=').ok_or_else(invalid)?;
+            if name.is_empty() {
+                return Err(invalid());
+            }
+            let hook = Webhook::new(url, secret, Vec::new()).map_err(|e| format!("webhook {:?}: {}", name, e))?;
+            hooks.insert(name.to_string(), hook);
+        }
+        drop(hooks);
+        Ok(webhooks)
+    }
+
+    // The event for a change, or None when no webhook wants it.
+    pub fn event(&self, kind: &'static str, key: u64, data: impl Serialize) -> Option<Event> {
+        if !KINDS.contains(&kind) || !self.hooks.lock().unwrap().values().any(|hook| hook.wants(kind)) {
+            return None;
+        }
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        Some(Event::new(&self.source, seq, Topic::Books, kind, key, data))
+    }
+
+    // Hands events over for delivery, once the changes they describe are
+    // stored.
+    pub fn send(&self, events: impl IntoIterator<Item = Event>) {
+        for event in events {
+            let _ = self.sender.send(event);
+        }
+    }
+
+    // For changes made outside `Storage`, which are stored by the time
+    // they're announced.
+    pub fn announce(&self, kind: &'static str, book: &Book) {
+        self.send(self.event(kind, book.id, book));
+    }
+
+    fn record(&self, name: &str, delivery: Delivery) {
+        if let Some(hook) = self.hooks.lock().unwrap().get_mut(name) {
+            if hook.log.len() >= LOG_SIZE {
+                hook.log.pop_front();
+            }
+            hook.log.push_back(delivery);
+        }
+    }
+
+    async fn post(&self, name: &str, event: &Event) -> Result<StatusCode, String> {
+        let (url, secret) = {
+            let hooks = self.hooks.lock().unwrap();
+            let hook = hooks.get(name).ok_or("the webhook was removed")?;
+            (hook.url.clone(), hook.secret.clone())
+        };
+        let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()).to_string();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(&url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::USER_AGENT, "book-api-webhooks")
+            .header(EVENT_HEADER, &event.kind)
+            .header(DELIVERY_HEADER, &event.id)
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, signature(&secret, &timestamp, &body))
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())?;
+        let response = tokio::time::timeout(ATTEMPT_TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| "timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+        Ok(response.status())
+    }
+}
+
+// The X-Dojo-Signature of a body sent at `timestamp`.
+fn signature(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    format!("sha256={}", federation::sign(secret, &[timestamp.as_bytes(), body]))
+}
+
+// Hands each event to every webhook that wants it. Once they've all had it,
+// or given up and left it in the dead-letter store, it's taken out of the
+// stored queue.
+pub async fn run(state: SharedState) {
+    let Some(mut receiver) = state.webhooks.receiver.lock().unwrap().take() else {
+        return;
+    };
+    let stored = state.storage.read().await.disk.is_some();
+    while let Some(event) = receiver.recv().await {
+        let names: Vec<String> = state
+            .webhooks
+            .hooks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, hook)| hook.wants(&event.kind))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let deliveries: Vec<_> = names
+                .into_iter()
+                .map(|name| tokio::spawn(deliver(state.clone(), name, event.clone(), Vec::new())))
+                .collect();
+            for delivery in deliveries {
+                let _ = delivery.await;
+            }
+            if !stored {
+                return;
+            }
+            let acked = state
+                .with_storage(ACK, || format!("event={}", event.id), |storage| {
+                    storage.record(Write::Hooked(event.id.clone()))
+                })
+                .await;
+            if let Err(e) = acked {
+                tracing::warn!(event = %event.id, "clearing a delivered webhook event failed, it'll be sent again: {}", e);
+            }
+        });
+    }
+}
+
+// Tries a few times with backoff, then buries the delivery so an operator
+// can retry it. A 4xx other than 408 and 429 won't change on a retry.
+pub async fn deliver(state: SharedState, name: String, event: Event, mut failures: Vec<Failure>) {
+    for attempt in 0..ATTEMPTS {
+        let start = Instant::now();
+        let result = state.webhooks.post(&name, &event).await;
+        let mut delivery = Delivery {
+            event_id: event.id.clone(),
+            kind: event.kind.clone(),
+            attempt: failures.len() as u32 + 1,
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            duration_ms: start.elapsed().as_millis() as u64,
+            status: result.as_ref().ok().map(|status| status.as_u16()),
+            error: None,
+        };
+        let (error, retry) = match result {
+            Ok(status) if status.is_success() => {
+                state.webhooks.record(&name, delivery);
+                return;
+            }
+            Err(e) if e == "the webhook was removed" => return,
+            Ok(status) => (
+                format!("answered {}", status),
+                !status.is_client_error() || matches!(status, StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS),
+            ),
+            Err(e) => (e, true),
+        };
+        tracing::warn!(webhook = %name, event = %event.id, "delivering a webhook failed: {}", error);
+        delivery.error = Some(error.clone());
+        state.webhooks.record(&name, delivery);
+        failures.push(Failure::now(error));
+        if !retry {
+            break;
+        }
+        if attempt + 1 < ATTEMPTS {
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+        }
+    }
+    tracing::warn!(webhook = %name, event = %event.id, "giving up on delivering a webhook");
+    state.tasks.lock().await.bury(Job::DeliverWebhook { webhook: name, event }, failures);
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookRequest {
+    url: String,
+    secret: String,
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookView {
+    name: String,
+    url: String,
+    events: Vec<String>,
+}
+
+impl WebhookView {
+    fn of(name: &str, hook: &Webhook) -> Self {
+        WebhookView {
+            name: name.to_string(),
+            url: hook.url.clone(),
+            events: match hook.events.is_empty() {
+                true => KINDS.iter().map(|kind| kind.to_string()).collect(),
+                false => hook.events.clone(),
+            },
+        }
+    }
+}
+
+pub async fn list_webhooks(State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let hooks = state.webhooks.hooks.lock().unwrap();
+    let views: Vec<WebhookView> = hooks.iter().map(|(name, hook)| WebhookView::of(name, hook)).collect();
+    drop(hooks);
+    json_response(StatusCode::OK, &views)
+}
+
+// Replacing a webhook keeps its delivery log.
+pub async fn put_webhook(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+    Json(request): Json<WebhookRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let mut hook = Webhook::new(&request.url, &request.secret, request.events).map_err(ApiError::BadRequest)?;
+    let mut hooks = state.webhooks.hooks.lock().unwrap();
+    if let Some(old) = hooks.remove(&name) {
+        hook.log = old.log;
+    }
+    let view = WebhookView::of(&name, &hook);
+    hooks.insert(name, hook);
+    drop(hooks);
+    Ok(json_response(StatusCode::OK, &view)?)
+}
+
+pub async fn delete_webhook(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    state.webhooks.hooks.lock().unwrap().remove(&name).ok_or_else(ApiError::not_found)?;
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn list_deliveries(
+    Path(name): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let log: Option<Vec<Delivery>> =
+        state.webhooks.hooks.lock().unwrap().get(&name).map(|hook| hook.log.iter().cloned().collect());
+    Ok(json_response(StatusCode::OK, &log.ok_or_else(ApiError::not_found)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HMAC-SHA256 of "1700000000\n" and the body under "whsec", worked out
+    // apart, as a receiver would check it.
+    #[test]
+    fn signatures_cover_the_timestamp_and_body() {
+        let body = br#"{"id":"node-1","type":"book.created"}"#;
+        let signed = signature("whsec", "1700000000", body);
+        assert_eq!(signed, "sha256=5b5fc0239bf46d712b46647f380f4171aae4e8fac914c977cb4f5ba5e7a52506");
+        assert_ne!(signature("whsec", "1700000001", body), signed);
+        assert_ne!(signature("other", "1700000000", body), signed);
+    }
+
+    #[test]
+    fn webhooks_need_a_url_a_secret_and_known_events() {
+        assert!(Webhook::new("https://hooks.test", "s", Vec::new()).err().unwrap().contains("must start with http://"));
+        assert_eq!(Webhook::new("http://hooks.test", "", Vec::new()).err().as_deref(), Some("secret must not be empty"));
+        let unknown = Webhook::new("http://hooks.test", "s", vec!["book.read".to_string()]).err().unwrap();
+        assert!(unknown.starts_with("unknown event type \"book.read\""), "{}", unknown);
+        assert_eq!(Webhook::new("http://hooks.test/", "s", Vec::new()).unwrap().url, "http://hooks.test");
+    }
+
+    // No event is made for a change no webhook wants.
+    #[test]
+    fn events_are_only_made_for_wanted_kinds() {
+        let webhooks = Webhooks::default();
+        assert!(webhooks.event("book.created", 1, ()).is_none());
+        let deletes = Webhook::new("http://hooks.test", "s", vec!["book.deleted".to_string()]).unwrap();
+        assert!(deletes.wants("book.deleted") && !deletes.wants("book.created"));
+        webhooks.hooks.lock().unwrap().insert("audit".to_string(), deletes);
+        assert!(webhooks.event("book.created", 1, ()).is_none());
+        let event = webhooks.event("book.deleted", 1, ()).unwrap();
+        assert_eq!(event.kind, "book.deleted");
+        assert!(webhooks.event("author.deleted", 1, ()).is_none());
+    }
+}
@@ -0,0 +1,481 @@
+use hyper::{
+    header::{self, HeaderValue},
+    upgrade::{OnUpgrade, Upgraded},
+    Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::{collections::BTreeSet, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc},
+};
+
+use crate::{
+    body::{self, Body},
+    codec::base64_encode,
+    events::Event,
+    extract::{FromRequest, RequestContext, State},
+    problem,
+    store::BookStore,
+    tenants, SharedState,
+};
+
+// RFC 6455's fixed suffix for the handshake's accept key.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Commands are small; anything bigger is refused with 1009.
+const MAX_MESSAGE: u64 = 64 * 1024;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// A request to switch to WebSocket, with its key checked.
+pub struct Upgrade {
+    key: String,
+    on_upgrade: Option<OnUpgrade>,
+}
+
+impl FromRequest for Upgrade {
+    async fn from_request(ctx: &mut RequestContext) -> Result<Self, Response<Body>> {
+        let headers = ctx.headers();
+        let has = |name: header::HeaderName, token: &str| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        if !has(header::UPGRADE, "websocket") || !has(header::CONNECTION, "upgrade") {
+            let mut response = problem::respond(StatusCode::UPGRADE_REQUIRED, "GET /ws has to be a WebSocket handshake");
+            response.headers_mut().insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+            return Err(response);
+        }
+        if headers.get(header::SEC_WEBSOCKET_VERSION).is_none_or(|version| version != "13") {
+            let mut response = problem::respond(StatusCode::BAD_REQUEST, "only WebSocket version 13 is supported");
+            response.headers_mut().insert(header::SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+            return Err(response);
+        }
+        let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY).and_then(|key| key.to_str().ok()) else {
+            return Err(problem::respond(StatusCode::BAD_REQUEST, "Sec-WebSocket-Key is missing"));
+        };
+        let key = key.trim().to_string();
+        Ok(Upgrade {
+            key,
+            on_upgrade: ctx.take_upgrade(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum Command {
+    // With an ID, add the book to what's watched; without one, watch every
+    // book again.
+    Subscribe { id: Option<u64> },
+    // With an ID, stop watching that book; without one, stop watching.
+    Unsubscribe { id: Option<u64> },
+    // The books being watched, as they are now.
+    Snapshot,
+}
+
+// Which books a connection hears about. Every book, to begin with.
+enum Watching {
+    All,
+    Ids(BTreeSet<u64>),
+}
+
+impl Watching {
+    fn wants(&self, event: &Event) -> bool {
+        match self {
+            Watching::All => true,
+            Watching::Ids(ids) => event.key.parse().is_ok_and(|id: u64| ids.contains(&id)),
+        }
+    }
+
+    fn describe(&self) -> Value {
+        match self {
+            Watching::All => json!({"type": "subscriptions", "ids": null}),
+            Watching::Ids(ids) => json!({"type": "subscriptions", "ids": ids}),
+        }
+    }
+}
+
+enum Incoming {
+    Text(String),
+    Ping(Vec<u8>),
+    Close(Option<u16>),
+    // The client broke the protocol; the connection closes with this code.
+    Violation(u16, &'static str),
+}
+
+// GET /ws: a WebSocket that pushes every change to a book as a text
+// message with the event envelope, and takes JSON commands:
+// `{"op":"subscribe","id":1}` narrows the changes to the books subscribed
+// to, `{"op":"unsubscribe","id":1}` drops one, and `{"op":"snapshot"}`
+// answers with the watched books as they are, so a client can load its
+// state and then follow the changes. Subscribing or unsubscribing without
+// an ID watches every book or none. Commands are answered with a
+// `subscriptions` or `snapshot` message, or an `error` one.
+//
+// The actix runner can't hand the connection over, so there the
+// handshake goes unanswered.
+pub async fn connect(upgrade: Upgrade, State(state): State<SharedState>) -> Result<Response<Body>, body::Error> {
+    let accept = base64_encode(&Sha1::digest(format!("{}{}", upgrade.key, GUID)));
+    match upgrade.on_upgrade {
+        Some(on_upgrade) => {
+            tokio::spawn(tenants::carry(async move {
+                match on_upgrade.await {
+                    Ok(upgraded) => serve(upgraded, state).await,
+                    Err(e) => tracing::warn!("the WebSocket upgrade failed: {}", e),
+                }
+            }));
+        }
+        None => tracing::warn!("this runner can't upgrade connections, so the WebSocket will be dropped"),
+    }
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap())
+}
+
+async fn serve(upgraded: Upgraded, state: SharedState) {
+    let (reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+    let (messages, mut incoming) = mpsc::channel(16);
+    tokio::spawn(read_messages(reader, messages));
+    let (mut events, mut closed) = state.live().subscribe();
+    let mut watching = Watching::All;
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    loop {
+        let sent = tokio::select! {
+            received = events.recv() => match received {
+                Ok(event) if watching.wants(&event) => send_json(&mut writer, &event).await,
+                Ok(_) => Ok(()),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    send_json(&mut writer, &json!({"type": "resync", "missed": missed})).await
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = incoming.recv() => match message {
+                Some(Incoming::Text(text)) => {
+                    let reply = answer(&text, &mut watching, &state).await;
+                    send_json(&mut writer, &reply).await
+                }
+                Some(Incoming::Ping(payload)) => send_frame(&mut writer, PONG, &payload).await,
+                Some(Incoming::Close(code)) => {
+                    let _ = close(&mut writer, code.unwrap_or(1000), "").await;
+                    return;
+                }
+                Some(Incoming::Violation(code, reason)) => {
+                    let _ = close(&mut writer, code, reason).await;
+                    return;
+                }
+                None => return,
+            },
+            _ = ping.tick() => send_frame(&mut writer, PING, b"").await,
+            _ = async { closed.wait_for(|closed| *closed).await.is_ok() } => {
+                let _ = close(&mut writer, 1001, "the server is shutting down").await;
+                return;
+            }
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+}
+
+async fn answer(text: &str, watching: &mut Watching, state: &SharedState) -> Value {
+    let command = match serde_json::from_str::<Command>(text) {
+        Ok(command) => command,
+        Err(e) => return json!({"type": "error", "detail": format!("invalid command: {}", e)}),
+    };
+    match command {
+        Command::Subscribe { id: None } => *watching = Watching::All,
+        Command::Subscribe { id: Some(id) } => match watching {
+            Watching::All => *watching = Watching::Ids(BTreeSet::from([id])),
+            Watching::Ids(ids) => {
+                ids.insert(id);
+            }
+        },
+        Command::Unsubscribe { id: None } => *watching = Watching::Ids(BTreeSet::new()),
+        Command::Unsubscribe { id: Some(id) } => {
+            if let Watching::Ids(ids) = watching {
+                ids.remove(&id);
+            }
+        }
+        Command::Snapshot => {
+            let books = match &*watching {
+                Watching::All => state.list().await,
+                Watching::Ids(ids) => {
+                    let mut books = Vec::new();
+                    for id in ids {
+                        match state.get(*id).await {
+                            Ok(Some(book)) => books.push(book),
+                            Ok(None) => {}
+                            Err(e) => return json!({"type": "error", "detail": e.to_string()}),
+                        }
+                    }
+                    Ok(books)
+                }
+            };
+            return match books {
+                Ok(books) => json!({"type": "snapshot", "books": books}),
+                Err(e) => json!({"type": "error", "detail": e.to_string()}),
+            };
+        }
+    }
+    watching.describe()
+}
+
+// Reads messages until the client closes or breaks the protocol, putting
+// fragmented ones back together and answering nothing itself.
+async fn read_messages(mut reader: impl AsyncRead + Unpin, messages: mpsc::Sender<Incoming>) {
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    loop {
+        let (fin, opcode, payload) = match read_frame(&mut reader).await {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(violation)) => {
+                let _ = messages.send(violation).await;
+                return;
+            }
+            Err(_) => return,
+        };
+        let incoming = match opcode {
+            PING => Incoming::Ping(payload),
+            PONG => continue,
+            CLOSE => Incoming::Close(payload.get(..2).map(|code| u16::from_be_bytes([code[0], code[1]]))),
+            TEXT | BINARY | 0x0 => {
+                // A continuation carries on the message that was started;
+                // anything else has to start one.
+                let (kind, mut data) = match (opcode, message.take()) {
+                    (0x0, Some(started)) => started,
+                    (TEXT | BINARY, None) => (opcode, Vec::new()),
+                    _ => {
+                        let _ = messages.send(Incoming::Violation(1002, "unexpected continuation")).await;
+                        return;
+                    }
+                };
+                data.extend(payload);
+                if data.len() as u64 > MAX_MESSAGE {
+                    let _ = messages.send(Incoming::Violation(1009, "the message is too big")).await;
+                    return;
+                }
+                if !fin {
+                    message = Some((kind, data));
+                    continue;
+                }
+                if kind == BINARY {
+                    Incoming::Violation(1003, "commands are text")
+                } else {
+                    match String::from_utf8(data) {
+                        Ok(text) => Incoming::Text(text),
+                        Err(_) => Incoming::Violation(1007, "the message isn't UTF-8"),
+                    }
+                }
+            }
+            _ => Incoming::Violation(1002, "unknown opcode"),
+        };
+        let last = matches!(incoming, Incoming::Close(_) | Incoming::Violation(..));
+        if messages.send(incoming).await.is_err() || last {
+            return;
+        }
+    }
+}
+
+type Frame = Result<(bool, u8, Vec<u8>), Incoming>;
+
+// One frame, unmasked. Clients have to mask what they send.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Frame> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0f);
+    if head[0] & 0x70 != 0 {
+        return Ok(Err(Incoming::Violation(1002, "no extensions were negotiated")));
+    }
+    if head[1] & 0x80 == 0 {
+        return Ok(Err(Incoming::Violation(1002, "client frames have to be masked")));
+    }
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if opcode & 0x8 != 0 && (len > 125 || !fin) {
+        return Ok(Err(Incoming::Violation(1002, "control frames have to be small and whole")));
+    }
+    if len > MAX_MESSAGE {
+        return Ok(Err(Incoming::Violation(1009, "the message is too big")));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Ok((fin, opcode, payload)))
+}
+
+async fn send_frame(writer: &mut (impl AsyncWrite + Unpin), opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+async fn send_json(writer: &mut (impl AsyncWrite + Unpin), value: &impl serde::Serialize) -> std::io::Result<()> {
+    let text = serde_json::to_vec(value).expect("a message serializes");
+    send_frame(writer, TEXT, &text).await
+}
+
+async fn close(writer: &mut (impl AsyncWrite + Unpin), code: u16, reason: &str) -> std::io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend(reason.as_bytes());
+    send_frame(writer, CLOSE, &payload).await?;
+    writer.shutdown().await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    // A frame as a client sends it, masked.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        frame.extend(MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        frame
+    }
+
+    // What the reader hands the connection for `wire`, up to where it stops.
+    async fn incoming(wire: Vec<u8>) -> Vec<String> {
+        let (messages, mut received) = mpsc::channel(64);
+        read_messages(wire.as_slice(), messages).await;
+        let mut described = Vec::new();
+        while let Ok(message) = received.try_recv() {
+            described.push(match message {
+                Incoming::Text(text) => format!("text {}", text),
+                Incoming::Ping(payload) => format!("ping {}", String::from_utf8_lossy(&payload)),
+                Incoming::Close(code) => format!("close {:?}", code),
+                Incoming::Violation(code, reason) => format!("{} {}", code, reason),
+            });
+        }
+        described
+    }
+
+    #[tokio::test]
+    async fn masked_frames_are_unmasked() {
+        // The single-frame masked text message of RFC 6455, section 5.7.
+        let hello = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(incoming(hello).await, ["text Hello"]);
+        let long = "x".repeat(300);
+        assert_eq!(incoming(frame(true, TEXT, long.as_bytes())).await, [format!("text {}", long)]);
+    }
+
+    #[tokio::test]
+    async fn fragments_are_put_back_together() {
+        let mut wire = frame(false, TEXT, "{\"op\":".as_bytes());
+        // Control frames may come between the fragments of a message.
+        wire.extend(frame(true, PING, b"are you there"));
+        wire.extend(frame(true, PONG, b""));
+        wire.extend(frame(false, 0x0, "\"snap".as_bytes()));
+        wire.extend(frame(true, 0x0, "shot\"}".as_bytes()));
+        wire.extend(frame(true, TEXT, "Sól".as_bytes()));
+        wire.extend(frame(true, CLOSE, &1000u16.to_be_bytes()));
+        wire.extend(frame(true, TEXT, b"after the close"));
+        assert_eq!(
+            incoming(wire).await,
+            ["ping are you there", "text {\"op\":\"snapshot\"}", "text Sól", "close Some(1000)"]
+        );
+        assert_eq!(incoming(frame(true, CLOSE, b"")).await, ["close None"]);
+    }
+
+    #[tokio::test]
+    async fn protocol_violations_close_the_connection() {
+        let mut unmasked = frame(true, TEXT, b"hi");
+        unmasked[1] &= 0x7f;
+        let mut extended = frame(true, TEXT, b"hi");
+        extended[0] |= 0x40;
+        let mut interrupted = frame(false, TEXT, b"half");
+        interrupted.extend(frame(true, TEXT, b"another"));
+        let mut too_many_fragments = frame(false, TEXT, &[b'x'; 40 * 1024]);
+        too_many_fragments.extend(frame(false, 0x0, &[b'x'; 40 * 1024]));
+        let mut huge = vec![0x81, 0x80 | 127];
+        huge.extend((MAX_MESSAGE + 1).to_be_bytes());
+        let cases = [
+            (unmasked, "1002 client frames have to be masked"),
+            (extended, "1002 no extensions were negotiated"),
+            (frame(true, 0x0, b"orphan"), "1002 unexpected continuation"),
+            (interrupted, "1002 unexpected continuation"),
+            (frame(false, PING, b""), "1002 control frames have to be small and whole"),
+            (frame(true, PING, &[0; 126]), "1002 control frames have to be small and whole"),
+            (frame(true, 0x3, b""), "1002 unknown opcode"),
+            (frame(true, BINARY, b"{}"), "1003 commands are text"),
+            (frame(true, TEXT, &[0xc3, 0x28]), "1007 the message isn't UTF-8"),
+            (huge, "1009 the message is too big"),
+            (too_many_fragments, "1009 the message is too big"),
+        ];
+        for (wire, expected) in cases {
+            assert_eq!(incoming(wire).await, [expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn cut_off_frames_end_the_connection_quietly() {
+        let mut wire = frame(true, TEXT, b"whole");
+        wire.extend(&frame(true, TEXT, b"partial")[..8]);
+        assert_eq!(incoming(wire).await, ["text whole"]);
+    }
+
+    #[tokio::test]
+    async fn server_frames_go_unmasked() {
+        let mut out = Vec::new();
+        send_frame(&mut out, TEXT, b"Hello").await.unwrap();
+        assert_eq!(out, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        for (len, head) in [(125, vec![0x82, 125]), (126, vec![0x82, 126, 0, 126]), (65536, vec![0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0])] {
+            let mut out = Vec::new();
+            send_frame(&mut out, BINARY, &vec![7; len]).await.unwrap();
+            assert_eq!(out[..head.len()], head[..]);
+            assert_eq!(out.len(), head.len() + len);
+        }
+
+        let mut out = Vec::new();
+        close(&mut out, 1001, "bye").await.unwrap();
+        assert_eq!(out, [0x88, 0x05, 0x03, 0xe9, b'b', b'y', b'e']);
+    }
+}
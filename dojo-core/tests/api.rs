@@ -0,0 +1,373 @@
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderMap},
+    Method, Request, StatusCode, Version,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tokio::{net::TcpListener, task::JoinSet};
+
+// A server on an ephemeral port with an empty in-memory catalog, and a
+// client speaking to it over the network like any other would.
+#[derive(Clone)]
+struct TestServer {
+    addr: SocketAddr,
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        let app = dojo_core::app(dojo_core::open().await);
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    let _ = auto::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(tcp), app).await;
+                });
+            }
+        });
+        TestServer {
+            addr,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+
+    async fn send(&self, method: Method, path: &str, headers: &[(&str, &str)], body: Option<&str>) -> Answer {
+        let mut req = Request::builder().method(method).uri(format!("http://{}{}", self.addr, path));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        if body.is_some() && !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type")) {
+            req = req.header(header::CONTENT_TYPE, "application/json");
+        }
+        let req = req.body(body.map_or_else(Full::default, |body| Full::from(body.to_string()))).unwrap();
+        let response = self.client.request(req).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let bytes = body.collect().await.unwrap().to_bytes();
+        Answer {
+            status: parts.status,
+            body: serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+            headers: parts.headers,
+        }
+    }
+
+    async fn get(&self, path: &str) -> Answer {
+        self.send(Method::GET, path, &[], None).await
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Answer {
+        self.send(Method::POST, path, &[], Some(&body.to_string())).await
+    }
+
+    async fn put(&self, path: &str, if_match: &str, body: Value) -> Answer {
+        self.send(Method::PUT, path, &[("if-match", if_match)], Some(&body.to_string())).await
+    }
+
+    async fn delete(&self, path: &str, if_match: &str) -> Answer {
+        self.send(Method::DELETE, path, &[("if-match", if_match)], None).await
+    }
+}
+
+struct Answer {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Value,
+}
+
+impl Answer {
+    fn etag(&self) -> &str {
+        self.headers.get(header::ETAG).expect("no ETag").to_str().unwrap()
+    }
+
+    fn is_problem(&self) -> bool {
+        self.headers.get(header::CONTENT_TYPE).is_some_and(|v| v == "application/problem+json")
+            && self.body["status"] == self.status.as_u16()
+    }
+}
+
+fn dune() -> Value {
+    json!({"title": "Dune", "author": "Frank Herbert", "isbn": "9780441172719"})
+}
+
+#[tokio::test]
+async fn books_go_through_their_whole_lifecycle() {
+    let server = TestServer::start().await;
+
+    let created = server.post("/books", dune()).await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    assert_eq!(created.body["title"], "Dune");
+    let id = created.body["id"].as_u64().expect("no id");
+    let path = format!("/books/{}", id);
+
+    let fetched = server.get(&path).await;
+    assert_eq!(fetched.status, StatusCode::OK);
+    assert_eq!(fetched.body["author"], "Frank Herbert");
+    assert_eq!(fetched.body["available"], true);
+    assert_eq!(server.get(&format!("/v1{}", path)).await.body["title"], "Dune");
+    assert_eq!(server.get(&format!("/v2{}", path)).await.body["title"], "Dune");
+
+    let listed = server.get("/books").await;
+    assert_eq!(listed.status, StatusCode::OK);
+    assert_eq!(listed.body.as_array().map(Vec::len), Some(1));
+    assert_eq!(listed.body[0]["id"], id);
+
+    let updated = server.put(&path, fetched.etag(), json!({"title": "Dune Messiah"})).await;
+    assert_eq!(updated.status, StatusCode::OK);
+    assert_eq!(updated.body["title"], "Dune Messiah");
+    assert_eq!(updated.body["author"], "Frank Herbert");
+    assert_ne!(updated.etag(), fetched.etag());
+    assert_eq!(server.get(&path).await.body["title"], "Dune Messiah");
+
+    let deleted = server.delete(&path, updated.etag()).await;
+    assert_eq!(deleted.status, StatusCode::NO_CONTENT);
+    assert_eq!(server.get(&path).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(server.get("/books").await.body, json!([]));
+}
+
+#[tokio::test]
+async fn malformed_bodies_are_rejected() {
+    let server = TestServer::start().await;
+
+    let body = dune().to_string();
+    let plain = server.send(Method::POST, "/books", &[("content-type", "text/plain")], Some(&body)).await;
+    assert_eq!(plain.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert!(plain.is_problem());
+    let with_charset = server.send(Method::POST, "/books", &[("content-type", "application/json; charset=utf-8")], Some(&body)).await;
+    assert_eq!(with_charset.status, StatusCode::CREATED);
+    server.delete(&format!("/books/{}", with_charset.body["id"]), "*").await;
+
+    let syntax = server.send(Method::POST, "/books", &[], Some("{\"title\": ")).await;
+    assert_eq!(syntax.status, StatusCode::BAD_REQUEST);
+    assert!(syntax.is_problem());
+
+    let wrong_type = server.post("/books", json!({"title": 7, "author": "A", "isbn": "9780441172719"})).await;
+    assert_eq!(wrong_type.status, StatusCode::BAD_REQUEST);
+    assert!(wrong_type.is_problem());
+
+    let missing = server.post("/books", json!({"title": "Dune"})).await;
+    assert_eq!(missing.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(missing.is_problem());
+    assert_eq!(missing.body["errors"][0]["field"], "author");
+
+    let checksum = server.post("/books", json!({"title": "Dune", "author": "A", "isbn": "9780441172710"})).await;
+    assert_eq!(checksum.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(checksum.body["errors"][0]["field"], "isbn");
+
+    assert_eq!(server.get("/books").await.body, json!([]));
+}
+
+#[tokio::test]
+async fn missing_books_answer_404() {
+    let server = TestServer::start().await;
+
+    for answer in [
+        server.get("/books/999").await,
+        server.put("/books/999", "*", json!({"title": "Nothing"})).await,
+        server.delete("/books/999", "*").await,
+    ] {
+        assert_eq!(answer.status, StatusCode::NOT_FOUND);
+        assert!(answer.is_problem());
+    }
+
+    let bad_id = server.get("/books/abc").await;
+    assert_eq!(bad_id.status, StatusCode::BAD_REQUEST);
+    assert!(bad_id.is_problem());
+}
+
+#[tokio::test]
+async fn duplicate_isbns_conflict() {
+    let server = TestServer::start().await;
+
+    let first = server.post("/books", dune()).await;
+    assert_eq!(first.status, StatusCode::CREATED);
+    let second = server.post("/books", json!({"title": "Dune, again", "author": "F. H.", "isbn": "9780441172719"})).await;
+    assert_eq!(second.status, StatusCode::CONFLICT);
+    assert!(second.is_problem());
+    assert_eq!(second.body["book_id"], first.body["id"]);
+}
+
+#[tokio::test]
+async fn writes_need_the_current_etag() {
+    let server = TestServer::start().await;
+    let id = server.post("/books", dune()).await.body["id"].clone();
+    let path = format!("/books/{}", id);
+
+    let unconditional = server.send(Method::PUT, &path, &[], Some("{\"title\": \"Dune\"}")).await;
+    assert_eq!(unconditional.status, StatusCode::PRECONDITION_REQUIRED);
+
+    let stale = server.put(&path, "\"0000000000000000\"", json!({"title": "Stale"})).await;
+    assert_eq!(stale.status, StatusCode::PRECONDITION_FAILED);
+    assert!(stale.is_problem());
+    assert_eq!(server.get(&path).await.body["title"], "Dune");
+}
+
+// Writers that all read the same version race to update it; the check and
+// the write are one step, so exactly one wins and the rest see 412.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_updates_let_one_writer_win() {
+    let server = TestServer::start().await;
+    let id = server.post("/books", dune()).await.body["id"].clone();
+    let path = format!("/books/{}", id);
+    let etag = server.get(&path).await.etag().to_string();
+
+    let mut writers = JoinSet::new();
+    for n in 0..8 {
+        let (server, path, etag) = (server.clone(), path.clone(), etag.clone());
+        writers.spawn(async move { server.put(&path, &etag, json!({"title": format!("Dune {}", n)})).await });
+    }
+    let answers = writers.join_all().await;
+
+    let winners: Vec<&Answer> = answers.iter().filter(|a| a.status == StatusCode::OK).collect();
+    assert_eq!(winners.len(), 1, "statuses: {:?}", answers.iter().map(|a| a.status).collect::<Vec<_>>());
+    assert!(answers
+        .iter()
+        .all(|a| a.status == StatusCode::OK || a.status == StatusCode::PRECONDITION_FAILED));
+    assert_eq!(server.get(&path).await.body["title"], winners[0].body["title"]);
+}
+
+// HEAD answers like GET, down to the length of the body it leaves out.
+#[tokio::test]
+async fn head_mirrors_get_without_a_body() {
+    let server = TestServer::start().await;
+    let id = server.post("/books", dune()).await.body["id"].clone();
+
+    for path in ["/books".to_string(), format!("/books/{}", id)] {
+        let get = server.get(&path).await;
+        let head = server.send(Method::HEAD, &path, &[], None).await;
+        assert_eq!(head.status, get.status);
+        assert_eq!(head.body, Value::Null);
+        for name in [header::CONTENT_TYPE, header::CONTENT_LENGTH, header::ETAG] {
+            assert_eq!(head.headers.get(&name), get.headers.get(&name), "{} of {}", name, path);
+        }
+    }
+    assert_eq!(server.send(Method::HEAD, "/books/999", &[], None).await.status, StatusCode::NOT_FOUND);
+}
+
+// Dry runs and /books/validate check everything a write would, ISBNs the
+// catalog already has included, and leave the catalog as it was.
+#[tokio::test]
+async fn dry_runs_store_nothing() {
+    let server = TestServer::start().await;
+
+    let preview = server.post("/books?dry_run=true", dune()).await;
+    assert_eq!(preview.status, StatusCode::OK);
+    assert_eq!(preview.body["title"], "Dune");
+    assert_eq!(server.get("/books").await.body, json!([]));
+
+    let created = server.post("/books", dune()).await;
+    let path = format!("/books/{}", created.body["id"]);
+    let again = server.post("/books?dry_run=true", dune()).await;
+    assert_eq!(again.status, StatusCode::CONFLICT);
+    assert_eq!(again.body["book_id"], created.body["id"]);
+
+    let etag = server.get(&path).await.etag().to_string();
+    let update = server.put(&format!("{}?dry_run=true", path), &etag, json!({"title": "  Dune Messiah "})).await;
+    assert_eq!(update.status, StatusCode::OK);
+    assert_eq!(update.body["title"], "Dune Messiah");
+    let stale = server.put(&format!("{}?dry_run=true", path), "\"0000000000000000\"", json!({"title": "Stale"})).await;
+    assert_eq!(stale.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(server.get(&path).await.body["title"], "Dune");
+
+    let batch = json!([
+        {"title": "Emma", "author": "Jane Austen", "isbn": "9780141439587"},
+        {"title": "", "author": "Nobody", "isbn": "9780441172719"},
+        {"title": "Emma, again", "author": "J. A.", "isbn": "978-0-14-143958-7"},
+    ]);
+    let checked = server.post("/books/validate", batch).await;
+    assert_eq!(checked.status, StatusCode::OK);
+    assert_eq!(checked.body["valid"], false);
+    let fields: Vec<&str> = checked.body["errors"].as_array().unwrap().iter().map(|e| e["field"].as_str().unwrap()).collect();
+    assert_eq!(fields, ["[1].title", "[2].isbn", "[1].isbn"]);
+    assert_eq!(checked.body["books"][2]["isbn"], "9780141439587");
+
+    let single = server.post("/books/validate", json!({"title": "Emma", "author": "Jane Austen"})).await;
+    assert_eq!(single.body, json!({"valid": true, "errors": [], "books": {"title": "Emma", "author": "Jane Austen", "isbn": null}}));
+    assert_eq!(server.get("/books").await.body.as_array().map(Vec::len), Some(1));
+}
+
+// A transaction's operations see what the ones before them did, and a
+// failing one takes the others back with it.
+#[tokio::test]
+async fn transactions_apply_all_or_nothing() {
+    let server = TestServer::start().await;
+    let id = server.post("/books", dune()).await.body["id"].clone();
+
+    let committed = server
+        .post(
+            "/books/transactions",
+            json!([
+                {"op": "create", "book": {"title": "Emma", "author": "Jane Austen"}},
+                {"op": "update", "id": "$0", "if_match": "*", "changes": {"isbn": "9780141439587"}},
+                {"op": "delete", "id": id, "if_match": "*"},
+                {"op": "create", "book": {"title": "Dune", "author": "Frank Herbert", "isbn": "9780441172719"}},
+            ]),
+        )
+        .await;
+    assert_eq!(committed.status, StatusCode::OK);
+    let results = committed.body["results"].as_array().unwrap();
+    let statuses: Vec<_> = results.iter().map(|result| result["status"].as_u64().unwrap()).collect();
+    assert_eq!(statuses, [201, 200, 204, 201]);
+    assert_eq!(results[1]["id"], results[0]["id"]);
+    assert_eq!(results[1]["book"]["isbn"], "9780141439587");
+    assert_eq!(results[2]["id"], id);
+    let listed = server.get("/books").await.body;
+    let titles: Vec<_> = listed.as_array().unwrap().iter().map(|book| book["title"].as_str().unwrap()).collect();
+    assert_eq!(titles, ["Emma", "Dune"]);
+
+    let failed = server
+        .post(
+            "/books/transactions",
+            json!([
+                {"op": "create", "book": {"title": "Persuasion", "author": "Jane Austen"}},
+                {"op": "delete", "id": 999, "if_match": "*"},
+            ]),
+        )
+        .await;
+    assert_eq!(failed.status, StatusCode::NOT_FOUND);
+    assert!(failed.is_problem());
+    // Updates and deletes need if_match, as PUT and DELETE need If-Match.
+    let unconditional = server.post("/books/transactions", json!([{"op": "delete", "id": results[0]["id"]}])).await;
+    assert_eq!(unconditional.status, StatusCode::PRECONDITION_REQUIRED);
+    assert_eq!(unconditional.body["errors"][0]["field"], "[0].if_match");
+    let clash = server
+        .post(
+            "/books/transactions",
+            json!([
+                {"op": "create", "book": {"title": "Persuasion", "author": "Jane Austen", "isbn": "9780141439686"}},
+                {"op": "update", "id": results[0]["id"], "if_match": "*", "changes": {"isbn": "9780141439686"}},
+            ]),
+        )
+        .await;
+    assert_eq!(clash.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(clash.body["errors"][0]["field"], "[1].changes.isbn");
+    assert_eq!(server.get("/books").await.body, listed);
+}
+
+// Clients that know the server speaks HTTP/2 can open with it, without
+// TLS to negotiate it.
+#[tokio::test]
+async fn http2_is_spoken_in_the_clear() {
+    let server = TestServer::start().await;
+    let client = Client::builder(TokioExecutor::new()).http2_only(true).build_http::<Full<Bytes>>();
+
+    let post = Request::post(format!("http://{}/books", server.addr))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::from(dune().to_string()))
+        .unwrap();
+    let created = client.request(post).await.unwrap();
+    assert_eq!(created.version(), Version::HTTP_2);
+    assert_eq!(created.status(), StatusCode::CREATED);
+
+    let listed = client.get(format!("http://{}/books", server.addr).parse().unwrap()).await.unwrap();
+    assert_eq!(listed.version(), Version::HTTP_2);
+    let bytes = listed.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(serde_json::from_slice::<Value>(&bytes).unwrap()[0]["title"], "Dune");
+}
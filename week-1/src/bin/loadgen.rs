@@ -0,0 +1,152 @@
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, Method, Request, StatusCode};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use std::{
+    env, process,
+    time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
+
+// Drives a running server with concurrent readers and writers and reports
+// throughput and latency for each. Readers alternate between listing the
+// catalog and fetching single books; writers keep updating random books.
+//
+//     loadgen [base-url] [--readers N] [--writers N] [--books N] [--secs N]
+#[tokio::main]
+async fn main() {
+    let mut args = env::args().skip(1);
+    let mut base_url = "http://127.0.0.1:3000".to_string();
+    let (mut readers, mut writers, mut books, mut secs) = (32, 4, 1000, 10);
+
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--readers" => &mut readers,
+            "--writers" => &mut writers,
+            "--books" => &mut books,
+            "--secs" => &mut secs,
+            _ if arg.starts_with("--") => usage(),
+            _ => {
+                base_url = arg.trim_end_matches('/').to_string();
+                continue;
+            }
+        };
+        *target = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage());
+    }
+    if books == 0 {
+        usage();
+    }
+
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let mut ids = Vec::new();
+    for i in 0..books {
+        let body = format!(r#"{{"title":"Load {}","author":"loadgen"}}"#, i);
+        let (status, bytes) = send(&client, Method::POST, &format!("{}/v1/books", base_url), body).await;
+        if status != StatusCode::CREATED {
+            eprintln!("seeding failed: {} {}", status, String::from_utf8_lossy(&bytes));
+            process::exit(2);
+        }
+        let book: serde_json::Value = serde_json::from_slice(&bytes).unwrap_or_default();
+        ids.push(book["id"].as_u64().unwrap_or_default());
+    }
+    println!("seeded {} books, running for {}s", books, secs);
+
+    let until = Instant::now() + Duration::from_secs(secs);
+    let mut tasks = JoinSet::new();
+    for worker in 0..readers + writers {
+        let (client, base_url, ids) = (client.clone(), base_url.clone(), ids.clone());
+        let reader = worker < readers;
+        tasks.spawn(async move {
+            let mut rng = worker + 1;
+            let mut latencies = Vec::new();
+            let mut failures = 0;
+            while Instant::now() < until {
+                let id = ids[next(&mut rng) as usize % ids.len()];
+                let start = Instant::now();
+                let (status, _) = match (reader, latencies.len() % 2) {
+                    (true, 0) => send(&client, Method::GET, &format!("{}/v1/books", base_url), String::new()).await,
+                    (true, _) => send(&client, Method::GET, &format!("{}/v1/books/{}", base_url, id), String::new()).await,
+                    (false, _) => {
+                        let body = format!(r#"{{"title":"Load {} v{}"}}"#, id, latencies.len());
+                        send(&client, Method::PUT, &format!("{}/v1/books/{}", base_url, id), body).await
+                    }
+                };
+                latencies.push(start.elapsed());
+                if !status.is_success() {
+                    failures += 1;
+                }
+            }
+            (reader, latencies, failures)
+        });
+    }
+
+    let (mut reads, mut writes, mut failures) = (Vec::new(), Vec::new(), 0);
+    while let Some(result) = tasks.join_next().await {
+        let (reader, latencies, failed) = result.unwrap();
+        failures += failed;
+        match reader {
+            true => reads.extend(latencies),
+            false => writes.extend(latencies),
+        }
+    }
+    report("reads", &mut reads, secs);
+    report("writes", &mut writes, secs);
+    if failures > 0 {
+        println!("{} requests failed", failures);
+        process::exit(1);
+    }
+}
+
+async fn send(client: &Client<HttpConnector, Full<Bytes>>, method: Method, uri: &str, body: String) -> (StatusCode, Bytes) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        // Writers overwrite whatever version is stored; the load is the
+        // same as with a real ETag, without a read before every write.
+        .header("If-Match", "*")
+        .body(Full::from(body))
+        .unwrap();
+    match client.request(request).await {
+        Ok(response) => {
+            let status = response.status();
+            (status, response.into_body().collect().await.map(|body| body.to_bytes()).unwrap_or_default())
+        }
+        Err(e) => {
+            eprintln!("{}: request failed: {}", uri, e);
+            process::exit(2);
+        }
+    }
+}
+
+fn report(kind: &str, latencies: &mut [Duration], secs: u64) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort_unstable();
+    let at = |quantile: f64| latencies[((latencies.len() - 1) as f64 * quantile) as usize].as_secs_f64() * 1000.0;
+    println!(
+        "{:<7} {:>8} requests {:>9.1}/s   p50 {:>7.2}ms   p99 {:>7.2}ms   max {:>7.2}ms",
+        kind,
+        latencies.len(),
+        latencies.len() as f64 / secs as f64,
+        at(0.5),
+        at(0.99),
+        at(1.0)
+    );
+}
+
+// xorshift64, enough to spread requests over the catalog.
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn usage() -> ! {
+    eprintln!("usage: loadgen [base-url] [--readers N] [--writers N] [--books N] [--secs N]");
+    process::exit(2);
+}
@@ -0,0 +1,173 @@
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, Method, Request, Uri};
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{BufRead, BufReader},
+    process,
+};
+
+const SKIPPED_HEADERS: &[&str] = &["host", "content-length", "connection", "transfer-encoding"];
+const COMPARED_HEADERS: &[&str] = &["content-type"];
+
+// Re-sends every request from a DOJO_CAPTURE_FILE recording against a
+// running server and reports responses that differ from the recorded ones.
+//
+//     replay <capture.jsonl> [base-url] [--header "Name: value"]...
+#[tokio::main]
+async fn main() {
+    let mut args = env::args().skip(1);
+    let mut path = None;
+    let mut base_url = "http://127.0.0.1:3000".to_string();
+    let mut extra_headers = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--header" {
+            let header = args.next().unwrap_or_else(|| usage());
+            match header.split_once(':') {
+                Some((name, value)) => extra_headers.push((name.trim().to_string(), value.trim().to_string())),
+                None => usage(),
+            }
+        } else if path.is_none() {
+            path = Some(arg);
+        } else {
+            base_url = arg.trim_end_matches('/').to_string();
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let file = File::open(&path).unwrap_or_else(|e| {
+        eprintln!("cannot open {}: {}", path, e);
+        process::exit(2);
+    });
+
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let (mut total, mut mismatched) = (0, 0);
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("cannot read {}: {}", path, e);
+            process::exit(2);
+        });
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("line {}: invalid record: {}", index + 1, e);
+                process::exit(2);
+            }
+        };
+        total += 1;
+
+        let request = &record["request"];
+        let expected = &record["response"];
+        let method = request["method"].as_str().unwrap_or("GET");
+        let uri = request["uri"].as_str().unwrap_or("/");
+
+        let mut builder = Request::builder()
+            .method(Method::from_bytes(method.as_bytes()).unwrap_or(Method::GET))
+            .uri(format!("{}{}", base_url, uri).parse::<Uri>().unwrap_or_else(|e| {
+                eprintln!("line {}: invalid uri: {}", index + 1, e);
+                process::exit(2);
+            }));
+        if let Some(headers) = request["headers"].as_object() {
+            for (name, value) in headers {
+                let value = value.as_str().unwrap_or_default();
+                if value != "[redacted]" && !SKIPPED_HEADERS.contains(&name.as_str()) {
+                    builder = builder.header(name.as_str(), value);
+                }
+            }
+        }
+        for (name, value) in &extra_headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let body = request["body"].as_str().unwrap_or_default().to_string();
+
+        let response = match client.request(builder.body(Full::<Bytes>::from(body)).unwrap()).await {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("{} {}: request failed: {}", method, uri, e);
+                process::exit(2);
+            }
+        };
+
+        let status = response.status().as_u16();
+        let headers: BTreeMap<String, String> = COMPARED_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = response.headers().get(*name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+        let bytes = response.into_body().collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        let actual_body = String::from_utf8_lossy(&bytes);
+
+        let mut differences = Vec::new();
+        if expected["status"].as_u64() != Some(status as u64) {
+            differences.push(format!("status: expected {}, got {}", expected["status"], status));
+        }
+        for name in COMPARED_HEADERS {
+            let recorded = expected["headers"][*name].as_str();
+            let replayed = headers.get(*name).map(String::as_str);
+            if recorded != replayed {
+                differences.push(format!("header {}: expected {:?}, got {:?}", name, recorded, replayed));
+            }
+        }
+        diff_bodies(expected["body"].as_str().unwrap_or_default(), &actual_body, &mut differences);
+
+        if !differences.is_empty() {
+            mismatched += 1;
+            println!("#{} {} {}", index + 1, method, uri);
+            for difference in differences {
+                println!("    {}", difference);
+            }
+        }
+    }
+
+    println!("{} requests replayed, {} mismatched", total, mismatched);
+    if mismatched > 0 {
+        process::exit(1);
+    }
+}
+
+fn diff_bodies(expected: &str, actual: &str, out: &mut Vec<String>) {
+    match (serde_json::from_str::<Value>(expected), serde_json::from_str::<Value>(actual)) {
+        (Ok(expected), Ok(actual)) => diff_json("body", &expected, &actual, out),
+        _ if expected != actual => out.push(format!("body: expected {:?}, got {:?}", expected, actual)),
+        _ => {}
+    }
+}
+
+fn diff_json(path: &str, expected: &Value, actual: &Value, out: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let child = format!("{}.{}", path, key);
+                match b.get(key) {
+                    Some(other) => diff_json(&child, value, other, out),
+                    None => out.push(format!("{}: missing", child)),
+                }
+            }
+            for key in b.keys().filter(|key| !a.contains_key(*key)) {
+                out.push(format!("{}.{}: unexpected", path, key));
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (x, y)) in a.iter().zip(b).enumerate() {
+                diff_json(&format!("{}[{}]", path, i), x, y, out);
+            }
+        }
+        _ if expected != actual => out.push(format!("{}: expected {}, got {}", path, expected, actual)),
+        _ => {}
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: replay <capture.jsonl> [base-url] [--header \"Name: value\"]...");
+    process::exit(2);
+}